@@ -1,8 +1,16 @@
 use super::compose::rpc::v1::{
-    bdev::{Bdev, ListBdevOptions},
+    bdev::{
+        Bdev,
+        BdevShareRequest,
+        BdevUnshareRequest,
+        CreateBdevRequest,
+        DestroyBdevRequest,
+        ListBdevOptions,
+    },
     SharedRpcHandle,
     Status,
 };
+use tonic::Code;
 
 pub async fn list_bdevs(rpc: &SharedRpcHandle) -> Result<Vec<Bdev>, Status> {
     rpc.borrow_mut()
@@ -13,3 +21,71 @@ pub async fn list_bdevs(rpc: &SharedRpcHandle) -> Result<Vec<Bdev>, Status> {
         .await
         .map(|r| r.into_inner().bdevs)
 }
+
+pub async fn find_bdev_by_name(
+    rpc: &SharedRpcHandle,
+    name: &str,
+) -> Result<Bdev, Status> {
+    list_bdevs(rpc)
+        .await?
+        .into_iter()
+        .find(|b| b.name == name)
+        .ok_or_else(|| {
+            Status::new(Code::NotFound, format!("Bdev '{}' not found", name))
+        })
+}
+
+pub async fn create_bdev(
+    rpc: &SharedRpcHandle,
+    uri: &str,
+) -> Result<Bdev, Status> {
+    rpc.borrow_mut()
+        .bdev
+        .create(CreateBdevRequest {
+            uri: uri.to_owned(),
+        })
+        .await
+        .map(|r| r.into_inner().bdev.unwrap())
+}
+
+pub async fn destroy_bdev(
+    rpc: &SharedRpcHandle,
+    uri: &str,
+) -> Result<(), Status> {
+    rpc.borrow_mut()
+        .bdev
+        .destroy(DestroyBdevRequest {
+            uri: uri.to_owned(),
+        })
+        .await
+        .map(|r| r.into_inner())
+}
+
+/// Shares the given bdev over NVMf.
+pub async fn share_bdev(
+    rpc: &SharedRpcHandle,
+    name: &str,
+) -> Result<Bdev, Status> {
+    rpc.borrow_mut()
+        .bdev
+        .share(BdevShareRequest {
+            name: name.to_owned(),
+            protocol: 1,
+            ..Default::default()
+        })
+        .await
+        .map(|r| r.into_inner().bdev.unwrap())
+}
+
+pub async fn unshare_bdev(
+    rpc: &SharedRpcHandle,
+    name: &str,
+) -> Result<(), Status> {
+    rpc.borrow_mut()
+        .bdev
+        .unshare(BdevUnshareRequest {
+            name: name.to_owned(),
+        })
+        .await
+        .map(|_| ())
+}
@@ -15,6 +15,7 @@ use io_engine::core::{
 };
 use std::time::Duration;
 
+pub mod network;
 pub mod rpc;
 
 /// Mayastor test structure that simplifies sending futures. Mayastor has
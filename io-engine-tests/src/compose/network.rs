@@ -0,0 +1,244 @@
+//! Network failures between the containers of a compose test.
+//!
+//! Partitions are imposed with iptables rules and latency and packet loss
+//! with a netem queueing discipline, both set up in the network namespace of
+//! the containers with the tools of the host, through nsenter, so that the
+//! container images do not need to ship them. The tests must therefore run
+//! as root, which they already do to connect to NVMf targets.
+//!
+//! Every failure is undone when the guard returned for it is dropped, so
+//! that connectivity is restored even if the test panics.
+
+use std::{process::Command, time::Duration};
+
+use composer::ComposeTest;
+
+/// Runs a command in the network namespace of the given container.
+fn run_in_netns(
+    container_id: &str,
+    program: &str,
+    args: &[&str],
+) -> Result<(), String> {
+    let output = Command::new("docker")
+        .args(&["inspect", "-f", "{{.State.Pid}}", container_id])
+        .output()
+        .map_err(|e| format!("Failed to run docker inspect: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Failed to inspect container '{}': {}",
+            container_id,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    let pid = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    let output = Command::new("nsenter")
+        .args(&["-t", &pid, "-n", program])
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "'{} {}' failed in container '{}': {}",
+            program,
+            args.join(" "),
+            container_id,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(())
+}
+
+/// Latency and packet loss imposed on the traffic sent by a container.
+#[derive(Debug, Clone, Default)]
+pub struct Netem {
+    pub delay: Option<Duration>,
+    pub jitter: Option<Duration>,
+    /// Percentage of the packets dropped.
+    pub loss_percent: Option<f32>,
+}
+
+impl Netem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = Some(jitter);
+        self
+    }
+
+    pub fn with_loss(mut self, loss_percent: f32) -> Self {
+        self.loss_percent = Some(loss_percent);
+        self
+    }
+
+    fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(delay) = self.delay {
+            args.push("delay".to_string());
+            args.push(format!("{}us", delay.as_micros()));
+            if let Some(jitter) = self.jitter {
+                args.push(format!("{}us", jitter.as_micros()));
+            }
+        }
+        if let Some(loss) = self.loss_percent {
+            args.push("loss".to_string());
+            args.push(format!("{}%", loss));
+        }
+        args
+    }
+}
+
+/// Imposes network failures between the containers of a compose test.
+pub struct Network<'a> {
+    ct: &'a ComposeTest,
+    /// Interface of the containers on the network of the test.
+    interface: String,
+}
+
+impl<'a> Network<'a> {
+    pub fn new(ct: &'a ComposeTest) -> Self {
+        Self {
+            ct,
+            interface: "eth0".to_string(),
+        }
+    }
+
+    pub fn with_interface(mut self, interface: &str) -> Self {
+        self.interface = interface.to_owned();
+        self
+    }
+
+    /// Returns the id and the address of the given container.
+    fn container(&self, name: &str) -> Result<(String, String), String> {
+        self.ct
+            .containers()
+            .iter()
+            .find(|c| c.0 == name)
+            .map(|c| (c.1 .0.to_string(), c.1 .1.to_string()))
+            .ok_or_else(|| format!("Container {} not found!", name))
+    }
+
+    /// Drops all the traffic between the two given containers, until the
+    /// returned guard is dropped.
+    pub fn partition(
+        &self,
+        first: &str,
+        second: &str,
+    ) -> Result<PartitionGuard, String> {
+        let (first_id, first_ip) = self.container(first)?;
+        let (second_id, second_ip) = self.container(second)?;
+
+        let mut guard = PartitionGuard {
+            rules: Vec::new(),
+        };
+        // each side drops the traffic of the other, so that the partition
+        // holds whichever side initiates a connection
+        for (id, peer) in [(first_id, second_ip), (second_id, first_ip)] {
+            for rule in [["INPUT", "-s"], ["OUTPUT", "-d"]] {
+                let rule = vec![
+                    rule[0].to_string(),
+                    rule[1].to_string(),
+                    peer.clone(),
+                    "-j".to_string(),
+                    "DROP".to_string(),
+                ];
+                let mut args = vec!["-I"];
+                args.extend(rule.iter().map(|s| s.as_str()));
+                run_in_netns(&id, "iptables", &args)?;
+                guard.rules.push((id.clone(), rule));
+            }
+        }
+        Ok(guard)
+    }
+
+    /// Drops all the traffic between the given container and all the other
+    /// containers of the test, until the returned guards are dropped.
+    pub fn isolate(&self, name: &str) -> Result<Vec<PartitionGuard>, String> {
+        self.ct
+            .containers()
+            .iter()
+            .map(|c| c.0.clone())
+            .filter(|other| other != name)
+            .map(|other| self.partition(name, &other))
+            .collect()
+    }
+
+    /// Imposes latency and packet loss on all the traffic sent by the given
+    /// container, until the returned guard is dropped.
+    pub fn netem(
+        &self,
+        name: &str,
+        netem: &Netem,
+    ) -> Result<NetemGuard, String> {
+        let (id, _) = self.container(name)?;
+        let netem_args = netem.args();
+        let mut args = vec![
+            "qdisc",
+            "add",
+            "dev",
+            self.interface.as_str(),
+            "root",
+            "netem",
+        ];
+        args.extend(netem_args.iter().map(|s| s.as_str()));
+        run_in_netns(&id, "tc", &args)?;
+
+        Ok(NetemGuard {
+            container_id: id,
+            interface: self.interface.clone(),
+        })
+    }
+}
+
+/// Restores the traffic between two containers when dropped.
+#[must_use]
+pub struct PartitionGuard {
+    /// iptables rules added, by container id.
+    rules: Vec<(String, Vec<String>)>,
+}
+
+impl PartitionGuard {
+    /// Restores the traffic between the containers.
+    pub fn heal(self) {}
+}
+
+impl Drop for PartitionGuard {
+    fn drop(&mut self) {
+        for (id, rule) in self.rules.drain(..) {
+            let mut args = vec!["-D"];
+            args.extend(rule.iter().map(|s| s.as_str()));
+            if let Err(e) = run_in_netns(&id, "iptables", &args) {
+                tracing::error!("Failed to heal partition: {}", e);
+            }
+        }
+    }
+}
+
+/// Removes latency and packet loss from the traffic of a container when
+/// dropped.
+#[must_use]
+pub struct NetemGuard {
+    container_id: String,
+    interface: String,
+}
+
+impl NetemGuard {
+    /// Removes the latency and the packet loss.
+    pub fn heal(self) {}
+}
+
+impl Drop for NetemGuard {
+    fn drop(&mut self) {
+        let args = ["qdisc", "del", "dev", self.interface.as_str(), "root"];
+        if let Err(e) = run_in_netns(&self.container_id, "tc", &args) {
+            tracing::error!("Failed to remove netem: {}", e);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! Execution of fio jobs and parsing of their results.
+
+use serde::Deserialize;
+use std::process::Command;
+
+/// A fio job run against a block device.
+#[derive(Debug, Clone)]
+pub struct Fio {
+    pub name: String,
+    pub rw: String,
+    pub bs: String,
+    pub iodepth: u32,
+    pub numjobs: u32,
+    pub size: Option<String>,
+    pub runtime_secs: Option<u64>,
+    pub verify: Option<String>,
+    pub extra_args: Vec<String>,
+}
+
+impl Default for Fio {
+    fn default() -> Self {
+        Self {
+            name: "fio".to_string(),
+            rw: "randwrite".to_string(),
+            bs: "4k".to_string(),
+            iodepth: 16,
+            numjobs: 1,
+            size: None,
+            runtime_secs: None,
+            verify: None,
+            extra_args: Vec::new(),
+        }
+    }
+}
+
+impl Fio {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = name.to_owned();
+        self
+    }
+
+    pub fn with_rw(mut self, rw: &str) -> Self {
+        self.rw = rw.to_owned();
+        self
+    }
+
+    pub fn with_bs(mut self, bs: &str) -> Self {
+        self.bs = bs.to_owned();
+        self
+    }
+
+    pub fn with_iodepth(mut self, iodepth: u32) -> Self {
+        self.iodepth = iodepth;
+        self
+    }
+
+    pub fn with_numjobs(mut self, numjobs: u32) -> Self {
+        self.numjobs = numjobs;
+        self
+    }
+
+    pub fn with_size(mut self, size: &str) -> Self {
+        self.size = Some(size.to_owned());
+        self
+    }
+
+    /// Runs the job for the given time, repeating the workload if needed.
+    pub fn with_runtime_secs(mut self, runtime_secs: u64) -> Self {
+        self.runtime_secs = Some(runtime_secs);
+        self
+    }
+
+    /// Verifies the data written with the given checksum, e.g. `crc32`.
+    pub fn with_verify(mut self, verify: &str) -> Self {
+        self.verify = Some(verify.to_owned());
+        self
+    }
+
+    pub fn with_arg(mut self, arg: &str) -> Self {
+        self.extra_args.push(arg.to_owned());
+        self
+    }
+
+    /// Returns the command line arguments of the job.
+    pub fn args(&self, filename: &str) -> Vec<String> {
+        let mut args = vec![
+            format!("--name={}", self.name),
+            format!("--filename={}", filename),
+            format!("--rw={}", self.rw),
+            format!("--bs={}", self.bs),
+            format!("--iodepth={}", self.iodepth),
+            format!("--numjobs={}", self.numjobs),
+            "--ioengine=libaio".to_string(),
+            "--direct=1".to_string(),
+            "--thread=1".to_string(),
+            "--group_reporting=1".to_string(),
+            "--output-format=json".to_string(),
+        ];
+        if let Some(size) = &self.size {
+            args.push(format!("--size={}", size));
+        }
+        if let Some(runtime) = self.runtime_secs {
+            args.push(format!("--runtime={}", runtime));
+            args.push("--time_based=1".to_string());
+        }
+        if let Some(verify) = &self.verify {
+            args.push(format!("--verify={}", verify));
+            args.push("--verify_fatal=1".to_string());
+        }
+        args.extend(self.extra_args.iter().cloned());
+        args
+    }
+
+    /// Runs the job against the given device and returns its result. The
+    /// job fails if fio cannot be run, or if it reports an error.
+    pub fn run(&self, filename: &str) -> Result<FioResult, String> {
+        let output = Command::new("fio")
+            .args(self.args(filename))
+            .output()
+            .map_err(|e| format!("Failed to run fio: {}", e))?;
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let result = FioResult::parse(&stdout).map_err(|e| {
+            format!(
+                "Failed to parse fio output: {}\nstdout: {}\nstderr: {}",
+                e,
+                stdout,
+                String::from_utf8_lossy(&output.stderr)
+            )
+        })?;
+
+        if !output.status.success() || result.error != 0 {
+            return Err(format!(
+                "fio job '{}' failed with error {}: {}",
+                self.name,
+                result.error,
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+        Ok(result)
+    }
+}
+
+/// Statistics of the reads or the writes of a fio job.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct FioIoStats {
+    pub io_bytes: u64,
+    /// Bandwidth in KiB/s.
+    pub bw: u64,
+    pub iops: f64,
+    pub lat_ns: FioLatency,
+}
+
+/// Latency of the reads or the writes of a fio job.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct FioLatency {
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+}
+
+/// Result of a fio job, with the statistics of all its threads grouped.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct FioResult {
+    pub jobname: String,
+    pub error: i32,
+    pub read: FioIoStats,
+    pub write: FioIoStats,
+}
+
+#[derive(Deserialize)]
+struct FioOutput {
+    jobs: Vec<FioResult>,
+}
+
+impl FioResult {
+    /// Parses the json output of fio.
+    pub fn parse(output: &str) -> Result<Self, String> {
+        // fio may print notices before the json output
+        let json = output
+            .find('{')
+            .map(|start| &output[start ..])
+            .ok_or_else(|| "no json output".to_string())?;
+
+        serde_json::from_str::<FioOutput>(json)
+            .map_err(|e| e.to_string())?
+            .jobs
+            .into_iter()
+            .next()
+            .ok_or_else(|| "no job in output".to_string())
+    }
+}
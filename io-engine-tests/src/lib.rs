@@ -25,11 +25,13 @@ pub mod cli_tools;
 pub mod compose;
 pub mod error_bdev;
 pub mod file_io;
+pub mod fio;
 pub mod nexus;
 pub mod nvme;
 pub mod nvmf;
 pub mod pool;
 pub mod replica;
+pub mod scenario;
 
 pub use compose::MayastorTest;
 
@@ -54,6 +56,34 @@ where
     panic!("failed operation with retries");
 }
 
+/// Polls the given check every `interval` until it returns a value, or fails
+/// with a `DeadlineExceeded` status naming what was awaited once `timeout`
+/// elapses. Errors returned by the check end the wait.
+pub async fn wait_until<F, Fut, T>(
+    what: &str,
+    timeout: Duration,
+    interval: Duration,
+    mut check: F,
+) -> Result<T, tonic::Status>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<Option<T>, tonic::Status>>,
+{
+    let start = std::time::Instant::now();
+    loop {
+        if let Some(result) = check().await? {
+            return Ok(result);
+        }
+        if start.elapsed() > timeout {
+            return Err(tonic::Status::deadline_exceeded(format!(
+                "Timed out after {:?} waiting for {}",
+                timeout, what
+            )));
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
 pub static MSTEST: OnceCell<MayastorEnvironment> = OnceCell::new();
 
 #[macro_export]
@@ -2,13 +2,26 @@ use super::{
     compose::rpc::v1::{
         nexus::{
             AddChildNexusRequest,
+            Child,
             ChildAction,
             ChildOperationRequest,
             ChildState,
             CreateNexusRequest,
+            DestroyNexusRequest,
+            FaultNexusChildRequest,
             ListNexusOptions,
             Nexus,
+            PauseRebuildRequest,
             PublishNexusRequest,
+            RebuildStateRequest,
+            RebuildStatsRequest,
+            RebuildStatsResponse,
+            RemoveChildNexusRequest,
+            ResumeRebuildRequest,
+            ShutdownNexusRequest,
+            StartRebuildRequest,
+            StopRebuildRequest,
+            UnpublishNexusRequest,
         },
         SharedRpcHandle,
         Status,
@@ -16,9 +29,14 @@ use super::{
     generate_uuid,
     nvmf::{test_write_to_nvmf, NvmfLocation},
     replica::ReplicaBuilder,
+    wait_until,
 };
-use io_engine::{constants::NVME_NQN_PREFIX, subsys::make_subsystem_serial};
-use std::time::{Duration, Instant};
+use io_engine::{
+    bdev::nexus::{nexus_create, nexus_lookup_mut},
+    constants::NVME_NQN_PREFIX,
+    subsys::make_subsystem_serial,
+};
+use std::time::Duration;
 use tonic::Code;
 
 #[derive(Clone)]
@@ -193,19 +211,102 @@ impl NexusBuilder {
         self.add_child(&self.replica_uri(r), norebuild).await
     }
 
-    pub async fn online_child_bdev(&self, bdev: &str) -> Result<Nexus, Status> {
+    pub async fn destroy(&mut self) -> Result<(), Status> {
+        self.rpc()
+            .borrow_mut()
+            .nexus
+            .destroy_nexus(DestroyNexusRequest {
+                uuid: self.uuid(),
+            })
+            .await
+            .map(|r| r.into_inner())
+    }
+
+    pub async fn shutdown(&self) -> Result<(), Status> {
+        self.rpc()
+            .borrow_mut()
+            .nexus
+            .shutdown_nexus(ShutdownNexusRequest {
+                uuid: self.uuid(),
+            })
+            .await
+            .map(|r| r.into_inner())
+    }
+
+    pub async fn unpublish(&self) -> Result<Nexus, Status> {
+        self.rpc()
+            .borrow_mut()
+            .nexus
+            .unpublish_nexus(UnpublishNexusRequest {
+                uuid: self.uuid(),
+            })
+            .await
+            .map(|r| r.into_inner().nexus.unwrap())
+    }
+
+    pub async fn get_nexus(&self) -> Result<Nexus, Status> {
+        find_nexus_by_uuid(self.rpc(), &self.uuid()).await
+    }
+
+    pub async fn remove_child_bdev(&self, bdev: &str) -> Result<Nexus, Status> {
+        self.rpc()
+            .borrow_mut()
+            .nexus
+            .remove_child_nexus(RemoveChildNexusRequest {
+                uuid: self.uuid(),
+                uri: bdev.to_owned(),
+            })
+            .await
+            .map(|r| r.into_inner().nexus.unwrap())
+    }
+
+    pub async fn remove_child_replica(
+        &self,
+        r: &ReplicaBuilder,
+    ) -> Result<Nexus, Status> {
+        self.remove_child_bdev(&self.replica_uri(r)).await
+    }
+
+    pub async fn fault_child_bdev(&self, bdev: &str) -> Result<(), Status> {
+        self.rpc()
+            .borrow_mut()
+            .nexus
+            .fault_nexus_child(FaultNexusChildRequest {
+                uuid: self.uuid(),
+                uri: bdev.to_owned(),
+            })
+            .await
+            .map(|r| r.into_inner())
+    }
+
+    pub async fn fault_child_replica(
+        &self,
+        r: &ReplicaBuilder,
+    ) -> Result<(), Status> {
+        self.fault_child_bdev(&self.replica_uri(r)).await
+    }
+
+    async fn child_operation(
+        &self,
+        bdev: &str,
+        action: ChildAction,
+    ) -> Result<Nexus, Status> {
         self.rpc()
             .borrow_mut()
             .nexus
             .child_operation(ChildOperationRequest {
                 nexus_uuid: self.uuid(),
                 uri: bdev.to_owned(),
-                action: ChildAction::Online as i32,
+                action: action as i32,
             })
             .await
             .map(|r| r.into_inner().nexus.unwrap())
     }
 
+    pub async fn online_child_bdev(&self, bdev: &str) -> Result<Nexus, Status> {
+        self.child_operation(bdev, ChildAction::Online).await
+    }
+
     pub async fn online_child_replica(
         &self,
         r: &ReplicaBuilder,
@@ -213,30 +314,176 @@ impl NexusBuilder {
         self.online_child_bdev(&self.replica_uri(r)).await
     }
 
+    pub async fn offline_child_bdev(
+        &self,
+        bdev: &str,
+    ) -> Result<Nexus, Status> {
+        self.child_operation(bdev, ChildAction::Offline).await
+    }
+
+    pub async fn offline_child_replica(
+        &self,
+        r: &ReplicaBuilder,
+    ) -> Result<Nexus, Status> {
+        self.offline_child_bdev(&self.replica_uri(r)).await
+    }
+
+    /// Returns the child of the nexus with the given URI.
+    pub async fn get_child(&self, bdev: &str) -> Result<Child, Status> {
+        self.get_nexus()
+            .await?
+            .children
+            .into_iter()
+            .find(|c| c.uri == bdev)
+            .ok_or_else(|| {
+                Status::new(
+                    Code::NotFound,
+                    format!(
+                        "Child '{}' of nexus '{}' not found",
+                        bdev,
+                        self.uuid()
+                    ),
+                )
+            })
+    }
+
+    pub async fn get_child_replica(
+        &self,
+        r: &ReplicaBuilder,
+    ) -> Result<Child, Status> {
+        self.get_child(&self.replica_uri(r)).await
+    }
+
+    pub async fn start_rebuild(&self, bdev: &str) -> Result<Nexus, Status> {
+        self.rpc()
+            .borrow_mut()
+            .nexus
+            .start_rebuild(StartRebuildRequest {
+                nexus_uuid: self.uuid(),
+                uri: bdev.to_owned(),
+            })
+            .await
+            .map(|r| r.into_inner().nexus.unwrap())
+    }
+
+    pub async fn stop_rebuild(&self, bdev: &str) -> Result<Nexus, Status> {
+        self.rpc()
+            .borrow_mut()
+            .nexus
+            .stop_rebuild(StopRebuildRequest {
+                nexus_uuid: self.uuid(),
+                uri: bdev.to_owned(),
+            })
+            .await
+            .map(|r| r.into_inner().nexus.unwrap())
+    }
+
+    pub async fn pause_rebuild(&self, bdev: &str) -> Result<Nexus, Status> {
+        self.rpc()
+            .borrow_mut()
+            .nexus
+            .pause_rebuild(PauseRebuildRequest {
+                nexus_uuid: self.uuid(),
+                uri: bdev.to_owned(),
+            })
+            .await
+            .map(|r| r.into_inner().nexus.unwrap())
+    }
+
+    pub async fn resume_rebuild(&self, bdev: &str) -> Result<Nexus, Status> {
+        self.rpc()
+            .borrow_mut()
+            .nexus
+            .resume_rebuild(ResumeRebuildRequest {
+                nexus_uuid: self.uuid(),
+                uri: bdev.to_owned(),
+            })
+            .await
+            .map(|r| r.into_inner().nexus.unwrap())
+    }
+
+    /// Returns the state of the rebuild of the given child, such as
+    /// "running" or "paused".
+    pub async fn get_rebuild_state(
+        &self,
+        bdev: &str,
+    ) -> Result<String, Status> {
+        self.rpc()
+            .borrow_mut()
+            .nexus
+            .get_rebuild_state(RebuildStateRequest {
+                nexus_uuid: self.uuid(),
+                uri: bdev.to_owned(),
+            })
+            .await
+            .map(|r| r.into_inner().state)
+    }
+
+    pub async fn get_rebuild_stats(
+        &self,
+        bdev: &str,
+    ) -> Result<RebuildStatsResponse, Status> {
+        self.rpc()
+            .borrow_mut()
+            .nexus
+            .get_rebuild_stats(RebuildStatsRequest {
+                nexus_uuid: self.uuid(),
+                uri: bdev.to_owned(),
+            })
+            .await
+            .map(|r| r.into_inner())
+    }
+
+    /// Waits until the given child reaches the given state.
+    pub async fn wait_child_state(
+        &self,
+        bdev: &str,
+        state: ChildState,
+        timeout: Duration,
+    ) -> Result<Child, Status> {
+        wait_until(
+            &format!("child '{}' to become {:?}", bdev, state),
+            timeout,
+            Duration::from_millis(100),
+            || async move {
+                let child = self.get_child(bdev).await?;
+                Ok(if child.state == state as i32 {
+                    Some(child)
+                } else {
+                    None
+                })
+            },
+        )
+        .await
+    }
+
+    pub async fn wait_replica_state(
+        &self,
+        r: &ReplicaBuilder,
+        state: ChildState,
+        timeout: Duration,
+    ) -> Result<Child, Status> {
+        self.wait_child_state(&self.replica_uri(r), state, timeout)
+            .await
+    }
+
     pub async fn wait_children_online(
         &self,
         timeout: Duration,
     ) -> Result<(), Status> {
-        let start = Instant::now();
-
-        loop {
-            let n = find_nexus_by_uuid(self.rpc(), &self.uuid()).await?;
-            if n.children
-                .iter()
-                .all(|c| c.state == ChildState::Online as i32)
-            {
-                return Ok(());
-            }
-
-            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-
-            if Instant::now() - start > timeout {
-                return Err(Status::new(
-                    Code::Cancelled,
-                    "Waiting for children to get online timed out",
-                ));
-            }
-        }
+        wait_until(
+            "children to get online",
+            timeout,
+            Duration::from_millis(100),
+            || async move {
+                let n = self.get_nexus().await?;
+                Ok(n.children
+                    .iter()
+                    .all(|c| c.state == ChildState::Online as i32)
+                    .then(|| ()))
+            },
+        )
+        .await
     }
 }
 
@@ -270,3 +517,37 @@ pub async fn test_write_to_nexus(
 ) -> std::io::Result<()> {
     test_write_to_nvmf(&nex.nvmf_location(), count, buf_size_mb).await
 }
+
+/// Creates a nexus on a single child, which is itself a nexus on the given
+/// device, so that the writes of the nexus can be failed by making the inner
+/// nexus read-only. A write which fails on the inner nexus is retried once,
+/// and does not retire it.
+pub async fn create_nexus_on_nexus(
+    inner: &str,
+    inner_size: u64,
+    name: &str,
+    size: u64,
+    device: &str,
+) {
+    nexus_create(inner, inner_size, None, &[device.to_string()])
+        .await
+        .unwrap();
+    nexus_create(name, size, None, &[format!("bdev:///{}", inner)])
+        .await
+        .unwrap();
+
+    let nexus = nexus_lookup_mut(name).unwrap();
+    let mut retire = nexus.retire_policy();
+    retire.keep_last_healthy = true;
+    retire.max_retries = 1;
+    nexus.set_retire_policy(retire).unwrap();
+}
+
+/// Makes the writes of the given nexus fail, or succeed again.
+pub async fn set_nexus_read_only(name: &str, read_only: bool) {
+    nexus_lookup_mut(name)
+        .unwrap()
+        .set_read_only(read_only)
+        .await
+        .unwrap();
+}
@@ -1,7 +1,13 @@
 pub use super::compose::rpc::v1::pool::Pool;
 use super::{
     compose::rpc::v1::{
-        pool::{CreatePoolRequest, ListPoolOptions},
+        pool::{
+            CreatePoolRequest,
+            DestroyPoolRequest,
+            ExportPoolRequest,
+            ImportPoolRequest,
+            ListPoolOptions,
+        },
         SharedRpcHandle,
         Status,
     },
@@ -81,6 +87,45 @@ impl PoolBuilder {
             .map(|r| r.into_inner())
     }
 
+    pub async fn destroy(&mut self) -> Result<(), Status> {
+        self.rpc()
+            .borrow_mut()
+            .pool
+            .destroy_pool(DestroyPoolRequest {
+                name: self.name(),
+                uuid: Some(self.uuid()),
+            })
+            .await
+            .map(|r| r.into_inner())
+    }
+
+    pub async fn export(&mut self) -> Result<(), Status> {
+        self.rpc()
+            .borrow_mut()
+            .pool
+            .export_pool(ExportPoolRequest {
+                name: self.name(),
+                uuid: Some(self.uuid()),
+            })
+            .await
+            .map(|r| r.into_inner())
+    }
+
+    /// Imports the pool from its bdev, after it was exported.
+    pub async fn import(&mut self) -> Result<Pool, Status> {
+        self.rpc()
+            .borrow_mut()
+            .pool
+            .import_pool(ImportPoolRequest {
+                name: self.name(),
+                uuid: Some(self.uuid()),
+                disks: vec![self.bdev()],
+                ..Default::default()
+            })
+            .await
+            .map(|r| r.into_inner())
+    }
+
     pub async fn get_pool(&self) -> Result<Pool, Status> {
         let uuid = self.uuid();
         list_pools(self.rpc())
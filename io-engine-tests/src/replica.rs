@@ -6,6 +6,7 @@ use super::{
             DestroyReplicaRequest,
             ListReplicaOptions,
             ShareReplicaRequest,
+            UnshareReplicaRequest,
         },
         SharedRpcHandle,
         Status,
@@ -167,6 +168,20 @@ impl ReplicaBuilder {
         Ok(r)
     }
 
+    pub async fn unshare(&mut self) -> Result<Replica, Status> {
+        let r = self
+            .rpc()
+            .borrow_mut()
+            .replica
+            .unshare_replica(UnshareReplicaRequest {
+                uuid: self.uuid(),
+            })
+            .await
+            .map(|r| r.into_inner())?;
+        self.shared_uri = None;
+        Ok(r)
+    }
+
     pub async fn get_replica(&self) -> Result<Replica, Status> {
         let uuid = self.uuid();
         list_replicas(self.rpc())
@@ -0,0 +1,520 @@
+//! Declarative failure scenarios.
+//!
+//! A scenario describes a topology, a number of io-engine containers with a
+//! pool each, replicas on some of them and a nexus of these replicas, along
+//! with the steps run against it: writes, fio jobs, faults injected into the
+//! children of the nexus, and the child states expected afterwards. Every
+//! change of the state of a child observed while the steps run is recorded
+//! as an event, so that a test can assert on the sequence of events once the
+//! scenario completes.
+//!
+//! Faults are injected through the nexus fault injection API, which requires
+//! the io-engine to be built with the `fault_injection` feature.
+
+use std::time::{Duration, Instant};
+
+use composer::{Binary, Builder, ComposeTest};
+
+use super::{
+    compose::rpc::v1::{
+        nexus::{
+            ChildState,
+            ChildStateReason,
+            InjectNexusFaultRequest,
+            RemoveInjectedNexusFaultRequest,
+        },
+        GrpcConnect,
+        SharedRpcHandle,
+    },
+    fio::{Fio, FioResult},
+    nexus::{find_nexus_by_uuid, test_write_to_nexus, NexusBuilder},
+    nvme::{find_mayastor_nvme_device_path, NmveConnectGuard},
+    pool::PoolBuilder,
+    replica::ReplicaBuilder,
+};
+
+/// A step of a scenario.
+#[derive(Debug, Clone)]
+pub enum Step {
+    /// Write the given number of buffers of the given size to the nexus.
+    Write {
+        count: usize,
+        buf_size_mb: usize,
+    },
+    /// Run a fio job against the nexus.
+    Fio(Fio),
+    /// Inject a fault into the child of the nexus of the given replica,
+    /// active from `begin` after the first I/O it applies to and until
+    /// `end`, or forever.
+    InjectFault {
+        replica: usize,
+        begin: Duration,
+        end: Option<Duration>,
+    },
+    /// Remove the faults injected into the child of the given replica.
+    RemoveFault {
+        replica: usize,
+    },
+    /// Wait for the child of the given replica to reach the given state.
+    ExpectChild {
+        replica: usize,
+        state: ChildState,
+        timeout: Duration,
+    },
+    /// Wait for all the children of the nexus to be online.
+    ExpectChildrenOnline {
+        timeout: Duration,
+    },
+    Sleep(Duration),
+}
+
+/// An event observed while a scenario runs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventKind {
+    /// The state of the child of the given replica changed.
+    ChildState {
+        replica: usize,
+        state: ChildState,
+        reason: ChildStateReason,
+    },
+    /// A fault was injected into the child of the given replica.
+    FaultInjected { replica: usize },
+    /// The faults injected into the child of the given replica were removed.
+    FaultRemoved { replica: usize },
+    /// A fio job completed successfully.
+    FioCompleted { name: String },
+    /// A fio job failed.
+    FioFailed { name: String },
+}
+
+/// An event, along with the step during which it was observed.
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub step: usize,
+    pub elapsed: Duration,
+    pub kind: EventKind,
+}
+
+/// Declarative description of a scenario.
+#[derive(Debug, Clone)]
+pub struct ScenarioBuilder {
+    name: String,
+    nodes: usize,
+    pool_size_mb: u64,
+    replicas: usize,
+    replica_size_mb: u64,
+    thin: bool,
+    nexus_node: usize,
+    steps: Vec<Step>,
+}
+
+impl ScenarioBuilder {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            nodes: 1,
+            pool_size_mb: 100,
+            replicas: 1,
+            replica_size_mb: 80,
+            thin: false,
+            nexus_node: 0,
+            steps: Vec::new(),
+        }
+    }
+
+    /// Number of io-engine containers, each with a pool.
+    pub fn with_nodes(mut self, nodes: usize) -> Self {
+        self.nodes = nodes;
+        self
+    }
+
+    pub fn with_pool_size_mb(mut self, size_mb: u64) -> Self {
+        self.pool_size_mb = size_mb;
+        self
+    }
+
+    /// Number of replicas of the nexus, one on each of the first nodes.
+    pub fn with_replicas(mut self, replicas: usize) -> Self {
+        self.replicas = replicas;
+        self
+    }
+
+    pub fn with_replica_size_mb(mut self, size_mb: u64) -> Self {
+        self.replica_size_mb = size_mb;
+        self
+    }
+
+    pub fn with_thin(mut self, thin: bool) -> Self {
+        self.thin = thin;
+        self
+    }
+
+    /// Node of the nexus.
+    pub fn with_nexus_node(mut self, node: usize) -> Self {
+        self.nexus_node = node;
+        self
+    }
+
+    pub fn with_step(mut self, step: Step) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    /// Starts the containers and creates the pools, the replicas and the
+    /// nexus, which is published over NVMf.
+    pub async fn build(self) -> Result<Scenario, String> {
+        assert!(self.replicas > 0 && self.replicas <= self.nodes);
+        assert!(self.nexus_node < self.nodes);
+
+        let mut builder = Builder::new()
+            .name(&self.name)
+            .network("10.1.0.0/16")
+            .map_err(|e| e.to_string())?;
+        for i in 0 .. self.nodes {
+            builder = builder.add_container_bin(
+                &node_name(i),
+                Binary::from_dbg("io-engine")
+                    .with_args(vec!["-l", &(i + 1).to_string()]),
+            );
+        }
+        let compose = builder
+            .with_clean(true)
+            .build()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let conn = GrpcConnect::new(&compose);
+        let mut nodes = Vec::new();
+        for i in 0 .. self.nodes {
+            nodes.push(conn.grpc_handle_shared(&node_name(i)).await?);
+        }
+
+        let mut pools = Vec::new();
+        for (i, node) in nodes.iter().enumerate() {
+            let mut pool = PoolBuilder::new(node.clone())
+                .with_name(&format!("pool{}", i))
+                .with_new_uuid()
+                .with_malloc(&format!("mem{}", i), self.pool_size_mb);
+            pool.create().await.map_err(|e| e.to_string())?;
+            pools.push(pool);
+        }
+
+        let mut replicas = Vec::new();
+        for (i, pool) in pools.iter().take(self.replicas).enumerate() {
+            let mut replica = ReplicaBuilder::new(pool.rpc())
+                .with_pool(pool)
+                .with_name(&format!("r{}", i))
+                .with_new_uuid()
+                .with_size_mb(self.replica_size_mb)
+                .with_thin(self.thin);
+            replica.create().await.map_err(|e| e.to_string())?;
+            replica.share().await.map_err(|e| e.to_string())?;
+            replicas.push(replica);
+        }
+
+        let mut nexus = NexusBuilder::new(nodes[self.nexus_node].clone())
+            .with_name("nexus0")
+            .with_new_uuid()
+            .with_size_mb(self.replica_size_mb);
+        for replica in &replicas {
+            nexus = nexus.with_replica(replica);
+        }
+        nexus.create().await.map_err(|e| e.to_string())?;
+        nexus.publish().await.map_err(|e| e.to_string())?;
+
+        Ok(Scenario {
+            compose,
+            nodes,
+            pools,
+            replicas,
+            nexus,
+            steps: self.steps,
+            events: Vec::new(),
+            child_states: Vec::new(),
+            fio_results: Vec::new(),
+        })
+    }
+}
+
+fn node_name(i: usize) -> String {
+    format!("ms_{}", i)
+}
+
+/// A scenario whose topology has been created.
+pub struct Scenario {
+    pub compose: ComposeTest,
+    pub nodes: Vec<SharedRpcHandle>,
+    pub pools: Vec<PoolBuilder>,
+    pub replicas: Vec<ReplicaBuilder>,
+    pub nexus: NexusBuilder,
+    steps: Vec<Step>,
+    events: Vec<Event>,
+    /// Last observed state of the child of every replica.
+    child_states: Vec<Option<(ChildState, ChildStateReason)>>,
+    fio_results: Vec<FioResult>,
+}
+
+impl Scenario {
+    /// Runs the steps of the scenario in order, failing on the first step
+    /// which fails.
+    pub async fn run(&mut self) -> Result<(), String> {
+        let start = Instant::now();
+        self.child_states = vec![None; self.replicas.len()];
+        self.observe(0, start).await?;
+
+        for (i, step) in self.steps.clone().into_iter().enumerate() {
+            self.run_step(i, step.clone(), start).await.map_err(|e| {
+                format!("step {} ({:?}) failed: {}", i, step, e)
+            })?;
+            self.observe(i, start).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns the events observed so far, in order.
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// Returns the results of the fio jobs which completed, in order.
+    pub fn fio_results(&self) -> &[FioResult] {
+        &self.fio_results
+    }
+
+    /// Asserts that the given events were observed in this order, other
+    /// events possibly being observed in between.
+    pub fn assert_events(&self, expected: &[EventKind]) {
+        let mut observed = self.events.iter().map(|e| &e.kind);
+        for kind in expected {
+            assert!(
+                observed.any(|k| k == kind),
+                "event {:?} not observed in order, events: {:#?}",
+                kind,
+                self.events
+            );
+        }
+    }
+
+    /// Asserts that no observed event matches the given predicate.
+    pub fn assert_no_event(&self, f: impl Fn(&EventKind) -> bool) {
+        if let Some(event) = self.events.iter().find(|e| f(&e.kind)) {
+            panic!("unexpected event {:?}", event);
+        }
+    }
+
+    async fn run_step(
+        &mut self,
+        step_idx: usize,
+        step: Step,
+        start: Instant,
+    ) -> Result<(), String> {
+        match step {
+            Step::Write {
+                count,
+                buf_size_mb,
+            } => test_write_to_nexus(&self.nexus, count, buf_size_mb)
+                .await
+                .map_err(|e| e.to_string()),
+            Step::Fio(fio) => {
+                let location = self.nexus.nvmf_location();
+                let result = {
+                    let _cg = NmveConnectGuard::connect_addr(
+                        &location.addr,
+                        &location.nqn,
+                    );
+                    let path = find_mayastor_nvme_device_path(&location.serial)
+                        .map_err(|e| e.to_string())?;
+                    fio.run(path.to_str().unwrap())
+                };
+                match result {
+                    Ok(result) => {
+                        self.push_event(
+                            step_idx,
+                            start,
+                            EventKind::FioCompleted {
+                                name: fio.name,
+                            },
+                        );
+                        self.fio_results.push(result);
+                        Ok(())
+                    }
+                    Err(error) => {
+                        self.push_event(
+                            step_idx,
+                            start,
+                            EventKind::FioFailed {
+                                name: fio.name,
+                            },
+                        );
+                        Err(error)
+                    }
+                }
+            }
+            Step::InjectFault {
+                replica,
+                begin,
+                end,
+            } => {
+                let mut uri = format!(
+                    "inject://{}?begin={}",
+                    self.child_device_name(replica).await?,
+                    begin.as_millis()
+                );
+                if let Some(end) = end {
+                    uri.push_str(&format!("&end={}", end.as_millis()));
+                }
+                self.nexus
+                    .rpc()
+                    .borrow_mut()
+                    .nexus
+                    .inject_nexus_fault(InjectNexusFaultRequest {
+                        uuid: self.nexus.uuid(),
+                        uri,
+                    })
+                    .await
+                    .map_err(|e| e.to_string())?;
+                self.push_event(
+                    step_idx,
+                    start,
+                    EventKind::FaultInjected {
+                        replica,
+                    },
+                );
+                Ok(())
+            }
+            Step::RemoveFault {
+                replica,
+            } => {
+                let uri = format!(
+                    "inject://{}",
+                    self.child_device_name(replica).await?
+                );
+                self.nexus
+                    .rpc()
+                    .borrow_mut()
+                    .nexus
+                    .remove_injected_nexus_fault(
+                        RemoveInjectedNexusFaultRequest {
+                            uuid: self.nexus.uuid(),
+                            uri,
+                        },
+                    )
+                    .await
+                    .map_err(|e| e.to_string())?;
+                self.push_event(
+                    step_idx,
+                    start,
+                    EventKind::FaultRemoved {
+                        replica,
+                    },
+                );
+                Ok(())
+            }
+            Step::ExpectChild {
+                replica,
+                state,
+                timeout,
+            } => {
+                let deadline = Instant::now() + timeout;
+                loop {
+                    self.observe(step_idx, start).await?;
+                    if matches!(self.child_states[replica], Some((s, _)) if s == state)
+                    {
+                        return Ok(());
+                    }
+                    if Instant::now() > deadline {
+                        return Err(format!(
+                            "child of replica {} is {:?} after {:?}, \
+                            expected {:?}",
+                            replica, self.child_states[replica], timeout, state
+                        ));
+                    }
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+            Step::ExpectChildrenOnline {
+                timeout,
+            } => self
+                .nexus
+                .wait_children_online(timeout)
+                .await
+                .map_err(|e| e.to_string()),
+            Step::Sleep(duration) => {
+                tokio::time::sleep(duration).await;
+                Ok(())
+            }
+        }
+    }
+
+    /// Records an event for every child whose state changed since the last
+    /// observation.
+    async fn observe(
+        &mut self,
+        step_idx: usize,
+        start: Instant,
+    ) -> Result<(), String> {
+        let nexus = find_nexus_by_uuid(self.nexus.rpc(), &self.nexus.uuid())
+            .await
+            .map_err(|e| e.to_string())?;
+
+        for replica in 0 .. self.replicas.len() {
+            let uri = self.child_uri(replica);
+            let child = match nexus.children.iter().find(|c| c.uri == uri) {
+                Some(child) => child,
+                None => continue,
+            };
+            let state = ChildState::from_i32(child.state)
+                .unwrap_or(ChildState::Unknown);
+            let reason = ChildStateReason::from_i32(child.state_reason)
+                .unwrap_or(ChildStateReason::None);
+            if self.child_states[replica] != Some((state, reason)) {
+                self.child_states[replica] = Some((state, reason));
+                self.push_event(
+                    step_idx,
+                    start,
+                    EventKind::ChildState {
+                        replica,
+                        state,
+                        reason,
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn push_event(&mut self, step: usize, start: Instant, kind: EventKind) {
+        self.events.push(Event {
+            step,
+            elapsed: start.elapsed(),
+            kind,
+        });
+    }
+
+    /// Returns the URI of the child of the nexus of the given replica.
+    fn child_uri(&self, replica: usize) -> String {
+        let replica = &self.replicas[replica];
+        if replica.rpc() == self.nexus.rpc() {
+            replica.bdev()
+        } else {
+            replica.shared_uri()
+        }
+    }
+
+    /// Returns the name of the block device of the child of the given
+    /// replica, which faults are injected into.
+    async fn child_device_name(
+        &self,
+        replica: usize,
+    ) -> Result<String, String> {
+        let uri = self.child_uri(replica);
+        find_nexus_by_uuid(self.nexus.rpc(), &self.nexus.uuid())
+            .await
+            .map_err(|e| e.to_string())?
+            .children
+            .into_iter()
+            .find(|c| c.uri == uri)
+            .and_then(|c| c.device_name)
+            .ok_or_else(|| format!("child '{}' has no block device", uri))
+    }
+}
@@ -0,0 +1,161 @@
+//! Backup of replica snapshots to S3-compatible object storage.
+//!
+//! A backup job streams the allocated clusters of a snapshot to the object
+//! store, so that the data can be kept off-cluster without an extra data
+//! mover. Each backup is stored under its own prefix as two objects:
+//!
+//! * `data`: the allocated ranges of the snapshot, back to back, uploaded as a
+//!   multipart upload;
+//! * `manifest.json`: the size of the snapshot and the location of each range
+//!   within the data object, written once the data object is complete.
+//!
+//! A backup can be incremental to a previous backup, whose snapshot is an
+//! ancestor of the snapshot to back up. Only the ranges allocated in the
+//! snapshots since that one are then uploaded, and the manifest refers to the
+//! previous backup, forming a chain down to a full backup.
+//!
+//! A restore job creates a new thin provisioned replica on a local pool and
+//! writes to it the ranges recorded in the manifests of the chain, starting
+//! with the full backup, which restores the point in time of the last backup
+//! of the chain. A restore job never writes to a replica it has not created
+//! itself, so that an existing replica is not overwritten.
+//!
+//! The progress of the jobs is kept in memory and, if enabled, in the
+//! persistent store, so that a failed job can be resumed where it stopped,
+//! also after a restart. The credentials of the object store are never
+//! recorded and must be given again when resuming a job.
+//!
+//! Jobs are managed with the `backup_*` json-rpc methods.
+
+use snafu::Snafu;
+
+use crate::{
+    core::CoreError,
+    jsonrpc::{Code, RpcErrorCode},
+};
+
+mod job;
+mod s3;
+
+pub use job::{
+    lookup_job,
+    restore,
+    resume,
+    BackupExtent,
+    BackupJob,
+    BackupManifest,
+    BackupRestoreArgs,
+    BackupResumeArgs,
+    JobKind,
+    JobState,
+};
+pub use s3::{S3Config, UploadedPart};
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)), context(suffix(false)))]
+pub enum BackupError {
+    #[snafu(display("Invalid S3 endpoint {}: {}", endpoint, reason))]
+    InvalidEndpoint { endpoint: String, reason: String },
+    #[snafu(display("S3 request {} {} failed: {}", method, key, source))]
+    Http {
+        source: hyper::Error,
+        method: String,
+        key: String,
+    },
+    #[snafu(display(
+        "S3 request {} {} failed with status {}: {}",
+        method,
+        key,
+        status,
+        message
+    ))]
+    S3Status {
+        method: String,
+        key: String,
+        status: u16,
+        message: String,
+    },
+    #[snafu(display(
+        "Invalid reply to S3 request {} {}: {}",
+        method,
+        key,
+        reason
+    ))]
+    S3Reply {
+        method: String,
+        key: String,
+        reason: String,
+    },
+    #[snafu(display("Invalid backup manifest {}: {}", key, source))]
+    Manifest {
+        source: serde_json::Error,
+        key: String,
+    },
+    #[snafu(display("Snapshot {} not found", name))]
+    SnapshotNotFound { name: String },
+    #[snafu(display("Replica {} is not a snapshot", name))]
+    NotSnapshot { name: String },
+    #[snafu(display("Snapshot {} does not descend from {}", snapshot, base))]
+    NotDescendant { snapshot: String, base: String },
+    #[snafu(display("Invalid chain of backups for {}: {}", id, reason))]
+    BackupChain { id: String, reason: String },
+    #[snafu(display("Pool {} not found", name))]
+    PoolNotFound { name: String },
+    #[snafu(display("Failed to create replica {}: {}", name, source))]
+    ReplicaCreate {
+        source: crate::lvs::Error,
+        name: String,
+    },
+    #[snafu(display("Replica {} already exists", name))]
+    ReplicaExists { name: String },
+    #[snafu(display("I/O failed on {}: {}", name, source))]
+    ReplicaIo { source: CoreError, name: String },
+    #[snafu(display("Backup job {} not found", id))]
+    JobNotFound { id: String },
+    #[snafu(display("Backup job {} already exists", id))]
+    JobExists { id: String },
+    #[snafu(display("Backup job {} is {:?}", id, state))]
+    JobState { id: String, state: JobState },
+    #[snafu(display("Backup job {} has been cancelled", id))]
+    JobCancelled { id: String },
+}
+
+impl RpcErrorCode for BackupError {
+    fn rpc_error_code(&self) -> Code {
+        match self {
+            BackupError::SnapshotNotFound {
+                ..
+            }
+            | BackupError::PoolNotFound {
+                ..
+            }
+            | BackupError::JobNotFound {
+                ..
+            } => Code::NotFound,
+            BackupError::JobExists {
+                ..
+            }
+            | BackupError::ReplicaExists {
+                ..
+            } => Code::AlreadyExists,
+            BackupError::InvalidEndpoint {
+                ..
+            }
+            | BackupError::NotSnapshot {
+                ..
+            }
+            | BackupError::NotDescendant {
+                ..
+            }
+            | BackupError::JobState {
+                ..
+            } => Code::InvalidParams,
+            _ => Code::InternalError,
+        }
+    }
+}
+
+/// Register the backup json-rpc methods.
+pub(crate) fn register_rpc_methods() {
+    job::register_rpc_methods();
+}
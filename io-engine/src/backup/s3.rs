@@ -0,0 +1,447 @@
+//! Minimal client for S3-compatible object stores.
+//!
+//! Only the requests needed to store and retrieve backups are implemented:
+//! single object put and (ranged) get, along with multipart uploads. Requests
+//! are signed with AWS signature version 4 and use path-style addressing,
+//! which is supported by all S3-compatible stores. Requests run on the tokio
+//! runtime, the results are sent back to the calling reactor.
+//!
+//! Endpoints are reached over https, plain http is only allowed to a loopback
+//! endpoint, or when explicitly asked for.
+
+use std::net::IpAddr;
+
+use futures::channel::oneshot;
+use hmac::{Hmac, Mac};
+use hyper::{client::HttpConnector, Body, Client, Method, Request};
+use hyper_rustls::HttpsConnector;
+use sha2::{Digest, Sha256};
+use snafu::ResultExt;
+
+use super::{BackupError, Http, InvalidEndpoint, S3Reply, S3Status};
+use crate::core::{runtime, Reactor};
+
+/// Service name used to sign the requests.
+const SERVICE: &str = "s3";
+
+/// Location of the backups, along with the credentials to access them.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct S3Config {
+    /// Endpoint of the object store, e.g. https://minio.backup:9000.
+    pub endpoint: String,
+    /// Allow a plain http endpoint which is not a loopback one, the data of
+    /// the backups then go unencrypted over the network.
+    #[serde(default)]
+    pub insecure: bool,
+    #[serde(default = "default_region")]
+    pub region: String,
+    pub bucket: String,
+    /// Prefix of the keys of the backup objects within the bucket.
+    #[serde(default)]
+    pub prefix: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+impl std::fmt::Debug for S3Config {
+    /// The credentials are left out, as the config ends up in the logs.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3Config")
+            .field("endpoint", &self.endpoint)
+            .field("region", &self.region)
+            .field("bucket", &self.bucket)
+            .field("prefix", &self.prefix)
+            .field("insecure", &self.insecure)
+            .finish()
+    }
+}
+
+/// Part of a multipart upload which has been uploaded.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UploadedPart {
+    pub number: u32,
+    pub etag: String,
+}
+
+/// S3 client.
+#[derive(Clone)]
+pub(crate) struct S3Client {
+    config: S3Config,
+    /// Host and port of the endpoint, as signed.
+    host: String,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+/// Returns true if the given host is a loopback one.
+fn is_loopback(host: &str) -> bool {
+    host == "localhost"
+        || host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .map_or(false, |ip| ip.is_loopback())
+}
+
+impl S3Client {
+    /// Returns a new client. Plain http endpoints are refused unless they are
+    /// loopback ones or the config is explicitly insecure.
+    pub(crate) fn new(config: S3Config) -> Result<Self, BackupError> {
+        let uri = config.endpoint.parse::<http::Uri>().map_err(|e| {
+            BackupError::InvalidEndpoint {
+                endpoint: config.endpoint.clone(),
+                reason: e.to_string(),
+            }
+        })?;
+
+        match uri.scheme_str() {
+            Some("https") => {}
+            Some("http")
+                if config.insecure || uri.host().map_or(false, is_loopback) => {
+            }
+            Some("http") => {
+                return InvalidEndpoint {
+                    endpoint: config.endpoint.clone(),
+                    reason:
+                        "plain http is only allowed to a loopback endpoint \
+                        unless the config is insecure",
+                }
+                .fail();
+            }
+            _ => {
+                return InvalidEndpoint {
+                    endpoint: config.endpoint.clone(),
+                    reason: "only https and http endpoints are supported",
+                }
+                .fail();
+            }
+        }
+
+        let host = match uri.authority() {
+            Some(authority) => authority.to_string(),
+            None => {
+                return InvalidEndpoint {
+                    endpoint: config.endpoint.clone(),
+                    reason: "no host",
+                }
+                .fail()
+            }
+        };
+
+        Ok(Self {
+            config,
+            host,
+            client: Client::builder()
+                .build(HttpsConnector::with_native_roots()),
+        })
+    }
+
+    /// Returns the key of the given object of a backup.
+    pub(crate) fn key(&self, backup: &str, object: &str) -> String {
+        let prefix = self.config.prefix.trim_matches('/');
+        if prefix.is_empty() {
+            format!("{}/{}", backup, object)
+        } else {
+            format!("{}/{}/{}", prefix, backup, object)
+        }
+    }
+
+    /// Store an object.
+    pub(crate) async fn put_object(
+        &self,
+        key: &str,
+        data: Vec<u8>,
+    ) -> Result<(), BackupError> {
+        self.execute(Method::PUT, key, &[], None, data)
+            .await
+            .map(|_| ())
+    }
+
+    /// Retrieve an object, or the given range of bytes of it.
+    pub(crate) async fn get_object(
+        &self,
+        key: &str,
+        range: Option<(u64, u64)>,
+    ) -> Result<Vec<u8>, BackupError> {
+        let range = range.map(|(offset, len)| {
+            format!("bytes={}-{}", offset, offset + len - 1)
+        });
+        self.execute(Method::GET, key, &[], range, Vec::new())
+            .await
+            .map(|(_, body)| body)
+    }
+
+    /// Start a multipart upload and returns its id.
+    pub(crate) async fn create_multipart_upload(
+        &self,
+        key: &str,
+    ) -> Result<String, BackupError> {
+        let (_, body) = self
+            .execute(Method::POST, key, &[("uploads", "")], None, Vec::new())
+            .await?;
+        xml_element(&body, "UploadId").ok_or_else(|| BackupError::S3Reply {
+            method: "POST".to_string(),
+            key: key.to_string(),
+            reason: "no UploadId".to_string(),
+        })
+    }
+
+    /// Upload a part of a multipart upload.
+    pub(crate) async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        number: u32,
+        data: Vec<u8>,
+    ) -> Result<UploadedPart, BackupError> {
+        let number_str = number.to_string();
+        let (etag, _) = self
+            .execute(
+                Method::PUT,
+                key,
+                &[("partNumber", &number_str), ("uploadId", upload_id)],
+                None,
+                data,
+            )
+            .await?;
+        let etag = etag.ok_or_else(|| BackupError::S3Reply {
+            method: "PUT".to_string(),
+            key: key.to_string(),
+            reason: "no ETag".to_string(),
+        })?;
+        Ok(UploadedPart {
+            number,
+            etag,
+        })
+    }
+
+    /// Complete a multipart upload with the given parts.
+    pub(crate) async fn complete_multipart_upload(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[UploadedPart],
+    ) -> Result<(), BackupError> {
+        let mut parts = parts.to_vec();
+        parts.sort_by_key(|p| p.number);
+        let mut body = String::from("<CompleteMultipartUpload>");
+        for part in parts {
+            body.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                part.number, part.etag
+            ));
+        }
+        body.push_str("</CompleteMultipartUpload>");
+
+        self.execute(
+            Method::POST,
+            key,
+            &[("uploadId", upload_id)],
+            None,
+            body.into_bytes(),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Abort a multipart upload, discarding the parts uploaded so far.
+    pub(crate) async fn abort_multipart_upload(
+        &self,
+        key: &str,
+        upload_id: &str,
+    ) -> Result<(), BackupError> {
+        self.execute(
+            Method::DELETE,
+            key,
+            &[("uploadId", upload_id)],
+            None,
+            Vec::new(),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Execute a request on the tokio runtime and returns the ETag and the
+    /// body of the reply.
+    async fn execute(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        range: Option<String>,
+        body: Vec<u8>,
+    ) -> Result<(Option<String>, Vec<u8>), BackupError> {
+        let request = self.sign(method.clone(), key, query, range, body);
+        let client = self.client.clone();
+        let key = key.to_string();
+        let (s, r) = oneshot::channel();
+
+        runtime::spawn(async move {
+            let result = async {
+                let reply = client.request(request).await.context(Http {
+                    method: method.to_string(),
+                    key: key.clone(),
+                })?;
+                let status = reply.status();
+                let etag = reply
+                    .headers()
+                    .get(hyper::header::ETAG)
+                    .and_then(|v| v.to_str().ok())
+                    .map(String::from);
+                let body = hyper::body::to_bytes(reply.into_body())
+                    .await
+                    .context(Http {
+                        method: method.to_string(),
+                        key: key.clone(),
+                    })?;
+
+                if !status.is_success() {
+                    return S3Status {
+                        method: method.to_string(),
+                        key,
+                        status: status.as_u16(),
+                        message: xml_element(&body, "Message").unwrap_or_else(
+                            || String::from_utf8_lossy(&body).to_string(),
+                        ),
+                    }
+                    .fail();
+                }
+                Ok((etag, body.to_vec()))
+            }
+            .await;
+
+            // complete the request on the primary reactor, which drives the
+            // backup jobs
+            Reactor::spawn_at_primary(async move { s.send(result).ok() })
+                .expect("Failed to send future to Mayastor thread");
+        });
+
+        r.await.expect("S3 request sender is gone")
+    }
+
+    /// Build a request signed with AWS signature version 4.
+    fn sign(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        range: Option<String>,
+        body: Vec<u8>,
+    ) -> Request<Body> {
+        let now = chrono::Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex::encode(Sha256::digest(&body));
+
+        let path = format!(
+            "/{}/{}",
+            uri_encode(&self.config.bucket, false),
+            uri_encode(key, true)
+        );
+        let mut query = query
+            .iter()
+            .map(|(k, v)| (uri_encode(k, false), uri_encode(v, false)))
+            .collect::<Vec<_>>();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method,
+            path,
+            query,
+            self.host,
+            payload_hash,
+            amz_date,
+            signed_headers,
+            payload_hash
+        );
+
+        let scope =
+            format!("{}/{}/{}/aws4_request", date, self.config.region, SERVICE);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let key = format!("AWS4{}", self.config.secret_access_key);
+        let key = hmac_sha256(key.as_bytes(), date.as_bytes());
+        let key = hmac_sha256(&key, self.config.region.as_bytes());
+        let key = hmac_sha256(&key, SERVICE.as_bytes());
+        let key = hmac_sha256(&key, b"aws4_request");
+        let signature =
+            hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()));
+
+        let uri = if query.is_empty() {
+            format!("{}{}", self.config.endpoint.trim_end_matches('/'), path)
+        } else {
+            format!(
+                "{}{}?{}",
+                self.config.endpoint.trim_end_matches('/'),
+                path,
+                query
+            )
+        };
+
+        let mut builder = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(hyper::header::HOST, &self.host)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header(
+                hyper::header::AUTHORIZATION,
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                    self.config.access_key_id, scope, signed_headers, signature
+                ),
+            );
+        if let Some(range) = range {
+            builder = builder.header(hyper::header::RANGE, range);
+        }
+        builder
+            .body(Body::from(body))
+            .expect("Failed to build the S3 request")
+    }
+}
+
+/// HMAC-SHA256 of the given data.
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key)
+        .expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// URI encode the given string as required by the signature, the slashes
+/// are kept when encoding a path.
+fn uri_encode(s: &str, path: bool) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A' ..= b'Z' | b'a' ..= b'z' | b'0' ..= b'9' => {
+                (b as char).to_string()
+            }
+            b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            b'/' if path => "/".to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Returns the text of the first given element of an XML document.
+fn xml_element(xml: &[u8], name: &str) -> Option<String> {
+    let xml = String::from_utf8_lossy(xml);
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + xml[start ..].find(&format!("</{}>", name))?;
+    Some(xml[start .. end].to_string())
+}
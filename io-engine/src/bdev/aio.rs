@@ -3,6 +3,7 @@ use std::{
     convert::TryFrom,
     ffi::CString,
     fmt::{Debug, Formatter},
+    path::Path,
 };
 
 use async_trait::async_trait;
@@ -18,6 +19,7 @@ use crate::{
     bdev_api::{self, BdevError},
     core::{UntypedBdev, VerboseError},
     ffihelper::{cb_arg, done_errno_cb, ErrnoResult},
+    host::claims::device_claims,
 };
 
 pub(super) struct Aio {
@@ -25,6 +27,8 @@ pub(super) struct Aio {
     alias: String,
     blk_size: u32,
     uuid: Option<uuid::Uuid>,
+    /// Use the device even if the kernel uses it.
+    force: bool,
 }
 
 impl Debug for Aio {
@@ -50,16 +54,7 @@ impl TryFrom<&Url> for Aio {
         let mut parameters: HashMap<String, String> =
             url.query_pairs().into_owned().collect();
 
-        let blk_size: u32 = match parameters.remove("blk_size") {
-            Some(value) => {
-                value.parse().context(bdev_api::IntParamParseFailed {
-                    uri: url.to_string(),
-                    parameter: String::from("blk_size"),
-                    value: value.clone(),
-                })?
-            }
-            None => 512,
-        };
+        let blk_size = uri::block_size(url, parameters.remove("blk_size"))?;
 
         let uuid = uri::uuid(parameters.remove("uuid")).context(
             bdev_api::UuidParamParseFailed {
@@ -67,6 +62,8 @@ impl TryFrom<&Url> for Aio {
             },
         )?;
 
+        let force = uri::force(url, parameters.remove("force"))?;
+
         reject_unknown_parameters(url, parameters)?;
 
         Ok(Aio {
@@ -74,10 +71,33 @@ impl TryFrom<&Url> for Aio {
             alias: url.to_string(),
             blk_size,
             uuid,
+            force,
         })
     }
 }
 
+/// Refuse a block device of the host which the kernel uses, as it would be
+/// destroyed, unless forced to.
+pub(super) fn refuse_claimed(path: &str, force: bool) -> Result<(), BdevError> {
+    let claims = device_claims(Path::new(path));
+    if claims.is_empty() {
+        return Ok(());
+    }
+    let claims = claims
+        .iter()
+        .map(|c| c.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    if force {
+        warn!("Using device {} although it is in use: {}", path, claims);
+        return Ok(());
+    }
+    Err(BdevError::DeviceClaimed {
+        path: path.to_string(),
+        claims,
+    })
+}
+
 impl GetName for Aio {
     fn get_name(&self) -> String {
         self.name.clone()
@@ -96,6 +116,8 @@ impl CreateDestroy for Aio {
             });
         }
 
+        refuse_claimed(&self.name, self.force)?;
+
         debug!("{:?}: creating bdev", self);
 
         let cname = CString::new(self.get_name()).unwrap();
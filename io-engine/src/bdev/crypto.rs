@@ -0,0 +1,371 @@
+//! Encrypted bdevs.
+//!
+//! The crypto bdev of SPDK encrypts the blocks written to another bdev, its
+//! base bdev, and decrypts the blocks read from it, with a data encryption
+//! key (DEK). A nexus child given as a crypto URI keeps its replica
+//! encrypted: the data leaves the nexus encrypted, and the replica only ever
+//! holds the encrypted blocks. The base bdev is given by its own URI, e.g.:
+//!
+//! ```ignore
+//!     crypto:///vol1-r1?base=nvmf%3A%2F%2F10.0.0.2%3A8420%2Fnqn...&dek=file:/keys/vol1
+//! ```
+//!
+//! The DEK is given as a secret reference, see `core::secret`, to a key
+//! encoded in hex: 16 bytes for AES_CBC, the default cipher, and a second
+//! key, `dek2`, of 16 bytes as well for AES_XTS. The DEK of an encrypted
+//! child is rotated in place, see `nexus_rekey`: as the crypto bdev claims
+//! its base bdev, the child is replaced with one encrypted with the new key
+//! over the same base bdev, whose blocks are encrypted again as it is
+//! rebuilt.
+
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    ffi::CString,
+    fmt::{Debug, Formatter},
+    os::raw::c_char,
+};
+
+use async_trait::async_trait;
+use futures::channel::oneshot;
+use nix::errno::Errno;
+use snafu::ResultExt;
+use url::Url;
+
+use spdk_rs::libspdk::{
+    create_crypto_disk,
+    delete_crypto_disk,
+    vbdev_crypto_opts,
+};
+
+use crate::{
+    bdev::{
+        dev::{reject_unknown_parameters, uri as device_uri},
+        util::uri,
+        CreateDestroy,
+        GetName,
+    },
+    bdev_api::{self, BdevError},
+    core::{
+        secret::{resolve_secret, Secret},
+        UntypedBdev,
+        VerboseError,
+    },
+    ffihelper::{cb_arg, done_errno_cb, ErrnoResult},
+};
+
+/// Ciphers of the crypto bdev.
+const AES_CBC: &str = "AES_CBC";
+const AES_XTS: &str = "AES_XTS";
+
+/// Crypto driver used when none is given.
+const DEFAULT_DRIVER: &str = "crypto_aesni_mb";
+
+/// Size of the keys of the ciphers, in bytes.
+const KEY_SIZE: usize = 16;
+
+pub(super) struct Crypto {
+    /// Name of the bdev, which is the URI path.
+    name: String,
+    alias: String,
+    /// URI of the base bdev.
+    base: String,
+    driver: String,
+    cipher: String,
+    key: Secret,
+    key2: Option<Secret>,
+    uuid: Option<uuid::Uuid>,
+}
+
+impl Debug for Crypto {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Crypto '{}' ({})", self.name, self.cipher)
+    }
+}
+
+/// Returns the URI of the base bdev of the given URI of an encrypted bdev,
+/// None if it is not one.
+pub(crate) fn base_uri(uri: &str) -> Option<String> {
+    let url = Url::parse(uri).ok()?;
+    if url.scheme() != "crypto" {
+        return None;
+    }
+    url.query_pairs()
+        .find(|(k, _)| k == "base")
+        .map(|(_, v)| v.into_owned())
+}
+
+/// Resolve the key of the given parameter, a secret reference to a key
+/// encoded in hex.
+fn resolve_key(
+    url: &Url,
+    parameter: &str,
+    reference: &str,
+) -> Result<Secret, BdevError> {
+    let invalid = |message: String| BdevError::InvalidUri {
+        uri: url.to_string(),
+        message: format!("'{}': {}", parameter, message),
+    };
+    let key = resolve_secret(reference).map_err(|e| invalid(e.to_string()))?;
+    match hex::decode(key.expose()) {
+        Ok(bytes) if bytes.len() == KEY_SIZE => Ok(key),
+        _ => Err(invalid(format!(
+            "the key must be {} bytes encoded in hex",
+            KEY_SIZE
+        ))),
+    }
+}
+
+/// Convert a URI to a Crypto "object"
+impl TryFrom<&Url> for Crypto {
+    type Error = BdevError;
+
+    fn try_from(url: &Url) -> Result<Self, Self::Error> {
+        if uri::segments(url).is_empty() {
+            return Err(BdevError::InvalidUri {
+                uri: url.to_string(),
+                message: String::from("no path segments"),
+            });
+        }
+
+        let mut parameters: HashMap<String, String> =
+            url.query_pairs().into_owned().collect();
+
+        let base =
+            parameters
+                .remove("base")
+                .ok_or_else(|| BdevError::InvalidUri {
+                    uri: url.to_string(),
+                    message: String::from("no base URI"),
+                })?;
+        // the base URI must be one of a bdev this io-engine can create
+        device_uri::parse(&base)?;
+
+        let cipher = parameters
+            .remove("cipher")
+            .unwrap_or_else(|| AES_CBC.to_string());
+        if cipher != AES_CBC && cipher != AES_XTS {
+            return Err(BdevError::InvalidUri {
+                uri: url.to_string(),
+                message: format!(
+                    "'cipher' must be one of: {}, {}",
+                    AES_CBC, AES_XTS
+                ),
+            });
+        }
+
+        let key = match parameters.remove("dek") {
+            Some(reference) => resolve_key(url, "dek", &reference)?,
+            None => {
+                return Err(BdevError::InvalidUri {
+                    uri: url.to_string(),
+                    message: String::from("no data encryption key"),
+                })
+            }
+        };
+        let key2 = parameters
+            .remove("dek2")
+            .map(|reference| resolve_key(url, "dek2", &reference))
+            .transpose()?;
+        if (cipher == AES_XTS) != key2.is_some() {
+            return Err(BdevError::InvalidUri {
+                uri: url.to_string(),
+                message: format!(
+                    "'dek2' is required by {}, and only by it",
+                    AES_XTS
+                ),
+            });
+        }
+
+        let driver = parameters
+            .remove("driver")
+            .unwrap_or_else(|| DEFAULT_DRIVER.to_string());
+
+        let uuid = uri::uuid(parameters.remove("uuid")).context(
+            bdev_api::UuidParamParseFailed {
+                uri: url.to_string(),
+            },
+        )?;
+
+        reject_unknown_parameters(url, parameters)?;
+
+        Ok(Crypto {
+            name: url.path()[1 ..].into(),
+            alias: url.to_string(),
+            base,
+            driver,
+            cipher,
+            key,
+            key2,
+            uuid,
+        })
+    }
+}
+
+impl GetName for Crypto {
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+/// Returns a copy of the given string allocated by libc, as SPDK frees the
+/// options of a crypto bdev once it is deleted.
+fn libc_str(s: &str) -> *mut c_char {
+    let s = CString::new(s).unwrap();
+    unsafe { libc::strdup(s.as_ptr()) }
+}
+
+/// Returns a copy of the given keys, one after the other, allocated by
+/// libc.
+fn libc_key(keys: &[&Secret]) -> *mut u8 {
+    let bytes = keys
+        .iter()
+        .flat_map(|key| hex::decode(key.expose()).unwrap_or_default())
+        .collect::<Vec<u8>>();
+    unsafe {
+        let ptr = libc::malloc(bytes.len()) as *mut u8;
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len());
+        ptr
+    }
+}
+
+impl Crypto {
+    /// Returns the options of the crypto bdev over the given base bdev,
+    /// which SPDK takes ownership of.
+    fn opts(&self, base_name: &str) -> *mut vbdev_crypto_opts {
+        unsafe {
+            let opts = libc::calloc(1, std::mem::size_of::<vbdev_crypto_opts>())
+                as *mut vbdev_crypto_opts;
+            (*opts).vbdev_name = libc_str(&self.name);
+            (*opts).bdev_name = libc_str(base_name);
+            (*opts).drv_name = libc_str(&self.driver);
+            (*opts).cipher = libc_str(&self.cipher);
+            (*opts).key = libc_key(&[&self.key]);
+            (*opts).key_size = KEY_SIZE as u8;
+            if let Some(key2) = &self.key2 {
+                (*opts).key2 = libc_key(&[key2]);
+                (*opts).key2_size = KEY_SIZE as u8;
+                // AES_XTS is given both keys, the second following the first
+                (*opts).xts_key = libc_key(&[&self.key, key2]);
+            }
+            opts
+        }
+    }
+
+    /// Free the options of a crypto bdev which SPDK failed to create.
+    fn free_opts(opts: *mut vbdev_crypto_opts) {
+        unsafe {
+            libc::free((*opts).vbdev_name as *mut libc::c_void);
+            libc::free((*opts).bdev_name as *mut libc::c_void);
+            libc::free((*opts).drv_name as *mut libc::c_void);
+            libc::free((*opts).cipher as *mut libc::c_void);
+            let xts_size = (*opts).key_size + (*opts).key2_size;
+            for &(key, size) in &[
+                ((*opts).key, (*opts).key_size),
+                ((*opts).key2, (*opts).key2_size),
+                ((*opts).xts_key, xts_size),
+            ] {
+                if !key.is_null() {
+                    std::ptr::write_bytes(key, 0, size as usize);
+                    libc::free(key as *mut libc::c_void);
+                }
+            }
+            libc::free(opts as *mut libc::c_void);
+        }
+    }
+
+    /// Destroy the base bdev.
+    async fn destroy_base(&self) {
+        let result = match device_uri::parse(&self.base) {
+            Ok(base) => base.destroy().await,
+            Err(error) => Err(error),
+        };
+        if let Err(error) = result {
+            warn!(
+                "{:?}: failed to destroy the base bdev: {}",
+                self,
+                error.verbose()
+            );
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl CreateDestroy for Crypto {
+    type Error = BdevError;
+
+    /// Create a crypto bdev, and its base bdev
+    async fn create(&self) -> Result<String, Self::Error> {
+        if UntypedBdev::lookup_by_name(&self.name).is_some() {
+            return Err(BdevError::BdevExists {
+                name: self.get_name(),
+            });
+        }
+
+        debug!("{:?}: creating bdev", self);
+
+        let base_name = device_uri::parse(&self.base)?.create().await?;
+
+        let opts = self.opts(&base_name);
+        let errno = unsafe { create_crypto_disk(opts) };
+        if errno != 0 {
+            Self::free_opts(opts);
+            let err = BdevError::CreateBdevFailed {
+                source: Errno::from_i32(errno.abs()),
+                name: self.get_name(),
+            };
+            error!("{:?} error: {}", self, err.verbose());
+            self.destroy_base().await;
+            return Err(err);
+        }
+
+        if let Some(mut bdev) = UntypedBdev::lookup_by_name(&self.name) {
+            if let Some(uuid) = self.uuid {
+                unsafe { bdev.set_raw_uuid(uuid.into()) };
+            }
+
+            if !bdev.add_alias(&self.alias) {
+                warn!("{:?}: failed to add alias '{}'", self, self.alias);
+            }
+
+            return Ok(self.get_name());
+        }
+
+        Err(BdevError::BdevNotFound {
+            name: self.get_name(),
+        })
+    }
+
+    /// Destroy the given crypto bdev, and its base bdev
+    async fn destroy(self: Box<Self>) -> Result<(), Self::Error> {
+        debug!("{:?}: deleting", self);
+
+        match UntypedBdev::lookup_by_name(&self.name) {
+            Some(mut bdev) => {
+                bdev.remove_alias(&self.alias);
+                let cname = CString::new(self.get_name()).unwrap();
+                let (sender, receiver) = oneshot::channel::<ErrnoResult<()>>();
+                unsafe {
+                    delete_crypto_disk(
+                        cname.as_ptr(),
+                        Some(done_errno_cb),
+                        cb_arg(sender),
+                    );
+                }
+                receiver
+                    .await
+                    .context(bdev_api::BdevCommandCanceled {
+                        name: self.get_name(),
+                    })?
+                    .context(bdev_api::DestroyBdevFailed {
+                        name: self.get_name(),
+                    })?;
+                self.destroy_base().await;
+                Ok(())
+            }
+            None => Err(BdevError::BdevNotFound {
+                name: self.get_name(),
+            }),
+        }
+    }
+}
@@ -39,15 +39,22 @@ pub(crate) mod uri {
     use crate::{
         bdev::{
             aio,
+            crypto,
             loopback,
             malloc,
             null_bdev,
             nvme,
             nvmx,
+            pmem,
+            raid,
             uring,
             BdevCreateDestroy,
         },
         bdev_api::{self, BdevError},
+        core::{
+            secret::{has_inline_credentials, redact_uri},
+            MayastorEnvironment,
+        },
     };
 
     pub fn parse(
@@ -57,14 +64,41 @@ pub(crate) mod uri {
             uri: uri.to_string(),
         })?;
 
+        // credentials are only accepted as secret references, so that the
+        // URI never shows them
+        if has_inline_credentials(&url) {
+            return Err(BdevError::InvalidUri {
+                uri: redact_uri(uri),
+                message: "credentials must be given as a secret reference"
+                    .to_string(),
+            });
+        }
+
+        // in simulation mode there are no hugepages to do I/O to real
+        // devices with, only to memory
+        if MayastorEnvironment::global_or_default().simulate()
+            && !matches!(url.scheme(), "bdev" | "loopback" | "malloc" | "null")
+        {
+            return Err(BdevError::InvalidUri {
+                uri: uri.to_string(),
+                message: format!(
+                    "scheme '{}' is not supported in simulation mode",
+                    url.scheme()
+                ),
+            });
+        }
+
         match url.scheme() {
             "aio" => Ok(Box::new(aio::Aio::try_from(&url)?)),
             "bdev" => Ok(Box::new(loopback::Loopback::try_from(&url)?)),
+            "crypto" => Ok(Box::new(crypto::Crypto::try_from(&url)?)),
             "loopback" => Ok(Box::new(loopback::Loopback::try_from(&url)?)),
             "malloc" => Ok(Box::new(malloc::Malloc::try_from(&url)?)),
             "null" => Ok(Box::new(null_bdev::Null::try_from(&url)?)),
             "nvmf" => Ok(Box::new(nvmx::NvmfDeviceTemplate::try_from(&url)?)),
             "pcie" => Ok(Box::new(nvme::NVMe::try_from(&url)?)),
+            "pmem" => Ok(Box::new(pmem::Pmem::try_from(&url)?)),
+            "raid0" | "raid5" => Ok(Box::new(raid::Raid::try_from(&url)?)),
             "uring" => Ok(Box::new(uring::Uring::try_from(&url)?)),
 
             scheme => Err(BdevError::UriSchemeUnsupported {
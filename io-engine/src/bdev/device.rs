@@ -16,6 +16,7 @@ use spdk_rs::{
     libspdk::{
         iovec,
         spdk_bdev_free_io,
+        spdk_bdev_get_md_size,
         spdk_bdev_io,
         spdk_bdev_readv_blocks,
         spdk_bdev_reset,
@@ -129,6 +130,10 @@ impl BlockDevice for SpdkBlockDevice {
     fn io_type_supported(&self, io_type: IoType) -> bool {
         self.0.io_type_supported(io_type)
     }
+    /// returns the size of the metadata of a block
+    fn md_size(&self) -> u64 {
+        unsafe { spdk_bdev_get_md_size(self.0.unsafe_inner_ptr()) as u64 }
+    }
     /// returns the IO statistics
     async fn io_stats(&self) -> Result<BlockDeviceIoStats, CoreError> {
         self.0.stats_async().await
@@ -11,6 +11,7 @@ pub use nvmx::{
 };
 
 mod aio;
+pub(crate) mod crypto;
 pub(crate) mod dev;
 use crate::core::{MayastorEnvironment, PtplProps};
 pub(crate) use dev::uri;
@@ -21,9 +22,11 @@ mod malloc;
 pub mod nexus;
 mod null_bdev;
 pub mod null_ng;
-mod nvme;
+pub(crate) mod nvme;
 mod nvmf;
 pub(crate) mod nvmx;
+pub(crate) mod pmem;
+pub(crate) mod raid;
 mod uring;
 pub mod util;
 
@@ -4,26 +4,63 @@ use crate::core::VerboseError;
 use futures::{future::Future, FutureExt};
 use std::pin::Pin;
 
+mod nexus_admission;
 mod nexus_bdev;
 mod nexus_bdev_children;
 mod nexus_bdev_error;
 mod nexus_bdev_rebuild;
 mod nexus_bdev_snapshot;
+mod nexus_block_adapter;
 mod nexus_channel;
+mod nexus_checksum;
 mod nexus_child;
+mod nexus_child_grace;
+mod nexus_child_intent;
+mod nexus_child_undo;
+mod nexus_consumer;
+mod nexus_error_log;
+mod nexus_fault;
+mod nexus_flight_recorder;
+mod nexus_group;
+mod nexus_health_log;
 mod nexus_injection;
 mod nexus_io;
+mod nexus_io_deadline;
 mod nexus_io_subsystem;
+mod nexus_io_trace;
 mod nexus_iter;
+mod nexus_latency_slo;
+mod nexus_lazy;
+mod nexus_lease;
+mod nexus_local;
+mod nexus_metadata;
+mod nexus_migrate;
 mod nexus_module;
 mod nexus_nbd;
 mod nexus_persistence;
+mod nexus_read_routing;
+mod nexus_readahead;
+mod nexus_rebuild_source;
+mod nexus_rekey;
+mod nexus_retire_policy;
+mod nexus_seed;
+mod nexus_shard;
 mod nexus_share;
+mod nexus_state;
+#[cfg(feature = "ublk")]
+mod nexus_ublk;
+mod nexus_validate;
+mod nexus_write_ack;
+mod nexus_write_generation;
+mod nexus_write_intent;
+mod nexus_write_merge;
 
 use crate::bdev::nexus::nexus_iter::NexusIterMut;
+pub use nexus_admission::{AdmissionPolicy, AdmissionStats};
 pub(crate) use nexus_bdev::NEXUS_PRODUCT_ID;
 pub use nexus_bdev::{
     nexus_create,
+    nexus_create_lazy,
     nexus_create_v2,
     Nexus,
     NexusNvmeParams,
@@ -36,10 +73,20 @@ pub use nexus_bdev::{
     NvmeReservation,
 };
 pub(crate) use nexus_bdev_error::{nexus_err, Error};
+pub use nexus_block_adapter::RmwStats;
+pub use nexus_channel::ChannelStats;
 pub(crate) use nexus_channel::{DrEvent, NexusChannel};
+pub use nexus_checksum::{ChecksumAlgo, ChecksumStats};
 pub use nexus_child::{ChildError, ChildState, NexusChild, Reason};
+pub use nexus_child_intent::{ChildIntent, ChildIntentPhase};
+pub use nexus_error_log::ErrorLogEntry;
+pub(crate) use nexus_error_log::VENDOR_ERROR_LOG_LID;
+pub use nexus_fault::{AdminAction, FaultDetail, IoErrorKind};
+pub use nexus_flight_recorder::{ChildIoRecord, IoRecord};
+pub(crate) use nexus_health_log::health_log_page;
 use nexus_io::{NexusBio, NioCtx};
 use nexus_io_subsystem::{NexusIoSubsystem, NexusPauseState};
+pub use nexus_io_trace::StageSummary;
 pub use nexus_iter::{
     nexus_iter,
     nexus_iter_mut,
@@ -48,11 +95,39 @@ pub use nexus_iter::{
     nexus_lookup_name_uuid,
     nexus_lookup_uuid_mut,
 };
+pub use nexus_latency_slo::{
+    ChannelLatency,
+    ChildLatency,
+    LatencySlo,
+    SloWindow,
+};
+pub use nexus_local::LocalDisk;
+pub use nexus_metadata::{
+    FilesystemHint,
+    NexusMetadata,
+    ReservationBackup,
+    METADATA_VERSION,
+};
+pub use nexus_migrate::{CoreMigration, MigrationState};
 pub(crate) use nexus_module::{NexusModule, NEXUS_MODULE_NAME};
 pub(crate) use nexus_nbd::{NbdDisk, NbdError};
 pub(crate) use nexus_persistence::PersistOp;
 pub use nexus_persistence::{ChildInfo, NexusInfo};
+pub use nexus_read_routing::{ReadRoutingPolicy, Topology};
+pub use nexus_readahead::{ReadaheadPolicy, ReadaheadStats};
+pub use nexus_rebuild_source::{RebuildSource, SourceCandidate};
+pub use nexus_rekey::{RekeyMarker, RekeyState, RekeyStatus};
+pub use nexus_retire_policy::RetirePolicy;
+pub use nexus_seed::{SeedOutcome, SeedVerify};
 pub(crate) use nexus_share::NexusPtpl;
+pub use nexus_state::StateTransition;
+pub(crate) use nexus_state::TransitionLog;
+pub(crate) use nexus_validate::validate_children;
+pub use nexus_validate::{ChildViolation, ValidationReport, ViolationKind};
+pub use nexus_write_ack::{ChildLagStats, WriteAckMode, WriteAckPolicy};
+pub use nexus_write_generation::NullRebuildPolicy;
+pub use nexus_write_intent::WriteIntentStats;
+pub use nexus_write_merge::{WriteMergePolicy, WriteMergeStats};
 
 /// TODO
 #[derive(Deserialize)]
@@ -74,12 +149,109 @@ struct NexusShareReply {
     uri: String,
 }
 
+/// Arguments of the `nexus_offline_child` and `nexus_online_child` json-rpc
+/// methods.
+#[derive(Deserialize)]
+struct NexusChildArgs {
+    /// Name or uuid of the nexus.
+    name: String,
+    /// URI of the child.
+    uri: String,
+}
+
+/// Reply of the `nexus_offline_child` and `nexus_online_child` json-rpc
+/// methods.
+#[derive(Serialize)]
+struct NexusChildReply {
+    status: NexusStatus,
+}
+
+/// Arguments of the `nexus_set_read_only` json-rpc method.
+#[derive(Deserialize)]
+struct NexusReadOnlyArgs {
+    /// Name or uuid of the nexus.
+    name: String,
+    read_only: bool,
+}
+
+/// Reply of the `nexus_set_read_only` json-rpc method.
+#[derive(Serialize)]
+struct NexusReadOnlyReply {
+    read_only: bool,
+}
+
+/// Arguments of the `nexus_child_stats` and `nexus_channel_stats` json-rpc
+/// methods.
+#[derive(Deserialize)]
+struct NexusChildStatsArgs {
+    /// Name or uuid of the nexus.
+    name: String,
+}
+
+/// Statistics of a nexus child, as returned by the `nexus_child_stats`
+/// json-rpc method. The statistics of a closed child are not available.
+#[derive(Serialize)]
+struct NexusChildStatsReply {
+    uri: String,
+    io_stats: Option<BlockDeviceIoStats>,
+    /// Initiator-side qpair statistics, for the NVMe-oF children only.
+    qpair_stats: Option<BlockDeviceQpairStats>,
+    /// Block size of the child, which may differ from the nexus one.
+    block_len: Option<u64>,
+    /// IOs of the nexus which are not aligned on the blocks of the child.
+    rmw_stats: RmwStats,
+}
+
+/// Statistics of the channels of a nexus, as returned by the
+/// `nexus_channel_stats` json-rpc method, along with their totals.
+#[derive(Serialize)]
+struct NexusChannelStatsReply {
+    name: String,
+    fail_fast: u64,
+    retries: u64,
+    requeues: u64,
+    reconnects: u64,
+    channels: Vec<ChannelStats>,
+}
+
 /// public function which simply calls register module
 pub fn register_module() {
     nexus_module::register_module();
+    nexus_admission::register_rpc_methods();
+    nexus_checksum::register_rpc_methods();
+    nexus_child_undo::register_rpc_methods();
+    nexus_consumer::register_rpc_methods();
+    nexus_fault::register_rpc_methods();
+    nexus_flight_recorder::register_rpc_methods();
+    nexus_group::register_rpc_methods();
+    nexus_io_deadline::register_rpc_methods();
+    nexus_io_trace::register_rpc_methods();
+    nexus_latency_slo::register_rpc_methods();
+    nexus_lazy::register_rpc_methods();
+    nexus_metadata::register_rpc_methods();
+    nexus_migrate::register_rpc_methods();
+    nexus_read_routing::register_rpc_methods();
+    nexus_readahead::register_rpc_methods();
+    nexus_rebuild_source::register_rpc_methods();
+    nexus_rekey::register_rpc_methods();
+    nexus_retire_policy::register_rpc_methods();
+    nexus_seed::register_rpc_methods();
+    nexus_shard::register_rpc_methods();
+    nexus_share::register_rpc_methods();
+    nexus_state::register_rpc_methods();
+    nexus_write_ack::register_rpc_methods();
+    nexus_write_generation::register_rpc_methods();
+    nexus_write_intent::register_rpc_methods();
+    nexus_write_merge::register_rpc_methods();
 
     use crate::{
-        core::{Share, ShareProps, UntypedBdev},
+        core::{
+            BlockDeviceIoStats,
+            BlockDeviceQpairStats,
+            Share,
+            ShareProps,
+            UntypedBdev,
+        },
         jsonrpc::{jsonrpc_register, Code, JsonRpcError, Result},
     };
 
@@ -126,6 +298,103 @@ pub fn register_module() {
             Box::pin(f.boxed_local())
         },
     );
+
+    jsonrpc_register("nexus_offline_child", |args: NexusChildArgs| {
+        async move {
+            let nexus = nexus_lookup_mut(&args.name)
+                .or_else(|| nexus_lookup_uuid_mut(&args.name))
+                .ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+            let status = nexus.take_child_offline(&args.uri).await?;
+            Ok::<_, Error>(NexusChildReply {
+                status,
+            })
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_online_child", |args: NexusChildArgs| {
+        async move {
+            let nexus = nexus_lookup_mut(&args.name)
+                .or_else(|| nexus_lookup_uuid_mut(&args.name))
+                .ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+            let status = nexus.online_child(&args.uri).await?;
+            Ok::<_, Error>(NexusChildReply {
+                status,
+            })
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_set_read_only", |args: NexusReadOnlyArgs| {
+        async move {
+            let mut nexus = nexus_lookup_mut(&args.name)
+                .or_else(|| nexus_lookup_uuid_mut(&args.name))
+                .ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+            nexus.as_mut().set_read_only(args.read_only).await?;
+            Ok::<_, Error>(NexusReadOnlyReply {
+                read_only: nexus.is_read_only(),
+            })
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_child_stats", |args: NexusChildStatsArgs| {
+        async move {
+            let nexus = nexus_lookup_mut(&args.name)
+                .or_else(|| nexus_lookup_uuid_mut(&args.name))
+                .ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+            let mut stats = Vec::new();
+            for child in nexus.children_iter() {
+                let (io_stats, qpair_stats, block_len) =
+                    match child.get_device() {
+                        Ok(device) => (
+                            device.io_stats().await.ok(),
+                            device.qpair_stats().await.ok().flatten(),
+                            Some(device.block_len()),
+                        ),
+                        Err(_) => (None, None, None),
+                    };
+                stats.push(NexusChildStatsReply {
+                    uri: child.uri().to_string(),
+                    io_stats,
+                    qpair_stats,
+                    block_len,
+                    rmw_stats: child.rmw_stats(),
+                });
+            }
+            Ok::<_, Error>(stats)
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_channel_stats", |args: NexusChildStatsArgs| {
+        async move {
+            let nexus = nexus_lookup_mut(&args.name)
+                .or_else(|| nexus_lookup_uuid_mut(&args.name))
+                .ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+            let channels = nexus.channel_stats().await;
+            let total = nexus.channel_counters();
+            Ok::<_, Error>(NexusChannelStatsReply {
+                name: nexus.name.clone(),
+                fail_fast: total.fail_fast,
+                retries: total.retries,
+                requeues: total.requeues,
+                reconnects: total.reconnects,
+                channels,
+            })
+        }
+        .boxed_local()
+    });
 }
 
 /// called during shutdown so that all nexus children are in Destroying state
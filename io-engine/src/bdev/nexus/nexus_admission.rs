@@ -0,0 +1,253 @@
+//! Queue-depth aware admission control.
+//!
+//! When initiators keep submitting I/Os to a nexus faster than its children
+//! complete them, the I/Os pile up in the submission queues of the children,
+//! where they eventually time out on the initiators, which submit them again
+//! while the previous ones still hold their buffers. When admission control
+//! is enabled, the I/Os outstanding on the nexus and on each of its children
+//! are accounted, and an I/O which would exceed either limit is not admitted:
+//! it is handed back to the bdev layer as out of resources, which queues it
+//! and submits it again once I/Os of the nexus complete.
+//!
+//! Both limits apply to the I/Os submitted from a single core, that is to
+//! the I/Os of a channel of the nexus and to the I/O queues of its children.
+//! The bdev layer only submits the queued I/Os of a core again when I/Os of
+//! that same core complete, so that an I/O is only queued on a channel which
+//! has I/Os outstanding.
+
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+use crossbeam::atomic::AtomicCell;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+
+use super::{nexus_lookup, Error, Nexus};
+use crate::jsonrpc::jsonrpc_register;
+
+/// Admission control policy of a nexus.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AdmissionPolicy {
+    pub enabled: bool,
+    /// Maximum number of I/Os outstanding on an I/O channel of the nexus.
+    pub max_nexus_ios: u32,
+    /// Maximum number of I/Os outstanding on an I/O queue of a child.
+    pub max_child_ios: u32,
+}
+
+impl Default for AdmissionPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_nexus_ios: 1024,
+            max_child_ios: 256,
+        }
+    }
+}
+
+/// Admission control statistics of a nexus.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct AdmissionStats {
+    /// Number of admitted I/Os outstanding on the nexus.
+    pub outstanding_ios: u32,
+    /// Number of I/Os which were not admitted and queued.
+    pub queued_ios: u64,
+}
+
+/// Admission control policy of a nexus, along with the I/Os it admitted.
+pub(crate) struct Admission {
+    policy: AtomicCell<AdmissionPolicy>,
+    outstanding: AtomicU32,
+    queued: AtomicU64,
+}
+
+impl Admission {
+    pub(crate) fn new() -> Self {
+        Self {
+            policy: AtomicCell::new(AdmissionPolicy::default()),
+            outstanding: AtomicU32::new(0),
+            queued: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the current policy.
+    #[inline(always)]
+    pub(crate) fn policy(&self) -> AdmissionPolicy {
+        self.policy.load()
+    }
+
+    /// Replace the policy.
+    pub(crate) fn set_policy(&self, policy: AdmissionPolicy) {
+        self.policy.store(policy);
+    }
+
+    /// Account an I/O admitted on a channel of the nexus.
+    pub(super) fn admitted(&self) {
+        self.outstanding.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Account the completion of an admitted I/O.
+    pub(super) fn release(&self) {
+        self.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Account an I/O which was not admitted.
+    pub(super) fn queued(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the statistics.
+    pub(crate) fn stats(&self) -> AdmissionStats {
+        AdmissionStats {
+            outstanding_ios: self.outstanding.load(Ordering::Relaxed),
+            queued_ios: self.queued.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<'n> Nexus<'n> {
+    /// Returns the admission control policy of the nexus.
+    pub fn admission_policy(&self) -> AdmissionPolicy {
+        self.admission.policy()
+    }
+
+    /// Replace the admission control policy of the nexus.
+    pub fn set_admission_policy(&self, policy: AdmissionPolicy) {
+        info!("{:?}: setting admission policy: {:?}", self, policy);
+        self.admission.set_policy(policy);
+    }
+
+    /// Returns the admission control statistics of the nexus.
+    pub fn admission_stats(&self) -> AdmissionStats {
+        self.admission.stats()
+    }
+}
+
+/// I/Os admitted on a nexus channel, and outstanding on the I/O queues of
+/// its children by child device.
+#[derive(Debug, Default)]
+pub(super) struct ChannelQueues {
+    ios: u32,
+    children: HashMap<String, u32>,
+}
+
+impl ChannelQueues {
+    /// Account an I/O of the channel, returning false if the channel already
+    /// has the maximum number of I/Os outstanding.
+    pub(super) fn acquire(&mut self, max_ios: u32) -> bool {
+        if self.ios >= max_ios {
+            return false;
+        }
+        self.ios += 1;
+        true
+    }
+
+    /// Account the completion of an I/O of the channel.
+    pub(super) fn release(&mut self) {
+        self.ios = self.ios.saturating_sub(1);
+    }
+
+    /// Returns true if the queue of the given child device has room for
+    /// another I/O.
+    pub(super) fn has_room(&self, device_name: &str, max_ios: u32) -> bool {
+        self.children
+            .get(device_name)
+            .map_or(true, |n| *n < max_ios)
+    }
+
+    /// Account an I/O submitted to the given child device.
+    pub(super) fn submitted(&mut self, device_name: String) {
+        *self.children.entry(device_name).or_default() += 1;
+    }
+
+    /// Account the completion of an I/O of the given child device.
+    pub(super) fn completed(&mut self, device_name: &str) {
+        if let Some(n) = self.children.get_mut(device_name) {
+            *n = n.saturating_sub(1);
+        }
+    }
+}
+
+/// Arguments of the `nexus_set_admission` json-rpc method, the fields which
+/// are not given are left unchanged.
+#[derive(Debug, Deserialize)]
+struct SetAdmissionArgs {
+    /// Name of the nexus.
+    name: String,
+    enabled: Option<bool>,
+    max_nexus_ios: Option<u32>,
+    max_child_ios: Option<u32>,
+}
+
+/// Arguments of the `nexus_get_admission` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct GetAdmissionArgs {
+    /// Name of the nexus.
+    name: String,
+}
+
+/// Reply of the admission control json-rpc methods.
+#[derive(Debug, Serialize)]
+struct AdmissionReply {
+    name: String,
+    policy: AdmissionPolicy,
+    stats: AdmissionStats,
+}
+
+impl AdmissionReply {
+    fn new(nexus: &Nexus) -> Self {
+        Self {
+            name: nexus.name.clone(),
+            policy: nexus.admission.policy(),
+            stats: nexus.admission.stats(),
+        }
+    }
+}
+
+/// Register the admission control json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nexus_set_admission", |args: SetAdmissionArgs| {
+        async move {
+            let nexus =
+                nexus_lookup(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+
+            let current = nexus.admission.policy();
+            let policy = AdmissionPolicy {
+                enabled: args.enabled.unwrap_or(current.enabled),
+                max_nexus_ios: args
+                    .max_nexus_ios
+                    .unwrap_or(current.max_nexus_ios),
+                max_child_ios: args
+                    .max_child_ios
+                    .unwrap_or(current.max_child_ios),
+            };
+            if policy.max_nexus_ios == 0 || policy.max_child_ios == 0 {
+                return Err(Error::InvalidArguments {
+                    name: args.name,
+                    args: "at least one I/O must be admitted on the nexus \
+                        and on its children"
+                        .to_string(),
+                });
+            }
+
+            nexus.set_admission_policy(policy);
+            Ok(AdmissionReply::new(nexus))
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_get_admission", |args: GetAdmissionArgs| {
+        async move {
+            nexus_lookup(&args.name).map(AdmissionReply::new).ok_or(
+                Error::NexusNotFound {
+                    name: args.name,
+                },
+            )
+        }
+        .boxed_local()
+    });
+}
@@ -5,12 +5,14 @@
 //! application needs synchronous mirroring may be required.
 
 use std::{
-    cmp::min,
+    cmp::{max, min},
     convert::TryFrom,
     fmt::{Debug, Display, Formatter},
     marker::PhantomPinned,
     os::raw::c_void,
     pin::Pin,
+    sync::Arc,
+    time::Duration,
 };
 
 use crossbeam::atomic::AtomicCell;
@@ -20,18 +22,46 @@ use snafu::ResultExt;
 use uuid::Uuid;
 
 use super::{
+    nexus_admission::Admission,
+    nexus_channel::{ChannelCounters, ChannelEpoch},
+    nexus_checksum::ChecksumLayer,
+    nexus_child_grace::ChildGrace,
+    nexus_child_undo::ChildUndo,
     nexus_err,
+    nexus_error_log::NexusErrorLog,
+    nexus_flight_recorder::FlightRecorder,
     nexus_injection::Injections,
+    nexus_io_deadline::IoDeadline,
+    nexus_io_trace::IoTracer,
+    nexus_iter,
+    nexus_latency_slo::LatencySloMonitor,
+    nexus_lazy::LazyConnect,
+    nexus_lease::start_lease_renewal,
     nexus_lookup_name_uuid,
+    nexus_metadata::MetadataRegion,
+    nexus_migrate::NexusMigration,
+    nexus_read_routing::ReadRouting,
+    nexus_readahead::Readahead,
+    nexus_rebuild_source::ChildReadStats,
+    nexus_rekey::forget_rekey,
+    nexus_retire_policy::ChildRetirePolicy,
+    nexus_shard::ChildShards,
+    nexus_write_ack::WriteAck,
+    nexus_write_generation::WriteGeneration,
+    nexus_write_intent::WriteIntentLog,
+    nexus_write_merge::WriteMerge,
+    validate_children,
     ChildState,
     DrEvent,
     Error,
+    LocalDisk,
     NbdDisk,
     NexusBio,
     NexusChannel,
     NexusChild,
     NexusModule,
     PersistOp,
+    TransitionLog,
 };
 
 use crate::{
@@ -49,9 +79,13 @@ use crate::{
         Protocol,
         Reactor,
         Share,
+        UntypedBdev,
         VerboseError,
     },
-    subsys::NvmfSubsystem,
+    drain,
+    labels::{validate_labels, Labels},
+    rebuild::IoLatency,
+    subsys::{forget_queue_limits, NvmfSubsystem},
 };
 
 use crate::bdev::PtplFileOps;
@@ -70,11 +104,18 @@ pub static NVME_MAX_CNTLID: u16 = 0xffef;
 
 pub(crate) static NEXUS_PRODUCT_ID: &str = "Nexus CAS Driver v0.0.1";
 
+/// Time the destruction of a nexus waits for its I/O channels to be
+/// destroyed.
+const CHANNEL_RELEASE_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// TODO
 #[derive(Debug)]
 pub enum NexusTarget {
     NbdDisk(NbdDisk),
     NexusNvmfTarget,
+    NexusIscsiTarget,
+    NexusVfioUserTarget,
+    LocalDisk(LocalDisk),
 }
 
 /// Sensitive nexus operations that might require extra checks against
@@ -84,6 +125,7 @@ pub enum NexusOperation {
     ReplicaAdd,
     ReplicaRemove,
     ReplicaOnline,
+    ReplicaOffline,
 }
 
 /// TODO
@@ -232,6 +274,11 @@ impl NexusNvmeParams {
 pub struct Nexus<'n> {
     /// Name of the Nexus instance
     pub(crate) name: String,
+    /// Mutable human-readable name of the nexus, by which it can be looked up
+    /// in addition to its name.
+    alias: parking_lot::Mutex<Option<String>>,
+    /// Key/value labels of the nexus.
+    pub(super) labels: parking_lot::Mutex<Labels>,
     /// The requested size of the Nexus in bytes. Children are allowed to
     /// be larger. The actual Nexus size will be calculated based on the
     /// capabilities of the underlying child devices.
@@ -246,6 +293,8 @@ pub struct Nexus<'n> {
     bdev: Option<Bdev<Nexus<'n>>>,
     /// represents the current state of the Nexus
     pub(crate) state: parking_lot::Mutex<NexusState>,
+    /// Transitions of the state of the nexus.
+    pub(crate) history: TransitionLog,
     /// The offset in blocks where the data partition starts.
     pub(crate) data_ent_offset: u64,
     /// enum containing the protocol-specific target used to publish the nexus
@@ -261,8 +310,64 @@ pub struct Nexus<'n> {
     /// TODO
     #[allow(dead_code)]
     pub(super) injections: Injections,
+    /// Records the last I/Os of the nexus, when enabled.
+    pub(crate) flight_recorder: FlightRecorder,
+    /// Policy deciding when a child is retired on I/O errors.
+    pub(crate) retire_policy: ChildRetirePolicy,
+    /// Children suspended for their grace period on transport errors.
+    pub(crate) child_grace: ChildGrace,
+    /// Children detached from the nexus, within the undo window of their
+    /// removal.
+    pub(crate) child_undo: ChildUndo<'n>,
+    /// Routing of the reads to the closest children.
+    pub(crate) read_routing: ReadRouting,
+    /// Readahead of the sequential read streams.
+    pub(crate) readahead: Readahead,
+    /// Volume attributes kept in the metadata region of the children.
+    pub(crate) metadata: MetadataRegion,
+    /// Epoch of the I/O channels, which stop referring to the nexus once it
+    /// is being destroyed.
+    pub(crate) channels: Arc<ChannelEpoch>,
+    /// Counters of the I/O channels, across their re-creations.
+    pub(crate) channel_counters: ChannelCounters,
+    /// Latency of the I/Os, measured for the pacing of the rebuilds.
+    pub(crate) io_latency: IoLatency,
+    /// Reads of the children, measured to select the source of the
+    /// rebuilds.
+    pub(crate) child_reads: ChildReadStats,
+    /// Latency SLO of the nexus, and the latency of its I/Os.
+    pub(crate) latency_slo: LatencySloMonitor,
+    /// Sampled tracing of the IOs.
+    pub(crate) io_trace: IoTracer,
+    /// Lazy connection of the remote children.
+    pub(crate) lazy: LazyConnect,
+    /// Failed child I/Os, served as the error log page of the nexus.
+    pub(crate) error_log: NexusErrorLog,
+    /// Deadline of the I/Os, from the I/O timeout of the initiators.
+    pub(crate) io_deadline: IoDeadline,
+    /// Cores connecting to the remote children.
+    pub(crate) shards: ChildShards,
+    /// Last migration of the nexus off a core.
+    pub(crate) migration: NexusMigration,
+    /// Policy deciding when writes are acknowledged.
+    pub(crate) write_ack: WriteAck,
+    /// Policy deciding when adjacent writes are merged.
+    pub(crate) write_merge: WriteMerge,
+    /// Policy deciding when I/Os are admitted.
+    pub(crate) admission: Admission,
+    /// Block checksums of the children, when enabled.
+    pub(crate) checksums: ChecksumLayer,
+    /// Regions being written, logged on the children when enabled.
+    pub(crate) write_intent: WriteIntentLog,
+    /// Whether the volume has been written, and the policy adding children
+    /// without a rebuild to a volume which has never been.
+    pub(crate) write_generation: WriteGeneration,
     /// Flag to control shutdown from I/O path.
     pub(crate) shutdown_requested: AtomicCell<bool>,
+    /// New I/Os are failed, as the ownership of the nexus has been lost.
+    pub(crate) fenced: AtomicCell<bool>,
+    /// Writes are failed, as the nexus is exported read-only.
+    pub(crate) read_only: AtomicCell<bool>,
     /// Prevent auto-Unpin.
     _pin: PhantomPinned,
 }
@@ -352,8 +457,11 @@ impl<'n> Nexus<'n> {
     ) -> spdk_rs::Bdev<Nexus<'n>> {
         let n = Nexus {
             name: name.to_string(),
+            alias: parking_lot::Mutex::new(None),
+            labels: parking_lot::Mutex::new(Labels::new()),
             children: Vec::new(),
             state: parking_lot::Mutex::new(NexusState::Init),
+            history: TransitionLog::default(),
             bdev: None,
             data_ent_offset: 0,
             req_size: size,
@@ -367,7 +475,33 @@ impl<'n> Nexus<'n> {
             nexus_uuid: Default::default(),
             event_sink: None,
             injections: Injections::new(),
+            flight_recorder: FlightRecorder::new(),
+            retire_policy: ChildRetirePolicy::new(),
+            child_grace: ChildGrace::new(),
+            child_undo: ChildUndo::new(),
+            read_routing: ReadRouting::new(),
+            readahead: Readahead::new(),
+            metadata: MetadataRegion::new(),
+            channels: Arc::new(ChannelEpoch::default()),
+            channel_counters: ChannelCounters::default(),
+            io_latency: IoLatency::default(),
+            child_reads: ChildReadStats::default(),
+            latency_slo: LatencySloMonitor::new(),
+            io_trace: IoTracer::new(),
+            lazy: LazyConnect::new(),
+            error_log: NexusErrorLog::new(),
+            io_deadline: IoDeadline::new(),
+            shards: ChildShards::new(name),
+            migration: NexusMigration::default(),
+            write_ack: WriteAck::new(),
+            write_merge: WriteMerge::new(),
+            admission: Admission::new(),
+            checksums: ChecksumLayer::new(),
+            write_intent: WriteIntentLog::new(),
+            write_generation: WriteGeneration::new(),
             shutdown_requested: AtomicCell::new(false),
+            fenced: AtomicCell::new(false),
+            read_only: AtomicCell::new(false),
             _pin: Default::default(),
         };
 
@@ -448,18 +582,144 @@ impl<'n> Nexus<'n> {
         self.nexus_uuid
     }
 
-    /// Sets the state of the Nexus.
-    fn set_state(self: Pin<&mut Self>, state: NexusState) -> NexusState {
-        debug!("{:?}: changing state to '{}'", self, state);
-        *self.state.lock() = state;
-        state
-    }
-
     /// Returns name of the underlying Bdev.
     pub(crate) fn bdev_name(&self) -> String {
         unsafe { self.bdev().name().to_string() }
     }
 
+    /// Returns the alias of the nexus, if it has been renamed.
+    pub fn alias(&self) -> Option<String> {
+        self.alias.lock().clone()
+    }
+
+    /// Returns true if the nexus has the given name or alias.
+    pub(crate) fn has_name(&self, name: &str) -> bool {
+        self.name == name || self.alias.lock().as_deref() == Some(name)
+    }
+
+    /// Renames the nexus: the alias becomes a human-readable name by which
+    /// the nexus and its bdev can be looked up, in addition to the name and
+    /// uuid given on creation, which remain stable. A `None` alias removes the
+    /// current one.
+    pub async fn rename(
+        mut self: Pin<&mut Self>,
+        alias: Option<String>,
+    ) -> Result<(), Error> {
+        let previous = self.alias();
+        if previous == alias {
+            return Ok(());
+        }
+
+        if let Some(alias) = &alias {
+            if UntypedBdev::lookup_by_name(alias).is_some()
+                || nexus_iter().any(|n| n.has_name(alias))
+            {
+                return Err(Error::NameExists {
+                    name: alias.clone(),
+                });
+            }
+        }
+
+        let bdev = unsafe { self.as_mut().bdev_mut() };
+        if let Some(previous) = &previous {
+            bdev.remove_alias(previous);
+        }
+        if let Some(alias) = &alias {
+            if !bdev.add_alias(alias) {
+                if let Some(previous) = &previous {
+                    bdev.add_alias(previous);
+                }
+                return Err(Error::NameExists {
+                    name: alias.clone(),
+                });
+            }
+        }
+
+        info!("{:?}: renamed from {:?} to {:?}", self, previous, alias);
+        *self.alias.lock() = alias;
+        self.persist_spec().await;
+        Ok(())
+    }
+
+    /// Returns the number of live I/O channels of the nexus.
+    pub fn live_channels(&self) -> usize {
+        self.channels.live()
+    }
+
+    /// Returns the labels of the nexus.
+    pub fn labels(&self) -> Labels {
+        self.labels.lock().clone()
+    }
+
+    /// Replace the labels of the nexus, which are recorded with its
+    /// definition and in its metadata region. The region is brought up to
+    /// date when the nexus is next opened should it fail to be written.
+    pub async fn set_labels(&self, labels: Labels) -> Result<(), Error> {
+        validate_labels(&labels).map_err(|args| Error::InvalidArguments {
+            name: self.name.clone(),
+            args,
+        })?;
+        info!("{:?}: labels set to {:?}", self, labels);
+        *self.labels.lock() = labels.clone();
+        self.persist_spec().await;
+        if *self.state.lock() != NexusState::Init {
+            if let Err(error) =
+                self.update_metadata(|m| m.labels = labels).await
+            {
+                warn!("{:?}: {}", self, error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Shrink the nexus to the given size in bytes, once a replica it has as
+    /// a child has been shrunk below its size, which the child could not be
+    /// opened again with otherwise. SPDK does not shrink a device which is
+    /// open, so the nexus must not be shared. Returns the size of the nexus.
+    pub(crate) async fn shrink(
+        mut self: Pin<&mut Self>,
+        size: u64,
+    ) -> Result<u64, Error> {
+        if size >= self.req_size {
+            return Ok(self.size_in_bytes());
+        }
+        if !matches!(self.shared(), None | Some(Protocol::Off)) {
+            return Err(Error::OperationNotAllowed {
+                reason: format!(
+                    "nexus {} is shared and cannot be shrunk",
+                    self.name
+                ),
+            });
+        }
+
+        // the data partition of the smallest child, as on opening the nexus
+        let blk_size = self.block_len();
+        let start_blk = self.data_ent_offset;
+        let end_blk = self
+            .children_iter()
+            .filter_map(|c| c.get_device().ok())
+            .filter_map(|dev| {
+                let bs = dev.block_len();
+                partition::calc_data_partition(size, dev.num_blocks(), bs)
+                    .map(|(_, end)| end / (blk_size / bs))
+            })
+            .min()
+            .unwrap_or(start_blk + size / blk_size);
+        let num_blocks =
+            end_blk.saturating_sub(start_blk).min(self.num_blocks());
+
+        unsafe {
+            self.as_mut().get_unchecked_mut().req_size = size;
+            self.as_mut().set_num_blocks(num_blocks);
+        }
+        info!(
+            "{:?}: shrunk to {} bytes, {} blocks",
+            self, size, num_blocks
+        );
+        self.persist_spec().await;
+        Ok(self.size_in_bytes())
+    }
+
     /// TODO
     pub fn req_size(&self) -> u64 {
         self.req_size
@@ -514,9 +774,25 @@ impl<'n> Nexus<'n> {
             idx,
             self.children[idx].uri()
         );
+        // the transitions of the child outlive it in the history of the nexus
+        self.history.append(self.children[idx].history());
         self.unpin_mut().children.remove(idx);
     }
 
+    /// Take the child at the given index out of the nexus, keeping it.
+    pub(super) unsafe fn child_take_at_unsafe(
+        self: Pin<&mut Self>,
+        idx: usize,
+    ) -> NexusChild<'n> {
+        debug!(
+            "{:?}: taking child at index: {}: '{}'",
+            self,
+            idx,
+            self.children[idx].uri()
+        );
+        self.unpin_mut().children.remove(idx)
+    }
+
     /// TODO
     pub fn child_at(&self, idx: usize) -> &NexusChild<'n> {
         self.children.get(idx).expect("Bad child index")
@@ -569,6 +845,17 @@ impl<'n> Nexus<'n> {
 
     /// Reconfigures the child event handler.
     pub(crate) async fn reconfigure(&self, event: DrEvent) {
+        // the channels of a nexus being destroyed must not be traversed, as
+        // its I/O device may already be unregistered
+        if self.channels.is_closed() {
+            debug!(
+                "{:?}: dynamic reconfiguration event: {} skipped, nexus \
+                being destroyed",
+                self, event
+            );
+            return;
+        }
+
         info!(
             "{:?}: dynamic reconfiguration event: {} started...",
             self, event
@@ -589,6 +876,8 @@ impl<'n> Nexus<'n> {
         );
 
         let result = recv.await.expect("reconfigure sender already dropped");
+        self.purge_shard_handles().await;
+        self.rekey_check_child().await;
 
         info!(
             "{:?}: dynamic reconfiguration event: {} completed: {:?}",
@@ -610,11 +899,14 @@ impl<'n> Nexus<'n> {
         // Determine Nexus block size and data start and end offsets.
         let mut start_blk = 0;
         let mut end_blk = 0;
-        let mut blk_size = 0;
-
-        for child in self.children_iter() {
-            let dev = match child.get_device() {
-                Ok(dev) => dev,
+        let mut blk_size = self.lazy.block_len().map_or(0, u64::from);
+
+        // The nexus exposes the largest block size of its children, so that
+        // its IOs are aligned on the blocks of all of them. The children whose
+        // connection is deferred are checked once connected.
+        for child in self.children_iter().filter(|c| !c.is_deferred()) {
+            match child.get_device() {
+                Ok(dev) => blk_size = max(blk_size, dev.block_len()),
                 Err(_) => {
                     return Err(Error::NexusIncomplete {
                         name,
@@ -624,43 +916,63 @@ impl<'n> Nexus<'n> {
                         ),
                     })
                 }
-            };
+            }
+        }
 
-            let nb = dev.num_blocks();
+        // Validate all the children before opening any of them.
+        let devices = self
+            .children_iter()
+            .filter_map(|c| Some((c.uri(), c.get_device().ok()?)))
+            .collect::<Vec<_>>();
+        let report =
+            validate_children(self.req_size(), blk_size, &[], &devices);
+        for warning in &report.warnings {
+            warn!("{:?}: {}", self, warning);
+        }
+        if !report.is_valid() {
+            return Err(Error::ChildValidation {
+                name,
+                report,
+            });
+        }
+
+        for (uri, dev) in devices {
             let bs = dev.block_len();
+            if bs != blk_size {
+                info!(
+                    "{:?}: child {} has {} byte blocks, IOs are scaled to its blocks",
+                    self,
+                    uri,
+                    bs
+                );
+            }
 
-            if blk_size == 0 {
-                blk_size = bs;
-            } else if bs != blk_size {
-                return Err(Error::MixedBlockSizes {
-                    name: self.name.clone(),
-                });
+            // the data partition, in blocks of the nexus
+            if let Some((start, end)) = partition::calc_data_partition(
+                self.req_size(),
+                dev.num_blocks(),
+                bs,
+            ) {
+                let ratio = blk_size / bs;
+                start_blk = start / ratio;
+                end_blk = if end_blk == 0 {
+                    end / ratio
+                } else {
+                    min(end_blk, end / ratio)
+                };
             }
+        }
 
-            match partition::calc_data_partition(self.req_size(), nb, bs) {
-                Some((start, end)) => {
-                    if start_blk == 0 {
-                        start_blk = start;
-                        end_blk = end;
-                    } else {
-                        end_blk = min(end_blk, end);
-
-                        if start_blk != start {
-                            return Err(Error::ChildGeometry {
-                                child: child.uri().to_owned(),
-                                name,
-                            });
-                        }
-                    }
-                }
-                None => {
-                    return Err(Error::ChildTooSmall {
-                        child: child.uri().to_owned(),
-                        name,
-                        num_blocks: nb,
-                        block_size: bs,
-                    })
-                }
+        // none of the children is connected yet, the nexus has the size it
+        // is created with
+        if end_blk == 0 {
+            if let Some((start, end)) = partition::calc_data_partition(
+                self.req_size(),
+                u64::MAX,
+                blk_size,
+            ) {
+                start_blk = start;
+                end_blk = end;
             }
         }
 
@@ -689,7 +1001,14 @@ impl<'n> Nexus<'n> {
 
         info!("{:?}: registering nexus bdev...", nex);
 
-        nex.as_mut().setup_nexus_bdev().await?;
+        // Make sure no other node owns the nexus before touching its
+        // children.
+        nex.acquire_ownership().await?;
+
+        if let Err(err) = nex.as_mut().setup_nexus_bdev().await {
+            nex.release_ownership().await;
+            return Err(err);
+        }
 
         // Register the bdev with SPDK and set the callbacks for io channel
         // creation.
@@ -703,6 +1022,7 @@ impl<'n> Nexus<'n> {
                 nex,
                 err.verbose()
             );
+            nex.release_ownership().await;
             return Err(err).context(nexus_err::RegisterNexus {
                 name: nex.name.clone(),
             });
@@ -717,15 +1037,24 @@ impl<'n> Nexus<'n> {
             Err(err) => {
                 error!("{:?} failed to open children: {}", nex, err.verbose());
                 bdev.unregister_bdev();
+                nex.release_ownership().await;
                 return Err(err);
             }
         };
 
+        // Resynchronise the regions written when the nexus went down.
+        nex.write_intent_recover().await;
+
+        // Adopt the volume attributes kept on the children.
+        nex.metadata_load().await;
+        nex.rekey_resume();
+
         // Persist the fact that the nexus is now successfully open.
         // We have to do this before setting the nexus to open so that
         // nexus list does not return this nexus until it is persisted.
         nex.persist(PersistOp::Create).await;
-        nex.as_mut().set_state(NexusState::Open);
+        nex.transition(NexusState::Open, "nexus bdev registered");
+        start_lease_renewal(nex.name.clone());
         info!("{:?}: nexus bdev registered successfully", nex);
 
         Ok(())
@@ -746,7 +1075,17 @@ impl<'n> Nexus<'n> {
     ) -> Result<(), Error> {
         info!("{:?}: destroying nexus...", self);
 
+        // A module layered on top of the nexus must release it first, unless
+        // the io-engine is going away anyway.
+        if !sigterm {
+            self.check_unclaimed()?;
+        }
+
+        // The hosts are done with the nexus before it is unshared, so that
+        // no I/O races with the closing of the children.
+        self.as_mut().withdraw_namespace().await;
         self.as_mut().unshare_nexus().await?;
+        self.detach_vhost(None)?;
 
         // wait for all rebuild jobs to be cancelled before proceeding with the
         // destruction of the nexus
@@ -755,6 +1094,8 @@ impl<'n> Nexus<'n> {
             self.as_mut().cancel_rebuild_jobs(&child).await;
         }
 
+        self.write_intent_shutdown().await;
+
         info!("{:?}: closing {} children...", self, self.children.len());
         unsafe {
             for child in self.as_mut().children_iter_mut() {
@@ -772,7 +1113,13 @@ impl<'n> Nexus<'n> {
 
         // Persist the fact that the nexus destruction has completed.
         self.persist(PersistOp::Shutdown).await;
+        forget_queue_limits(&self.name);
+        forget_rekey(&self.name);
+        // The definition of the nexus is only forgotten when it is destroyed
+        // on request: on termination it is kept, so that the nexus can be
+        // restored on startup.
         if !sigterm {
+            self.forget_spec().await;
             if let Err(error) = self.ptpl().destroy() {
                 tracing::error!(
                     "{:?}: Failed to clean up persistence through power loss for nexus: {}",
@@ -838,10 +1185,11 @@ impl<'n> Nexus<'n> {
                     });
                 }
                 // Save current state and mark nexus as being under shutdown.
-                t => {
-                    *s = NexusState::ShuttingDown;
-                    t
-                }
+                _ => self.transition_locked(
+                    &mut s,
+                    NexusState::ShuttingDown,
+                    "shutdown requested",
+                ),
             }
         };
 
@@ -859,7 +1207,7 @@ impl<'n> Nexus<'n> {
             );
 
             // Restore previous nexus state.
-            *self.state.lock() = prev_state;
+            self.transition(prev_state, "failed to pause the I/O subsystem");
             error
         })?;
 
@@ -874,6 +1222,8 @@ impl<'n> Nexus<'n> {
             self.as_mut().cancel_rebuild_jobs(&child).await;
         }
 
+        self.write_intent_shutdown().await;
+
         // Step 3: Close all nexus children.
         self.as_mut().close_children().await;
 
@@ -881,7 +1231,7 @@ impl<'n> Nexus<'n> {
         self.persist(PersistOp::Shutdown).await;
 
         // Finally, mark nexus as being fully shutdown.
-        *self.state.lock() = NexusState::Shutdown;
+        self.transition(NexusState::Shutdown, "shutdown completed");
 
         info!(
             nexus=%self.name,
@@ -897,8 +1247,13 @@ impl<'n> Nexus<'n> {
     /// with the nexus paused once they are awakened via resume().
     /// Note: in order to handle concurrent pauses properly, this function must
     /// be called only from the master core.
+    /// Asynchronous child writes still in flight are waited for, as the IO
+    /// channels may be reconfigured while the nexus is paused.
     pub async fn pause(self: Pin<&mut Self>) -> Result<(), Error> {
-        self.io_subsystem_mut().suspend().await
+        let tracker = self.write_ack.tracker.clone();
+        self.io_subsystem_mut().suspend().await?;
+        tracker.drain().await;
+        Ok(())
     }
 
     /// get ANA state of the NVMe subsystem
@@ -967,18 +1322,23 @@ impl<'n> Nexus<'n> {
             NexusState::ShuttingDown => NexusStatus::ShuttingDown,
             NexusState::Shutdown => NexusStatus::Shutdown,
             NexusState::Open | NexusState::Reconfiguring => {
+                // a child whose connection is deferred is deemed online
+                // until it fails to connect
+                let online = |c: &NexusChild| {
+                    c.state() == ChildState::Open || c.is_deferred()
+                };
                 if self
                     .children
                     .iter()
                     // All children are online, so the Nexus is also online
-                    .all(|c| c.state() == ChildState::Open)
+                    .all(online)
                 {
                     NexusStatus::Online
                 } else if self
                     .children
                     .iter()
                     // at least one child online, so the Nexus is also online
-                    .any(|c| c.state() == ChildState::Open)
+                    .any(online)
                 {
                     NexusStatus::Degraded
                 } else {
@@ -1104,6 +1464,9 @@ impl<'n> BdevOps for Nexus<'n> {
             return;
         }
 
+        // the work deferred by the channels stops referring to the nexus
+        self.channels.close();
+
         let self_ptr = unsafe { unsafe_static_ptr(&*self) };
 
         Reactor::block_on(async move {
@@ -1142,7 +1505,24 @@ impl<'n> BdevOps for Nexus<'n> {
             self.as_mut().get_unchecked_mut().has_io_device = false;
         }
 
-        self.as_mut().set_state(NexusState::Closed);
+        // the nexus is reclaimed once destructed: its channels should be
+        // destroyed by then, and those which are not stop referring to it as
+        // the epoch is closed
+        let name = self.name.clone();
+        let channels = self.channels.clone();
+        Reactor::block_on(async move {
+            if !channels.released(CHANNEL_RELEASE_TIMEOUT).await {
+                error!(
+                    "Nexus '{}': {} I/O channel(s) still live after {:?}, \
+                    destructing the nexus",
+                    name,
+                    channels.live(),
+                    CHANNEL_RELEASE_TIMEOUT
+                );
+            }
+        });
+
+        self.transition(NexusState::Closed, "nexus bdev unregistered");
 
         info!("{:?}: nexus bdev unregistered", self);
     }
@@ -1155,7 +1535,11 @@ impl<'n> BdevOps for Nexus<'n> {
         chan: IoChannel<NexusChannel<'n>>,
         bio: BdevIo<Nexus<'n>>,
     ) {
-        let io = NexusBio::new(chan, bio);
+        let mut io = NexusBio::new(chan, bio);
+        io.start_recording();
+        io.start_timing();
+        io.start_deadline();
+        io.start_trace();
         io.submit_request();
     }
 
@@ -1225,6 +1609,7 @@ pub async fn nexus_create(
         NexusNvmeParams::default(),
         children,
         None,
+        None,
     )
     .await
 }
@@ -1239,6 +1624,60 @@ pub async fn nexus_create_v2(
     nvme_params: NexusNvmeParams,
     children: &[String],
     nexus_info_key: Option<String>,
+) -> Result<(), Error> {
+    nexus_create_checked(
+        name,
+        size,
+        uuid,
+        nvme_params,
+        children,
+        nexus_info_key,
+        None,
+    )
+    .await
+}
+
+/// As nexus_create_v2, with the remote children connected on the first IO of
+/// the nexus rather than on its creation, see `nexus_lazy`.
+/// block_len: block size of the nexus until its children are connected
+pub async fn nexus_create_lazy(
+    name: &str,
+    size: u64,
+    uuid: &str,
+    nvme_params: NexusNvmeParams,
+    children: &[String],
+    nexus_info_key: Option<String>,
+    block_len: u32,
+) -> Result<(), Error> {
+    if block_len < 512 || !block_len.is_power_of_two() {
+        let args = format!("invalid block size {}", block_len);
+        error!("failed to create nexus {}: {}", name, args);
+        return Err(Error::InvalidArguments {
+            name: name.to_owned(),
+            args,
+        });
+    }
+    nexus_create_checked(
+        name,
+        size,
+        uuid,
+        nvme_params,
+        children,
+        nexus_info_key,
+        Some(block_len),
+    )
+    .await
+}
+
+/// Validate the parameters of the nexus, and create it.
+async fn nexus_create_checked(
+    name: &str,
+    size: u64,
+    uuid: &str,
+    nvme_params: NexusNvmeParams,
+    children: &[String],
+    nexus_info_key: Option<String>,
+    lazy_block_len: Option<u32>,
 ) -> Result<(), Error> {
     if nvme_params.min_cntlid < NVME_MIN_CNTLID
         || nvme_params.min_cntlid > nvme_params.max_cntlid
@@ -1284,6 +1723,7 @@ pub async fn nexus_create_v2(
                 nvme_params,
                 children,
                 nexus_info_key,
+                lazy_block_len,
             )
             .await
         }
@@ -1296,12 +1736,14 @@ pub async fn nexus_create_v2(
                 nvme_params,
                 children,
                 nexus_info_key,
+                lazy_block_len,
             )
             .await
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn nexus_create_internal(
     name: &str,
     size: u64,
@@ -1310,6 +1752,7 @@ async fn nexus_create_internal(
     nvme_params: NexusNvmeParams,
     children: &[String],
     nexus_info_key: Option<String>,
+    lazy_block_len: Option<u32>,
 ) -> Result<(), Error> {
     info!(
         "Creating new nexus '{}' ({} child(ren): {:?})...",
@@ -1337,6 +1780,12 @@ async fn nexus_create_internal(
         return Ok(());
     }
 
+    if drain::is_draining() {
+        return Err(Error::NodeDraining {
+            name: name.to_owned(),
+        });
+    }
+
     // Create a new Nexus object, and immediately add it to the global list.
     // This is necessary to ensure proper cleanup, as the code responsible for
     // closing a child assumes that the nexus to which it belongs will appear
@@ -1350,6 +1799,9 @@ async fn nexus_create_internal(
         nvme_params,
         nexus_info_key,
     );
+    if let Some(block_len) = lazy_block_len {
+        nexus_bdev.data().lazy.enable(block_len);
+    }
 
     for uri in children {
         if let Err(error) = nexus_bdev.data_mut().new_child(uri).await {
@@ -15,6 +15,10 @@
 //! 'fault_child` will do the same as `offline_child` except, it will not close
 //! the child.
 //!
+//! `take_child_offline` administratively takes an open child out of the IO
+//! path, keeping it attached and its device open. `online_child` brings it
+//! back through a rebuild, so that it catches up with the writes it missed.
+//!
 //! `add_child` will construct a new `NexusChild` and add the bdev given by the
 //! uri to the nexus. The nexus will transition to degraded mode as the new
 //! child requires rebuild first. If the rebuild flag is set then the rebuild
@@ -29,11 +33,16 @@ use futures::{channel::oneshot, future::join_all};
 use snafu::ResultExt;
 
 use super::{
+    nexus_child_intent::ChildAddition,
     nexus_err,
+    nexus_lazy::is_remote,
     nexus_lookup_mut,
+    validate_children,
+    AdminAction,
     ChildState,
     DrEvent,
     Error,
+    FaultDetail,
     Nexus,
     NexusChannel,
     NexusChild,
@@ -42,6 +51,7 @@ use super::{
     NexusStatus,
     PersistOp,
     Reason,
+    ViolationKind,
 };
 
 use crate::{
@@ -71,13 +81,15 @@ impl<'n> Nexus<'n> {
         info!("{:?}: adding child: '{}'...", self, uri);
 
         let nexus_name = self.nexus_name().to_owned();
-        let device_name = device_create(uri).await?;
+        // the connection of a remote child of a lazy nexus is deferred
+        // to its first IO
+        let device = if self.lazy.is_pending() && is_remote(uri) {
+            None
+        } else {
+            device_lookup(&device_create(uri).await?)
+        };
 
-        let c = NexusChild::new(
-            uri.to_string(),
-            nexus_name,
-            device_lookup(&device_name),
-        );
+        let c = NexusChild::new(uri.to_string(), nexus_name, device);
 
         info!("{:?}: added to nexus", c);
 
@@ -106,6 +118,19 @@ impl<'n> Nexus<'n> {
 
         let status = self.as_mut().add_child_only(uri).await?;
 
+        // a volume which has never been written has nothing to rebuild
+        match self.null_rebuild(uri).await {
+            Ok(true) => return Ok(self.status()),
+            Ok(false) => {}
+            Err(error) => warn!(
+                "{:?}: failed to bring child '{}' online without a \
+                rebuild: {}",
+                self,
+                uri,
+                error.verbose()
+            ),
+        }
+
         if !norebuild {
             if let Err(e) = self.as_mut().start_rebuild(uri).await {
                 // todo: CAS-253 retry starting the rebuild again when ready
@@ -128,12 +153,30 @@ impl<'n> Nexus<'n> {
 
     /// The child may require a rebuild first, so the nexus will
     /// transition to degraded mode when the addition has been successful.
-    async fn add_child_only(
+    /// The addition is recorded as an intent until it has been persisted,
+    /// see `nexus_child_intent`.
+    pub(super) async fn add_child_only(
         mut self: Pin<&mut Self>,
         uri: &str,
     ) -> Result<NexusStatus, Error> {
         self.check_nexus_operation(NexusOperation::ReplicaAdd)?;
 
+        // a detached child is added anew
+        self.as_mut().close_detached_child(uri).await;
+
+        let mut addition = self.prepare_child_addition(uri).await?;
+        let res = self.as_mut().open_added_child(uri, &mut addition).await;
+        addition.finish(res.is_ok()).await;
+        res
+    }
+
+    /// Open the child being added and register it with the nexus, the
+    /// second phase of its addition.
+    async fn open_added_child(
+        mut self: Pin<&mut Self>,
+        uri: &str,
+        addition: &mut ChildAddition,
+    ) -> Result<NexusStatus, Error> {
         let name =
             device_create(uri).await.context(nexus_err::CreateChild {
                 name: self.name.clone(),
@@ -143,27 +186,7 @@ impl<'n> Nexus<'n> {
         assert!(self.block_len() > 0);
 
         let child_bdev = match device_lookup(&name) {
-            Some(child) => {
-                if child.block_len() != self.block_len()
-                    || self
-                        .min_num_blocks()
-                        .map_or(true, |n| n > child.num_blocks())
-                {
-                    if let Err(err) = device_destroy(uri).await {
-                        error!(
-                            "Failed to destroy child bdev with wrong geometry: {}",
-                            err.to_string()
-                        );
-                    }
-
-                    return Err(Error::ChildGeometry {
-                        child: name,
-                        name: self.name.clone(),
-                    });
-                } else {
-                    child
-                }
-            }
+            Some(child) => child,
             None => {
                 return Err(Error::ChildMissing {
                     child: name,
@@ -179,6 +202,52 @@ impl<'n> Nexus<'n> {
             });
         }
 
+        // Validate the child against the open children before opening it.
+        let open = self
+            .children_iter()
+            .filter(|c| c.state() == ChildState::Open)
+            .filter_map(|c| c.get_device().ok())
+            .collect::<Vec<_>>();
+        let mut report = validate_children(
+            self.req_size(),
+            self.block_len(),
+            &open,
+            &[(uri, &*child_bdev)],
+        );
+        if open.is_empty() {
+            report.error(
+                uri,
+                ViolationKind::Size,
+                "the nexus has no open child to rebuild it from".to_string(),
+            );
+        }
+        for warning in &report.warnings {
+            warn!("{:?}: {}", self, warning);
+        }
+        if !report.is_valid() {
+            if let Err(err) = device_destroy(uri).await {
+                error!(
+                    "Failed to destroy child bdev which is not valid: {}",
+                    err.to_string()
+                );
+            }
+            return Err(Error::ChildValidation {
+                name: self.name.clone(),
+                report,
+            });
+        }
+        if child_bdev.block_len() > self.block_len() {
+            warn!(
+                "{:?}: child {} has {} byte blocks, larger than \
+                the {} byte blocks of the nexus: unaligned \
+                writes to it are read-modify-writes",
+                self,
+                uri,
+                child_bdev.block_len(),
+                self.block_len()
+            );
+        }
+
         let mut child = NexusChild::new(
             uri.to_owned(),
             self.nexus_name().to_owned(),
@@ -196,13 +265,20 @@ impl<'n> Nexus<'n> {
             // data and metadata must be validated. The child
             // will be added and marked as faulted, once the rebuild has
             // completed the device can transition to online
-            if let Err(e) = child.reservation_acquire(&self.nvme_params).await {
+            let uuid = self.uuid().to_string();
+            if let Err(e) = child.lease_acquire(&uuid).await {
+                res = Err(e);
+            } else if let Err(e) =
+                child.reservation_acquire(&self.nvme_params).await
+            {
+                child.lease_release(&uuid).await;
                 res = Err(e);
             }
         }
 
         match res {
             Ok(child_uri) => {
+                addition.opened().await;
                 let child_state = child.state();
 
                 // Register event listener for newly added child.
@@ -240,6 +316,8 @@ impl<'n> Nexus<'n> {
 
     /// Destroy child with given uri.
     /// If the child does not exist the method returns success.
+    /// A healthy child is only detached if the nexus has an undo window,
+    /// see `nexus_child_undo`, and removing it again destroys it.
     pub async fn remove_child(
         mut self: Pin<&mut Self>,
         uri: &str,
@@ -248,6 +326,10 @@ impl<'n> Nexus<'n> {
 
         self.check_nexus_operation(NexusOperation::ReplicaRemove)?;
 
+        if self.as_mut().close_detached_child(uri).await {
+            return Ok(());
+        }
+
         if self.child_count() == 1 {
             return Err(Error::DestroyLastChild {
                 name: self.name.clone(),
@@ -284,6 +366,18 @@ impl<'n> Nexus<'n> {
             Some(val) => val,
         };
 
+        match self.as_mut().detach_child(idx).await {
+            Ok(false) => {}
+            res => {
+                paused.resume().await;
+                return res.map(|_| ());
+            }
+        }
+
+        self.child_at(idx)
+            .lease_release(&self.uuid().to_string())
+            .await;
+
         let res = unsafe {
             self.as_mut().child_at_mut(idx).close().await.map_err(|e| {
                 Error::CloseChild {
@@ -336,6 +430,70 @@ impl<'n> Nexus<'n> {
         res
     }
 
+    /// Administratively take a child out of the IO path, e.g. to perform
+    /// maintenance on its backing device. Unlike `offline_child`, the child
+    /// device is kept open and the child stays attached to the nexus. The IO
+    /// in flight is flushed by pausing the nexus while the IO channels are
+    /// reconfigured. Bringing the child back with `online_child` rebuilds it,
+    /// so that it catches up with the writes it missed.
+    pub async fn take_child_offline(
+        mut self: Pin<&mut Self>,
+        child_uri: &str,
+    ) -> Result<NexusStatus, Error> {
+        self.check_nexus_operation(NexusOperation::ReplicaOffline)?;
+
+        info!(
+            "{:?}: administrative offline child request: '{}'",
+            self, child_uri
+        );
+
+        match self.as_mut().child_mut(child_uri)?.state() {
+            ChildState::Open => {}
+            ChildState::Faulted(Reason::Offline) => {
+                warn!("{:?}: child '{}' is already offline", self, child_uri);
+                return Ok(self.status());
+            }
+            state => {
+                return Err(Error::ChildNotOpen {
+                    child: child_uri.to_owned(),
+                    name: self.name.clone(),
+                    state: state.to_string(),
+                })
+            }
+        }
+
+        if !self
+            .children_iter()
+            .any(|c| c.uri() != child_uri && c.is_healthy())
+        {
+            return Err(Error::OfflineLastHealthyChild {
+                child: child_uri.to_owned(),
+                name: self.name.clone(),
+            });
+        }
+
+        // pausing waits for the IO in flight to complete
+        self.as_mut().pause().await?;
+
+        self.as_mut()
+            .child_mut(child_uri)?
+            .fault(Reason::Offline)
+            .await;
+        self.reconfigure(DrEvent::ChildOffline).await;
+
+        // the child must not be considered healthy anymore, before any
+        // further write is issued
+        self.persist(PersistOp::Update {
+            child_uri: child_uri.to_owned(),
+            child_state: ChildState::Faulted(Reason::Offline),
+        })
+        .await;
+
+        self.as_mut().resume().await?;
+
+        Ok(self.status())
+    }
+
     /// Faults a child device and reconfigures the IO channels.
     pub async fn fault_child(
         mut self: Pin<&mut Self>,
@@ -407,7 +565,9 @@ impl<'n> Nexus<'n> {
         if let Some(dev_name) = ch.get_device_name() {
             self.as_mut().retire_child_device(
                 &dev_name,
-                Reason::IoError,
+                FaultDetail::Admin {
+                    action: AdminAction::Retire,
+                },
                 false,
             );
         } else {
@@ -419,7 +579,8 @@ impl<'n> Nexus<'n> {
 
     /// online a child and reconfigure the IO channels. The child is already
     /// registered, but simply not opened. This can be required in case where
-    /// a child is misbehaving.
+    /// a child is misbehaving. A child taken offline administratively is
+    /// still open, it is only rebuilt.
     pub async fn online_child(
         mut self: Pin<&mut Self>,
         child_uri: &str,
@@ -433,13 +594,20 @@ impl<'n> Nexus<'n> {
 
         let child = self.as_mut().child_mut(child_uri)?;
 
-        child
-            .online(nexus_size)
-            .await
-            .context(nexus_err::OnlineChild {
-                child: child_uri.to_owned(),
-                name: nexus_name,
-            })?;
+        if child.state() == ChildState::Faulted(Reason::Offline) {
+            child.transition(
+                ChildState::Faulted(Reason::OutOfSync),
+                "online requested by client",
+            );
+        } else {
+            child
+                .online(nexus_size)
+                .await
+                .context(nexus_err::OnlineChild {
+                    child: child_uri.to_owned(),
+                    name: nexus_name,
+                })?;
+        }
 
         self.as_mut().start_rebuild(child_uri).await.map(|_| {})?;
 
@@ -448,6 +616,9 @@ impl<'n> Nexus<'n> {
 
     /// Close each child that belongs to this nexus.
     pub(crate) async fn close_children(mut self: Pin<&mut Self>) {
+        self.as_mut().close_detached_children().await;
+        self.release_leases().await;
+        self.purge_shard_handles().await;
         let futures =
             unsafe { self.as_mut().children_iter_mut().map(|c| c.close()) };
         let results = join_all(futures).await;
@@ -474,8 +645,14 @@ impl<'n> Nexus<'n> {
         let mut error = None;
         let evt_listener = self.as_mut().get_event_sink();
 
+        // the children whose connection is deferred are opened once
+        // connected
         unsafe {
-            for child in self.as_mut().children_iter_mut() {
+            for child in self
+                .as_mut()
+                .children_iter_mut()
+                .filter(|c| !c.is_deferred())
+            {
                 match child.open(size, ChildState::Open) {
                     Ok(_) => {
                         child.set_event_listener(evt_listener.clone());
@@ -513,10 +690,19 @@ impl<'n> Nexus<'n> {
             });
         }
 
-        // acquire a write exclusive reservation on all children,
-        // if any one fails, close all children.
+        // take the lease of the nexus and acquire a write exclusive
+        // reservation on all children, if any one fails, close all children.
+        let uuid = self.uuid().to_string();
         let mut write_ex_err: Result<(), Error> = Ok(());
-        for child in self.children_iter() {
+        for child in self.children_iter().filter(|c| !c.is_deferred()) {
+            if let Err(error) = child.lease_acquire(&uuid).await {
+                write_ex_err = Err(Error::ChildLeaseFailed {
+                    source: error,
+                    child: child.uri().to_owned(),
+                    name: self.name.clone(),
+                });
+                break;
+            }
             if let Err(error) =
                 child.reservation_acquire(&self.nvme_params).await
             {
@@ -530,6 +716,7 @@ impl<'n> Nexus<'n> {
         }
 
         if let Err(error) = write_ex_err {
+            self.release_leases().await;
             unsafe {
                 for child in self.as_mut().children_iter_mut() {
                     if let Err(error) = child.close().await {
@@ -546,7 +733,7 @@ impl<'n> Nexus<'n> {
 
         let mut new_alignment = self.alignment();
 
-        for child in self.children_iter() {
+        for child in self.children_iter().filter(|c| !c.is_deferred()) {
             let alignment = child.get_device().as_ref().unwrap().alignment();
             if new_alignment < alignment {
                 info!(
@@ -596,12 +783,12 @@ impl<'n> Nexus<'n> {
     }
 
     /// The nexus is allowed to be smaller then the underlying child devices
-    /// this function returns the smallest blkcnt of all online children as
-    /// they MAY vary in size.
-    pub(crate) fn min_num_blocks(&self) -> Option<u64> {
+    /// this function returns the smallest size, in bytes, of all online
+    /// children as they MAY vary in size and block size.
+    pub(crate) fn min_size(&self) -> Option<u64> {
         self.children_iter()
             .filter(|c| c.state() == ChildState::Open)
-            .map(|c| c.get_device().unwrap().num_blocks())
+            .map(|c| c.get_device().unwrap().size_in_bytes())
             .reduce(min)
     }
 
@@ -673,6 +860,16 @@ impl<'n> DeviceEventListener for Nexus<'n> {
                 ));
             }
             DeviceEventType::AdminCommandCompletionFailed => {
+                if self.retire_policy.get().keep_last_healthy
+                    && self.is_last_healthy_child_device(dev_name)
+                {
+                    warn!(
+                        "{:?}: admin command completion failure event: \
+                        not retiring the last healthy child '{}'",
+                        self, dev_name
+                    );
+                    return;
+                }
                 info!(
                     "{:?}: admin command completion failure event: \
                     retiring child '{}'",
@@ -680,7 +877,7 @@ impl<'n> DeviceEventListener for Nexus<'n> {
                 );
                 self.retire_child_device(
                     dev_name,
-                    Reason::AdminCommandFailed,
+                    FaultDetail::AdminCommandFailed,
                     false,
                 );
             }
@@ -727,18 +924,13 @@ impl<'n> Nexus<'n> {
     fn child_io_faulted(
         self: Pin<&mut Self>,
         device_name: &str,
-        reason: Reason,
+        detail: FaultDetail,
     ) -> bool {
         match self.lookup_child_device(device_name) {
             Some(c) => {
-                debug!("{:?}: faulting with {}...", c, reason);
+                debug!("{:?}: faulting with {}...", c, detail);
 
-                if Ok(ChildState::Open)
-                    == c.state.compare_exchange(
-                        ChildState::Open,
-                        ChildState::Faulted(reason),
-                    )
-                {
+                if c.fault_if_open(detail) {
                     warn!("{:?}: I/O faulted; will retire", c);
                     true
                 } else {
@@ -761,11 +953,11 @@ impl<'n> Nexus<'n> {
     pub(crate) fn retire_child_device(
         mut self: Pin<&mut Self>,
         child_device: &str,
-        reason: Reason,
+        detail: FaultDetail,
         retry: bool,
     ) {
         // check if this child needs to be retired
-        let need_retire = self.as_mut().child_io_faulted(child_device, reason);
+        let need_retire = self.as_mut().child_io_faulted(child_device, detail);
 
         // The child state was not faulted yet, so this is the first I/O
         // to this child for which we encountered an error.
@@ -805,7 +997,7 @@ impl<'n> Nexus<'n> {
     }
 
     /// Retire a child for this nexus.
-    async fn child_retire_routine(
+    pub(super) async fn child_retire_routine(
         nexus_name: String,
         child_device: String,
         retry: bool,
@@ -864,6 +1056,7 @@ impl<'n> Nexus<'n> {
         warn!("{:?}: retiring child device '{}'...", self, device_name);
 
         self.disconnect_all_channels(device_name.clone()).await?;
+        self.write_ack.tracker.forget(&device_name);
 
         debug!("{:?}: pausing...", self);
         self.as_mut().pause().await?;
@@ -958,6 +1151,7 @@ impl<'n> Nexus<'n> {
 
             r.await
                 .expect("disconnect_all_children() sender already dropped");
+            self.purge_shard_handles().await;
 
             info!(
                 "{:?}: device '{}' disconnected from all I/O channels",
@@ -7,13 +7,17 @@ use super::{
     ChildError,
     NbdError,
     NexusPauseState,
+    ValidationReport,
 };
 
 use crate::{
     bdev_api::BdevError,
     core::{CoreError, VerboseError},
+    jsonrpc::{Code as RpcCode, RpcErrorCode},
     rebuild::RebuildError,
+    store::store_defs::StoreError,
     subsys::NvmfError,
+    target::vhost::Error as VhostError,
 };
 
 /// Common errors for nexus basic operations and child operations
@@ -52,8 +56,18 @@ pub enum Error {
     NotSharedNvmf { name: String },
     #[snafu(display("Failed to share nexus over NBD {}", name))]
     ShareNbdNexus { source: NbdError, name: String },
+    #[snafu(display("Failed to attach nexus {} locally", name))]
+    ShareLocalNexus { source: NbdError, name: String },
     #[snafu(display("Failed to share nvmf nexus {}", name))]
     ShareNvmfNexus { source: CoreError, name: String },
+    #[snafu(display("Failed to share iSCSI nexus {}", name))]
+    ShareIscsiNexus { source: CoreError, name: String },
+    #[snafu(display("Failed to share vfio-user nexus {}", name))]
+    ShareVfioUserNexus { source: CoreError, name: String },
+    #[snafu(display("Failed to attach nexus {} over vhost", name))]
+    AttachVhost { source: VhostError, name: String },
+    #[snafu(display("Failed to detach nexus {} from vhost", name))]
+    DetachVhost { source: VhostError, name: String },
     #[snafu(display("Failed to unshare nexus {}", name))]
     UnshareNexus { source: CoreError, name: String },
     #[snafu(display(
@@ -83,14 +97,31 @@ pub enum Error {
         num_blocks: u64,
         block_size: u64,
     },
-    #[snafu(display("Children of nexus {} have mixed block sizes", name))]
-    MixedBlockSizes { name: String },
+    #[snafu(display(
+        "Children of nexus {} have incompatible block sizes: child {} has {} \
+        byte blocks, expected a divisor or multiple of {}",
+        name,
+        child,
+        block_size,
+        expected
+    ))]
+    MixedBlockSizes {
+        name: String,
+        child: String,
+        block_size: u64,
+        expected: u64,
+    },
     #[snafu(display(
         "Child {} of nexus {} has incompatible size or block size",
         child,
         name
     ))]
     ChildGeometry { child: String, name: String },
+    #[snafu(display("Children of nexus {} are not valid: {}", name, report))]
+    ChildValidation {
+        name: String,
+        report: ValidationReport,
+    },
     #[snafu(display("Child {} of nexus {} cannot be found", child, name))]
     ChildMissing { child: String, name: String },
     #[snafu(display("Child {} of nexus {} has no error store", child, name))]
@@ -105,6 +136,16 @@ pub enum Error {
         child: String,
         name: String,
     },
+    #[snafu(display(
+        "Failed to take the lease of child {} of nexus {}",
+        child,
+        name
+    ))]
+    ChildLeaseFailed {
+        source: ChildError,
+        child: String,
+        name: String,
+    },
     #[snafu(display("Failed to open child {} of nexus {}", child, name))]
     OpenChild {
         source: ChildError,
@@ -147,6 +188,64 @@ pub enum Error {
         name
     ))]
     FaultingLastHealthyChild { child: String, name: String },
+    #[snafu(display(
+        "Cannot take the last healthy child {} of nexus {} offline",
+        child,
+        name
+    ))]
+    OfflineLastHealthyChild { child: String, name: String },
+    #[snafu(display(
+        "Child {} of nexus {} is not open but {}",
+        child,
+        name,
+        state
+    ))]
+    ChildNotOpen {
+        child: String,
+        name: String,
+        state: String,
+    },
+    #[snafu(display(
+        "Failed to set up the checksum region of child {} of nexus {}: {}",
+        child,
+        name,
+        reason
+    ))]
+    ChecksumRegion {
+        child: String,
+        name: String,
+        reason: String,
+    },
+    #[snafu(display(
+        "Failed to set up the write-intent log of child {} of nexus {}: {}",
+        child,
+        name,
+        reason
+    ))]
+    WriteIntentLog {
+        child: String,
+        name: String,
+        reason: String,
+    },
+    #[snafu(display(
+        "Failed to update the metadata region of nexus {}: {}",
+        name,
+        reason
+    ))]
+    MetadataRegion { name: String, reason: String },
+    #[snafu(display(
+        "Failed to rotate the key of child {} of nexus {}: {}",
+        child,
+        name,
+        reason
+    ))]
+    RekeyChild {
+        child: String,
+        name: String,
+        reason: String,
+    },
+    #[snafu(display("Cannot share nexus {}: {}", name, reason))]
+    TenantQuota { name: String, reason: String },
     #[snafu(display("Failed to destroy child {} of nexus {}", child, name))]
     DestroyChild {
         source: BdevError,
@@ -236,6 +335,36 @@ pub enum Error {
     InvalidReservation { reservation: u8 },
     #[snafu(display("failed to update share properties {}", name))]
     UpdateShareProperties { source: CoreError, name: String },
+    #[snafu(display("Failed to acquire ownership of nexus {}", name))]
+    AcquireOwnership { source: StoreError, name: String },
+    #[snafu(display(
+        "Persisted information of nexus {} cannot be used: {}",
+        name,
+        source
+    ))]
+    PersistedSchema { source: StoreError, name: String },
+    #[snafu(display(
+        "Failed to record the addition of child {} to nexus {}",
+        child,
+        name
+    ))]
+    RecordChildAddition {
+        source: StoreError,
+        child: String,
+        name: String,
+    },
+    #[snafu(display("Name {} is already in use", name))]
+    NameExists { name: String },
+    #[snafu(display("Nexus group {} does not exist", name))]
+    GroupNotFound { name: String },
+    #[snafu(display("Nexus group {} already exists", name))]
+    GroupExists { name: String },
+    #[snafu(display("Failed to snapshot nexus {}: {}", name, reason))]
+    GroupSnapshot { name: String, reason: String },
+    #[snafu(display("Cannot create nexus {}: the node is drained", name))]
+    NodeDraining { name: String },
+    #[snafu(display("Nexus {} is claimed by SPDK module {}", name, module))]
+    NexusClaimed { name: String, module: String },
 }
 
 impl From<NvmfError> for Error {
@@ -255,6 +384,15 @@ impl From<Error> for tonic::Status {
             Error::InvalidUuid {
                 ..
             } => Status::invalid_argument(e.to_string()),
+            Error::NameExists {
+                ..
+            } => Status::already_exists(e.to_string()),
+            Error::NodeDraining {
+                ..
+            } => Status::unavailable(e.to_string()),
+            Error::NexusClaimed {
+                ..
+            } => Status::failed_precondition(e.to_string()),
             Error::InvalidKey {
                 ..
             } => Status::invalid_argument(e.to_string()),
@@ -276,6 +414,9 @@ impl From<Error> for tonic::Status {
             Error::ChildGeometry {
                 ..
             } => Status::invalid_argument(e.to_string()),
+            Error::ChildValidation {
+                ..
+            } => Status::invalid_argument(e.to_string()),
             Error::OpenChild {
                 ..
             } => Status::invalid_argument(e.to_string()),
@@ -294,7 +435,95 @@ impl From<Error> for tonic::Status {
             Error::ChildTooSmall {
                 ..
             } => Status::invalid_argument(e.to_string()),
+            Error::OfflineLastHealthyChild {
+                ..
+            } => Status::failed_precondition(e.to_string()),
+            Error::ChildNotOpen {
+                ..
+            } => Status::failed_precondition(e.to_string()),
+            Error::ChecksumRegion {
+                ..
+            } => Status::failed_precondition(e.to_string()),
+            Error::WriteIntentLog {
+                ..
+            } => Status::failed_precondition(e.to_string()),
+            Error::MetadataRegion {
+                ..
+            } => Status::failed_precondition(e.to_string()),
+            Error::RekeyChild {
+                ..
+            } => Status::failed_precondition(e.to_string()),
+            Error::TenantQuota {
+                ..
+            } => Status::resource_exhausted(e.to_string()),
+            Error::ChildLeaseFailed {
+                ..
+            } => Status::failed_precondition(e.to_string()),
+            Error::PersistedSchema {
+                ..
+            } => Status::failed_precondition(e.to_string()),
+            Error::AcquireOwnership {
+                source:
+                    StoreError::OwnershipConflict {
+                        ..
+                    },
+                ..
+            } => Status::failed_precondition(e.to_string()),
             e => Status::new(Code::Internal, e.verbose()),
         }
     }
 }
+
+impl RpcErrorCode for Error {
+    fn rpc_error_code(&self) -> RpcCode {
+        match self {
+            Error::NexusNotFound {
+                ..
+            } => RpcCode::NotFound,
+            Error::ChildNotFound {
+                ..
+            } => RpcCode::NotFound,
+            Error::GroupNotFound {
+                ..
+            } => RpcCode::NotFound,
+            Error::GroupExists {
+                ..
+            } => RpcCode::AlreadyExists,
+            Error::NameExists {
+                ..
+            } => RpcCode::AlreadyExists,
+            Error::NodeDraining {
+                ..
+            } => RpcCode::InvalidRequest,
+            Error::NexusClaimed {
+                ..
+            } => RpcCode::InvalidRequest,
+            Error::TenantQuota {
+                ..
+            } => RpcCode::InvalidRequest,
+            Error::RekeyChild {
+                ..
+            } => RpcCode::InvalidRequest,
+            Error::InvalidUuid {
+                ..
+            } => RpcCode::InvalidParams,
+            Error::InvalidKey {
+                ..
+            } => RpcCode::InvalidParams,
+            Error::DetachVhost {
+                source:
+                    VhostError::ControllerNotFound {
+                        ..
+                    },
+                ..
+            } => RpcCode::NotFound,
+            Error::InvalidArguments {
+                ..
+            } => RpcCode::InvalidParams,
+            Error::ChildValidation {
+                ..
+            } => RpcCode::InvalidParams,
+            _ => RpcCode::InternalError,
+        }
+    }
+}
@@ -11,6 +11,7 @@ use super::{
     Error,
     Nexus,
     Reason,
+    RebuildSource,
 };
 
 use crate::{
@@ -75,15 +76,12 @@ impl<'n> Nexus<'n> {
         let name = self.name.clone();
         trace!("{}: start rebuild request for {}", name, child_uri);
 
-        let src_child_uri = match self
-            .children_iter()
-            .find(|c| c.state() == ChildState::Open && c.uri() != child_uri)
-        {
-            Some(child) => Ok(child.uri().to_owned()),
-            None => Err(Error::NoRebuildSource {
+        let source = self.select_rebuild_source(child_uri).ok_or(
+            Error::NoRebuildSource {
                 name: name.clone(),
-            }),
-        }?;
+            },
+        )?;
+        let src_child_uri = source.uri.clone();
 
         let dst_child_uri = match self.lookup_child(child_uri) {
             Some(c) if c.state() == ChildState::Faulted(Reason::OutOfSync) => {
@@ -107,8 +105,20 @@ impl<'n> Nexus<'n> {
             }),
         }?;
 
-        self.as_mut()
-            .create_rebuild_job(&src_child_uri, &dst_child_uri)?;
+        // the rebuild copies the data but not the checksums of the blocks
+        self.checksum_prepare_child(&dst_child_uri).await?;
+        self.write_intent_prepare_child(&dst_child_uri).await?;
+        self.metadata_prepare_child(&dst_child_uri).await?;
+
+        info!(
+            "{:?}: rebuilding '{}' from '{}': {}",
+            self, dst_child_uri, src_child_uri, source.reason
+        );
+        self.as_mut().create_rebuild_job(
+            &src_child_uri,
+            &dst_child_uri,
+            source,
+        )?;
 
         // We're now rebuilding the `dst_child` which means it HAS to become an
         // active participant in the frontend nexus bdev for Writes.
@@ -133,7 +143,10 @@ impl<'n> Nexus<'n> {
         self: Pin<&mut Self>,
         src_child_uri: &str,
         dst_child_uri: &str,
+        source: RebuildSource,
     ) -> Result<(), Error> {
+        // a rotation of the key of a child resumes where it was left
+        let resume = self.rekey_resume_blocks(dst_child_uri);
         RebuildJob::new(
             &self.name,
             src_child_uri,
@@ -148,6 +161,11 @@ impl<'n> Nexus<'n> {
                 });
             },
         )
+        .map(|mut job| {
+            job.source_selection = Some(source);
+            job.skip(resume);
+            job
+        })
         .and_then(RebuildJob::store)
         .context(nexus_err::CreateRebuild {
             child: dst_child_uri.to_owned(),
@@ -368,7 +386,7 @@ impl<'n> Nexus<'n> {
 
         match job.state() {
             RebuildState::Completed => {
-                dst_child.set_state(ChildState::Open);
+                dst_child.transition(ChildState::Open, "rebuild completed");
                 info!("Child {} has been rebuilt successfully", child_uri);
                 let child_uri = child_uri.to_owned();
                 let child_state = dst_child.state();
@@ -0,0 +1,543 @@
+//! Children whose block size differs from the block size of the nexus.
+//!
+//! A nexus exposes the largest block size of its children, so that its IOs
+//! are aligned on the blocks of all of them: the IOs of a child with smaller
+//! blocks are merely scaled. A child with larger blocks than the nexus, as
+//! when a 4K child is added to a nexus of 512 bytes blocks, may receive IOs
+//! which are not aligned on its blocks: such reads go through a bounce
+//! buffer, and such writes become read-modify-writes of the child blocks
+//! they partially cover. The penalty is reported in the statistics of the
+//! child. The writes to such a child, aligned or not, lock the child blocks
+//! they cover for as long as they are in flight, so that a read-modify-write
+//! never writes back a block another write has changed meanwhile.
+
+use std::{
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use async_trait::async_trait;
+use futures::channel::oneshot;
+use serde::Serialize;
+use spdk_rs::{DmaBuf, DmaError, IoVec};
+
+use super::nexus_checksum::{gather, scatter, StripeGuard};
+use crate::core::{
+    BlockDevice,
+    BlockDeviceHandle,
+    CoreError,
+    GenericStatusCode,
+    IoCompletionCallback,
+    IoCompletionCallbackArg,
+    IoCompletionStatus,
+    NvmeStatus,
+    Reactors,
+    ReadMode,
+};
+
+/// Number of locks serialising the writes to the blocks of a child.
+const LOCK_STRIPES: usize = 64;
+
+/// Statistics of the IOs of a child which are not aligned on its blocks.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct RmwStats {
+    /// Number of reads done through a bounce buffer.
+    pub unaligned_reads: u64,
+    /// Number of writes, write zeroes and unmaps done as read-modify-writes.
+    pub read_modify_writes: u64,
+}
+
+/// State shared by the IO handles of a child on all cores. The writes to a
+/// child block are serialised by striped locks, as a read-modify-write of
+/// the block racing with another write to it would otherwise lose the
+/// other write.
+pub(crate) struct RmwState {
+    unaligned_reads: AtomicU64,
+    read_modify_writes: AtomicU64,
+    stripes: Vec<AtomicBool>,
+}
+
+impl Default for RmwState {
+    fn default() -> Self {
+        Self {
+            unaligned_reads: AtomicU64::new(0),
+            read_modify_writes: AtomicU64::new(0),
+            stripes: (0 .. LOCK_STRIPES)
+                .map(|_| AtomicBool::new(false))
+                .collect(),
+        }
+    }
+}
+
+impl RmwState {
+    pub(crate) fn stats(&self) -> RmwStats {
+        RmwStats {
+            unaligned_reads: self.unaligned_reads.load(Ordering::Relaxed),
+            read_modify_writes: self.read_modify_writes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Lock the given range of child blocks.
+    async fn lock(&self, first: u64, last: u64) -> StripeGuard<'_> {
+        StripeGuard::lock(&self.stripes, first ..= last).await
+    }
+}
+
+/// An IO not aligned on the blocks of the child.
+enum UnalignedIo {
+    Read(*const libc::iovec, usize),
+    Write(*const libc::iovec, usize),
+    /// Write zeroes and unmaps, whose blocks must read back as zeroes as on
+    /// the other children.
+    Zeroes,
+}
+
+/// A write aligned on the blocks of the child, submitted as it is once the
+/// child blocks it covers are locked.
+enum AlignedWrite {
+    Write(*mut IoVec, i32),
+    Unmap,
+    WriteZeroes,
+}
+
+/// IO handle of a child translating the blocks of the nexus into blocks of
+/// the child.
+pub(super) struct BlockAdapter {
+    inner: Rc<Box<dyn BlockDeviceHandle>>,
+    /// Block size of the nexus.
+    block_len: u64,
+    /// Block size of the child.
+    child_block_len: u64,
+    state: Arc<RmwState>,
+}
+
+impl BlockAdapter {
+    /// Returns the handle of a child for the IOs of a nexus with the given
+    /// block size, wrapped if the block size of the child differs.
+    pub(super) fn wrap(
+        hdl: Box<dyn BlockDeviceHandle>,
+        block_len: u64,
+        state: &Arc<RmwState>,
+    ) -> Box<dyn BlockDeviceHandle> {
+        let child_block_len = hdl.get_device().block_len();
+        if block_len == 0 || child_block_len == block_len {
+            return hdl;
+        }
+        Box::new(Self {
+            inner: Rc::new(hdl),
+            block_len,
+            child_block_len,
+            state: Arc::clone(state),
+        })
+    }
+
+    /// Returns the child blocks of the given nexus blocks, if they are
+    /// aligned on the blocks of the child.
+    fn child_blocks(&self, offset: u64, num_blocks: u64) -> Option<(u64, u64)> {
+        if self.block_len > self.child_block_len {
+            let ratio = self.block_len / self.child_block_len;
+            return Some((offset * ratio, num_blocks * ratio));
+        }
+        let ratio = self.child_block_len / self.block_len;
+        if offset % ratio == 0 && num_blocks % ratio == 0 {
+            Some((offset / ratio, num_blocks / ratio))
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if the IOs of the nexus may not be aligned on the blocks
+    /// of the child, in which case the writes to the child lock its blocks.
+    fn unaligned(&self) -> bool {
+        self.child_block_len > self.block_len
+    }
+
+    /// Submit a write aligned on the blocks of the child once the blocks it
+    /// covers are locked, calling back once it has completed.
+    fn submit_locked(
+        &self,
+        write: AlignedWrite,
+        offset: u64,
+        num_blocks: u64,
+        cb: IoCompletionCallback,
+        cb_arg: IoCompletionCallbackArg,
+    ) -> Result<(), CoreError> {
+        let inner = Rc::clone(&self.inner);
+        let state = Arc::clone(&self.state);
+
+        Reactors::current().send_future(async move {
+            let _guard = state.lock(offset, offset + num_blocks - 1).await;
+            let (sender, receiver) = oneshot::channel::<IoCompletionStatus>();
+            let ctx = Box::into_raw(Box::new(sender)).cast();
+            let result = match write {
+                AlignedWrite::Write(iov, iovcnt) => inner.writev_blocks(
+                    iov,
+                    iovcnt,
+                    offset,
+                    num_blocks,
+                    locked_completion,
+                    ctx,
+                ),
+                AlignedWrite::Unmap => inner.unmap_blocks(
+                    offset,
+                    num_blocks,
+                    locked_completion,
+                    ctx,
+                ),
+                AlignedWrite::WriteZeroes => inner.write_zeroes(
+                    offset,
+                    num_blocks,
+                    locked_completion,
+                    ctx,
+                ),
+            };
+
+            let status = match result {
+                Ok(()) => receiver.await.unwrap_or_else(|_| {
+                    IoCompletionStatus::NvmeError(NvmeStatus::Generic(
+                        GenericStatusCode::InternalDeviceError,
+                    ))
+                }),
+                Err(error) => {
+                    drop(unsafe {
+                        Box::from_raw(
+                            ctx as *mut oneshot::Sender<IoCompletionStatus>,
+                        )
+                    });
+                    error!(
+                        "{}: locked write at block {} failed: {}",
+                        inner.get_device().device_name(),
+                        offset,
+                        error
+                    );
+                    IoCompletionStatus::NvmeError(NvmeStatus::Generic(
+                        GenericStatusCode::InternalDeviceError,
+                    ))
+                }
+            };
+            cb(inner.get_device(), status, cb_arg);
+        });
+        Ok(())
+    }
+
+    /// Submit an IO not aligned on the blocks of the child, calling back once
+    /// it has completed.
+    fn submit_unaligned(
+        &self,
+        io: UnalignedIo,
+        offset: u64,
+        num_blocks: u64,
+        cb: IoCompletionCallback,
+        cb_arg: IoCompletionCallbackArg,
+    ) -> Result<(), CoreError> {
+        let inner = Rc::clone(&self.inner);
+        let state = Arc::clone(&self.state);
+        let start = offset * self.block_len;
+        let end = (offset + num_blocks) * self.block_len;
+
+        Reactors::current().send_future(async move {
+            let status =
+                match unaligned_io(&**inner, &state, io, start, end).await {
+                    Ok(()) => IoCompletionStatus::Success,
+                    Err(error) => {
+                        error!(
+                            "{}: unaligned IO at byte {} failed: {}",
+                            inner.get_device().device_name(),
+                            start,
+                            error
+                        );
+                        IoCompletionStatus::NvmeError(NvmeStatus::Generic(
+                            GenericStatusCode::InternalDeviceError,
+                        ))
+                    }
+                };
+            cb(inner.get_device(), status, cb_arg);
+        });
+        Ok(())
+    }
+}
+
+/// Completion of a write submitted with the child blocks it covers locked.
+fn locked_completion(
+    _device: &dyn BlockDevice,
+    status: IoCompletionStatus,
+    ctx: IoCompletionCallbackArg,
+) {
+    let sender = unsafe {
+        Box::from_raw(ctx as *mut oneshot::Sender<IoCompletionStatus>)
+    };
+    sender.send(status).ok();
+}
+
+/// Do an IO of the given byte range, not aligned on the blocks of the
+/// child, through a bounce buffer covering the child blocks of the range.
+async fn unaligned_io(
+    hdl: &dyn BlockDeviceHandle,
+    state: &RmwState,
+    io: UnalignedIo,
+    start: u64,
+    end: u64,
+) -> Result<(), CoreError> {
+    let child_block_len = hdl.get_device().block_len();
+    let first = start / child_block_len;
+    let last = (end - 1) / child_block_len;
+    let byte_offset = first * child_block_len;
+    let skip = (start - byte_offset) as usize;
+    let len = (end - start) as usize;
+
+    let size = (last - first + 1) * child_block_len;
+    let mut buf =
+        hdl.dma_malloc(size)
+            .map_err(|_| CoreError::DmaAllocationFailed {
+                size,
+            })?;
+
+    if let UnalignedIo::Read(iovs, count) = io {
+        hdl.read_at(byte_offset, &mut buf).await?;
+        scatter(&buf.as_slice()[skip .. skip + len], iovs, count);
+        state.unaligned_reads.fetch_add(1, Ordering::Relaxed);
+        return Ok(());
+    }
+
+    let _guard = state.lock(first, last).await;
+    hdl.read_at(byte_offset, &mut buf).await?;
+    let data = &mut buf.as_mut_slice()[skip .. skip + len];
+    match io {
+        UnalignedIo::Write(iovs, count) => {
+            data.copy_from_slice(&gather(iovs, count))
+        }
+        _ => data.fill(0),
+    }
+    hdl.write_at(byte_offset, &buf).await?;
+    state.read_modify_writes.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+}
+
+#[async_trait(?Send)]
+impl BlockDeviceHandle for BlockAdapter {
+    fn get_device(&self) -> &dyn BlockDevice {
+        self.inner.get_device()
+    }
+
+    fn dma_malloc(&self, size: u64) -> Result<DmaBuf, DmaError> {
+        self.inner.dma_malloc(size)
+    }
+
+    async fn read_at(
+        &self,
+        offset: u64,
+        buffer: &mut DmaBuf,
+    ) -> Result<u64, CoreError> {
+        self.inner.read_at(offset, buffer).await
+    }
+
+    fn set_read_mode(&mut self, mode: ReadMode) {
+        if let Some(inner) = Rc::get_mut(&mut self.inner) {
+            inner.set_read_mode(mode);
+        }
+    }
+
+    async fn write_at(
+        &self,
+        offset: u64,
+        buffer: &DmaBuf,
+    ) -> Result<u64, CoreError> {
+        self.inner.write_at(offset, buffer).await
+    }
+
+    fn readv_blocks(
+        &self,
+        iov: *mut IoVec,
+        iovcnt: i32,
+        offset_blocks: u64,
+        num_blocks: u64,
+        cb: IoCompletionCallback,
+        cb_arg: IoCompletionCallbackArg,
+    ) -> Result<(), CoreError> {
+        match self.child_blocks(offset_blocks, num_blocks) {
+            Some((offset, num)) => self
+                .inner
+                .readv_blocks(iov, iovcnt, offset, num, cb, cb_arg),
+            None => self.submit_unaligned(
+                UnalignedIo::Read(iov as *const libc::iovec, iovcnt as usize),
+                offset_blocks,
+                num_blocks,
+                cb,
+                cb_arg,
+            ),
+        }
+    }
+
+    fn writev_blocks(
+        &self,
+        iov: *mut IoVec,
+        iovcnt: i32,
+        offset_blocks: u64,
+        num_blocks: u64,
+        cb: IoCompletionCallback,
+        cb_arg: IoCompletionCallbackArg,
+    ) -> Result<(), CoreError> {
+        match self.child_blocks(offset_blocks, num_blocks) {
+            Some((offset, num)) if self.unaligned() => self.submit_locked(
+                AlignedWrite::Write(iov, iovcnt),
+                offset,
+                num,
+                cb,
+                cb_arg,
+            ),
+            Some((offset, num)) => self
+                .inner
+                .writev_blocks(iov, iovcnt, offset, num, cb, cb_arg),
+            None => self.submit_unaligned(
+                UnalignedIo::Write(iov as *const libc::iovec, iovcnt as usize),
+                offset_blocks,
+                num_blocks,
+                cb,
+                cb_arg,
+            ),
+        }
+    }
+
+    fn reset(
+        &self,
+        cb: IoCompletionCallback,
+        cb_arg: IoCompletionCallbackArg,
+    ) -> Result<(), CoreError> {
+        self.inner.reset(cb, cb_arg)
+    }
+
+    fn unmap_blocks(
+        &self,
+        offset_blocks: u64,
+        num_blocks: u64,
+        cb: IoCompletionCallback,
+        cb_arg: IoCompletionCallbackArg,
+    ) -> Result<(), CoreError> {
+        match self.child_blocks(offset_blocks, num_blocks) {
+            Some((offset, num)) if self.unaligned() => {
+                self.submit_locked(AlignedWrite::Unmap, offset, num, cb, cb_arg)
+            }
+            Some((offset, num)) => {
+                self.inner.unmap_blocks(offset, num, cb, cb_arg)
+            }
+            None => self.submit_unaligned(
+                UnalignedIo::Zeroes,
+                offset_blocks,
+                num_blocks,
+                cb,
+                cb_arg,
+            ),
+        }
+    }
+
+    fn write_zeroes(
+        &self,
+        offset_blocks: u64,
+        num_blocks: u64,
+        cb: IoCompletionCallback,
+        cb_arg: IoCompletionCallbackArg,
+    ) -> Result<(), CoreError> {
+        match self.child_blocks(offset_blocks, num_blocks) {
+            Some((offset, num)) if self.unaligned() => self.submit_locked(
+                AlignedWrite::WriteZeroes,
+                offset,
+                num,
+                cb,
+                cb_arg,
+            ),
+            Some((offset, num)) => {
+                self.inner.write_zeroes(offset, num, cb, cb_arg)
+            }
+            None => self.submit_unaligned(
+                UnalignedIo::Zeroes,
+                offset_blocks,
+                num_blocks,
+                cb,
+                cb_arg,
+            ),
+        }
+    }
+
+    async fn nvme_admin_custom(&self, opcode: u8) -> Result<(), CoreError> {
+        self.inner.nvme_admin_custom(opcode).await
+    }
+
+    async fn nvme_admin(
+        &self,
+        nvme_cmd: &spdk_rs::libspdk::spdk_nvme_cmd,
+        buffer: Option<&mut DmaBuf>,
+    ) -> Result<(), CoreError> {
+        self.inner.nvme_admin(nvme_cmd, buffer).await
+    }
+
+    async fn nvme_identify_ctrlr(&self) -> Result<DmaBuf, CoreError> {
+        self.inner.nvme_identify_ctrlr().await
+    }
+
+    async fn create_snapshot(&self) -> Result<u64, CoreError> {
+        self.inner.create_snapshot().await
+    }
+
+    async fn nvme_resv_register(
+        &self,
+        current_key: u64,
+        new_key: u64,
+        register_action: u8,
+        cptpl: u8,
+    ) -> Result<(), CoreError> {
+        self.inner
+            .nvme_resv_register(current_key, new_key, register_action, cptpl)
+            .await
+    }
+
+    async fn nvme_resv_acquire(
+        &self,
+        current_key: u64,
+        preempt_key: u64,
+        acquire_action: u8,
+        resv_type: u8,
+    ) -> Result<(), CoreError> {
+        self.inner
+            .nvme_resv_acquire(
+                current_key,
+                preempt_key,
+                acquire_action,
+                resv_type,
+            )
+            .await
+    }
+
+    async fn nvme_resv_release(
+        &self,
+        current_key: u64,
+        resv_type: u8,
+        release_action: u8,
+    ) -> Result<(), CoreError> {
+        self.inner
+            .nvme_resv_release(current_key, resv_type, release_action)
+            .await
+    }
+
+    async fn nvme_resv_report(
+        &self,
+        cdw11: u32,
+        buffer: &mut DmaBuf,
+    ) -> Result<(), CoreError> {
+        self.inner.nvme_resv_report(cdw11, buffer).await
+    }
+
+    async fn io_passthru(
+        &self,
+        nvme_cmd: &spdk_rs::libspdk::spdk_nvme_cmd,
+        buffer: Option<&mut DmaBuf>,
+    ) -> Result<(), CoreError> {
+        self.inner.io_passthru(nvme_cmd, buffer).await
+    }
+
+    async fn host_id(&self) -> Result<[u8; 16], CoreError> {
+        self.inner.host_id().await
+    }
+}
@@ -3,22 +3,235 @@
 use std::{
     cell::UnsafeCell,
     fmt::{Debug, Display, Formatter},
+    ops::Deref,
     pin::Pin,
+    ptr::NonNull,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use super::{ChildState, Nexus, Reason};
+use futures::channel::oneshot;
+use serde::Serialize;
+use spdk_rs::{ChannelTraverseStatus, IoDeviceChannelTraverse};
+
+use super::{
+    nexus_admission::ChannelQueues,
+    nexus_checksum::YieldNow,
+    nexus_readahead::ReadaheadStream,
+    nexus_shard::channel_io_handle,
+    nexus_write_ack::WriteLagTracker,
+    nexus_write_merge::WriteMerger,
+    ChildState,
+    Nexus,
+    Reason,
+};
 
 use crate::core::{BlockDeviceHandle, CoreError, Cores};
 
+/// Epoch of the channels of a nexus. Each channel holds a reference to the
+/// nexus, so the nexus should not be reclaimed before all its channels are
+/// destroyed: the channels are counted, and the destruction of the nexus
+/// waits a bounded time for them to be destroyed. The epoch is closed once
+/// the nexus is being destroyed, for the channels and the work they deferred,
+/// such as their completions and pollers, to stop referring to the nexus.
+#[derive(Debug, Default)]
+pub(crate) struct ChannelEpoch {
+    /// Number of live channels.
+    live: AtomicUsize,
+    /// The nexus is being destroyed.
+    closed: AtomicBool,
+}
+
+impl ChannelEpoch {
+    /// Returns the number of live channels.
+    pub(crate) fn live(&self) -> usize {
+        self.live.load(Ordering::SeqCst)
+    }
+
+    /// Returns true if the nexus is being destroyed.
+    #[inline(always)]
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Close the epoch, as the nexus is being destroyed.
+    pub(super) fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    /// Wait for all the channels to be destroyed, for at most the given
+    /// time. Returns false if some are still live.
+    pub(super) async fn released(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.live() > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            YieldNow(false).await;
+        }
+        true
+    }
+}
+
+/// Reference of a channel to the epoch of its nexus, released when the
+/// channel is dropped.
+#[derive(Debug)]
+struct EpochGuard(Arc<ChannelEpoch>);
+
+impl EpochGuard {
+    fn enter(epoch: &Arc<ChannelEpoch>) -> Self {
+        epoch.live.fetch_add(1, Ordering::SeqCst);
+        Self(epoch.clone())
+    }
+}
+
+impl Drop for EpochGuard {
+    fn drop(&mut self) {
+        self.0.live.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Reference of a channel to its nexus, valid for as long as the epoch of
+/// the channels of the nexus is open. Once it is closed, the nexus may be
+/// reclaimed while the channel is still live, and the reference must not be
+/// followed anymore.
+struct NexusRef<'n> {
+    nexus: NonNull<Nexus<'n>>,
+    epoch: EpochGuard,
+}
+
+impl<'n> NexusRef<'n> {
+    fn new(nexus: Pin<&mut Nexus<'n>>) -> Self {
+        let epoch = EpochGuard::enter(&nexus.channels);
+        Self {
+            nexus: NonNull::from(unsafe {
+                nexus.pinned_mut().get_unchecked_mut()
+            }),
+            epoch,
+        }
+    }
+
+    /// Returns true if the nexus is not being destroyed.
+    #[inline(always)]
+    fn is_live(&self) -> bool {
+        !self.epoch.0.is_closed()
+    }
+
+    #[inline(always)]
+    fn as_mut(&mut self) -> Pin<&mut Nexus<'n>> {
+        unsafe { Pin::new_unchecked(self.nexus.as_mut()) }
+    }
+}
+
+impl<'n> Deref for NexusRef<'n> {
+    type Target = Nexus<'n>;
+
+    #[inline(always)]
+    fn deref(&self) -> &Self::Target {
+        unsafe { self.nexus.as_ref() }
+    }
+}
+
+/// Statistics of a nexus channel, counted since the channel was created.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct ChannelStats {
+    /// Core of the channel.
+    pub core: u32,
+    /// Number of children read from and written to.
+    pub readers: usize,
+    pub writers: usize,
+    /// Number of child devices taken out of the channel on an IO error,
+    /// ahead of the reconfiguration of the nexus.
+    pub fail_fast: u64,
+    /// Number of IOs submitted again to the children once their previous
+    /// submission failed.
+    pub retries: u64,
+    /// Number of IOs handed back to the bdev layer, to be submitted again
+    /// once IOs of the nexus complete.
+    pub requeues: u64,
+    /// Number of reconfigurations of the nexus the channel went through.
+    pub reconnects: u64,
+    /// Number of IOs forwarded directly to the single local child of the
+    /// nexus.
+    pub local: u64,
+}
+
+impl ChannelStats {
+    /// Adds up the counters of the given channels.
+    pub fn total(channels: &[ChannelStats]) -> ChannelStats {
+        channels.iter().fold(Self::default(), |t, c| ChannelStats {
+            core: 0,
+            readers: 0,
+            writers: 0,
+            fail_fast: t.fail_fast + c.fail_fast,
+            retries: t.retries + c.retries,
+            requeues: t.requeues + c.requeues,
+            reconnects: t.reconnects + c.reconnects,
+            local: t.local + c.local,
+        })
+    }
+}
+
+/// Counters of the channels of a nexus, kept by the nexus: unlike the
+/// statistics of a channel, which start from zero whenever the channel is
+/// created again, they only ever increase.
+#[derive(Debug, Default)]
+pub(crate) struct ChannelCounters {
+    fail_fast: AtomicU64,
+    retries: AtomicU64,
+    requeues: AtomicU64,
+    reconnects: AtomicU64,
+    local: AtomicU64,
+}
+
+impl ChannelCounters {
+    #[inline(always)]
+    fn add(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the counters, as the statistics of the channels of the
+    /// nexus since it was created.
+    fn stats(&self) -> ChannelStats {
+        ChannelStats {
+            fail_fast: self.fail_fast.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            requeues: self.requeues.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            local: self.local.load(Ordering::Relaxed),
+            ..Default::default()
+        }
+    }
+}
+
 /// io channel, per core
 #[repr(C)]
 pub struct NexusChannel<'n> {
     writers: Vec<Box<dyn BlockDeviceHandle>>,
     readers: Vec<Box<dyn BlockDeviceHandle>>,
+    /// Distance of the readers, by the read routing of the nexus, and the
+    /// distance of the closest of them.
+    reader_distances: Vec<u8>,
+    nearest_distance: u8,
     previous_reader: UnsafeCell<usize>,
-    fail_fast: u32,
-    nexus: Pin<&'n mut Nexus<'n>>,
+    stats: ChannelStats,
+    nexus: NexusRef<'n>,
     core: u32,
+    /// Adjacent writes held to be merged.
+    pub(super) merger: WriteMerger<'n>,
+    /// I/Os outstanding on the channel and on its children, accounted for
+    /// admission control.
+    pub(super) queues: ChannelQueues,
+    /// Stream of sequential reads, and the data prefetched ahead of it.
+    pub(super) readahead: ReadaheadStream,
+    /// IOs left before the next one is traced
+    trace_countdown: u64,
+    /// The nexus has a single child, a local lvol, which IOs can be
+    /// forwarded to directly.
+    pub(super) local: bool,
 }
 
 impl<'n> Debug for NexusChannel<'n> {
@@ -48,6 +261,14 @@ pub enum DrEvent {
     ChildUnplug,
     /// Child rebuild event
     ChildRebuild,
+    /// A suspended child is back into the I/O path
+    ChildResume,
+    /// Deferred children have been connected
+    ChildConnect,
+    /// The read routing of the nexus has changed
+    ReadRouting,
+    /// The shard cores of the nexus have changed
+    ShardCores,
 }
 
 impl Display for DrEvent {
@@ -60,6 +281,10 @@ impl Display for DrEvent {
                 Self::ChildFault => "fault",
                 Self::ChildUnplug => "unplug",
                 Self::ChildRebuild => "rebuild",
+                Self::ChildResume => "resume",
+                Self::ChildConnect => "connect",
+                Self::ReadRouting => "read routing",
+                Self::ShardCores => "shard cores",
             }
         )
     }
@@ -72,40 +297,67 @@ impl<'n> NexusChannel<'n> {
 
         let mut writers = Vec::new();
         let mut readers = Vec::new();
+        let mut reader_distances = Vec::new();
+        let block_len = nexus.block_len();
+        let shard = nexus
+            .shards
+            .forward_target(nexus.nexus_name(), Cores::current());
+        let routing = nexus.read_routing.policy();
 
         unsafe {
             nexus.as_mut().children_iter_mut()
                 .filter(|c| c.state() == ChildState::Open)
-                .for_each(|c| match (c.get_io_handle(), c.get_io_handle()) {
+                .for_each(|c| match (channel_io_handle(shard.as_ref(), c, block_len), channel_io_handle(shard.as_ref(), c, block_len)) {
                     (Ok(w), Ok(r)) => {
                         writers.push(w);
                         readers.push(r);
+                        reader_distances.push(routing.distance(c.uri()));
                     }
                     _ => {
-                        c.set_state(ChildState::Faulted(Reason::CantOpen));
+                        c.transition(
+                            ChildState::Faulted(Reason::CantOpen),
+                            "failed to get an I/O handle",
+                        );
                         error!("Failed to get I/O handle for {}, skipping block device", c.uri())
                     }
                 });
         }
 
+        let local = is_local_volume(&nexus, &writers, &readers);
         Self {
             writers,
             readers,
+            nearest_distance: nearest(&reader_distances),
+            reader_distances,
             previous_reader: UnsafeCell::new(0),
-            nexus: unsafe { nexus.pinned_mut() },
-            fail_fast: 0,
+            local,
+            nexus: NexusRef::new(nexus),
+            stats: ChannelStats::default(),
             core: Cores::current(),
+            merger: WriteMerger::new(),
+            queues: ChannelQueues::default(),
+            readahead: ReadaheadStream::new(),
+            trace_countdown: 0,
         }
     }
 
     /// TODO
     pub(crate) fn destroy(mut self) {
-        debug!(
-            "{:?}: destroying IO channel on core {}",
-            self.nexus, self.core
-        );
+        // the nexus may be gone once its destruction stopped waiting for the
+        // channel
+        if self.nexus.is_live() {
+            debug!(
+                "{:?}: destroying IO channel on core {}",
+                *self.nexus, self.core
+            );
+        } else {
+            debug!("destroying IO channel on core {}", self.core);
+        }
+        self.merger.stop();
+        self.readahead.stop();
         self.writers.clear();
         self.readers.clear();
+        self.reader_distances.clear();
     }
 
     /// Returns reference to channel's Nexus.
@@ -130,36 +382,159 @@ impl<'n> NexusChannel<'n> {
         self.writers.iter().try_for_each(|h| f(h.as_ref()))
     }
 
+    /// Returns the handles of the children written to.
+    pub(super) fn writers(
+        &self,
+    ) -> impl Iterator<Item = &dyn BlockDeviceHandle> + '_ {
+        self.writers.iter().map(|h| h.as_ref())
+    }
+
+    /// Returns the handles of the children read from.
+    pub(super) fn readers(
+        &self,
+    ) -> impl Iterator<Item = &dyn BlockDeviceHandle> + '_ {
+        self.readers.iter().map(|h| h.as_ref())
+    }
+
+    /// Returns the write handle of the given child device.
+    pub(super) fn writer(
+        &self,
+        device_name: &str,
+    ) -> Option<&dyn BlockDeviceHandle> {
+        self.writers()
+            .find(|h| h.get_device().device_name() == device_name)
+    }
+
     /// very simplistic routine to rotate between children for read operations
     /// note that the channels can be None during a reconfigure; this is usually
     /// not the case but a side effect of using the async. As we poll
     /// threads more often depending on what core we are on etc, we might be
     /// "awaiting' while the thread is already trying to submit IO.
+    /// Only the closest children are rotated between, when the nexus routes
+    /// its reads by distance.
     pub(crate) fn select_reader(&self) -> Option<&dyn BlockDeviceHandle> {
+        let hdl = self.next_reader()?;
+        self.count_read(hdl);
+        Some(hdl)
+    }
+
+    /// Count a read served by the given reader, when the nexus routes its
+    /// reads by distance.
+    #[inline(always)]
+    fn count_read(&self, hdl: &dyn BlockDeviceHandle) {
+        if self.nexus.read_routing.is_enabled() {
+            self.nexus
+                .read_routing
+                .served(&hdl.get_device().device_name());
+        }
+    }
+
+    /// Returns the next of the closest readers in turn.
+    fn next_reader(&self) -> Option<&dyn BlockDeviceHandle> {
         if self.readers.is_empty() {
             None
         } else {
             let idx = unsafe {
                 let idx = &mut *self.previous_reader.get();
-                if *idx < self.readers.len() - 1 {
-                    *idx += 1;
-                } else {
-                    *idx = 0;
+                loop {
+                    if *idx < self.readers.len() - 1 {
+                        *idx += 1;
+                    } else {
+                        *idx = 0;
+                    }
+                    if self.reader_distances[*idx] == self.nearest_distance {
+                        break *idx;
+                    }
                 }
-                *idx
             };
             Some(self.readers[idx].as_ref())
         }
     }
 
+    /// Selects a reader among the children which have no asynchronous
+    /// writes in flight, so that a read never returns data older than a write
+    /// already acknowledged. The closest such child is selected when the
+    /// nexus routes its reads by distance.
+    pub(crate) fn select_synced_reader(
+        &self,
+        tracker: &WriteLagTracker,
+    ) -> Option<&dyn BlockDeviceHandle> {
+        let synced = |h: &&dyn BlockDeviceHandle| {
+            tracker.lag(&h.get_device().device_name()) == 0
+        };
+        let hdl = (0 .. self.readers.len())
+            .find_map(|_| self.next_reader().filter(synced))
+            .or_else(|| {
+                self.readers
+                    .iter()
+                    .zip(&self.reader_distances)
+                    .map(|(h, d)| (h.as_ref(), *d))
+                    .filter(|(h, _)| synced(h))
+                    .min_by_key(|(_, d)| *d)
+                    .map(|(h, _)| h)
+            })?;
+        self.count_read(hdl);
+        Some(hdl)
+    }
+
+    /// Returns true if the next IO of the channel is to be traced.
+    #[inline(always)]
+    pub(super) fn sample_trace(&mut self) -> bool {
+        self.nexus.io_trace.sample(&mut self.trace_countdown)
+    }
+
+    /// Account an IO submitted again to the children.
+    #[inline(always)]
+    pub(super) fn retried(&mut self) {
+        self.stats.retries += 1;
+        ChannelCounters::add(&self.nexus.channel_counters.retries);
+    }
+
+    /// Account an IO handed back to the bdev layer.
+    #[inline(always)]
+    pub(super) fn requeued(&mut self) {
+        self.stats.requeues += 1;
+        ChannelCounters::add(&self.nexus.channel_counters.requeues);
+    }
+
+    /// Account an IO forwarded directly to the local child.
+    #[inline(always)]
+    pub(super) fn forwarded(&mut self) {
+        self.stats.local += 1;
+        ChannelCounters::add(&self.nexus.channel_counters.local);
+    }
+
+    /// Returns the statistics of the channel.
+    pub(crate) fn stats(&self) -> ChannelStats {
+        ChannelStats {
+            core: self.core,
+            readers: self.readers.len(),
+            writers: self.writers.len(),
+            ..self.stats
+        }
+    }
+
     /// Disconnects a child device from the I/O path.
     pub fn disconnect_device(&mut self, device_name: &str) {
         self.previous_reader = UnsafeCell::new(0);
+        let writers = self.writers.len();
 
-        self.readers
-            .retain(|c| c.get_device().device_name() != device_name);
+        if let Some(idx) = self
+            .readers
+            .iter()
+            .position(|c| c.get_device().device_name() == device_name)
+        {
+            self.readers.remove(idx);
+            self.reader_distances.remove(idx);
+            self.nearest_distance = nearest(&self.reader_distances);
+        }
         self.writers
             .retain(|c| c.get_device().device_name() != device_name);
+        self.local = false;
+        if self.writers.len() != writers {
+            self.stats.fail_fast += 1;
+            ChannelCounters::add(&self.nexus.channel_counters.fail_fast);
+        }
 
         debug!("{:?}: device '{}' disconnected", self, device_name);
     }
@@ -169,6 +544,8 @@ impl<'n> NexusChannel<'n> {
     /// we simply put back all the channels, and reopen the bdevs that are in
     /// the online state.
     pub(crate) fn reconnect_all(&mut self) {
+        let _span =
+            info_span!("channel_refresh", core = Cores::current()).entered();
         debug!("{:?}: reconnecting all children", self);
 
         // clear the vector of channels and reset other internal values,
@@ -183,20 +560,36 @@ impl<'n> NexusChannel<'n> {
 
         let mut writers = Vec::new();
         let mut readers = Vec::new();
+        let mut reader_distances = Vec::new();
+        let block_len = self.nexus.block_len();
+        let shard = self
+            .nexus
+            .shards
+            .forward_target(self.nexus.nexus_name(), Cores::current());
+        let routing = self.nexus.read_routing.policy();
 
         // iterate over all our children which are in the open state
         unsafe {
             self.nexus_mut()
                 .children_iter_mut()
                 .filter(|c| c.state() == ChildState::Open)
-                .for_each(|c| match (c.get_io_handle(), c.get_io_handle()) {
-                    (Ok(w), Ok(r)) => {
-                        writers.push(w);
-                        readers.push(r);
-                    }
-                    _ => {
-                        c.set_state(ChildState::Faulted(Reason::CantOpen));
-                        error!("failed to get I/O handle for {}", c.uri());
+                .for_each(|c| {
+                    match (
+                        channel_io_handle(shard.as_ref(), c, block_len),
+                        channel_io_handle(shard.as_ref(), c, block_len),
+                    ) {
+                        (Ok(w), Ok(r)) => {
+                            writers.push(w);
+                            readers.push(r);
+                            reader_distances.push(routing.distance(c.uri()));
+                        }
+                        _ => {
+                            c.transition(
+                                ChildState::Faulted(Reason::CantOpen),
+                                "failed to get an I/O handle",
+                            );
+                            error!("failed to get I/O handle for {}", c.uri());
+                        }
                     }
                 });
         }
@@ -208,10 +601,15 @@ impl<'n> NexusChannel<'n> {
                     .children_iter_mut()
                     .filter(|c| c.rebuilding())
                     .for_each(|c| {
-                        if let Ok(hdl) = c.get_io_handle() {
+                        if let Ok(hdl) =
+                            channel_io_handle(shard.as_ref(), c, block_len)
+                        {
                             writers.push(hdl);
                         } else {
-                            c.set_state(ChildState::Faulted(Reason::CantOpen));
+                            c.transition(
+                                ChildState::Faulted(Reason::CantOpen),
+                                "failed to get an I/O handle",
+                            );
                             error!("failed to get I/O handle for {}", c.uri());
                         }
                     });
@@ -221,8 +619,13 @@ impl<'n> NexusChannel<'n> {
         self.writers.clear();
         self.readers.clear();
 
+        self.local = is_local_volume(&self.nexus, &writers, &readers);
         self.writers = writers;
         self.readers = readers;
+        self.nearest_distance = nearest(&reader_distances);
+        self.reader_distances = reader_distances;
+        self.stats.reconnects += 1;
+        ChannelCounters::add(&self.nexus.channel_counters.reconnects);
 
         trace!("{:?}: new number of readers/writes", self);
     }
@@ -232,3 +635,60 @@ impl<'n> NexusChannel<'n> {
         self.core
     }
 }
+
+/// Statistics of the channels collected on their cores.
+struct ChannelStatsCtx {
+    sender: oneshot::Sender<Vec<ChannelStats>>,
+    stats: Vec<ChannelStats>,
+}
+
+impl<'n> Nexus<'n> {
+    /// Returns the statistics of the channels of the nexus, one per core
+    /// the nexus has IOs submitted on.
+    pub async fn channel_stats(&self) -> Vec<ChannelStats> {
+        if !self.has_io_device || self.channels.is_closed() {
+            return Vec::new();
+        }
+
+        let (sender, recv) = oneshot::channel();
+        self.traverse_io_channels(
+            |chan, ctx: &mut ChannelStatsCtx| -> ChannelTraverseStatus {
+                ctx.stats.push(chan.stats());
+                ChannelTraverseStatus::Ok
+            },
+            |_status, ctx| {
+                ctx.sender.send(ctx.stats).ok();
+            },
+            ChannelStatsCtx {
+                sender,
+                stats: Vec::new(),
+            },
+        );
+        recv.await.unwrap_or_default()
+    }
+
+    /// Returns the counters of the channels of the nexus since it was
+    /// created, including those of the channels destroyed since.
+    pub fn channel_counters(&self) -> ChannelStats {
+        self.channel_counters.stats()
+    }
+}
+
+/// Returns the distance of the closest of the readers.
+fn nearest(distances: &[u8]) -> u8 {
+    distances.iter().copied().min().unwrap_or_default()
+}
+
+/// Returns true if the only child of the nexus is a local lvol, both read
+/// from and written to: the IOs are then forwarded to it without the
+/// mirroring machinery.
+fn is_local_volume(
+    nexus: &Nexus,
+    writers: &[Box<dyn BlockDeviceHandle>],
+    readers: &[Box<dyn BlockDeviceHandle>],
+) -> bool {
+    nexus.child_count() == 1
+        && writers.len() == 1
+        && readers.len() == 1
+        && writers[0].get_device().driver_name() == "lvol"
+}
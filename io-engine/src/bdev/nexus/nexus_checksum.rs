@@ -0,0 +1,890 @@
+//! Block checksums.
+//!
+//! When enabled on a nexus, a checksum of every block written through the
+//! nexus is kept on each child, in a checksum region located right after the
+//! data partition of the child. The checksums of the blocks written are
+//! marked unknown before the data is written, and updated once it has been,
+//! so that a crash in between does not leave stale checksums behind. They
+//! are verified when data is read back from the child. On a mismatch, the data
+//! is read again from the child, in case a write raced with the read, and
+//! otherwise read from another child whose checksums match and returned
+//! instead, while the faulty child is repaired in the background. This detects
+//! the silent corruptions commodity disks are prone to.
+//!
+//! Checksum region layout, on each child:
+//!
+//! region       ───── header: magic, algorithm and number of data blocks
+//! region + 1   ──┐
+//!                ├── 4 bytes checksum per data block, little endian
+//! region + N   ──┘
+//!
+//! A checksum of zero means the checksum of the block is unknown, i.e. the
+//! block has not been written since checksums were enabled, it has been
+//! unmapped or it has been copied by a rebuild. Such blocks are not verified.
+//! The children must be large enough to hold the region in addition to the
+//! data partition and the backup GPT.
+
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+
+use crossbeam::atomic::AtomicCell;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+
+use spdk_rs::{DmaBuf, LbaRange};
+
+use super::{nexus_lookup, nexus_lookup_mut, Error, Nexus, NexusChannel};
+use crate::{
+    core::{
+        partition::{bytes_to_alinged_blocks, GPT_TABLE_SIZE},
+        Bdev,
+        BlockDeviceHandle,
+        CoreError,
+        Reactors,
+    },
+    jsonrpc::jsonrpc_register,
+};
+
+/// Magic of the checksum region header.
+const MAGIC: &[u8; 8] = b"MAYACSUM";
+
+/// Size of a checksum, in bytes.
+const CHECKSUM_SIZE: u64 = 4;
+
+/// Checksum of a block which is not known.
+pub(super) const UNKNOWN: u32 = 0;
+
+/// Number of locks serialising the updates of the checksum region blocks.
+const LOCK_STRIPES: usize = 256;
+
+/// Size of the buffer used to clear a checksum region, in bytes.
+const CLEAR_CHUNK: u64 = 1024 * 1024;
+
+/// Number of times blocks failing verification are read again from the
+/// same child before looking for another copy.
+const VERIFY_REREADS: usize = 2;
+
+/// Checksum algorithm.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChecksumAlgo {
+    /// CRC32 with the Castagnoli polynomial.
+    Crc32c,
+    /// 32 bits xxHash.
+    Xxhash32,
+}
+
+impl ChecksumAlgo {
+    /// Identifier of the algorithm in the region header.
+    fn id(&self) -> u32 {
+        match self {
+            Self::Crc32c => 1,
+            Self::Xxhash32 => 2,
+        }
+    }
+
+    /// Returns the checksum of a block, which is never UNKNOWN.
+    pub(super) fn checksum(&self, block: &[u8]) -> u32 {
+        let checksum = match self {
+            Self::Crc32c => crc::crc32::checksum_castagnoli(block),
+            Self::Xxhash32 => xxhash_rust::xxh32::xxh32(block, 0),
+        };
+        if checksum == UNKNOWN {
+            1
+        } else {
+            checksum
+        }
+    }
+
+    /// Returns the checksums of the given blocks.
+    pub(super) fn checksums(&self, data: &[u8], block_len: u64) -> Vec<u32> {
+        data.chunks(block_len as usize)
+            .map(|b| self.checksum(b))
+            .collect()
+    }
+}
+
+/// Checksum statistics of a child.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ChecksumStats {
+    /// Number of blocks read whose checksum has been verified.
+    pub verified: u64,
+    /// Number of blocks read whose checksum did not match.
+    pub mismatches: u64,
+    /// Number of blocks rewritten from another child.
+    pub repaired: u64,
+    /// Number of mismatching blocks no other child had a good copy of.
+    pub unrepaired: u64,
+}
+
+/// Outcome of the verification of the data read from a child.
+pub(super) enum Verified {
+    /// The data matches its checksums.
+    Match,
+    /// A write raced with the read, the data read again from the same child
+    /// matches its checksums.
+    Reread(Vec<u8>),
+    /// The data is corrupted, it has been read from another child.
+    Copied(Vec<u8>),
+}
+
+/// Future which yields once to the reactor, letting other futures run.
+pub(super) struct YieldNow(pub(super) bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+/// Stripes of blocks locked by a write, released when dropped.
+/// The stripes are flags rather than mutexes, as they are held across the
+/// IOs to the blocks.
+pub(super) struct StripeGuard<'a> {
+    stripes: &'a [AtomicBool],
+    held: Vec<usize>,
+}
+
+impl<'a> StripeGuard<'a> {
+    /// Lock the stripes of the given blocks. The stripes are taken in order,
+    /// so that ranges locked concurrently do not deadlock, and only ever
+    /// tried, so that the futures waiting for them stay on their core.
+    pub(super) async fn lock(
+        stripes: &'a [AtomicBool],
+        blocks: impl Iterator<Item = u64>,
+    ) -> StripeGuard<'a> {
+        // consecutive blocks cover all the stripes past their number
+        let mut held = blocks
+            .take(stripes.len())
+            .map(|b| (b % stripes.len() as u64) as usize)
+            .collect::<Vec<_>>();
+        held.sort_unstable();
+        held.dedup();
+
+        let mut guard = StripeGuard {
+            stripes,
+            held: Vec::with_capacity(held.len()),
+        };
+        for stripe in held {
+            while stripes[stripe]
+                .compare_exchange(
+                    false,
+                    true,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                )
+                .is_err()
+            {
+                YieldNow(false).await;
+            }
+            guard.held.push(stripe);
+        }
+        guard
+    }
+}
+
+impl Drop for StripeGuard<'_> {
+    fn drop(&mut self) {
+        for stripe in &self.held {
+            self.stripes[*stripe].store(false, Ordering::Release);
+        }
+    }
+}
+
+/// Checksum layer of a nexus. Checksums are updated from all cores, so the
+/// read-modify-write of the region blocks is serialised by striped locks.
+pub(crate) struct ChecksumLayer {
+    algo: AtomicCell<Option<ChecksumAlgo>>,
+    /// First block of the checksum region of the children.
+    region: AtomicCell<u64>,
+    stripes: Vec<AtomicBool>,
+    stats: parking_lot::Mutex<HashMap<String, ChecksumStats>>,
+}
+
+impl ChecksumLayer {
+    pub(crate) fn new() -> Self {
+        Self {
+            algo: AtomicCell::new(None),
+            region: AtomicCell::new(0),
+            stripes: (0 .. LOCK_STRIPES)
+                .map(|_| AtomicBool::new(false))
+                .collect(),
+            stats: parking_lot::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the checksum algorithm, if checksums are enabled.
+    #[inline(always)]
+    pub(crate) fn algo(&self) -> Option<ChecksumAlgo> {
+        self.algo.load()
+    }
+
+    /// Returns the checksum statistics, per child device.
+    pub(crate) fn stats(&self) -> HashMap<String, ChecksumStats> {
+        self.stats.lock().clone()
+    }
+
+    fn update_stats(&self, device: &str, f: impl FnOnce(&mut ChecksumStats)) {
+        f(self.stats.lock().entry(device.to_string()).or_default())
+    }
+
+    /// Returns the range of blocks of the checksum region holding the
+    /// checksums of the given blocks, along with the offset of the first
+    /// checksum within the range, in bytes.
+    fn region_blocks(
+        &self,
+        offset: u64,
+        num_blocks: u64,
+        block_len: u64,
+    ) -> (u64, u64, usize) {
+        let start = offset * CHECKSUM_SIZE;
+        let end = (offset + num_blocks) * CHECKSUM_SIZE;
+        let first = start / block_len;
+        let last = (end - 1) / block_len;
+        (first, last, (start - first * block_len) as usize)
+    }
+
+    /// Reads the checksums of the given blocks from a child.
+    async fn read(
+        &self,
+        hdl: &dyn BlockDeviceHandle,
+        offset: u64,
+        num_blocks: u64,
+        block_len: u64,
+    ) -> Result<Vec<u32>, CoreError> {
+        let (first, last, skip) =
+            self.region_blocks(offset, num_blocks, block_len);
+        let mut buf = dma_buf(hdl, (last - first + 1) * block_len)?;
+        hdl.read_at((self.region.load() + 1 + first) * block_len, &mut buf)
+            .await?;
+
+        Ok(
+            buf.as_slice()
+                [skip .. skip + (num_blocks * CHECKSUM_SIZE) as usize]
+                .chunks(CHECKSUM_SIZE as usize)
+                .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+                .collect(),
+        )
+    }
+
+    /// Writes the checksums of the given blocks to a child.
+    async fn write(
+        &self,
+        hdl: &dyn BlockDeviceHandle,
+        offset: u64,
+        checksums: &[u32],
+        block_len: u64,
+    ) -> Result<(), CoreError> {
+        let (first, last, skip) =
+            self.region_blocks(offset, checksums.len() as u64, block_len);
+        let _guard = StripeGuard::lock(&self.stripes, first ..= last).await;
+
+        let mut buf = dma_buf(hdl, (last - first + 1) * block_len)?;
+        let region_offset = (self.region.load() + 1 + first) * block_len;
+        hdl.read_at(region_offset, &mut buf).await?;
+
+        let data = &mut buf.as_mut_slice()
+            [skip .. skip + checksums.len() * CHECKSUM_SIZE as usize];
+        for (c, checksum) in data
+            .chunks_mut(CHECKSUM_SIZE as usize)
+            .zip(checksums.iter())
+        {
+            c.copy_from_slice(&checksum.to_le_bytes());
+        }
+
+        hdl.write_at(region_offset, &buf).await?;
+        Ok(())
+    }
+
+    /// Prepare the checksum region of a child: the region is kept if it has
+    /// been written with the same algorithm and geometry, otherwise it is
+    /// cleared.
+    async fn prepare(
+        &self,
+        hdl: &dyn BlockDeviceHandle,
+        algo: ChecksumAlgo,
+        region: u64,
+        num_blocks: u64,
+        clear: bool,
+    ) -> Result<(), CoreError> {
+        let block_len = hdl.get_device().block_len();
+        let mut header = dma_buf(hdl, block_len)?;
+
+        if !clear {
+            hdl.read_at(region * block_len, &mut header).await?;
+            if header_matches(header.as_slice(), algo, num_blocks) {
+                return Ok(());
+            }
+        }
+
+        // clear the checksums before writing the header, so that a region
+        // with a valid header never holds stale checksums
+        let size =
+            bytes_to_alinged_blocks(num_blocks * CHECKSUM_SIZE, block_len)
+                * block_len;
+        let zeroes = dma_buf(hdl, CLEAR_CHUNK.min(size))?;
+        let mut cleared = 0;
+        while cleared < size {
+            let offset = (region + 1) * block_len + cleared;
+            if size - cleared < zeroes.len() {
+                let tail = dma_buf(hdl, size - cleared)?;
+                hdl.write_at(offset, &tail).await?;
+                cleared = size;
+            } else {
+                hdl.write_at(offset, &zeroes).await?;
+                cleared += zeroes.len();
+            }
+        }
+
+        let mut header = dma_buf(hdl, block_len)?;
+        write_header(header.as_mut_slice(), algo, num_blocks);
+        hdl.write_at(region * block_len, &header).await?;
+        Ok(())
+    }
+}
+
+/// Allocates a zeroed DMA buffer.
+fn dma_buf(
+    hdl: &dyn BlockDeviceHandle,
+    size: u64,
+) -> Result<DmaBuf, CoreError> {
+    let mut buf =
+        hdl.dma_malloc(size)
+            .map_err(|_| CoreError::DmaAllocationFailed {
+                size,
+            })?;
+    buf.fill(0);
+    Ok(buf)
+}
+
+fn write_header(block: &mut [u8], algo: ChecksumAlgo, num_blocks: u64) {
+    block[0 .. 8].copy_from_slice(MAGIC);
+    block[8 .. 12].copy_from_slice(&algo.id().to_le_bytes());
+    block[12 .. 20].copy_from_slice(&num_blocks.to_le_bytes());
+}
+
+fn header_matches(block: &[u8], algo: ChecksumAlgo, num_blocks: u64) -> bool {
+    let mut expected = vec![0; 20];
+    write_header(&mut expected, algo, num_blocks);
+    block[0 .. 20] == expected[..]
+}
+
+/// Number of blocks a child must have to hold the data partition and the
+/// checksum region after it.
+fn required_blocks(region: u64, num_blocks: u64, block_len: u64) -> u64 {
+    region
+        + 1
+        + bytes_to_alinged_blocks(num_blocks * CHECKSUM_SIZE, block_len)
+        + bytes_to_alinged_blocks(GPT_TABLE_SIZE, block_len)
+        + 1
+}
+
+/// Gathers the data of an I/O vector.
+pub(super) fn gather(iovs: *const libc::iovec, count: usize) -> Vec<u8> {
+    let mut data = Vec::new();
+    for i in 0 .. count {
+        let iov = unsafe { &*iovs.add(i) };
+        data.extend_from_slice(unsafe {
+            std::slice::from_raw_parts(iov.iov_base as *const u8, iov.iov_len)
+        });
+    }
+    data
+}
+
+/// Scatters data into an I/O vector.
+pub(super) fn scatter(data: &[u8], iovs: *const libc::iovec, count: usize) {
+    let mut copied = 0;
+    for i in 0 .. count {
+        let iov = unsafe { &*iovs.add(i) };
+        let len = iov.iov_len.min(data.len() - copied);
+        unsafe { std::slice::from_raw_parts_mut(iov.iov_base as *mut u8, len) }
+            .copy_from_slice(&data[copied .. copied + len]);
+        copied += len;
+    }
+}
+
+impl<'n> Nexus<'n> {
+    /// Enable block checksums on the nexus, with the given algorithm.
+    /// The nexus is paused while the checksum regions of the children are
+    /// prepared.
+    pub async fn checksum_enable(
+        mut self: Pin<&mut Self>,
+        algo: ChecksumAlgo,
+    ) -> Result<(), Error> {
+        if self.checksums.algo() == Some(algo) {
+            return Ok(());
+        }
+
+        let region = self.data_ent_offset + self.num_blocks();
+        let num_blocks = self.num_blocks();
+        let block_len = self.block_len();
+        let required = required_blocks(region, num_blocks, block_len);
+
+        for child in self.children_iter().filter(|c| c.is_healthy()) {
+            let available = child
+                .get_device()
+                .map(|d| d.num_blocks())
+                .unwrap_or_default();
+            if available < required {
+                return Err(Error::ChecksumRegion {
+                    child: child.uri().to_owned(),
+                    name: self.name.clone(),
+                    reason: format!(
+                        "{} blocks needed, {} available",
+                        required, available
+                    ),
+                });
+            }
+        }
+
+        self.as_mut().pause().await?;
+
+        let mut result = Ok(());
+        for child in self.children_iter().filter(|c| c.is_healthy()) {
+            let prepared = match child.get_io_handle() {
+                Ok(hdl) => {
+                    self.checksums
+                        .prepare(&*hdl, algo, region, num_blocks, false)
+                        .await
+                }
+                Err(error) => Err(error),
+            };
+            if let Err(error) = prepared {
+                result = Err(Error::ChecksumRegion {
+                    child: child.uri().to_owned(),
+                    name: self.name.clone(),
+                    reason: error.to_string(),
+                });
+                break;
+            }
+        }
+
+        if result.is_ok() {
+            info!("{:?}: enabling {:?} block checksums", self, algo);
+            self.checksums.region.store(region);
+            self.checksums.algo.store(Some(algo));
+        }
+
+        self.as_mut().resume().await?;
+        result
+    }
+
+    /// Returns the checksum statistics of the nexus, per child device.
+    pub fn checksum_stats(&self) -> HashMap<String, ChecksumStats> {
+        self.checksums.stats()
+    }
+
+    /// Disable block checksums on the nexus. The checksum regions are left
+    /// as is, but become stale as soon as data is written.
+    pub async fn checksum_disable(
+        mut self: Pin<&mut Self>,
+    ) -> Result<(), Error> {
+        if self.checksums.algo().is_none() {
+            return Ok(());
+        }
+
+        self.as_mut().pause().await?;
+        info!("{:?}: disabling block checksums", self);
+        self.checksums.algo.store(None);
+        // the header is invalidated by the next enable, as the checksums
+        // are not updated from now on
+        self.checksums.region.store(0);
+        self.as_mut().resume().await
+    }
+
+    /// Clear the checksum region of a child about to be rebuilt, as the
+    /// rebuild does not copy the checksums.
+    pub(super) async fn checksum_prepare_child(
+        &self,
+        child_uri: &str,
+    ) -> Result<(), Error> {
+        let algo = match self.checksums.algo() {
+            Some(algo) => algo,
+            None => return Ok(()),
+        };
+
+        let region = self.checksums.region.load();
+        let num_blocks = self.num_blocks();
+        let error = |reason: String| Error::ChecksumRegion {
+            child: child_uri.to_owned(),
+            name: self.name.clone(),
+            reason,
+        };
+
+        let child = self.lookup_child(child_uri).ok_or_else(|| {
+            Error::ChildNotFound {
+                child: child_uri.to_owned(),
+                name: self.name.clone(),
+            }
+        })?;
+        let available = child
+            .get_device()
+            .map(|d| d.num_blocks())
+            .unwrap_or_default();
+        let required = required_blocks(region, num_blocks, self.block_len());
+        if available < required {
+            return Err(error(format!(
+                "{} blocks needed, {} available",
+                required, available
+            )));
+        }
+
+        let hdl = child.get_io_handle().map_err(|e| error(e.to_string()))?;
+        self.checksums
+            .prepare(&*hdl, algo, region, num_blocks, true)
+            .await
+            .map_err(|e| error(e.to_string()))
+    }
+
+    /// Update the checksums of the given blocks on all the children the
+    /// channel writes to.
+    pub(super) async fn checksum_update(
+        &self,
+        channel: &NexusChannel<'n>,
+        offset: u64,
+        checksums: &[u32],
+    ) -> Result<(), CoreError> {
+        for hdl in channel.writers() {
+            self.checksums
+                .write(hdl, offset, checksums, self.block_len())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Verify the data read from the given child against its checksums.
+    /// Returns the data to complete the read with, if the child returned
+    /// data which does not match.
+    pub(super) async fn checksum_verify(
+        &self,
+        channel: &NexusChannel<'n>,
+        device: &str,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<Verified, ()> {
+        let algo = match self.checksums.algo() {
+            Some(algo) => algo,
+            None => return Ok(Verified::Match),
+        };
+        let block_len = self.block_len();
+        let num_blocks = data.len() as u64 / block_len;
+        let byte_offset = (offset + self.data_ent_offset) * block_len;
+
+        let hdl = channel.writer(device).ok_or(())?;
+        let stored = self
+            .checksums
+            .read(hdl, offset, num_blocks, block_len)
+            .await
+            .map_err(|error| {
+                error!(
+                    "{:?}: failed to read checksums of '{}': {}",
+                    self, device, error
+                );
+            })?;
+        let actual = algo.checksums(data, block_len);
+
+        let bad = stored
+            .iter()
+            .zip(actual.iter())
+            .enumerate()
+            .filter(|(_, (s, a))| **s != UNKNOWN && s != a)
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+
+        self.checksums
+            .update_stats(device, |s| s.verified += num_blocks);
+
+        if bad.is_empty() {
+            return Ok(Verified::Match);
+        }
+
+        // the checksums of the blocks may have been updated by a write which
+        // raced with the read, after the data was read
+        for _ in 0 .. VERIFY_REREADS {
+            let mut buf = match dma_buf(hdl, data.len() as u64) {
+                Ok(buf) => buf,
+                Err(_) => break,
+            };
+            if hdl.read_at(byte_offset, &mut buf).await.is_err() {
+                break;
+            }
+            let stored = match self
+                .checksums
+                .read(hdl, offset, num_blocks, block_len)
+                .await
+            {
+                Ok(checksums) => checksums,
+                Err(_) => break,
+            };
+            let actual = algo.checksums(buf.as_slice(), block_len);
+            if stored
+                .iter()
+                .zip(actual.iter())
+                .all(|(s, a)| *s == UNKNOWN || s == a)
+            {
+                debug!(
+                    "{:?}: blocks read again from '{}' at {} match",
+                    self, device, offset
+                );
+                return Ok(Verified::Reread(buf.as_slice().to_vec()));
+            }
+        }
+
+        self.checksums
+            .update_stats(device, |s| s.mismatches += bad.len() as u64);
+        error!(
+            "{:?}: checksum mismatch of {} block(s) read from '{}' at {}",
+            self,
+            bad.len(),
+            device,
+            offset
+        );
+
+        // look for a child holding a good copy of the mismatching blocks
+        for other in channel.writers() {
+            let other_device = other.get_device().device_name();
+            if other_device == device {
+                continue;
+            }
+
+            let mut buf = match dma_buf(other, data.len() as u64) {
+                Ok(buf) => buf,
+                Err(_) => continue,
+            };
+            if other.read_at(byte_offset, &mut buf).await.is_err() {
+                continue;
+            }
+            let other_stored = match self
+                .checksums
+                .read(other, offset, num_blocks, block_len)
+                .await
+            {
+                Ok(checksums) => checksums,
+                Err(_) => continue,
+            };
+            let other_actual = algo.checksums(buf.as_slice(), block_len);
+
+            if bad.iter().all(|i| {
+                other_stored[*i] != UNKNOWN
+                    && other_stored[*i] == other_actual[*i]
+            }) {
+                warn!(
+                    "{:?}: using the copy of '{}' for the blocks at {}",
+                    self, other_device, offset
+                );
+                return Ok(Verified::Copied(buf.as_slice().to_vec()));
+            }
+        }
+
+        self.checksums
+            .update_stats(device, |s| s.unrepaired += bad.len() as u64);
+        Err(())
+    }
+
+    /// Rewrite the blocks of a child which failed checksum verification,
+    /// once the read returning them has completed. The range is locked on
+    /// the nexus so that no write races with the repair.
+    pub(super) async fn checksum_repair(
+        nexus_name: String,
+        device: String,
+        offset: u64,
+        num_blocks: u64,
+    ) {
+        let descriptor = match Bdev::<Nexus>::open_by_name(&nexus_name, false) {
+            Ok(descriptor) => descriptor,
+            Err(_) => return,
+        };
+
+        let range = LbaRange::new(offset, num_blocks);
+        let lock = match descriptor.lock_lba_range(range).await {
+            Ok(lock) => lock,
+            Err(error) => {
+                error!(
+                    "Nexus '{}': failed to lock range for repair: {}",
+                    nexus_name, error
+                );
+                return;
+            }
+        };
+
+        if let Some(nexus) = nexus_lookup_mut(&nexus_name) {
+            if let Err(error) =
+                nexus.repair_blocks(&device, offset, num_blocks).await
+            {
+                error!(
+                    "{:?}: failed to repair blocks of '{}' at {}: {}",
+                    nexus, device, offset, error
+                );
+            }
+        }
+
+        if let Err(error) = descriptor.unlock_lba_range(lock).await {
+            error!(
+                "Nexus '{}': failed to unlock range after repair: {}",
+                nexus_name, error
+            );
+        }
+    }
+
+    /// Rewrite the given blocks of a child with a verified copy.
+    async fn repair_blocks(
+        &self,
+        device: &str,
+        offset: u64,
+        num_blocks: u64,
+    ) -> Result<(), CoreError> {
+        let algo = match self.checksums.algo() {
+            Some(algo) => algo,
+            None => return Ok(()),
+        };
+        let block_len = self.block_len();
+        let byte_offset = (offset + self.data_ent_offset) * block_len;
+
+        let handles = self
+            .children_iter()
+            .filter(|c| c.is_healthy())
+            .filter_map(|c| c.get_io_handle().ok())
+            .collect::<Vec<_>>();
+        let target = handles
+            .iter()
+            .find(|h| h.get_device().device_name() == device)
+            .ok_or(CoreError::NoDevicesAvailable {})?;
+
+        for source in handles
+            .iter()
+            .filter(|h| h.get_device().device_name() != device)
+        {
+            let mut buf = dma_buf(&**source, num_blocks * block_len)?;
+            source.read_at(byte_offset, &mut buf).await?;
+            let stored = self
+                .checksums
+                .read(&**source, offset, num_blocks, block_len)
+                .await?;
+            let actual = algo.checksums(buf.as_slice(), block_len);
+            if stored
+                .iter()
+                .zip(actual.iter())
+                .any(|(s, a)| *s != UNKNOWN && s != a)
+            {
+                continue;
+            }
+
+            target.write_at(byte_offset, &buf).await?;
+            self.checksums
+                .write(&**target, offset, &actual, block_len)
+                .await?;
+            self.checksums
+                .update_stats(device, |s| s.repaired += num_blocks);
+            info!(
+                "{:?}: repaired {} block(s) of '{}' at {}",
+                self, num_blocks, device, offset
+            );
+            return Ok(());
+        }
+
+        Err(CoreError::NoDevicesAvailable {})
+    }
+}
+
+/// Schedule the repair of blocks which failed checksum verification.
+pub(super) fn schedule_repair(
+    nexus_name: String,
+    device: String,
+    offset: u64,
+    num_blocks: u64,
+) {
+    Reactors::master().send_future(Nexus::checksum_repair(
+        nexus_name, device, offset, num_blocks,
+    ));
+}
+
+/// Arguments of the `nexus_checksum_enable` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct ChecksumEnableArgs {
+    /// Name of the nexus.
+    name: String,
+    /// Checksum algorithm, defaults to crc32c.
+    algo: Option<ChecksumAlgo>,
+}
+
+/// Arguments of the `nexus_checksum_disable` and `nexus_checksum_stats`
+/// json-rpc methods.
+#[derive(Debug, Deserialize)]
+struct ChecksumArgs {
+    /// Name of the nexus.
+    name: String,
+}
+
+/// Reply of the checksum json-rpc methods.
+#[derive(Debug, Serialize)]
+struct ChecksumReply {
+    name: String,
+    algo: Option<ChecksumAlgo>,
+    /// Checksum statistics, per child device.
+    children: HashMap<String, ChecksumStats>,
+}
+
+impl ChecksumReply {
+    fn new(nexus: &Nexus) -> Self {
+        Self {
+            name: nexus.name.clone(),
+            algo: nexus.checksums.algo(),
+            children: nexus.checksums.stats(),
+        }
+    }
+}
+
+/// Register the checksum json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nexus_checksum_enable", |args: ChecksumEnableArgs| {
+        async move {
+            let mut nexus =
+                nexus_lookup_mut(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+            nexus
+                .as_mut()
+                .checksum_enable(args.algo.unwrap_or(ChecksumAlgo::Crc32c))
+                .await?;
+            Ok(ChecksumReply::new(&nexus))
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_checksum_disable", |args: ChecksumArgs| {
+        async move {
+            let mut nexus =
+                nexus_lookup_mut(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+            nexus.as_mut().checksum_disable().await?;
+            Ok(ChecksumReply::new(&nexus))
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_checksum_stats", |args: ChecksumArgs| {
+        async move {
+            nexus_lookup(&args.name).map(ChecksumReply::new).ok_or(
+                Error::NexusNotFound {
+                    name: args.name,
+                },
+            )
+        }
+        .boxed_local()
+    });
+}
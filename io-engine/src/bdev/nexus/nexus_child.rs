@@ -2,6 +2,7 @@ use std::{
     convert::TryFrom,
     fmt::{Debug, Display, Formatter},
     marker::PhantomData,
+    sync::Arc,
 };
 
 use crossbeam::atomic::AtomicCell;
@@ -11,7 +12,15 @@ use serde::Serialize;
 use snafu::{ResultExt, Snafu};
 use url::Url;
 
-use super::{nexus_lookup_mut, DrEvent};
+use super::{
+    nexus_block_adapter::{BlockAdapter, RmwState},
+    nexus_lookup_mut,
+    DrEvent,
+    FaultDetail,
+    RmwStats,
+    StateTransition,
+    TransitionLog,
+};
 
 use crate::{
     bdev::{device_create, device_destroy, device_lookup},
@@ -37,6 +46,7 @@ use crate::{
         NvmeReservation,
     },
     core::MayastorEnvironment,
+    lvs::LeaseError,
 };
 use spdk_rs::{
     libspdk::{
@@ -106,6 +116,13 @@ pub enum ChildError {
     },
     #[snafu(display("Failed to get NVMe host ID: {}", source))]
     NvmeHostId { source: CoreError },
+    #[snafu(display("Failed to take the lease of the replica: {}", source))]
+    ReplicaLease { source: LeaseError },
+    #[snafu(display(
+        "Failed to take the lease of the remote replica: {}",
+        source
+    ))]
+    ReplicaLeaseCmd { source: CoreError },
     #[snafu(display("Failed to create a BlockDevice for child {}", child))]
     ChildBdevCreate { child: String, source: BdevError },
 }
@@ -137,6 +154,9 @@ pub enum Reason {
     ByClient,
     /// Admin command failure.
     AdminCommandFailed,
+    /// The child has been administratively taken out of the I/O path, its
+    /// device is kept open.
+    Offline,
 }
 
 impl Display for Reason {
@@ -151,6 +171,7 @@ impl Display for Reason {
             Self::IoError => write!(f, "io error"),
             Self::ByClient => write!(f, "by client"),
             Self::AdminCommandFailed => write!(f, "admin command failed"),
+            Self::Offline => write!(f, "offline"),
         }
     }
 }
@@ -191,10 +212,13 @@ pub struct NexusChild<'c> {
     parent: String,
     /// current state of the child
     #[serde(skip_serializing)]
-    pub state: AtomicCell<ChildState>,
+    state: AtomicCell<ChildState>,
     /// previous state of the child
     #[serde(skip_serializing)]
     prev_state: AtomicCell<ChildState>,
+    /// detail of the fault of the child, while it is faulted
+    #[serde(skip_serializing)]
+    fault_detail: AtomicCell<Option<FaultDetail>>,
     /// TODO
     #[serde(skip_serializing)]
     remove_channel: (mpsc::Sender<()>, mpsc::Receiver<()>),
@@ -213,6 +237,16 @@ pub struct NexusChild<'c> {
     /// TODO
     #[serde(skip_serializing)]
     rebuild_job: Option<RebuildJob<'c>>,
+    /// The child was added with pre-seeded data, which is being verified:
+    /// it receives the writes of the nexus but is not read from.
+    #[serde(skip_serializing)]
+    seeding: bool,
+    /// Transitions of the state of the child.
+    #[serde(skip_serializing)]
+    history: TransitionLog,
+    /// IOs of the nexus not aligned on the blocks of the child.
+    #[serde(skip_serializing)]
+    rmw: Arc<RmwState>,
     /// TODO
     #[serde(skip_serializing)]
     _c: PhantomData<&'c ()>,
@@ -231,11 +265,79 @@ impl Debug for NexusChild<'_> {
 }
 
 impl<'c> NexusChild<'c> {
-    /// TODO
-    pub(crate) fn set_state(&self, state: ChildState) {
-        debug!("{:?}: changing state to '{}'", self, state);
+    /// Changes the state of the child, recording the transition with its
+    /// cause.
+    pub(crate) fn transition(&self, state: ChildState, cause: &str) {
+        let detail = match state {
+            ChildState::Faulted(reason) => Some(FaultDetail::from(reason)),
+            _ => None,
+        };
+        self.transition_with_detail(state, detail, cause);
+    }
+
+    /// Changes the state of the child, recording the detail of its fault.
+    pub(super) fn transition_with_detail(
+        &self,
+        state: ChildState,
+        detail: Option<FaultDetail>,
+        cause: &str,
+    ) {
+        self.fault_detail.store(detail);
         let prev_state = self.state.swap(state);
         self.prev_state.store(prev_state);
+
+        let unexpected = !prev_state.can_transition_to(state);
+        if unexpected {
+            warn!(
+                "{:?}: unexpected state transition '{}' -> '{}': {}",
+                self, prev_state, state, cause
+            );
+        } else {
+            debug!(
+                "{:?}: changing state '{}' -> '{}': {}",
+                self, prev_state, state, cause
+            );
+        }
+        self.history
+            .record(&self.name, prev_state, state, cause, unexpected);
+    }
+
+    /// Faults the child with the given detail if it is open, atomically with
+    /// respect to concurrent faults. Returns true if the child was open.
+    pub(crate) fn fault_if_open(&self, detail: FaultDetail) -> bool {
+        let state = ChildState::Faulted(detail.reason());
+        if self
+            .state
+            .compare_exchange(ChildState::Open, state)
+            .is_err()
+        {
+            return false;
+        }
+        self.fault_detail.store(Some(detail));
+        let cause = format!("child faulted: {}", detail);
+        debug!(
+            "{:?}: changing state '{}' -> '{}': {}",
+            self,
+            ChildState::Open,
+            state,
+            cause
+        );
+        self.history
+            .record(&self.name, ChildState::Open, state, &cause, false);
+        true
+    }
+
+    /// Returns the detail of the fault of the child, if it is faulted.
+    pub fn fault_detail(&self) -> Option<FaultDetail> {
+        match self.state() {
+            ChildState::Faulted(_) => self.fault_detail.load(),
+            _ => None,
+        }
+    }
+
+    /// Returns the transitions of the state of the child, oldest first.
+    pub(crate) fn history(&self) -> Vec<StateTransition> {
+        self.history.transitions()
     }
 
     /// Open the child in RW mode and claim the device to be ours. If the child
@@ -253,6 +355,9 @@ impl<'c> NexusChild<'c> {
         parent_size: u64,
         opened_state: ChildState,
     ) -> Result<String, ChildError> {
+        let _span =
+            info_span!("child_open", nexus = %self.parent, child = %self.name)
+                .entered();
         info!("{:?}: opening child device...", self);
 
         // verify the state of the child before we open it
@@ -287,7 +392,7 @@ impl<'c> NexusChild<'c> {
                 self, parent_size, child_size
             );
 
-            self.set_state(ChildState::ConfigInvalid);
+            self.transition(ChildState::ConfigInvalid, "child is too small");
             return Err(ChildError::ChildTooSmall {
                 parent_size,
                 child_size,
@@ -295,14 +400,17 @@ impl<'c> NexusChild<'c> {
         }
 
         let desc = dev.open(true).map_err(|source| {
-            self.set_state(ChildState::Faulted(Reason::CantOpen));
+            self.transition(
+                ChildState::Faulted(Reason::CantOpen),
+                "failed to open the device",
+            );
             ChildError::OpenChild {
                 source,
             }
         })?;
         self.device_descriptor = Some(desc);
 
-        self.set_state(opened_state);
+        self.transition(opened_state, "device opened");
 
         info!("{:?}: opened successfully", self);
         Ok(self.name.clone())
@@ -758,17 +866,28 @@ impl<'c> NexusChild<'c> {
     /// We do not close the child if it is out-of-sync because it will
     /// subsequently be rebuilt.
     pub(crate) async fn fault(&mut self, reason: Reason) {
+        self.fault_with_detail(reason.into()).await
+    }
+
+    /// Fault the child, recording the detail of the fault.
+    pub(crate) async fn fault_with_detail(&mut self, detail: FaultDetail) {
+        let reason = detail.reason();
+        let cause = format!("child faulted: {}", detail);
         match reason {
-            Reason::OutOfSync => {
-                self.set_state(ChildState::Faulted(reason));
-            }
+            // the device is kept open, the child is expected to be brought
+            // back into the I/O path after a rebuild
+            Reason::OutOfSync | Reason::Offline => {}
             _ => {
                 if let Err(e) = self.close().await {
                     error!("{:?}: failed to close: {}", self, e.verbose());
                 }
-                self.set_state(ChildState::Faulted(reason));
             }
         }
+        self.transition_with_detail(
+            ChildState::Faulted(reason),
+            Some(detail),
+            &cause,
+        );
     }
 
     /// Set the child as temporarily offline
@@ -823,7 +942,7 @@ impl<'c> NexusChild<'c> {
             return Err(ChildError::ChildInaccessible {});
         }
 
-        self.set_state(ChildState::Closed);
+        self.transition(ChildState::Closed, "device recreated to online");
         self.open(parent_size, ChildState::Faulted(Reason::OutOfSync))
     }
 
@@ -854,10 +973,16 @@ impl<'c> NexusChild<'c> {
     }
 
     pub(crate) fn rebuilding(&self) -> bool {
-        self.rebuild_job.is_some()
+        (self.rebuild_job.is_some() || self.seeding)
             && self.state() == ChildState::Faulted(Reason::OutOfSync)
     }
 
+    /// Mark the child as being seeded: an out-of-sync child which receives
+    /// writes while its pre-seeded data is verified.
+    pub(super) fn set_seeding(&mut self, seeding: bool) {
+        self.seeding = seeding;
+    }
+
     /// Closes the nexus child.
     pub(crate) async fn close(&mut self) -> Result<(), BdevError> {
         info!("{:?}: closing child...", self);
@@ -910,17 +1035,19 @@ impl<'c> NexusChild<'c> {
         }
 
         match state {
-            ChildState::Open | ChildState::Faulted(Reason::OutOfSync) => {
+            ChildState::Open
+            | ChildState::Faulted(Reason::OutOfSync)
+            | ChildState::Faulted(Reason::Offline) => {
                 // Change the state of the child to ensure it is taken out of
                 // the I/O path when the nexus is reconfigured.
-                self.set_state(ChildState::Closed);
+                self.transition(ChildState::Closed, "device removed");
             }
             // leave the state into whatever we found it as
             _ => {
                 if was_destroying {
                     // Restore the previous state
                     info!("{:?}: reverting to previous state: {}", self, state);
-                    self.set_state(state);
+                    self.transition(state, "device removal completed");
                 }
             }
         }
@@ -983,8 +1110,12 @@ impl<'c> NexusChild<'c> {
             device_descriptor: None,
             state: AtomicCell::new(ChildState::Init),
             prev_state: AtomicCell::new(ChildState::Init),
+            fault_detail: AtomicCell::new(None),
             remove_channel: mpsc::channel(0),
             rebuild_job: None,
+            seeding: false,
+            history: TransitionLog::default(),
+            rmw: Arc::new(RmwState::default()),
             _c: Default::default(),
         }
     }
@@ -992,7 +1123,7 @@ impl<'c> NexusChild<'c> {
     /// Destroys the child's block device.
     pub(super) async fn destroy_device(&self) -> Result<(), BdevError> {
         if self.device.is_some() {
-            self.set_state(ChildState::Destroying);
+            self.transition(ChildState::Destroying, "destroying the device");
             info!("{:?}: destroying block device...", self);
             device_destroy(&self.name).await?;
             info!("{:?}: block device destroyed ok", self);
@@ -1055,11 +1186,46 @@ impl<'c> NexusChild<'c> {
         }
     }
 
+    /// Get I/O handle for the IOs of a nexus with the given block size, which
+    /// are translated into blocks of the child if its block size differs.
+    pub(super) fn get_nexus_io_handle(
+        &self,
+        block_len: u64,
+    ) -> Result<Box<dyn BlockDeviceHandle>, CoreError> {
+        self.get_io_handle()
+            .map(|hdl| BlockAdapter::wrap(hdl, block_len, &self.rmw))
+    }
+
+    /// Returns the statistics of the IOs of the nexus which are not aligned
+    /// on the blocks of the child.
+    pub fn rmw_stats(&self) -> RmwStats {
+        self.rmw.stats()
+    }
+
     /// TODO
     pub fn get_device_name(&self) -> Option<String> {
         self.device.as_ref().map(|d| d.device_name())
     }
 
+    /// Returns true if the device of the child is open.
+    pub(crate) fn is_device_open(&self) -> bool {
+        self.device_descriptor.is_some()
+    }
+
+    /// Returns true if the connection of the child to its device has been
+    /// deferred, see `nexus_lazy`.
+    pub(crate) fn is_deferred(&self) -> bool {
+        self.device.is_none() && self.state() == ChildState::Init
+    }
+
+    /// Attach the device of a child whose connection has been deferred.
+    pub(super) fn attach_device(
+        &mut self,
+        device: Option<Box<dyn BlockDevice>>,
+    ) {
+        self.device = device;
+    }
+
     /// TODO
     pub fn match_device_name(&self, bdev_name: &str) -> bool {
         match self.get_device_name() {
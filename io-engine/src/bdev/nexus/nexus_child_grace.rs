@@ -0,0 +1,502 @@
+//! Grace period of the children losing their transport.
+//!
+//! A child whose I/Os fail because the connection to its device is lost,
+//! for example during a network blip, would be retired on the first error
+//! and fully rebuilt once back, however short the outage. When the retire
+//! policy of the nexus gives a transport grace period, such a child is
+//! suspended instead: it is faulted as timed out and taken out of the I/O
+//! path, but its device is kept open, and the regions written in the
+//! meantime are recorded in a bitmap, as missed by the child.
+//!
+//! The device of a suspended child is probed periodically. Once it responds
+//! again, the regions missed by the child are copied to it from a healthy
+//! child, first while the nexus runs, then a last time with the nexus paused
+//! so that no write is missed, and the child is brought back into the I/O
+//! path. Should the grace period expire first, the child is retired as it
+//! would have been on its first error.
+//!
+//! The last healthy child of the nexus is never suspended, its I/Os are
+//! handled by the retire policy as usual.
+//!
+//! The writes missed by a child detached from the nexus for its undo window
+//! are recorded the same way, see `nexus_child_undo`.
+
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crossbeam::atomic::AtomicCell;
+
+use super::{
+    nexus_lookup_mut,
+    nexus_write_intent::{dma_buf, region_bit, DEFAULT_REGION_SIZE, WORD_BITS},
+    ChildState,
+    DrEvent,
+    FaultDetail,
+    IoErrorKind,
+    Nexus,
+    NexusState,
+    PersistOp,
+    Reason,
+};
+use crate::{
+    core::{
+        BlockDeviceHandle,
+        CoreError,
+        IoCompletionStatus,
+        Reactors,
+        VerboseError,
+    },
+    sleep::mayastor_sleep,
+};
+
+/// Interval at which the device of a suspended child is probed.
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Number of passes copying the missed regions while the nexus runs, before
+/// the last pass made with the nexus paused.
+const CATCH_UP_PASSES: usize = 3;
+
+/// Regions written while a child is suspended.
+pub(super) struct MissedWrites {
+    since: Instant,
+    error: IoErrorKind,
+    /// The child has been detached from the nexus, rather than suspended.
+    detached: bool,
+    /// Size of a region, in blocks.
+    region_blocks: u64,
+    regions: u64,
+    words: Vec<AtomicU64>,
+}
+
+impl MissedWrites {
+    fn new(
+        error: IoErrorKind,
+        detached: bool,
+        num_blocks: u64,
+        block_len: u64,
+    ) -> Self {
+        let region_blocks = DEFAULT_REGION_SIZE / block_len;
+        let regions = (num_blocks + region_blocks - 1) / region_blocks;
+        Self {
+            since: Instant::now(),
+            error,
+            detached,
+            region_blocks,
+            regions,
+            words: (0 .. (regions + WORD_BITS - 1) / WORD_BITS)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+        }
+    }
+
+    /// Record a write to the given blocks.
+    fn mark(&self, offset: u64, num_blocks: u64) {
+        let first = offset / self.region_blocks;
+        let last = (offset + num_blocks.max(1) - 1) / self.region_blocks;
+        for region in first ..= last.min(self.regions - 1) {
+            let (word, bit) = region_bit(region);
+            self.words[word].fetch_or(bit, Ordering::SeqCst);
+        }
+    }
+
+    /// Returns the regions written so far, forgetting them.
+    fn take(&self) -> Vec<u64> {
+        let mut regions = Vec::new();
+        for (i, word) in self.words.iter().enumerate() {
+            let bits = word.swap(0, Ordering::SeqCst);
+            regions.extend(
+                (0 .. WORD_BITS)
+                    .filter(|b| bits & (1 << b) != 0)
+                    .map(|b| i as u64 * WORD_BITS + b),
+            );
+        }
+        regions
+    }
+}
+
+/// Children of a nexus within their grace period, by device name.
+pub(crate) struct ChildGrace {
+    /// Number of suspended children, so that the writes only look for them
+    /// when there are any.
+    count: AtomicCell<usize>,
+    suspended: parking_lot::RwLock<HashMap<String, Arc<MissedWrites>>>,
+}
+
+impl ChildGrace {
+    pub(crate) fn new() -> Self {
+        Self {
+            count: AtomicCell::new(0),
+            suspended: parking_lot::RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Returns true if any child is suspended.
+    #[inline(always)]
+    pub(super) fn any(&self) -> bool {
+        self.count.load() > 0
+    }
+
+    /// Returns true if the given child device is suspended.
+    pub(super) fn is_suspended(&self, device: &str) -> bool {
+        self.any() && self.suspended.read().contains_key(device)
+    }
+
+    /// Record a write to the given blocks as missed by the suspended
+    /// children.
+    #[inline(always)]
+    pub(super) fn write(&self, offset: u64, num_blocks: u64) {
+        if self.any() {
+            self.suspended
+                .read()
+                .values()
+                .for_each(|m| m.mark(offset, num_blocks));
+        }
+    }
+
+    fn get(&self, device: &str) -> Option<Arc<MissedWrites>> {
+        self.suspended.read().get(device).cloned()
+    }
+
+    /// Start recording the writes missed by the given child device, which
+    /// is detached from the nexus.
+    pub(super) fn detach(&self, device: &str, num_blocks: u64, block_len: u64) {
+        let missed = Arc::new(MissedWrites::new(
+            IoErrorKind::Other,
+            true,
+            num_blocks,
+            block_len,
+        ));
+        if self
+            .suspended
+            .write()
+            .insert(device.to_string(), missed)
+            .is_none()
+        {
+            self.count.fetch_add(1);
+        }
+    }
+
+    pub(super) fn end(&self, device: &str) {
+        if self.suspended.write().remove(device).is_some() {
+            self.count.fetch_sub(1);
+        }
+    }
+
+    /// Returns the time each child device has been suspended for, in
+    /// milliseconds.
+    pub(crate) fn suspended(&self) -> HashMap<String, u64> {
+        self.suspended
+            .read()
+            .iter()
+            .filter(|(_, m)| !m.detached)
+            .map(|(d, m)| (d.clone(), m.since.elapsed().as_millis() as u64))
+            .collect()
+    }
+}
+
+/// Returns true if the I/O failed because the transport to the device was
+/// lost.
+fn is_transport_error(status: IoCompletionStatus) -> bool {
+    matches!(
+        IoErrorKind::from(status),
+        IoErrorKind::Aborted | IoErrorKind::SubmissionFailed
+    )
+}
+
+impl<'n> Nexus<'n> {
+    /// Suspend the given child device on a failed I/O, if the failure is
+    /// due to its transport and the retire policy gives a grace period.
+    /// Returns true if the child is suspended, already or from now on.
+    pub(super) fn suspend_child_device(
+        &self,
+        device_name: &str,
+        status: IoCompletionStatus,
+    ) -> bool {
+        if self.child_grace.is_suspended(device_name) {
+            return true;
+        }
+        if self.retire_policy.get().transport_grace_ms == 0
+            || !is_transport_error(status)
+        {
+            return false;
+        }
+
+        let error = IoErrorKind::from(status);
+
+        // the other children are checked with the suspended children locked,
+        // so that two children failing at once are not both suspended
+        {
+            let mut suspended = self.child_grace.suspended.write();
+            if suspended.contains_key(device_name) {
+                return true;
+            }
+            let healthy = self.children_iter().any(|c| {
+                c.is_healthy()
+                    && !c.match_device_name(device_name)
+                    && c.get_device_name()
+                        .map_or(true, |d| !suspended.contains_key(&d))
+            });
+            if !healthy {
+                return false;
+            }
+            suspended.insert(
+                device_name.to_string(),
+                Arc::new(MissedWrites::new(
+                    error,
+                    false,
+                    self.num_blocks(),
+                    self.block_len(),
+                )),
+            );
+            self.child_grace.count.fetch_add(1);
+        }
+
+        let faulted =
+            self.lookup_child_device(device_name).map_or(false, |c| {
+                c.fault_if_open(FaultDetail::Suspended {
+                    error,
+                })
+            });
+        if !faulted {
+            // the child is being retired already
+            self.child_grace.end(device_name);
+            return false;
+        }
+
+        warn!(
+            "{:?}: child device '{}' suspended after a transport error ({:?})",
+            self, device_name, error
+        );
+        Reactors::master().send_future(Nexus::child_grace_routine(
+            self.name.clone(),
+            device_name.to_owned(),
+        ));
+        true
+    }
+
+    /// Take a suspended child out of the I/O path, then probe its device
+    /// until it responds again or the grace period expires.
+    async fn child_grace_routine(nexus_name: String, device_name: String) {
+        if let Some(nexus) = nexus_lookup_mut(&nexus_name) {
+            if let Err(error) =
+                nexus.disconnect_all_channels(device_name.clone()).await
+            {
+                error!(
+                    "{:?}: failed to disconnect suspended device '{}': {}",
+                    nexus,
+                    device_name,
+                    error.verbose()
+                );
+            }
+            if let Some(child) = nexus.lookup_child_device(&device_name) {
+                nexus
+                    .persist(PersistOp::Update {
+                        child_uri: child.uri().to_owned(),
+                        child_state: child.state(),
+                    })
+                    .await;
+            }
+        }
+
+        loop {
+            mayastor_sleep(PROBE_INTERVAL).await.ok();
+
+            let mut nexus = match nexus_lookup_mut(&nexus_name) {
+                Some(nexus) => nexus,
+                None => return,
+            };
+            let missed = match nexus.child_grace.get(&device_name) {
+                Some(missed) => missed,
+                None => return,
+            };
+            if !matches!(
+                *nexus.state.lock(),
+                NexusState::Open | NexusState::Reconfiguring
+            ) || nexus.lookup_child_device(&device_name).is_none()
+            {
+                // the children are being closed, or the child is gone
+                nexus.child_grace.end(&device_name);
+                return;
+            }
+
+            if nexus.probe_child_device(&device_name).await {
+                match nexus.as_mut().child_grace_resume(&device_name).await {
+                    Ok(true) => return,
+                    // the nexus is paused by another operation, retry
+                    Ok(false) => {}
+                    Err(error) => {
+                        error!(
+                            "{:?}: failed to catch up suspended device '{}': {}",
+                            nexus,
+                            device_name,
+                            error.verbose()
+                        );
+                        nexus.child_grace_expire(&device_name, &missed).await;
+                        return;
+                    }
+                }
+            }
+
+            let grace = Duration::from_millis(
+                nexus.retire_policy.get().transport_grace_ms,
+            );
+            if missed.since.elapsed() >= grace {
+                warn!(
+                    "{:?}: grace period of suspended device '{}' expired",
+                    nexus, device_name
+                );
+                nexus.child_grace_expire(&device_name, &missed).await;
+                return;
+            }
+        }
+    }
+
+    /// Returns true if the device of the child responds to a read.
+    async fn probe_child_device(&self, device_name: &str) -> bool {
+        let hdl = match self
+            .lookup_child_device(device_name)
+            .map(|c| c.get_io_handle())
+        {
+            Some(Ok(hdl)) => hdl,
+            _ => return false,
+        };
+        let block_len = self.block_len();
+        match dma_buf(&*hdl, block_len) {
+            Ok(mut buf) => hdl
+                .read_at(self.data_ent_offset * block_len, &mut buf)
+                .await
+                .is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// Copy the regions missed by a suspended child to it from a healthy
+    /// child, and bring the child back into the I/O path. Returns false if
+    /// the nexus could not be paused, for the child to be resumed later.
+    pub(super) async fn child_grace_resume(
+        mut self: Pin<&mut Self>,
+        device_name: &str,
+    ) -> Result<bool, CoreError> {
+        let missed = match self.child_grace.get(device_name) {
+            Some(missed) => missed,
+            None => return Ok(true),
+        };
+        let handles = self.child_grace_handles(device_name)?;
+
+        for _ in 0 .. CATCH_UP_PASSES {
+            let regions = missed.take();
+            if regions.is_empty() {
+                break;
+            }
+            self.catch_up(&handles, &missed, regions).await?;
+        }
+
+        // no write is missed while the nexus is paused
+        if let Err(error) = self.as_mut().pause().await {
+            debug!("{:?}: cannot resume suspended device: {}", self, error);
+            return Ok(false);
+        }
+        let result = self.catch_up(&handles, &missed, missed.take()).await;
+        if result.is_ok() {
+            self.child_grace.end(device_name);
+            self.retire_policy.reset_errors(device_name);
+            let uri = self.lookup_child_device(device_name).map(|c| {
+                c.transition(ChildState::Open, "transport restored");
+                c.uri().to_owned()
+            });
+            self.reconfigure(DrEvent::ChildResume).await;
+            if let Some(uri) = uri {
+                self.persist(PersistOp::Update {
+                    child_uri: uri,
+                    child_state: ChildState::Open,
+                })
+                .await;
+            }
+            info!(
+                "{:?}: suspended device '{}' caught up after {:?}",
+                self,
+                device_name,
+                missed.since.elapsed()
+            );
+        }
+        if let Err(error) = self.as_mut().resume().await {
+            error!("{:?}: failed to resume: {}", self, error.verbose());
+        }
+        result.map(|_| true)
+    }
+
+    /// Returns the handles of a healthy child, then of the suspended child.
+    fn child_grace_handles(
+        &self,
+        device_name: &str,
+    ) -> Result<Vec<Box<dyn BlockDeviceHandle>>, CoreError> {
+        let source = self
+            .children_iter()
+            .filter(|c| c.is_healthy())
+            .find_map(|c| c.get_io_handle().ok())
+            .ok_or_else(|| CoreError::BdevNotFound {
+                name: self.name.clone(),
+            })?;
+        let target = self
+            .lookup_child_device(device_name)
+            .and_then(|c| c.get_io_handle().ok())
+            .ok_or_else(|| CoreError::BdevNotFound {
+                name: device_name.to_owned(),
+            })?;
+        Ok(vec![source, target])
+    }
+
+    /// Copy the given regions from the first handle to the second.
+    async fn catch_up(
+        &self,
+        handles: &[Box<dyn BlockDeviceHandle>],
+        missed: &MissedWrites,
+        regions: Vec<u64>,
+    ) -> Result<(), CoreError> {
+        for region in regions {
+            let offset = region * missed.region_blocks;
+            let num_blocks =
+                missed.region_blocks.min(self.num_blocks() - offset);
+            if let Err(error) =
+                self.resync_region(handles, offset, num_blocks).await
+            {
+                // the region is copied again on the next attempt
+                missed.mark(offset, num_blocks);
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Retire a suspended child whose grace period has expired.
+    async fn child_grace_expire(
+        &self,
+        device_name: &str,
+        missed: &MissedWrites,
+    ) {
+        self.child_grace.end(device_name);
+        if let Some(child) = self.lookup_child_device(device_name) {
+            let detail = FaultDetail::IoError {
+                error: missed.error,
+            };
+            child.transition_with_detail(
+                ChildState::Faulted(Reason::IoError),
+                Some(detail),
+                "transport grace period expired",
+            );
+        }
+        Nexus::child_retire_routine(
+            self.name.clone(),
+            device_name.to_owned(),
+            true,
+        )
+        .await;
+    }
+}
@@ -0,0 +1,150 @@
+//! Two-phase addition of the children.
+//!
+//! Adding a child creates its device, opens it, takes the lease of its
+//! replica and records it in the persistent store, one step after the other.
+//! An io-engine going down half way leaves a connection to the replica, or a
+//! child recorded in the nexus information but not in the definition of the
+//! nexus, with nothing to tell that the addition never completed. When the
+//! persistent store is enabled, the addition is therefore made in two phases:
+//!
+//! - the intent of adding the child is recorded first,
+//! - the child is then opened, its intent updated, and the child registered
+//!   with the nexus and persisted,
+//! - the intent is finally deleted: once persisted, the addition is committed,
+//!   and if it failed, it has been undone.
+//!
+//! An intent found on startup is in doubt. The startup reconciliation
+//! replays the additions whose child had been opened, and rolls back the
+//! others, see `reconcile`.
+
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+
+use super::{nexus_err, Error, Nexus};
+use crate::{
+    persistent_store::PersistentStore,
+    reconcile::child_intent_key,
+    store::store_schema::Versioned,
+};
+
+/// Progress of the addition of a child.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChildIntentPhase {
+    /// The child is being opened.
+    Prepared,
+    /// The child has been opened, and is being registered and persisted.
+    Opened,
+}
+
+/// Intent of adding a child to a nexus, as recorded in the persistent store.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChildIntent {
+    /// Name of the nexus.
+    pub nexus: String,
+    /// UUID of the nexus.
+    pub nexus_uuid: String,
+    /// Key of the persisted NexusInfo structure of the nexus.
+    pub nexus_info_key: String,
+    /// URI of the child.
+    pub child_uri: String,
+    /// Progress of the addition.
+    pub phase: ChildIntentPhase,
+}
+
+impl Versioned for ChildIntent {
+    const KIND: &'static str = "child addition intent";
+}
+
+/// Addition of a child in progress. Without the persistent store, the
+/// addition is not recorded.
+pub(super) struct ChildAddition {
+    key: Option<String>,
+    intent: ChildIntent,
+}
+
+impl ChildAddition {
+    /// Record that the child has been opened.
+    /// A failure only means that the addition is rolled back rather than
+    /// replayed should this node go down before it completes.
+    pub(super) async fn opened(&mut self) {
+        let key = match &self.key {
+            Some(key) => key,
+            None => return,
+        };
+        self.intent.phase = ChildIntentPhase::Opened;
+        if let Err(e) = PersistentStore::put_record(key, &self.intent).await {
+            warn!(
+                "Failed to record child '{}' of nexus {} as opened: {}",
+                self.intent.child_uri, self.intent.nexus, e
+            );
+        }
+    }
+
+    /// Delete the intent once the addition has been committed, or undone if
+    /// it failed. An intent left behind is resolved on startup.
+    pub(super) async fn finish(self, committed: bool) {
+        let key = match self.key {
+            Some(key) => key,
+            None => return,
+        };
+        debug!(
+            "Addition of child '{}' to nexus {} {}",
+            self.intent.child_uri,
+            self.intent.nexus,
+            if committed {
+                "committed"
+            } else {
+                "rolled back"
+            }
+        );
+        if let Err(e) = PersistentStore::delete(&key).await {
+            warn!(
+                "Failed to delete the intent of adding child '{}' to nexus \
+                {}: {}",
+                self.intent.child_uri, self.intent.nexus, e
+            );
+        }
+    }
+}
+
+impl<'n> Nexus<'n> {
+    /// Record the intent of adding the given child, the first phase of its
+    /// addition.
+    pub(super) async fn prepare_child_addition(
+        &self,
+        uri: &str,
+    ) -> Result<ChildAddition, Error> {
+        let intent = ChildIntent {
+            nexus: self.name.clone(),
+            nexus_uuid: self.uuid().to_string(),
+            nexus_info_key: self
+                .nexus_info
+                .lock()
+                .await
+                .info_key(self.uuid().to_string()),
+            child_uri: uri.to_string(),
+            phase: ChildIntentPhase::Prepared,
+        };
+
+        // a fenced nexus does not persist its children either
+        if !PersistentStore::enabled() || self.fenced.load() {
+            return Ok(ChildAddition {
+                key: None,
+                intent,
+            });
+        }
+
+        let key = child_intent_key(&self.name, uri);
+        PersistentStore::put_record(&key, &intent).await.context(
+            nexus_err::RecordChildAddition {
+                child: uri.to_string(),
+                name: self.name.clone(),
+            },
+        )?;
+        Ok(ChildAddition {
+            key: Some(key),
+            intent,
+        })
+    }
+}
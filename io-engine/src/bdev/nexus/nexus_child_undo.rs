@@ -0,0 +1,365 @@
+//! Undo window of the removal of the children.
+//!
+//! Removing a child closes its device, so adding it back after an accidental
+//! removal means rebuilding it in full. When the nexus is given an undo
+//! window, removing a healthy child detaches it instead: it is taken out of
+//! the nexus, but its device is kept open and claimed, and the regions
+//! written in the meantime are recorded as missed by the child, as for a
+//! child within its transport grace period.
+//!
+//! Restoring the child within the window adds it back and only copies the
+//! regions it missed, from a healthy child. Once the window expires, or if
+//! the child is removed again, its device is closed as it would have been on
+//! removal. A child which is not healthy, or the last healthy child, is
+//! removed at once.
+
+use std::{
+    pin::Pin,
+    time::{Duration, Instant},
+};
+
+use crossbeam::atomic::AtomicCell;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    nexus_lookup,
+    nexus_lookup_mut,
+    AdminAction,
+    ChildState,
+    DrEvent,
+    Error,
+    FaultDetail,
+    Nexus,
+    NexusChild,
+    NexusOperation,
+    NexusStatus,
+    PersistOp,
+    Reason,
+};
+use crate::{
+    core::{Reactors, VerboseError},
+    jsonrpc::jsonrpc_register,
+    sleep::mayastor_sleep,
+};
+
+/// Child detached from its nexus.
+struct DetachedChild<'n> {
+    child: NexusChild<'n>,
+    device: String,
+    since: Instant,
+}
+
+/// Children detached from a nexus, within their undo window.
+pub(crate) struct ChildUndo<'n> {
+    /// Undo window, in milliseconds. Zero disables it.
+    window_ms: AtomicCell<u64>,
+    detached: parking_lot::Mutex<Vec<DetachedChild<'n>>>,
+}
+
+impl<'n> ChildUndo<'n> {
+    pub(crate) fn new() -> Self {
+        Self {
+            window_ms: AtomicCell::new(0),
+            detached: parking_lot::Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns the undo window of the removals.
+    pub(crate) fn window(&self) -> Duration {
+        Duration::from_millis(self.window_ms.load())
+    }
+
+    fn take(&self, uri: &str) -> Option<DetachedChild<'n>> {
+        let mut detached = self.detached.lock();
+        let idx = detached.iter().position(|d| d.child.uri() == uri)?;
+        Some(detached.remove(idx))
+    }
+
+    /// Returns the detached children, along with the time left of their
+    /// undo window, in milliseconds.
+    fn list(&self) -> Vec<DetachedChildInfo> {
+        let window = self.window();
+        self.detached
+            .lock()
+            .iter()
+            .map(|d| DetachedChildInfo {
+                uri: d.child.uri().to_owned(),
+                remaining_ms: window
+                    .saturating_sub(d.since.elapsed())
+                    .as_millis() as u64,
+            })
+            .collect()
+    }
+}
+
+/// Child detached from a nexus, as listed.
+#[derive(Serialize, Debug)]
+struct DetachedChildInfo {
+    uri: String,
+    remaining_ms: u64,
+}
+
+impl<'n> Nexus<'n> {
+    /// Set the undo window of the removal of the children, zero disabling
+    /// it. The children already detached keep their window.
+    pub fn set_undo_window(&self, window: Duration) {
+        info!(
+            "{:?}: setting the undo window of child removals to {:?}",
+            self, window
+        );
+        self.child_undo.window_ms.store(window.as_millis() as u64);
+    }
+
+    /// Detach the child at the given index rather than closing it, for its
+    /// removal to be undone within the undo window. Returns false if the
+    /// child cannot be detached, for it to be removed at once.
+    pub(super) async fn detach_child(
+        mut self: Pin<&mut Self>,
+        idx: usize,
+    ) -> Result<bool, Error> {
+        let window = self.child_undo.window();
+        let child = self.child_at(idx);
+        let uri = child.uri().to_owned();
+        let device = match child.get_device_name() {
+            Some(device) if child.state() == ChildState::Open => device,
+            _ => return Ok(false),
+        };
+        if window.is_zero()
+            || !self
+                .children_iter()
+                .any(|c| c.uri() != uri && c.is_healthy())
+        {
+            return Ok(false);
+        }
+
+        // pausing waits for the IO in flight to complete, the writes are
+        // recorded as missed by the child from then on
+        self.as_mut().pause().await?;
+        self.child_grace
+            .detach(&device, self.num_blocks(), self.block_len());
+        self.child_at(idx).transition_with_detail(
+            ChildState::Faulted(Reason::Offline),
+            Some(FaultDetail::Admin {
+                action: AdminAction::Remove,
+            }),
+            "removal deferred",
+        );
+        self.reconfigure(DrEvent::ChildOffline).await;
+
+        let child = unsafe { self.as_mut().child_take_at_unsafe(idx) };
+        let since = Instant::now();
+        self.child_undo.detached.lock().push(DetachedChild {
+            child,
+            device,
+            since,
+        });
+
+        self.persist(PersistOp::Update {
+            child_uri: uri.clone(),
+            child_state: ChildState::Faulted(Reason::Offline),
+        })
+        .await;
+        self.as_mut().resume().await?;
+
+        info!(
+            "{:?}: child '{}' detached, its removal can be undone for {:?}",
+            self, uri, window
+        );
+        Reactors::master().send_future(Nexus::child_undo_routine(
+            self.name.clone(),
+            uri,
+            since,
+            window,
+        ));
+        Ok(true)
+    }
+
+    /// Close the device of a detached child, completing its removal.
+    /// Returns false if the child is not detached.
+    pub(super) async fn close_detached_child(
+        mut self: Pin<&mut Self>,
+        uri: &str,
+    ) -> bool {
+        let detached = match self.child_undo.take(uri) {
+            Some(detached) => detached,
+            None => return false,
+        };
+        self.child_grace.end(&detached.device);
+
+        // the child is closed from the nexus, which handles the removal of
+        // its device
+        unsafe {
+            self.as_mut().child_add_unsafe(detached.child);
+        }
+        let idx = self.child_count() - 1;
+        self.child_at(idx)
+            .lease_release(&self.uuid().to_string())
+            .await;
+        if let Err(error) =
+            unsafe { self.as_mut().child_at_mut(idx).close().await }
+        {
+            error!(
+                "{:?}: failed to close detached child '{}': {}",
+                self,
+                uri,
+                error.verbose()
+            );
+        }
+        unsafe {
+            self.as_mut().child_remove_at_unsafe(idx);
+        }
+        info!("{:?}: detached child '{}' removed", self, uri);
+        true
+    }
+
+    /// Close the devices of all the detached children.
+    pub(super) async fn close_detached_children(mut self: Pin<&mut Self>) {
+        let uris = self
+            .child_undo
+            .detached
+            .lock()
+            .iter()
+            .map(|d| d.child.uri().to_owned())
+            .collect::<Vec<_>>();
+        for uri in uris {
+            self.as_mut().close_detached_child(&uri).await;
+        }
+    }
+
+    /// Complete the removal of a detached child once its undo window
+    /// expires, unless it has been restored in the meantime.
+    async fn child_undo_routine(
+        nexus_name: String,
+        uri: String,
+        since: Instant,
+        window: Duration,
+    ) {
+        mayastor_sleep(window).await.ok();
+        if let Some(nexus) = nexus_lookup_mut(&nexus_name) {
+            let expired = nexus
+                .child_undo
+                .detached
+                .lock()
+                .iter()
+                .any(|d| d.child.uri() == uri && d.since == since);
+            if expired {
+                nexus.close_detached_child(&uri).await;
+            }
+        }
+    }
+
+    /// Undo the removal of a detached child: it is added back, and brought
+    /// back into the I/O path once the regions it missed have been copied
+    /// to it. The child is rebuilt should the copy fail.
+    pub async fn restore_child(
+        mut self: Pin<&mut Self>,
+        uri: &str,
+    ) -> Result<NexusStatus, Error> {
+        self.check_nexus_operation(NexusOperation::ReplicaAdd)?;
+
+        info!("{:?}: restore child request: '{}'", self, uri);
+
+        let detached =
+            self.child_undo
+                .take(uri)
+                .ok_or_else(|| Error::ChildNotFound {
+                    child: uri.to_owned(),
+                    name: self.name.clone(),
+                })?;
+        let device = detached.device;
+        unsafe {
+            self.as_mut().child_add_unsafe(detached.child);
+        }
+
+        match self.as_mut().child_grace_resume(&device).await {
+            Ok(true) => {
+                info!("{:?}: child '{}' restored", self, uri);
+                return Ok(self.status());
+            }
+            Ok(false) => warn!(
+                "{:?}: cannot catch up restored child '{}', rebuilding it",
+                self, uri
+            ),
+            Err(error) => warn!(
+                "{:?}: failed to catch up restored child '{}', rebuilding \
+                it: {}",
+                self,
+                uri,
+                error.verbose()
+            ),
+        }
+
+        self.child_grace.end(&device);
+        self.as_mut().child_mut(uri)?.transition(
+            ChildState::Faulted(Reason::OutOfSync),
+            "restored child to be rebuilt",
+        );
+        self.as_mut().start_rebuild(uri).await?;
+        Ok(self.status())
+    }
+}
+
+/// Arguments of the nexus_set_undo_window json-rpc method.
+#[derive(Debug, Deserialize)]
+struct SetUndoWindowArgs {
+    /// Name of the nexus.
+    name: String,
+    /// Undo window of the removal of the children, in milliseconds.
+    window_ms: u64,
+}
+
+/// Arguments of the nexus_restore_child json-rpc method.
+#[derive(Debug, Deserialize)]
+struct RestoreChildArgs {
+    /// Name of the nexus.
+    name: String,
+    uri: String,
+}
+
+/// Arguments of the nexus_detached_children json-rpc method.
+#[derive(Debug, Deserialize)]
+struct DetachedChildrenArgs {
+    /// Name of the nexus.
+    name: String,
+}
+
+/// Register the child undo json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nexus_set_undo_window", |args: SetUndoWindowArgs| {
+        async move {
+            let nexus =
+                nexus_lookup(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+            nexus.set_undo_window(Duration::from_millis(args.window_ms));
+            Ok(())
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_restore_child", |args: RestoreChildArgs| {
+        async move {
+            let nexus =
+                nexus_lookup_mut(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+            nexus.restore_child(&args.uri).await.map(|_| ())
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register(
+        "nexus_detached_children",
+        |args: DetachedChildrenArgs| {
+            async move {
+                nexus_lookup(&args.name).map(|n| n.child_undo.list()).ok_or(
+                    Error::NexusNotFound {
+                        name: args.name,
+                    },
+                )
+            }
+            .boxed_local()
+        },
+    );
+}
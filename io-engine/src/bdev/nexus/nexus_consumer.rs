@@ -0,0 +1,119 @@
+//! Nexus as a bdev of other SPDK modules.
+//!
+//! The nexus is a regular SPDK bdev, registered along with its io_device, so
+//! that the bdev modules of SPDK, such as crypto or delay, can be layered on
+//! top of it over the SPDK json-rpc socket, and claim it as their base bdev.
+//! A claimed nexus cannot be shared, as the NVMe-oF target claims the bdevs
+//! it shares, nor destroyed: the module layered on top of it must be deleted
+//! first.
+//!
+//! Nexuses can also be created and destroyed over json-rpc, which is also how
+//! they are written to the configuration saved by SPDK, so that a saved
+//! configuration layering modules on top of nexuses can be loaded back.
+
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+
+use super::{nexus_create, nexus_lookup, nexus_lookup_mut, Error, Nexus};
+use crate::jsonrpc::jsonrpc_register;
+
+impl<'n> Nexus<'n> {
+    /// Returns the name of the SPDK module claiming the nexus bdev, unless it
+    /// is the NVMe-oF target sharing the nexus.
+    pub fn claimed_by_module(&self) -> Option<String> {
+        unsafe { self.bdev() }
+            .claimed_by()
+            .filter(|module| module != "NVMe-oF Target")
+    }
+
+    /// Fails if the nexus bdev is claimed by another SPDK module.
+    pub(super) fn check_unclaimed(&self) -> Result<(), Error> {
+        match self.claimed_by_module() {
+            Some(module) => Err(Error::NexusClaimed {
+                name: self.name.clone(),
+                module,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Arguments of the `nexus_create` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct NexusCreateArgs {
+    name: String,
+    uuid: Option<String>,
+    /// Size of the nexus in bytes.
+    size: u64,
+    /// URIs of the children.
+    children: Vec<String>,
+}
+
+/// Arguments of the nexus json-rpc methods.
+#[derive(Debug, Deserialize)]
+struct NexusArgs {
+    /// Name or uuid of the nexus.
+    name: String,
+}
+
+/// Nexus as seen by the SPDK modules.
+#[derive(Debug, Serialize)]
+struct NexusBdevReply {
+    name: String,
+    /// Name of the nexus bdev, to be given to the SPDK modules.
+    bdev: String,
+    /// SPDK module claiming the nexus bdev, if any.
+    claimed_by: Option<String>,
+}
+
+impl From<&Nexus<'_>> for NexusBdevReply {
+    fn from(nexus: &Nexus) -> Self {
+        Self {
+            name: nexus.name.clone(),
+            bdev: nexus.bdev_name(),
+            claimed_by: nexus.claimed_by_module(),
+        }
+    }
+}
+
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nexus_create", |args: NexusCreateArgs| {
+        async move {
+            nexus_create(
+                &args.name,
+                args.size,
+                args.uuid.as_deref(),
+                &args.children,
+            )
+            .await?;
+            nexus_lookup(&args.name).map(NexusBdevReply::from).ok_or(
+                Error::NexusNotFound {
+                    name: args.name,
+                },
+            )
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_destroy", |args: NexusArgs| {
+        async move {
+            let nexus =
+                nexus_lookup_mut(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name,
+                })?;
+            nexus.destroy().await
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_get_bdev", |args: NexusArgs| {
+        async move {
+            nexus_lookup(&args.name).map(NexusBdevReply::from).ok_or(
+                Error::NexusNotFound {
+                    name: args.name,
+                },
+            )
+        }
+        .boxed_local()
+    });
+}
@@ -0,0 +1,202 @@
+//! Errors of the I/Os of a nexus, as reported to the initiators.
+//!
+//! A failed child I/O is retried, or its child retired, so that the host
+//! rarely sees an error; when it does, or to find out why a volume degraded,
+//! `nvme error-log` on the host is where one looks first. The failed child
+//! I/Os of a nexus are therefore kept, the most recent first, and served by
+//! the NVMf target as the Error Information log page of the controllers of
+//! the shared nexus: each entry has the NVMe status of the failure,
+//! translated from the error of the child, and the LBA of the nexus I/O.
+//!
+//! The standard entries have no room for the time of the error nor for the
+//! child it happened on, so they refer to a vendor specific log page, whose
+//! entries, in the same order, give the time of the error, the I/O type,
+//! the number of blocks and the name of the child device.
+
+use std::{
+    collections::VecDeque,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::core::{IoCompletionStatus, IoType, LvolFailure, NvmeStatus};
+
+/// Number of errors kept.
+const ERROR_LOG_ENTRIES: usize = 64;
+/// Size of an entry of the log pages.
+const ERROR_LOG_ENTRY_SIZE: usize = 64;
+/// Log identifier of the vendor specific log page of the errors.
+pub(crate) const VENDOR_ERROR_LOG_LID: u8 = 0xc1;
+/// Size of the name of the child in a vendor specific entry.
+const CHILD_NAME_SIZE: usize = 32;
+
+/// Status code types of the NVMe status.
+const SCT_GENERIC: u8 = 0;
+const SCT_MEDIA_ERROR: u8 = 2;
+/// Generic status codes of the errors which have no NVMe status.
+const SC_INTERNAL_DEVICE_ERROR: u8 = 0x06;
+const SC_CAPACITY_EXCEEDED: u8 = 0x81;
+/// Transport type of the shared nexus.
+const TRTYPE_TCP: u8 = 3;
+
+/// Failed child I/O of a nexus.
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorLogEntry {
+    /// Number of the error, from 1, unique within the nexus.
+    pub error_count: u64,
+    /// Status code type and status code returned for the error.
+    pub sct: u8,
+    pub sc: u8,
+    /// Offset and number of blocks of the nexus I/O.
+    pub lba: u64,
+    pub num_blocks: u64,
+    pub io_type: String,
+    #[serde(skip)]
+    opcode: u8,
+    /// Name of the child device the I/O failed on.
+    pub child: String,
+    /// Time of the error, in microseconds since the epoch.
+    pub timestamp_us: u64,
+}
+
+#[derive(Debug, Default)]
+struct ErrorLogInner {
+    error_count: u64,
+    media_errors: u64,
+    entries: VecDeque<ErrorLogEntry>,
+}
+
+/// Errors of the I/Os of a nexus.
+#[derive(Debug, Default)]
+pub(crate) struct NexusErrorLog {
+    inner: Mutex<ErrorLogInner>,
+}
+
+/// Returns the NVMe status type and code of a failed child I/O.
+fn nvme_status(status: IoCompletionStatus) -> (u8, u8) {
+    match status {
+        IoCompletionStatus::NvmeError(NvmeStatus::Generic(code)) => {
+            (SCT_GENERIC, code as u8)
+        }
+        IoCompletionStatus::NvmeError(NvmeStatus::MediaError(code)) => {
+            (SCT_MEDIA_ERROR, code as u8)
+        }
+        IoCompletionStatus::LvolError(LvolFailure::NoSpace) => {
+            (SCT_GENERIC, SC_CAPACITY_EXCEEDED)
+        }
+        _ => (SCT_GENERIC, SC_INTERNAL_DEVICE_ERROR),
+    }
+}
+
+/// Returns the opcode of the NVMe command of an I/O type.
+fn opcode(io_type: IoType) -> u8 {
+    match io_type {
+        IoType::Flush => 0x00,
+        IoType::Write => 0x01,
+        IoType::Read => 0x02,
+        IoType::WriteZeros => 0x08,
+        IoType::Unmap => 0x09,
+        _ => 0xff,
+    }
+}
+
+impl NexusErrorLog {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a failed child I/O.
+    pub(crate) fn record(
+        &self,
+        child: &str,
+        io_type: IoType,
+        lba: u64,
+        num_blocks: u64,
+        status: IoCompletionStatus,
+    ) {
+        let (sct, sc) = nvme_status(status);
+        let timestamp_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or_default();
+
+        let mut inner = self.inner.lock();
+        inner.error_count += 1;
+        if sct == SCT_MEDIA_ERROR {
+            inner.media_errors += 1;
+        }
+        let entry = ErrorLogEntry {
+            error_count: inner.error_count,
+            sct,
+            sc,
+            lba,
+            num_blocks,
+            io_type: format!("{:?}", io_type),
+            opcode: opcode(io_type),
+            child: child.to_string(),
+            timestamp_us,
+        };
+        if inner.entries.len() == ERROR_LOG_ENTRIES {
+            inner.entries.pop_back();
+        }
+        inner.entries.push_front(entry);
+    }
+
+    /// Returns the number of errors, and of media errors, since the nexus
+    /// was created.
+    pub(crate) fn counts(&self) -> (u64, u64) {
+        let inner = self.inner.lock();
+        (inner.error_count, inner.media_errors)
+    }
+
+    /// Returns the errors recorded, the most recent first.
+    pub fn entries(&self) -> Vec<ErrorLogEntry> {
+        self.inner.lock().entries.iter().cloned().collect()
+    }
+
+    /// Builds the Error Information log page, for the namespace of the given
+    /// id.
+    pub(crate) fn error_log_page(&self, nsid: u32) -> Vec<u8> {
+        let entries = self.entries();
+        let mut log = vec![0u8; entries.len() * ERROR_LOG_ENTRY_SIZE];
+        for (e, buf) in entries
+            .iter()
+            .zip(log.chunks_exact_mut(ERROR_LOG_ENTRY_SIZE))
+        {
+            buf[0 .. 8].copy_from_slice(&e.error_count.to_le_bytes());
+            // the error is not tied to a command of the host
+            buf[8 .. 10].copy_from_slice(&0xffffu16.to_le_bytes());
+            buf[10 .. 12].copy_from_slice(&0xffffu16.to_le_bytes());
+            // the status field, without the phase tag
+            let status = (e.sc as u16) << 1 | ((e.sct as u16) & 0x7) << 9;
+            buf[12 .. 14].copy_from_slice(&status.to_le_bytes());
+            buf[14 .. 16].copy_from_slice(&0xffffu16.to_le_bytes());
+            buf[16 .. 24].copy_from_slice(&e.lba.to_le_bytes());
+            buf[24 .. 28].copy_from_slice(&nsid.to_le_bytes());
+            buf[28] = VENDOR_ERROR_LOG_LID;
+            buf[29] = TRTYPE_TCP;
+        }
+        log
+    }
+
+    /// Builds the vendor specific log page of the errors.
+    pub(crate) fn vendor_log_page(&self) -> Vec<u8> {
+        let entries = self.entries();
+        let mut log = vec![0u8; entries.len() * ERROR_LOG_ENTRY_SIZE];
+        for (e, buf) in entries
+            .iter()
+            .zip(log.chunks_exact_mut(ERROR_LOG_ENTRY_SIZE))
+        {
+            buf[0 .. 8].copy_from_slice(&e.error_count.to_le_bytes());
+            buf[8 .. 16].copy_from_slice(&e.timestamp_us.to_le_bytes());
+            buf[16] = e.opcode;
+            buf[24 .. 32].copy_from_slice(&e.num_blocks.to_le_bytes());
+            // the name of the child is null terminated
+            let len = e.child.len().min(CHILD_NAME_SIZE - 1);
+            buf[32 .. 32 + len].copy_from_slice(&e.child.as_bytes()[.. len]);
+        }
+        log
+    }
+}
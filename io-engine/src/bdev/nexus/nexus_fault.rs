@@ -0,0 +1,261 @@
+//! Structured detail of the faults of the nexus children.
+//!
+//! The `Reason` a child is faulted with is a coarse category, which the
+//! state of the child carries. The `FaultDetail` recorded with the fault
+//! refines it with what the nexus knows about the failure, such as the kind
+//! of I/O error the device completed with, so that the control plane can
+//! tell a transient failure, like a lost connection to a replica, from a
+//! failed device which must be replaced.
+//!
+//! The faults of the children of a nexus are returned by the
+//! `nexus_child_faults` json-rpc method.
+
+use std::fmt::{Display, Formatter};
+
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+
+use super::{nexus_lookup, ChildState, Error, Reason};
+use crate::{
+    core::{GenericStatusCode, IoCompletionStatus, LvolFailure, NvmeStatus},
+    jsonrpc::jsonrpc_register,
+};
+
+/// Kind of a failed I/O.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IoErrorKind {
+    /// The I/O was aborted as the queue of the device was deleted, which
+    /// happens when the connection to the device is lost.
+    Aborted,
+    /// The I/O could not be submitted to the device.
+    SubmissionFailed,
+    /// The device reported an internal error.
+    DeviceError,
+    /// The medium of the device failed.
+    MediaError,
+    Other,
+}
+
+impl From<IoCompletionStatus> for IoErrorKind {
+    fn from(status: IoCompletionStatus) -> Self {
+        match status {
+            IoCompletionStatus::NvmeError(NvmeStatus::Generic(
+                GenericStatusCode::AbortedSubmissionQueueDeleted,
+            )) => Self::Aborted,
+            IoCompletionStatus::NvmeError(NvmeStatus::Generic(
+                GenericStatusCode::InternalDeviceError,
+            )) => Self::DeviceError,
+            IoCompletionStatus::NvmeError(NvmeStatus::MediaError(_)) => {
+                Self::MediaError
+            }
+            IoCompletionStatus::IoSubmissionError(_) => Self::SubmissionFailed,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// Administrative action which faulted a child.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminAction {
+    Fault,
+    Offline,
+    Retire,
+    /// The child has been removed, within the undo window of the removal.
+    Remove,
+}
+
+/// Detail of the fault of a child.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FaultDetail {
+    /// An I/O to the device failed.
+    IoError {
+        error: IoErrorKind,
+    },
+    /// The device did not complete an I/O in time.
+    Timeout,
+    /// An I/O to the device failed on a transport error, the child is
+    /// suspended for its grace period.
+    Suspended {
+        error: IoErrorKind,
+    },
+    /// The thin-provisioned device could not allocate space for a write.
+    NoSpace,
+    /// An admin command to the device failed.
+    AdminCommandFailed,
+    /// Data read from the device did not match its checksum.
+    ChecksumMismatch {
+        lba: u64,
+    },
+    /// The device could not be opened.
+    CantOpen,
+    RebuildFailed,
+    /// The child was faulted by an administrative action.
+    Admin {
+        action: AdminAction,
+    },
+    /// The child is out of sync and is to be rebuilt.
+    OutOfSync,
+    Unknown,
+}
+
+impl FaultDetail {
+    /// Detail of a fault caused by a failed I/O.
+    pub fn io_error(status: IoCompletionStatus) -> Self {
+        match status {
+            IoCompletionStatus::LvolError(LvolFailure::NoSpace) => {
+                Self::NoSpace
+            }
+            status => Self::IoError {
+                error: status.into(),
+            },
+        }
+    }
+
+    /// Returns the reason the child is faulted with.
+    pub fn reason(&self) -> Reason {
+        match self {
+            Self::IoError {
+                ..
+            }
+            | Self::ChecksumMismatch {
+                ..
+            } => Reason::IoError,
+            Self::Timeout
+            | Self::Suspended {
+                ..
+            } => Reason::TimedOut,
+            Self::NoSpace => Reason::NoSpace,
+            Self::AdminCommandFailed => Reason::AdminCommandFailed,
+            Self::CantOpen => Reason::CantOpen,
+            Self::RebuildFailed => Reason::RebuildFailed,
+            Self::Admin {
+                action: AdminAction::Fault,
+            } => Reason::ByClient,
+            Self::Admin {
+                action: AdminAction::Offline | AdminAction::Remove,
+            } => Reason::Offline,
+            Self::Admin {
+                action: AdminAction::Retire,
+            } => Reason::IoError,
+            Self::OutOfSync => Reason::OutOfSync,
+            Self::Unknown => Reason::Unknown,
+        }
+    }
+
+    /// Returns true if the child may recover from the fault without its
+    /// device being replaced, for example once its connection is restored.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::IoError {
+                error: IoErrorKind::Aborted | IoErrorKind::SubmissionFailed,
+            } | Self::Timeout
+                | Self::Suspended { .. }
+                | Self::NoSpace
+                | Self::CantOpen
+                | Self::OutOfSync
+        )
+    }
+}
+
+impl From<Reason> for FaultDetail {
+    fn from(reason: Reason) -> Self {
+        match reason {
+            Reason::Unknown => Self::Unknown,
+            Reason::OutOfSync => Self::OutOfSync,
+            Reason::NoSpace => Self::NoSpace,
+            Reason::TimedOut => Self::Timeout,
+            Reason::CantOpen => Self::CantOpen,
+            Reason::RebuildFailed => Self::RebuildFailed,
+            Reason::IoError => Self::IoError {
+                error: IoErrorKind::Other,
+            },
+            Reason::ByClient => Self::Admin {
+                action: AdminAction::Fault,
+            },
+            Reason::AdminCommandFailed => Self::AdminCommandFailed,
+            Reason::Offline => Self::Admin {
+                action: AdminAction::Offline,
+            },
+        }
+    }
+}
+
+impl Display for FaultDetail {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError {
+                error,
+            } => write!(f, "I/O error ({:?})", error),
+            Self::Timeout => write!(f, "timed out"),
+            Self::Suspended {
+                error,
+            } => write!(f, "suspended on transport error ({:?})", error),
+            Self::NoSpace => write!(f, "no space"),
+            Self::AdminCommandFailed => write!(f, "admin command failed"),
+            Self::ChecksumMismatch {
+                lba,
+            } => write!(f, "checksum mismatch at LBA {}", lba),
+            Self::CantOpen => write!(f, "cannot open"),
+            Self::RebuildFailed => write!(f, "rebuild failed"),
+            Self::Admin {
+                action,
+            } => write!(f, "{:?} by client", action),
+            Self::OutOfSync => write!(f, "out of sync"),
+            Self::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// Arguments of the `nexus_child_faults` json-rpc method.
+#[derive(Deserialize)]
+struct ChildFaultsArgs {
+    /// Name or uuid of the nexus.
+    name: String,
+}
+
+/// Fault of a child, as returned by the `nexus_child_faults` json-rpc
+/// method.
+#[derive(Serialize)]
+struct ChildFault {
+    uri: String,
+    state: String,
+    reason: Option<Reason>,
+    detail: Option<FaultDetail>,
+    transient: Option<bool>,
+}
+
+/// Register the child fault json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nexus_child_faults", |args: ChildFaultsArgs| {
+        async move {
+            let nexus =
+                nexus_lookup(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+
+            Ok(nexus
+                .children_iter()
+                .map(|c| {
+                    let (reason, detail) = match c.state() {
+                        ChildState::Faulted(reason) => {
+                            (Some(reason), c.fault_detail())
+                        }
+                        _ => (None, None),
+                    };
+                    ChildFault {
+                        uri: c.uri().to_string(),
+                        state: c.state().to_string(),
+                        reason,
+                        detail,
+                        transient: detail.map(|d| d.is_transient()),
+                    }
+                })
+                .collect::<Vec<_>>())
+        }
+        .boxed_local()
+    });
+}
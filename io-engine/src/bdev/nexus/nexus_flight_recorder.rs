@@ -0,0 +1,257 @@
+//! Per-nexus I/O flight recorder.
+//!
+//! When enabled on a nexus, the flight recorder keeps the descriptors of the
+//! last N I/Os completed by the nexus: opcode, LBA range, the children the I/O
+//! was routed to along with their completion status, the overall status and
+//! the latency. This allows to look into a single slow or failing volume
+//! without enabling tracing for the whole io-engine.
+
+use std::{
+    collections::VecDeque,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+
+use super::nexus_lookup;
+use crate::{
+    core::{Cores, IoCompletionStatus, IoType},
+    jsonrpc::{jsonrpc_register, Code, JsonRpcError},
+};
+
+/// Number of I/Os kept by default.
+const DEFAULT_CAPACITY: usize = 1024;
+
+/// Child I/O of a recorded nexus I/O.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChildIoRecord {
+    /// Name of the child device.
+    pub device: String,
+    /// Completion status of the child I/O, none if it never completed
+    /// (i.e the nexus I/O failed before).
+    pub status: Option<String>,
+}
+
+/// Descriptor of a nexus I/O.
+#[derive(Debug, Clone, Serialize)]
+pub struct IoRecord {
+    /// Completion time, in RFC 3339 format.
+    pub completed: String,
+    /// Core the I/O has been submitted on.
+    pub core: u32,
+    pub opcode: String,
+    /// Offset of the I/O, in blocks.
+    pub offset: u64,
+    pub num_blocks: u64,
+    /// Child I/Os, in submission order.
+    pub children: Vec<ChildIoRecord>,
+    /// Number of times the I/O has been resubmitted to the children.
+    pub resubmissions: u32,
+    pub success: bool,
+    pub latency_us: u64,
+}
+
+/// State of a recorded I/O while it is in flight. It is referenced from the
+/// context of the nexus I/O.
+pub(super) struct PendingIo {
+    start: Instant,
+    core: u32,
+    opcode: IoType,
+    offset: u64,
+    num_blocks: u64,
+    children: Vec<ChildIoRecord>,
+    resubmissions: u32,
+}
+
+impl PendingIo {
+    pub(super) fn new(opcode: IoType, offset: u64, num_blocks: u64) -> Self {
+        Self {
+            start: Instant::now(),
+            core: Cores::current(),
+            opcode,
+            offset,
+            num_blocks,
+            children: Vec::new(),
+            resubmissions: 0,
+        }
+    }
+
+    /// Record the submission of a child I/O.
+    pub(super) fn child_submitted(&mut self, device: String) {
+        self.children.push(ChildIoRecord {
+            device,
+            status: None,
+        });
+    }
+
+    /// Record the completion of a child I/O.
+    pub(super) fn child_completed(
+        &mut self,
+        device: &str,
+        status: IoCompletionStatus,
+    ) {
+        if let Some(child) = self
+            .children
+            .iter_mut()
+            .find(|c| c.status.is_none() && c.device == device)
+        {
+            child.status = Some(format!("{:?}", status));
+        }
+    }
+
+    /// Record the resubmission of the I/O.
+    pub(super) fn resubmitted(&mut self) {
+        self.resubmissions += 1;
+    }
+
+    /// Complete the record.
+    pub(super) fn finish(self, success: bool) -> IoRecord {
+        IoRecord {
+            completed: chrono::Local::now().to_rfc3339(),
+            core: self.core,
+            opcode: format!("{:?}", self.opcode),
+            offset: self.offset,
+            num_blocks: self.num_blocks,
+            children: self.children,
+            resubmissions: self.resubmissions,
+            success,
+            latency_us: self.start.elapsed().as_micros() as u64,
+        }
+    }
+}
+
+/// Flight recorder of a nexus. I/Os completing on any core are recorded, so
+/// the records are behind a lock; the recorder is meant for debugging and is
+/// disabled by default.
+pub(crate) struct FlightRecorder {
+    enabled: AtomicBool,
+    capacity: AtomicUsize,
+    records: parking_lot::Mutex<VecDeque<IoRecord>>,
+}
+
+impl FlightRecorder {
+    pub(crate) fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            capacity: AtomicUsize::new(DEFAULT_CAPACITY),
+            records: parking_lot::Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Start recording, keeping up to the given number of I/Os.
+    pub(crate) fn enable(&self, capacity: usize) {
+        let capacity = capacity.max(1);
+        let mut records = self.records.lock();
+        while records.len() > capacity {
+            records.pop_front();
+        }
+        self.capacity.store(capacity, Ordering::Relaxed);
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    /// Stop recording and discard the records.
+    pub(crate) fn disable(&self) {
+        self.enabled.store(false, Ordering::Relaxed);
+        self.records.lock().clear();
+    }
+
+    #[inline(always)]
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Add a completed I/O, evicting the oldest one if the recorder is full.
+    pub(super) fn push(&self, record: IoRecord) {
+        if !self.is_enabled() {
+            return;
+        }
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        let mut records = self.records.lock();
+        if records.len() >= capacity {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+
+    /// Returns the recorded I/Os, oldest first.
+    pub(crate) fn records(&self) -> Vec<IoRecord> {
+        self.records.lock().iter().cloned().collect()
+    }
+
+    /// Returns the number of I/Os the recorder keeps.
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity.load(Ordering::Relaxed)
+    }
+}
+
+/// Arguments of the flight recorder json-rpc methods.
+#[derive(Debug, Deserialize)]
+struct FlightRecorderArgs {
+    /// Name of the nexus.
+    name: String,
+    /// Number of I/Os to keep, when enabling the recorder.
+    capacity: Option<usize>,
+}
+
+/// Reply of the flight recorder json-rpc methods.
+#[derive(Debug, Serialize)]
+struct FlightRecorderReply {
+    name: String,
+    enabled: bool,
+    capacity: usize,
+    records: Vec<IoRecord>,
+}
+
+fn lookup_recorder<F, R>(name: &str, f: F) -> Result<R, JsonRpcError>
+where
+    F: FnOnce(&FlightRecorder) -> R,
+{
+    nexus_lookup(name)
+        .map(|n| f(&n.flight_recorder))
+        .ok_or_else(|| {
+            JsonRpcError::new(
+                Code::NotFound,
+                format!("nexus {} not found", name),
+            )
+        })
+}
+
+/// Register the flight recorder json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register(
+        "nexus_flight_recorder_enable",
+        |args: FlightRecorderArgs| {
+            async move {
+                lookup_recorder(&args.name, |r| {
+                    r.enable(args.capacity.unwrap_or(DEFAULT_CAPACITY));
+                })
+            }
+            .boxed_local()
+        },
+    );
+
+    jsonrpc_register(
+        "nexus_flight_recorder_disable",
+        |args: FlightRecorderArgs| {
+            async move { lookup_recorder(&args.name, |r| r.disable()) }
+                .boxed_local()
+        },
+    );
+
+    jsonrpc_register(
+        "nexus_flight_recorder_dump",
+        |args: FlightRecorderArgs| {
+            async move {
+                lookup_recorder(&args.name, |r| FlightRecorderReply {
+                    name: args.name.clone(),
+                    enabled: r.is_enabled(),
+                    capacity: r.capacity(),
+                    records: r.records(),
+                })
+            }
+            .boxed_local()
+        },
+    );
+}
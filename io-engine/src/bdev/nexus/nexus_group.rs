@@ -0,0 +1,364 @@
+//! Consistency groups of nexuses.
+//!
+//! A consistency group is a set of nexuses of this node whose volumes are
+//! used together by an application, for instance the data and the log
+//! volumes of a database. A group snapshot freezes the I/O of all the member
+//! nexuses before snapshotting any of their replicas, and resumes them once
+//! all the replicas have been snapshotted, so that the snapshots of all the
+//! volumes are crash consistent with each other.
+//!
+//! All the snapshots of a group snapshot share the same transaction id, which
+//! is made of the snapshot time in seconds and of a sequence number telling
+//! apart the group snapshots taken within the same second, so that two group
+//! snapshots never share it. The snapshot of each replica is named
+//! `<replica>-snap-<time>`, followed by `-<seq>` if the sequence number is not
+//! zero.
+//!
+//! Groups are kept in memory only and must be re-created by the control
+//! plane after a restart.
+
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use spdk_rs::{libspdk::spdk_nvme_cmd, nvme_admin_opc};
+
+use super::{nexus_lookup, nexus_lookup_mut, Error, Nexus, NexusChild};
+use crate::{
+    core::{UntypedBdev, VerboseError},
+    jsonrpc::jsonrpc_register,
+    lvs::Lvol,
+    subsys,
+};
+
+/// Member nexuses of the consistency groups, by group name.
+static GROUPS: Lazy<Mutex<HashMap<String, Vec<String>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Transaction id of a group snapshot.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+struct TxnId {
+    /// Snapshot time, in seconds.
+    time: u64,
+    /// Number of the group snapshots taken before within the same second.
+    seq: u32,
+}
+
+impl fmt::Display for TxnId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.time, self.seq)
+    }
+}
+
+impl TxnId {
+    /// Returns the name of the snapshot of the given replica or nexus.
+    fn snapshot_name(&self, base_name: &str) -> String {
+        Lvol::format_group_snapshot_name(base_name, self.time, self.seq)
+    }
+}
+
+/// Transaction id of the last group snapshot.
+static LAST_TXN_ID: Lazy<Mutex<TxnId>> =
+    Lazy::new(|| Mutex::new(TxnId::default()));
+
+/// Returns a new transaction id: the current time in seconds, along with the
+/// next sequence number should the clock not have moved on since the last
+/// one.
+fn next_txn_id() -> TxnId {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let mut last = LAST_TXN_ID.lock();
+    *last = if now > last.time {
+        TxnId {
+            time: now,
+            seq: 0,
+        }
+    } else {
+        TxnId {
+            time: last.time,
+            seq: last.seq + 1,
+        }
+    };
+    *last
+}
+
+/// Arguments of the `nexus_group_create` json-rpc method.
+#[derive(Deserialize)]
+struct CreateGroupArgs {
+    /// Name of the group.
+    name: String,
+    /// Names of the member nexuses.
+    nexuses: Vec<String>,
+}
+
+/// Arguments of the `nexus_group_destroy` and `create_group_snapshot`
+/// json-rpc methods.
+#[derive(Deserialize)]
+struct GroupArgs {
+    /// Name of the group.
+    name: String,
+}
+
+/// Consistency group, as returned by the json-rpc methods.
+#[derive(Serialize, Debug)]
+struct GroupReply {
+    name: String,
+    nexuses: Vec<String>,
+}
+
+/// Snapshot of a member nexus of a group snapshot.
+#[derive(Serialize, Debug)]
+struct NexusSnapshotReply {
+    /// Name of the nexus.
+    nexus: String,
+    /// Name of the snapshot of the nexus.
+    name: String,
+    /// URIs of the children which have been snapshotted.
+    children: Vec<String>,
+}
+
+/// Reply of the `create_group_snapshot` json-rpc method.
+#[derive(Serialize, Debug)]
+struct GroupSnapshotReply {
+    /// Name of the group.
+    name: String,
+    /// Transaction id shared by all the snapshots.
+    txn_id: TxnId,
+    snapshots: Vec<NexusSnapshotReply>,
+}
+
+/// Snapshot the given child with the given transaction id as snapshot time.
+async fn snapshot_child(
+    nexus: &Nexus<'_>,
+    child: &NexusChild<'_>,
+    txn_id: TxnId,
+) -> Result<(), Error> {
+    let snapshot_error = |reason: String| Error::GroupSnapshot {
+        name: nexus.name.clone(),
+        reason: format!("child {}: {}", child.uri(), reason),
+    };
+
+    let device = child
+        .get_device_name()
+        .ok_or_else(|| snapshot_error("child is not open".to_string()))?;
+
+    if child.is_local() == Some(true) {
+        // a local child is not exported over nvme and does not support the
+        // snapshot admin command, snapshot the lvol directly instead
+        let lvol = UntypedBdev::lookup_by_name(&device)
+            .and_then(|bdev| Lvol::try_from(bdev).ok())
+            .ok_or_else(|| snapshot_error("child is not a lvol".to_string()))?;
+        lvol.snapshot(&txn_id.snapshot_name(&lvol.name()))
+            .await
+            .map_err(|e| snapshot_error(e.to_string()))
+    } else {
+        let mut cmd = spdk_nvme_cmd::default();
+        cmd.set_opc(nvme_admin_opc::CREATE_SNAPSHOT.into());
+        subsys::encode_snapshot_time(&mut cmd, txn_id.time);
+        subsys::encode_snapshot_seq(&mut cmd, txn_id.seq);
+        child
+            .get_io_handle()
+            .map_err(|e| snapshot_error(e.verbose()))?
+            .nvme_admin(&cmd, None)
+            .await
+            .map_err(|e| snapshot_error(e.verbose()))
+    }
+}
+
+/// Snapshot the healthy children of the given nexus.
+async fn snapshot_nexus(
+    nexus: &Nexus<'_>,
+    txn_id: TxnId,
+) -> Result<NexusSnapshotReply, Error> {
+    let mut children = Vec::new();
+    for child in nexus.children_iter().filter(|c| c.is_healthy()) {
+        snapshot_child(nexus, child, txn_id).await?;
+        children.push(child.uri().to_string());
+    }
+
+    Ok(NexusSnapshotReply {
+        nexus: nexus.name.clone(),
+        name: txn_id.snapshot_name(&nexus.bdev_name()),
+        children,
+    })
+}
+
+/// Resume the given paused nexuses, logging the failures.
+async fn resume_all(nexuses: &[String]) {
+    for name in nexuses {
+        if let Some(nexus) = nexus_lookup_mut(name) {
+            if let Err(error) = nexus.resume().await {
+                error!(
+                    "Failed to resume nexus {} of a group snapshot: {}",
+                    name,
+                    error.verbose()
+                );
+            }
+        }
+    }
+}
+
+/// Create a crash consistent snapshot of all the nexuses of the given group.
+async fn create_group_snapshot(
+    name: &str,
+) -> Result<GroupSnapshotReply, Error> {
+    let members = GROUPS.lock().get(name).cloned().ok_or_else(|| {
+        Error::GroupNotFound {
+            name: name.to_string(),
+        }
+    })?;
+
+    for member in &members {
+        let nexus =
+            nexus_lookup(member).ok_or_else(|| Error::NexusNotFound {
+                name: member.clone(),
+            })?;
+        if !nexus.children_iter().any(|c| c.is_healthy()) {
+            return Err(Error::GroupSnapshot {
+                name: member.clone(),
+                reason: "no healthy child".to_string(),
+            });
+        }
+    }
+
+    // freeze all the members before snapshotting any of them
+    let mut paused = Vec::with_capacity(members.len());
+    for member in &members {
+        let result = match nexus_lookup_mut(member) {
+            Some(nexus) => nexus.pause().await,
+            None => Err(Error::NexusNotFound {
+                name: member.clone(),
+            }),
+        };
+        if let Err(error) = result {
+            resume_all(&paused).await;
+            return Err(error);
+        }
+        paused.push(member.clone());
+    }
+
+    let txn_id = next_txn_id();
+    info!(
+        "Creating snapshot {} of nexus group {} ({:?})",
+        txn_id, name, members
+    );
+
+    let mut snapshots = Vec::with_capacity(members.len());
+    let mut result = Ok(());
+    for member in &members {
+        let snapshot = match nexus_lookup(member) {
+            Some(nexus) => snapshot_nexus(nexus, txn_id).await,
+            None => Err(Error::NexusNotFound {
+                name: member.clone(),
+            }),
+        };
+        match snapshot {
+            Ok(snapshot) => snapshots.push(snapshot),
+            Err(error) => {
+                result = Err(error);
+                break;
+            }
+        }
+    }
+
+    resume_all(&paused).await;
+
+    if let Err(error) = result {
+        error!(
+            "Failed to create snapshot {} of nexus group {}, snapshots \
+            already taken: {:?}: {}",
+            txn_id,
+            name,
+            snapshots,
+            error.verbose()
+        );
+        return Err(error);
+    }
+
+    Ok(GroupSnapshotReply {
+        name: name.to_string(),
+        txn_id,
+        snapshots,
+    })
+}
+
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nexus_group_create", |args: CreateGroupArgs| {
+        async move {
+            if args.nexuses.is_empty() {
+                return Err(Error::InvalidArguments {
+                    name: args.name,
+                    args: "a group needs at least one nexus".to_string(),
+                });
+            }
+            for nexus in &args.nexuses {
+                if nexus_lookup(nexus).is_none() {
+                    return Err(Error::NexusNotFound {
+                        name: nexus.clone(),
+                    });
+                }
+            }
+
+            let mut groups = GROUPS.lock();
+            if groups.contains_key(&args.name) {
+                return Err(Error::GroupExists {
+                    name: args.name,
+                });
+            }
+            let mut nexuses = args.nexuses;
+            nexuses.sort();
+            nexuses.dedup();
+            info!("Creating nexus group {} ({:?})", args.name, nexuses);
+            groups.insert(args.name.clone(), nexuses.clone());
+            Ok(GroupReply {
+                name: args.name,
+                nexuses,
+            })
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_group_destroy", |args: GroupArgs| {
+        async move {
+            match GROUPS.lock().remove(&args.name) {
+                Some(_) => {
+                    info!("Destroyed nexus group {}", args.name);
+                    Ok(())
+                }
+                None => Err(Error::GroupNotFound {
+                    name: args.name,
+                }),
+            }
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register::<(), _, _, Error>("nexus_group_list", |_| {
+        async move {
+            let mut groups = GROUPS
+                .lock()
+                .iter()
+                .map(|(name, nexuses)| GroupReply {
+                    name: name.clone(),
+                    nexuses: nexuses.clone(),
+                })
+                .collect::<Vec<_>>();
+            groups.sort_by(|a, b| a.name.cmp(&b.name));
+            Ok(groups)
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("create_group_snapshot", |args: GroupArgs| {
+        async move { create_group_snapshot(&args.name).await }.boxed_local()
+    });
+}
@@ -0,0 +1,112 @@
+//! SMART / Health Information log page of a shared nexus.
+//!
+//! Monitoring agents of the hosts read the health of their NVMe devices from
+//! this log page; SPDK fills it with zeroes, so a volume always looks pristine
+//! to them. The page of a nexus is instead derived from its state:
+//!
+//! - the available spare is the redundancy left, the share of the children
+//!   beyond the first which are healthy, with a threshold of 1% so that a nexus
+//!   left without redundancy raises the spare critical warning,
+//! - a nexus which is degraded or faulted raises the reliability critical
+//!   warning,
+//! - the percentage used is the fill level of the fullest pool of its local
+//!   children, the pools of the remote ones being unknown to this node,
+//! - the data units and commands read and written are those of the nexus,
+//! - the media errors and error log entries come from its error log.
+//!
+//! The composite temperature is left at zero, as not reported.
+
+use std::convert::TryFrom;
+
+use crate::{
+    bdev::nexus::{nexus_lookup, NexusStatus},
+    core::UntypedBdev,
+    lvs::Lvol,
+};
+
+/// Size of the SMART / Health Information log page.
+const HEALTH_LOG_SIZE: usize = 512;
+/// Data units are of 1000 blocks of 512 bytes.
+const DATA_UNIT_BYTES: u64 = 512_000;
+/// Critical warnings.
+const WARN_SPARE_BELOW_THRESHOLD: u8 = 1 << 0;
+const WARN_RELIABILITY_DEGRADED: u8 = 1 << 2;
+/// Available spare below which the spare critical warning is raised.
+const SPARE_THRESHOLD: u8 = 1;
+
+/// Writes a 128-bit counter of the log page.
+fn put_counter(log: &mut [u8], offset: usize, value: u64) {
+    log[offset .. offset + 16].copy_from_slice(&(value as u128).to_le_bytes());
+}
+
+/// Returns the share of the redundancy of the nexus left, in percent.
+fn available_spare(healthy: usize, total: usize) -> u8 {
+    if total > 1 {
+        (100 * healthy.saturating_sub(1) / (total - 1)) as u8
+    } else {
+        (100 * healthy.min(1)) as u8
+    }
+}
+
+/// Builds the SMART / Health Information log page of the given nexus, or
+/// None if there is no such nexus.
+pub(crate) async fn health_log_page(name: &str) -> Option<Vec<u8>> {
+    let (status, healthy, total, pools_used, errors) = {
+        let nexus = nexus_lookup(name)?;
+        let healthy =
+            nexus.children().iter().filter(|c| c.is_healthy()).count();
+        let pools_used = nexus
+            .children()
+            .iter()
+            .filter(|c| c.is_local() == Some(true))
+            .filter_map(|c| c.get_device_name())
+            .filter_map(|d| UntypedBdev::lookup_by_name(&d))
+            .filter_map(|b| Lvol::try_from(b).ok())
+            .map(|l| {
+                let lvs = l.lvs();
+                lvs.used() * 100 / lvs.capacity().max(1)
+            })
+            .max()
+            .unwrap_or_default();
+        (
+            nexus.status(),
+            healthy,
+            nexus.child_count(),
+            pools_used,
+            nexus.error_log.counts(),
+        )
+    };
+    let stats = UntypedBdev::lookup_by_name(name)?
+        .stats_async()
+        .await
+        .unwrap_or_default();
+
+    let mut log = vec![0u8; HEALTH_LOG_SIZE];
+    let spare = available_spare(healthy, total);
+    if spare < SPARE_THRESHOLD {
+        log[0] |= WARN_SPARE_BELOW_THRESHOLD;
+    }
+    if status != NexusStatus::Online {
+        log[0] |= WARN_RELIABILITY_DEGRADED;
+    }
+    // the composite temperature, in bytes 1 and 2, is not reported
+    log[3] = spare;
+    log[4] = SPARE_THRESHOLD;
+    log[5] = pools_used.min(255) as u8;
+    put_counter(
+        &mut log,
+        32,
+        (stats.bytes_read + DATA_UNIT_BYTES - 1) / DATA_UNIT_BYTES,
+    );
+    put_counter(
+        &mut log,
+        48,
+        (stats.bytes_written + DATA_UNIT_BYTES - 1) / DATA_UNIT_BYTES,
+    );
+    put_counter(&mut log, 64, stats.num_read_ops);
+    put_counter(&mut log, 80, stats.num_write_ops);
+    let (error_count, media_errors) = errors;
+    put_counter(&mut log, 160, media_errors);
+    put_counter(&mut log, 176, error_count);
+    Some(log)
+}
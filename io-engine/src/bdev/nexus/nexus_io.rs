@@ -7,37 +7,54 @@ use libc::c_void;
 use nix::errno::Errno;
 
 use spdk_rs::{
-    libspdk::{spdk_bdev_io, spdk_io_channel},
+    libspdk::{
+        spdk_bdev_io,
+        spdk_bdev_io_complete_nvme_status,
+        spdk_io_channel,
+    },
     BdevIo,
 };
 
 use super::{
+    nexus_checksum::{gather, scatter, schedule_repair, Verified, UNKNOWN},
+    nexus_flight_recorder::PendingIo,
     nexus_lookup_mut,
+    nexus_readahead::Prefetch,
+    nexus_retire_policy::RetireAction,
+    nexus_write_ack::{AsyncWrite, WriteAckMode},
+    FaultDetail,
     Nexus,
     NexusChannel,
     NexusState,
-    Reason,
     NEXUS_PRODUCT_ID,
 };
 
-use crate::core::{
-    device_cmd_queue,
-    BlockDevice,
-    BlockDeviceHandle,
-    CoreError,
-    Cores,
-    DeviceCommand,
-    GenericStatusCode,
-    IoCompletionStatus,
-    IoStatus,
-    IoSubmissionFailure,
-    IoType,
-    LvolFailure,
-    Mthread,
-    NvmeStatus,
-    Reactors,
+use crate::{
+    core::{
+        device_cmd_queue,
+        BlockDevice,
+        BlockDeviceHandle,
+        CoreError,
+        Cores,
+        DeviceCommand,
+        GenericStatusCode,
+        IoCompletionStatus,
+        IoStatus,
+        IoSubmissionFailure,
+        IoType,
+        Mthread,
+        NvmeStatus,
+        Reactors,
+    },
+    rebuild,
 };
 
+/// NVMe status code type of the generic command statuses.
+const NVME_SCT_GENERIC: i32 = 0;
+
+/// NVMe generic status code of a write to a write-protected namespace.
+const NVME_SC_WRITE_PROTECTED: i32 = 0x20;
+
 /// TODO
 #[repr(C)]
 #[derive(Debug)]
@@ -51,12 +68,168 @@ pub(super) struct NioCtx<'n> {
     channel: spdk_rs::IoChannel<NexusChannel<'n>>,
     /// the IO must fail regardless of when it completes
     must_fail: bool,
+    /// flight recorder state of the IO, null if the IO is not recorded
+    recording: *mut PendingIo,
+    /// number of times the IO has been retried without retiring the failed
+    /// child, as allowed by the retire policy
+    retries: u8,
+    /// the IO was admitted by the admission control of the nexus
+    admitted: bool,
+    /// the IO is accounted for in the write-intent log of the nexus
+    intent: bool,
+    /// the checksums of the blocks written by the IO have been marked
+    /// unknown on the children
+    checksums_cleared: bool,
+    /// ticks at which the IO was submitted, 0 if its latency is not measured
+    submitted: u64,
+    /// ticks at which the read was submitted to its child, 0 if it was not
+    read_submitted: u64,
+    /// ticks past which the IO is no longer submitted to the children, 0 if
+    /// it has no deadline
+    deadline: u64,
+    /// ticks at which the IO was submitted, 0 if it is not traced
+    traced: u64,
+    /// ticks at which the traced IO was dispatched to the children
+    dispatched: u64,
+    /// ticks at which the traced IO was last completed by a child
+    child_completed: u64,
 }
 
 /// TODO
 #[repr(transparent)]
 pub(super) struct NexusBio<'n>(BdevIo<Nexus<'n>>);
 
+/// Context of a write merging adjacent nexus writes.
+struct MergedWrite<'n> {
+    bios: Vec<NexusBio<'n>>,
+    /// I/O vectors of all the merged writes.
+    iovs: Vec<libc::iovec>,
+    /// Number of child writes in flight.
+    in_flight: usize,
+    /// The write failed on, or could not be submitted to, a child.
+    failed: bool,
+}
+
+impl MergedWrite<'_> {
+    /// Completion callback of the child writes of a merged write.
+    fn completion(
+        _device: &dyn BlockDevice,
+        status: IoCompletionStatus,
+        ctx: *mut c_void,
+    ) {
+        let merged = ctx as *mut MergedWrite;
+        let write = unsafe { &mut *merged };
+        write.failed |= status != IoCompletionStatus::Success;
+        write.in_flight -= 1;
+        if write.in_flight == 0 {
+            Self::done(merged);
+        }
+    }
+
+    /// Complete the merged writes once the write completed on all the
+    /// children, or submit them again one by one if it failed.
+    fn done(merged: *mut MergedWrite) {
+        let write = unsafe { Box::from_raw(merged) };
+        if write.failed {
+            write.bios[0].nexus().write_merge.fallback();
+            for bio in write.bios {
+                bio.submit_held();
+            }
+        } else {
+            write.bios[0].nexus().write_merge.merged(write.bios.len());
+            for mut bio in write.bios {
+                bio.ok();
+            }
+        }
+    }
+}
+
+/// Context of a child IO submitted in several parts, as the child does not
+/// accept IOs as large as the nexus IO. The parts complete as a single child
+/// IO, failed if any of the parts failed, so that a child is not retired
+/// once per failed part.
+struct SplitIo {
+    /// The nexus IO.
+    io: *mut spdk_bdev_io,
+    /// I/O vectors of the parts.
+    iovs: Vec<Vec<libc::iovec>>,
+    /// Number of parts in flight, plus one while the parts are submitted.
+    in_flight: usize,
+    /// Status of the first part which failed, if any.
+    status: IoCompletionStatus,
+}
+
+impl SplitIo {
+    /// Completion callback of the parts of a split IO.
+    fn completion(
+        device: &dyn BlockDevice,
+        status: IoCompletionStatus,
+        ctx: *mut c_void,
+    ) {
+        let split = ctx as *mut SplitIo;
+        let io = unsafe { &mut *split };
+        if io.status == IoCompletionStatus::Success {
+            io.status = status;
+        }
+        Self::put(split, device);
+    }
+
+    /// Drop a reference on the split IO, completing the child IO once all
+    /// the parts completed.
+    fn put(split: *mut SplitIo, device: &dyn BlockDevice) {
+        let io = unsafe { &mut *split };
+        io.in_flight -= 1;
+        if io.in_flight == 0 {
+            let io = unsafe { Box::from_raw(split) };
+            NexusBio::child_completion(device, io.status, io.io.cast());
+        }
+    }
+}
+
+/// Returns the I/O vectors describing `len` bytes of the given I/O vectors,
+/// starting at byte `offset`.
+fn slice_iovs(
+    iovs: *const libc::iovec,
+    iov_count: usize,
+    mut offset: u64,
+    mut len: u64,
+) -> Vec<libc::iovec> {
+    let mut slice = Vec::new();
+    for i in 0 .. iov_count {
+        if len == 0 {
+            break;
+        }
+        let iov = unsafe { &*iovs.add(i) };
+        let iov_len = iov.iov_len as u64;
+        if offset >= iov_len {
+            offset -= iov_len;
+            continue;
+        }
+        let part = (iov_len - offset).min(len);
+        slice.push(libc::iovec {
+            iov_base: unsafe { (iov.iov_base as *mut u8).add(offset as usize) }
+                .cast(),
+            iov_len: part as usize,
+        });
+        offset = 0;
+        len -= part;
+    }
+    slice
+}
+
+/// Returns the maximum number of nexus blocks of an I/O of the given type
+/// which the device accepts, as the device may have another block size than
+/// the nexus.
+fn max_nexus_blocks(
+    device: &dyn BlockDevice,
+    io_type: IoType,
+    block_len: u64,
+) -> Option<u64> {
+    device
+        .max_io_blocks(io_type)
+        .map(|max| max * device.block_len() / block_len)
+}
+
 impl<'n> Debug for NexusBio<'n> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "{:?} I/O [{:?}]", self.channel(), self.ctx().status)
@@ -85,7 +258,31 @@ impl<'n> From<*mut spdk_bdev_io> for NexusBio<'n> {
 
 impl Clone for NexusBio<'_> {
     fn clone(&self) -> Self {
-        Self::new(self.ctx().channel.clone(), self.0.clone())
+        // the clone shares the context of the IO, which is reset by new()
+        let recording = self.ctx().recording;
+        let retries = self.ctx().retries;
+        let submitted = self.ctx().submitted;
+        let deadline = self.ctx().deadline;
+        let checksums_cleared = self.ctx().checksums_cleared;
+        let admitted = self.ctx().admitted;
+        let intent = self.ctx().intent;
+        let (traced, dispatched, child_completed) = (
+            self.ctx().traced,
+            self.ctx().dispatched,
+            self.ctx().child_completed,
+        );
+        let mut bio = Self::new(self.ctx().channel.clone(), self.0.clone());
+        bio.ctx_mut().recording = recording;
+        bio.ctx_mut().retries = retries;
+        bio.ctx_mut().submitted = submitted;
+        bio.ctx_mut().deadline = deadline;
+        bio.ctx_mut().checksums_cleared = checksums_cleared;
+        bio.ctx_mut().admitted = admitted;
+        bio.ctx_mut().intent = intent;
+        bio.ctx_mut().traced = traced;
+        bio.ctx_mut().dispatched = dispatched;
+        bio.ctx_mut().child_completed = child_completed;
+        bio
     }
 }
 
@@ -105,11 +302,482 @@ impl<'n> NexusBio<'n> {
         ctx.status = IoStatus::Pending;
         ctx.in_flight = 0;
         ctx.must_fail = false;
+        ctx.recording = std::ptr::null_mut();
+        ctx.retries = 0;
+        ctx.admitted = false;
+        ctx.intent = false;
+        ctx.checksums_cleared = false;
+        ctx.submitted = 0;
+        ctx.read_submitted = 0;
+        ctx.deadline = 0;
+        ctx.traced = 0;
+        ctx.dispatched = 0;
+        ctx.child_completed = 0;
         bio
     }
 
+    /// Start recording the IO, if the flight recorder of the nexus is
+    /// enabled.
+    pub(super) fn start_recording(&mut self) {
+        if self.nexus().flight_recorder.is_enabled() {
+            let pending = Box::new(PendingIo::new(
+                self.io_type(),
+                self.offset(),
+                self.num_blocks(),
+            ));
+            self.ctx_mut().recording = Box::into_raw(pending);
+        }
+    }
+
+    /// Update the flight recorder state of the IO, if it is recorded.
+    #[inline(always)]
+    fn record(&self, f: impl FnOnce(&mut PendingIo)) {
+        let recording = self.ctx().recording;
+        if !recording.is_null() {
+            f(unsafe { &mut *recording });
+        }
+    }
+
+    /// Hand over the IO to the flight recorder once it has completed.
+    #[inline]
+    fn finish_recording(&mut self, success: bool) {
+        let recording = self.ctx().recording;
+        if !recording.is_null() {
+            self.ctx_mut().recording = std::ptr::null_mut();
+            let pending = unsafe { Box::from_raw(recording) };
+            self.nexus().flight_recorder.push(pending.finish(success));
+        }
+    }
+
+    /// Start measuring the latency of the IO, if the rebuilds are paced by
+    /// the latency of the nexus, or if the nexus has a latency SLO.
+    #[inline(always)]
+    pub(super) fn start_timing(&mut self) {
+        if rebuild::latency_tracked() || self.nexus().latency_slo.is_enabled() {
+            self.ctx_mut().submitted = rebuild::ticks();
+        }
+    }
+
+    /// Start the deadline of the IO, from the I/O timeout of the initiators
+    /// of the nexus. An IO handed back to the bdev layer is submitted to the
+    /// nexus again, its deadline runs from its first submission.
+    #[inline(always)]
+    pub(super) fn start_deadline(&mut self) {
+        let submitted = unsafe { (*self.as_ptr()).internal.submit_tsc };
+        self.ctx_mut().deadline = self.nexus().io_deadline.start(submitted);
+    }
+
+    /// Fail the IO if its deadline has passed, as the initiator has given up
+    /// on it: it must not be submitted to the children anymore. Returns true
+    /// if the IO has been failed.
+    #[inline(always)]
+    fn deadline_passed(&mut self) -> bool {
+        if !self.nexus().io_deadline.expired(self.ctx().deadline) {
+            return false;
+        }
+        debug!(?self, "IO deadline passed, not submitted to the children");
+        self.fail();
+        true
+    }
+
+    /// Start tracing the IO, if it is sampled by the I/O tracing of the
+    /// nexus.
+    #[inline(always)]
+    pub(super) fn start_trace(&mut self) {
+        if self.channel_mut().sample_trace() {
+            self.ctx_mut().traced = rebuild::ticks();
+        }
+    }
+
+    /// Timestamp the first dispatch of a traced IO to the children.
+    #[inline(always)]
+    fn trace_dispatched(&mut self) {
+        if self.ctx().traced != 0 && self.ctx().dispatched == 0 {
+            self.ctx_mut().dispatched = rebuild::ticks();
+        }
+    }
+
+    /// Timestamp the completion of a traced IO by the given child.
+    #[inline(always)]
+    fn trace_child_completed(&mut self, device: &str) {
+        let dispatched = self.ctx().dispatched;
+        if self.ctx().traced != 0 && dispatched != 0 {
+            let now = rebuild::ticks();
+            self.ctx_mut().child_completed = now;
+            self.nexus()
+                .io_trace
+                .child_completed(device, dispatched, now);
+        }
+    }
+
+    /// Account the stages of a traced IO once it has completed.
+    #[inline(always)]
+    fn finish_trace(&mut self) {
+        let traced = self.ctx().traced;
+        if traced != 0 {
+            self.ctx_mut().traced = 0;
+            self.nexus().io_trace.completed(
+                traced,
+                self.ctx().dispatched,
+                self.ctx().child_completed,
+                rebuild::ticks(),
+            );
+        }
+    }
+
+    /// Account the latency of the IO once it has completed.
+    #[inline(always)]
+    fn finish_timing(&mut self) {
+        let submitted = self.ctx().submitted;
+        if submitted != 0 {
+            self.ctx_mut().submitted = 0;
+            self.nexus().io_latency.completed(submitted);
+            if self.nexus().latency_slo.is_enabled() {
+                self.nexus().latency_slo.completed(submitted);
+            }
+        }
+    }
+
+    /// Account for the completion of the IO, before completing it.
+    fn finish(&mut self, success: bool) {
+        self.finish_recording(success);
+        self.finish_timing();
+        self.finish_trace();
+        self.release();
+        self.end_intent();
+        self.grace_write();
+    }
+
+    /// Complete the IO successfully.
+    fn ok(&mut self) {
+        self.finish(true);
+        self.0.ok();
+    }
+
+    /// Complete the IO with failure.
+    fn fail(&mut self) {
+        self.finish(false);
+        self.0.fail();
+    }
+
+    /// Complete the IO with the given NVMe status, which the NVMf target
+    /// passes on to the initiator as is.
+    fn fail_nvme_status(&mut self, sct: i32, sc: i32) {
+        self.finish(false);
+        unsafe {
+            spdk_bdev_io_complete_nvme_status(self.as_ptr(), 0, sct, sc);
+        }
+    }
+
+    /// Record the write as missed by the children suspended for their grace
+    /// period, and as invalidating the data prefetched for the reads. Writes
+    /// are recorded both when submitted and when completed, so that a write
+    /// landing while the missed regions are being copied is copied again.
+    #[inline(always)]
+    fn grace_write(&self) {
+        if matches!(
+            self.io_type(),
+            IoType::Write | IoType::WriteZeros | IoType::Unmap
+        ) {
+            self.nexus()
+                .child_grace
+                .write(self.offset(), self.num_blocks());
+            self.nexus().readahead.write();
+        }
+    }
+
+    /// Admit the IO if neither the nexus channel nor the children it is
+    /// submitted to have the maximum number of IOs outstanding.
+    fn admit(&mut self) -> bool {
+        let policy = self.nexus().admission.policy();
+        if !policy.enabled {
+            return true;
+        }
+        if !self.channel_mut().queues.acquire(policy.max_nexus_ios) {
+            return false;
+        }
+
+        let queues = &self.channel().queues;
+        let has_room = |h: &dyn BlockDeviceHandle| {
+            queues.has_room(&h.get_device().device_name(), policy.max_child_ios)
+        };
+        let admitted = match self.io_type() {
+            IoType::Read => self.channel().readers().any(has_room),
+            IoType::Write | IoType::WriteZeros | IoType::Unmap => {
+                self.channel().writers().all(has_room)
+            }
+            _ => true,
+        };
+
+        if admitted {
+            self.ctx_mut().admitted = true;
+            self.nexus().admission.admitted();
+        } else {
+            self.channel_mut().queues.release();
+        }
+        admitted
+    }
+
+    /// Release the admission of the IO, once it has completed.
+    #[inline(always)]
+    fn release(&mut self) {
+        if self.ctx().admitted {
+            self.ctx_mut().admitted = false;
+            self.channel_mut().queues.release();
+            self.nexus().admission.release();
+        }
+    }
+
+    /// Returns true if the IO is submitted before the remote children of a
+    /// lazy nexus are connected, in which case it is submitted again once
+    /// they have been.
+    fn begin_connect(&mut self) -> bool {
+        if !self.nexus().lazy.is_pending() {
+            return false;
+        }
+
+        let io = self.as_ptr();
+        Reactors::current().send_future(async move {
+            let mut bio = NexusBio::from(io);
+            match bio.nexus().wait_connected().await {
+                Ok(()) => bio.submit_request(),
+                Err(error) => {
+                    error!(
+                        "{:?}: failed to connect the children: {}",
+                        bio, error
+                    );
+                    bio.fail();
+                }
+            }
+        });
+        true
+    }
+
+    /// Returns true if the IO is a write submitted before the nexus has
+    /// increased the write generation of the volume, in which case it is
+    /// submitted again once the generation has been.
+    fn begin_generation(&mut self) -> bool {
+        if self.nexus().write_generation.is_written()
+            || !matches!(
+                self.io_type(),
+                IoType::Write | IoType::WriteZeros | IoType::Unmap
+            )
+        {
+            return false;
+        }
+
+        let io = self.as_ptr();
+        Reactors::current().send_future(async move {
+            let mut bio = NexusBio::from(io);
+            match bio.nexus().write_generation_begin().await {
+                Ok(()) => bio.submit_request(),
+                Err(error) => {
+                    error!(
+                        "{:?}: failed to increase the write generation: {}",
+                        bio, error
+                    );
+                    bio.fail();
+                }
+            }
+        });
+        true
+    }
+
+    /// Account the write in the write-intent log of the nexus. Returns true
+    /// if the IO is submitted again once its regions have been logged dirty
+    /// on the children.
+    fn begin_intent(&mut self) -> bool {
+        if !self.nexus().write_intent.is_enabled()
+            || !matches!(
+                self.io_type(),
+                IoType::Write | IoType::WriteZeros | IoType::Unmap
+            )
+        {
+            return false;
+        }
+
+        self.ctx_mut().intent = true;
+        if self
+            .nexus()
+            .write_intent
+            .begin(self.offset(), self.num_blocks())
+        {
+            return false;
+        }
+
+        let io = self.as_ptr();
+        Reactors::current().send_future(async move {
+            let mut bio = NexusBio::from(io);
+            match bio.nexus().write_intent_flush(bio.channel()).await {
+                Ok(()) => bio.submit_request(),
+                Err(error) => {
+                    error!(
+                        "{:?}: failed to update the write-intent log: {}",
+                        bio, error
+                    );
+                    bio.fail();
+                }
+            }
+        });
+        true
+    }
+
+    /// Mark the checksums of the blocks written by the IO unknown on the
+    /// children, so that a crash between the write of the data and the update
+    /// of the checksums does not leave stale checksums behind. Returns true as
+    /// the IO is submitted again once they have been marked.
+    fn begin_checksum(&mut self) -> bool {
+        if self.nexus().checksums.algo().is_none()
+            || !matches!(
+                self.io_type(),
+                IoType::Write | IoType::WriteZeros | IoType::Unmap
+            )
+        {
+            return false;
+        }
+
+        let checksums = vec![UNKNOWN; self.num_blocks() as usize];
+        let io = self.as_ptr();
+        Reactors::current().send_future(async move {
+            let mut bio = NexusBio::from(io);
+            let result = bio
+                .nexus()
+                .checksum_update(bio.channel(), bio.offset(), &checksums)
+                .await;
+
+            match result {
+                Ok(()) => {
+                    bio.ctx_mut().checksums_cleared = true;
+                    bio.submit_request();
+                }
+                Err(error) => {
+                    error!("{:?}: failed to clear checksums: {}", bio, error);
+                    bio.fail();
+                }
+            }
+        });
+        true
+    }
+
+    /// Account the completion of the write in the write-intent log.
+    #[inline(always)]
+    fn end_intent(&mut self) {
+        if self.ctx().intent {
+            self.ctx_mut().intent = false;
+            self.nexus()
+                .write_intent
+                .end(self.offset(), self.num_blocks());
+        }
+    }
+
+    /// Hand the IO back to the bdev layer, which submits it again once IOs of
+    /// the nexus channel complete.
+    fn queue(&mut self) {
+        let recording = self.ctx().recording;
+        if !recording.is_null() {
+            self.ctx_mut().recording = std::ptr::null_mut();
+            drop(unsafe { Box::from_raw(recording) });
+        }
+        // the IO is submitted to the nexus afresh
+        self.ctx_mut().traced = 0;
+        self.channel_mut().requeued();
+        self.0.no_mem();
+    }
+
+    /// Account the IOs submitted to the given children.
+    fn children_submitted(&mut self, devices: Vec<String>) {
+        let queues = &mut self.channel_mut().queues;
+        devices.into_iter().for_each(|d| queues.submitted(d));
+    }
+
     /// TODO
     pub(super) fn submit_request(mut self) {
+        // another node owns the nexus, its data must not be touched anymore
+        if self.nexus().fenced.load() {
+            trace!(?self, "nexus is fenced");
+            self.fail();
+            return;
+        }
+
+        // the initiator has given up on an IO past its deadline
+        if self.deadline_passed() {
+            return;
+        }
+
+        if self.nexus().read_only.load()
+            && matches!(
+                self.io_type(),
+                IoType::Write | IoType::WriteZeros | IoType::Unmap
+            )
+        {
+            trace!(?self, "nexus is read-only");
+            self.fail_nvme_status(NVME_SCT_GENERIC, NVME_SC_WRITE_PROTECTED);
+            return;
+        }
+
+        // the first IO of a lazy nexus waits for its remote children to be
+        // connected
+        if self.begin_connect() {
+            trace!(?self, "IO waiting for the children to be connected");
+            return;
+        }
+
+        // the first write of the nexus waits for the write generation of the
+        // volume to be increased
+        if self.begin_generation() {
+            trace!(?self, "IO waiting for the write generation");
+            return;
+        }
+
+        self.grace_write();
+
+        // a nexus on a single local replica forwards the IO to it directly
+        if self.local_fast_path() && self.submit_local() {
+            return;
+        }
+
+        // an IO which is resubmitted has already been admitted
+        if !self.ctx().admitted && !self.admit() {
+            trace!(?self, "IO not admitted, queued");
+            self.nexus().admission.queued();
+            self.queue();
+            return;
+        }
+
+        // the regions written are logged dirty before the write is
+        // submitted; an IO which is resubmitted has been logged already
+        if !self.ctx().intent && self.begin_intent() {
+            trace!(?self, "IO waiting for the write-intent log");
+            return;
+        }
+
+        // the checksums are marked unknown before the data is written, and
+        // updated once it has been
+        if !self.ctx().checksums_cleared && self.begin_checksum() {
+            trace!(?self, "IO waiting for its checksums to be cleared");
+            return;
+        }
+
+        if self.io_type() == IoType::Write {
+            let policy = self.nexus().write_merge.policy();
+            if policy.enabled && self.mergeable() {
+                let mut channel = self.ctx().channel.clone();
+                let poller_channel = channel.clone();
+                channel.channel_data_mut().merger.queue(
+                    self,
+                    &poller_channel,
+                    &policy,
+                );
+                return;
+            }
+        }
+
+        // the writes held to be merged are submitted before any other IO, so
+        // that they are not reordered after a flush or a reset
+        if !self.channel().merger.is_empty() {
+            self.channel_mut().merger.flush();
+        }
+
         if let Err(_e) = match self.io_type() {
             IoType::Read => self.readv(),
             // these IOs are submitted to all the underlying children
@@ -139,6 +807,97 @@ impl<'n> NexusBio<'n> {
         }
     }
 
+    /// Returns true if the IO can be forwarded to the single local child of
+    /// the nexus, none of the features which need the mirroring machinery
+    /// being enabled.
+    #[inline]
+    fn local_fast_path(&self) -> bool {
+        let nexus = self.nexus();
+        self.channel().local
+            && !cfg!(feature = "fault_injection")
+            && self.channel().merger.is_empty()
+            && !nexus.flight_recorder.is_enabled()
+            && !nexus.admission.policy().enabled
+            && !nexus.write_merge.policy().enabled
+            && nexus.checksums.algo().is_none()
+            && !nexus.write_intent.is_enabled()
+            && !nexus.child_grace.any()
+            && !nexus.readahead.is_enabled()
+            && nexus.write_ack.policy().mode == WriteAckMode::All
+    }
+
+    /// Forward the IO to the single local child of the nexus, to complete
+    /// from the completion of the child IO. Returns false if the IO is not
+    /// submitted, for it to take the regular path, which also handles the
+    /// submission failures.
+    fn submit_local(&mut self) -> bool {
+        let hdl = match self.channel().writers().next() {
+            Some(hdl) => hdl,
+            None => return false,
+        };
+        if self.split_size(hdl).is_some() {
+            return false;
+        }
+
+        let offset = self.offset() + self.data_ent_offset();
+        let ctx = self.as_ptr().cast();
+        let result = match self.io_type() {
+            IoType::Read if !self.need_buf() => hdl.readv_blocks(
+                self.iovs(),
+                self.iov_count(),
+                offset,
+                self.num_blocks(),
+                Self::local_completion,
+                ctx,
+            ),
+            IoType::Write => hdl.writev_blocks(
+                self.iovs(),
+                self.iov_count(),
+                offset,
+                self.num_blocks(),
+                Self::local_completion,
+                ctx,
+            ),
+            IoType::Unmap => hdl.unmap_blocks(
+                offset,
+                self.num_blocks(),
+                Self::local_completion,
+                ctx,
+            ),
+            IoType::WriteZeros => hdl.write_zeroes(
+                offset,
+                self.num_blocks(),
+                Self::local_completion,
+                ctx,
+            ),
+            _ => return false,
+        };
+
+        if result.is_err() {
+            return false;
+        }
+        self.trace_dispatched();
+        self.channel_mut().forwarded();
+        self.ctx_mut().in_flight = 1;
+        true
+    }
+
+    /// invoked when an IO forwarded to the local child completes
+    fn local_completion(
+        device: &dyn BlockDevice,
+        status: IoCompletionStatus,
+        ctx: *mut c_void,
+    ) {
+        let mut nexus_io = NexusBio::from(ctx as *mut spdk_bdev_io);
+        if status == IoCompletionStatus::Success {
+            nexus_io.trace_child_completed(&device.device_name());
+            nexus_io.ctx_mut().in_flight = 0;
+            nexus_io.ok();
+        } else {
+            nexus_io.complete(device, status);
+        }
+    }
+
     /// assess the IO if we need to mark it failed or ok.
     /// obtain the Nexus struct embedded within the bdev
     pub(crate) fn nexus(&self) -> &Nexus<'n> {
@@ -175,11 +934,39 @@ impl<'n> NexusBio<'n> {
     ) {
         let success = status == IoCompletionStatus::Success;
 
+        self.record(|r| r.child_completed(&child.device_name(), status));
+        self.trace_child_completed(&child.device_name());
+
+        if self.ctx().admitted {
+            self.channel_mut().queues.completed(&child.device_name());
+        }
+
+        let read_submitted = self.ctx().read_submitted;
+        if read_submitted != 0 {
+            self.ctx_mut().read_submitted = 0;
+            self.nexus()
+                .child_reads
+                .completed(&child.device_name(), read_submitted);
+        }
+
+        let submitted = self.ctx().submitted;
+        if submitted != 0 && self.nexus().latency_slo.is_enabled() {
+            self.nexus()
+                .latency_slo
+                .child_completed(&child.device_name(), submitted);
+        }
+
         debug_assert!(self.ctx().in_flight > 0);
         self.ctx_mut().in_flight -= 1;
 
         if success {
-            self.ok_checked();
+            if self.io_type() == IoType::Read
+                && self.nexus().checksums.algo().is_some()
+            {
+                self.checksum_verify(child.device_name());
+            } else {
+                self.ok_checked();
+            }
         } else {
             // IO failure, mark the IO failed and take the child out
             error!(
@@ -189,6 +976,13 @@ impl<'n> NexusBio<'n> {
                 status,
                 self.ctx()
             );
+            self.nexus().error_log.record(
+                &child.device_name(),
+                self.io_type(),
+                self.offset(),
+                self.num_blocks(),
+                status,
+            );
             self.ctx_mut().status = IoStatus::Failed;
             self.ctx_mut().must_fail = true;
             self.handle_failure(child, status);
@@ -205,12 +999,93 @@ impl<'n> NexusBio<'n> {
                 //warn!(?self, "resubmitted due to must_fail");
                 self.retry_checked();
                 //self.fail();
+            } else if self.nexus().checksums.algo().is_some()
+                && matches!(self.io_type(), IoType::Write | IoType::WriteZeros)
+            {
+                // the checksums of unmapped blocks stay unknown
+                self.checksum_update();
             } else {
                 self.ok();
             }
         }
     }
 
+    /// Update the checksums of the blocks written by the IO on all children,
+    /// completing the IO once they are updated.
+    fn checksum_update(&mut self) {
+        let algo = match self.nexus().checksums.algo() {
+            Some(algo) => algo,
+            None => return self.ok(),
+        };
+        let block_len = self.nexus().block_len();
+        let num_blocks = self.num_blocks() as usize;
+
+        let checksums = match self.io_type() {
+            IoType::Write => algo.checksums(
+                &gather(self.iovs() as *const _, self.iov_count() as usize),
+                block_len,
+            ),
+            IoType::WriteZeros => {
+                vec![algo.checksum(&vec![0; block_len as usize]); num_blocks]
+            }
+            _ => vec![UNKNOWN; num_blocks],
+        };
+
+        let io = self.as_ptr();
+        Reactors::current().send_future(async move {
+            let mut bio = NexusBio::from(io);
+            let result = bio
+                .nexus()
+                .checksum_update(bio.channel(), bio.offset(), &checksums)
+                .await;
+
+            match result {
+                Ok(()) => bio.ok(),
+                Err(error) => {
+                    error!("{:?}: failed to update checksums: {}", bio, error);
+                    bio.fail();
+                }
+            }
+        });
+    }
+
+    /// Verify the data read from the given child device against its
+    /// checksums, completing the IO with the data of another child if it
+    /// does not match.
+    fn checksum_verify(&mut self, device: String) {
+        let io = self.as_ptr();
+        Reactors::current().send_future(async move {
+            let mut bio = NexusBio::from(io);
+            let iovs = bio.iovs() as *const libc::iovec;
+            let iov_count = bio.iov_count() as usize;
+            let data = gather(iovs, iov_count);
+
+            let result = bio
+                .nexus()
+                .checksum_verify(bio.channel(), &device, bio.offset(), &data)
+                .await;
+
+            match result {
+                Ok(Verified::Match) => bio.ok(),
+                Ok(Verified::Reread(data)) => {
+                    scatter(&data, iovs, iov_count);
+                    bio.ok();
+                }
+                Ok(Verified::Copied(good)) => {
+                    scatter(&good, iovs, iov_count);
+                    schedule_repair(
+                        bio.nexus().name.clone(),
+                        device,
+                        bio.offset(),
+                        bio.num_blocks(),
+                    );
+                    bio.ok();
+                }
+                Err(()) => bio.fail(),
+            }
+        });
+    }
+
     /// Complete the IO marking it as failed.
     #[inline]
     fn fail_checked(&mut self) {
@@ -224,6 +1099,8 @@ impl<'n> NexusBio<'n> {
     fn retry_checked(&mut self) {
         if self.ctx().in_flight == 0 {
             debug!(?self, "resubmitting IO");
+            self.record(|r| r.resubmitted());
+            self.channel_mut().retried();
             self.clone().submit_request();
         }
     }
@@ -263,6 +1140,10 @@ impl<'n> NexusBio<'n> {
             });
         }
 
+        if let Some(max_blocks) = self.split_size(hdl) {
+            return self.submit_split(hdl, max_blocks);
+        }
+
         hdl.readv_blocks(
             self.iovs(),
             self.iov_count(),
@@ -273,9 +1154,56 @@ impl<'n> NexusBio<'n> {
         )
     }
 
+    /// Serve the read from the data prefetched on the channel if it holds
+    /// it, and prefetch the data following the stream of reads. Returns true
+    /// if the read has been completed.
+    fn readahead(&mut self) -> bool {
+        let nexus = self.nexus();
+        // the prefetched data is neither verified against the checksums nor
+        // synced with the children lagging behind the asynchronous writes
+        if !nexus.readahead.is_enabled()
+            || nexus.checksums.algo().is_some()
+            || nexus.write_ack.tracker.lagging()
+        {
+            return false;
+        }
+
+        let mut channel = self.ctx().channel.clone();
+        let hit = channel.channel_data_mut().readahead.read(
+            &nexus.readahead,
+            self.offset(),
+            self.num_blocks(),
+            nexus.block_len(),
+            self.iovs() as *const libc::iovec,
+            self.iov_count() as usize,
+        );
+        Prefetch::submit(channel);
+
+        if hit {
+            self.ok();
+        }
+        hit
+    }
+
     /// submit a read operation
     fn do_readv(&mut self) -> Result<(), CoreError> {
-        if let Some(hdl) = self.channel().select_reader() {
+        // the read may have waited for a buffer past its deadline
+        if self.deadline_passed() {
+            return Ok(());
+        }
+
+        if self.readahead() {
+            return Ok(());
+        }
+
+        let tracker = &self.nexus().write_ack.tracker;
+        let hdl = if tracker.lagging() {
+            self.channel().select_synced_reader(tracker)
+        } else {
+            self.channel().select_reader()
+        };
+
+        if let Some(hdl) = hdl {
             let r = self.submit_read(hdl);
 
             if r.is_err() {
@@ -304,6 +1232,16 @@ impl<'n> NexusBio<'n> {
 
                 self.fail();
             } else {
+                self.record(|r| {
+                    r.child_submitted(hdl.get_device().device_name())
+                });
+                let device = hdl.get_device().device_name();
+                self.nexus().child_reads.submitted(&device);
+                self.ctx_mut().read_submitted = rebuild::ticks();
+                self.trace_dispatched();
+                if self.ctx().admitted {
+                    self.children_submitted(vec![device]);
+                }
                 self.ctx_mut().in_flight = 1;
             }
             r
@@ -331,7 +1269,10 @@ impl<'n> NexusBio<'n> {
                 Cores::current(),
                 Mthread::current().unwrap().name()
             );
-            bio.no_mem();
+            // the IO is admitted again once it is submitted again
+            bio.release();
+            bio.queue();
+            return;
         }
 
         let _ = bio.do_readv();
@@ -354,6 +1295,10 @@ impl<'n> NexusBio<'n> {
         &self,
         hdl: &dyn BlockDeviceHandle,
     ) -> Result<(), CoreError> {
+        if let Some(max_blocks) = self.split_size(hdl) {
+            return self.submit_split(hdl, max_blocks);
+        }
+
         hdl.writev_blocks(
             self.iovs(),
             self.iov_count(),
@@ -364,11 +1309,84 @@ impl<'n> NexusBio<'n> {
         )
     }
 
+    /// Submit a write which completes independently of the nexus IO, on a copy
+    /// of its data.
+    fn submit_async_write(
+        &self,
+        hdl: &dyn BlockDeviceHandle,
+    ) -> Result<(), CoreError> {
+        let size = self.num_blocks() * self.nexus().block_len();
+        let mut buf = hdl.dma_malloc(size).map_err(|_| {
+            CoreError::DmaAllocationFailed {
+                size,
+            }
+        })?;
+
+        let data = buf.as_mut_slice();
+        let mut copied = 0;
+        for i in 0 .. self.iov_count() as usize {
+            let iov = unsafe { &*(self.iovs() as *const libc::iovec).add(i) };
+            let src = unsafe {
+                std::slice::from_raw_parts(
+                    iov.iov_base as *const u8,
+                    iov.iov_len,
+                )
+            };
+            data[copied .. copied + iov.iov_len].copy_from_slice(src);
+            copied += iov.iov_len;
+        }
+
+        let mut write =
+            AsyncWrite::new(self.nexus(), hdl.get_device().device_name(), buf);
+        let iov = write.iov();
+        let ctx = Box::into_raw(write);
+
+        hdl.writev_blocks(
+            iov,
+            1,
+            self.offset() + self.data_ent_offset(),
+            self.num_blocks(),
+            AsyncWrite::completion,
+            ctx.cast(),
+        )
+        .map_err(|error| {
+            unsafe { Box::from_raw(ctx) }.submission_failed();
+            error
+        })
+    }
+
+    /// Returns the devices the IO may be written to asynchronously, as
+    /// allowed by the write acknowledgement policy of the nexus.
+    fn async_write_devices(&self) -> Vec<String> {
+        // checksums are updated, and regions logged clean, once all children
+        // have been written
+        if self.io_type() != IoType::Write
+            || self.nexus().write_ack.policy().mode != WriteAckMode::LocalQuorum
+            || self.nexus().checksums.algo().is_some()
+            || self.nexus().write_intent.is_enabled()
+        {
+            return Vec::new();
+        }
+
+        let mut devices = Vec::new();
+        let _ = self.channel().for_each_writer(|h| {
+            let device = h.get_device();
+            devices
+                .push((device.device_name(), device.driver_name() != "nvme"));
+            Ok(())
+        });
+        self.nexus().write_ack.async_devices(&devices)
+    }
+
     #[inline]
     fn submit_unmap(
         &self,
         hdl: &dyn BlockDeviceHandle,
     ) -> Result<(), CoreError> {
+        if let Some(max_blocks) = self.split_size(hdl) {
+            return self.submit_split(hdl, max_blocks);
+        }
+
         hdl.unmap_blocks(
             self.offset() + self.data_ent_offset(),
             self.num_blocks(),
@@ -382,6 +1400,10 @@ impl<'n> NexusBio<'n> {
         &self,
         hdl: &dyn BlockDeviceHandle,
     ) -> Result<(), CoreError> {
+        if let Some(max_blocks) = self.split_size(hdl) {
+            return self.submit_split(hdl, max_blocks);
+        }
+
         hdl.write_zeroes(
             self.offset() + self.data_ent_offset(),
             self.num_blocks(),
@@ -390,6 +1412,131 @@ impl<'n> NexusBio<'n> {
         )
     }
 
+    /// Returns the maximum number of blocks of the IOs submitted to the given
+    /// child, if the IO is larger than what the child accepts.
+    #[inline]
+    fn split_size(&self, hdl: &dyn BlockDeviceHandle) -> Option<u64> {
+        max_nexus_blocks(
+            hdl.get_device(),
+            self.io_type(),
+            self.nexus().block_len(),
+        )
+        .filter(|max| *max > 0 && self.num_blocks() > *max)
+    }
+
+    /// Submit the IO to a child in parts of at most `max_blocks` blocks,
+    /// which complete as a single child IO. If only some of the parts can be
+    /// submitted, the child IO fails once the submitted parts completed.
+    fn submit_split(
+        &self,
+        hdl: &dyn BlockDeviceHandle,
+        max_blocks: u64,
+    ) -> Result<(), CoreError> {
+        let io_type = self.io_type();
+        let block_len = self.nexus().block_len();
+        let num_blocks = self.num_blocks();
+        let offset = self.offset() + self.data_ent_offset();
+
+        let split = Box::into_raw(Box::new(SplitIo {
+            io: self.as_ptr(),
+            iovs: Vec::with_capacity(
+                ((num_blocks + max_blocks - 1) / max_blocks) as usize,
+            ),
+            in_flight: 1,
+            status: IoCompletionStatus::Success,
+        }));
+        let io = unsafe { &mut *split };
+
+        let mut submitted = 0;
+        let mut result = Ok(());
+        while submitted < num_blocks {
+            let blocks = max_blocks.min(num_blocks - submitted);
+            let part_offset = offset + submitted;
+
+            result = match io_type {
+                IoType::Read | IoType::Write => {
+                    let mut iovs = slice_iovs(
+                        self.iovs() as *const libc::iovec,
+                        self.iov_count() as usize,
+                        submitted * block_len,
+                        blocks * block_len,
+                    );
+                    let (iov, iov_count) =
+                        (iovs.as_mut_ptr().cast(), iovs.len() as i32);
+                    io.iovs.push(iovs);
+
+                    if io_type == IoType::Read {
+                        hdl.readv_blocks(
+                            iov,
+                            iov_count,
+                            part_offset,
+                            blocks,
+                            SplitIo::completion,
+                            split.cast(),
+                        )
+                    } else {
+                        hdl.writev_blocks(
+                            iov,
+                            iov_count,
+                            part_offset,
+                            blocks,
+                            SplitIo::completion,
+                            split.cast(),
+                        )
+                    }
+                }
+                IoType::Unmap => hdl.unmap_blocks(
+                    part_offset,
+                    blocks,
+                    SplitIo::completion,
+                    split.cast(),
+                ),
+                IoType::WriteZeros => hdl.write_zeroes(
+                    part_offset,
+                    blocks,
+                    SplitIo::completion,
+                    split.cast(),
+                ),
+                // we should never reach here, if we do it is a bug.
+                _ => unreachable!(),
+            };
+
+            if result.is_err() {
+                break;
+            }
+            io.in_flight += 1;
+            submitted += blocks;
+        }
+
+        if let Err(error) = result {
+            // nothing was submitted, fail the submission to the child
+            if io.in_flight == 1 {
+                drop(unsafe { Box::from_raw(split) });
+                return Err(error);
+            }
+
+            error!(
+                "{:?}: split IO to '{}' partially submitted, {} of {} blocks: \
+                {:?}",
+                self,
+                hdl.get_device().device_name(),
+                submitted,
+                num_blocks,
+                error
+            );
+            io.status = IoCompletionStatus::IoSubmissionError(
+                if io_type == IoType::Read {
+                    IoSubmissionFailure::Read
+                } else {
+                    IoSubmissionFailure::Write
+                },
+            );
+        }
+
+        SplitIo::put(split, hdl.get_device());
+        Ok(())
+    }
+
     #[inline]
     fn submit_reset(
         &self,
@@ -404,11 +1551,36 @@ impl<'n> NexusBio<'n> {
     /// avoid double frees. This function handles IO for a subset that must
     /// be submitted to all the underlying children.
     fn submit_all(&mut self) -> Result<(), CoreError> {
+        // the write may have been held to be merged past its deadline
+        if self.deadline_passed() {
+            return Ok(());
+        }
+
+        self.trace_dispatched();
         let mut inflight = 0;
         // Name of the device which experiences I/O submission failures.
         let mut failed_device = None;
+        // Devices the IO is written to without waiting for completion.
+        let async_devices = self.async_write_devices();
+        // Devices the IO was submitted to, when accounted for admission
+        // control.
+        let admitted = self.ctx().admitted;
+        let mut submitted = Vec::new();
 
         let result = self.channel().for_each_writer(|h| {
+            if !async_devices.is_empty()
+                && async_devices.contains(&h.get_device().device_name())
+            {
+                return self.submit_async_write(h).map_err(|err| {
+                    error!(
+                        "(core: {} thread: {}): async write submission failed with error {:?}",
+                        Cores::current(), Mthread::current().unwrap().name(), err
+                    );
+                    failed_device = Some(h.get_device().device_name());
+                    err
+                });
+            }
+
             match self.io_type() {
                 IoType::Write => self.submit_write(h),
                 IoType::Unmap => self.submit_unmap(h),
@@ -418,6 +1590,12 @@ impl<'n> NexusBio<'n> {
                 _ => unreachable!(),
             }
                 .map(|_| {
+                    self.record(|r| {
+                        r.child_submitted(h.get_device().device_name())
+                    });
+                    if admitted {
+                        submitted.push(h.get_device().device_name());
+                    }
                     inflight += 1;
                 })
                 .map_err(|err| {
@@ -432,6 +1610,10 @@ impl<'n> NexusBio<'n> {
                 })
         });
 
+        if !submitted.is_empty() {
+            self.children_submitted(submitted);
+        }
+
         // Submission errors can also trigger device retire.
         // Such a situation can happen when there is no active I/O in the
         // queues, but error on qpair is observed due to network
@@ -471,109 +1653,150 @@ impl<'n> NexusBio<'n> {
         result
     }
 
-    /// Initiate shutdown of the nexus associated with this BIO request.
-    fn try_self_shutdown_nexus(&mut self) {
-        if self
-            .channel_mut()
-            .nexus_mut()
-            .shutdown_requested
-            .compare_exchange(false, true)
-            .is_ok()
-        {
-            let nexus_name =
-                self.channel_mut().nexus_mut().nexus_name().to_owned();
-
-            Reactors::master().send_future(async move {
-                if let Some(mut nexus) = nexus_lookup_mut(&nexus_name) {
-                    // Check against concurrent graceful nexus shutdown
-                    // initiated by user and mark nexus as being shutdown.
-                    {
-                        let mut s = nexus.state.lock();
-                        match *s {
-                            NexusState::Shutdown |
-                            NexusState::ShuttingDown => {
-                                info!(
-                                    nexus_name,
-                                    "Nexus is under user-triggered shutdown, skipping self shutdown"
-                                );
-                                return;
-                            },
-                            nexus_state => {
-                                info!(
-                                    nexus_name,
-                                    nexus_state=%nexus_state,
-                                    "Initiating self shutdown for nexus"
-                                );
-                            }
-                        };
-                        *s = NexusState::ShuttingDown;
-                    }
-
-                    // 1: Close I/O channels for all children.
-                    let devices = unsafe {
-                        nexus
-                            .as_mut()
-                            .children_iter_mut()
-                            .filter_map(|c| c.get_device_name())
-                            .collect::<Vec<_>>()
-                    };
+    /// Returns true if the write may be merged with adjacent writes: writes
+    /// acknowledged early or whose checksums are updated are not.
+    fn mergeable(&self) -> bool {
+        self.nexus().write_ack.policy().mode == WriteAckMode::All
+            && self.nexus().checksums.algo().is_none()
+    }
 
-                    for d in devices {
-                        if let Err(e) =
-                            nexus.disconnect_all_channels(d.clone()).await
-                        {
-                            error!(
-                                "{}: failed to disconnect I/O channels: {:?}",
-                                d, e
-                            );
-                        }
+    /// Submit a write held to be merged on its own. The write is failed if
+    /// it could not be submitted to any child, and otherwise completes once
+    /// the children it was submitted to complete, failed or retried as per
+    /// the retire policy, as the writes which are not merged are. It may be
+    /// completed by then, so only the error is logged.
+    fn submit_held(mut self) {
+        let name = self.nexus().name.clone();
+        if let Err(error) = self.submit_all() {
+            debug!("{}: held write submission failed: {:?}", name, error);
+        }
+    }
 
-                        device_cmd_queue().enqueue(
-                            DeviceCommand::RemoveDevice {
-                                nexus_name: nexus.name.clone(),
-                                child_device: d.clone(),
-                            },
-                        );
-                    }
+    /// Submit the given adjacent writes to all the children as a single
+    /// write, completing them once it completes on all the children.
+    pub(super) fn submit_merged(bios: Vec<NexusBio<'n>>) {
+        // the writes held past their deadline are failed, not merged
+        let mut bios = bios
+            .into_iter()
+            .filter_map(
+                |mut b| if b.deadline_passed() { None } else { Some(b) },
+            )
+            .collect::<Vec<_>>();
+        if bios.is_empty() {
+            return;
+        }
 
-                    // Step 2: cancel all active rebuild jobs.
-                    let child_uris = nexus.children_uris();
-                    for child in child_uris {
-                        nexus.as_mut().cancel_rebuild_jobs(&child).await;
-                    }
+        if bios.len() == 1 {
+            bios.pop().unwrap().submit_held();
+            return;
+        }
 
-                    // Step 3: close all children.
-                    nexus.as_mut().close_children().await;
+        let first = &bios[0];
+        let offset = first.offset() + first.data_ent_offset();
+        let num_blocks = bios.iter().map(|b| b.num_blocks()).sum();
+
+        // a merged write larger than what a child accepts would have to be
+        // split again, submit the writes as they are instead
+        let block_len = first.nexus().block_len();
+        let mut too_large = false;
+        let _ = first.channel().for_each_writer(|h| {
+            too_large |=
+                max_nexus_blocks(h.get_device(), IoType::Write, block_len)
+                    .map_or(false, |max| num_blocks > max);
+            Ok(())
+        });
+        if too_large {
+            for bio in bios {
+                bio.submit_held();
+            }
+            return;
+        }
+        let iovs = bios
+            .iter()
+            .flat_map(|b| {
+                (0 .. b.iov_count() as usize).map(move |i| unsafe {
+                    *(b.iovs() as *const libc::iovec).add(i)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let merged = Box::into_raw(Box::new(MergedWrite {
+            bios,
+            iovs,
+            in_flight: 0,
+            failed: false,
+        }));
+        let write = unsafe { &mut *merged };
+
+        let channel = write.bios[0].ctx().channel.clone();
+        let result = channel.channel_data().for_each_writer(|h| {
+            h.writev_blocks(
+                write.iovs.as_mut_ptr().cast(),
+                write.iovs.len() as i32,
+                offset,
+                num_blocks,
+                MergedWrite::completion,
+                merged.cast(),
+            )
+            .map(|_| write.in_flight += 1)
+        });
 
-                    // Step 4: Mark nexus as shutdown.
-                    // Note: we don't persist nexus's state in ETCd as nexus
-                    // might be recreated on onother node.
-                    *nexus.state.lock() = NexusState::Shutdown;
-                }
-            });
+        if let Err(error) = result {
+            debug!(
+                "{:?}: merged write submission failed: {:?}",
+                write.bios[0], error
+            );
+            write.failed = true;
+        }
+        if write.in_flight == 0 {
+            MergedWrite::done(merged);
         }
     }
 
+    /// Initiate shutdown of the nexus associated with this BIO request.
+    fn try_self_shutdown_nexus(&mut self) {
+        request_self_shutdown(self.nexus());
+    }
+
     /// TODO
     fn retire_device(
         &mut self,
         child_device: &str,
         io_status: IoCompletionStatus,
     ) {
-        let reason = match io_status {
-            IoCompletionStatus::LvolError(LvolFailure::NoSpace) => {
-                Reason::NoSpace
-            }
-            _ => Reason::IoError,
-        };
-
         self.channel_mut().nexus_mut().retire_child_device(
             child_device,
-            reason,
+            FaultDetail::io_error(io_status),
             true,
         );
     }
 
+    /// Applies the retire policy of the nexus to a failed child IO, and
+    /// retires the child device if the policy says so.
+    fn retire_device_by_policy(
+        &mut self,
+        child_device: &str,
+        io_status: IoCompletionStatus,
+    ) -> RetireAction {
+        // a child losing its transport may be given a grace period to come
+        // back instead
+        if self.nexus().suspend_child_device(child_device, io_status) {
+            return RetireAction::Suspend;
+        }
+
+        let action = self.nexus().child_io_error_action(
+            child_device,
+            FaultDetail::io_error(io_status).reason(),
+            self.ctx().retries,
+        );
+
+        if action == RetireAction::Retire {
+            self.retire_device(child_device, io_status);
+        }
+
+        action
+    }
+
     /// Test handle_failure()
     fn handle_failure(
         &mut self,
@@ -621,16 +1844,26 @@ impl<'n> NexusBio<'n> {
             );
             self.try_self_shutdown_nexus();
         } else {
-            self.retire_device(&child.device_name(), status);
-
-            let retry = matches!(
-                status,
-                IoCompletionStatus::NvmeError(NvmeStatus::Generic(
-                    GenericStatusCode::AbortedSubmissionQueueDeleted
-                ))
-            );
+            let retry = match self
+                .retire_device_by_policy(&child.device_name(), status)
+            {
+                RetireAction::Retire => matches!(
+                    status,
+                    IoCompletionStatus::NvmeError(NvmeStatus::Generic(
+                        GenericStatusCode::AbortedSubmissionQueueDeleted
+                    ))
+                ),
+                RetireAction::Retry => {
+                    self.ctx_mut().retries += 1;
+                    true
+                }
+                RetireAction::Fail => false,
+                RetireAction::Suspend => true,
+            };
 
-            // if the IO was failed because of retire, resubmit the IO
+            // resubmit the IO if it was failed because of retire or of the
+            // suspension of the child, or if the retire policy allows to
+            // retry it
             if retry {
                 return self.ok_checked();
             }
@@ -639,3 +1872,92 @@ impl<'n> NexusBio<'n> {
         self.fail_checked();
     }
 }
+
+/// Initiate the shutdown of a nexus from within the io-engine, unless it has
+/// been requested already: the children are closed and the nexus is marked
+/// as shut down, waiting to be destroyed by the control plane.
+pub(super) fn request_self_shutdown(nexus: &Nexus) {
+    if nexus
+        .shutdown_requested
+        .compare_exchange(false, true)
+        .is_ok()
+    {
+        let nexus_name = nexus.nexus_name().to_owned();
+
+        Reactors::master().send_future(async move {
+            if let Some(mut nexus) = nexus_lookup_mut(&nexus_name) {
+                // Check against concurrent graceful nexus shutdown
+                // initiated by user and mark nexus as being shutdown.
+                {
+                    let mut s = nexus.state.lock();
+                    match *s {
+                        NexusState::Shutdown |
+                        NexusState::ShuttingDown => {
+                            info!(
+                                nexus_name,
+                                "Nexus is under user-triggered shutdown, skipping self shutdown"
+                            );
+                            return;
+                        },
+                        nexus_state => {
+                            info!(
+                                nexus_name,
+                                nexus_state=%nexus_state,
+                                "Initiating self shutdown for nexus"
+                            );
+                        }
+                    };
+                    nexus.transition_locked(
+                        &mut s,
+                        NexusState::ShuttingDown,
+                        "self shutdown after a child failure",
+                    );
+                }
+
+                // 1: Close I/O channels for all children.
+                let devices = unsafe {
+                    nexus
+                        .as_mut()
+                        .children_iter_mut()
+                        .filter_map(|c| c.get_device_name())
+                        .collect::<Vec<_>>()
+                };
+
+                for d in devices {
+                    if let Err(e) =
+                        nexus.disconnect_all_channels(d.clone()).await
+                    {
+                        error!(
+                            "{}: failed to disconnect I/O channels: {:?}",
+                            d, e
+                        );
+                    }
+
+                    device_cmd_queue().enqueue(
+                        DeviceCommand::RemoveDevice {
+                            nexus_name: nexus.name.clone(),
+                            child_device: d.clone(),
+                        },
+                    );
+                }
+
+                // Step 2: cancel all active rebuild jobs.
+                let child_uris = nexus.children_uris();
+                for child in child_uris {
+                    nexus.as_mut().cancel_rebuild_jobs(&child).await;
+                }
+
+                // Step 3: close all children.
+                nexus.as_mut().close_children().await;
+
+                // Step 4: Mark nexus as shutdown.
+                // Note: we don't persist nexus's state in ETCd as nexus
+                // might be recreated on onother node.
+                nexus.transition(
+                    NexusState::Shutdown,
+                    "self shutdown completed",
+                );
+            }
+        });
+    }
+}
@@ -0,0 +1,157 @@
+//! Deadline of the nexus I/Os.
+//!
+//! An initiator gives up on an I/O after its own timeout, and aborts or
+//! resubmits it, possibly on another path, once its controller is reset.
+//! Retrying the I/O on the children past that point only wastes the children
+//! and, worse, may complete an I/O the initiator has already given up on.
+//! When the nexus is given the I/O timeout of its initiators, each I/O
+//! carries a deadline from its first submission to the nexus on, which an
+//! I/O handed back to the bdev layer and submitted again keeps. The deadline
+//! is checked whenever the I/O is submitted to the children: an I/O
+//! resubmitted after a child failure, or submitted once done waiting for the
+//! write-intent log, for admission, for a buffer or for the writes it is
+//! merged with, is failed instead once its deadline has passed.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+
+use spdk_rs::libspdk::spdk_get_ticks_hz;
+
+use super::{nexus_lookup, Error, Nexus};
+use crate::{jsonrpc::jsonrpc_register, rebuild};
+
+/// Deadline of the I/Os of a nexus, along with the I/Os which expired.
+pub(crate) struct IoDeadline {
+    /// I/O timeout of the initiators, in ticks. Zero disables the
+    /// deadline.
+    timeout_ticks: AtomicU64,
+    timeout_ms: AtomicU64,
+    expired: AtomicU64,
+}
+
+impl IoDeadline {
+    pub(crate) fn new() -> Self {
+        Self {
+            timeout_ticks: AtomicU64::new(0),
+            timeout_ms: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the deadline of an I/O submitted at the given ticks, zero if
+    /// there is none.
+    #[inline(always)]
+    pub(super) fn start(&self, submitted: u64) -> u64 {
+        match self.timeout_ticks.load(Ordering::Relaxed) {
+            0 => 0,
+            timeout => submitted + timeout,
+        }
+    }
+
+    /// Returns true if the given deadline has passed, accounting the I/O as
+    /// expired.
+    #[inline(always)]
+    pub(super) fn expired(&self, deadline: u64) -> bool {
+        if deadline == 0 || rebuild::ticks() < deadline {
+            return false;
+        }
+        self.expired.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Returns the I/O timeout of the initiators, in milliseconds.
+    pub(crate) fn timeout_ms(&self) -> u64 {
+        self.timeout_ms.load(Ordering::Relaxed)
+    }
+
+    fn set_timeout_ms(&self, timeout_ms: u64) {
+        let ticks_hz = unsafe { spdk_get_ticks_hz() };
+        self.timeout_ms.store(timeout_ms, Ordering::Relaxed);
+        self.timeout_ticks
+            .store(timeout_ms * ticks_hz / 1000, Ordering::Relaxed);
+    }
+
+    /// Returns the number of I/Os failed as their deadline had passed.
+    pub(crate) fn expired_count(&self) -> u64 {
+        self.expired.load(Ordering::Relaxed)
+    }
+}
+
+impl<'n> Nexus<'n> {
+    /// Set the I/O timeout of the initiators of the nexus, past which the
+    /// I/Os are no longer resubmitted to the children. Zero disables the
+    /// deadline of the I/Os.
+    pub fn set_io_timeout(&self, timeout_ms: u64) {
+        info!("{:?}: setting I/O timeout to {} ms", self, timeout_ms);
+        self.io_deadline.set_timeout_ms(timeout_ms);
+    }
+
+    /// Returns the number of I/Os of the nexus failed as their deadline had
+    /// passed.
+    pub fn expired_ios(&self) -> u64 {
+        self.io_deadline.expired_count()
+    }
+}
+
+/// Arguments of the `nexus_set_io_timeout` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct SetIoTimeoutArgs {
+    /// Name of the nexus.
+    name: String,
+    /// I/O timeout of the initiators, in milliseconds, zero to disable it.
+    timeout_ms: u64,
+}
+
+/// Arguments of the `nexus_get_io_timeout` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct GetIoTimeoutArgs {
+    /// Name of the nexus.
+    name: String,
+}
+
+/// Reply of the I/O timeout json-rpc methods.
+#[derive(Debug, Serialize)]
+struct IoTimeoutReply {
+    name: String,
+    timeout_ms: u64,
+    /// Number of I/Os failed as their deadline had passed.
+    expired: u64,
+}
+
+impl IoTimeoutReply {
+    fn new(nexus: &Nexus) -> Self {
+        Self {
+            name: nexus.name.clone(),
+            timeout_ms: nexus.io_deadline.timeout_ms(),
+            expired: nexus.io_deadline.expired_count(),
+        }
+    }
+}
+
+/// Register the I/O deadline json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nexus_set_io_timeout", |args: SetIoTimeoutArgs| {
+        async move {
+            let nexus =
+                nexus_lookup(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+            nexus.set_io_timeout(args.timeout_ms);
+            Ok(IoTimeoutReply::new(nexus))
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_get_io_timeout", |args: GetIoTimeoutArgs| {
+        async move {
+            nexus_lookup(&args.name).map(IoTimeoutReply::new).ok_or(
+                Error::NexusNotFound {
+                    name: args.name,
+                },
+            )
+        }
+        .boxed_local()
+    });
+}
@@ -0,0 +1,280 @@
+//! Sampled tracing of the I/Os of a nexus.
+//!
+//! The latency of a nexus tells how long its I/Os take, not where the time
+//! goes. When tracing is enabled on a nexus, one in every N I/Os of each of
+//! its channels is timestamped at each stage of the data path:
+//!
+//! - queued: from the submission of the I/O to the nexus to its dispatch to the
+//!   children, covering admission control, the write generation and the
+//!   write-intent log,
+//! - children: from its first dispatch to the last completion by a child,
+//!   covering the retries,
+//! - completion: from then on to the completion of the I/O by the nexus,
+//!   covering the checksums,
+//! - total: from its submission to its completion.
+//!
+//! The latency of each child, from the dispatch of the I/O to its completion
+//! by the child, is accounted as well. The latencies are aggregated in the
+//! histograms of the latency SLO, so that the breakdown is kept at a fixed
+//! cost for as long as tracing is enabled, and an I/O which is not sampled
+//! only costs a countdown on its channel.
+
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use futures::FutureExt;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    nexus_latency_slo::{percentile, Histogram},
+    nexus_lookup,
+    Error,
+    Nexus,
+};
+use crate::{jsonrpc::jsonrpc_register, rebuild::ticks_to_us};
+
+/// Stages of the data path, in the order of the aggregated latencies.
+const STAGES: [&str; 4] = ["queued", "children", "completion", "total"];
+
+/// Latency of a stage of the sampled I/Os.
+#[derive(Default)]
+struct StageLatency {
+    histogram: Histogram,
+    total_us: AtomicU64,
+    max_us: AtomicU64,
+}
+
+impl StageLatency {
+    fn record(&self, us: u64) {
+        self.histogram.record(us);
+        self.total_us.fetch_add(us, Ordering::Relaxed);
+        self.max_us.fetch_max(us, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        self.histogram.take();
+        self.total_us.store(0, Ordering::Relaxed);
+        self.max_us.store(0, Ordering::Relaxed);
+    }
+
+    fn summary(&self, stage: String) -> StageSummary {
+        let counts = self.histogram.counts();
+        let (samples, p50_us) = percentile(&counts, 50.0);
+        let (_, p99_us) = percentile(&counts, 99.0);
+        StageSummary {
+            stage,
+            samples,
+            mean_us: self.total_us.load(Ordering::Relaxed) / samples.max(1),
+            p50_us,
+            p99_us,
+            max_us: self.max_us.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Latency of a stage of the data path, over the sampled I/Os. The
+/// percentiles are the upper bounds of their buckets.
+#[derive(Debug, Clone, Serialize)]
+pub struct StageSummary {
+    /// Stage of the data path, or URI of the child.
+    pub stage: String,
+    pub samples: u64,
+    pub mean_us: u64,
+    pub p50_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+/// Sampled tracing of the I/Os of a nexus.
+pub(crate) struct IoTracer {
+    /// One in this many I/Os of a channel is traced, none if 0.
+    rate: AtomicU64,
+    stages: [StageLatency; 4],
+    /// Latency of the children, by child device.
+    children: RwLock<HashMap<String, StageLatency>>,
+    /// Time tracing was enabled, in RFC 3339 format.
+    since: Mutex<Option<String>>,
+}
+
+impl IoTracer {
+    pub(crate) fn new() -> Self {
+        Self {
+            rate: AtomicU64::new(0),
+            stages: Default::default(),
+            children: RwLock::new(HashMap::new()),
+            since: Mutex::new(None),
+        }
+    }
+
+    /// Returns true if an I/O of the channel of the given countdown is to be
+    /// traced.
+    #[inline(always)]
+    pub(super) fn sample(&self, countdown: &mut u64) -> bool {
+        let rate = self.rate.load(Ordering::Relaxed);
+        if rate == 0 {
+            return false;
+        }
+        if *countdown == 0 || *countdown > rate {
+            *countdown = rate;
+        }
+        *countdown -= 1;
+        *countdown == 0
+    }
+
+    /// Account a traced I/O, from the ticks it was submitted, dispatched to
+    /// the children and last completed by a child at, 0 for the stages it
+    /// did not go through, to now.
+    pub(super) fn completed(
+        &self,
+        submitted: u64,
+        dispatched: u64,
+        child_completed: u64,
+        now: u64,
+    ) {
+        let us = |from: u64, to: u64| ticks_to_us(to.saturating_sub(from));
+        if dispatched != 0 {
+            self.stages[0].record(us(submitted, dispatched));
+            if child_completed != 0 {
+                self.stages[1].record(us(dispatched, child_completed));
+                self.stages[2].record(us(child_completed, now));
+            }
+        }
+        self.stages[3].record(us(submitted, now));
+    }
+
+    /// Account the completion by the given child device of a traced I/O
+    /// dispatched at the given ticks.
+    pub(super) fn child_completed(
+        &self,
+        device: &str,
+        dispatched: u64,
+        now: u64,
+    ) {
+        let us = ticks_to_us(now.saturating_sub(dispatched));
+        if let Some(latency) = self.children.read().get(device) {
+            latency.record(us);
+            return;
+        }
+        self.children
+            .write()
+            .entry(device.to_string())
+            .or_default()
+            .record(us);
+    }
+
+    /// Trace one in every given number of I/Os, starting afresh, or stop
+    /// tracing if 0.
+    fn set_rate(&self, rate: u64) {
+        self.rate.store(0, Ordering::Relaxed);
+        self.stages.iter().for_each(StageLatency::reset);
+        self.children.write().clear();
+        *self.since.lock() = if rate > 0 {
+            Some(chrono::Utc::now().to_rfc3339())
+        } else {
+            None
+        };
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+}
+
+impl<'n> Nexus<'n> {
+    /// Trace one in every given number of the I/Os of each channel, or stop
+    /// tracing if 0. The latencies traced so far are discarded.
+    pub fn set_io_trace(&self, rate: u64) {
+        info!("{:?}: tracing 1 in {} I/Os", self, rate);
+        self.io_trace.set_rate(rate);
+    }
+
+    /// Returns the latency of each stage of the data path, then of each
+    /// child, over the sampled I/Os.
+    pub fn io_trace_breakdown(&self) -> (Vec<StageSummary>, Vec<StageSummary>) {
+        let stages = STAGES
+            .iter()
+            .zip(self.io_trace.stages.iter())
+            .map(|(stage, latency)| latency.summary(stage.to_string()))
+            .collect();
+        let uris = self
+            .children_iter()
+            .filter_map(|c| Some((c.get_device_name()?, c.uri().to_string())))
+            .collect::<HashMap<_, _>>();
+        let mut children = self
+            .io_trace
+            .children
+            .read()
+            .iter()
+            .map(|(device, latency)| {
+                latency.summary(uris.get(device).unwrap_or(device).clone())
+            })
+            .collect::<Vec<_>>();
+        children.sort_by(|a, b| b.p99_us.cmp(&a.p99_us));
+        (stages, children)
+    }
+}
+
+/// Arguments of the `nexus_set_io_trace` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct SetIoTraceArgs {
+    /// Name of the nexus.
+    name: String,
+    /// One in this many I/Os is traced, none if 0.
+    sample_rate: u64,
+}
+
+/// Arguments of the `nexus_get_io_trace` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct GetIoTraceArgs {
+    /// Name of the nexus.
+    name: String,
+}
+
+/// Reply of the I/O tracing json-rpc methods.
+#[derive(Debug, Serialize)]
+struct IoTraceReply {
+    name: String,
+    sample_rate: u64,
+    since: Option<String>,
+    stages: Vec<StageSummary>,
+    children: Vec<StageSummary>,
+}
+
+impl IoTraceReply {
+    fn new(nexus: &Nexus) -> Self {
+        let (stages, children) = nexus.io_trace_breakdown();
+        Self {
+            name: nexus.name.clone(),
+            sample_rate: nexus.io_trace.rate.load(Ordering::Relaxed),
+            since: nexus.io_trace.since.lock().clone(),
+            stages,
+            children,
+        }
+    }
+}
+
+/// Register the I/O tracing json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nexus_set_io_trace", |args: SetIoTraceArgs| {
+        async move {
+            let nexus =
+                nexus_lookup(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+            nexus.set_io_trace(args.sample_rate);
+            Ok(IoTraceReply::new(nexus))
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_get_io_trace", |args: GetIoTraceArgs| {
+        async move {
+            nexus_lookup(&args.name).map(IoTraceReply::new).ok_or(
+                Error::NexusNotFound {
+                    name: args.name,
+                },
+            )
+        }
+        .boxed_local()
+    });
+}
@@ -13,27 +13,29 @@ pub fn nexus_iter_mut<'n>() -> NexusIterMut<'n> {
     NexusIterMut::new()
 }
 
-/// Looks up a Nexus by its name, and returns a reference to it.
+/// Looks up a Nexus by its name or alias, and returns a reference to it.
 pub fn nexus_lookup<'n>(
     name: &str,
 ) -> Option<<NexusIter<'n> as Iterator>::Item> {
-    NexusIter::new().find(|n| n.name == name)
+    NexusIter::new().find(|n| n.has_name(name))
 }
 
-/// Looks up a Nexus by its name, and returns a mutable reference to it.
+/// Looks up a Nexus by its name or alias, and returns a mutable reference to
+/// it.
 pub fn nexus_lookup_mut<'n>(
     name: &str,
 ) -> Option<<NexusIterMut<'n> as Iterator>::Item> {
-    NexusIterMut::new().find(|n| n.name == name)
+    NexusIterMut::new().find(|n| n.has_name(name))
 }
 
-/// Looks up a Nexus by its name or uuid, and returns a reference to it.
+/// Looks up a Nexus by its name, alias or uuid, and returns a reference to it.
 pub fn nexus_lookup_name_uuid<'n>(
     name: &str,
     nexus_uuid: Option<uuid::Uuid>,
 ) -> Option<<NexusIter<'n> as Iterator>::Item> {
     NexusIter::new().find(|n| {
-        n.name == name || (nexus_uuid.is_some() && Some(n.uuid()) == nexus_uuid)
+        n.has_name(name)
+            || (nexus_uuid.is_some() && Some(n.uuid()) == nexus_uuid)
     })
 }
 
@@ -0,0 +1,530 @@
+//! Latency SLO of a nexus.
+//!
+//! A nexus can be given a latency SLO, a percentile of the latency of its
+//! I/Os which must stay under a threshold, e.g. 99% of the I/Os completing
+//! within 5ms. The latency of the I/Os is then measured by each of the
+//! channels of the nexus, from the submission of an I/O to the nexus to its
+//! completion, as well as up to the completion of the I/O by each of the
+//! children, so that a violation of the SLO points at the children which
+//! caused it.
+//!
+//! The latencies are kept in histograms with buckets growing exponentially,
+//! four of them for every power of two of microseconds, so that the
+//! percentiles are known within 25%: the percentile reported is the upper
+//! bound of its bucket. The SLO is evaluated at the end of every window, over
+//! the I/Os which completed within it, the histograms then starting afresh.
+//! A window in which the SLO is not met publishes an event, which breaks the
+//! percentile down by channel and by child, as does a window in which the SLO
+//! is met again after a violation. Windows with too few I/Os for the
+//! percentile to be meaningful are not evaluated.
+
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crossbeam::atomic::AtomicCell;
+use futures::FutureExt;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+
+use super::{nexus_lookup, Error, Nexus};
+use crate::{
+    core::{Cores, Reactors},
+    events::{publish, EventCategory, EventSeverity},
+    jsonrpc::jsonrpc_register,
+    rebuild::{ticks, ticks_to_us},
+    sleep::mayastor_sleep,
+};
+
+/// Number of buckets of the histograms: one for each of the first 8
+/// microseconds, then 4 for every power of two up to 2^32 microseconds.
+const BUCKETS: usize = 8 + 4 * 30;
+
+/// Shortest and longest window of a latency SLO, in seconds.
+const MIN_WINDOW_SECS: u64 = 1;
+const MAX_WINDOW_SECS: u64 = 3600;
+
+/// Least number of I/Os completed within a window for the SLO to be
+/// evaluated.
+const MIN_SAMPLES: u64 = 100;
+
+/// Latency SLO of a nexus.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LatencySlo {
+    /// Percentile of the latency, e.g. 99.0.
+    pub percentile: f64,
+    /// Latency the percentile must stay under, in microseconds.
+    pub threshold_us: u64,
+    /// Window over which the percentile is computed, in seconds.
+    pub window_secs: u64,
+}
+
+/// Returns the bucket of the given latency.
+fn bucket(us: u64) -> usize {
+    if us < 8 {
+        return us as usize;
+    }
+    let exp = 63 - us.leading_zeros() as usize;
+    let sub = ((us >> (exp - 2)) & 3) as usize;
+    (8 + (exp - 3) * 4 + sub).min(BUCKETS - 1)
+}
+
+/// Returns the highest latency of the given bucket.
+fn bucket_max(bucket: usize) -> u64 {
+    if bucket < 8 {
+        return bucket as u64;
+    }
+    let exp = (bucket - 8) / 4 + 3;
+    let sub = ((bucket - 8) % 4) as u64;
+    ((4 + sub + 1) << (exp - 2)) - 1
+}
+
+/// Histogram of the latency of the I/Os.
+pub(super) struct Histogram(Vec<AtomicU64>);
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self((0 .. BUCKETS).map(|_| AtomicU64::new(0)).collect())
+    }
+}
+
+impl Histogram {
+    pub(super) fn record(&self, us: u64) {
+        self.0[bucket(us)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the counts of the buckets.
+    pub(super) fn counts(&self) -> Vec<u64> {
+        self.0.iter().map(|b| b.load(Ordering::Relaxed)).collect()
+    }
+
+    /// Returns the counts of the buckets, and resets them.
+    pub(super) fn take(&self) -> Vec<u64> {
+        self.0
+            .iter()
+            .map(|b| b.swap(0, Ordering::Relaxed))
+            .collect()
+    }
+}
+
+/// Returns the number of I/Os of the given bucket counts, and the given
+/// percentile of their latency.
+pub(super) fn percentile(counts: &[u64], percentile: f64) -> (u64, u64) {
+    let ios = counts.iter().sum::<u64>();
+    let rank = ((ios as f64) * percentile / 100.0).ceil().max(1.0) as u64;
+    let mut seen = 0;
+    for (bucket, count) in counts.iter().enumerate() {
+        seen += count;
+        if seen >= rank {
+            return (ios, bucket_max(bucket));
+        }
+    }
+    (ios, 0)
+}
+
+/// Latency of the I/Os of a channel of the nexus within a window.
+#[derive(Serialize, Debug, Clone)]
+pub struct ChannelLatency {
+    pub core: u32,
+    pub ios: u64,
+    /// Percentile of the latency of the SLO, in microseconds.
+    pub latency_us: u64,
+}
+
+/// Latency of the I/Os of the nexus up to their completion by a child,
+/// within a window.
+#[derive(Serialize, Debug, Clone)]
+pub struct ChildLatency {
+    pub uri: String,
+    pub ios: u64,
+    /// Percentile of the latency of the SLO, in microseconds.
+    pub latency_us: u64,
+    /// The percentile of the child is over the threshold of the SLO.
+    pub violating: bool,
+}
+
+/// Evaluation of the latency SLO of a nexus over a window.
+#[derive(Serialize, Debug, Clone)]
+pub struct SloWindow {
+    /// End of the window, in RFC 3339 format.
+    pub time: String,
+    pub ios: u64,
+    /// Percentile of the latency of the SLO, in microseconds.
+    pub latency_us: u64,
+    pub violated: bool,
+    pub channels: Vec<ChannelLatency>,
+    pub children: Vec<ChildLatency>,
+}
+
+/// Latency SLO of a nexus, along with the latency of its I/Os.
+pub(crate) struct LatencySloMonitor {
+    slo: AtomicCell<Option<LatencySlo>>,
+    enabled: AtomicBool,
+    /// Incremented every time the SLO is set, for the routine evaluating a
+    /// previous SLO to stop.
+    generation: AtomicU64,
+    /// Latency of the I/Os, by core of their channel.
+    channels: RwLock<HashMap<u32, Histogram>>,
+    /// Latency of the I/Os up to their completion by a child, by child
+    /// device.
+    children: RwLock<HashMap<String, Histogram>>,
+    /// Last evaluated window, and the number of violations.
+    last: Mutex<Option<SloWindow>>,
+    violations: AtomicU64,
+}
+
+impl LatencySloMonitor {
+    pub(crate) fn new() -> Self {
+        Self {
+            slo: AtomicCell::new(None),
+            enabled: AtomicBool::new(false),
+            generation: AtomicU64::new(0),
+            channels: RwLock::new(HashMap::new()),
+            children: RwLock::new(HashMap::new()),
+            last: Mutex::new(None),
+            violations: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns true if the nexus has a latency SLO.
+    #[inline(always)]
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Returns the latency SLO.
+    pub(crate) fn slo(&self) -> Option<LatencySlo> {
+        self.slo.load()
+    }
+
+    /// Replace the latency SLO, and start afresh. Returns the generation of
+    /// the SLO.
+    fn set_slo(&self, slo: Option<LatencySlo>) -> u64 {
+        self.slo.store(slo);
+        self.enabled.store(slo.is_some(), Ordering::Relaxed);
+        self.channels.write().clear();
+        self.children.write().clear();
+        *self.last.lock() = None;
+        self.violations.store(0, Ordering::Relaxed);
+        self.generation.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    fn with<K: std::hash::Hash + Eq>(
+        map: &RwLock<HashMap<K, Histogram>>,
+        key: K,
+        us: u64,
+    ) {
+        if let Some(histogram) = map.read().get(&key) {
+            histogram.record(us);
+            return;
+        }
+        map.write().entry(key).or_default().record(us);
+    }
+
+    /// Account an I/O submitted at the given ticks and completed now by the
+    /// nexus.
+    pub(super) fn completed(&self, submitted: u64) {
+        let us = ticks_to_us(ticks().saturating_sub(submitted));
+        Self::with(&self.channels, Cores::current(), us);
+    }
+
+    /// Account an I/O submitted at the given ticks and completed now by the
+    /// given child device.
+    pub(super) fn child_completed(&self, device: &str, submitted: u64) {
+        let us = ticks_to_us(ticks().saturating_sub(submitted));
+        if let Some(histogram) = self.children.read().get(device) {
+            histogram.record(us);
+            return;
+        }
+        Self::with(&self.children, device.to_string(), us);
+    }
+
+    /// Evaluate the SLO over the window which just ended, if enough I/Os
+    /// completed within it.
+    fn evaluate(
+        &self,
+        slo: &LatencySlo,
+        uris: &HashMap<String, String>,
+    ) -> Option<SloWindow> {
+        let mut total = vec![0; BUCKETS];
+        let mut channels = self
+            .channels
+            .read()
+            .iter()
+            .map(|(core, histogram)| {
+                let counts = histogram.take();
+                total.iter_mut().zip(&counts).for_each(|(t, c)| *t += c);
+                let (ios, latency_us) = percentile(&counts, slo.percentile);
+                ChannelLatency {
+                    core: *core,
+                    ios,
+                    latency_us,
+                }
+            })
+            .filter(|c| c.ios > 0)
+            .collect::<Vec<_>>();
+        channels.sort_by_key(|c| c.core);
+
+        let mut children = self
+            .children
+            .read()
+            .iter()
+            .map(|(device, histogram)| {
+                let (ios, latency_us) =
+                    percentile(&histogram.take(), slo.percentile);
+                ChildLatency {
+                    uri: uris.get(device).unwrap_or(device).clone(),
+                    ios,
+                    latency_us,
+                    violating: latency_us > slo.threshold_us,
+                }
+            })
+            .filter(|c| c.ios > 0)
+            .collect::<Vec<_>>();
+        children.sort_by(|a, b| b.latency_us.cmp(&a.latency_us));
+        // forget the children which are gone
+        self.children
+            .write()
+            .retain(|device, _| uris.contains_key(device));
+
+        let (ios, latency_us) = percentile(&total, slo.percentile);
+        if ios < MIN_SAMPLES {
+            return None;
+        }
+        Some(SloWindow {
+            time: chrono::Utc::now().to_rfc3339(),
+            ios,
+            latency_us,
+            violated: latency_us > slo.threshold_us,
+            channels,
+            children,
+        })
+    }
+}
+
+/// Returns the description of a window, for its event.
+fn describe(slo: &LatencySlo, window: &SloWindow) -> String {
+    let children = window
+        .children
+        .iter()
+        .map(|c| {
+            format!(
+                "{}{}: {}us ({} I/Os)",
+                c.uri,
+                if c.violating { " (violating)" } else { "" },
+                c.latency_us,
+                c.ios
+            )
+        })
+        .collect::<Vec<_>>();
+    let channels = window
+        .channels
+        .iter()
+        .map(|c| {
+            format!("core {}: {}us ({} I/Os)", c.core, c.latency_us, c.ios)
+        })
+        .collect::<Vec<_>>();
+    format!(
+        "p{} latency of {}us {} the SLO of {}us over {}s ({} I/Os); \
+        children: {}; channels: {}",
+        slo.percentile,
+        window.latency_us,
+        if window.violated { "over" } else { "within" },
+        slo.threshold_us,
+        slo.window_secs,
+        window.ios,
+        children.join(", "),
+        channels.join(", ")
+    )
+}
+
+impl<'n> Nexus<'n> {
+    /// Set the latency SLO of the nexus, or remove it.
+    pub fn set_latency_slo(
+        &self,
+        slo: Option<LatencySlo>,
+    ) -> Result<(), Error> {
+        if let Some(slo) = &slo {
+            let invalid = |args: String| Error::InvalidArguments {
+                name: self.name.clone(),
+                args,
+            };
+            if !(slo.percentile > 0.0 && slo.percentile < 100.0) {
+                return Err(invalid(
+                    "the percentile must be between 0 and 100".to_string(),
+                ));
+            }
+            if slo.threshold_us == 0 {
+                return Err(invalid(
+                    "the latency threshold must not be 0".to_string(),
+                ));
+            }
+            if slo.window_secs < MIN_WINDOW_SECS
+                || slo.window_secs > MAX_WINDOW_SECS
+            {
+                return Err(invalid(format!(
+                    "the window must be between {} and {} seconds",
+                    MIN_WINDOW_SECS, MAX_WINDOW_SECS
+                )));
+            }
+        }
+
+        info!("{:?}: setting latency SLO: {:?}", self, slo);
+        let generation = self.latency_slo.set_slo(slo);
+        if slo.is_some() {
+            Reactors::master().send_future(Nexus::latency_slo_routine(
+                self.name.clone(),
+                generation,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Evaluate the latency SLO of the nexus at the end of every window,
+    /// until the SLO is changed or the nexus is gone.
+    async fn latency_slo_routine(name: String, generation: u64) {
+        let current = |name: &str| {
+            nexus_lookup(name)
+                .filter(|n| {
+                    n.latency_slo.generation.load(Ordering::SeqCst)
+                        == generation
+                })
+                .and_then(|n| n.latency_slo.slo())
+        };
+
+        while let Some(slo) = current(&name) {
+            mayastor_sleep(Duration::from_secs(slo.window_secs))
+                .await
+                .ok();
+            if current(&name) != Some(slo) {
+                return;
+            }
+            if let Some(nexus) = nexus_lookup(&name) {
+                nexus.latency_slo_window(&slo);
+            }
+        }
+    }
+
+    /// Evaluate the latency SLO over the window which just ended.
+    fn latency_slo_window(&self, slo: &LatencySlo) {
+        let uris = self
+            .children_iter()
+            .filter_map(|c| {
+                c.get_device_name().map(|d| (d, c.uri().to_string()))
+            })
+            .collect::<HashMap<_, _>>();
+        let window = match self.latency_slo.evaluate(slo, &uris) {
+            Some(window) => window,
+            None => return,
+        };
+
+        let monitor = &self.latency_slo;
+        let was_violated =
+            monitor.last.lock().as_ref().map_or(false, |w| w.violated);
+        if window.violated {
+            monitor.violations.fetch_add(1, Ordering::Relaxed);
+            publish(
+                EventCategory::Nexus,
+                EventSeverity::Warning,
+                "latency_slo_violated",
+                &self.name,
+                describe(slo, &window),
+            );
+        } else if was_violated {
+            publish(
+                EventCategory::Nexus,
+                EventSeverity::Info,
+                "latency_slo_restored",
+                &self.name,
+                describe(slo, &window),
+            );
+        }
+        *monitor.last.lock() = Some(window);
+    }
+}
+
+/// Arguments of the `nexus_set_latency_slo` json-rpc method, the SLO is
+/// removed if no threshold is given.
+#[derive(Debug, Deserialize)]
+struct SetLatencySloArgs {
+    /// Name of the nexus.
+    name: String,
+    #[serde(default)]
+    threshold_us: Option<u64>,
+    #[serde(default = "default_percentile")]
+    percentile: f64,
+    #[serde(default = "default_window_secs")]
+    window_secs: u64,
+}
+
+fn default_percentile() -> f64 {
+    99.0
+}
+
+fn default_window_secs() -> u64 {
+    10
+}
+
+/// Arguments of the `nexus_get_latency_slo` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct GetLatencySloArgs {
+    /// Name of the nexus.
+    name: String,
+}
+
+/// Reply of the latency SLO json-rpc methods.
+#[derive(Debug, Serialize)]
+struct LatencySloReply {
+    name: String,
+    slo: Option<LatencySlo>,
+    /// Number of windows which violated the SLO.
+    violations: u64,
+    /// Last evaluated window.
+    last_window: Option<SloWindow>,
+}
+
+impl LatencySloReply {
+    fn new(nexus: &Nexus) -> Self {
+        Self {
+            name: nexus.name.clone(),
+            slo: nexus.latency_slo.slo(),
+            violations: nexus.latency_slo.violations.load(Ordering::Relaxed),
+            last_window: nexus.latency_slo.last.lock().clone(),
+        }
+    }
+}
+
+/// Register the latency SLO json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nexus_set_latency_slo", |args: SetLatencySloArgs| {
+        async move {
+            let nexus =
+                nexus_lookup(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+
+            nexus.set_latency_slo(args.threshold_us.map(|threshold_us| {
+                LatencySlo {
+                    percentile: args.percentile,
+                    threshold_us,
+                    window_secs: args.window_secs,
+                }
+            }))?;
+            Ok(LatencySloReply::new(nexus))
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_get_latency_slo", |args: GetLatencySloArgs| {
+        async move {
+            nexus_lookup(&args.name).map(LatencySloReply::new).ok_or(
+                Error::NexusNotFound {
+                    name: args.name,
+                },
+            )
+        }
+        .boxed_local()
+    });
+}
@@ -0,0 +1,467 @@
+//! Lazy connection of the remote children of a nexus.
+//!
+//! Restarting a node hosting hundreds of volumes connects to all their
+//! remote replicas at once, although most of the volumes are idle. A nexus
+//! can instead be created with its remote children connected lazily: they
+//! are registered, but their NVMe connection is only established on the
+//! first IO of the nexus, or when a prefetch of its children is asked for.
+//! The local children are opened on creation as usual.
+//!
+//! Until the children are connected, the size of the nexus is the size it
+//! has been created with, and its block size the one it has been created
+//! with, or the largest block size of its local children. The IOs are held
+//! until the remote children are connected, and submitted then, so that the
+//! children are never out of sync. A remote child which fails to be
+//! connected is faulted, and one whose size, block size or alignment does
+//! not fit the nexus is marked invalid: the nexus goes on without it, as a
+//! nexus whose child fails to open. The children not connected yet are
+//! accounted as healthy.
+//!
+//! When none of the children could be connected, the IOs fail right away
+//! rather than each connecting the children again, until the connection is
+//! retried: by the first IO past a retry interval, or right away by an
+//! explicit prefetch, which also connects again the children which could
+//! not be connected before. The encrypted children over a remote base bdev
+//! are remote children as well.
+//!
+//! The nexuses restored on startup keep their children connected lazily.
+
+use std::{
+    pin::Pin,
+    time::{Duration, Instant},
+};
+
+use crossbeam::atomic::AtomicCell;
+use futures::{channel::oneshot, FutureExt};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+
+use super::{
+    nexus_bdev::nexus_create_lazy,
+    nexus_err,
+    nexus_lookup,
+    nexus_lookup_mut,
+    ChildState,
+    DrEvent,
+    Error,
+    Nexus,
+    NexusChild,
+    NexusNvmeParams,
+    PersistOp,
+    Reason,
+};
+use crate::{
+    bdev::{crypto::base_uri, device_create, device_lookup},
+    core::{partition, Reactors, VerboseError},
+    jsonrpc::jsonrpc_register,
+};
+
+/// Default block size of a nexus created with its children connected
+/// lazily.
+const DEFAULT_BLOCK_LEN: u32 = 512;
+
+/// Time the IOs fail for once the children failed to be connected, before
+/// one of them connects the children again.
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Connection of the remote children in progress, or failed.
+#[derive(Default)]
+struct ConnectState {
+    /// IOs and prefetches waiting for the children to be connected, the
+    /// first of which connects them.
+    waiters: Vec<oneshot::Sender<Result<(), String>>>,
+    /// Reason and time of the last failure to connect the children.
+    failure: Option<(String, Instant)>,
+}
+
+/// Lazy connection of the remote children of a nexus.
+pub(crate) struct LazyConnect {
+    /// Block size of the nexus until its children are connected, 0 if they
+    /// are connected on creation.
+    block_len: AtomicCell<u32>,
+    /// The remote children have been connected, IOs go through.
+    connected: AtomicCell<bool>,
+    /// Number of attempts to connect the children.
+    attempts: AtomicCell<u64>,
+    state: parking_lot::Mutex<ConnectState>,
+}
+
+impl LazyConnect {
+    pub(crate) fn new() -> Self {
+        Self {
+            block_len: AtomicCell::new(0),
+            connected: AtomicCell::new(true),
+            attempts: AtomicCell::new(0),
+            state: parking_lot::Mutex::new(ConnectState::default()),
+        }
+    }
+
+    /// Connect the remote children lazily, with the given block size of the
+    /// nexus until they are.
+    pub(super) fn enable(&self, block_len: u32) {
+        self.block_len.store(block_len);
+        self.connected.store(false);
+    }
+
+    /// Returns the block size of the nexus until its children are connected,
+    /// None if they are connected on creation.
+    pub(crate) fn block_len(&self) -> Option<u32> {
+        Some(self.block_len.load()).filter(|b| *b > 0)
+    }
+
+    /// Returns true if the remote children are yet to be connected.
+    #[inline(always)]
+    pub(super) fn is_pending(&self) -> bool {
+        !self.connected.load()
+    }
+
+    /// Wake up the waiters once the children have been connected, or have
+    /// failed to be.
+    fn finish(&self, result: Result<(), String>) {
+        let mut state = self.state.lock();
+        match &result {
+            Ok(()) => {
+                self.connected.store(true);
+                state.failure = None;
+            }
+            Err(reason) => {
+                state.failure = Some((reason.clone(), Instant::now()))
+            }
+        }
+        state.waiters.drain(..).for_each(|w| {
+            let _ = w.send(result.clone());
+        });
+    }
+}
+
+/// Returns true if the child of the given URI is remote.
+pub(super) fn is_remote(uri: &str) -> bool {
+    match base_uri(uri) {
+        // an encrypted child is remote when its base bdev is
+        Some(base) => is_remote(&base),
+        None => url::Url::parse(uri).map_or(false, |u| u.scheme() == "nvmf"),
+    }
+}
+
+/// Returns true if the given child is a remote child which failed to be
+/// connected.
+fn failed_to_connect(child: &NexusChild) -> bool {
+    child.get_device().is_err()
+        && child.state() == ChildState::Faulted(Reason::CantOpen)
+}
+
+impl<'n> Nexus<'n> {
+    /// Wait for the remote children of the nexus to be connected, connecting
+    /// them unless they are being connected already, or have failed to be
+    /// connected within the retry interval.
+    pub(crate) async fn wait_connected(&self) -> Result<(), Error> {
+        self.connect_children(false).await
+    }
+
+    /// Returns the number of attempts to connect the remote children of the
+    /// nexus.
+    pub fn lazy_connect_attempts(&self) -> u64 {
+        self.lazy.attempts.load()
+    }
+
+    /// Wait for the remote children of the nexus to be connected. Once they
+    /// failed to be connected, they are connected again right away if asked
+    /// to retry, or else past the retry interval.
+    async fn connect_children(&self, retry: bool) -> Result<(), Error> {
+        let receiver = {
+            let mut state = self.lazy.state.lock();
+            if !self.lazy.is_pending() {
+                return Ok(());
+            }
+            let failed = match &state.failure {
+                Some((reason, at))
+                    if state.waiters.is_empty()
+                        && !retry
+                        && at.elapsed() < CONNECT_RETRY_INTERVAL =>
+                {
+                    return Err(Error::NexusIncomplete {
+                        name: self.name.clone(),
+                        reason: reason.clone(),
+                    });
+                }
+                failure => failure.is_some(),
+            };
+            let (sender, receiver) = oneshot::channel();
+            state.waiters.push(sender);
+            if state.waiters.len() == 1 {
+                self.lazy.attempts.fetch_add(1);
+                let name = self.name.clone();
+                Reactors::master().send_future(async move {
+                    let result = match nexus_lookup_mut(&name) {
+                        Some(nexus) => nexus
+                            .connect_deferred_children(failed)
+                            .await
+                            .map(|_| ())
+                            .map_err(|e| e.verbose()),
+                        None => return,
+                    };
+                    if let Some(nexus) = nexus_lookup(&name) {
+                        nexus.lazy.finish(result);
+                    }
+                });
+            }
+            receiver
+        };
+
+        receiver
+            .await
+            .unwrap_or_else(|_| Err("the nexus has been destroyed".into()))
+            .map_err(|reason| Error::NexusIncomplete {
+                name: self.name.clone(),
+                reason,
+            })
+    }
+
+    /// Connect the remote children whose connection has been deferred, and
+    /// those which failed to be connected if asked to retry. Returns the
+    /// number of children connected.
+    async fn connect_deferred_children(
+        mut self: Pin<&mut Self>,
+        retry: bool,
+    ) -> Result<usize, Error> {
+        let uris = self
+            .children_iter()
+            .filter(|c| c.is_deferred() || (retry && failed_to_connect(c)))
+            .map(|c| c.uri().to_string())
+            .collect::<Vec<_>>();
+        info!("{:?}: connecting {} deferred children...", self, uris.len());
+
+        let mut connected = 0;
+        for uri in uris {
+            if let Ok(child) = self.as_mut().child_mut(&uri) {
+                if !child.is_deferred() {
+                    child.transition(
+                        ChildState::Init,
+                        "connecting the device again",
+                    );
+                }
+            }
+            let result = self.as_mut().connect_deferred_child(&uri).await;
+            if let Err(error) = result {
+                error!(
+                    "{:?}: failed to connect child '{}': {}",
+                    self,
+                    uri,
+                    error.verbose()
+                );
+                if let Ok(child) = self.as_mut().child_mut(&uri) {
+                    if child.state() != ChildState::ConfigInvalid {
+                        child.transition(
+                            ChildState::Faulted(Reason::CantOpen),
+                            "failed to connect the device",
+                        );
+                    }
+                }
+            } else {
+                connected += 1;
+            }
+            if let Some(child) = self.children_iter().find(|c| c.uri() == uri) {
+                let child_state = child.state();
+                self.persist(PersistOp::Update {
+                    child_uri: uri,
+                    child_state,
+                })
+                .await;
+            }
+        }
+
+        if !self.children_iter().any(|c| c.state() == ChildState::Open) {
+            return Err(Error::NexusIncomplete {
+                name: self.name.clone(),
+                reason: "none of the children could be connected".to_string(),
+            });
+        }
+
+        if connected > 0 {
+            self.reconfigure(DrEvent::ChildConnect).await;
+            // the children were not there when the nexus was opened
+            self.write_intent_recover().await;
+            self.metadata_load().await;
+        }
+        info!("{:?}: {} deferred children connected", self, connected);
+        Ok(connected)
+    }
+
+    /// Connect and open the remote child of the given URI, whose connection
+    /// has been deferred.
+    async fn connect_deferred_child(
+        mut self: Pin<&mut Self>,
+        uri: &str,
+    ) -> Result<(), Error> {
+        let name = self.name.clone();
+        let device_name =
+            device_create(uri).await.context(nexus_err::CreateChild {
+                name: name.clone(),
+            })?;
+
+        let size = self.req_size();
+        let block_len = self.block_len();
+        let alignment = self.alignment();
+        let end_blk = self.data_ent_offset + self.num_blocks();
+        let listener = self.get_event_sink();
+        let child = self.as_mut().child_mut(uri)?;
+        child.attach_device(device_lookup(&device_name));
+
+        // the data partition of the child must hold the whole nexus, whose
+        // geometry has been set without it
+        let fits = child.get_device().map_or(false, |dev| {
+            let bs = dev.block_len();
+            block_len % bs == 0
+                && dev.alignment() <= alignment
+                && partition::calc_data_partition(size, dev.num_blocks(), bs)
+                    .map_or(false, |(_, end)| end / (block_len / bs) >= end_blk)
+        });
+        if !fits {
+            child.transition(
+                ChildState::ConfigInvalid,
+                "incompatible size, block size or alignment",
+            );
+            return Err(Error::ChildGeometry {
+                child: uri.to_string(),
+                name,
+            });
+        }
+
+        child
+            .open(size, ChildState::Open)
+            .context(nexus_err::OpenChild {
+                child: uri.to_string(),
+                name: name.clone(),
+            })?;
+        child.set_event_listener(listener);
+
+        let uuid = self.uuid().to_string();
+        let child = self.children_iter().find(|c| c.uri() == uri).unwrap();
+        let result = match child.lease_acquire(&uuid).await {
+            Ok(()) => child
+                .reservation_acquire(&self.nvme_params)
+                .await
+                .context(nexus_err::ChildWriteExclusiveResvFailed {
+                    child: uri.to_string(),
+                    name: name.clone(),
+                }),
+            Err(source) => Err(Error::ChildLeaseFailed {
+                source,
+                child: uri.to_string(),
+                name: name.clone(),
+            }),
+        };
+        if result.is_err() {
+            let child = self.as_mut().child_mut(uri)?;
+            if let Err(error) = child.close().await {
+                error!(
+                    "{:?}: child failed to close: {}",
+                    child,
+                    error.verbose()
+                );
+            }
+        }
+        result
+    }
+
+    /// Connect the remote children of the nexus now, rather than on its first
+    /// IO, or again if they failed to be connected.
+    pub async fn prefetch_children(&self) -> Result<(), Error> {
+        if self.lazy.is_pending() {
+            info!("{:?}: prefetching children", self);
+        }
+        self.connect_children(true).await
+    }
+}
+
+/// Arguments of the `nexus_create_lazy` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct CreateLazyArgs {
+    /// Name of the nexus.
+    name: String,
+    /// Size of the nexus in bytes.
+    size: u64,
+    /// UUID of the nexus.
+    uuid: String,
+    /// URIs of the children.
+    children: Vec<String>,
+    /// Block size of the nexus until its children are connected.
+    #[serde(default)]
+    block_len: Option<u32>,
+    /// Key of the persisted NexusInfo structure.
+    #[serde(default)]
+    nexus_info_key: Option<String>,
+}
+
+/// Arguments of the `nexus_prefetch` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct PrefetchArgs {
+    /// Name of the nexus.
+    name: String,
+}
+
+/// Reply of the lazy connection json-rpc methods.
+#[derive(Debug, Serialize)]
+struct LazyReply {
+    name: String,
+    /// The remote children are connected lazily.
+    lazy: bool,
+    /// The remote children have been connected.
+    connected: bool,
+    /// URIs of the children yet to be connected.
+    deferred: Vec<String>,
+    /// Number of attempts to connect the children.
+    attempts: u64,
+}
+
+impl LazyReply {
+    fn new(nexus: &Nexus) -> Self {
+        Self {
+            name: nexus.name.clone(),
+            lazy: nexus.lazy.block_len().is_some(),
+            connected: !nexus.lazy.is_pending(),
+            deferred: nexus
+                .children_iter()
+                .filter(|c| c.is_deferred())
+                .map(|c| c.uri().to_string())
+                .collect(),
+            attempts: nexus.lazy_connect_attempts(),
+        }
+    }
+}
+
+/// Register the lazy connection json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nexus_create_lazy", |args: CreateLazyArgs| {
+        async move {
+            nexus_create_lazy(
+                &args.name,
+                args.size,
+                &args.uuid,
+                NexusNvmeParams::default(),
+                &args.children,
+                args.nexus_info_key,
+                args.block_len.unwrap_or(DEFAULT_BLOCK_LEN),
+            )
+            .await?;
+            nexus_lookup(&args.name).map(LazyReply::new).ok_or(
+                Error::NexusNotFound {
+                    name: args.name,
+                },
+            )
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_prefetch", |args: PrefetchArgs| {
+        async move {
+            let nexus =
+                nexus_lookup(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+            nexus.prefetch_children().await?;
+            Ok(LazyReply::new(nexus))
+        }
+        .boxed_local()
+    });
+}
@@ -0,0 +1,225 @@
+//! Leases of the nexus on the replicas of its children.
+//!
+//! The nexus takes a lease on the replica of each child it opens, which
+//! keeps other nexuses from opening the replica as long as the lease is
+//! renewed. The lease of a replica of the node is taken directly, the lease
+//! of a remote replica through a custom NVMe admin command to its target.
+//! Children which are not replicas have no lease: the target of a remote
+//! child which does not support the command, or does not share a replica,
+//! fails it with an invalid opcode.
+//!
+//! The leases are renewed periodically while the nexus is open, and given
+//! up when its children are closed. A child whose lease has been taken by
+//! another nexus, once expired or broken, is faulted.
+
+use std::{convert::TryFrom, pin::Pin, time::Duration};
+
+use spdk_rs::libspdk::spdk_nvme_cmd;
+
+use super::{
+    nexus_lookup_mut,
+    ChildError,
+    Nexus,
+    NexusChild,
+    NexusState,
+    Reason,
+};
+use crate::{
+    core::{
+        CoreError,
+        GenericStatusCode,
+        MayastorEnvironment,
+        NvmeStatus,
+        Reactors,
+        UntypedBdev,
+        VerboseError,
+    },
+    lvs::{LeaseError, Lvol},
+    sleep::mayastor_sleep,
+    subsys::{LeaseRequest, REPLICA_LEASE_OPC},
+};
+
+/// Duration of the leases taken by the nexus.
+const LEASE_TTL: Duration = Duration::from_secs(60);
+
+/// Interval at which the leases are renewed, well within their duration.
+const LEASE_RENEW_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Size of the data buffer carrying the lease request.
+const LEASE_REQUEST_SIZE: u64 = 4096;
+
+impl<'c> NexusChild<'c> {
+    /// Take or renew the lease of the nexus on the replica of the child for
+    /// the given duration, or release it if the duration is zero.
+    async fn lease_update(
+        &self,
+        nexus: &str,
+        ttl: Duration,
+    ) -> Result<(), ChildError> {
+        let device = self.get_device()?;
+        let node = MayastorEnvironment::global_or_default().node_name;
+
+        if device.driver_name() == "nvme" {
+            let request = serde_json::to_vec(&LeaseRequest {
+                nexus: nexus.to_string(),
+                node,
+                ttl: ttl.as_secs(),
+            })
+            .unwrap();
+            let hdl = self.get_io_handle().map_err(|source| {
+                ChildError::HandleOpen {
+                    source,
+                }
+            })?;
+            let mut buf =
+                hdl.dma_malloc(LEASE_REQUEST_SIZE).map_err(|source| {
+                    ChildError::HandleDmaMalloc {
+                        source,
+                    }
+                })?;
+            buf.as_mut_slice().fill(0);
+            buf.as_mut_slice()[.. request.len()].copy_from_slice(&request);
+
+            let mut cmd = spdk_nvme_cmd::default();
+            cmd.set_opc(REPLICA_LEASE_OPC.into());
+            cmd.nsid = 1;
+            match hdl.nvme_admin(&cmd, Some(&mut buf)).await {
+                // the target does not support leases, or the replica is not
+                // a logical volume
+                Err(CoreError::NvmeAdminFailed {
+                    status:
+                        NvmeStatus::Generic(GenericStatusCode::InvalidOpcode),
+                    ..
+                }) => Ok(()),
+                result => {
+                    result.map_err(|source| ChildError::ReplicaLeaseCmd {
+                        source,
+                    })
+                }
+            }
+        } else if let Some(mut lvol) =
+            UntypedBdev::lookup_by_name(&device.device_name())
+                .and_then(|b| Lvol::try_from(b).ok())
+        {
+            let lvol = Pin::new(&mut lvol);
+            let result = if ttl.is_zero() {
+                lvol.release_lease(nexus, &node).await
+            } else {
+                lvol.acquire_lease(nexus, &node, ttl).await.map(|_| ())
+            };
+            result.map_err(|source| ChildError::ReplicaLease {
+                source,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Take or renew the lease of the nexus on the replica of the child.
+    pub(super) async fn lease_acquire(
+        &self,
+        nexus: &str,
+    ) -> Result<(), ChildError> {
+        self.lease_update(nexus, LEASE_TTL).await
+    }
+
+    /// Give up the lease of the nexus on the replica of the child.
+    pub(super) async fn lease_release(&self, nexus: &str) {
+        if let Err(error) = self.lease_update(nexus, Duration::ZERO).await {
+            warn!(
+                "{:?}: failed to release the lease of nexus {}: {}",
+                self,
+                nexus,
+                error.verbose()
+            );
+        }
+    }
+}
+
+/// Returns true if the error shows that another nexus holds the lease, as
+/// opposed to the lease not being reachable: the target of a remote replica
+/// refuses the lease with a reservation conflict.
+fn lease_lost(error: &ChildError) -> bool {
+    matches!(
+        error,
+        ChildError::ReplicaLease {
+            source: LeaseError::LeaseHeld { .. },
+        } | ChildError::ReplicaLeaseCmd {
+            source: CoreError::NvmeAdminFailed {
+                status: NvmeStatus::Generic(
+                    GenericStatusCode::ReservationConflict
+                ),
+                ..
+            },
+        }
+    )
+}
+
+impl<'n> Nexus<'n> {
+    /// Give up the leases of the nexus on the replicas of its children.
+    pub(super) async fn release_leases(&self) {
+        let uuid = self.uuid().to_string();
+        for child in self.children_iter() {
+            if child.get_device().is_ok() {
+                child.lease_release(&uuid).await;
+            }
+        }
+    }
+
+    /// Renew the leases of the nexus on the replicas of its children, and
+    /// fault the children whose lease has been taken by another nexus.
+    async fn renew_leases(mut self: Pin<&mut Self>) {
+        let uuid = self.uuid().to_string();
+        let mut lost = Vec::new();
+        for child in self.children_iter() {
+            if child.get_device().is_err() {
+                continue;
+            }
+            if let Err(error) = child.lease_acquire(&uuid).await {
+                error!(
+                    "{:?}: failed to renew the lease of nexus {}: {}",
+                    child,
+                    uuid,
+                    error.verbose()
+                );
+                if lease_lost(&error) {
+                    lost.push(child.uri().to_string());
+                }
+            }
+        }
+
+        for uri in lost {
+            if let Err(error) =
+                self.as_mut().fault_child(&uri, Reason::CantOpen).await
+            {
+                error!(
+                    "{:?}: failed to fault child {} which lost its lease: {}",
+                    self,
+                    uri,
+                    error.verbose()
+                );
+            }
+        }
+    }
+}
+
+/// Renew the leases of the nexus periodically, until it is shut down or
+/// destroyed.
+pub(super) fn start_lease_renewal(nexus_name: String) {
+    Reactors::master().send_future(async move {
+        loop {
+            mayastor_sleep(LEASE_RENEW_INTERVAL).await.ok();
+            match nexus_lookup_mut(&nexus_name) {
+                Some(nexus) if *nexus.state.lock() == NexusState::Open => {
+                    nexus.renew_leases().await
+                }
+                Some(nexus)
+                    if matches!(
+                        *nexus.state.lock(),
+                        NexusState::Init | NexusState::Reconfiguring
+                    ) => {}
+                _ => break,
+            }
+        }
+    });
+}
@@ -0,0 +1,70 @@
+//! Local attach of a nexus as a kernel block device on its own node.
+//!
+//! Consumers on the node of the nexus, such as hostPath volumes or a local
+//! hypervisor, can use the volume without a network round-trip. The nexus
+//! is exposed through ublk when built with the `ublk` feature and the kernel
+//! supports it, and through NBD otherwise.
+
+use std::fmt;
+
+#[cfg(feature = "ublk")]
+use super::nexus_ublk::UblkDisk;
+use super::{NbdDisk, NbdError};
+
+/// Kernel block device a nexus is attached as.
+pub enum LocalDisk {
+    #[cfg(feature = "ublk")]
+    Ublk(UblkDisk),
+    Nbd(NbdDisk),
+}
+
+impl LocalDisk {
+    /// Attach the bdev as a ublk device, or as an NBD device if ublk fails.
+    /// When the function returns the disk is ready for IO.
+    pub async fn create(bdev_name: &str) -> Result<Self, NbdError> {
+        #[cfg(feature = "ublk")]
+        match UblkDisk::create(bdev_name).await {
+            Ok(disk) => return Ok(Self::Ublk(disk)),
+            Err(error) => {
+                warn!(
+                    "Failed to attach {} over ublk, falling back to NBD: {}",
+                    bdev_name, error
+                );
+            }
+        }
+        NbdDisk::create(bdev_name).await.map(Self::Nbd)
+    }
+
+    /// Detach and release the block device.
+    pub async fn destroy(self) {
+        match self {
+            #[cfg(feature = "ublk")]
+            Self::Ublk(disk) => {
+                let name = disk.to_string();
+                if let Err(error) = disk.destroy().await {
+                    error!("Failed to detach {}: {}", name, error);
+                }
+            }
+            Self::Nbd(disk) => disk.destroy(),
+        }
+    }
+
+    /// Get the device path uri (file:///dev/...) of the disk.
+    pub fn as_uri(&self) -> String {
+        match self {
+            #[cfg(feature = "ublk")]
+            Self::Ublk(disk) => disk.as_uri(),
+            Self::Nbd(disk) => disk.as_uri(),
+        }
+    }
+}
+
+impl fmt::Debug for LocalDisk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "ublk")]
+            Self::Ublk(disk) => write!(f, "ublk:{:?}", disk),
+            Self::Nbd(disk) => write!(f, "nbd:{:?}", disk),
+        }
+    }
+}
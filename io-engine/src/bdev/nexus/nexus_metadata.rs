@@ -0,0 +1,475 @@
+//! Metadata region of a nexus.
+//!
+//! The attributes of a volume, such as the generation of its format, hints
+//! about the filesystem it holds, a backup of its reservation settings and
+//! its labels, belong with its data rather than with the node which happens
+//! to serve it. They are kept in a metadata region on each child, within the
+//! metadata reservation ahead of the data partition, right after the
+//! write-intent log: the region is not part of the namespace exported by the
+//! nexus, nor copied by a rebuild, which writes it to the rebuilt child
+//! instead.
+//!
+//! The region holds two slots, written in turn, so that an update torn by a
+//! crash leaves the previous one intact:
+//!
+//! region        ──┐
+//!                 ├── slot 0: header, followed by the attributes as JSON
+//! region + 32K  ──┤
+//!                 ├── slot 1: header, followed by the attributes as JSON
+//! region + 64K  ──┘
+//!
+//! The header holds a magic, the version of the layout, the sequence number
+//! of the update, the length of the attributes and their CRC32C. The slot of
+//! the highest sequence number is the current one. The region is read from
+//! the children when the nexus is opened, and the most recent copy is
+//! adopted. A region written with a newer layout than this io-engine knows
+//! is left untouched: the nexus is opened, but its metadata cannot be
+//! updated.
+
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crossbeam::atomic::AtomicCell;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    nexus_checksum::YieldNow,
+    nexus_lookup,
+    nexus_rekey::RekeyMarker,
+    nexus_write_intent::{dma_buf, LOG_SIZE},
+    Error,
+    Nexus,
+};
+use crate::{
+    core::{
+        partition::METADATA_RESERVATION_OFFSET,
+        BlockDeviceHandle,
+        CoreError,
+    },
+    jsonrpc::jsonrpc_register,
+    labels::Labels,
+};
+
+/// Magic of the slot header.
+const MAGIC: &[u8; 8] = b"MAYANXMD";
+
+/// Version of the layout of the region.
+pub const METADATA_VERSION: u32 = 1;
+
+/// Offset of the region on the children, in bytes.
+const REGION_OFFSET: u64 = METADATA_RESERVATION_OFFSET + LOG_SIZE;
+
+/// Size of a slot, in bytes.
+const SLOT_SIZE: u64 = 32 * 1024;
+
+/// Size of the slot header, in bytes.
+const HEADER_SIZE: usize = 32;
+
+/// Hints about the filesystem held by the volume.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FilesystemHint {
+    /// Type of the filesystem, e.g. ext4 or xfs.
+    pub fs_type: String,
+    #[serde(default)]
+    pub fs_label: Option<String>,
+    #[serde(default)]
+    pub fs_uuid: Option<String>,
+}
+
+/// Backup of the reservation settings of the nexus.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReservationBackup {
+    pub resv_key: u64,
+    pub resv_type: u8,
+    #[serde(default)]
+    pub preempt_key: Option<u64>,
+}
+
+/// Attributes of the volume, kept in the metadata region of the nexus.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NexusMetadata {
+    /// Generation of the format of the volume, increased by the control
+    /// plane whenever the volume is formatted.
+    pub format_generation: u64,
+    pub filesystem: Option<FilesystemHint>,
+    pub reservation: Option<ReservationBackup>,
+    pub labels: Labels,
+    /// Generation of the writes of the volume, 0 if it has never been
+    /// written, see `nexus_write_generation`.
+    pub write_generation: u64,
+    /// Rotation of the key of an encrypted child in progress, see
+    /// `nexus_rekey`.
+    pub rekey: Option<RekeyMarker>,
+    /// Free-form attributes.
+    pub attributes: BTreeMap<String, String>,
+}
+
+/// Metadata of a nexus, as last read from or written to its children.
+pub(crate) struct MetadataRegion {
+    metadata: parking_lot::Mutex<NexusMetadata>,
+    /// Sequence number of the last update.
+    sequence: AtomicU64,
+    /// Version of the layout found on the children.
+    version: AtomicCell<u32>,
+    update: parking_lot::Mutex<()>,
+}
+
+impl MetadataRegion {
+    pub(crate) fn new() -> Self {
+        Self {
+            metadata: parking_lot::Mutex::new(NexusMetadata::default()),
+            sequence: AtomicU64::new(0),
+            version: AtomicCell::new(METADATA_VERSION),
+            update: parking_lot::Mutex::new(()),
+        }
+    }
+
+    /// Lock the updates of the region.
+    async fn lock(&self) -> parking_lot::MutexGuard<'_, ()> {
+        loop {
+            if let Some(guard) = self.update.try_lock() {
+                return guard;
+            }
+            YieldNow(false).await;
+        }
+    }
+}
+
+/// Copy of the metadata read from a slot.
+struct Slot {
+    version: u32,
+    sequence: u64,
+    metadata: NexusMetadata,
+}
+
+impl Slot {
+    /// Parse a slot, None if it has not been written or is torn.
+    fn parse(data: &[u8]) -> Option<Self> {
+        if &data[0 .. 8] != MAGIC {
+            return None;
+        }
+        let version = u32::from_le_bytes(data[8 .. 12].try_into().unwrap());
+        let len = u32::from_le_bytes(data[12 .. 16].try_into().unwrap());
+        let sequence = u64::from_le_bytes(data[16 .. 24].try_into().unwrap());
+        let crc = u32::from_le_bytes(data[24 .. 28].try_into().unwrap());
+
+        let payload = data.get(HEADER_SIZE .. HEADER_SIZE + len as usize)?;
+        if crc::crc32::checksum_castagnoli(payload) != crc {
+            return None;
+        }
+        // the attributes of a newer layout are not interpreted
+        let metadata = if version > METADATA_VERSION {
+            NexusMetadata::default()
+        } else {
+            serde_json::from_slice(payload).ok()?
+        };
+        Some(Self {
+            version,
+            sequence,
+            metadata,
+        })
+    }
+}
+
+/// Returns the offset of the given slot on a child, in bytes.
+fn slot_offset(sequence: u64) -> u64 {
+    REGION_OFFSET + (sequence % 2) * SLOT_SIZE
+}
+
+/// Reads the current copy of the metadata of a child, if it has one.
+async fn read_region(
+    hdl: &dyn BlockDeviceHandle,
+) -> Result<Option<Slot>, CoreError> {
+    let mut buf = dma_buf(hdl, 2 * SLOT_SIZE)?;
+    hdl.read_at(REGION_OFFSET, &mut buf).await?;
+    Ok(buf
+        .as_slice()
+        .chunks(SLOT_SIZE as usize)
+        .filter_map(Slot::parse)
+        .max_by_key(|s| s.sequence))
+}
+
+/// Writes the metadata to the slot of the given sequence number of a child.
+async fn write_region(
+    hdl: &dyn BlockDeviceHandle,
+    sequence: u64,
+    metadata: &NexusMetadata,
+) -> Result<(), CoreError> {
+    let payload = serde_json::to_vec(metadata).unwrap();
+    let mut buf = dma_buf(hdl, SLOT_SIZE)?;
+    let data = buf.as_mut_slice();
+    data[0 .. 8].copy_from_slice(MAGIC);
+    data[8 .. 12].copy_from_slice(&METADATA_VERSION.to_le_bytes());
+    data[12 .. 16].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+    data[16 .. 24].copy_from_slice(&sequence.to_le_bytes());
+    data[24 .. 28].copy_from_slice(
+        &crc::crc32::checksum_castagnoli(&payload).to_le_bytes(),
+    );
+    data[HEADER_SIZE .. HEADER_SIZE + payload.len()].copy_from_slice(&payload);
+    hdl.write_at(slot_offset(sequence), &buf).await?;
+    Ok(())
+}
+
+impl<'n> Nexus<'n> {
+    /// Returns the metadata of the nexus.
+    pub fn metadata(&self) -> NexusMetadata {
+        self.metadata.metadata.lock().clone()
+    }
+
+    /// Returns true if the metadata region has been found on, or written to,
+    /// the children, with a layout this io-engine can update.
+    pub(super) fn metadata_writable(&self) -> bool {
+        self.metadata.sequence.load(Ordering::SeqCst) > 0
+            && self.metadata.version.load() <= METADATA_VERSION
+    }
+
+    /// Read the metadata region from the children, adopting its most recent
+    /// copy. The labels of the region are adopted if the nexus has none, and
+    /// the backup of the reservation settings is refreshed.
+    pub(super) async fn metadata_load(&self) {
+        let mut current: Option<Slot> = None;
+        for child in self.children_iter().filter(|c| c.is_healthy()) {
+            let slot = match child.get_io_handle() {
+                Ok(hdl) => read_region(&*hdl).await,
+                Err(error) => Err(error),
+            };
+            match slot {
+                Ok(Some(slot))
+                    if current
+                        .as_ref()
+                        .map_or(true, |c| slot.sequence > c.sequence) =>
+                {
+                    current = Some(slot)
+                }
+                Ok(_) => {}
+                Err(error) => warn!(
+                    "{:?}: failed to read the metadata region of child '{}': \
+                    {}",
+                    self,
+                    child.uri(),
+                    error
+                ),
+            }
+        }
+
+        if let Some(slot) = current {
+            info!(
+                "{:?}: metadata region found, version {}, sequence {}",
+                self, slot.version, slot.sequence
+            );
+            self.metadata.version.store(slot.version);
+            self.metadata
+                .sequence
+                .store(slot.sequence, Ordering::SeqCst);
+            if self.labels.lock().is_empty() {
+                *self.labels.lock() = slot.metadata.labels.clone();
+            }
+            *self.metadata.metadata.lock() = slot.metadata;
+        }
+
+        let labels = self.labels();
+        let reservation = ReservationBackup {
+            resv_key: self.nvme_params.resv_key,
+            resv_type: self.nvme_params.resv_type as u8,
+            preempt_key: self.nvme_params.preempt_key.map(|k| k.get()),
+        };
+        let current = self.metadata();
+        if current.labels != labels || current.reservation != Some(reservation)
+        {
+            let updated = self
+                .update_metadata(|m| {
+                    m.labels = labels;
+                    m.reservation = Some(reservation);
+                })
+                .await;
+            if let Err(error) = updated {
+                warn!("{:?}: {}", self, error);
+            }
+        }
+    }
+
+    /// Update the metadata of the nexus on its healthy children. The update
+    /// succeeds if the metadata has been written to any of them, as the most
+    /// recent copy is adopted when the nexus is opened.
+    pub async fn update_metadata(
+        &self,
+        update: impl FnOnce(&mut NexusMetadata),
+    ) -> Result<NexusMetadata, Error> {
+        let version = self.metadata.version.load();
+        if version > METADATA_VERSION {
+            return Err(Error::MetadataRegion {
+                name: self.name.clone(),
+                reason: format!(
+                    "the region has version {}, newer than version {}",
+                    version, METADATA_VERSION
+                ),
+            });
+        }
+
+        let _guard = self.metadata.lock().await;
+        let mut metadata = self.metadata();
+        update(&mut metadata);
+        let payload = serde_json::to_vec(&metadata).unwrap();
+        if payload.len() > SLOT_SIZE as usize - HEADER_SIZE {
+            return Err(Error::MetadataRegion {
+                name: self.name.clone(),
+                reason: format!(
+                    "{} bytes of metadata, at most {} fit in the region",
+                    payload.len(),
+                    SLOT_SIZE as usize - HEADER_SIZE
+                ),
+            });
+        }
+
+        let sequence = self.metadata.sequence.load(Ordering::SeqCst) + 1;
+        let mut written = 0;
+        let mut failure = None;
+        for child in self.children_iter().filter(|c| c.is_healthy()) {
+            let result = match child.get_io_handle() {
+                Ok(hdl) => write_region(&*hdl, sequence, &metadata).await,
+                Err(error) => Err(error),
+            };
+            match result {
+                Ok(()) => written += 1,
+                Err(error) => {
+                    warn!(
+                        "{:?}: failed to write the metadata region of child \
+                        '{}': {}",
+                        self,
+                        child.uri(),
+                        error
+                    );
+                    failure = Some(error.to_string());
+                }
+            }
+        }
+        if written == 0 {
+            return Err(Error::MetadataRegion {
+                name: self.name.clone(),
+                reason: failure
+                    .unwrap_or_else(|| "no healthy child".to_string()),
+            });
+        }
+
+        self.metadata.sequence.store(sequence, Ordering::SeqCst);
+        self.metadata.version.store(METADATA_VERSION);
+        *self.metadata.metadata.lock() = metadata.clone();
+        Ok(metadata)
+    }
+
+    /// Write the metadata to a child about to be rebuilt, as the rebuild
+    /// does not copy the metadata reservation.
+    pub(super) async fn metadata_prepare_child(
+        &self,
+        child_uri: &str,
+    ) -> Result<(), Error> {
+        if !self.metadata_writable() {
+            return Ok(());
+        }
+
+        let child = self.lookup_child(child_uri).ok_or_else(|| {
+            Error::ChildNotFound {
+                child: child_uri.to_owned(),
+                name: self.name.clone(),
+            }
+        })?;
+
+        let _guard = self.metadata.lock().await;
+        let sequence = self.metadata.sequence.load(Ordering::SeqCst);
+        let written = match child.get_io_handle() {
+            Ok(hdl) => write_region(&*hdl, sequence, &self.metadata()).await,
+            Err(error) => Err(error),
+        };
+        written.map_err(|error| Error::MetadataRegion {
+            name: self.name.clone(),
+            reason: format!("child {}: {}", child_uri, error),
+        })
+    }
+}
+
+/// Arguments of the `nexus_get_metadata` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct GetMetadataArgs {
+    /// Name of the nexus.
+    name: String,
+}
+
+/// Arguments of the `nexus_set_metadata` json-rpc method, the fields which
+/// are not given are left unchanged.
+#[derive(Debug, Deserialize)]
+struct SetMetadataArgs {
+    /// Name of the nexus.
+    name: String,
+    format_generation: Option<u64>,
+    filesystem: Option<FilesystemHint>,
+    labels: Option<Labels>,
+    attributes: Option<BTreeMap<String, String>>,
+}
+
+/// Reply of the metadata json-rpc methods.
+#[derive(Debug, Serialize)]
+struct MetadataReply {
+    name: String,
+    version: u32,
+    sequence: u64,
+    metadata: NexusMetadata,
+}
+
+impl MetadataReply {
+    fn new(nexus: &Nexus) -> Self {
+        Self {
+            name: nexus.name.clone(),
+            version: nexus.metadata.version.load(),
+            sequence: nexus.metadata.sequence.load(Ordering::SeqCst),
+            metadata: nexus.metadata(),
+        }
+    }
+}
+
+/// Register the metadata region json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nexus_get_metadata", |args: GetMetadataArgs| {
+        async move {
+            nexus_lookup(&args.name).map(MetadataReply::new).ok_or(
+                Error::NexusNotFound {
+                    name: args.name,
+                },
+            )
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_set_metadata", |args: SetMetadataArgs| {
+        async move {
+            let nexus =
+                nexus_lookup(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+
+            // the labels are also recorded with the definition of the nexus
+            if let Some(labels) = args.labels {
+                nexus.set_labels(labels).await?;
+            }
+            nexus
+                .update_metadata(|m| {
+                    if let Some(generation) = args.format_generation {
+                        m.format_generation = generation;
+                    }
+                    if let Some(filesystem) = args.filesystem {
+                        m.filesystem = Some(filesystem);
+                    }
+                    if let Some(attributes) = args.attributes {
+                        m.attributes = attributes;
+                    }
+                })
+                .await?;
+            Ok(MetadataReply::new(nexus))
+        }
+        .boxed_local()
+    });
+}
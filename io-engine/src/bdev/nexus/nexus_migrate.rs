@@ -0,0 +1,238 @@
+//! Migration of a nexus off a core.
+//!
+//! Before a core is taken out of the core mask, the I/Os of the nexuses must
+//! be moved away from it, without disconnecting their hosts. The I/Os of a
+//! nexus run on the threads of the NVMe-oF poll groups the queue pairs of its
+//! hosts are connected to, where the nexus has its channels, and on its shard
+//! cores, which connect to its remote children. Migrating a nexus off a core
+//! first takes the core out of its shard cores and reconnects its channels to
+//! the remaining ones, then moves the poll group threads of the core to the
+//! other poll group cores: their queue pairs and the channels of the nexus
+//! on them move along, and the hosts stay connected.
+//!
+//! The poll groups are shared by all the subsystems of the target, so the
+//! queue pairs of the other nexuses and replicas on the core are moved as
+//! well, and the core is no longer rebalanced onto. The migration runs in
+//! the background when started over json-rpc, and its progress is reported
+//! by the `nexus_migration_status` method.
+
+use futures::FutureExt;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use super::{nexus_lookup, DrEvent, Error, Nexus};
+use crate::{
+    core::{Cores, Reactors},
+    jsonrpc::jsonrpc_register,
+    subsys::{evacuate_core, PollGroupMove},
+};
+
+/// State of the migration of a nexus off a core.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationState {
+    /// The core is being taken out of the shard cores.
+    ShardCores,
+    /// The poll groups of the core are being moved.
+    PollGroups,
+    Completed,
+    Failed,
+}
+
+/// Progress of the migration of a nexus off a core.
+#[derive(Serialize, Debug, Clone)]
+pub struct CoreMigration {
+    pub core: u32,
+    pub state: MigrationState,
+    /// Shard cores of the nexus once the core was taken out of them, all
+    /// the cores if empty.
+    pub shard_cores: Vec<u32>,
+    /// Number of poll groups of the core to move.
+    pub poll_groups: usize,
+    /// Poll groups moved so far.
+    pub moved: Vec<PollGroupMove>,
+    pub error: Option<String>,
+    /// Time the migration started, in RFC 3339 format.
+    pub started: String,
+}
+
+impl CoreMigration {
+    fn running(&self) -> bool {
+        matches!(
+            self.state,
+            MigrationState::ShardCores | MigrationState::PollGroups
+        )
+    }
+}
+
+/// Last migration of a nexus off a core.
+#[derive(Default)]
+pub(crate) struct NexusMigration(Mutex<Option<CoreMigration>>);
+
+impl NexusMigration {
+    fn update(&self, f: impl FnOnce(&mut CoreMigration)) {
+        if let Some(migration) = self.0.lock().as_mut() {
+            f(migration);
+        }
+    }
+
+    /// Returns the progress of the last migration.
+    pub(crate) fn get(&self) -> Option<CoreMigration> {
+        self.0.lock().clone()
+    }
+}
+
+impl<'n> Nexus<'n> {
+    /// Migrate the I/Os of the nexus off the given core, without
+    /// disconnecting its hosts, returning once done.
+    /// Must be called from the master core.
+    pub async fn migrate_off_core(
+        &self,
+        core: u32,
+    ) -> Result<CoreMigration, Error> {
+        self.begin_migration(core)?;
+        Ok(self.run_migration(core).await)
+    }
+
+    /// Returns the progress of the last migration of the nexus off a core.
+    pub fn core_migration(&self) -> Option<CoreMigration> {
+        self.migration.get()
+    }
+
+    /// Check that the nexus can be migrated off the given core and record
+    /// the start of the migration.
+    fn begin_migration(&self, core: u32) -> Result<(), Error> {
+        let invalid = |args: String| Error::InvalidArguments {
+            name: self.name.clone(),
+            args,
+        };
+        if Reactors::get_by_core(core).is_none() {
+            return Err(invalid(format!("core {} is not in use", core)));
+        }
+        if core == Cores::first() {
+            return Err(invalid(format!(
+                "core {} is the master core and cannot be migrated off",
+                core
+            )));
+        }
+
+        let mut migration = self.migration.0.lock();
+        if let Some(m) = migration.as_ref().filter(|m| m.running()) {
+            return Err(invalid(format!(
+                "the nexus is being migrated off core {}",
+                m.core
+            )));
+        }
+        *migration = Some(CoreMigration {
+            core,
+            state: MigrationState::ShardCores,
+            shard_cores: Vec::new(),
+            poll_groups: 0,
+            moved: Vec::new(),
+            error: None,
+            started: chrono::Utc::now().to_rfc3339(),
+        });
+        Ok(())
+    }
+
+    /// Run the migration of the nexus off the given core.
+    async fn run_migration(&self, core: u32) -> CoreMigration {
+        info!("{:?}: migrating off core {}", self, core);
+
+        if self.shards.exclude(core) {
+            self.purge_core_shard_handles(core).await;
+            self.reconfigure(DrEvent::ShardCores).await;
+        }
+        let shard_cores = self.shards.cores();
+        self.migration.update(|m| {
+            m.shard_cores = shard_cores;
+            m.state = MigrationState::PollGroups;
+        });
+
+        let result = evacuate_core(core, |mv, total| {
+            self.migration.update(|m| {
+                m.poll_groups = total;
+                m.moved.push(mv.clone());
+            })
+        })
+        .await;
+
+        self.migration.update(|m| match result {
+            Ok(_) => {
+                info!("{:?}: migrated off core {}", self, core);
+                m.state = MigrationState::Completed;
+            }
+            Err(error) => {
+                error!(
+                    "{:?}: failed to migrate off core {}: {}",
+                    self, core, error
+                );
+                m.state = MigrationState::Failed;
+                m.error = Some(error);
+            }
+        });
+        self.migration.get().unwrap()
+    }
+}
+
+/// Arguments of the `nexus_migrate_off_core` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct MigrateOffCoreArgs {
+    /// Name of the nexus.
+    name: String,
+    /// Core to migrate the nexus off.
+    core: u32,
+}
+
+/// Arguments of the `nexus_migration_status` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct MigrationStatusArgs {
+    /// Name of the nexus.
+    name: String,
+}
+
+/// Reply of the migration json-rpc methods.
+#[derive(Debug, Serialize)]
+struct MigrationReply {
+    name: String,
+    migration: Option<CoreMigration>,
+}
+
+/// Register the migration json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nexus_migrate_off_core", |args: MigrateOffCoreArgs| {
+        async move {
+            let nexus =
+                nexus_lookup(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+            nexus.begin_migration(args.core)?;
+
+            let name = args.name.clone();
+            Reactors::master().send_future(async move {
+                if let Some(nexus) = nexus_lookup(&name) {
+                    nexus.run_migration(args.core).await;
+                }
+            });
+            Ok(MigrationReply {
+                name: args.name,
+                migration: nexus.core_migration(),
+            })
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_migration_status", |args: MigrationStatusArgs| {
+        async move {
+            nexus_lookup(&args.name)
+                .map(|n| MigrationReply {
+                    name: n.name.clone(),
+                    migration: n.core_migration(),
+                })
+                .ok_or(Error::NexusNotFound {
+                    name: args.name,
+                })
+        }
+        .boxed_local()
+    });
+}
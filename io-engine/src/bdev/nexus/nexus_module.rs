@@ -66,7 +66,7 @@ impl WithModuleConfigJson for NexusModule {
                 .collect::<Vec<String>>();
 
             let json = json!({
-                "method": "create_nexus",
+                "method": "nexus_create",
                 "params": {
                     "name" : nexus.name,
                     "uuid" : unsafe { nexus.bdev().uuid_as_string() },
@@ -1,7 +1,27 @@
-use super::{ChildState, Nexus, NexusChild};
-use crate::{persistent_store::PersistentStore, sleep::mayastor_sleep};
+use super::{
+    nexus_err,
+    nexus_io::request_self_shutdown,
+    nexus_iter,
+    nexus_lookup_mut,
+    ChildState,
+    Error,
+    Nexus,
+    NexusChild,
+};
+use crate::{
+    core::Reactor,
+    persistent_store::{OwnershipEvent, PersistentStore},
+    reconcile::{nexus_spec_key, NexusSpec},
+    sleep::mayastor_sleep,
+    store::{
+        backoff::Backoff,
+        store_defs::StoreError,
+        store_schema::Versioned,
+    },
+};
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use snafu::ResultExt;
+use std::{pin::Pin, sync::Once, time::Duration};
 
 /// Information associated with the persisted NexusInfo structure.
 pub struct PersistentNexusInfo {
@@ -25,6 +45,16 @@ impl PersistentNexusInfo {
     fn inner_mut(&mut self) -> &mut NexusInfo {
         &mut self.inner
     }
+
+    /// Key under which the NexusInfo structure is stored.
+    pub(super) fn info_key(&self, nexus_uuid: String) -> String {
+        self.key.clone().unwrap_or(nexus_uuid)
+    }
+
+    /// Key under which the ownership record of the nexus is stored.
+    fn owner_key(&self, nexus_uuid: String) -> String {
+        format!("{}/owner", self.info_key(nexus_uuid))
+    }
 }
 
 /// Definition of the nexus information that gets saved in the persistent
@@ -37,6 +67,10 @@ pub struct NexusInfo {
     pub children: Vec<ChildInfo>,
 }
 
+impl Versioned for NexusInfo {
+    const KIND: &'static str = "nexus information";
+}
+
 /// Definition of the child information that gets saved in the persistent
 /// store.
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
@@ -75,12 +109,113 @@ pub(crate) enum PersistOp<'a> {
 }
 
 impl<'n> Nexus<'n> {
+    /// Take ownership of the nexus in the persistent store. The ownership
+    /// record is bound to the lease of this instance, so it is released
+    /// automatically should this node lose contact with the store.
+    pub(crate) async fn acquire_ownership(&self) -> Result<(), Error> {
+        if !PersistentStore::enabled() {
+            return Ok(());
+        }
+
+        let (info_key, key) = {
+            let info = self.nexus_info.lock().await;
+            let uuid = self.uuid().to_string();
+            (info.info_key(uuid.clone()), info.owner_key(uuid))
+        };
+
+        // The information of a nexus written by a newer io-engine must not
+        // be overwritten with an older schema.
+        match PersistentStore::get_record::<NexusInfo>(&info_key).await {
+            Err(
+                source @ StoreError::SchemaDowngrade {
+                    ..
+                },
+            ) => {
+                return Err(Error::PersistedSchema {
+                    name: self.name.clone(),
+                    source,
+                });
+            }
+            _ => (),
+        }
+
+        PersistentStore::acquire_ownership(&key).await.context(
+            nexus_err::AcquireOwnership {
+                name: self.name.clone(),
+            },
+        )?;
+
+        watch_ownership();
+        Ok(())
+    }
+
+    /// Fence the nexus as its ownership has been lost: new I/Os are failed
+    /// right away. If another node took the nexus over, the hosts are
+    /// disconnected and the nexus is shut down, waiting for the control
+    /// plane to destroy it. Otherwise the lease of this node expired, and
+    /// the nexus is unfenced once its ownership has been re-acquired.
+    pub(crate) async fn fence(mut self: Pin<&mut Self>, owner: Option<String>) {
+        if !self.fenced.swap(true) {
+            warn!("{:?}: ownership lost, fencing I/O", self);
+        }
+
+        let owner = match owner {
+            Some(owner) => owner,
+            None => return,
+        };
+
+        error!(
+            "{:?}: nexus taken over by node '{}', shutting down",
+            self, owner
+        );
+        if let Err(e) = self.as_mut().unshare_nexus().await {
+            error!("{:?}: failed to disconnect hosts: {}", self, e);
+        }
+        request_self_shutdown(&self);
+    }
+
+    /// Lift the fencing of the nexus once its ownership has been
+    /// re-acquired, unless it is being shut down already.
+    pub(crate) fn unfence(&self) {
+        if !self.shutdown_requested.load() && self.fenced.swap(false) {
+            info!("{:?}: ownership re-acquired, resuming I/O", self);
+        }
+    }
+
+    /// Give up ownership of the nexus in the persistent store.
+    pub(crate) async fn release_ownership(&self) {
+        if !PersistentStore::enabled() {
+            return;
+        }
+
+        let key = self
+            .nexus_info
+            .lock()
+            .await
+            .owner_key(self.uuid().to_string());
+        if let Err(e) = PersistentStore::release_ownership(&key).await {
+            warn!(
+                "{:?}: failed to release ownership of key {}: {}",
+                self, key, e
+            );
+        }
+    }
+
     /// Persist information to the store.
     pub(crate) async fn persist(&self, op: PersistOp<'_>) {
         if !PersistentStore::enabled() {
             return;
         }
 
+        // the nexus information may belong to another node by now
+        if self.fenced.load() {
+            warn!("{:?}: fenced, not persisting nexus information", self);
+            if matches!(op, PersistOp::Shutdown) {
+                self.release_ownership().await;
+            }
+            return;
+        }
+
         let mut persistent_nexus_info = self.nexus_info.lock().await;
         let mut nexus_info = persistent_nexus_info.inner_mut();
 
@@ -96,7 +231,10 @@ impl<'n> Nexus<'n> {
                     let child_info = ChildInfo {
                         uuid: NexusChild::uuid(c.uri())
                             .expect("Failed to get child UUID."),
-                        healthy: Self::child_healthy(&c.state()),
+                        // a child whose connection is deferred is deemed
+                        // healthy until it fails to connect
+                        healthy: Self::child_healthy(&c.state())
+                            || c.is_deferred(),
                     };
                     nexus_info.children.push(child_info);
                 });
@@ -156,7 +294,77 @@ impl<'n> Nexus<'n> {
                 nexus_info.clean_shutdown = true;
             }
         }
+        let shutdown = nexus_info.clean_shutdown;
         self.save(&persistent_nexus_info).await;
+        let nexus_info_key = persistent_nexus_info.key.clone();
+        drop(persistent_nexus_info);
+
+        // Once the clean shutdown has been recorded the nexus no longer
+        // needs to be owned by this node.
+        if shutdown {
+            self.release_ownership().await;
+        } else {
+            self.save_spec(nexus_info_key).await;
+        }
+    }
+
+    /// Record the definition of the nexus, so that it can be restored on
+    /// startup should this node go down.
+    async fn save_spec(&self, nexus_info_key: Option<String>) {
+        let spec = self.make_spec(nexus_info_key);
+        if let Err(e) =
+            PersistentStore::put_record(&nexus_spec_key(&self.name), &spec)
+                .await
+        {
+            warn!("{:?}: failed to record nexus definition: {}", self, e);
+        }
+    }
+
+    /// Record the definition of the nexus again after it has changed, if it
+    /// is being persisted.
+    pub(crate) async fn persist_spec(&self) {
+        if !PersistentStore::enabled() {
+            return;
+        }
+        let nexus_info_key = self.nexus_info.lock().await.key.clone();
+        self.save_spec(nexus_info_key).await;
+    }
+
+    /// Returns the definition of the nexus.
+    pub(crate) async fn spec(&self) -> NexusSpec {
+        let nexus_info_key = self.nexus_info.lock().await.key.clone();
+        self.make_spec(nexus_info_key)
+    }
+
+    fn make_spec(&self, nexus_info_key: Option<String>) -> NexusSpec {
+        NexusSpec {
+            name: self.name.clone(),
+            alias: self.alias(),
+            labels: self.labels(),
+            read_only: self.read_only.load(),
+            uuid: self.uuid().to_string(),
+            size: self.req_size(),
+            children: self.children_uris(),
+            nexus_info_key,
+            min_cntlid: self.nvme_params.min_cntlid,
+            max_cntlid: self.nvme_params.max_cntlid,
+            resv_key: self.nvme_params.resv_key,
+            resv_type: self.nvme_params.resv_type as u8,
+            lazy_block_len: self.lazy.block_len(),
+        }
+    }
+
+    /// Remove the definition of the nexus once it has been destroyed.
+    pub(crate) async fn forget_spec(&self) {
+        if !PersistentStore::enabled() {
+            return;
+        }
+
+        if let Err(e) =
+            PersistentStore::delete(&nexus_spec_key(&self.name)).await
+        {
+            warn!("{:?}: failed to delete nexus definition: {}", self, e);
+        }
     }
 
     /// Determine child health.
@@ -170,16 +378,15 @@ impl<'n> Nexus<'n> {
     // TODO: Should we give up retrying eventually?
     async fn save(&self, info: &PersistentNexusInfo) {
         let mut output_err = true;
+        let mut backoff =
+            Backoff::new(Duration::from_millis(100), Duration::from_secs(5));
         let nexus_uuid = self.uuid().to_string();
         // If a key has been provided use this to store the NexusInfo.
         // If a key is not provided, use the nexus uuid as the key.
-        let key = match &info.key {
-            Some(k) => k.clone(),
-            None => self.uuid().to_string(),
-        };
+        let key = info.info_key(self.uuid().to_string());
 
         loop {
-            match PersistentStore::put(&key, &info.inner).await {
+            match PersistentStore::put_record(&key, &info.inner).await {
                 Ok(_) => {
                     // The state was saved successfully.
                     break;
@@ -199,7 +406,7 @@ impl<'n> Nexus<'n> {
 
                     // Allow some time for the connection to the persistent
                     // store to be re-established before retrying the operation.
-                    let rx = mayastor_sleep(Duration::from_secs(1));
+                    let rx = mayastor_sleep(backoff.next_delay());
                     if rx.await.is_err() {
                         // Failed to wait for sleep but just carry on around the
                         // loop and try the 'put' again anyway.
@@ -210,3 +417,52 @@ impl<'n> Nexus<'n> {
         }
     }
 }
+
+/// Register the listener fencing the nexuses whose ownership is lost, once.
+fn watch_ownership() {
+    static WATCH: Once = Once::new();
+    WATCH.call_once(|| {
+        PersistentStore::add_ownership_listener(|event| {
+            if let Err(e) = Reactor::spawn_at_primary(ownership_changed(event))
+            {
+                error!("Failed to handle nexus ownership change: {}", e);
+            }
+        });
+    });
+}
+
+/// Fence or unfence the nexus owned under the key of an ownership change.
+async fn ownership_changed(event: OwnershipEvent) {
+    let (key, lost, owner) = match event {
+        OwnershipEvent::Lost {
+            key,
+            owner,
+        } => (key, true, owner),
+        OwnershipEvent::Reacquired {
+            key,
+        } => (key, false, None),
+    };
+
+    let names = nexus_iter().map(|n| n.name.clone()).collect::<Vec<_>>();
+    for name in names {
+        let nexus = match nexus_lookup_mut(&name) {
+            Some(nexus) => nexus,
+            None => continue,
+        };
+        let owner_key = nexus
+            .nexus_info
+            .lock()
+            .await
+            .owner_key(nexus.uuid().to_string());
+        if owner_key != key {
+            continue;
+        }
+
+        if lost {
+            nexus.fence(owner).await;
+        } else {
+            nexus.unfence();
+        }
+        return;
+    }
+}
@@ -0,0 +1,246 @@
+//! Routing of the reads by fault domain.
+//!
+//! The children of a nexus are usually spread over several nodes, racks or
+//! zones, so that losing one of them does not lose the data, but reading
+//! from a distant child is slower, and may cost cross-zone traffic. The
+//! children can be given topology hints, their zone, rack and node, along
+//! with the location of the nexus itself: the reads are then sent to the
+//! closest healthy children, in turn, and only fall back to more distant
+//! children once no closer child is left in the I/O path.
+//!
+//! The distance between two locations is 0 on the same node, 1 in the same
+//! rack, 2 in the same zone, and 3 otherwise, or when unknown. The number of
+//! reads served by each child is counted from the time the routing is set,
+//! so that the placement of the children can be checked against it.
+
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+use crossbeam::atomic::AtomicCell;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+
+use super::{nexus_lookup, DrEvent, Error, Nexus};
+use crate::jsonrpc::jsonrpc_register;
+
+/// Distance to a location which is not known.
+const UNKNOWN_DISTANCE: u8 = 3;
+
+/// Location of a nexus or of a child.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Topology {
+    pub zone: Option<String>,
+    pub rack: Option<String>,
+    pub node: Option<String>,
+}
+
+impl Topology {
+    /// Returns the distance to the given location.
+    pub fn distance(&self, other: &Topology) -> u8 {
+        let same = |a: &Option<String>, b: &Option<String>| matches!((a, b), (Some(a), Some(b)) if a == b);
+        if same(&self.node, &other.node) {
+            0
+        } else if same(&self.rack, &other.rack) {
+            1
+        } else if same(&self.zone, &other.zone) {
+            2
+        } else {
+            UNKNOWN_DISTANCE
+        }
+    }
+}
+
+/// Read routing of a nexus.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ReadRoutingPolicy {
+    /// Route the reads by distance, rather than to all children in turn.
+    pub enabled: bool,
+    /// Location of the nexus.
+    #[serde(default)]
+    pub local: Topology,
+    /// Location of the children, by uri.
+    #[serde(default)]
+    pub children: HashMap<String, Topology>,
+}
+
+impl ReadRoutingPolicy {
+    /// Returns the distance of the child with the given uri, 0 for all
+    /// children if the routing is disabled.
+    pub(super) fn distance(&self, uri: &str) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        self.children
+            .get(uri)
+            .map_or(UNKNOWN_DISTANCE, |t| self.local.distance(t))
+    }
+}
+
+/// Read routing of a nexus, along with the reads served by its children.
+pub(crate) struct ReadRouting {
+    enabled: AtomicCell<bool>,
+    policy: parking_lot::RwLock<ReadRoutingPolicy>,
+    since: AtomicCell<Instant>,
+    /// Reads served, by child device.
+    served: parking_lot::RwLock<HashMap<String, AtomicU64>>,
+}
+
+impl ReadRouting {
+    pub(crate) fn new() -> Self {
+        Self {
+            enabled: AtomicCell::new(false),
+            policy: parking_lot::RwLock::new(ReadRoutingPolicy::default()),
+            since: AtomicCell::new(Instant::now()),
+            served: parking_lot::RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the read routing.
+    pub(crate) fn policy(&self) -> ReadRoutingPolicy {
+        self.policy.read().clone()
+    }
+
+    fn set(&self, policy: ReadRoutingPolicy) {
+        self.enabled.store(policy.enabled);
+        *self.policy.write() = policy;
+        self.served.write().clear();
+        self.since.store(Instant::now());
+    }
+
+    /// Returns true if the reads are routed by distance.
+    #[inline(always)]
+    pub(super) fn is_enabled(&self) -> bool {
+        self.enabled.load()
+    }
+
+    /// Count a read served by the given child device.
+    pub(super) fn served(&self, device: &str) {
+        if let Some(count) = self.served.read().get(device) {
+            count.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.served
+            .write()
+            .entry(device.to_owned())
+            .or_insert_with(|| AtomicU64::new(0))
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn served_count(&self, device: &str) -> u64 {
+        self.served
+            .read()
+            .get(device)
+            .map_or(0, |c| c.load(Ordering::Relaxed))
+    }
+}
+
+impl<'n> Nexus<'n> {
+    /// Set the read routing of the nexus, which restarts the count of the
+    /// reads served by the children.
+    pub async fn set_read_routing(&self, policy: ReadRoutingPolicy) {
+        info!("{:?}: setting read routing: {:?}", self, policy);
+        self.read_routing.set(policy);
+        // the channels order their readers by distance
+        self.reconfigure(DrEvent::ReadRouting).await;
+    }
+
+    /// Returns the number of reads served by each child since the read
+    /// routing has been set, by uri. Reads are only counted while the
+    /// routing is enabled.
+    pub fn reads_served(&self) -> HashMap<String, u64> {
+        self.children_iter()
+            .map(|c| {
+                let count = c
+                    .get_device_name()
+                    .map_or(0, |d| self.read_routing.served_count(&d));
+                (c.uri().to_owned(), count)
+            })
+            .collect()
+    }
+}
+
+/// Reads served by a child.
+#[derive(Serialize, Debug)]
+struct ChildReads {
+    uri: String,
+    distance: u8,
+    reads_served: u64,
+}
+
+/// Read routing of a nexus, with the reads served by its children since it
+/// has been set.
+#[derive(Serialize, Debug)]
+struct ReadRoutingReply {
+    name: String,
+    policy: ReadRoutingPolicy,
+    since_ms: u64,
+    children: Vec<ChildReads>,
+}
+
+impl ReadRoutingReply {
+    fn new(nexus: &Nexus) -> Self {
+        let policy = nexus.read_routing.policy();
+        let mut children = nexus
+            .reads_served()
+            .into_iter()
+            .map(|(uri, reads_served)| ChildReads {
+                distance: policy.distance(&uri),
+                uri,
+                reads_served,
+            })
+            .collect::<Vec<_>>();
+        children.sort_by(|a, b| a.uri.cmp(&b.uri));
+        Self {
+            name: nexus.name.clone(),
+            policy,
+            since_ms: nexus.read_routing.since.load().elapsed().as_millis()
+                as u64,
+            children,
+        }
+    }
+}
+
+/// Arguments of the nexus_set_read_routing json-rpc method.
+#[derive(Debug, Deserialize)]
+struct SetReadRoutingArgs {
+    /// Name of the nexus.
+    name: String,
+    #[serde(flatten)]
+    policy: ReadRoutingPolicy,
+}
+
+/// Arguments of the nexus_get_read_routing json-rpc method.
+#[derive(Debug, Deserialize)]
+struct GetReadRoutingArgs {
+    /// Name of the nexus.
+    name: String,
+}
+
+/// Register the read routing json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nexus_set_read_routing", |args: SetReadRoutingArgs| {
+        async move {
+            let nexus =
+                nexus_lookup(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+            nexus.set_read_routing(args.policy).await;
+            Ok(ReadRoutingReply::new(&nexus))
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_get_read_routing", |args: GetReadRoutingArgs| {
+        async move {
+            nexus_lookup(&args.name)
+                .map(|n| ReadRoutingReply::new(&n))
+                .ok_or(Error::NexusNotFound {
+                    name: args.name,
+                })
+        }
+        .boxed_local()
+    });
+}
@@ -0,0 +1,546 @@
+//! Readahead of sequential read streams.
+//!
+//! Streaming a large file reads it in a sequence of adjacent reads, each of
+//! which waits for a round-trip to a child. When readahead is enabled, each
+//! nexus channel follows the stream of reads submitted on it: once a few
+//! reads followed each other, the data following the stream is prefetched
+//! from a child, a window at a time, and the reads which fall within the
+//! prefetched data are served from it without reaching the children.
+//!
+//! At most two windows are prefetched ahead of a stream, and a read which
+//! does not follow the stream drops them. Any write to the nexus, on any
+//! core, invalidates the prefetched data: the writes are counted, both when
+//! submitted and when completed, and data prefetched before the last count
+//! is not served.
+
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use crossbeam::atomic::AtomicCell;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+
+use spdk_rs::{DmaBuf, IoChannel, IoVec};
+
+use super::{
+    nexus_channel::ChannelEpoch,
+    nexus_checksum::scatter,
+    nexus_lookup,
+    Error,
+    Nexus,
+    NexusChannel,
+};
+use crate::{
+    core::{BlockDevice, IoCompletionStatus},
+    jsonrpc::jsonrpc_register,
+};
+
+/// Number of adjacent reads after which a stream is prefetched.
+const SEQUENTIAL_READS: u32 = 2;
+
+/// Maximum number of windows prefetched ahead of a stream.
+const MAX_WINDOWS: usize = 2;
+
+/// Maximum readahead window, in bytes.
+const MAX_WINDOW_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Readahead policy of a nexus.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ReadaheadPolicy {
+    pub enabled: bool,
+    /// Size of the data prefetched at a time, in bytes.
+    pub window_bytes: u64,
+}
+
+impl Default for ReadaheadPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// Readahead statistics of a nexus.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct ReadaheadStats {
+    /// Number of reads served from the prefetched data.
+    pub hits: u64,
+    /// Number of reads submitted to the children.
+    pub misses: u64,
+    /// Number of windows prefetched from the children.
+    pub prefetches: u64,
+    /// Number of bytes prefetched from the children.
+    pub prefetched_bytes: u64,
+    /// Number of prefetched windows dropped as written in the meantime.
+    pub invalidated: u64,
+}
+
+impl ReadaheadStats {
+    /// Returns the ratio of the reads served from the prefetched data.
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            reads => self.hits as f64 / reads as f64,
+        }
+    }
+}
+
+/// Readahead policy of a nexus, along with its statistics.
+pub(crate) struct Readahead {
+    policy: AtomicCell<ReadaheadPolicy>,
+    /// Number of writes submitted and completed.
+    generation: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    prefetches: AtomicU64,
+    prefetched_bytes: AtomicU64,
+    invalidated: AtomicU64,
+}
+
+impl Readahead {
+    pub(crate) fn new() -> Self {
+        Self {
+            policy: AtomicCell::new(ReadaheadPolicy::default()),
+            generation: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            prefetches: AtomicU64::new(0),
+            prefetched_bytes: AtomicU64::new(0),
+            invalidated: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the current policy.
+    #[inline(always)]
+    pub(crate) fn policy(&self) -> ReadaheadPolicy {
+        self.policy.load()
+    }
+
+    /// Returns true if readahead is enabled.
+    #[inline(always)]
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.policy.load().enabled
+    }
+
+    /// Replace the policy, dropping the data already prefetched.
+    fn set_policy(&self, policy: ReadaheadPolicy) {
+        self.policy.store(policy);
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Account a write, invalidating the data prefetched so far.
+    #[inline(always)]
+    pub(super) fn write(&self) {
+        if self.is_enabled() {
+            self.generation.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Returns the number of writes accounted so far.
+    #[inline(always)]
+    pub(super) fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Account a read, served from the prefetched data or not.
+    #[inline(always)]
+    pub(super) fn read(&self, hit: bool) {
+        if hit {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn prefetched(&self, bytes: u64) {
+        self.prefetches.fetch_add(1, Ordering::Relaxed);
+        self.prefetched_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Returns the statistics.
+    pub(crate) fn stats(&self) -> ReadaheadStats {
+        ReadaheadStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            prefetches: self.prefetches.load(Ordering::Relaxed),
+            prefetched_bytes: self.prefetched_bytes.load(Ordering::Relaxed),
+            invalidated: self.invalidated.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Window of data prefetched on a nexus channel.
+struct Prefetched {
+    offset: u64,
+    num_blocks: u64,
+    /// Number of writes accounted when the window was prefetched.
+    generation: u64,
+    buf: DmaBuf,
+}
+
+/// Stream of sequential reads on a nexus channel, along with the data
+/// prefetched ahead of it.
+pub(super) struct ReadaheadStream {
+    /// Identifies the stream, the windows prefetched for a previous stream
+    /// are dropped.
+    id: u64,
+    /// Offset of the block following the last read.
+    next_offset: u64,
+    /// Number of adjacent reads of the stream.
+    sequential: u32,
+    /// Offset of the block following the data prefetched, or being
+    /// prefetched.
+    ahead: u64,
+    /// A window is being prefetched.
+    in_flight: bool,
+    windows: VecDeque<Prefetched>,
+    /// Cleared once the channel of the stream is destroyed, for the
+    /// prefetches in flight not to refer to it.
+    live: Rc<Cell<bool>>,
+}
+
+impl ReadaheadStream {
+    pub(super) fn new() -> Self {
+        Self {
+            id: 0,
+            next_offset: 0,
+            sequential: 0,
+            ahead: 0,
+            in_flight: false,
+            windows: VecDeque::new(),
+            live: Rc::new(Cell::new(true)),
+        }
+    }
+
+    /// Stop the stream, as its channel is being destroyed.
+    pub(super) fn stop(&mut self) {
+        self.live.set(false);
+        self.windows.clear();
+    }
+
+    /// Account a read of the stream, copying the data read to the given I/O
+    /// vectors if it has been prefetched. Returns true if it has.
+    pub(super) fn read(
+        &mut self,
+        readahead: &Readahead,
+        offset: u64,
+        num_blocks: u64,
+        block_len: u64,
+        iovs: *const libc::iovec,
+        iov_count: usize,
+    ) -> bool {
+        if offset == self.next_offset {
+            self.sequential = self.sequential.saturating_add(1);
+        } else {
+            self.restart();
+        }
+        self.next_offset = offset + num_blocks;
+
+        let generation = readahead.generation();
+        if self.windows.iter().any(|w| w.generation != generation) {
+            readahead
+                .invalidated
+                .fetch_add(self.windows.len() as u64, Ordering::Relaxed);
+            self.windows.clear();
+            self.ahead = self.next_offset;
+        }
+        while matches!(self.windows.front(), Some(w) if w.offset + w.num_blocks <= offset)
+        {
+            self.windows.pop_front();
+        }
+
+        let hit = match self.windows.front() {
+            Some(w)
+                if w.offset <= offset
+                    && offset + num_blocks <= w.offset + w.num_blocks =>
+            {
+                let start = ((offset - w.offset) * block_len) as usize;
+                let end = start + (num_blocks * block_len) as usize;
+                scatter(&w.buf.as_slice()[start .. end], iovs, iov_count);
+                true
+            }
+            _ => false,
+        };
+        readahead.read(hit);
+        hit
+    }
+
+    /// Returns the identifier, offset and number of blocks of the window to
+    /// prefetch next, if the stream is sequential and not prefetched far
+    /// enough ahead.
+    pub(super) fn next_window(
+        &mut self,
+        window_blocks: u64,
+        num_blocks: u64,
+    ) -> Option<(u64, u64, u64)> {
+        if self.in_flight
+            || self.sequential < SEQUENTIAL_READS
+            || self.windows.len() >= MAX_WINDOWS
+        {
+            return None;
+        }
+
+        let offset = self.ahead.max(self.next_offset);
+        let blocks = window_blocks.min(num_blocks.saturating_sub(offset));
+        if blocks == 0 || offset >= self.next_offset + window_blocks {
+            return None;
+        }
+        self.in_flight = true;
+        self.ahead = offset + blocks;
+        Some((self.id, offset, blocks))
+    }
+
+    /// Drop the data prefetched for the stream, the reads no longer
+    /// following each other.
+    fn restart(&mut self) {
+        self.id += 1;
+        self.sequential = 0;
+        self.ahead = 0;
+        self.in_flight = false;
+        self.windows.clear();
+    }
+
+    /// Account the completion of the prefetch of a window of the given
+    /// stream, successful if it holds the data.
+    fn completed(&mut self, id: u64, window: Option<Prefetched>) {
+        if id != self.id {
+            return;
+        }
+        self.in_flight = false;
+        match window {
+            Some(window) => self.windows.push_back(window),
+            // the stream is prefetched again from where it is
+            None => self.ahead = 0,
+        }
+    }
+}
+
+/// Context of the prefetch of a window.
+pub(super) struct Prefetch<'n> {
+    channel: IoChannel<NexusChannel<'n>>,
+    /// Epoch of the channels of the nexus, and liveness of the channel: the
+    /// channel is not followed once either is gone.
+    epoch: Arc<ChannelEpoch>,
+    live: Rc<Cell<bool>>,
+    stream: u64,
+    offset: u64,
+    num_blocks: u64,
+    generation: u64,
+    iov: libc::iovec,
+    buf: DmaBuf,
+}
+
+impl<'n> Prefetch<'n> {
+    /// Prefetch the next window of the stream of the given channel from one
+    /// of its readers, if it is due.
+    pub(super) fn submit(channel: IoChannel<NexusChannel<'n>>) {
+        let ch = channel.channel_data();
+        let nexus = ch.nexus();
+        let block_len = nexus.block_len();
+        let window_blocks =
+            (nexus.readahead.policy().window_bytes / block_len).max(1);
+
+        let mut stream_channel = channel.clone();
+        let stream = &mut stream_channel.channel_data_mut().readahead;
+        let (id, offset, num_blocks) =
+            match stream.next_window(window_blocks, nexus.num_blocks()) {
+                Some(window) => window,
+                None => return,
+            };
+
+        let hdl = match ch.select_reader() {
+            Some(hdl) => hdl,
+            None => return stream.completed(id, None),
+        };
+        let buf = match hdl.dma_malloc(num_blocks * block_len) {
+            Ok(buf) => buf,
+            Err(_) => return stream.completed(id, None),
+        };
+
+        let mut prefetch = Box::new(Prefetch {
+            channel: channel.clone(),
+            epoch: nexus.channels.clone(),
+            live: stream.live.clone(),
+            stream: id,
+            offset,
+            num_blocks,
+            generation: nexus.readahead.generation(),
+            iov: libc::iovec {
+                iov_base: *buf,
+                iov_len: buf.len() as usize,
+            },
+            buf,
+        });
+        let iov = &mut prefetch.iov as *mut libc::iovec as *mut IoVec;
+        let ctx = Box::into_raw(prefetch);
+
+        if let Err(error) = hdl.readv_blocks(
+            iov,
+            1,
+            offset + nexus.data_ent_offset,
+            num_blocks,
+            Self::completion,
+            ctx.cast(),
+        ) {
+            debug!(
+                "{:?}: readahead submission to '{}' failed: {:?}",
+                ch,
+                hdl.get_device().device_name(),
+                error
+            );
+            drop(unsafe { Box::from_raw(ctx) });
+            stream.completed(id, None);
+        }
+    }
+
+    /// Completion callback of the prefetch of a window.
+    fn completion(
+        _device: &dyn BlockDevice,
+        status: IoCompletionStatus,
+        ctx: *mut libc::c_void,
+    ) {
+        let prefetch = unsafe { Box::from_raw(ctx as *mut Prefetch) };
+        let Prefetch {
+            mut channel,
+            epoch,
+            live,
+            stream,
+            offset,
+            num_blocks,
+            generation,
+            buf,
+            ..
+        } = *prefetch;
+
+        // the nexus is being destroyed or the channel is gone, the window is
+        // dropped along with the channel
+        if epoch.is_closed() || !live.get() {
+            return;
+        }
+
+        let window = if status == IoCompletionStatus::Success {
+            channel
+                .channel_data()
+                .nexus()
+                .readahead
+                .prefetched(buf.len());
+            Some(Prefetched {
+                offset,
+                num_blocks,
+                generation,
+                buf,
+            })
+        } else {
+            // a failed prefetch is not a failed read, the reads are left to
+            // handle the failure of the child
+            None
+        };
+        channel
+            .channel_data_mut()
+            .readahead
+            .completed(stream, window);
+    }
+}
+
+impl<'n> Nexus<'n> {
+    /// Set the readahead policy of the nexus.
+    pub fn set_readahead(&self, policy: ReadaheadPolicy) -> Result<(), Error> {
+        if policy.window_bytes < self.block_len()
+            || policy.window_bytes > MAX_WINDOW_BYTES
+        {
+            return Err(Error::InvalidArguments {
+                name: self.name.clone(),
+                args: format!(
+                    "the readahead window must be between {} and {} bytes",
+                    self.block_len(),
+                    MAX_WINDOW_BYTES
+                ),
+            });
+        }
+        info!("{:?}: setting readahead policy: {:?}", self, policy);
+        self.readahead.set_policy(policy);
+        Ok(())
+    }
+
+    /// Returns the readahead statistics of the nexus.
+    pub fn readahead_stats(&self) -> ReadaheadStats {
+        self.readahead.stats()
+    }
+}
+
+/// Arguments of the `nexus_set_readahead` json-rpc method, the fields which
+/// are not given are left unchanged.
+#[derive(Debug, Deserialize)]
+struct SetReadaheadArgs {
+    /// Name of the nexus.
+    name: String,
+    enabled: Option<bool>,
+    window_bytes: Option<u64>,
+}
+
+/// Arguments of the `nexus_get_readahead` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct GetReadaheadArgs {
+    /// Name of the nexus.
+    name: String,
+}
+
+/// Reply of the readahead json-rpc methods.
+#[derive(Debug, Serialize)]
+struct ReadaheadReply {
+    name: String,
+    policy: ReadaheadPolicy,
+    stats: ReadaheadStats,
+    hit_rate: f64,
+}
+
+impl ReadaheadReply {
+    fn new(nexus: &Nexus) -> Self {
+        let stats = nexus.readahead_stats();
+        Self {
+            name: nexus.name.clone(),
+            policy: nexus.readahead.policy(),
+            hit_rate: stats.hit_rate(),
+            stats,
+        }
+    }
+}
+
+/// Register the readahead json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nexus_set_readahead", |args: SetReadaheadArgs| {
+        async move {
+            let nexus =
+                nexus_lookup(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+
+            let current = nexus.readahead.policy();
+            nexus.set_readahead(ReadaheadPolicy {
+                enabled: args.enabled.unwrap_or(current.enabled),
+                window_bytes: args.window_bytes.unwrap_or(current.window_bytes),
+            })?;
+            Ok(ReadaheadReply::new(nexus))
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_get_readahead", |args: GetReadaheadArgs| {
+        async move {
+            nexus_lookup(&args.name).map(ReadaheadReply::new).ok_or(
+                Error::NexusNotFound {
+                    name: args.name,
+                },
+            )
+        }
+        .boxed_local()
+    });
+}
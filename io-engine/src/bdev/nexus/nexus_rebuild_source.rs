@@ -0,0 +1,200 @@
+//! Selection of the source of a rebuild.
+//!
+//! When several children of a nexus are healthy, a rebuild reads from the
+//! one which can best afford it rather than from the first one: the closest
+//! one when the children are given topology hints by the read routing, then
+//! the one with the lowest read latency weighted by its load, the reads the
+//! nexus has in flight to it and the rebuilds already reading from it.
+//!
+//! The read latency of a child is a moving average of the reads the nexus
+//! sends to it. A child which has not served reads lately is given the
+//! average latency of the other children, so that it is ranked by its load.
+//! The candidates, and the reason the source was selected among them, are
+//! kept in the rebuild job.
+
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+
+use super::{nexus_lookup, ChildState, Error, Nexus};
+use crate::{
+    jsonrpc::jsonrpc_register,
+    rebuild::{IoLatency, RebuildJob},
+};
+
+/// Reads the nexus has in flight to a child, and their latency.
+#[derive(Default)]
+struct ChildReads {
+    in_flight: AtomicU64,
+    latency: IoLatency,
+}
+
+/// Reads of the children of a nexus, by child device.
+#[derive(Default)]
+pub(crate) struct ChildReadStats(
+    parking_lot::RwLock<HashMap<String, ChildReads>>,
+);
+
+impl ChildReadStats {
+    fn with(&self, device: &str, f: impl FnOnce(&ChildReads)) {
+        if let Some(reads) = self.0.read().get(device) {
+            f(reads);
+            return;
+        }
+        f(self.0.write().entry(device.to_owned()).or_default());
+    }
+
+    /// Account a read submitted to the given child device.
+    pub(super) fn submitted(&self, device: &str) {
+        self.with(device, |r| {
+            r.in_flight.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+
+    /// Account a read of the given child device submitted at the given
+    /// ticks and completed now.
+    pub(super) fn completed(&self, device: &str, submitted: u64) {
+        self.with(device, |r| {
+            r.in_flight.fetch_sub(1, Ordering::Relaxed);
+            r.latency.completed(submitted);
+        });
+    }
+
+    /// Returns the reads in flight to the given child device and their
+    /// average latency in microseconds, 0 if unknown.
+    fn get(&self, device: &str) -> (u64, u64) {
+        self.0.read().get(device).map_or((0, 0), |r| {
+            (r.in_flight.load(Ordering::Relaxed), r.latency.average_us())
+        })
+    }
+}
+
+/// Healthy child which could be the source of a rebuild.
+#[derive(Serialize, Debug, Clone)]
+pub struct SourceCandidate {
+    pub uri: String,
+    /// Distance to the nexus, from the topology hints of the read routing.
+    pub distance: u8,
+    /// Average read latency in microseconds, 0 if unknown.
+    pub read_latency_us: u64,
+    pub reads_in_flight: u64,
+    /// Rebuilds reading from the child.
+    pub rebuilds: u64,
+    /// Read latency weighted by the load, the lower the better.
+    pub cost: u64,
+}
+
+/// Source of a rebuild, along with the candidates it was selected among.
+#[derive(Serialize, Debug, Clone)]
+pub struct RebuildSource {
+    pub uri: String,
+    pub reason: String,
+    pub candidates: Vec<SourceCandidate>,
+    /// Time the source was selected, in RFC 3339 format.
+    pub selected: String,
+}
+
+impl<'n> Nexus<'n> {
+    /// Select the source of the rebuild of the given child among the
+    /// healthy children, if any.
+    pub(super) fn select_rebuild_source(
+        &self,
+        dst_uri: &str,
+    ) -> Option<RebuildSource> {
+        let routing = self.read_routing.policy();
+        let mut candidates = self
+            .children_iter()
+            .filter(|c| c.state() == ChildState::Open && c.uri() != dst_uri)
+            .map(|c| {
+                let (reads_in_flight, read_latency_us) = c
+                    .get_device_name()
+                    .map_or((0, 0), |d| self.child_reads.get(&d));
+                SourceCandidate {
+                    uri: c.uri().to_owned(),
+                    distance: routing.distance(c.uri()),
+                    read_latency_us,
+                    reads_in_flight,
+                    rebuilds: RebuildJob::lookup_src(c.uri()).len() as u64,
+                    cost: 0,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let measured = candidates
+            .iter()
+            .filter(|c| c.read_latency_us > 0)
+            .map(|c| c.read_latency_us)
+            .collect::<Vec<_>>();
+        let unknown = match measured.len() as u64 {
+            0 => 1,
+            n => measured.iter().sum::<u64>() / n,
+        };
+        for c in candidates.iter_mut() {
+            let latency = match c.read_latency_us {
+                0 => unknown,
+                l => l,
+            };
+            c.cost = latency * (1 + c.reads_in_flight + c.rebuilds);
+        }
+
+        // the sort is stable: on a tie, the first child is selected
+        let mut ranked = candidates.iter().collect::<Vec<_>>();
+        ranked.sort_by_key(|c| (c.distance, c.cost));
+        let best = ranked.first()?;
+        let reason = match ranked.get(1) {
+            None => "only healthy child".to_string(),
+            Some(next) if next.distance > best.distance => format!(
+                "closest of {} healthy children, at distance {}",
+                ranked.len(),
+                best.distance
+            ),
+            Some(next) if next.cost > best.cost => format!(
+                "lowest read latency weighted by load of {} healthy \
+                children: {}us with {} reads in flight and {} rebuilds",
+                ranked.len(),
+                best.read_latency_us,
+                best.reads_in_flight,
+                best.rebuilds
+            ),
+            Some(_) => format!(
+                "first of {} equally ranked healthy children",
+                ranked.len()
+            ),
+        };
+
+        Some(RebuildSource {
+            uri: best.uri.clone(),
+            reason,
+            selected: chrono::Utc::now().to_rfc3339(),
+            candidates: candidates.clone(),
+        })
+    }
+}
+
+/// Arguments of the `nexus_rebuild_source` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct RebuildSourceArgs {
+    /// Name of the nexus.
+    name: String,
+    /// URI of the child being rebuilt.
+    uri: String,
+}
+
+/// Register the rebuild source json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nexus_rebuild_source", |args: RebuildSourceArgs| {
+        async move {
+            let nexus =
+                nexus_lookup(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+            let job = nexus.rebuild_job(&args.uri)?;
+            Ok(job.source_selection.clone())
+        }
+        .boxed_local()
+    });
+}
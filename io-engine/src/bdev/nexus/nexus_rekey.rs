@@ -0,0 +1,576 @@
+//! Online rotation of the keys of the encrypted children.
+//!
+//! The data encryption key of an encrypted child, see `bdev::crypto`, is
+//! rotated in place, on the replica of the child: the blocks of the replica
+//! are encrypted again under the new key, in the background, while the
+//! nexus keeps serving I/O. As the crypto bdev claims its base bdev, a
+//! replica is encrypted under a single key at a time: the child encrypted
+//! with the current key is taken out of the nexus, and the replica is added
+//! back as a child encrypted with the new key, over the same base bdev. The
+//! new child is rebuilt from the other healthy children, which rewrites the
+//! blocks of the replica, a segment at a time, under the new key, while the
+//! writes of the nexus are sent to it as well. The rotation needs no other
+//! replica, but the key of the only healthy child of a nexus cannot be
+//! rotated, as its blocks are rebuilt from the other children.
+//!
+//! The blocks are rewritten by the copies of a rebuild, and are throttled
+//! by the rebuild pacing, see the `rebuild_set_pacing` json-rpc method.
+//!
+//! The progress of the rotation is recorded in the metadata of the nexus
+//! every few seconds: the blocks rewritten so far, from the start of the
+//! data, along with the write generation of the volume. As the replica
+//! holds blocks encrypted under both keys until the rotation completes, the
+//! child encrypted with the current key is never used again once the
+//! rotation has started: should the io-engine crash, the child is taken out
+//! of the I/O path as soon as the nexus is opened again, and the rotation
+//! resumes, skipping the blocks recorded as rewritten, provided the volume
+//! has not been written to since without the new child, i.e. its write
+//! generation is unchanged, and the new child has not failed in the
+//! meantime. Cancelling a rotation leaves the replica out of the nexus, to
+//! be added back, and rebuilt in full, under either key. The URIs of the
+//! children only hold references to their keys, which are never recorded.
+
+use std::{collections::HashMap, pin::Pin, time::Duration};
+
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use super::{nexus_lookup_mut, ChildState, Error, Nexus, NexusState, Reason};
+use crate::{
+    bdev::crypto::base_uri,
+    core::{Reactors, VerboseError},
+    jsonrpc::jsonrpc_register,
+    sleep::mayastor_sleep,
+};
+
+/// Interval at which the progress of a rotation is recorded.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// URI scheme of the encrypted children.
+const CRYPTO_SCHEME: &str = "crypto";
+
+/// Rotations of the nexuses, by nexus name.
+static REKEYS: Lazy<Mutex<HashMap<String, RekeyStatus>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Rotation of the key of a child in progress, recorded in the metadata of
+/// the nexus.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RekeyMarker {
+    /// URI of the child encrypted with the current key.
+    pub from: String,
+    /// URI of the child encrypted with the new key, on the same replica.
+    pub to: String,
+    /// Number of blocks rewritten under the new key, from the start of the
+    /// data.
+    pub copied: u64,
+    /// Write generation of the volume when the progress was recorded.
+    pub generation: u64,
+    /// Time the rotation started, in RFC 3339 format.
+    pub started: String,
+}
+
+/// State of the rotation of the key of a child.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RekeyState {
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+/// Status of the last rotation of the key of a child of a nexus.
+#[derive(Serialize, Debug, Clone)]
+pub struct RekeyStatus {
+    pub nexus: String,
+    pub from: String,
+    pub to: String,
+    pub state: RekeyState,
+    /// Number of blocks rewritten under the new key, out of the blocks of
+    /// the nexus.
+    pub copied: u64,
+    pub total: u64,
+    /// Progress of the rotation, in percent.
+    pub progress: u32,
+    pub started: String,
+    pub error: Option<String>,
+}
+
+impl RekeyStatus {
+    fn new(nexus: &Nexus, marker: &RekeyMarker) -> Self {
+        Self {
+            nexus: nexus.name.clone(),
+            from: marker.from.clone(),
+            to: marker.to.clone(),
+            state: RekeyState::Running,
+            copied: marker.copied,
+            total: nexus.num_blocks(),
+            progress: 0,
+            started: marker.started.clone(),
+            error: None,
+        }
+    }
+
+    /// Update the status of the rotation of the given nexus.
+    fn update(nexus: &str, update: impl FnOnce(&mut RekeyStatus)) {
+        if let Some(status) = REKEYS.lock().get_mut(nexus) {
+            update(status);
+            status.progress = match status.state {
+                RekeyState::Completed => 100,
+                _ if status.total > 0 => {
+                    (status.copied * 100 / status.total) as u32
+                }
+                _ => 0,
+            };
+        }
+    }
+}
+
+/// Returns true if the given URI is that of an encrypted child.
+fn is_encrypted(uri: &str) -> bool {
+    url::Url::parse(uri).map_or(false, |u| u.scheme() == CRYPTO_SCHEME)
+}
+
+/// Forget the rotations of a nexus which is gone.
+pub(super) fn forget_rekey(nexus: &str) {
+    REKEYS.lock().remove(nexus);
+}
+
+impl<'n> Nexus<'n> {
+    /// Returns the status of the last rotation of the key of a child of the
+    /// nexus, if any.
+    pub fn rekey_status(&self) -> Option<RekeyStatus> {
+        REKEYS.lock().get(&self.name).cloned()
+    }
+
+    fn rekey_error(&self, child: &str, reason: impl Into<String>) -> Error {
+        Error::RekeyChild {
+            child: child.to_owned(),
+            name: self.name.clone(),
+            reason: reason.into(),
+        }
+    }
+
+    /// Rotate the key of the given encrypted child in place, by adding its
+    /// replica back as the child of the given URI, encrypted with the new
+    /// key over the same base bdev. The rotation carries on in the
+    /// background.
+    pub async fn rekey_child(
+        mut self: Pin<&mut Self>,
+        child_uri: &str,
+        new_uri: &str,
+    ) -> Result<RekeyStatus, Error> {
+        info!(
+            "{:?}: rotating the key of child '{}' with child '{}'",
+            self, child_uri, new_uri
+        );
+
+        if !is_encrypted(child_uri) {
+            return Err(
+                self.rekey_error(child_uri, "the child is not encrypted")
+            );
+        }
+        if !is_encrypted(new_uri) {
+            return Err(
+                self.rekey_error(child_uri, "the new child is not encrypted")
+            );
+        }
+        if base_uri(child_uri) != base_uri(new_uri) {
+            return Err(self.rekey_error(
+                child_uri,
+                "the new child must be on the replica of the child",
+            ));
+        }
+        match self.lookup_child(child_uri) {
+            Some(child) if child.state() == ChildState::Open => {}
+            Some(child) => {
+                return Err(self.rekey_error(
+                    child_uri,
+                    format!("the child is {}", child.state()),
+                ))
+            }
+            None => {
+                return Err(Error::ChildNotFound {
+                    child: child_uri.to_owned(),
+                    name: self.name.clone(),
+                })
+            }
+        }
+        if self.lookup_child(new_uri).is_some() {
+            return Err(Error::ChildAlreadyExists {
+                child: new_uri.to_owned(),
+                name: self.name.clone(),
+            });
+        }
+        if !self
+            .children_iter()
+            .any(|c| c.uri() != child_uri && c.state() == ChildState::Open)
+        {
+            return Err(self.rekey_error(
+                child_uri,
+                "no other healthy child to rebuild the replica from",
+            ));
+        }
+        if self.metadata().rekey.is_some() {
+            return Err(self.rekey_error(
+                child_uri,
+                "the key of a child is being rotated already",
+            ));
+        }
+
+        let marker = RekeyMarker {
+            from: child_uri.to_owned(),
+            to: new_uri.to_owned(),
+            copied: 0,
+            generation: self.metadata().write_generation,
+            started: chrono::Utc::now().to_rfc3339(),
+        };
+        self.update_metadata(|m| m.rekey = Some(marker.clone()))
+            .await?;
+        REKEYS
+            .lock()
+            .insert(self.name.clone(), RekeyStatus::new(&self, &marker));
+
+        if let Err(error) = self.as_mut().rekey_swap(&marker).await {
+            self.rekey_fail(error.verbose()).await;
+            return Err(error);
+        }
+
+        Reactors::master().send_future(Nexus::rekey_routine(self.name.clone()));
+        Ok(self.rekey_status().unwrap())
+    }
+
+    /// Cancel the rotation of the key of a child, removing the new child. Its
+    /// replica holds blocks encrypted under both keys, and is left out of
+    /// the nexus.
+    pub async fn rekey_cancel(mut self: Pin<&mut Self>) -> Result<(), Error> {
+        let marker = match self.metadata().rekey {
+            Some(marker) => marker,
+            None => {
+                return Err(Error::InvalidArguments {
+                    name: self.name.clone(),
+                    args: "the key of no child is being rotated".to_string(),
+                })
+            }
+        };
+        info!(
+            "{:?}: cancelling the rotation of the key of child '{}'",
+            self, marker.from
+        );
+
+        self.update_metadata(|m| m.rekey = None).await?;
+        RekeyStatus::update(&self.name, |s| s.state = RekeyState::Cancelled);
+        if self.lookup_child(&marker.to).is_some() {
+            self.as_mut().remove_child(&marker.to).await?;
+        }
+        Ok(())
+    }
+
+    /// Resume the rotation recorded in the metadata of the nexus, as it is
+    /// opened. The children of the replica are taken out of the I/O path
+    /// right away, as it holds blocks encrypted under both keys.
+    pub(super) fn rekey_resume(&self) {
+        if let Some(marker) = self.metadata().rekey {
+            info!(
+                "{:?}: resuming the rotation of the key of child '{}', {} \
+                blocks rewritten",
+                self, marker.from, marker.copied
+            );
+            for uri in &[&marker.from, &marker.to] {
+                if let Some(child) = self.lookup_child(uri) {
+                    if child.state() == ChildState::Open {
+                        child.transition(
+                            ChildState::Faulted(Reason::OutOfSync),
+                            "the key of the child is being rotated",
+                        );
+                    }
+                }
+            }
+            REKEYS
+                .lock()
+                .insert(self.name.clone(), RekeyStatus::new(self, &marker));
+            let name = self.name.clone();
+            Reactors::master().send_future(async move {
+                if Nexus::rekey_restart(&name).await {
+                    Nexus::rekey_routine(name).await;
+                }
+            });
+        }
+    }
+
+    /// Returns the number of blocks the rebuild of the given child skips,
+    /// those recorded as copied by the rotation being resumed, provided the
+    /// volume has not been written to since.
+    pub(super) fn rekey_resume_blocks(&self, child_uri: &str) -> u64 {
+        match self.metadata().rekey {
+            Some(marker)
+                if marker.to == child_uri
+                    && marker.generation
+                        == self.metadata().write_generation =>
+            {
+                marker.copied
+            }
+            _ => 0,
+        }
+    }
+
+    /// Forget the progress of the rotation when its new child has failed,
+    /// as the writes it missed may fall within the blocks already copied.
+    /// The new child is yet to be added, or rebuilt, when the rotation is
+    /// resumed.
+    pub(super) async fn rekey_check_child(&self) {
+        let marker = match self.metadata().rekey {
+            Some(marker) if marker.copied > 0 => marker,
+            _ => return,
+        };
+        let failed = self.lookup_child(&marker.to).map_or(false, |c| {
+            !matches!(
+                c.state(),
+                ChildState::Open | ChildState::Faulted(Reason::OutOfSync)
+            )
+        });
+        if !failed {
+            return;
+        }
+        let updated = self
+            .update_metadata(|m| {
+                if let Some(marker) = &mut m.rekey {
+                    marker.copied = 0;
+                }
+            })
+            .await;
+        if let Err(error) = updated {
+            warn!(
+                "{:?}: failed to reset the progress of the rotation: {}",
+                self, error
+            );
+        }
+    }
+
+    /// Take the child encrypted with the current key out of the nexus, and
+    /// add the new child of the rotation, or rebuild it.
+    async fn rekey_swap(
+        mut self: Pin<&mut Self>,
+        marker: &RekeyMarker,
+    ) -> Result<(), Error> {
+        // the crypto bdev of the current key releases the replica for the
+        // new one to claim it
+        if self.lookup_child(&marker.from).is_some() {
+            self.as_mut().remove_child(&marker.from).await?;
+        }
+
+        match self.lookup_child(&marker.to).map(|c| c.state()) {
+            None => {
+                self.as_mut().add_child(&marker.to, false).await.map(|_| ())
+            }
+            Some(ChildState::Faulted(Reason::OutOfSync)) => {
+                self.as_mut().start_rebuild(&marker.to).await.map(|_| ())
+            }
+            Some(state) => Err(self.rekey_error(
+                &marker.from,
+                format!("the new child is {}", state),
+            )),
+        }
+    }
+
+    /// Restart the rotation being resumed. Returns false if it cannot be
+    /// resumed.
+    async fn rekey_restart(name: &str) -> bool {
+        // the rotation resumes once the nexus is open
+        loop {
+            match nexus_lookup_mut(name) {
+                Some(nexus) if *nexus.state.lock() == NexusState::Open => break,
+                Some(nexus) if *nexus.state.lock() == NexusState::Init => {}
+                _ => return false,
+            }
+            mayastor_sleep(Duration::from_millis(100)).await.ok();
+        }
+        let mut nexus = match nexus_lookup_mut(name) {
+            Some(nexus) => nexus,
+            None => return false,
+        };
+        let marker = match nexus.metadata().rekey {
+            Some(marker) => marker,
+            None => return false,
+        };
+
+        match nexus.as_mut().rekey_swap(&marker).await {
+            Ok(()) => true,
+            Err(error) => {
+                nexus.rekey_fail(error.verbose()).await;
+                false
+            }
+        }
+    }
+
+    /// Record the progress of the rotation until the new child is rebuilt.
+    async fn rekey_routine(name: String) {
+        loop {
+            mayastor_sleep(CHECKPOINT_INTERVAL).await.ok();
+
+            // a nexus which is gone resumes the rotation when opened again
+            let nexus = match nexus_lookup_mut(&name) {
+                Some(nexus) => nexus,
+                None => return,
+            };
+            if *nexus.state.lock() != NexusState::Open {
+                return;
+            }
+            let marker = match nexus.metadata().rekey {
+                Some(marker) => marker,
+                None => return,
+            };
+
+            let child = match nexus.lookup_child(&marker.to) {
+                Some(child) => child,
+                None => {
+                    nexus.rekey_fail("the new child has been removed").await;
+                    return;
+                }
+            };
+            if child.state() == ChildState::Open {
+                nexus.rekey_complete(&marker).await;
+                return;
+            }
+            let copied = match child.rebuild_job() {
+                Some(job) if child.rebuilding() => job.checkpoint(),
+                _ => {
+                    let reason = format!("the new child is {}", child.state());
+                    nexus.rekey_fail(reason).await;
+                    return;
+                }
+            };
+
+            RekeyStatus::update(&name, |s| s.copied = copied);
+            let generation = nexus.metadata().write_generation;
+            if copied > marker.copied || generation != marker.generation {
+                let updated = nexus
+                    .update_metadata(|m| {
+                        if let Some(marker) = &mut m.rekey {
+                            marker.copied = copied;
+                            marker.generation = m.write_generation;
+                        }
+                    })
+                    .await;
+                if let Err(error) = updated {
+                    warn!(
+                        "{:?}: failed to record the progress of the \
+                        rotation: {}",
+                        nexus, error
+                    );
+                }
+            }
+        }
+    }
+
+    /// Complete the rotation, once the new child is rebuilt.
+    async fn rekey_complete(&self, marker: &RekeyMarker) {
+        if let Err(error) = self.update_metadata(|m| m.rekey = None).await {
+            warn!(
+                "{:?}: failed to record the completion of the rotation: {}",
+                self, error
+            );
+        }
+        info!(
+            "{:?}: key of child '{}' rotated, now child '{}'",
+            self, marker.from, marker.to
+        );
+        RekeyStatus::update(&self.name, |s| {
+            s.state = RekeyState::Completed;
+            s.copied = s.total;
+        });
+    }
+
+    /// Fail the rotation, the new child is left as it is, and the child
+    /// encrypted with the current key out of the nexus.
+    async fn rekey_fail(&self, reason: impl Into<String>) {
+        let reason = reason.into();
+        error!(
+            "{:?}: failed to rotate the key of a child: {}",
+            self, reason
+        );
+        if let Err(error) = self.update_metadata(|m| m.rekey = None).await {
+            warn!("{:?}: failed to clear the rotation: {}", self, error);
+        }
+        RekeyStatus::update(&self.name, |s| {
+            s.state = RekeyState::Failed;
+            s.error = Some(reason);
+        });
+    }
+}
+
+/// Arguments of the `nexus_rekey_child` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct RekeyChildArgs {
+    /// Name of the nexus.
+    name: String,
+    /// URI of the child whose key is rotated.
+    child: String,
+    /// URI of the child replacing it, encrypted with the new key over the
+    /// same base bdev.
+    uri: String,
+}
+
+/// Arguments of the `nexus_rekey_status` and `nexus_rekey_cancel` json-rpc
+/// methods.
+#[derive(Debug, Deserialize)]
+struct RekeyArgs {
+    /// Name of the nexus.
+    name: String,
+}
+
+/// Returns the status of the rotation of the given nexus, with the progress
+/// of the rebuild of its new child.
+fn current_status(nexus: &Nexus) -> Option<RekeyStatus> {
+    if let Some(status) = nexus.rekey_status() {
+        if status.state == RekeyState::Running {
+            if let Some(job) =
+                nexus.lookup_child(&status.to).and_then(|c| c.rebuild_job())
+            {
+                let copied = job.checkpoint();
+                RekeyStatus::update(&nexus.name, |s| s.copied = copied);
+            }
+        }
+    }
+    nexus.rekey_status()
+}
+
+/// Register the key rotation json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nexus_rekey_child", |args: RekeyChildArgs| {
+        async move {
+            let nexus =
+                nexus_lookup_mut(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+            nexus.rekey_child(&args.child, &args.uri).await
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_rekey_status", |args: RekeyArgs| {
+        async move {
+            nexus_lookup_mut(&args.name)
+                .map(|n| current_status(&n))
+                .ok_or(Error::NexusNotFound {
+                    name: args.name,
+                })
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_rekey_cancel", |args: RekeyArgs| {
+        async move {
+            let mut nexus =
+                nexus_lookup_mut(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+            nexus.as_mut().rekey_cancel().await?;
+            Ok(current_status(&nexus))
+        }
+        .boxed_local()
+    });
+}
@@ -0,0 +1,321 @@
+//! Child retire policy.
+//!
+//! By default, a child is retired from the nexus on the first I/O error it
+//! reports, which requires a full rebuild once it is brought back. The retire
+//! policy of a nexus allows to tolerate transient errors instead: a child is
+//! only retired once the number of errors within a time window reaches a
+//! threshold, and the I/Os failing in the meantime can be retried. The last
+//! healthy child of the nexus can also be protected from being retired, in
+//! which case the I/O is failed back to the initiator. A child losing its
+//! transport can be given a grace period to come back before the policy
+//! applies, see `nexus_child_grace`.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crossbeam::atomic::AtomicCell;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+
+use super::{nexus_lookup, Error, Nexus, Reason};
+use crate::jsonrpc::jsonrpc_register;
+
+/// Retire policy of the children of a nexus.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RetirePolicy {
+    /// Number of I/O errors of a child, within the error window, at which
+    /// the child is retired. At most `max_retries + 1`, so that an I/O
+    /// failing on every attempt retires the child.
+    pub error_threshold: u32,
+    /// Window over which the I/O errors of a child are counted, in
+    /// milliseconds.
+    pub error_window_ms: u64,
+    /// Number of times an I/O failing on a child which is not retired is
+    /// resubmitted, before it is failed.
+    pub max_retries: u8,
+    /// Whether a child running out of space is retired.
+    pub retire_on_no_space: bool,
+    /// Never retire the last healthy child of the nexus.
+    pub keep_last_healthy: bool,
+    /// Time a child failing on transport errors is suspended for, waiting
+    /// for its transport to come back before it is retired, in
+    /// milliseconds. Zero disables the grace period.
+    #[serde(default)]
+    pub transport_grace_ms: u64,
+}
+
+impl Default for RetirePolicy {
+    /// The default policy retires a child on its first I/O error.
+    fn default() -> Self {
+        Self {
+            error_threshold: 1,
+            error_window_ms: 60_000,
+            max_retries: 0,
+            retire_on_no_space: true,
+            keep_last_healthy: false,
+            transport_grace_ms: 0,
+        }
+    }
+}
+
+impl RetirePolicy {
+    fn error_window(&self) -> Duration {
+        Duration::from_millis(self.error_window_ms)
+    }
+}
+
+/// Action to take on a failed child I/O.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum RetireAction {
+    /// Retire the child.
+    Retire,
+    /// Keep the child and resubmit the I/O.
+    Retry,
+    /// Keep the child and fail the I/O.
+    Fail,
+    /// The child is suspended for its grace period, resubmit the I/O.
+    Suspend,
+}
+
+/// Retire policy of a nexus, along with the I/O errors of its children.
+/// Errors are reported from all cores, so they are kept behind a lock.
+pub(crate) struct ChildRetirePolicy {
+    policy: AtomicCell<RetirePolicy>,
+    errors: parking_lot::Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl ChildRetirePolicy {
+    pub(crate) fn new() -> Self {
+        Self {
+            policy: AtomicCell::new(RetirePolicy::default()),
+            errors: parking_lot::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the current policy.
+    pub(crate) fn get(&self) -> RetirePolicy {
+        self.policy.load()
+    }
+
+    /// Replace the policy. The errors counted so far are kept.
+    pub(crate) fn set(&self, policy: RetirePolicy) {
+        self.policy.store(policy);
+    }
+
+    /// Record an I/O error of the given child device and returns the number
+    /// of errors within the error window.
+    fn record_error(&self, device: &str, window: Duration) -> u32 {
+        let now = Instant::now();
+        let mut errors = self.errors.lock();
+        let events = errors.entry(device.to_string()).or_default();
+        events.push_back(now);
+        while matches!(events.front(), Some(t) if now.duration_since(*t) > window)
+        {
+            events.pop_front();
+        }
+        events.len() as u32
+    }
+
+    /// Forget the errors of the given child device.
+    pub(crate) fn reset_errors(&self, device: &str) {
+        self.errors.lock().remove(device);
+    }
+
+    /// Returns the number of errors within the error window, per child
+    /// device.
+    pub(crate) fn error_counts(&self) -> HashMap<String, u32> {
+        let window = self.get().error_window();
+        let now = Instant::now();
+        self.errors
+            .lock()
+            .iter()
+            .map(|(device, events)| {
+                let count = events
+                    .iter()
+                    .filter(|t| now.duration_since(**t) <= window)
+                    .count();
+                (device.clone(), count as u32)
+            })
+            .collect()
+    }
+
+    /// Decide what to do with an I/O which failed on the given child device,
+    /// after having been retried the given number of times.
+    fn evaluate(
+        &self,
+        device: &str,
+        reason: Reason,
+        last_healthy: bool,
+        retries: u8,
+    ) -> RetireAction {
+        let policy = self.get();
+
+        let retire = match reason {
+            Reason::NoSpace if !policy.retire_on_no_space => false,
+            _ if last_healthy && policy.keep_last_healthy => false,
+            _ => {
+                self.record_error(device, policy.error_window())
+                    >= policy.error_threshold
+            }
+        };
+
+        if retire {
+            self.reset_errors(device);
+            RetireAction::Retire
+        } else if retries < policy.max_retries {
+            RetireAction::Retry
+        } else {
+            RetireAction::Fail
+        }
+    }
+}
+
+impl<'n> Nexus<'n> {
+    /// Returns the retire policy of the children of the nexus.
+    pub fn retire_policy(&self) -> RetirePolicy {
+        self.retire_policy.get()
+    }
+
+    /// Replace the retire policy of the children of the nexus. The errors
+    /// counted so far are kept.
+    pub fn set_retire_policy(&self, policy: RetirePolicy) -> Result<(), Error> {
+        if policy.error_threshold == 0 {
+            return Err(Error::InvalidArguments {
+                name: self.name.clone(),
+                args: "error threshold must be at least 1".to_string(),
+            });
+        }
+        // an I/O failing on every attempt must have retired the child by its
+        // last attempt, or the child is left out of sync with the others
+        if policy.error_threshold > policy.max_retries as u32 + 1 {
+            return Err(Error::InvalidArguments {
+                name: self.name.clone(),
+                args: format!(
+                    "error threshold {} exceeds the {} attempts of an I/O",
+                    policy.error_threshold,
+                    policy.max_retries as u32 + 1
+                ),
+            });
+        }
+
+        info!("{:?}: setting retire policy: {:?}", self, policy);
+        self.retire_policy.set(policy);
+        Ok(())
+    }
+
+    /// Apply the retire policy of the nexus to an I/O which failed on the
+    /// given child device.
+    pub(super) fn child_io_error_action(
+        &self,
+        device: &str,
+        reason: Reason,
+        retries: u8,
+    ) -> RetireAction {
+        self.retire_policy.evaluate(
+            device,
+            reason,
+            self.is_last_healthy_child_device(device),
+            retries,
+        )
+    }
+
+    /// Checks if the given child device is the only healthy child left.
+    pub(super) fn is_last_healthy_child_device(&self, device: &str) -> bool {
+        self.children_iter()
+            .filter(|c| c.is_healthy())
+            .all(|c| c.match_device_name(device))
+    }
+}
+
+/// Arguments of the `nexus_set_retire_policy` json-rpc method, the fields
+/// which are not given are left unchanged.
+#[derive(Debug, Deserialize)]
+struct SetRetirePolicyArgs {
+    /// Name of the nexus.
+    name: String,
+    error_threshold: Option<u32>,
+    error_window_ms: Option<u64>,
+    max_retries: Option<u8>,
+    retire_on_no_space: Option<bool>,
+    keep_last_healthy: Option<bool>,
+    transport_grace_ms: Option<u64>,
+}
+
+/// Arguments of the `nexus_get_retire_policy` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct GetRetirePolicyArgs {
+    /// Name of the nexus.
+    name: String,
+}
+
+/// Reply of the retire policy json-rpc methods.
+#[derive(Debug, Serialize)]
+struct RetirePolicyReply {
+    name: String,
+    policy: RetirePolicy,
+    /// Number of errors within the error window, per child device.
+    errors: HashMap<String, u32>,
+    /// Time the suspended child devices have been suspended for, in
+    /// milliseconds.
+    suspended: HashMap<String, u64>,
+}
+
+impl RetirePolicyReply {
+    fn new(nexus: &Nexus) -> Self {
+        Self {
+            name: nexus.name.clone(),
+            policy: nexus.retire_policy.get(),
+            errors: nexus.retire_policy.error_counts(),
+            suspended: nexus.child_grace.suspended(),
+        }
+    }
+}
+
+/// Register the retire policy json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nexus_set_retire_policy", |args: SetRetirePolicyArgs| {
+        async move {
+            let nexus =
+                nexus_lookup(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+
+            let current = nexus.retire_policy.get();
+            let policy = RetirePolicy {
+                error_threshold: args
+                    .error_threshold
+                    .unwrap_or(current.error_threshold),
+                error_window_ms: args
+                    .error_window_ms
+                    .unwrap_or(current.error_window_ms),
+                max_retries: args.max_retries.unwrap_or(current.max_retries),
+                retire_on_no_space: args
+                    .retire_on_no_space
+                    .unwrap_or(current.retire_on_no_space),
+                keep_last_healthy: args
+                    .keep_last_healthy
+                    .unwrap_or(current.keep_last_healthy),
+                transport_grace_ms: args
+                    .transport_grace_ms
+                    .unwrap_or(current.transport_grace_ms),
+            };
+
+            nexus.set_retire_policy(policy)?;
+            Ok(RetirePolicyReply::new(nexus))
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_get_retire_policy", |args: GetRetirePolicyArgs| {
+        async move {
+            nexus_lookup(&args.name).map(RetirePolicyReply::new).ok_or(
+                Error::NexusNotFound {
+                    name: args.name,
+                },
+            )
+        }
+        .boxed_local()
+    });
+}
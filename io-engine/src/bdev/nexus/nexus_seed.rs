@@ -0,0 +1,260 @@
+//! Addition of children with pre-seeded data.
+//!
+//! A replica restored from the same snapshot as the other children of a
+//! nexus already holds their data, and rebuilding it in full only slows
+//! down replica moves. Such a child is added as seeded: it receives the
+//! writes of the nexus from the moment it is added, like a child being
+//! rebuilt, and is put online without copying any data.
+//!
+//! Before that, samples of its data are optionally compared with the data of
+//! a healthy child, each sample being locked on the nexus so that no write
+//! races with the comparison. If any sample differs, or cannot be read, the
+//! child is rebuilt in full instead.
+
+use std::pin::Pin;
+
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+
+use spdk_rs::{DmaBuf, LbaRange};
+
+use super::{
+    nexus_lookup_mut,
+    ChildState,
+    DrEvent,
+    Error,
+    Nexus,
+    NexusStatus,
+    PersistOp,
+};
+use crate::{
+    core::{Bdev, BlockDeviceHandle, CoreError, VerboseError},
+    jsonrpc::jsonrpc_register,
+};
+
+/// Verification of the data of a seeded child.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SeedVerify {
+    /// Number of samples compared, spread evenly over the nexus.
+    pub samples: u32,
+    /// Size of a sample in blocks.
+    pub sample_blocks: u64,
+}
+
+impl Default for SeedVerify {
+    fn default() -> Self {
+        Self {
+            samples: 64,
+            sample_blocks: 256,
+        }
+    }
+}
+
+/// Outcome of the addition of a seeded child.
+#[derive(Debug, Clone, Serialize)]
+pub struct SeedOutcome {
+    /// Number of samples which matched the data of the nexus.
+    pub verified_samples: u32,
+    /// The child failed verification and is rebuilt in full.
+    pub rebuilding: bool,
+}
+
+fn dma_buf(
+    hdl: &dyn BlockDeviceHandle,
+    size: u64,
+) -> Result<DmaBuf, CoreError> {
+    hdl.dma_malloc(size)
+        .map_err(|_| CoreError::DmaAllocationFailed {
+            size,
+        })
+}
+
+impl<'n> Nexus<'n> {
+    /// Add a child known to hold the same data as the nexus, without
+    /// rebuilding it, once samples of its data have optionally been
+    /// verified. The child is rebuilt in full if the verification fails.
+    pub async fn add_seeded_child(
+        mut self: Pin<&mut Self>,
+        uri: &str,
+        verify: Option<SeedVerify>,
+    ) -> Result<SeedOutcome, Error> {
+        if !self.children_iter().any(|c| c.is_healthy()) {
+            return Err(Error::NoRebuildSource {
+                name: self.name.clone(),
+            });
+        }
+
+        self.as_mut().add_child_only(uri).await?;
+        info!("{:?}: added seeded child '{}'", self, uri);
+
+        // the child must not miss any write from now on
+        self.as_mut().child_mut(uri)?.set_seeding(true);
+        self.reconfigure(DrEvent::ChildRebuild).await;
+
+        let verified = match verify {
+            Some(verify) => self.verify_seeded_child(uri, verify).await,
+            None => Ok(0),
+        };
+
+        self.as_mut().child_mut(uri)?.set_seeding(false);
+
+        let verified_samples = match verified {
+            Ok(samples) => samples,
+            Err(error) => {
+                warn!(
+                    "{:?}: seeded child '{}' failed verification, \
+                    rebuilding it: {}",
+                    self, uri, error
+                );
+                self.reconfigure(DrEvent::ChildRebuild).await;
+                if let Err(e) = self.as_mut().start_rebuild(uri).await {
+                    error!(
+                        "Seeded child added but rebuild failed to start: {}",
+                        e.verbose()
+                    );
+                }
+                return Ok(SeedOutcome {
+                    verified_samples: 0,
+                    rebuilding: true,
+                });
+            }
+        };
+
+        let child = self.as_mut().child_mut(uri)?;
+        child.transition(ChildState::Open, "seed verified");
+        let child_state = child.state();
+        info!(
+            "{:?}: seeded child '{}' is online, {} samples verified",
+            self, uri, verified_samples
+        );
+        self.persist(PersistOp::Update {
+            child_uri: uri.to_owned(),
+            child_state,
+        })
+        .await;
+        self.reconfigure(DrEvent::ChildRebuild).await;
+
+        Ok(SeedOutcome {
+            verified_samples,
+            rebuilding: false,
+        })
+    }
+
+    /// Compare samples of the data of a seeded child with the data of a
+    /// healthy child, returning the number of samples compared.
+    async fn verify_seeded_child(
+        &self,
+        uri: &str,
+        verify: SeedVerify,
+    ) -> Result<u32, String> {
+        let num_blocks = self.num_blocks();
+        let sample_blocks = verify.sample_blocks.clamp(1, num_blocks);
+        let samples = verify.samples.max(1) as u64;
+        // the first and the last samples are at the start and the end of
+        // the nexus
+        let stride = (num_blocks - sample_blocks) / (samples - 1).max(1);
+
+        let descriptor = Bdev::<Nexus>::open_by_name(&self.name, false)
+            .map_err(|e| e.to_string())?;
+
+        for i in 0 .. samples {
+            let offset = (i * stride).min(num_blocks - sample_blocks);
+            let range = LbaRange::new(offset, sample_blocks);
+            let lock = descriptor
+                .lock_lba_range(range)
+                .await
+                .map_err(|e| e.to_string())?;
+
+            let result = self.compare_sample(uri, offset, sample_blocks).await;
+
+            descriptor
+                .unlock_lba_range(lock)
+                .await
+                .map_err(|e| e.to_string())?;
+
+            match result {
+                Ok(true) => {}
+                Ok(false) => {
+                    return Err(format!(
+                        "data differs in the {} blocks at {}",
+                        sample_blocks, offset
+                    ))
+                }
+                Err(error) => return Err(error.to_string()),
+            }
+        }
+
+        Ok(samples as u32)
+    }
+
+    /// Returns true if the given blocks of the seeded child match the blocks
+    /// of a healthy child.
+    async fn compare_sample(
+        &self,
+        uri: &str,
+        offset: u64,
+        num_blocks: u64,
+    ) -> Result<bool, CoreError> {
+        let block_len = self.block_len();
+        let byte_offset = (offset + self.data_ent_offset) * block_len;
+        let size = num_blocks * block_len;
+
+        let source = self
+            .children_iter()
+            .find(|c| c.is_healthy())
+            .and_then(|c| c.get_io_handle().ok())
+            .ok_or(CoreError::NoDevicesAvailable {})?;
+        let seeded = self
+            .children_iter()
+            .find(|c| c.uri() == uri)
+            .and_then(|c| c.get_io_handle().ok())
+            .ok_or(CoreError::NoDevicesAvailable {})?;
+
+        let mut expected = dma_buf(&*source, size)?;
+        source.read_at(byte_offset, &mut expected).await?;
+        let mut actual = dma_buf(&*seeded, size)?;
+        seeded.read_at(byte_offset, &mut actual).await?;
+
+        Ok(expected.as_slice() == actual.as_slice())
+    }
+}
+
+/// Arguments of the `nexus_add_seeded_child` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct AddSeededChildArgs {
+    /// Name of the nexus.
+    name: String,
+    /// URI of the child.
+    uri: String,
+    /// Verification of the data of the child, none if not given.
+    verify: Option<SeedVerify>,
+}
+
+/// Reply of the `nexus_add_seeded_child` json-rpc method.
+#[derive(Debug, Serialize)]
+struct AddSeededChildReply {
+    #[serde(flatten)]
+    outcome: SeedOutcome,
+    status: NexusStatus,
+}
+
+/// Register the seeded child json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nexus_add_seeded_child", |args: AddSeededChildArgs| {
+        async move {
+            let mut nexus =
+                nexus_lookup_mut(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+            let outcome = nexus
+                .as_mut()
+                .add_seeded_child(&args.uri, args.verify)
+                .await?;
+            Ok(AddSeededChildReply {
+                outcome,
+                status: nexus.status(),
+            })
+        }
+        .boxed_local()
+    });
+}
@@ -0,0 +1,648 @@
+//! Sharding of the connections to the remote children.
+//!
+//! Each channel of a nexus opens an I/O handle to each of its children, which
+//! for a remote child is a qpair of the NVMe-oF connection to its target, so
+//! that a node with many cores and many volumes ends up with as many qpairs
+//! as cores times volumes times replicas. When sharding is enabled, only a
+//! subset of the cores, the shard cores of the nexus, connect to its remote
+//! children. The channels of the other cores hold handles which forward the
+//! I/Os of the remote children to a shard core, where they are submitted on a
+//! handle of the shard core, and whose completions are sent back to the core
+//! the I/Os were submitted on. Local children are not sharded, as their
+//! handles cost no connection.
+//!
+//! The number of shard cores of a nexus is set with the `--child-shard-cores`
+//! option. The shard cores of each nexus are picked from its name, so that
+//! the nexuses of the node are spread over all the cores.
+//!
+//! The handles of a shard core are created on the first I/O forwarded to it,
+//! and dropped whenever the children of the nexus change.
+//!
+//! When the nexus is migrated off a core, the core is replaced by another one
+//! among the shard cores, or, without sharding, all the other cores become
+//! shard cores, so that the core no longer connects to the remote children.
+
+use std::{
+    cell::RefCell,
+    collections::{hash_map::DefaultHasher, HashMap},
+    future::Future,
+    hash::{Hash, Hasher},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use async_trait::async_trait;
+use futures::{channel::oneshot, FutureExt};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use spdk_rs::{libspdk::spdk_nvme_cmd, DmaBuf, DmaError, IoVec};
+
+use super::{nexus_lookup, Error, Nexus, NexusChild};
+use crate::{
+    bdev::device_lookup,
+    core::{
+        BlockDevice,
+        BlockDeviceHandle,
+        CoreError,
+        Cores,
+        IoCompletionCallback,
+        IoCompletionCallbackArg,
+        IoCompletionStatus,
+        IoSubmissionFailure,
+        MayastorEnvironment,
+        Reactor,
+        Reactors,
+        ReadMode,
+    },
+    jsonrpc::jsonrpc_register,
+};
+
+/// Nexus and device of a child handle of a shard core.
+type ShardKey = (String, String);
+
+thread_local! {
+    /// Handles of the remote children on this core, for the I/Os forwarded
+    /// by the other cores.
+    static SHARD_HANDLES: RefCell<HashMap<ShardKey, Rc<Box<dyn BlockDeviceHandle>>>> =
+        RefCell::new(HashMap::new());
+}
+
+/// Shard cores of a nexus.
+pub(crate) struct ChildShards {
+    /// Cores connecting to the remote children, all of them if empty.
+    cores: Mutex<Vec<u32>>,
+    /// Cores the nexus has been migrated off.
+    excluded: Mutex<Vec<u32>>,
+    /// Number of I/Os forwarded to the shard cores.
+    forwarded: Arc<AtomicU64>,
+}
+
+impl ChildShards {
+    pub(crate) fn new(nexus_name: &str) -> Self {
+        let count = MayastorEnvironment::global_or_default()
+            .child_shard_cores()
+            .unwrap_or_default() as usize;
+        let all = Reactors::iter().map(|r| r.core()).collect::<Vec<_>>();
+
+        let cores = if count == 0 || count >= all.len() {
+            Vec::new()
+        } else {
+            let mut hasher = DefaultHasher::new();
+            nexus_name.hash(&mut hasher);
+            let first = hasher.finish() as usize % all.len();
+            (0 .. count).map(|i| all[(first + i) % all.len()]).collect()
+        };
+
+        Self {
+            cores: Mutex::new(cores),
+            excluded: Mutex::new(Vec::new()),
+            forwarded: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Returns the cores connecting to the remote children, all of them if
+    /// empty.
+    pub(super) fn cores(&self) -> Vec<u32> {
+        self.cores.lock().clone()
+    }
+
+    /// Stop connecting to the remote children from the given core. Returns
+    /// false if the core was not connecting to them.
+    pub(super) fn exclude(&self, core: u32) -> bool {
+        let mut excluded = self.excluded.lock();
+        if !excluded.contains(&core) {
+            excluded.push(core);
+        }
+        let available = Reactors::iter()
+            .map(|r| r.core())
+            .filter(|c| !excluded.contains(c))
+            .collect::<Vec<_>>();
+
+        let mut cores = self.cores.lock();
+        if cores.is_empty() {
+            *cores = available;
+            return true;
+        }
+        match cores.iter().position(|c| *c == core) {
+            Some(i) => {
+                match available.iter().find(|c| !cores.contains(c)) {
+                    Some(replacement) => cores[i] = *replacement,
+                    None => {
+                        cores.remove(i);
+                    }
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the shard core the given core forwards the I/Os of the remote
+    /// children of the nexus to, unless it connects to them itself.
+    pub(super) fn forward_target(
+        &self,
+        nexus_name: &str,
+        core: u32,
+    ) -> Option<ShardTarget> {
+        let cores = self.cores.lock();
+        if cores.is_empty() || cores.contains(&core) {
+            return None;
+        }
+        Some(ShardTarget {
+            nexus: nexus_name.to_string(),
+            core: cores[core as usize % cores.len()],
+            forwarded: Arc::clone(&self.forwarded),
+        })
+    }
+}
+
+/// Shard core the I/Os of the remote children of a channel are forwarded to.
+pub(super) struct ShardTarget {
+    nexus: String,
+    core: u32,
+    forwarded: Arc<AtomicU64>,
+}
+
+/// Returns the I/O handle of a child for a channel of the nexus, forwarding
+/// the I/Os to the shard core, if any, when the child is remote.
+pub(super) fn channel_io_handle(
+    target: Option<&ShardTarget>,
+    child: &NexusChild,
+    block_len: u64,
+) -> Result<Box<dyn BlockDeviceHandle>, CoreError> {
+    match target {
+        Some(target) if child.is_local() == Some(false) => {
+            let name = child.get_device_name().unwrap_or_default();
+            let device = device_lookup(&name).ok_or_else(|| {
+                CoreError::BdevNotFound {
+                    name: name.clone(),
+                }
+            })?;
+            Ok(Box::new(ForwardHandle {
+                key: (target.nexus.clone(), name),
+                device: Rc::new(device),
+                block_len,
+                core: target.core,
+                forwarded: Arc::clone(&target.forwarded),
+            }))
+        }
+        _ => child.get_nexus_io_handle(block_len),
+    }
+}
+
+/// Returns the handle of the given child on the current shard core.
+fn shard_handle(
+    key: &ShardKey,
+    block_len: u64,
+) -> Result<Rc<Box<dyn BlockDeviceHandle>>, CoreError> {
+    SHARD_HANDLES.with(|handles| {
+        if let Some(hdl) = handles.borrow().get(key) {
+            return Ok(Rc::clone(hdl));
+        }
+        let hdl = nexus_lookup(&key.0)
+            .and_then(|n| n.lookup_child_device(&key.1))
+            .ok_or_else(|| CoreError::BdevNotFound {
+                name: key.1.clone(),
+            })?
+            .get_nexus_io_handle(block_len)?;
+        let hdl = Rc::new(hdl);
+        handles.borrow_mut().insert(key.clone(), Rc::clone(&hdl));
+        Ok(hdl)
+    })
+}
+
+/// Returns the reactor of the given core.
+fn reactor(core: u32) -> &'static Reactor {
+    Reactors::get_by_core(core).expect("no reactor on the core")
+}
+
+/// An I/O forwarded to a shard core.
+struct ForwardedIo {
+    /// Device of the child, only used on the submitting core.
+    device: Rc<Box<dyn BlockDevice>>,
+    /// Core the I/O was submitted on.
+    origin: u32,
+    cb: IoCompletionCallback,
+    cb_arg: IoCompletionCallbackArg,
+}
+
+impl ForwardedIo {
+    /// Completion callback of the I/O on the shard core.
+    fn completion(
+        _device: &dyn BlockDevice,
+        status: IoCompletionStatus,
+        ctx: *mut libc::c_void,
+    ) {
+        Self::complete(ctx.cast(), status);
+    }
+
+    /// Complete the I/O on the core it was submitted on.
+    fn complete(io: *mut ForwardedIo, status: IoCompletionStatus) {
+        let origin = unsafe { (*io).origin };
+        reactor(origin).send_future(async move {
+            let io = unsafe { Box::from_raw(io) };
+            (io.cb)(&**io.device, status, io.cb_arg);
+        });
+    }
+}
+
+/// I/O handle of a remote child forwarding the I/Os to a shard core.
+struct ForwardHandle {
+    key: ShardKey,
+    device: Rc<Box<dyn BlockDevice>>,
+    /// Block size of the nexus.
+    block_len: u64,
+    /// Shard core the I/Os are forwarded to.
+    core: u32,
+    forwarded: Arc<AtomicU64>,
+}
+
+impl ForwardHandle {
+    /// Forward an I/O to the shard core, completing it with the given
+    /// failure if it cannot be submitted there.
+    fn forward_io(
+        &self,
+        failure: IoSubmissionFailure,
+        cb: IoCompletionCallback,
+        cb_arg: IoCompletionCallbackArg,
+        submit: impl FnOnce(
+                &dyn BlockDeviceHandle,
+                IoCompletionCallback,
+                IoCompletionCallbackArg,
+            ) -> Result<(), CoreError>
+            + 'static,
+    ) -> Result<(), CoreError> {
+        self.forwarded.fetch_add(1, Ordering::Relaxed);
+        let io = Box::into_raw(Box::new(ForwardedIo {
+            device: Rc::clone(&self.device),
+            origin: Cores::current(),
+            cb,
+            cb_arg,
+        }));
+        let key = self.key.clone();
+        let block_len = self.block_len;
+
+        reactor(self.core).send_future(async move {
+            let result = shard_handle(&key, block_len).and_then(|hdl| {
+                submit(&**hdl, ForwardedIo::completion, io.cast())
+            });
+            if let Err(error) = result {
+                debug!(
+                    "{}: failed to submit a forwarded I/O: {}",
+                    key.1, error
+                );
+                ForwardedIo::complete(
+                    io,
+                    IoCompletionStatus::IoSubmissionError(failure),
+                );
+            }
+        });
+        Ok(())
+    }
+
+    /// Run an operation on the handle of the shard core, returning its
+    /// result on the current core.
+    async fn forward<T, F, Fut>(&self, op: F) -> Result<T, CoreError>
+    where
+        T: 'static,
+        F: FnOnce(Rc<Box<dyn BlockDeviceHandle>>) -> Fut + 'static,
+        Fut: Future<Output = Result<T, CoreError>> + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let origin = Cores::current();
+        let key = self.key.clone();
+        let block_len = self.block_len;
+
+        reactor(self.core).send_future(async move {
+            let result = match shard_handle(&key, block_len) {
+                Ok(hdl) => op(hdl).await,
+                Err(error) => Err(error),
+            };
+            // the futures of a core can only be woken on that core
+            reactor(origin).send_future(async move {
+                sender.send(result).ok();
+            });
+        });
+
+        receiver.await.unwrap_or_else(|_| {
+            Err(CoreError::BdevNotFound {
+                name: self.key.1.clone(),
+            })
+        })
+    }
+}
+
+// The operations given a buffer are awaited until they complete on the shard
+// core, the buffer outlives them.
+#[async_trait(?Send)]
+impl BlockDeviceHandle for ForwardHandle {
+    fn get_device(&self) -> &dyn BlockDevice {
+        &**self.device
+    }
+
+    fn dma_malloc(&self, size: u64) -> Result<DmaBuf, DmaError> {
+        DmaBuf::new(size, self.device.alignment())
+    }
+
+    async fn read_at(
+        &self,
+        offset: u64,
+        buffer: &mut DmaBuf,
+    ) -> Result<u64, CoreError> {
+        let buffer = buffer as *mut DmaBuf;
+        self.forward(move |hdl| async move {
+            hdl.read_at(offset, unsafe { &mut *buffer }).await
+        })
+        .await
+    }
+
+    /// The handles of the shard cores are shared, the read mode is left
+    /// unchanged.
+    fn set_read_mode(&mut self, _mode: ReadMode) {}
+
+    async fn write_at(
+        &self,
+        offset: u64,
+        buffer: &DmaBuf,
+    ) -> Result<u64, CoreError> {
+        let buffer = buffer as *const DmaBuf;
+        self.forward(move |hdl| async move {
+            hdl.write_at(offset, unsafe { &*buffer }).await
+        })
+        .await
+    }
+
+    fn readv_blocks(
+        &self,
+        iov: *mut IoVec,
+        iovcnt: i32,
+        offset_blocks: u64,
+        num_blocks: u64,
+        cb: IoCompletionCallback,
+        cb_arg: IoCompletionCallbackArg,
+    ) -> Result<(), CoreError> {
+        self.forward_io(
+            IoSubmissionFailure::Read,
+            cb,
+            cb_arg,
+            move |hdl, cb, cb_arg| {
+                hdl.readv_blocks(
+                    iov,
+                    iovcnt,
+                    offset_blocks,
+                    num_blocks,
+                    cb,
+                    cb_arg,
+                )
+            },
+        )
+    }
+
+    fn writev_blocks(
+        &self,
+        iov: *mut IoVec,
+        iovcnt: i32,
+        offset_blocks: u64,
+        num_blocks: u64,
+        cb: IoCompletionCallback,
+        cb_arg: IoCompletionCallbackArg,
+    ) -> Result<(), CoreError> {
+        self.forward_io(
+            IoSubmissionFailure::Write,
+            cb,
+            cb_arg,
+            move |hdl, cb, cb_arg| {
+                hdl.writev_blocks(
+                    iov,
+                    iovcnt,
+                    offset_blocks,
+                    num_blocks,
+                    cb,
+                    cb_arg,
+                )
+            },
+        )
+    }
+
+    fn reset(
+        &self,
+        cb: IoCompletionCallback,
+        cb_arg: IoCompletionCallbackArg,
+    ) -> Result<(), CoreError> {
+        self.forward_io(
+            IoSubmissionFailure::Write,
+            cb,
+            cb_arg,
+            |hdl, cb, cb_arg| hdl.reset(cb, cb_arg),
+        )
+    }
+
+    fn unmap_blocks(
+        &self,
+        offset_blocks: u64,
+        num_blocks: u64,
+        cb: IoCompletionCallback,
+        cb_arg: IoCompletionCallbackArg,
+    ) -> Result<(), CoreError> {
+        self.forward_io(
+            IoSubmissionFailure::Write,
+            cb,
+            cb_arg,
+            move |hdl, cb, cb_arg| {
+                hdl.unmap_blocks(offset_blocks, num_blocks, cb, cb_arg)
+            },
+        )
+    }
+
+    fn write_zeroes(
+        &self,
+        offset_blocks: u64,
+        num_blocks: u64,
+        cb: IoCompletionCallback,
+        cb_arg: IoCompletionCallbackArg,
+    ) -> Result<(), CoreError> {
+        self.forward_io(
+            IoSubmissionFailure::Write,
+            cb,
+            cb_arg,
+            move |hdl, cb, cb_arg| {
+                hdl.write_zeroes(offset_blocks, num_blocks, cb, cb_arg)
+            },
+        )
+    }
+
+    async fn nvme_admin_custom(&self, opcode: u8) -> Result<(), CoreError> {
+        self.forward(
+            move |hdl| async move { hdl.nvme_admin_custom(opcode).await },
+        )
+        .await
+    }
+
+    async fn nvme_admin(
+        &self,
+        nvme_cmd: &spdk_nvme_cmd,
+        buffer: Option<&mut DmaBuf>,
+    ) -> Result<(), CoreError> {
+        let cmd = nvme_cmd as *const spdk_nvme_cmd;
+        let buffer = buffer.map(|b| b as *mut DmaBuf);
+        self.forward(move |hdl| async move {
+            hdl.nvme_admin(unsafe { &*cmd }, buffer.map(|b| unsafe { &mut *b }))
+                .await
+        })
+        .await
+    }
+
+    async fn nvme_identify_ctrlr(&self) -> Result<DmaBuf, CoreError> {
+        self.forward(move |hdl| async move { hdl.nvme_identify_ctrlr().await })
+            .await
+    }
+
+    async fn create_snapshot(&self) -> Result<u64, CoreError> {
+        self.forward(move |hdl| async move { hdl.create_snapshot().await })
+            .await
+    }
+
+    async fn nvme_resv_register(
+        &self,
+        current_key: u64,
+        new_key: u64,
+        register_action: u8,
+        cptpl: u8,
+    ) -> Result<(), CoreError> {
+        self.forward(move |hdl| async move {
+            hdl.nvme_resv_register(current_key, new_key, register_action, cptpl)
+                .await
+        })
+        .await
+    }
+
+    async fn nvme_resv_acquire(
+        &self,
+        current_key: u64,
+        preempt_key: u64,
+        acquire_action: u8,
+        resv_type: u8,
+    ) -> Result<(), CoreError> {
+        self.forward(move |hdl| async move {
+            hdl.nvme_resv_acquire(
+                current_key,
+                preempt_key,
+                acquire_action,
+                resv_type,
+            )
+            .await
+        })
+        .await
+    }
+
+    async fn nvme_resv_release(
+        &self,
+        current_key: u64,
+        resv_type: u8,
+        release_action: u8,
+    ) -> Result<(), CoreError> {
+        self.forward(move |hdl| async move {
+            hdl.nvme_resv_release(current_key, resv_type, release_action)
+                .await
+        })
+        .await
+    }
+
+    async fn nvme_resv_report(
+        &self,
+        cdw11: u32,
+        buffer: &mut DmaBuf,
+    ) -> Result<(), CoreError> {
+        let buffer = buffer as *mut DmaBuf;
+        self.forward(move |hdl| async move {
+            hdl.nvme_resv_report(cdw11, unsafe { &mut *buffer }).await
+        })
+        .await
+    }
+
+    async fn io_passthru(
+        &self,
+        nvme_cmd: &spdk_nvme_cmd,
+        buffer: Option<&mut DmaBuf>,
+    ) -> Result<(), CoreError> {
+        let cmd = nvme_cmd as *const spdk_nvme_cmd;
+        let buffer = buffer.map(|b| b as *mut DmaBuf);
+        self.forward(move |hdl| async move {
+            hdl.io_passthru(
+                unsafe { &*cmd },
+                buffer.map(|b| unsafe { &mut *b }),
+            )
+            .await
+        })
+        .await
+    }
+
+    async fn host_id(&self) -> Result<[u8; 16], CoreError> {
+        self.forward(move |hdl| async move { hdl.host_id().await })
+            .await
+    }
+}
+
+impl<'n> Nexus<'n> {
+    /// Drop the handles of the shard cores of the nexus, once its children
+    /// have changed; they are created again on the next forwarded I/O.
+    pub(super) async fn purge_shard_handles(&self) {
+        for core in self.shards.cores() {
+            self.purge_core_shard_handles(core).await;
+        }
+    }
+
+    /// Drop the handles of the nexus on the given shard core.
+    pub(super) async fn purge_core_shard_handles(&self, core: u32) {
+        let origin = Cores::current();
+        let (sender, receiver) = oneshot::channel();
+        let name = self.name.clone();
+        reactor(core).send_future(async move {
+            SHARD_HANDLES.with(|handles| {
+                handles.borrow_mut().retain(|(nexus, _), _| *nexus != name)
+            });
+            reactor(origin).send_future(async move {
+                sender.send(()).ok();
+            });
+        });
+        receiver.await.ok();
+    }
+}
+
+/// Arguments of the `nexus_shards` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct NexusShardsArgs {
+    /// Name of the nexus.
+    name: String,
+}
+
+/// Reply of the `nexus_shards` json-rpc method.
+#[derive(Debug, Serialize)]
+struct NexusShardsReply {
+    name: String,
+    /// Cores connecting to the remote children, all of them if empty.
+    cores: Vec<u32>,
+    /// Number of I/Os forwarded to the shard cores.
+    forwarded_ios: u64,
+}
+
+/// Register the sharding json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nexus_shards", |args: NexusShardsArgs| {
+        async move {
+            nexus_lookup(&args.name)
+                .map(|n| NexusShardsReply {
+                    name: n.name.clone(),
+                    cores: n.shards.cores(),
+                    forwarded_ios: n.shards.forwarded.load(Ordering::Relaxed),
+                })
+                .ok_or(Error::NexusNotFound {
+                    name: args.name,
+                })
+        }
+        .boxed_local()
+    });
+}
@@ -1,11 +1,34 @@
 use crate::bdev::PtplFileOps;
 use async_trait::async_trait;
+use futures::FutureExt;
+use serde::Deserialize;
 use snafu::ResultExt;
-use std::pin::Pin;
+use std::{pin::Pin, time::Duration};
 
-use super::{nexus_err, Error, NbdDisk, Nexus, NexusTarget};
+use super::{
+    nexus_err,
+    nexus_lookup,
+    nexus_lookup_mut,
+    Error,
+    LocalDisk,
+    NbdDisk,
+    Nexus,
+    NexusState,
+    NexusTarget,
+};
 
-use crate::core::{Protocol, Share, ShareProps, UpdateProps};
+use crate::{
+    core::{Protocol, Share, ShareProps, UpdateProps},
+    jsonrpc::jsonrpc_register,
+    sleep::mayastor_sleep,
+    subsys::{add_referral, remove_referral, NvmfSubsystem},
+    target::vhost::{self, VhostController},
+    tenant::{check_quota, tenant_of, TenantUsage},
+};
+
+/// Time given to the hosts to handle the removal of the namespace of a
+/// nexus being destroyed, before they are disconnected.
+const NS_REMOVAL_GRACE: Duration = Duration::from_millis(200);
 
 ///
 /// The sharing of the nexus is different compared to regular bdevs
@@ -46,6 +69,11 @@ impl<'n> Share for Nexus<'n> {
                 info!("{:?}: already shared as '{}'", self, uri);
                 uri
             }
+            Some(Protocol::Iscsi | Protocol::Local | Protocol::VfioUser) => {
+                return Err(Error::AlreadyShared {
+                    name: self.name.clone(),
+                });
+            }
         };
 
         Ok(uri)
@@ -81,6 +109,10 @@ impl<'n> Share for Nexus<'n> {
 
     /// TODO
     fn shared(&self) -> Option<Protocol> {
+        // a local disk is served by the bdev layer, not by a target
+        if let Some(NexusTarget::LocalDisk(_)) = self.nexus_target {
+            return Some(Protocol::Local);
+        }
         unsafe { self.bdev().shared() }
     }
 
@@ -108,6 +140,9 @@ impl From<&NexusTarget> for Protocol {
     fn from(target: &NexusTarget) -> Protocol {
         match target {
             NexusTarget::NexusNvmfTarget => Protocol::Nvmf,
+            NexusTarget::NexusIscsiTarget => Protocol::Iscsi,
+            NexusTarget::LocalDisk(_) => Protocol::Local,
+            NexusTarget::NexusVfioUserTarget => Protocol::VfioUser,
             _ => Protocol::Off,
         }
     }
@@ -127,7 +162,7 @@ impl<'n> Nexus<'n> {
     pub async fn share_ext(
         mut self: Pin<&mut Self>,
         protocol: Protocol,
-        _key: Option<String>,
+        key: Option<String>,
         allowed_hosts: Vec<String>,
     ) -> Result<String, Error> {
         // This function should be idempotent as it's possible that
@@ -153,6 +188,18 @@ impl<'n> Nexus<'n> {
             });
         }
 
+        // A module layered on top of the nexus is its only consumer.
+        self.check_unclaimed()?;
+
+        if let Some(tenant) = tenant_of(&self.labels()) {
+            check_quota(tenant, &TenantUsage::shared_target())
+                .await
+                .map_err(|reason| Error::TenantQuota {
+                    name: self.name.clone(),
+                    reason,
+                })?;
+        }
+
         match protocol {
             // right now Off is mapped to Nbd, will clean up the Nbd related
             // code once we refactor the rust tests that use nbd.
@@ -193,6 +240,54 @@ impl<'n> Nexus<'n> {
                 }
                 Ok(uri)
             }
+            Protocol::Iscsi => {
+                info!("{:?}: sharing iSCSI target...", self);
+                // the key, if any, is the CHAP secret of the initiators
+                let uri = unsafe { self.bdev() }
+                    .share_iscsi(&allowed_hosts, key.as_deref())
+                    .context(nexus_err::ShareIscsiNexus {
+                        name: self.name.clone(),
+                    })?;
+                info!("{:?}: shared iSCSI target as '{}'", self, uri);
+
+                unsafe {
+                    self.as_mut().get_unchecked_mut().nexus_target =
+                        Some(NexusTarget::NexusIscsiTarget);
+                }
+                Ok(uri)
+            }
+            Protocol::VfioUser => {
+                info!("{:?}: sharing vfio-user controller...", self);
+                let uri = unsafe { self.bdev() }
+                    .share_vfio_user()
+                    .await
+                    .context(nexus_err::ShareVfioUserNexus {
+                        name: self.name.clone(),
+                    })?;
+                info!("{:?}: shared vfio-user controller as '{}'", self, uri);
+
+                unsafe {
+                    self.as_mut().get_unchecked_mut().nexus_target =
+                        Some(NexusTarget::NexusVfioUserTarget);
+                }
+                Ok(uri)
+            }
+            Protocol::Local => {
+                info!("{:?}: attaching local disk...", self);
+                let disk = LocalDisk::create(&self.name).await.context(
+                    nexus_err::ShareLocalNexus {
+                        name: self.name.clone(),
+                    },
+                )?;
+                let uri = disk.as_uri();
+                info!("{:?}: attached local disk as '{}'", self, uri);
+
+                unsafe {
+                    self.as_mut().get_unchecked_mut().nexus_target =
+                        Some(NexusTarget::LocalDisk(disk));
+                }
+                Ok(uri)
+            }
         }
     }
 
@@ -206,6 +301,16 @@ impl<'n> Nexus<'n> {
             Some(NexusTarget::NexusNvmfTarget) => {
                 info!("{:?}: unsharing NVMF target...", self);
             }
+            Some(NexusTarget::NexusIscsiTarget) => {
+                info!("{:?}: unsharing iSCSI target...", self);
+            }
+            Some(NexusTarget::NexusVfioUserTarget) => {
+                info!("{:?}: unsharing vfio-user controller...", self);
+            }
+            Some(NexusTarget::LocalDisk(disk)) => {
+                info!("{:?}: detaching local disk {:?}...", self, disk);
+                disk.destroy().await;
+            }
             None => {
                 // Try unshare nexus bdev anyway, just in case it was shared
                 // via bdev API. It is no-op if bdev was not shared.
@@ -215,11 +320,160 @@ impl<'n> Nexus<'n> {
         self.as_mut().unshare().await
     }
 
+    /// Withdraw the namespace of a nexus being destroyed from its hosts,
+    /// before its children are closed. The I/Os outstanding on the
+    /// namespace are completed while the nexus is paused, or aborted by the
+    /// I/O deadline of the nexus if it has one, the namespace is then removed,
+    /// which the hosts are notified of once the nexus resumes, and the
+    /// hosts are finally disconnected, which drains their queue pairs. A
+    /// nexus which has been shut down keeps its I/Os paused, and only has
+    /// its hosts disconnected.
+    pub(super) async fn withdraw_namespace(mut self: Pin<&mut Self>) {
+        if self.shared() != Some(Protocol::Nvmf) {
+            return;
+        }
+        let subsystem = match NvmfSubsystem::nqn_lookup(&self.name) {
+            Some(subsystem) => subsystem,
+            None => return,
+        };
+
+        if *self.state.lock() != NexusState::Shutdown {
+            info!("{:?}: removing the namespace of the nexus...", self);
+            if let Err(error) = self.as_mut().pause().await {
+                warn!(
+                    "{:?}: failed to pause the nexus, its namespace is not \
+                    removed: {}",
+                    self, error
+                );
+                return;
+            }
+            if let Err(error) = subsystem.remove_namespaces() {
+                warn!("{:?}: {}", self, error);
+            }
+            if let Err(error) = self.as_mut().resume().await {
+                warn!("{:?}: failed to resume the nexus: {}", self, error);
+            }
+        }
+
+        let mut hosts = subsystem.connected_hosts();
+        hosts.sort();
+        hosts.dedup();
+        if hosts.is_empty() {
+            return;
+        }
+        mayastor_sleep(NS_REMOVAL_GRACE).await.ok();
+        for host in hosts {
+            info!("{:?}: disconnecting host '{}'...", self, host);
+            if let Err(error) = subsystem.disconnect_host(&host).await {
+                warn!(
+                    "{:?}: failed to disconnect host '{}': {}",
+                    self, host, error
+                );
+            }
+        }
+    }
+
+    /// Unshares the nexus of a volume which moved to another node, referring
+    /// the hosts discovering it to the target it is now shared with, given
+    /// by its URI, for the given grace period.
+    pub async fn unshare_nexus_with_referral(
+        self: Pin<&mut Self>,
+        uri: &str,
+        grace: Duration,
+    ) -> Result<(), Error> {
+        // the URI is checked before the nexus is unshared
+        let referral = add_referral(uri, grace)?;
+        if let Err(e) = self.unshare_nexus().await {
+            remove_referral(&referral.nqn);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Exposes the nexus to the guests of the node through a vhost-user-blk
+    /// controller, named after the nexus unless a name is given. A read-only
+    /// nexus is always exposed read-only.
+    pub fn attach_vhost(
+        &self,
+        ctrlr: Option<&str>,
+        cpumask: Option<&str>,
+        read_only: bool,
+    ) -> Result<VhostController, Error> {
+        let ctrlr = vhost::create(
+            &self.name,
+            ctrlr,
+            cpumask,
+            read_only || self.is_read_only(),
+        )
+        .context(nexus_err::AttachVhost {
+            name: self.name.clone(),
+        })?;
+        info!("{:?}: attached over vhost at {}", self, ctrlr.socket);
+        Ok(ctrlr)
+    }
+
+    /// Removes the given vhost-user-blk controller of the nexus, or all of
+    /// them if none is given.
+    pub fn detach_vhost(&self, ctrlr: Option<&str>) -> Result<(), Error> {
+        let result = match ctrlr {
+            Some(ctrlr) => match vhost::controller(ctrlr) {
+                Some(c) if c.bdev == self.name => vhost::remove(ctrlr),
+                _ => Err(vhost::Error::ControllerNotFound {
+                    name: ctrlr.to_string(),
+                }),
+            },
+            None => vhost::remove_bdev(&self.name),
+        };
+        result.context(nexus_err::DetachVhost {
+            name: self.name.clone(),
+        })
+    }
+
+    /// Returns the vhost-user-blk controllers of the nexus.
+    pub fn vhost_controllers(&self) -> Vec<VhostController> {
+        vhost::bdev_controllers(&self.name)
+    }
+
+    /// Returns true if the nexus is exported read-only.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load()
+    }
+
+    /// Make the nexus read-only, or writable again, while it stays shared:
+    /// writes, write zeroes and unmaps are completed with the "Namespace is
+    /// Write Protected" NVMe status while it is read-only.
+    /// The nexus is paused during the change, so that no write is in flight
+    /// once it is read-only.
+    pub async fn set_read_only(
+        mut self: Pin<&mut Self>,
+        read_only: bool,
+    ) -> Result<(), Error> {
+        if self.read_only.load() == read_only {
+            return Ok(());
+        }
+
+        self.as_mut().pause().await?;
+        self.read_only.store(read_only);
+        info!(
+            "{:?}: exported {}",
+            self,
+            if read_only { "read-only" } else { "read-write" }
+        );
+        self.as_mut().resume().await?;
+        self.persist_spec().await;
+        Ok(())
+    }
+
     /// TODO
     pub fn get_share_uri(&self) -> Option<String> {
         match self.nexus_target {
             Some(NexusTarget::NbdDisk(ref disk)) => Some(disk.as_uri()),
-            Some(NexusTarget::NexusNvmfTarget) => self.share_uri(),
+            Some(NexusTarget::LocalDisk(ref disk)) => Some(disk.as_uri()),
+            Some(
+                NexusTarget::NexusNvmfTarget
+                | NexusTarget::NexusIscsiTarget
+                | NexusTarget::NexusVfioUserTarget,
+            ) => self.share_uri(),
             None => None,
         }
     }
@@ -266,3 +520,99 @@ impl PtplFileOps for NexusPtpl {
             .with_extension("json")
     }
 }
+
+/// Arguments of the `nexus_unshare_referral` json-rpc method.
+#[derive(Deserialize)]
+struct UnshareReferralArgs {
+    /// Name or uuid of the nexus.
+    name: String,
+    /// URI the volume is shared with on its new node.
+    uri: String,
+    /// Seconds the hosts are referred to the new node for.
+    grace_secs: u64,
+}
+
+/// Arguments of the `nexus_vhost_attach` json-rpc method.
+#[derive(Deserialize)]
+struct VhostAttachArgs {
+    /// Name or uuid of the nexus.
+    name: String,
+    /// Name of the controller, defaults to one derived from the nexus.
+    ctrlr: Option<String>,
+    /// Cores the controller is polled on, defaults to all the cores.
+    cpumask: Option<String>,
+    #[serde(default)]
+    read_only: bool,
+}
+
+/// Arguments of the `nexus_vhost_detach` json-rpc method.
+#[derive(Deserialize)]
+struct VhostDetachArgs {
+    /// Name or uuid of the nexus.
+    name: String,
+    /// Controller to remove, all the controllers of the nexus if none.
+    ctrlr: Option<String>,
+}
+
+/// Arguments of the `nexus_vhost_list` json-rpc method.
+#[derive(Deserialize)]
+struct VhostListArgs {
+    /// Name or uuid of the nexus.
+    name: String,
+}
+
+/// Register the nexus share json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nexus_unshare_referral", |args: UnshareReferralArgs| {
+        async move {
+            let nexus =
+                nexus_lookup_mut(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+            nexus
+                .unshare_nexus_with_referral(
+                    &args.uri,
+                    Duration::from_secs(args.grace_secs),
+                )
+                .await
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_vhost_attach", |args: VhostAttachArgs| {
+        async move {
+            let nexus =
+                nexus_lookup(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+            nexus.attach_vhost(
+                args.ctrlr.as_deref(),
+                args.cpumask.as_deref(),
+                args.read_only,
+            )
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_vhost_detach", |args: VhostDetachArgs| {
+        async move {
+            let nexus =
+                nexus_lookup(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+            nexus.detach_vhost(args.ctrlr.as_deref())
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_vhost_list", |args: VhostListArgs| {
+        async move {
+            nexus_lookup(&args.name)
+                .map(|n| n.vhost_controllers())
+                .ok_or(Error::NexusNotFound {
+                    name: args.name,
+                })
+        }
+        .boxed_local()
+    });
+}
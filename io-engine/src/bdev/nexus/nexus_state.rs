@@ -0,0 +1,183 @@
+//! State machines of the nexus and of its children.
+//!
+//! The states of a nexus and of its children only change through
+//! `Nexus::transition` and `NexusChild::transition`, which check the change
+//! against the transitions of the state machine and record it, with its
+//! cause, in a transition log kept by the resource. A transition which is
+//! not part of the state machine is still applied, as refusing it would
+//! leave the resource in a state its caller does not expect, but it is
+//! logged as unexpected so that it can be found and audited.
+//!
+//! The history of a nexus, its own transitions and those of its children,
+//! including the children which were removed, is returned by the
+//! `nexus_get_history` json-rpc method.
+
+use std::collections::VecDeque;
+
+use chrono::{DateTime, Utc};
+use futures::FutureExt;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use super::{nexus_lookup, ChildState, Error, Nexus, NexusState};
+use crate::jsonrpc::jsonrpc_register;
+
+/// Most transitions kept in the log of a resource, the oldest ones are
+/// dropped first.
+const MAX_TRANSITIONS: usize = 128;
+
+/// A change of state of a nexus or of one of its children.
+#[derive(Debug, Clone, Serialize)]
+pub struct StateTransition {
+    pub timestamp: DateTime<Utc>,
+    /// Name of the nexus, or URI of the child, whose state changed.
+    pub resource: String,
+    pub from: String,
+    pub to: String,
+    /// Who requested the transition and why.
+    pub cause: String,
+    /// The transition is not part of the state machine of the resource.
+    pub unexpected: bool,
+}
+
+/// Log of the transitions of a resource.
+#[derive(Debug, Default)]
+pub(crate) struct TransitionLog {
+    transitions: Mutex<VecDeque<StateTransition>>,
+}
+
+impl TransitionLog {
+    pub(crate) fn record(
+        &self,
+        resource: &str,
+        from: impl ToString,
+        to: impl ToString,
+        cause: &str,
+        unexpected: bool,
+    ) {
+        self.append(vec![StateTransition {
+            timestamp: Utc::now(),
+            resource: resource.to_string(),
+            from: from.to_string(),
+            to: to.to_string(),
+            cause: cause.to_string(),
+            unexpected,
+        }]);
+    }
+
+    /// Appends transitions recorded elsewhere, such as those of a child
+    /// removed from the nexus.
+    pub(crate) fn append(&self, transitions: Vec<StateTransition>) {
+        let mut log = self.transitions.lock();
+        log.extend(transitions);
+        while log.len() > MAX_TRANSITIONS {
+            log.pop_front();
+        }
+    }
+
+    pub(crate) fn transitions(&self) -> Vec<StateTransition> {
+        self.transitions.lock().iter().cloned().collect()
+    }
+}
+
+impl NexusState {
+    /// Returns true if the nexus can go from this state to the given one.
+    pub fn can_transition_to(&self, to: NexusState) -> bool {
+        use NexusState::*;
+        matches!(
+            (self, to),
+            (Init, Open)
+                | (Init, Closed)
+                | (Open, Reconfiguring)
+                | (Reconfiguring, Open)
+                | (Init | Closed | Open | Reconfiguring, ShuttingDown)
+                | (ShuttingDown, Shutdown)
+                // a failed shutdown restores the state the nexus was in
+                | (ShuttingDown, Init | Closed | Open | Reconfiguring)
+                | (Open | Reconfiguring | ShuttingDown | Shutdown, Closed)
+        )
+    }
+}
+
+impl ChildState {
+    /// Returns true if a child can go from this state to the given one.
+    pub fn can_transition_to(&self, to: ChildState) -> bool {
+        use ChildState::*;
+        matches!(
+            (self, to),
+            (_, Destroying)
+                // a child whose device removal fails reverts to the state
+                // it was in
+                | (Destroying, _)
+                | (Init | Closed, _)
+                | (Open, Faulted(_) | Closed)
+                | (Faulted(_), Faulted(_) | Open | Closed)
+                | (ConfigInvalid, Faulted(_) | Closed)
+        )
+    }
+}
+
+impl<'n> Nexus<'n> {
+    /// Changes the state of the nexus, recording the transition with its
+    /// cause. Returns the previous state.
+    pub(crate) fn transition(&self, to: NexusState, cause: &str) -> NexusState {
+        let mut state = self.state.lock();
+        self.transition_locked(&mut state, to, cause)
+    }
+
+    /// Changes the state of the nexus, whose lock is held by the caller.
+    pub(crate) fn transition_locked(
+        &self,
+        state: &mut NexusState,
+        to: NexusState,
+        cause: &str,
+    ) -> NexusState {
+        let from = *state;
+        let unexpected = !from.can_transition_to(to);
+        if unexpected {
+            warn!(
+                "{:?}: unexpected state transition '{}' -> '{}': {}",
+                self, from, to, cause
+            );
+        } else {
+            debug!(
+                "{:?}: changing state '{}' -> '{}': {}",
+                self, from, to, cause
+            );
+        }
+        *state = to;
+        self.history.record(&self.name, from, to, cause, unexpected);
+        from
+    }
+
+    /// Returns the transitions of the nexus and of its children, oldest
+    /// first.
+    pub fn history(&self) -> Vec<StateTransition> {
+        let mut transitions = self.history.transitions();
+        self.children_iter()
+            .for_each(|c| transitions.extend(c.history()));
+        transitions.sort_by_key(|t| t.timestamp);
+        transitions
+    }
+}
+
+/// Arguments of the `nexus_get_history` json-rpc method.
+#[derive(Deserialize)]
+struct GetHistoryArgs {
+    /// Name or uuid of the nexus.
+    name: String,
+}
+
+/// Register the state history json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nexus_get_history", |args: GetHistoryArgs| {
+        async move {
+            nexus_lookup(&args.name).map(|n| n.history()).ok_or(
+                Error::NexusNotFound {
+                    name: args.name,
+                },
+            )
+        }
+        .boxed_local()
+    });
+}
@@ -0,0 +1,186 @@
+//! Utility functions and wrappers for working with ublk devices in SPDK.
+//!
+//! A ublk device exposes a bdev as a kernel block device (/dev/ublkbN) served
+//! by the reactors through io_uring, which avoids the socket round-trip of
+//! NBD. It requires the ublk_drv kernel module, Linux 6.0 or later.
+
+use futures::channel::oneshot;
+use nix::errno::Errno;
+use snafu::{ResultExt, Snafu};
+use std::{
+    cell::Cell,
+    ffi::{c_void, CString},
+    fmt,
+    os::raw::{c_char, c_int},
+    path::Path,
+};
+
+use crate::ffihelper::{cb_arg, done_errno_cb, ErrnoResult};
+
+/// Control device of the ublk driver, present once the module is loaded.
+const UBLK_CONTROL: &str = "/dev/ublk-control";
+/// Most ublk devices looked at for a free one.
+const UBLK_MAX_DEVICES: u32 = 64;
+const UBLK_NUM_QUEUES: u32 = 1;
+const UBLK_QUEUE_DEPTH: u32 = 128;
+
+#[derive(Debug, Snafu)]
+#[snafu(context(suffix(false)))]
+pub enum UblkError {
+    #[snafu(display("ublk is unavailable (is the ublk_drv kmod loaded?)"))]
+    UblkUnavailable {},
+    #[snafu(display("No free ublk devices available"))]
+    UblkNoFreeDevice {},
+    #[snafu(display("Failed to create ublk target: {}", source))]
+    CreateUblkTarget { source: Errno },
+    #[snafu(display("Failed to start ublk device {}: {}", id, source))]
+    StartUblk { source: Errno, id: u32 },
+    #[snafu(display("Failed to stop ublk device {}: {}", id, source))]
+    StopUblk { source: Errno, id: u32 },
+}
+
+type UblkCtrlCb = extern "C" fn(*mut c_void, c_int);
+
+extern "C" {
+    fn ublk_create_target(cpumask_str: *const c_char) -> c_int;
+    fn ublk_start_disk(
+        bdev_name: *const c_char,
+        ublk_id: u32,
+        num_queues: u32,
+        queue_depth: u32,
+        ctrl_cb: Option<UblkCtrlCb>,
+        cb_arg: *mut c_void,
+    ) -> c_int;
+    fn ublk_stop_disk(
+        ublk_id: u32,
+        ctrl_cb: Option<UblkCtrlCb>,
+        cb_arg: *mut c_void,
+    ) -> c_int;
+}
+
+thread_local! {
+    /// The ublk target is created on the first device started.
+    static UBLK_TARGET: Cell<bool> = Cell::new(false);
+}
+
+/// Returns true if the ublk driver is loaded.
+pub fn is_available() -> bool {
+    Path::new(UBLK_CONTROL).exists()
+}
+
+/// Creates the ublk target, once, on all the cores of the reactor mask.
+fn create_target() -> Result<(), UblkError> {
+    if UBLK_TARGET.with(|t| t.get()) {
+        return Ok(());
+    }
+    let rc = unsafe { ublk_create_target(std::ptr::null()) };
+    if rc != 0 {
+        return Err(Errno::from_i32(rc.abs())).context(CreateUblkTarget {});
+    }
+    UBLK_TARGET.with(|t| t.set(true));
+    Ok(())
+}
+
+/// Return the first ublk id whose device does not exist.
+fn find_unused() -> Result<u32, UblkError> {
+    (0 .. UBLK_MAX_DEVICES)
+        .find(|id| {
+            !Path::new(&format!("/dev/ublkb{}", id)).exists()
+                && !Path::new(&format!("/dev/ublkc{}", id)).exists()
+        })
+        .ok_or(UblkError::UblkNoFreeDevice {})
+}
+
+/// ublk disk representation.
+pub struct UblkDisk {
+    id: u32,
+}
+
+impl UblkDisk {
+    /// Allocate a ublk device for the bdev and start it.
+    /// When the function returns the ublk disk is ready for IO.
+    pub async fn create(bdev_name: &str) -> Result<Self, UblkError> {
+        if !is_available() {
+            return Err(UblkError::UblkUnavailable {});
+        }
+        create_target()?;
+
+        let id = find_unused()?;
+        let c_bdev_name = CString::new(bdev_name).unwrap();
+        let (sender, receiver) = oneshot::channel::<ErrnoResult<()>>();
+
+        let rc = unsafe {
+            ublk_start_disk(
+                c_bdev_name.as_ptr(),
+                id,
+                UBLK_NUM_QUEUES,
+                UBLK_QUEUE_DEPTH,
+                Some(done_errno_cb),
+                cb_arg(sender),
+            )
+        };
+        if rc != 0 {
+            return Err(Errno::from_i32(rc.abs())).context(StartUblk {
+                id,
+            });
+        }
+        receiver
+            .await
+            .expect("Cancellation is not supported")
+            .context(StartUblk {
+                id,
+            })?;
+
+        let disk = Self {
+            id,
+        };
+        info!("Started ublk disk {} for {}", disk.get_path(), bdev_name);
+        Ok(disk)
+    }
+
+    /// Stop and release the ublk device.
+    pub async fn destroy(self) -> Result<(), UblkError> {
+        let id = self.id;
+        let (sender, receiver) = oneshot::channel::<ErrnoResult<()>>();
+
+        debug!("Stopping ublk device {}...", self);
+        let rc =
+            unsafe { ublk_stop_disk(id, Some(done_errno_cb), cb_arg(sender)) };
+        if rc != 0 {
+            return Err(Errno::from_i32(rc.abs())).context(StopUblk {
+                id,
+            });
+        }
+        receiver
+            .await
+            .expect("Cancellation is not supported")
+            .context(StopUblk {
+                id,
+            })?;
+
+        info!("ublk {} device stopped", self);
+        Ok(())
+    }
+
+    /// Get ublk device path (/dev/ublkb...) for the ublk disk.
+    pub fn get_path(&self) -> String {
+        format!("/dev/ublkb{}", self.id)
+    }
+
+    /// Get ublk device path uri (file:///dev/ublkb...) for the ublk disk.
+    pub fn as_uri(&self) -> String {
+        format!("file://{}", self.get_path())
+    }
+}
+
+impl fmt::Debug for UblkDisk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.get_path(), self.id)
+    }
+}
+
+impl fmt::Display for UblkDisk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.get_path())
+    }
+}
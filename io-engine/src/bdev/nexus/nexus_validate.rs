@@ -0,0 +1,240 @@
+//! Validation of the children of a nexus before they are opened.
+//!
+//! When a nexus is created or a child is added to it, the devices of the
+//! children are checked against the geometry of the nexus and against each
+//! other before any of them is opened: their size, their block size, the
+//! start of their data partition, their metadata format, and the size of
+//! the largest I/O they accept. Every violation is collected into a report,
+//! so that all the faulty children are known at once, and the nexus is left
+//! as it was.
+//!
+//! Children which do not support unmap or write zeroes while others do are
+//! valid, but the nexus then no longer advertises these I/O types, so they
+//! are reported as warnings.
+
+use std::{
+    cmp::max,
+    fmt::{Display, Formatter},
+};
+
+use serde::Serialize;
+
+use crate::core::{partition, BlockDevice, IoType};
+
+/// Kind of the violation of a child.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ViolationKind {
+    /// The child is too small for the nexus.
+    Size,
+    /// The block size of the child is not compatible with the nexus.
+    BlockSize,
+    /// The data partition of the child does not start at the same block as
+    /// the other children.
+    DataOffset,
+    /// The child does not support unmap while other children do.
+    Unmap,
+    /// The child does not support write zeroes while other children do.
+    WriteZeroes,
+    /// The metadata format, and so the protection information, of the child
+    /// differs from the other children.
+    ProtectionInfo,
+    /// The child does not accept I/Os of a block of the nexus.
+    MaxTransfer,
+}
+
+/// Violation of a child.
+#[derive(Serialize, Debug, Clone)]
+pub struct ChildViolation {
+    /// URI of the child.
+    pub child: String,
+    pub kind: ViolationKind,
+    pub detail: String,
+}
+
+impl Display for ChildViolation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "child {}: {}", self.child, self.detail)
+    }
+}
+
+/// Violations of the children of a nexus: the errors prevent the children
+/// from being opened, the warnings do not.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ValidationReport {
+    pub errors: Vec<ChildViolation>,
+    pub warnings: Vec<ChildViolation>,
+}
+
+impl Display for ValidationReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let errors = self
+            .errors
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        write!(f, "{}", errors.join("; "))
+    }
+}
+
+impl ValidationReport {
+    /// Returns true if no child has an error.
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// Record an error of the given child.
+    pub(crate) fn error(
+        &mut self,
+        child: &str,
+        kind: ViolationKind,
+        detail: String,
+    ) {
+        self.errors.push(ChildViolation {
+            child: child.to_string(),
+            kind,
+            detail,
+        });
+    }
+
+    fn warning(&mut self, child: &str, kind: ViolationKind, detail: String) {
+        self.warnings.push(ChildViolation {
+            child: child.to_string(),
+            kind,
+            detail,
+        });
+    }
+}
+
+/// Validate the devices of the given children, by URI, against a nexus of
+/// the given size and block size, in bytes, whose open children have the
+/// given devices.
+pub(crate) fn validate_children(
+    size: u64,
+    block_len: u64,
+    open: &[&dyn BlockDevice],
+    children: &[(&str, &dyn BlockDevice)],
+) -> ValidationReport {
+    let mut report = ValidationReport::default();
+    let all = || open.iter().copied().chain(children.iter().map(|c| c.1));
+
+    // an added child is rebuilt from the open children
+    let min_size = open.iter().map(|d| d.size_in_bytes()).min();
+    // children are compared with the first open child, or the first child
+    let md_size = all().next().map_or(0, |d| d.md_size());
+    let mut data_start = None;
+
+    for (uri, dev) in children {
+        let bs = dev.block_len();
+
+        if bs % block_len != 0 && block_len % bs != 0 {
+            report.error(
+                uri,
+                ViolationKind::BlockSize,
+                format!(
+                    "block size {} is not compatible with the {} byte \
+                    blocks of the nexus",
+                    bs, block_len
+                ),
+            );
+        }
+
+        let dev_size = dev.size_in_bytes();
+        let data = partition::calc_data_partition(size, dev.num_blocks(), bs);
+        // a too small child is reported once, against the largest size
+        let required = max(size, min_size.unwrap_or_default());
+        if data.is_none() {
+            report.error(
+                uri,
+                ViolationKind::Size,
+                format!(
+                    "{} blocks of {} bytes are too small for the metadata \
+                    of the nexus",
+                    dev.num_blocks(),
+                    bs
+                ),
+            );
+        } else if dev_size < required {
+            report.error(
+                uri,
+                ViolationKind::Size,
+                format!(
+                    "{} bytes are smaller than the {} bytes {}",
+                    dev_size,
+                    required,
+                    if required > size {
+                        "of the open children"
+                    } else {
+                        "of the nexus"
+                    }
+                ),
+            );
+        }
+
+        if let Some((start, _)) = data {
+            // in blocks of the nexus
+            let start = start * bs / block_len;
+            match data_start {
+                Some(expected) if expected != start => report.error(
+                    uri,
+                    ViolationKind::DataOffset,
+                    format!(
+                        "data starts at block {} of the nexus instead of {}",
+                        start, expected
+                    ),
+                ),
+                _ => data_start = Some(start),
+            }
+        }
+
+        if dev.md_size() != md_size {
+            report.error(
+                uri,
+                ViolationKind::ProtectionInfo,
+                format!(
+                    "blocks have {} bytes of metadata instead of {}",
+                    dev.md_size(),
+                    md_size
+                ),
+            );
+        }
+
+        for io_type in [IoType::Read, IoType::Write] {
+            if let Some(max) =
+                dev.max_io_blocks(io_type).filter(|&m| m * bs < block_len)
+            {
+                report.error(
+                    uri,
+                    ViolationKind::MaxTransfer,
+                    format!(
+                        "{:?} I/Os of {} bytes at most are smaller than the \
+                        {} byte blocks of the nexus",
+                        io_type,
+                        max * bs,
+                        block_len
+                    ),
+                );
+            }
+        }
+
+        for (io_type, kind) in [
+            (IoType::Unmap, ViolationKind::Unmap),
+            (IoType::WriteZeros, ViolationKind::WriteZeroes),
+        ] {
+            if !dev.io_type_supported(io_type)
+                && all().any(|d| d.io_type_supported(io_type))
+            {
+                report.warning(
+                    uri,
+                    kind,
+                    format!(
+                        "{:?} is not supported while other children \
+                        support it, the nexus no longer advertises it",
+                        io_type
+                    ),
+                );
+            }
+        }
+    }
+    report
+}
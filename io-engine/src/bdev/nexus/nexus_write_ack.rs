@@ -0,0 +1,433 @@
+//! Local replica preferred write acknowledgement.
+//!
+//! By default, a write to the nexus is acknowledged once all of its children
+//! have completed it. With the `local_quorum` mode, a write is acknowledged
+//! once the local replica and at least one remote replica have completed it,
+//! while the remaining remote replicas complete it asynchronously. This
+//! hides the latency of the slowest zone from latency sensitive workloads.
+//!
+//! The data of an asynchronous write is copied into a buffer of its own, as
+//! the buffers of the nexus I/O are released once it is acknowledged. The
+//! number of asynchronous writes in flight to each child is its lag: reads
+//! are never served by a lagging child, and a child whose lag reaches the
+//! configured bound is written synchronously until it catches up. An
+//! asynchronous write failing retires the child, as it is then out of sync:
+//! it is marked stale first, so that reads keep away from it until it has
+//! been disconnected from the channels.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crossbeam::atomic::AtomicCell;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+
+use spdk_rs::{DmaBuf, IoVec};
+
+use super::{nexus_lookup, nexus_lookup_mut, Error, FaultDetail, Nexus};
+use crate::{
+    core::{BlockDevice, IoCompletionStatus, Reactors},
+    jsonrpc::jsonrpc_register,
+    sleep::mayastor_sleep,
+};
+
+/// Write acknowledgement mode of a nexus.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteAckMode {
+    /// Writes are acknowledged once all children completed them.
+    All,
+    /// Writes are acknowledged once the local child and a remote child
+    /// completed them.
+    LocalQuorum,
+}
+
+/// Write acknowledgement policy of a nexus.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct WriteAckPolicy {
+    pub mode: WriteAckMode,
+    /// Maximum number of asynchronous writes in flight to a child.
+    pub max_lag: u64,
+}
+
+impl Default for WriteAckPolicy {
+    fn default() -> Self {
+        Self {
+            mode: WriteAckMode::All,
+            max_lag: 64,
+        }
+    }
+}
+
+/// Asynchronous write statistics of a child.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ChildLagStats {
+    /// Number of asynchronous writes in flight, i.e. the current lag.
+    pub in_flight: u64,
+    /// Highest lag observed.
+    pub max_in_flight: u64,
+    /// Number of asynchronous writes submitted.
+    pub submitted: u64,
+    /// Number of asynchronous writes which failed.
+    pub failed: u64,
+    /// Latency of the last completed asynchronous write, in microseconds.
+    pub last_latency_us: u64,
+    /// Whether an asynchronous write failed and the child has not been
+    /// disconnected from the channels yet.
+    pub stale: bool,
+}
+
+/// Asynchronous write counters of a child, updated from all cores.
+#[derive(Default)]
+pub(crate) struct ChildLag {
+    in_flight: AtomicU64,
+    max_in_flight: AtomicU64,
+    submitted: AtomicU64,
+    failed: AtomicU64,
+    last_latency_us: AtomicU64,
+    stale: AtomicBool,
+}
+
+impl ChildLag {
+    fn stats(&self) -> ChildLagStats {
+        ChildLagStats {
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            max_in_flight: self.max_in_flight.load(Ordering::Relaxed),
+            submitted: self.submitted.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            last_latency_us: self.last_latency_us.load(Ordering::Relaxed),
+            stale: self.stale.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Tracks the asynchronous writes in flight to each child. The counters of a
+/// child are atomics, held by its writes, and the lock over the children is
+/// only taken for writing when a child is first written to or forgotten.
+#[derive(Default)]
+pub(crate) struct WriteLagTracker {
+    children: parking_lot::RwLock<HashMap<String, Arc<ChildLag>>>,
+    /// Number of asynchronous writes in flight to all children, to check
+    /// for lagging children without taking the lock.
+    in_flight: AtomicU64,
+    /// Number of children marked stale.
+    stale: AtomicU64,
+}
+
+impl WriteLagTracker {
+    /// Returns the lag of the given child device, which is the maximum for
+    /// a stale child.
+    pub(crate) fn lag(&self, device: &str) -> u64 {
+        match self.children.read().get(device) {
+            Some(lag) if lag.stale.load(Ordering::Acquire) => u64::MAX,
+            Some(lag) => lag.in_flight.load(Ordering::Acquire),
+            None => 0,
+        }
+    }
+
+    /// Returns the number of asynchronous writes in flight to all children.
+    #[inline(always)]
+    pub(crate) fn in_flight(&self) -> u64 {
+        self.in_flight.load(Ordering::Acquire)
+    }
+
+    /// Returns true if a child may be behind the others, either because
+    /// asynchronous writes are in flight or because a child is stale.
+    #[inline(always)]
+    pub(crate) fn lagging(&self) -> bool {
+        self.in_flight() > 0 || self.stale.load(Ordering::Acquire) > 0
+    }
+
+    /// Returns the counters of the given child device.
+    fn child(&self, device: &str) -> Arc<ChildLag> {
+        if let Some(lag) = self.children.read().get(device) {
+            return lag.clone();
+        }
+        self.children
+            .write()
+            .entry(device.to_string())
+            .or_default()
+            .clone()
+    }
+
+    fn submitted(&self, device: &str) -> Arc<ChildLag> {
+        let lag = self.child(device);
+        self.in_flight.fetch_add(1, Ordering::AcqRel);
+        let in_flight = lag.in_flight.fetch_add(1, Ordering::AcqRel) + 1;
+        lag.submitted.fetch_add(1, Ordering::Relaxed);
+        lag.max_in_flight.fetch_max(in_flight, Ordering::Relaxed);
+        lag
+    }
+
+    fn completed(&self, lag: &ChildLag, success: bool, latency: Duration) {
+        if !success {
+            lag.failed.fetch_add(1, Ordering::Relaxed);
+        }
+        lag.last_latency_us
+            .store(latency.as_micros() as u64, Ordering::Relaxed);
+        lag.in_flight.fetch_sub(1, Ordering::AcqRel);
+        self.in_flight.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    /// Mark the child stale, keeping reads away from it.
+    fn mark_stale(&self, lag: &ChildLag) {
+        if !lag.stale.swap(true, Ordering::AcqRel) {
+            self.stale.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+
+    /// Forget the given child device, once it has been disconnected from the
+    /// channels.
+    pub(crate) fn forget(&self, device: &str) {
+        if let Some(lag) = self.children.write().remove(device) {
+            if lag.stale.load(Ordering::Acquire) {
+                self.stale.fetch_sub(1, Ordering::AcqRel);
+            }
+        }
+    }
+
+    /// Returns the statistics of all children.
+    pub(crate) fn stats(&self) -> HashMap<String, ChildLagStats> {
+        self.children
+            .read()
+            .iter()
+            .map(|(device, lag)| (device.clone(), lag.stats()))
+            .collect()
+    }
+
+    /// Wait for all asynchronous writes to complete.
+    pub(crate) async fn drain(&self) {
+        while self.in_flight() > 0 {
+            mayastor_sleep(Duration::from_millis(1)).await.ok();
+        }
+    }
+}
+
+/// Write acknowledgement policy of a nexus, along with the lag of its
+/// children.
+pub(crate) struct WriteAck {
+    policy: AtomicCell<WriteAckPolicy>,
+    pub(crate) tracker: Arc<WriteLagTracker>,
+}
+
+impl WriteAck {
+    pub(crate) fn new() -> Self {
+        Self {
+            policy: AtomicCell::new(WriteAckPolicy::default()),
+            tracker: Arc::new(WriteLagTracker::default()),
+        }
+    }
+
+    /// Returns the current policy.
+    pub(crate) fn policy(&self) -> WriteAckPolicy {
+        self.policy.load()
+    }
+
+    /// Replace the policy.
+    pub(crate) fn set_policy(&self, policy: WriteAckPolicy) {
+        self.policy.store(policy);
+    }
+
+    /// Returns the children devices a write may be completed asynchronously
+    /// on, given the devices it is submitted to along with their locality.
+    /// The local child and the remote child with the lowest lag are written
+    /// synchronously, as well as the remote children which reached the
+    /// maximum lag.
+    pub(super) fn async_devices(
+        &self,
+        devices: &[(String, bool)],
+    ) -> Vec<String> {
+        let policy = self.policy();
+        if policy.mode != WriteAckMode::LocalQuorum
+            || !devices.iter().any(|(_, local)| *local)
+        {
+            return Vec::new();
+        }
+
+        let mut remotes = devices
+            .iter()
+            .filter(|(_, local)| !*local)
+            .map(|(device, _)| (self.tracker.lag(device), device))
+            .collect::<Vec<_>>();
+        remotes.sort();
+
+        remotes
+            .into_iter()
+            .skip(1)
+            .filter(|(lag, _)| *lag < policy.max_lag)
+            .map(|(_, device)| device.clone())
+            .collect()
+    }
+}
+
+impl<'n> Nexus<'n> {
+    /// Returns the write acknowledgement policy of the nexus.
+    pub fn write_ack_policy(&self) -> WriteAckPolicy {
+        self.write_ack.policy()
+    }
+
+    /// Replace the write acknowledgement policy of the nexus.
+    pub fn set_write_ack_policy(&self, policy: WriteAckPolicy) {
+        info!("{:?}: setting write ack policy: {:?}", self, policy);
+        self.write_ack.set_policy(policy);
+    }
+
+    /// Returns the asynchronous write statistics of the nexus, per child
+    /// device.
+    pub fn write_ack_stats(&self) -> HashMap<String, ChildLagStats> {
+        self.write_ack.tracker.stats()
+    }
+}
+
+/// Context of an asynchronous child write.
+pub(super) struct AsyncWrite {
+    nexus: String,
+    device: String,
+    iov: libc::iovec,
+    /// Copy of the data, referenced by `iov`.
+    _buf: DmaBuf,
+    tracker: Arc<WriteLagTracker>,
+    lag: Arc<ChildLag>,
+    start: Instant,
+}
+
+impl AsyncWrite {
+    /// Returns a new asynchronous write of the given data.
+    pub(super) fn new(nexus: &Nexus, device: String, buf: DmaBuf) -> Box<Self> {
+        let iov = libc::iovec {
+            iov_base: *buf,
+            iov_len: buf.len() as usize,
+        };
+        let tracker = nexus.write_ack.tracker.clone();
+        let lag = tracker.submitted(&device);
+        Box::new(Self {
+            nexus: nexus.name.clone(),
+            device,
+            iov,
+            _buf: buf,
+            tracker,
+            lag,
+            start: Instant::now(),
+        })
+    }
+
+    /// Returns the I/O vector of the write, valid as long as the write.
+    pub(super) fn iov(&mut self) -> *mut IoVec {
+        &mut self.iov as *mut libc::iovec as *mut IoVec
+    }
+
+    /// Account for a write which could not be submitted.
+    pub(super) fn submission_failed(self: Box<Self>) {
+        self.tracker
+            .completed(&self.lag, false, self.start.elapsed());
+    }
+
+    /// Completion callback of an asynchronous child write.
+    pub(super) fn completion(
+        _device: &dyn BlockDevice,
+        status: IoCompletionStatus,
+        ctx: *mut libc::c_void,
+    ) {
+        let write = unsafe { Box::from_raw(ctx as *mut AsyncWrite) };
+        let success = status == IoCompletionStatus::Success;
+        if !success {
+            // the write has already been acknowledged, so the child is out
+            // of sync: it is marked stale before its lag drops, and retired
+            write.tracker.mark_stale(&write.lag);
+        }
+        write
+            .tracker
+            .completed(&write.lag, success, write.start.elapsed());
+
+        if !success {
+            error!(
+                "Nexus '{}': asynchronous write to '{}' failed: {:?}",
+                write.nexus, write.device, status
+            );
+            Reactors::master().send_future(async move {
+                if let Some(nexus) = nexus_lookup_mut(&write.nexus) {
+                    nexus.retire_child_device(
+                        &write.device,
+                        FaultDetail::io_error(status),
+                        true,
+                    );
+                }
+            });
+        }
+    }
+}
+
+/// Arguments of the `nexus_set_write_ack` json-rpc method, the fields which
+/// are not given are left unchanged.
+#[derive(Debug, Deserialize)]
+struct SetWriteAckArgs {
+    /// Name of the nexus.
+    name: String,
+    mode: Option<WriteAckMode>,
+    max_lag: Option<u64>,
+}
+
+/// Arguments of the `nexus_get_write_ack` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct GetWriteAckArgs {
+    /// Name of the nexus.
+    name: String,
+}
+
+/// Reply of the write acknowledgement json-rpc methods.
+#[derive(Debug, Serialize)]
+struct WriteAckReply {
+    name: String,
+    policy: WriteAckPolicy,
+    /// Asynchronous write statistics, per child device.
+    children: HashMap<String, ChildLagStats>,
+}
+
+impl WriteAckReply {
+    fn new(nexus: &Nexus) -> Self {
+        Self {
+            name: nexus.name.clone(),
+            policy: nexus.write_ack.policy(),
+            children: nexus.write_ack.tracker.stats(),
+        }
+    }
+}
+
+/// Register the write acknowledgement json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nexus_set_write_ack", |args: SetWriteAckArgs| {
+        async move {
+            let nexus =
+                nexus_lookup(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+
+            let current = nexus.write_ack.policy();
+            let policy = WriteAckPolicy {
+                mode: args.mode.unwrap_or(current.mode),
+                max_lag: args.max_lag.unwrap_or(current.max_lag),
+            };
+
+            nexus.set_write_ack_policy(policy);
+            Ok(WriteAckReply::new(nexus))
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_get_write_ack", |args: GetWriteAckArgs| {
+        async move {
+            nexus_lookup(&args.name).map(WriteAckReply::new).ok_or(
+                Error::NexusNotFound {
+                    name: args.name,
+                },
+            )
+        }
+        .boxed_local()
+    });
+}
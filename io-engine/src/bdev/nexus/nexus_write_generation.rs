@@ -0,0 +1,293 @@
+//! Write generation of a volume.
+//!
+//! A child added to a nexus is rebuilt from the healthy children, even when
+//! the volume has never been written and the rebuild has nothing to copy.
+//! To tell these volumes apart, the metadata region of the nexus holds a
+//! write generation: 0 for a volume which has never been written, and
+//! increased by each nexus which writes to the volume before its first write
+//! is submitted. The writes are held until the increased generation has been
+//! written to the children, so that a volume of generation 0 is known not to
+//! hold any data, even after a crash.
+//!
+//! When the null rebuild policy of the nexus is enabled, a child added to a
+//! nexus whose volume has never been written is brought online right away
+//! rather than rebuilt. The addition and the first write are serialised, so
+//! that the first write is submitted to the added child as well. The null
+//! rebuilds are rate-limited: past the maximum number of null rebuilds in a
+//! minute, the added children are rebuilt as usual, so that a control plane
+//! cycling the children of a volume does not churn its redundancy.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crossbeam::atomic::AtomicCell;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    nexus_checksum::YieldNow,
+    nexus_lookup,
+    nexus_persistence::PersistOp,
+    ChildState,
+    DrEvent,
+    Error,
+    Nexus,
+};
+use crate::jsonrpc::jsonrpc_register;
+
+/// Window of the rate limit of the null rebuilds.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Null rebuild policy of a nexus.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct NullRebuildPolicy {
+    /// Children added to a volume which has never been written are brought
+    /// online without a rebuild.
+    pub enabled: bool,
+    /// Maximum number of null rebuilds in a minute.
+    pub max_per_minute: u32,
+}
+
+impl Default for NullRebuildPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_per_minute: 8,
+        }
+    }
+}
+
+/// Write generation of a nexus.
+pub(crate) struct WriteGeneration {
+    /// The generation has been increased by this nexus, writes go through.
+    written: AtomicCell<bool>,
+    /// Serialises the increase of the generation with the additions of
+    /// children without a rebuild.
+    update: parking_lot::Mutex<()>,
+    policy: AtomicCell<NullRebuildPolicy>,
+    /// Times of the null rebuilds of the last minute.
+    recent: parking_lot::Mutex<VecDeque<Instant>>,
+}
+
+impl WriteGeneration {
+    pub(crate) fn new() -> Self {
+        Self {
+            written: AtomicCell::new(false),
+            update: parking_lot::Mutex::new(()),
+            policy: AtomicCell::new(NullRebuildPolicy::default()),
+            recent: parking_lot::Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Returns the current null rebuild policy.
+    pub(crate) fn policy(&self) -> NullRebuildPolicy {
+        self.policy.load()
+    }
+
+    /// Replace the null rebuild policy.
+    pub(crate) fn set_policy(&self, policy: NullRebuildPolicy) {
+        self.policy.store(policy);
+    }
+
+    /// Account a null rebuild, returns false if the policy does not allow
+    /// it.
+    fn null_rebuild_allowed(&self) -> bool {
+        let policy = self.policy();
+        if !policy.enabled {
+            return false;
+        }
+        let now = Instant::now();
+        let mut recent = self.recent.lock();
+        while recent
+            .front()
+            .map_or(false, |t| now.duration_since(*t) >= RATE_WINDOW)
+        {
+            recent.pop_front();
+        }
+        if recent.len() >= policy.max_per_minute as usize {
+            return false;
+        }
+        recent.push_back(now);
+        true
+    }
+
+    /// Returns true if the nexus has increased the generation, and so its
+    /// writes are submitted right away.
+    #[inline(always)]
+    pub(super) fn is_written(&self) -> bool {
+        self.written.load()
+    }
+
+    /// Lock the updates of the generation.
+    async fn lock(&self) -> parking_lot::MutexGuard<'_, ()> {
+        loop {
+            if let Some(guard) = self.update.try_lock() {
+                return guard;
+            }
+            YieldNow(false).await;
+        }
+    }
+}
+
+impl<'n> Nexus<'n> {
+    /// Returns true if the volume of the nexus is known to have never been
+    /// written.
+    pub fn never_written(&self) -> bool {
+        !self.write_generation.is_written()
+            && self.metadata_writable()
+            && self.metadata().write_generation == 0
+    }
+
+    /// Replace the null rebuild policy of the nexus.
+    pub fn set_null_rebuild_policy(&self, policy: NullRebuildPolicy) {
+        info!("{:?}: setting null rebuild policy: {:?}", self, policy);
+        self.write_generation.set_policy(policy);
+    }
+
+    /// Increase the write generation of the volume, before the first write
+    /// of the nexus is submitted.
+    pub(super) async fn write_generation_begin(&self) -> Result<(), Error> {
+        let _guard = self.write_generation.lock().await;
+        if self.write_generation.is_written() {
+            return Ok(());
+        }
+
+        match self.update_metadata(|m| m.write_generation += 1).await {
+            Ok(metadata) => info!(
+                "{:?}: first write, write generation {}",
+                self, metadata.write_generation
+            ),
+            // a region of a newer layout is left as it is, and the volume is
+            // then never taken for an empty one
+            Err(error) if !self.metadata_writable() => {
+                warn!("{:?}: write generation not recorded: {}", self, error)
+            }
+            Err(error) => return Err(error),
+        }
+        self.write_generation.written.store(true);
+        Ok(())
+    }
+
+    /// Bring a child which has just been added online without rebuilding
+    /// it, if the volume has never been written and the null rebuild policy
+    /// allows it. Returns true if it has been.
+    pub(super) async fn null_rebuild(
+        &self,
+        child_uri: &str,
+    ) -> Result<bool, Error> {
+        if !self.write_generation.policy().enabled {
+            return Ok(false);
+        }
+        let _guard = self.write_generation.lock().await;
+        if !self.never_written() {
+            return Ok(false);
+        }
+        if !self.write_generation.null_rebuild_allowed() {
+            info!(
+                "{:?}: child '{}' is rebuilt, past the maximum number of null \
+                rebuilds in a minute",
+                self, child_uri
+            );
+            return Ok(false);
+        }
+
+        let child = self.lookup_child(child_uri).ok_or_else(|| {
+            Error::ChildNotFound {
+                child: child_uri.to_owned(),
+                name: self.name.clone(),
+            }
+        })?;
+
+        // the metadata reservation is prepared as for a rebuild
+        self.checksum_prepare_child(child_uri).await?;
+        self.write_intent_prepare_child(child_uri).await?;
+        self.metadata_prepare_child(child_uri).await?;
+
+        child.transition(ChildState::Open, "volume never written");
+        info!(
+            "{:?}: child '{}' online without a rebuild, the volume has \
+            never been written",
+            self, child_uri
+        );
+        self.reconfigure(DrEvent::ChildRebuild).await;
+        self.persist(PersistOp::Update {
+            child_uri: child_uri.to_owned(),
+            child_state: child.state(),
+        })
+        .await;
+        Ok(true)
+    }
+}
+
+/// Arguments of the `nexus_set_null_rebuild` json-rpc method, the fields
+/// which are not given are left unchanged.
+#[derive(Debug, Deserialize)]
+struct SetNullRebuildArgs {
+    /// Name of the nexus.
+    name: String,
+    enabled: Option<bool>,
+    max_per_minute: Option<u32>,
+}
+
+/// Arguments of the `nexus_get_null_rebuild` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct GetNullRebuildArgs {
+    /// Name of the nexus.
+    name: String,
+}
+
+/// Reply of the null rebuild json-rpc methods.
+#[derive(Debug, Serialize)]
+struct NullRebuildReply {
+    name: String,
+    policy: NullRebuildPolicy,
+    /// Write generation of the volume, as recorded in the metadata region.
+    write_generation: u64,
+    never_written: bool,
+}
+
+impl NullRebuildReply {
+    fn new(nexus: &Nexus) -> Self {
+        Self {
+            name: nexus.name.clone(),
+            policy: nexus.write_generation.policy(),
+            write_generation: nexus.metadata().write_generation,
+            never_written: nexus.never_written(),
+        }
+    }
+}
+
+/// Register the null rebuild json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nexus_set_null_rebuild", |args: SetNullRebuildArgs| {
+        async move {
+            let nexus =
+                nexus_lookup(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+
+            let current = nexus.write_generation.policy();
+            nexus.set_null_rebuild_policy(NullRebuildPolicy {
+                enabled: args.enabled.unwrap_or(current.enabled),
+                max_per_minute: args
+                    .max_per_minute
+                    .unwrap_or(current.max_per_minute),
+            });
+            Ok(NullRebuildReply::new(nexus))
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_get_null_rebuild", |args: GetNullRebuildArgs| {
+        async move {
+            nexus_lookup(&args.name).map(NullRebuildReply::new).ok_or(
+                Error::NexusNotFound {
+                    name: args.name,
+                },
+            )
+        }
+        .boxed_local()
+    });
+}
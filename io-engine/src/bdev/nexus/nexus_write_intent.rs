@@ -0,0 +1,949 @@
+//! Write-intent log.
+//!
+//! When enabled on a nexus, the regions of the nexus being written are
+//! marked dirty in a bitmap kept on each child before the writes are
+//! submitted, much like the write-intent bitmap of md-raid. Should the nexus
+//! node crash, the children can only differ in the regions marked dirty, so
+//! that once the nexus is created again only those regions are compared and
+//! resynchronised between the children, instead of the whole volume.
+//!
+//! A region is logged dirty on the first write to it. Regions are logged
+//! clean lazily, by a background pass clearing the regions which have not
+//! been written since the previous pass, so that a region written
+//! continuously costs a single update of the log. All the regions are logged
+//! clean when the nexus is destroyed.
+//!
+//! The log is kept in the metadata reservation of each child, ahead of the
+//! data partition:
+//!
+//! log          ───── header: magic, region size and number of regions
+//! log + 1      ──┐
+//!                ├── 1 bit per region, set if the region may be dirty
+//! log + N      ──┘
+//!
+//! The log is looked for on the children when the nexus is opened: the
+//! dirty regions are resynchronised and the log stays enabled.
+
+use std::{
+    convert::TryInto,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crossbeam::atomic::AtomicCell;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    nexus_checksum::YieldNow,
+    nexus_lookup,
+    nexus_lookup_mut,
+    Error,
+    Nexus,
+    NexusChannel,
+};
+use crate::{
+    core::{
+        partition::{bytes_to_alinged_blocks, METADATA_RESERVATION_OFFSET},
+        BlockDeviceHandle,
+        CoreError,
+        Reactors,
+    },
+    jsonrpc::jsonrpc_register,
+    sleep::mayastor_sleep,
+};
+
+/// Magic of the log header.
+const MAGIC: &[u8; 8] = b"MAYAWINT";
+
+/// Size of the log, header included, within the metadata reservation.
+pub(super) const LOG_SIZE: u64 = 1024 * 1024;
+
+/// Default size of a region, in bytes.
+pub(super) const DEFAULT_REGION_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Interval of the passes logging idle regions clean.
+const CLEAR_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Number of regions per bitmap word.
+pub(super) const WORD_BITS: u64 = 64;
+
+/// Size of a bitmap word, in bytes.
+const WORD_SIZE: usize = 8;
+
+/// Geometry of the log, as recorded in its header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Geometry {
+    /// Size of a region, in bytes.
+    region_size: u64,
+    /// Number of regions of the nexus.
+    regions: u64,
+}
+
+impl Geometry {
+    /// Returns the geometry of the log of a nexus of the given size, with
+    /// regions of at least the given size. The regions are made larger for
+    /// the bitmap to fit in the log.
+    fn new(region_size: u64, num_blocks: u64, block_len: u64) -> Self {
+        let size = num_blocks * block_len;
+        let max_regions = (LOG_SIZE - block_len) * 8;
+        let mut region_size = region_size;
+        while (size + region_size - 1) / region_size > max_regions {
+            region_size *= 2;
+        }
+        Self {
+            region_size,
+            regions: (size + region_size - 1) / region_size,
+        }
+    }
+
+    fn words(&self) -> usize {
+        ((self.regions + WORD_BITS - 1) / WORD_BITS) as usize
+    }
+
+    fn write_header(&self, block: &mut [u8]) {
+        block[0 .. 8].copy_from_slice(MAGIC);
+        block[8 .. 16].copy_from_slice(&self.region_size.to_le_bytes());
+        block[16 .. 24].copy_from_slice(&self.regions.to_le_bytes());
+    }
+
+    fn read_header(block: &[u8]) -> Option<Self> {
+        if &block[0 .. 8] != MAGIC {
+            return None;
+        }
+        let geometry = Self {
+            region_size: u64::from_le_bytes(block[8 .. 16].try_into().unwrap()),
+            regions: u64::from_le_bytes(block[16 .. 24].try_into().unwrap()),
+        };
+        if geometry.region_size == 0 || geometry.regions == 0 {
+            return None;
+        }
+        Some(geometry)
+    }
+}
+
+/// Write-intent log statistics of a nexus.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct WriteIntentStats {
+    pub enabled: bool,
+    /// Size of a region, in bytes.
+    pub region_size: u64,
+    pub regions: u64,
+    /// Number of regions logged dirty on the children.
+    pub dirty_regions: u64,
+    /// Number of updates of the log on the children.
+    pub log_updates: u64,
+    /// Number of regions resynchronised when the nexus was opened.
+    pub resynced_regions: u64,
+}
+
+/// In-memory state of the regions of an enabled log.
+struct Regions {
+    geometry: Geometry,
+    /// Size of a region, in blocks.
+    region_blocks: u64,
+    /// Number of writes in flight, per region.
+    in_flight: Vec<AtomicU32>,
+    /// Regions with writes in flight or written recently.
+    dirty: Vec<AtomicU64>,
+    /// Regions logged dirty on the children. A region is cleared here before
+    /// it is logged clean, so that writes to it wait for the log update.
+    logged: Vec<AtomicU64>,
+    /// Regions written since the last clearing pass.
+    touched: Vec<AtomicU64>,
+    /// Serialises the updates of the log; only ever tried, so that the
+    /// futures waiting for it stay on their core.
+    update: parking_lot::Mutex<()>,
+}
+
+impl Regions {
+    fn new(geometry: Geometry, block_len: u64) -> Self {
+        let words = geometry.words();
+        let atomics = |n| (0 .. n).map(|_| AtomicU64::new(0)).collect();
+        Self {
+            geometry,
+            region_blocks: geometry.region_size / block_len,
+            in_flight: (0 .. geometry.regions)
+                .map(|_| AtomicU32::new(0))
+                .collect(),
+            dirty: atomics(words),
+            logged: atomics(words),
+            touched: atomics(words),
+            update: parking_lot::Mutex::new(()),
+        }
+    }
+
+    /// Returns the regions of the given blocks.
+    fn range(
+        &self,
+        offset: u64,
+        num_blocks: u64,
+    ) -> std::ops::RangeInclusive<u64> {
+        let last = (offset + num_blocks.max(1) - 1) / self.region_blocks;
+        offset / self.region_blocks ..= last.min(self.geometry.regions - 1)
+    }
+
+    /// Account a write to the given blocks. Returns true if their regions
+    /// are logged dirty on the children already.
+    fn begin(&self, offset: u64, num_blocks: u64) -> bool {
+        let mut logged = true;
+        for region in self.range(offset, num_blocks) {
+            let (word, bit) = region_bit(region);
+            self.in_flight[region as usize].fetch_add(1, Ordering::SeqCst);
+            self.touched[word].fetch_or(bit, Ordering::SeqCst);
+            self.dirty[word].fetch_or(bit, Ordering::SeqCst);
+            logged &= self.logged[word].load(Ordering::SeqCst) & bit != 0;
+        }
+        logged
+    }
+
+    /// Account the completion of a write to the given blocks.
+    fn end(&self, offset: u64, num_blocks: u64) {
+        for region in self.range(offset, num_blocks) {
+            self.in_flight[region as usize].fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Lock the updates of the log.
+    async fn lock(&self) -> parking_lot::MutexGuard<'_, ()> {
+        loop {
+            if let Some(guard) = self.update.try_lock() {
+                return guard;
+            }
+            YieldNow(false).await;
+        }
+    }
+
+    /// Returns the dirty regions, along with the range of words which differ
+    /// from the regions logged on the children.
+    fn changes(&self) -> Option<(Vec<u64>, usize, usize)> {
+        let words = self
+            .dirty
+            .iter()
+            .map(|w| w.load(Ordering::SeqCst))
+            .collect::<Vec<_>>();
+        let differs =
+            |i: &usize| words[*i] != self.logged[*i].load(Ordering::SeqCst);
+        let first = (0 .. words.len()).find(differs)?;
+        let last = (0 .. words.len()).rev().find(differs)?;
+        Some((words, first, last))
+    }
+
+    /// Record the given words as logged on the children.
+    fn set_logged(&self, words: &[u64], first: usize, last: usize) {
+        for i in first ..= last {
+            self.logged[i].store(words[i], Ordering::SeqCst);
+        }
+    }
+
+    /// Clear the dirty regions without writes in flight, which have not been
+    /// written since the previous pass unless forced. Returns the range of
+    /// words cleared.
+    fn clear_idle(&self, force: bool) -> Option<(usize, usize)> {
+        let mut cleared = None;
+        for i in 0 .. self.dirty.len() {
+            let touched = self.touched[i].swap(0, Ordering::SeqCst);
+            let mut idle = self.dirty[i].load(Ordering::SeqCst);
+            if !force {
+                idle &= !touched;
+            }
+            if idle == 0 {
+                continue;
+            }
+
+            for b in 0 .. WORD_BITS {
+                let bit = 1 << b;
+                if idle & bit == 0 {
+                    continue;
+                }
+                // writes beginning from now on wait for the log update
+                self.logged[i].fetch_and(!bit, Ordering::SeqCst);
+                self.dirty[i].fetch_and(!bit, Ordering::SeqCst);
+                let region = i as u64 * WORD_BITS + b;
+                if self.in_flight[region as usize].load(Ordering::SeqCst) > 0 {
+                    self.dirty[i].fetch_or(bit, Ordering::SeqCst);
+                    self.logged[i].fetch_or(bit, Ordering::SeqCst);
+                }
+            }
+            cleared = Some(cleared.map_or((i, i), |(first, _)| (first, i)));
+        }
+        cleared
+    }
+
+    fn dirty_regions(&self) -> u64 {
+        self.logged
+            .iter()
+            .map(|w| w.load(Ordering::SeqCst).count_ones() as u64)
+            .sum()
+    }
+}
+
+/// Returns the word and the bit of a region in the bitmap.
+#[inline(always)]
+pub(super) fn region_bit(region: u64) -> (usize, u64) {
+    ((region / WORD_BITS) as usize, 1 << (region % WORD_BITS))
+}
+
+/// Write-intent log of a nexus.
+pub(crate) struct WriteIntentLog {
+    enabled: AtomicCell<bool>,
+    regions: parking_lot::RwLock<Option<Arc<Regions>>>,
+    /// Incremented whenever the log is enabled, so that the clearing pass of
+    /// a previous enable stops.
+    epoch: AtomicCell<u64>,
+    log_updates: AtomicCell<u64>,
+    resynced_regions: AtomicCell<u64>,
+}
+
+impl WriteIntentLog {
+    pub(crate) fn new() -> Self {
+        Self {
+            enabled: AtomicCell::new(false),
+            regions: parking_lot::RwLock::new(None),
+            epoch: AtomicCell::new(0),
+            log_updates: AtomicCell::new(0),
+            resynced_regions: AtomicCell::new(0),
+        }
+    }
+
+    /// Returns true if the write-intent log is enabled.
+    #[inline(always)]
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled.load()
+    }
+
+    fn regions(&self) -> Option<Arc<Regions>> {
+        if !self.is_enabled() {
+            return None;
+        }
+        self.regions.read().clone()
+    }
+
+    /// Account a write to the given blocks. Returns true if their regions
+    /// are logged dirty on the children already, otherwise the write must
+    /// wait for the log to be updated.
+    pub(super) fn begin(&self, offset: u64, num_blocks: u64) -> bool {
+        self.regions().map_or(true, |r| r.begin(offset, num_blocks))
+    }
+
+    /// Account the completion of a write to the given blocks.
+    pub(super) fn end(&self, offset: u64, num_blocks: u64) {
+        if let Some(regions) = self.regions() {
+            regions.end(offset, num_blocks);
+        }
+    }
+
+    /// Returns the statistics of the log.
+    pub(crate) fn stats(&self) -> WriteIntentStats {
+        let mut stats = WriteIntentStats {
+            log_updates: self.log_updates.load(),
+            resynced_regions: self.resynced_regions.load(),
+            ..Default::default()
+        };
+        if let Some(regions) = self.regions() {
+            stats.enabled = true;
+            stats.region_size = regions.geometry.region_size;
+            stats.regions = regions.geometry.regions;
+            stats.dirty_regions = regions.dirty_regions();
+        }
+        stats
+    }
+
+    fn install(&self, regions: Option<Regions>) {
+        self.enabled.store(false);
+        *self.regions.write() = regions.map(Arc::new);
+        if self.regions.read().is_some() {
+            self.epoch.fetch_add(1);
+            self.enabled.store(true);
+        }
+    }
+}
+
+/// First block of the log on the children.
+fn log_block(block_len: u64) -> u64 {
+    METADATA_RESERVATION_OFFSET / block_len
+}
+
+/// Number of blocks of the bitmap of the log.
+fn bitmap_blocks(geometry: &Geometry, block_len: u64) -> u64 {
+    bytes_to_alinged_blocks(
+        geometry.words() as u64 * WORD_SIZE as u64,
+        block_len,
+    )
+}
+
+/// Allocates a zeroed DMA buffer.
+pub(super) fn dma_buf(
+    hdl: &dyn BlockDeviceHandle,
+    size: u64,
+) -> Result<spdk_rs::DmaBuf, CoreError> {
+    let mut buf =
+        hdl.dma_malloc(size)
+            .map_err(|_| CoreError::DmaAllocationFailed {
+                size,
+            })?;
+    buf.fill(0);
+    Ok(buf)
+}
+
+/// Writes the given words of the bitmap to a child.
+async fn write_words(
+    hdl: &dyn BlockDeviceHandle,
+    words: &[u64],
+    first: usize,
+    last: usize,
+) -> Result<(), CoreError> {
+    let block_len = hdl.get_device().block_len();
+    let words_per_block = block_len as usize / WORD_SIZE;
+    let first_block = first / words_per_block;
+    let last_block = last / words_per_block;
+
+    let mut buf =
+        dma_buf(hdl, (last_block - first_block + 1) as u64 * block_len)?;
+    let start = first_block * words_per_block;
+    for (c, w) in buf
+        .as_mut_slice()
+        .chunks_mut(WORD_SIZE)
+        .zip(words.iter().skip(start))
+    {
+        c.copy_from_slice(&w.to_le_bytes());
+    }
+
+    let offset = (log_block(block_len) + 1 + first_block as u64) * block_len;
+    hdl.write_at(offset, &buf).await?;
+    Ok(())
+}
+
+/// Writes the log with the given bitmap to a child: the bitmap is written
+/// before the header, so that a log with a valid header never holds a stale
+/// bitmap.
+async fn write_log(
+    hdl: &dyn BlockDeviceHandle,
+    geometry: &Geometry,
+    words: &[u64],
+) -> Result<(), CoreError> {
+    let block_len = hdl.get_device().block_len();
+    let mut header = dma_buf(hdl, block_len)?;
+    hdl.write_at(log_block(block_len) * block_len, &header)
+        .await?;
+    write_words(hdl, words, 0, words.len() - 1).await?;
+    geometry.write_header(header.as_mut_slice());
+    hdl.write_at(log_block(block_len) * block_len, &header)
+        .await?;
+    Ok(())
+}
+
+/// Reads the log of a child, if it has one.
+async fn read_log(
+    hdl: &dyn BlockDeviceHandle,
+) -> Result<Option<(Geometry, Vec<u64>)>, CoreError> {
+    let block_len = hdl.get_device().block_len();
+    let mut header = dma_buf(hdl, block_len)?;
+    hdl.read_at(log_block(block_len) * block_len, &mut header)
+        .await?;
+    let geometry = match Geometry::read_header(header.as_slice()) {
+        Some(geometry) => geometry,
+        None => return Ok(None),
+    };
+    if geometry.regions > (LOG_SIZE - block_len) * 8 {
+        return Ok(None);
+    }
+
+    let mut buf =
+        dma_buf(hdl, bitmap_blocks(&geometry, block_len) * block_len)?;
+    hdl.read_at((log_block(block_len) + 1) * block_len, &mut buf)
+        .await?;
+    let words = buf.as_slice()[.. geometry.words() * WORD_SIZE]
+        .chunks(WORD_SIZE)
+        .map(|c| u64::from_le_bytes(c.try_into().unwrap()))
+        .collect();
+    Ok(Some((geometry, words)))
+}
+
+impl<'n> Nexus<'n> {
+    /// Enable the write-intent log of the nexus, with regions of at least
+    /// the given size. The nexus is paused while the log is written to the
+    /// children.
+    pub async fn write_intent_enable(
+        mut self: Pin<&mut Self>,
+        region_size: Option<u64>,
+    ) -> Result<(), Error> {
+        let block_len = self.block_len();
+        let region_size = region_size.unwrap_or(DEFAULT_REGION_SIZE);
+        if region_size == 0 || region_size % block_len != 0 {
+            return Err(Error::InvalidArguments {
+                name: self.name.clone(),
+                args: format!(
+                    "region size {} is not a multiple of the block size {}",
+                    region_size, block_len
+                ),
+            });
+        }
+
+        let geometry = Geometry::new(region_size, self.num_blocks(), block_len);
+        if self
+            .write_intent
+            .regions()
+            .map_or(false, |r| r.geometry == geometry)
+        {
+            return Ok(());
+        }
+
+        let end =
+            log_block(block_len) + 1 + bitmap_blocks(&geometry, block_len);
+        if end > self.data_ent_offset {
+            return Err(Error::WriteIntentLog {
+                name: self.name.clone(),
+                child: String::new(),
+                reason: format!(
+                    "the log needs {} blocks, the data starts at block {}",
+                    end, self.data_ent_offset
+                ),
+            });
+        }
+
+        self.as_mut().pause().await?;
+
+        let words = vec![0; geometry.words()];
+        let mut result = Ok(());
+        for child in self.children_iter().filter(|c| c.is_healthy()) {
+            let written = match child.get_io_handle() {
+                Ok(hdl) => write_log(&*hdl, &geometry, &words).await,
+                Err(error) => Err(error),
+            };
+            if let Err(error) = written {
+                result = Err(Error::WriteIntentLog {
+                    child: child.uri().to_owned(),
+                    name: self.name.clone(),
+                    reason: error.to_string(),
+                });
+                break;
+            }
+        }
+
+        if result.is_ok() {
+            info!(
+                "{:?}: enabling the write-intent log, {} regions of {} bytes",
+                self, geometry.regions, geometry.region_size
+            );
+            self.write_intent
+                .install(Some(Regions::new(geometry, block_len)));
+            start_clearing(self.name.clone(), self.write_intent.epoch.load());
+        }
+
+        self.as_mut().resume().await?;
+        result
+    }
+
+    /// Disable the write-intent log of the nexus. The log is invalidated on
+    /// the children, so that it is not enabled again when the nexus is
+    /// opened.
+    pub async fn write_intent_disable(
+        mut self: Pin<&mut Self>,
+    ) -> Result<(), Error> {
+        if !self.write_intent.is_enabled() {
+            return Ok(());
+        }
+
+        self.as_mut().pause().await?;
+        info!("{:?}: disabling the write-intent log", self);
+        self.write_intent.install(None);
+
+        for child in self.children_iter().filter(|c| c.is_healthy()) {
+            let invalidated = match child.get_io_handle() {
+                Ok(hdl) => {
+                    let block_len = hdl.get_device().block_len();
+                    match dma_buf(&*hdl, block_len) {
+                        Ok(header) => hdl
+                            .write_at(log_block(block_len) * block_len, &header)
+                            .await
+                            .map(|_| ()),
+                        Err(error) => Err(error),
+                    }
+                }
+                Err(error) => Err(error),
+            };
+            if let Err(error) = invalidated {
+                warn!(
+                    "{:?}: failed to invalidate the write-intent log of '{}': {}",
+                    self,
+                    child.uri(),
+                    error
+                );
+            }
+        }
+
+        self.as_mut().resume().await
+    }
+
+    /// Returns the write-intent log statistics of the nexus.
+    pub fn write_intent_stats(&self) -> WriteIntentStats {
+        self.write_intent.stats()
+    }
+
+    /// Write the log to a child about to be rebuilt, as the rebuild does not
+    /// copy the metadata reservation.
+    pub(super) async fn write_intent_prepare_child(
+        &self,
+        child_uri: &str,
+    ) -> Result<(), Error> {
+        let regions = match self.write_intent.regions() {
+            Some(regions) => regions,
+            None => return Ok(()),
+        };
+
+        let child = self.lookup_child(child_uri).ok_or_else(|| {
+            Error::ChildNotFound {
+                child: child_uri.to_owned(),
+                name: self.name.clone(),
+            }
+        })?;
+
+        let _guard = regions.lock().await;
+        let words = regions
+            .logged
+            .iter()
+            .map(|w| w.load(Ordering::SeqCst))
+            .collect::<Vec<_>>();
+        let written = match child.get_io_handle() {
+            Ok(hdl) => write_log(&*hdl, &regions.geometry, &words).await,
+            Err(error) => Err(error),
+        };
+        written.map_err(|error| Error::WriteIntentLog {
+            child: child_uri.to_owned(),
+            name: self.name.clone(),
+            reason: error.to_string(),
+        })
+    }
+
+    /// Log dirty on the children the regions being written. The log is
+    /// updated on all the children the channel writes to, and succeeds if
+    /// it has been updated on any of them, as the logs of the children are
+    /// merged when the nexus is opened.
+    pub(super) async fn write_intent_flush(
+        &self,
+        channel: &NexusChannel<'n>,
+    ) -> Result<(), CoreError> {
+        let regions = match self.write_intent.regions() {
+            Some(regions) => regions,
+            None => return Ok(()),
+        };
+
+        let _guard = regions.lock().await;
+        let (words, first, last) = match regions.changes() {
+            Some(changes) => changes,
+            None => return Ok(()),
+        };
+
+        let mut result = Err(CoreError::NoDevicesAvailable {});
+        for hdl in channel.writers() {
+            match write_words(hdl, &words, first, last).await {
+                Ok(()) => result = Ok(()),
+                Err(error) => {
+                    error!(
+                        "{:?}: failed to update the write-intent log of '{}': {}",
+                        self,
+                        hdl.get_device().device_name(),
+                        error
+                    );
+                }
+            }
+        }
+
+        if result.is_ok() {
+            regions.set_logged(&words, first, last);
+            self.write_intent.log_updates.fetch_add(1);
+        }
+        result
+    }
+
+    /// Log clean the idle regions on the healthy children, all the regions
+    /// without writes in flight if forced.
+    async fn write_intent_clear(&self, force: bool) {
+        let regions = match self.write_intent.regions() {
+            Some(regions) => regions,
+            None => return,
+        };
+
+        let _guard = regions.lock().await;
+        let (first, last) = match regions.clear_idle(force) {
+            Some(cleared) => cleared,
+            None => return,
+        };
+        let words = regions
+            .dirty
+            .iter()
+            .map(|w| w.load(Ordering::SeqCst))
+            .collect::<Vec<_>>();
+
+        for child in self.children_iter().filter(|c| c.is_healthy()) {
+            let written = match child.get_io_handle() {
+                Ok(hdl) => write_words(&*hdl, &words, first, last).await,
+                Err(error) => Err(error),
+            };
+            if let Err(error) = written {
+                warn!(
+                    "{:?}: failed to clear the write-intent log of '{}': {}",
+                    self,
+                    child.uri(),
+                    error
+                );
+            }
+        }
+
+        // regions which failed to be logged clean on a child merely get
+        // resynchronised needlessly after a crash
+        regions.set_logged(&words, first, last);
+        self.write_intent.log_updates.fetch_add(1);
+    }
+
+    /// Log clean all the regions when the nexus is destroyed, leaving the
+    /// log enabled on the children for the next time the nexus is opened.
+    pub(super) async fn write_intent_shutdown(&self) {
+        if self.write_intent.is_enabled() {
+            self.write_intent_clear(true).await;
+            self.write_intent.install(None);
+        }
+    }
+
+    /// Look for the write-intent log on the children of a nexus being
+    /// opened. If found, the regions logged dirty on any child are
+    /// resynchronised between the healthy children and the log is enabled.
+    pub(super) async fn write_intent_recover(&self) {
+        let block_len = self.block_len();
+        let handles = self
+            .children_iter()
+            .filter(|c| c.is_healthy())
+            .filter_map(|c| c.get_io_handle().ok())
+            .collect::<Vec<_>>();
+
+        let mut found: Option<(Geometry, Vec<u64>)> = None;
+        for hdl in &handles {
+            match read_log(&**hdl).await {
+                Ok(Some((geometry, words))) => match found.as_mut() {
+                    None => found = Some((geometry, words)),
+                    Some((g, w)) if *g == geometry => {
+                        w.iter_mut().zip(words).for_each(|(a, b)| *a |= b)
+                    }
+                    Some(_) => {
+                        warn!(
+                            "{:?}: write-intent log of '{}' has another geometry, resynchronising all regions",
+                            self,
+                            hdl.get_device().device_name()
+                        );
+                        found.as_mut().unwrap().1.fill(u64::MAX);
+                    }
+                },
+                Ok(None) => {}
+                Err(error) => {
+                    warn!(
+                        "{:?}: failed to read the write-intent log of '{}': {}",
+                        self,
+                        hdl.get_device().device_name(),
+                        error
+                    );
+                }
+            }
+        }
+
+        let (logged, mut words) = match found {
+            Some(found) => found,
+            None => return,
+        };
+
+        // the nexus may have been resized since the log was written
+        let geometry =
+            Geometry::new(logged.region_size, self.num_blocks(), block_len);
+        if geometry != logged {
+            warn!(
+                "{:?}: write-intent log geometry changed, resynchronising all regions",
+                self
+            );
+            words = vec![u64::MAX; geometry.words()];
+        }
+
+        let region_blocks = geometry.region_size / block_len;
+        let dirty = (0 .. geometry.regions)
+            .filter(|r| {
+                let (word, bit) = region_bit(*r);
+                words[word] & bit != 0
+            })
+            .collect::<Vec<_>>();
+
+        if !dirty.is_empty() {
+            info!(
+                "{:?}: resynchronising {} region(s) logged dirty",
+                self,
+                dirty.len()
+            );
+        }
+
+        let mut resynced = 0;
+        for region in dirty {
+            let offset = region * region_blocks;
+            let num_blocks = region_blocks.min(self.num_blocks() - offset);
+            match self.resync_region(&handles, offset, num_blocks).await {
+                Ok(true) => resynced += 1,
+                Ok(false) => {}
+                Err(error) => {
+                    // the log is left as is, for the next open to retry
+                    error!(
+                        "{:?}: failed to resynchronise the region at {}, write-intent log disabled: {}",
+                        self, offset, error
+                    );
+                    return;
+                }
+            }
+        }
+
+        let clean = vec![0; geometry.words()];
+        for hdl in &handles {
+            if let Err(error) = write_log(&**hdl, &geometry, &clean).await {
+                error!(
+                    "{:?}: failed to reset the write-intent log of '{}': {}",
+                    self,
+                    hdl.get_device().device_name(),
+                    error
+                );
+            }
+        }
+
+        info!(
+            "{:?}: {} region(s) resynchronised, write-intent log enabled",
+            self, resynced
+        );
+        self.write_intent.resynced_regions.store(resynced);
+        self.write_intent
+            .install(Some(Regions::new(geometry, block_len)));
+        start_clearing(self.name.clone(), self.write_intent.epoch.load());
+    }
+
+    /// Copy the given blocks of the first child to the other children where
+    /// they differ. Returns true if any child has been rewritten.
+    pub(super) async fn resync_region(
+        &self,
+        handles: &[Box<dyn BlockDeviceHandle>],
+        offset: u64,
+        num_blocks: u64,
+    ) -> Result<bool, CoreError> {
+        let (source, targets) = match handles.split_first() {
+            Some(split) => split,
+            None => return Ok(false),
+        };
+        let block_len = self.block_len();
+        let byte_offset = (offset + self.data_ent_offset) * block_len;
+
+        let mut data = dma_buf(&**source, num_blocks * block_len)?;
+        source.read_at(byte_offset, &mut data).await?;
+
+        let mut rewritten = false;
+        for target in targets {
+            let mut other = dma_buf(&**target, num_blocks * block_len)?;
+            target.read_at(byte_offset, &mut other).await?;
+            if other.as_slice() != data.as_slice() {
+                target.write_at(byte_offset, &data).await?;
+                rewritten = true;
+            }
+        }
+        Ok(rewritten)
+    }
+}
+
+/// Start the passes logging idle regions clean, which stop once the log is
+/// disabled or enabled again.
+fn start_clearing(nexus_name: String, epoch: u64) {
+    Reactors::master().send_future(async move {
+        loop {
+            mayastor_sleep(CLEAR_INTERVAL).await.ok();
+            match nexus_lookup(&nexus_name) {
+                Some(nexus)
+                    if nexus.write_intent.is_enabled()
+                        && nexus.write_intent.epoch.load() == epoch =>
+                {
+                    nexus.write_intent_clear(false).await
+                }
+                _ => break,
+            }
+        }
+    });
+}
+
+/// Arguments of the `nexus_write_intent_enable` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct WriteIntentEnableArgs {
+    /// Name of the nexus.
+    name: String,
+    /// Minimum size of a region, in bytes, defaults to 4MiB.
+    region_size: Option<u64>,
+}
+
+/// Arguments of the `nexus_write_intent_disable` and
+/// `nexus_write_intent_stats` json-rpc methods.
+#[derive(Debug, Deserialize)]
+struct WriteIntentArgs {
+    /// Name of the nexus.
+    name: String,
+}
+
+/// Reply of the write-intent log json-rpc methods.
+#[derive(Debug, Serialize)]
+struct WriteIntentReply {
+    name: String,
+    #[serde(flatten)]
+    stats: WriteIntentStats,
+}
+
+impl WriteIntentReply {
+    fn new(nexus: &Nexus) -> Self {
+        Self {
+            name: nexus.name.clone(),
+            stats: nexus.write_intent_stats(),
+        }
+    }
+}
+
+/// Register the write-intent log json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register(
+        "nexus_write_intent_enable",
+        |args: WriteIntentEnableArgs| {
+            async move {
+                let mut nexus = nexus_lookup_mut(&args.name).ok_or(
+                    Error::NexusNotFound {
+                        name: args.name.clone(),
+                    },
+                )?;
+                nexus.as_mut().write_intent_enable(args.region_size).await?;
+                Ok(WriteIntentReply::new(&nexus))
+            }
+            .boxed_local()
+        },
+    );
+
+    jsonrpc_register("nexus_write_intent_disable", |args: WriteIntentArgs| {
+        async move {
+            let mut nexus =
+                nexus_lookup_mut(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+            nexus.as_mut().write_intent_disable().await?;
+            Ok(WriteIntentReply::new(&nexus))
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_write_intent_stats", |args: WriteIntentArgs| {
+        async move {
+            nexus_lookup(&args.name).map(WriteIntentReply::new).ok_or(
+                Error::NexusNotFound {
+                    name: args.name,
+                },
+            )
+        }
+        .boxed_local()
+    });
+}
@@ -0,0 +1,345 @@
+//! Merging of adjacent writes.
+//!
+//! Log-structured workloads issue streams of small sequential writes, which
+//! cost a round-trip to a remote replica, or a seek to an HDD, each. When
+//! write merging is enabled, the writes submitted on a nexus channel are
+//! held for a short window and the ones adjacent to each other are submitted
+//! to the children as a single write, with the I/O vectors of all the merged
+//! writes. Each nexus write is completed once the merged write completes on
+//! all the children.
+//!
+//! A batch is submitted as soon as it is full, once the window elapses, or
+//! when a write which does not follow it is submitted. If the merged write
+//! fails on a child, the writes of the batch are submitted again one by one,
+//! so that the failure is handled like the failure of an ordinary write. The
+//! poller watching the window only runs while a batch is held, so that a
+//! channel which is not merging writes, for instance once the policy is
+//! turned off, does not keep polling.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use crossbeam::atomic::AtomicCell;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+
+use spdk_rs::{IoChannel, Poller, PollerBuilder};
+
+use super::{nexus_lookup, Error, Nexus, NexusBio, NexusChannel};
+use crate::jsonrpc::jsonrpc_register;
+
+/// Write merging policy of a nexus.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct WriteMergePolicy {
+    pub enabled: bool,
+    /// Maximum number of writes merged together.
+    pub max_ios: u32,
+    /// Maximum size of a merged write, in bytes.
+    pub max_bytes: u64,
+    /// Maximum time a write is held waiting for adjacent writes, in
+    /// microseconds.
+    pub window_us: u64,
+}
+
+impl Default for WriteMergePolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_ios: 16,
+            max_bytes: 128 * 1024,
+            window_us: 100,
+        }
+    }
+}
+
+/// Write merging statistics of a nexus.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct WriteMergeStats {
+    /// Number of merged writes submitted to the children.
+    pub merged_writes: u64,
+    /// Number of nexus writes completed by the merged writes.
+    pub merged_ios: u64,
+    /// Number of merged writes which failed and whose writes were submitted
+    /// again one by one.
+    pub fallbacks: u64,
+}
+
+/// Write merging policy of a nexus, along with its statistics.
+pub(crate) struct WriteMerge {
+    policy: AtomicCell<WriteMergePolicy>,
+    merged_writes: AtomicU64,
+    merged_ios: AtomicU64,
+    fallbacks: AtomicU64,
+}
+
+impl WriteMerge {
+    pub(crate) fn new() -> Self {
+        Self {
+            policy: AtomicCell::new(WriteMergePolicy::default()),
+            merged_writes: AtomicU64::new(0),
+            merged_ios: AtomicU64::new(0),
+            fallbacks: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the current policy.
+    #[inline(always)]
+    pub(crate) fn policy(&self) -> WriteMergePolicy {
+        self.policy.load()
+    }
+
+    /// Replace the policy.
+    pub(crate) fn set_policy(&self, policy: WriteMergePolicy) {
+        self.policy.store(policy);
+    }
+
+    /// Account a merged write of the given number of nexus writes.
+    pub(super) fn merged(&self, ios: usize) {
+        self.merged_writes.fetch_add(1, Ordering::Relaxed);
+        self.merged_ios.fetch_add(ios as u64, Ordering::Relaxed);
+    }
+
+    /// Account a failed merged write.
+    pub(super) fn fallback(&self) {
+        self.fallbacks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the statistics.
+    pub(crate) fn stats(&self) -> WriteMergeStats {
+        WriteMergeStats {
+            merged_writes: self.merged_writes.load(Ordering::Relaxed),
+            merged_ios: self.merged_ios.load(Ordering::Relaxed),
+            fallbacks: self.fallbacks.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<'n> Nexus<'n> {
+    /// Returns the write merging policy of the nexus.
+    pub fn write_merge_policy(&self) -> WriteMergePolicy {
+        self.write_merge.policy()
+    }
+
+    /// Replace the write merging policy of the nexus.
+    pub fn set_write_merge_policy(&self, policy: WriteMergePolicy) {
+        info!("{:?}: setting write merge policy: {:?}", self, policy);
+        self.write_merge.set_policy(policy);
+    }
+
+    /// Returns the write merging statistics of the nexus.
+    pub fn write_merge_stats(&self) -> WriteMergeStats {
+        self.write_merge.stats()
+    }
+}
+
+/// Batch of adjacent writes held on a nexus channel.
+pub(super) struct WriteMerger<'n> {
+    bios: Vec<NexusBio<'n>>,
+    /// Offset of the block following the last write of the batch.
+    next_offset: u64,
+    /// Size of the batch in bytes.
+    bytes: u64,
+    /// Time at which the batch must be submitted.
+    deadline: Instant,
+    /// Poller submitting the batch once the window elapses, while a batch is
+    /// held.
+    poller: Option<Poller<'n>>,
+}
+
+impl<'n> WriteMerger<'n> {
+    pub(super) fn new() -> Self {
+        Self {
+            bios: Vec::new(),
+            next_offset: 0,
+            bytes: 0,
+            deadline: Instant::now(),
+            poller: None,
+        }
+    }
+
+    /// Returns true if no write is held.
+    #[inline(always)]
+    pub(super) fn is_empty(&self) -> bool {
+        self.bios.is_empty()
+    }
+
+    /// Add a write to the batch, submitting the batch first if the write
+    /// does not follow it, and afterwards if the batch is full.
+    pub(super) fn queue(
+        &mut self,
+        bio: NexusBio<'n>,
+        channel: &IoChannel<NexusChannel<'n>>,
+        policy: &WriteMergePolicy,
+    ) {
+        let bytes = bio.num_blocks() * bio.nexus().block_len();
+        if !self.bios.is_empty()
+            && (bio.offset() != self.next_offset
+                || self.bytes + bytes > policy.max_bytes)
+        {
+            self.flush();
+        }
+
+        if self.bios.is_empty() {
+            self.deadline =
+                Instant::now() + Duration::from_micros(policy.window_us);
+            self.start_poller(channel, policy.window_us);
+        }
+
+        self.next_offset = bio.offset() + bio.num_blocks();
+        self.bytes += bytes;
+        self.bios.push(bio);
+
+        if self.bios.len() >= policy.max_ios as usize
+            || self.bytes >= policy.max_bytes
+        {
+            self.flush();
+        }
+    }
+
+    /// Submit the writes of the batch, and stop the poller.
+    pub(super) fn flush(&mut self) {
+        self.submit();
+        self.poller.take();
+    }
+
+    /// Submit the writes of the batch.
+    fn submit(&mut self) {
+        if self.bios.is_empty() {
+            return;
+        }
+        self.bytes = 0;
+        NexusBio::submit_merged(std::mem::take(&mut self.bios));
+    }
+
+    /// Stop the poller of the channel, the batch must be empty.
+    pub(super) fn stop(&mut self) {
+        debug_assert!(self.bios.is_empty());
+        self.poller.take();
+    }
+
+    /// Start the poller submitting the batch.
+    fn start_poller(
+        &mut self,
+        channel: &IoChannel<NexusChannel<'n>>,
+        window_us: u64,
+    ) {
+        if self.poller.is_some() {
+            return;
+        }
+
+        // poll more often than the window, so that a batch started between
+        // two polls is not held much longer than the window
+        let channel = channel.clone();
+        let poller = PollerBuilder::new()
+            .with_name("nexus_write_merge")
+            .with_interval(Duration::from_micros((window_us / 4).max(1)))
+            .with_poll_fn(move |_| {
+                // the poller may be dropped by the call, along with the
+                // channel it captured
+                let mut channel = channel.clone();
+                channel.channel_data_mut().merger.poll()
+            })
+            .build();
+        self.poller = Some(poller);
+    }
+
+    /// Submit the batch if its window elapsed, and stop the poller once no
+    /// batch is held. The poll function does not refer to what it captured
+    /// past this call, so the poller can be unregistered from within it.
+    fn poll(&mut self) -> i32 {
+        let polled = if !self.bios.is_empty() && Instant::now() >= self.deadline
+        {
+            self.submit();
+            1
+        } else {
+            0
+        };
+
+        if self.bios.is_empty() {
+            self.poller.take();
+        }
+        polled
+    }
+}
+
+/// Arguments of the `nexus_set_write_merge` json-rpc method, the fields
+/// which are not given are left unchanged.
+#[derive(Debug, Deserialize)]
+struct SetWriteMergeArgs {
+    /// Name of the nexus.
+    name: String,
+    enabled: Option<bool>,
+    max_ios: Option<u32>,
+    max_bytes: Option<u64>,
+    window_us: Option<u64>,
+}
+
+/// Arguments of the `nexus_get_write_merge` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct GetWriteMergeArgs {
+    /// Name of the nexus.
+    name: String,
+}
+
+/// Reply of the write merging json-rpc methods.
+#[derive(Debug, Serialize)]
+struct WriteMergeReply {
+    name: String,
+    policy: WriteMergePolicy,
+    stats: WriteMergeStats,
+}
+
+impl WriteMergeReply {
+    fn new(nexus: &Nexus) -> Self {
+        Self {
+            name: nexus.name.clone(),
+            policy: nexus.write_merge.policy(),
+            stats: nexus.write_merge.stats(),
+        }
+    }
+}
+
+/// Register the write merging json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nexus_set_write_merge", |args: SetWriteMergeArgs| {
+        async move {
+            let nexus =
+                nexus_lookup(&args.name).ok_or(Error::NexusNotFound {
+                    name: args.name.clone(),
+                })?;
+
+            let current = nexus.write_merge.policy();
+            let policy = WriteMergePolicy {
+                enabled: args.enabled.unwrap_or(current.enabled),
+                max_ios: args.max_ios.unwrap_or(current.max_ios),
+                max_bytes: args.max_bytes.unwrap_or(current.max_bytes),
+                window_us: args.window_us.unwrap_or(current.window_us),
+            };
+            if policy.max_ios < 2 || policy.window_us == 0 {
+                return Err(Error::InvalidArguments {
+                    name: args.name,
+                    args: "at least 2 writes must be merged over a non-empty \
+                        window"
+                        .to_string(),
+                });
+            }
+
+            nexus.set_write_merge_policy(policy);
+            Ok(WriteMergeReply::new(nexus))
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_get_write_merge", |args: GetWriteMergeArgs| {
+        async move {
+            nexus_lookup(&args.name).map(WriteMergeReply::new).ok_or(
+                Error::NexusNotFound {
+                    name: args.name,
+                },
+            )
+        }
+        .boxed_local()
+    });
+}
@@ -1,23 +1,54 @@
+//! Bdevs on local NVMe devices.
+//!
+//! Some NVMe devices expose a controller memory buffer (CMB), memory of the
+//! controller mapped through a PCIe BAR. With the `cmb_sqs` parameter, the
+//! submission queues of the device are placed in its CMB, so the controller
+//! fetches the commands from its own memory instead of from host memory,
+//! which saves a round trip over PCIe for every command:
+//!
+//! ```ignore
+//!     pcie:///0000:01:00.0?cmb_sqs=true
+//! ```
+//!
+//! The queues are placed in host memory when the device has no CMB, or one
+//! which cannot hold submission queues. Whether the CMB of the device of a
+//! pool is in use is reported by the `pool_disk_info` json-rpc method. The
+//! data buffers, which are allocated by the bdev layer, are never placed in
+//! the CMB, and host memory buffers are not given to DRAM-less devices, as
+//! the SPDK NVMe driver supports neither.
+
 use std::{
+    collections::HashMap,
     convert::TryFrom,
     ffi::CStr,
+    mem::size_of,
     os::raw::{c_char, c_int, c_ulong, c_void},
 };
 
 use async_trait::async_trait;
 use futures::channel::oneshot;
+use serde::Serialize;
 use snafu::ResultExt;
 use url::Url;
 
 use spdk_rs::{
     ffihelper::copy_str_with_null,
-    libspdk::{bdev_nvme_create, bdev_nvme_delete, spdk_nvme_transport_id},
+    libspdk::{
+        bdev_nvme_create,
+        bdev_nvme_delete,
+        nvme_ctrlr_get_by_name,
+        spdk_nvme_ctrlr_get_default_ctrlr_opts,
+        spdk_nvme_ctrlr_get_opts,
+        spdk_nvme_ctrlr_get_regs_cmbsz,
+        spdk_nvme_ctrlr_opts,
+        spdk_nvme_transport_id,
+    },
 };
 
 use crate::{
     bdev::{util::uri, CreateDestroy, GetName},
     bdev_api::{self, BdevError},
-    core::UntypedBdev,
+    core::{Share, UntypedBdev},
     ffihelper::{cb_arg, errno_result_from_i32, ErrnoResult, IntoCString},
 };
 
@@ -27,6 +58,8 @@ pub(super) struct NVMe {
     /// name of the bdev that should be created
     name: String,
     url: Url,
+    /// place the submission queues in the controller memory buffer
+    cmb_sqs: bool,
 }
 
 /// Convert a URI to NVMe object
@@ -41,9 +74,24 @@ impl TryFrom<&Url> for NVMe {
             });
         }
 
+        let mut parameters: HashMap<String, String> =
+            url.query_pairs().into_owned().collect();
+
+        let cmb_sqs = match parameters.remove("cmb_sqs") {
+            Some(value) => uri::boolean(&value, true).context(
+                bdev_api::BoolParamParseFailed {
+                    uri: url.to_string(),
+                    parameter: String::from("cmb_sqs"),
+                    value: value.clone(),
+                },
+            )?,
+            None => false,
+        };
+
         Ok(Self {
             name: url.path()[1 ..].into(),
             url: url.clone(),
+            cmb_sqs,
         })
     }
 }
@@ -82,6 +130,18 @@ impl CreateDestroy for NVMe {
         let cname = self.name.clone().into_cstring();
         let mut context = NvmeCreateContext::new(self);
 
+        // the default options of the driver, unless the CMB is to be used
+        let mut drv_opts = spdk_nvme_ctrlr_opts::default();
+        if self.cmb_sqs {
+            unsafe {
+                spdk_nvme_ctrlr_get_default_ctrlr_opts(
+                    &mut drv_opts,
+                    size_of::<spdk_nvme_ctrlr_opts>() as u64,
+                );
+            }
+            drv_opts.use_cmb_sqs = true;
+        }
+
         let (sender, receiver) = oneshot::channel::<ErrnoResult<()>>();
 
         let errno = unsafe {
@@ -92,7 +152,11 @@ impl CreateDestroy for NVMe {
                 context.count,
                 Some(nvme_create_cb),
                 cb_arg(sender),
-                std::ptr::null_mut(),
+                if self.cmb_sqs {
+                    &mut drv_opts
+                } else {
+                    std::ptr::null_mut()
+                },
                 std::ptr::null_mut(),
                 false,
             )
@@ -121,6 +185,19 @@ impl CreateDestroy for NVMe {
             error!("failed to added alias too created bdev")
         }
 
+        if self.cmb_sqs {
+            match cmb_info(&self.name) {
+                Some(cmb) if cmb.in_use => {
+                    info!("{:?}: submission queues in the CMB", self)
+                }
+                cmb => warn!(
+                    "{:?}: submission queues in host memory, the device has \
+                    no CMB able to hold them: {:?}",
+                    self, cmb
+                ),
+            }
+        }
+
         Ok(unsafe { CStr::from_ptr(context.names[0]) }
             .to_str()
             .unwrap()
@@ -149,6 +226,64 @@ impl CreateDestroy for NVMe {
     }
 }
 
+/// Controller memory buffer of a local NVMe device.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct CmbInfo {
+    /// Size of the CMB in bytes, 0 if the device has none.
+    pub size: u64,
+    /// The CMB can hold submission queues.
+    pub sqs_supported: bool,
+    /// The CMB can hold write data.
+    pub wds_supported: bool,
+    /// The submission queues were asked to be placed in the CMB.
+    pub requested: bool,
+    /// The submission queues are placed in the CMB.
+    pub in_use: bool,
+}
+
+/// Returns the CMB of the local NVMe controller of the given name, i.e. the
+/// PCI address of the device.
+fn cmb_info(name: &str) -> Option<CmbInfo> {
+    let cname = name.to_string().into_cstring();
+    let nvme_ctrlr = unsafe { nvme_ctrlr_get_by_name(cname.as_ptr()) };
+    if nvme_ctrlr.is_null() {
+        return None;
+    }
+    let ctrlr = unsafe { (*nvme_ctrlr).ctrlr };
+    if ctrlr.is_null() {
+        return None;
+    }
+
+    let cmbsz = unsafe { spdk_nvme_ctrlr_get_regs_cmbsz(ctrlr) };
+    let bits = unsafe { cmbsz.bits };
+    // the size is in units of 4 KiB times 16 to the power of SZU
+    let size = if bits.szu() <= 6 {
+        (bits.sz() as u64) << (12 + 4 * bits.szu() as u64)
+    } else {
+        0
+    };
+    let requested = unsafe { (*spdk_nvme_ctrlr_get_opts(ctrlr)).use_cmb_sqs };
+    let sqs_supported = size > 0 && bits.sqs() != 0;
+
+    Some(CmbInfo {
+        size,
+        sqs_supported,
+        wds_supported: size > 0 && bits.wds() != 0,
+        requested,
+        in_use: requested && sqs_supported,
+    })
+}
+
+/// Returns the CMB of the device of the given bdev, if it is a local NVMe
+/// device.
+pub(crate) fn bdev_cmb_info(bdev: &UntypedBdev) -> Option<CmbInfo> {
+    let uri = Url::parse(&bdev.bdev_uri_original()?).ok()?;
+    if uri.scheme() != "pcie" || uri::segments(&uri).is_empty() {
+        return None;
+    }
+    cmb_info(&uri.path()[1 ..])
+}
+
 const MAX_NAMESPACES: usize = 1;
 
 struct NvmeCreateContext {
@@ -42,7 +42,13 @@ use crate::{
             NVME_CONTROLLERS,
         },
     },
-    core::{BlockDevice, BlockDeviceIoStats, CoreError, IoType},
+    core::{
+        BlockDevice,
+        BlockDeviceIoStats,
+        BlockDeviceQpairStats,
+        CoreError,
+        IoType,
+    },
 };
 
 #[repr(C)]
@@ -398,6 +404,7 @@ impl NvmeIoChannelInner<'_> {
 
         debug!("{} I/O channel successfully reinitialized", ctrlr_name);
         self.qpair = Some(qpair);
+        self.io_stats_controller.account_reconnect();
         0
     }
 
@@ -414,6 +421,10 @@ pub struct IoStatsController {
     // I/O stats to the caller, inside get_io_stats().
     io_stats: BlockDeviceIoStats,
     block_size: u64,
+    qpair_stats: BlockDeviceQpairStats,
+    // The disconnected qpair callback is invoked on every poll till the qpair
+    // is reconnected, so remember the disconnect to account it only once.
+    qpair_disconnected: bool,
 }
 
 /// Top-level wrapper around device I/O statistics.
@@ -422,6 +433,8 @@ impl IoStatsController {
         Self {
             io_stats: BlockDeviceIoStats::default(),
             block_size,
+            qpair_stats: BlockDeviceQpairStats::default(),
+            qpair_disconnected: false,
         }
     }
 
@@ -465,6 +478,45 @@ impl IoStatsController {
 
         stats
     }
+
+    #[inline]
+    /// Account a completed I/O operation.
+    pub fn account_completion(&mut self, succeeded: bool, retryable: bool) {
+        self.qpair_stats.num_completions += 1;
+        if !succeeded {
+            self.qpair_stats.num_errors += 1;
+            if retryable {
+                self.qpair_stats.num_retryable_errors += 1;
+            }
+        }
+    }
+
+    #[inline]
+    /// Account an I/O operation which could not be submitted due to the lack
+    /// of request resources.
+    pub fn account_no_resources(&mut self) {
+        self.qpair_stats.num_no_resources += 1;
+    }
+
+    /// Account a disconnect of the qpair.
+    pub fn account_disconnect(&mut self) {
+        if !self.qpair_disconnected {
+            self.qpair_disconnected = true;
+            self.qpair_stats.num_disconnects += 1;
+        }
+    }
+
+    /// Account a reconnect of the qpair.
+    pub fn account_reconnect(&mut self) {
+        self.qpair_disconnected = false;
+        self.qpair_stats.num_reconnects += 1;
+    }
+
+    /// Get qpair statistics for channel.
+    #[inline]
+    pub fn get_qpair_stats(&self) -> BlockDeviceQpairStats {
+        self.qpair_stats
+    }
 }
 
 pub struct NvmeControllerIoChannel(NonNull<spdk_io_channel>);
@@ -476,6 +528,7 @@ extern "C" fn disconnected_qpair_cb(
     let inner = NvmeIoChannel::from_raw(ctx).inner_mut();
 
     if let Some(ref qpair) = inner.qpair {
+        inner.io_stats_controller.account_disconnect();
         unsafe {
             nvme_qpair_abort_all_queued_reqs(qpair.as_ptr(), 1);
             nvme_transport_qpair_abort_reqs(qpair.as_ptr(), 1);
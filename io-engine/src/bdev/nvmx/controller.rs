@@ -34,7 +34,12 @@ use spdk_rs::{
 
 use crate::{
     bdev::nvmx::{
-        channel::{NvmeControllerIoChannel, NvmeIoChannel, NvmeIoChannelInner},
+        channel::{
+            IoStatsController,
+            NvmeControllerIoChannel,
+            NvmeIoChannel,
+            NvmeIoChannelInner,
+        },
         controller_inner::{SpdkNvmeController, TimeoutConfig},
         controller_state::{
             ControllerFailureReason,
@@ -57,6 +62,7 @@ use crate::{
     bdev_api::BdevError,
     core::{
         BlockDeviceIoStats,
+        BlockDeviceQpairStats,
         CoreError,
         DeviceEventDispatcher,
         DeviceEventSink,
@@ -453,10 +459,42 @@ impl<'a> NvmeController<'a> {
     where
         F: Fn(Result<BlockDeviceIoStats, CoreError>, T) + 'static,
     {
-        struct IoStatsCtx<V: 'static + Sized> {
-            cb: Box<dyn Fn(Result<BlockDeviceIoStats, CoreError>, V) + 'static>,
+        self.collect_channel_stats(IoStatsController::get_io_stats, cb, cb_arg)
+    }
+
+    /// Get qpair statistics for all I/O channels of the controller.
+    pub fn get_qpair_stats<T: 'static + Sized, F>(
+        &self,
+        cb: F,
+        cb_arg: T,
+    ) -> Result<(), CoreError>
+    where
+        F: Fn(Result<BlockDeviceQpairStats, CoreError>, T) + 'static,
+    {
+        self.collect_channel_stats(
+            IoStatsController::get_qpair_stats,
+            cb,
+            cb_arg,
+        )
+    }
+
+    /// Aggregate the statistics obtained by `get_stats` from all I/O channels
+    /// of the controller.
+    fn collect_channel_stats<S, T: 'static + Sized, F>(
+        &self,
+        get_stats: fn(&IoStatsController) -> S,
+        cb: F,
+        cb_arg: T,
+    ) -> Result<(), CoreError>
+    where
+        S: Merge + Default + 'static,
+        F: Fn(Result<S, CoreError>, T) + 'static,
+    {
+        struct StatsCtx<S: 'static, V: 'static + Sized> {
+            cb: Box<dyn Fn(Result<S, CoreError>, V) + 'static>,
             cb_arg: V,
-            io_stats: BlockDeviceIoStats,
+            get_stats: fn(&IoStatsController) -> S,
+            stats: S,
         }
 
         if self.state_machine.current_state() != Running {
@@ -470,26 +508,27 @@ impl<'a> NvmeController<'a> {
             });
         }
 
-        let ctx = IoStatsCtx {
+        let ctx = StatsCtx {
             cb: Box::new(cb),
             cb_arg,
-            io_stats: BlockDeviceIoStats::default(),
+            get_stats,
+            stats: S::default(),
         };
 
-        // Process I/O statistics for a given channel.
-        fn account_channel_stats<N>(
+        // Process statistics for a given channel.
+        fn account_channel_stats<S: Merge, N>(
             channel: &mut NvmeIoChannelInner,
-            ctx: &mut IoStatsCtx<N>,
+            ctx: &mut StatsCtx<S, N>,
         ) -> i32 {
-            ctx.io_stats
-                .merge(channel.get_io_stats_controller().get_io_stats());
+            ctx.stats
+                .merge((ctx.get_stats)(channel.get_io_stats_controller()));
             0
         }
 
-        // Pass aggregated I/O statistics back to the caller.
-        fn account_channel_stats_done<N>(result: i32, ctx: IoStatsCtx<N>) {
+        // Pass aggregated statistics back to the caller.
+        fn account_channel_stats_done<S, N>(result: i32, ctx: StatsCtx<S, N>) {
             let stats = if result == 0 {
-                Ok(ctx.io_stats)
+                Ok(ctx.stats)
             } else {
                 Err(CoreError::DeviceStatisticsFailed {
                     source: Errno::EAGAIN,
@@ -500,8 +539,8 @@ impl<'a> NvmeController<'a> {
         }
 
         self.inner.as_ref().unwrap().io_device.traverse_io_channels(
-            account_channel_stats::<T>,
-            account_channel_stats_done::<T>,
+            account_channel_stats::<S, T>,
+            account_channel_stats_done::<S, T>,
             NvmeIoChannel::inner_from_channel,
             ctx,
         );
@@ -1031,6 +1070,8 @@ pub(crate) mod options {
     use spdk_rs::ffihelper::copy_str_with_null;
     use std::mem::size_of;
 
+    use crate::core::secret::Secret;
+
     use spdk_rs::libspdk::{
         spdk_nvme_ctrlr_get_default_ctrlr_opts,
         spdk_nvme_ctrlr_opts,
@@ -1070,6 +1111,7 @@ pub(crate) mod options {
         ext_host_id: Option<[u8; 16]>,
         host_nqn: Option<String>,
         keep_alive_timeout_ms: Option<u32>,
+        psk: Option<Secret>,
         transport_retry_count: Option<u8>,
     }
 
@@ -1113,6 +1155,11 @@ pub(crate) mod options {
             self
         }
 
+        pub fn with_psk(mut self, psk: Secret) -> Self {
+            self.psk = Some(psk);
+            self
+        }
+
         /// Builder to override default values
         pub fn build(self) -> NvmeControllerOpts {
             let mut opts = NvmeControllerOpts::default();
@@ -1141,6 +1188,10 @@ pub(crate) mod options {
                 copy_str_with_null(&host_nqn, &mut opts.0.hostnqn);
             }
 
+            if let Some(psk) = self.psk {
+                copy_str_with_null(psk.expose(), &mut opts.0.psk);
+            }
+
             opts
         }
     }
@@ -8,6 +8,11 @@ use uuid::Uuid;
 use crate::{
     bdev::nvmx::{
         controller_inner::SpdkNvmeController,
+        handle::{
+            SPDK_NVME_DATASET_MANAGEMENT_MAX_RANGES,
+            SPDK_NVME_DATASET_MANAGEMENT_RANGE_MAX_BLOCKS,
+            SPDK_NVME_WRITE_ZEROES_MAX_BLOCKS,
+        },
         NvmeController,
         NvmeControllerState,
         NvmeDeviceHandle,
@@ -19,6 +24,7 @@ use crate::{
         BlockDeviceDescriptor,
         BlockDeviceHandle,
         BlockDeviceIoStats,
+        BlockDeviceQpairStats,
         CoreError,
         DeviceEventSink,
         DeviceIoController,
@@ -193,6 +199,27 @@ impl BlockDevice for NvmeBlockDevice {
         }
     }
 
+    fn md_size(&self) -> u64 {
+        self.ns.md_size()
+    }
+
+    fn max_io_blocks(&self, io_type: IoType) -> Option<u64> {
+        match io_type {
+            IoType::Read | IoType::Write => {
+                match self.ns.max_io_xfer_size() / self.ns.block_len() {
+                    0 => None,
+                    max => Some(max),
+                }
+            }
+            IoType::Unmap => Some(
+                SPDK_NVME_DATASET_MANAGEMENT_MAX_RANGES
+                    * SPDK_NVME_DATASET_MANAGEMENT_RANGE_MAX_BLOCKS,
+            ),
+            IoType::WriteZeros => Some(SPDK_NVME_WRITE_ZEROES_MAX_BLOCKS),
+            _ => None,
+        }
+    }
+
     async fn io_stats(&self) -> Result<BlockDeviceIoStats, CoreError> {
         let carc = NVME_CONTROLLERS.lookup_by_name(&self.name).ok_or(
             CoreError::BdevNotFound {
@@ -217,6 +244,32 @@ impl BlockDevice for NvmeBlockDevice {
         r.await.expect("Failed awaiting at io_stats")
     }
 
+    async fn qpair_stats(
+        &self,
+    ) -> Result<Option<BlockDeviceQpairStats>, CoreError> {
+        let carc = NVME_CONTROLLERS.lookup_by_name(&self.name).ok_or(
+            CoreError::BdevNotFound {
+                name: self.name.to_string(),
+            },
+        )?;
+
+        let (s, r) =
+            oneshot::channel::<Result<BlockDeviceQpairStats, CoreError>>();
+        // Schedule async qpair stats collection and wait for the result.
+        {
+            let controller = carc.lock();
+
+            controller.get_qpair_stats(
+                |stats, ch| {
+                    done_cb(ch, stats);
+                },
+                cb_arg(s),
+            )?;
+        }
+
+        r.await.expect("Failed awaiting at qpair_stats").map(Some)
+    }
+
     fn claimed_by(&self) -> Option<String> {
         None
     }
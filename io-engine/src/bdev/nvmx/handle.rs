@@ -38,7 +38,11 @@ use crate::{
         channel::NvmeControllerIoChannel,
         controller_inner::SpdkNvmeController,
         utils,
-        utils::{nvme_cpl_is_pi_error, nvme_cpl_succeeded},
+        utils::{
+            nvme_cpl_is_pi_error,
+            nvme_cpl_is_retryable,
+            nvme_cpl_succeeded,
+        },
         NvmeBlockDevice,
         NvmeIoChannel,
         NvmeNamespace,
@@ -88,11 +92,16 @@ static NVME_IOCTX_POOL: OnceCell<MemoryPool<NvmeIoCtx>> = OnceCell::new();
 
 // Maximum number of range sets that may be specified in the dataset management
 // command.
-const SPDK_NVME_DATASET_MANAGEMENT_MAX_RANGES: u64 = 256;
+pub(super) const SPDK_NVME_DATASET_MANAGEMENT_MAX_RANGES: u64 = 256;
 
 // Maximum number of blocks that may be specified in a single dataset management
 // range.
-const SPDK_NVME_DATASET_MANAGEMENT_RANGE_MAX_BLOCKS: u64 = 0xFFFFFFFF;
+pub(super) const SPDK_NVME_DATASET_MANAGEMENT_RANGE_MAX_BLOCKS: u64 =
+    0xFFFFFFFF;
+
+// Maximum number of blocks that may be specified in a write zeroes command,
+// the number of blocks being a 0's based 16 bit value.
+pub(super) const SPDK_NVME_WRITE_ZEROES_MAX_BLOCKS: u64 = 0x10000;
 
 /// I/O handle for NVMe block device.
 pub struct NvmeDeviceHandle {
@@ -176,7 +185,7 @@ extern "C" fn nvme_admin_passthru_done(
         "Admin passthrough completed, succeeded={}",
         nvme_cpl_succeeded(cpl)
     );
-    done_cb(ctx, nvme_cpl_succeeded(cpl));
+    done_cb(ctx, NvmeStatus::from(cpl));
 }
 
 extern "C" fn nvme_queued_reset_sgl(ctx: *mut c_void, sgl_offset: u32) {
@@ -240,10 +249,12 @@ fn complete_nvme_command(ctx: *mut NvmeIoCtx, cpl: *const spdk_nvme_cpl) {
     let inner = NvmeIoChannel::inner_from_channel(io_ctx.channel);
 
     // Update I/O statistics in case the operation succeeded.
+    let stats_controller = inner.get_io_stats_controller();
     if op_succeeded {
-        let stats_controller = inner.get_io_stats_controller();
         stats_controller.account_block_io(io_ctx.op, 1, io_ctx.num_blocks);
     }
+    stats_controller
+        .account_completion(op_succeeded, nvme_cpl_is_retryable(cpl));
 
     // Adjust the number of active I/O.
     inner.discard_io();
@@ -680,6 +691,10 @@ impl BlockDeviceHandle for NvmeDeviceHandle {
             }
         }
 
+        if rc == -libc::ENOMEM {
+            inner.get_io_stats_controller().account_no_resources();
+        }
+
         if rc < 0 {
             Err(CoreError::ReadDispatch {
                 source: Errno::from_i32(-rc),
@@ -757,6 +772,10 @@ impl BlockDeviceHandle for NvmeDeviceHandle {
             }
         }
 
+        if rc == -libc::ENOMEM {
+            inner.get_io_stats_controller().account_no_resources();
+        }
+
         if rc < 0 {
             Err(CoreError::WriteDispatch {
                 source: Errno::from_i32(-rc),
@@ -887,6 +906,10 @@ impl BlockDeviceHandle for NvmeDeviceHandle {
             )
         };
 
+        if rc == -libc::ENOMEM {
+            inner.get_io_stats_controller().account_no_resources();
+        }
+
         if rc < 0 {
             Err(CoreError::UnmapDispatch {
                 source: Errno::from_i32(-rc),
@@ -946,6 +969,10 @@ impl BlockDeviceHandle for NvmeDeviceHandle {
             )
         };
 
+        if rc == -libc::ENOMEM {
+            inner.get_io_stats_controller().account_no_resources();
+        }
+
         if rc < 0 {
             Err(CoreError::WriteZeroesDispatch {
                 source: Errno::from_i32(-rc),
@@ -995,7 +1022,7 @@ impl BlockDeviceHandle for NvmeDeviceHandle {
             None => (std::ptr::null_mut(), 0),
         };
 
-        let (s, r) = oneshot::channel::<bool>();
+        let (s, r) = oneshot::channel::<NvmeStatus>();
 
         unsafe {
             spdk_nvme_ctrlr_cmd_admin_raw(
@@ -1013,13 +1040,16 @@ impl BlockDeviceHandle for NvmeDeviceHandle {
         })?;
 
         inner.account_io();
-        let ret = if r.await.expect("Failed awaiting NVMe Admin command I/O") {
-            debug!("nvme_admin() done");
-            Ok(())
-        } else {
-            Err(CoreError::NvmeAdminFailed {
+        let ret = match r.await.expect("Failed awaiting NVMe Admin command I/O")
+        {
+            NvmeStatus::Generic(GenericStatusCode::Success) => {
+                debug!("nvme_admin() done");
+                Ok(())
+            }
+            status => Err(CoreError::NvmeAdminFailed {
                 opcode: (*cmd).opc(),
-            })
+                status,
+            }),
         };
         inner.discard_io();
         ret
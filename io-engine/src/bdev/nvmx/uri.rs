@@ -49,7 +49,10 @@ use crate::{
     },
     bdev_api::{self, BdevError},
     constants::NVME_NQN_PREFIX,
-    core::MayastorEnvironment,
+    core::{
+        secret::{resolve_secret, Secret},
+        MayastorEnvironment,
+    },
     ffihelper::ErrnoResult,
     subsys::Config,
 };
@@ -114,6 +117,9 @@ pub struct NvmfDeviceTemplate {
     uuid: Option<uuid::Uuid>,
     /// The HostNqn to connect to the nvmf target with.
     hostnqn: Option<String>,
+    /// Pre-shared key to connect to the nvmf target with, resolved from the
+    /// secret reference of the URI.
+    psk: Option<Secret>,
 }
 
 impl TryFrom<&Url> for NvmfDeviceTemplate {
@@ -179,6 +185,15 @@ impl TryFrom<&Url> for NvmfDeviceTemplate {
 
         let hostnqn = parameters.remove("hostnqn");
 
+        let psk = parameters
+            .remove("secret")
+            .map(|reference| resolve_secret(&reference))
+            .transpose()
+            .map_err(|e| BdevError::InvalidUri {
+                uri: url.to_string(),
+                message: e.to_string(),
+            })?;
+
         Ok(NvmfDeviceTemplate {
             name: url[url::Position::BeforeHost .. url::Position::AfterPath]
                 .to_string(),
@@ -189,6 +204,7 @@ impl TryFrom<&Url> for NvmfDeviceTemplate {
             prchk_flags,
             uuid,
             hostnqn,
+            psk,
         })
     }
 }
@@ -250,6 +266,10 @@ impl<'probe> NvmeControllerContext<'probe> {
             opts = opts.with_hostnqn(host_nqn);
         }
 
+        if let Some(psk) = &template.psk {
+            opts = opts.with_psk(psk.clone());
+        }
+
         let (sender, receiver) = oneshot::channel::<ErrnoResult<()>>();
         let opts = opts.build();
 
@@ -80,6 +80,13 @@ pub(crate) fn nvme_cpl_succeeded(cpl: *const spdk_nvme_cpl) -> bool {
         && sc == NvmeGenericCommandStatusCode::Success as u16
 }
 
+#[inline]
+/// Check if a failed NVMe command may be retried, i.e. if the controller did
+/// not set the Do Not Retry bit in its completion.
+pub(crate) fn nvme_cpl_is_retryable(cpl: *const spdk_nvme_cpl) -> bool {
+    unsafe { (*cpl).__bindgen_anon_1.status.dnr() == 0 }
+}
+
 /* Bit set of attributes for DATASET MANAGEMENT commands. */
 #[allow(dead_code)]
 pub enum NvmeDsmAttribute {
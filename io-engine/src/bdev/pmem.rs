@@ -0,0 +1,102 @@
+//! Bdevs on persistent memory (PMEM) namespaces.
+//!
+//! A PMEM namespace in fsdax mode shows on the host as a block device
+//! supporting DAX: its writes bypass the page cache and are copied straight
+//! to the persistent memory. The bdev of such a namespace is an AIO bdev over
+//! its block device, which the URI scheme only accepts once the device is
+//! found to be a DAX capable one, so that a pool is never created by mistake
+//! on a device whose writes are held in a volatile cache.
+//!
+//! A write to the namespace is complete once copied to the persistent
+//! memory, but it is only durable once flushed: the flush of the AIO bdev
+//! syncs the block device, which has the persistent memory controller drain
+//! its write pending queues, and is a no-op on platforms which flush these on
+//! power loss.
+
+use std::{
+    convert::TryFrom,
+    fmt::{Debug, Formatter},
+    path::Path,
+};
+
+use async_trait::async_trait;
+use url::Url;
+
+use crate::{
+    bdev::{aio::Aio, CreateDestroy, GetName},
+    bdev_api::BdevError,
+    host::blk_device::dax_device,
+};
+
+/// Durability of the writes to a pool on a persistent memory namespace.
+const PMEM_DURABILITY: &str =
+    "writes are durable once flushed to the persistent memory";
+
+/// Durability of the writes to a pool on any other disk.
+const DISK_DURABILITY: &str =
+    "writes are durable once flushed from the cache of the disk";
+
+/// Returns the durability of the writes to a pool on a bdev of the given URI
+/// scheme.
+pub(crate) fn durability(scheme: &str) -> &'static str {
+    match scheme {
+        "pmem" => PMEM_DURABILITY,
+        _ => DISK_DURABILITY,
+    }
+}
+
+pub(super) struct Pmem {
+    aio: Aio,
+}
+
+impl Debug for Pmem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Pmem '{}'", self.aio.get_name())
+    }
+}
+
+/// Convert a URI to a Pmem "object"
+impl TryFrom<&Url> for Pmem {
+    type Error = BdevError;
+
+    fn try_from(url: &Url) -> Result<Self, Self::Error> {
+        let aio = Aio::try_from(url)?;
+
+        if !dax_device(Path::new(url.path())) {
+            return Err(BdevError::InvalidUri {
+                uri: url.to_string(),
+                message: format!(
+                    "{} is not a DAX capable persistent memory device",
+                    url.path()
+                ),
+            });
+        }
+
+        Ok(Pmem {
+            aio,
+        })
+    }
+}
+
+impl GetName for Pmem {
+    fn get_name(&self) -> String {
+        self.aio.get_name()
+    }
+}
+
+#[async_trait(?Send)]
+impl CreateDestroy for Pmem {
+    type Error = BdevError;
+
+    /// Create a PMEM bdev
+    async fn create(&self) -> Result<String, Self::Error> {
+        debug!("{:?}: creating bdev", self);
+        self.aio.create().await
+    }
+
+    /// Destroy the given PMEM bdev
+    async fn destroy(self: Box<Self>) -> Result<(), Self::Error> {
+        debug!("{:?}: deleting", self);
+        Box::new(self.aio).destroy().await
+    }
+}
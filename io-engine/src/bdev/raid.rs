@@ -0,0 +1,478 @@
+//! Local RAID bdevs over several disks.
+//!
+//! A RAID bdev stripes its I/O over the disks of the node, and can be used
+//! as the base device of a pool, or as a child of a nexus, like any other
+//! bdev. Its URI lists the disks, which are opened as AIO bdevs, e.g.:
+//!
+//! ```ignore
+//!     raid0:///dev/sdb,/dev/sdc?strip=128k
+//!     raid5:///dev/sdb,/dev/sdc,/dev/sdd?strip=64k
+//! ```
+//!
+//! A disk given without a path is taken from /dev, e.g. `raid0:///sdb,sdc`.
+//! The strip size defaults to 64 KiB, and is given in KiB unless suffixed
+//! with `k` or `m`.
+//!
+//! RAID5 is the RAID5F level of SPDK, which computes the parity of full
+//! stripes only: its writes must cover whole stripes, i.e. the strip size
+//! times the number of data disks, so the pools and nexuses on it must use
+//! blocks of a matching size. A RAID bdev goes offline once one of its disks
+//! is gone, as SPDK does not rebuild a RAID bdev; the state of the RAID
+//! bdevs, and of their disks, is listed by the `raid_list` json-rpc method.
+
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    ffi::{CStr, CString},
+    fmt::{Debug, Formatter},
+};
+
+use async_trait::async_trait;
+use futures::{channel::oneshot, FutureExt};
+use nix::errno::Errno;
+use serde::Serialize;
+use snafu::ResultExt;
+use url::Url;
+
+use spdk_rs::libspdk::{
+    raid_bdev,
+    raid_bdev_add_base_device,
+    raid_bdev_create,
+    raid_bdev_delete,
+    raid_bdev_find_by_name,
+    raid_bdev_state,
+    raid_level,
+};
+
+use crate::{
+    bdev::{
+        aio::Aio,
+        dev::reject_unknown_parameters,
+        util::uri,
+        CreateDestroy,
+        GetName,
+    },
+    bdev_api::{self, BdevError},
+    core::{UntypedBdev, VerboseError},
+    ffihelper::{cb_arg, done_errno_cb, ErrnoResult},
+    jsonrpc::{jsonrpc_register, JsonRpcError},
+};
+
+/// Name of the SPDK module of the RAID bdevs.
+pub(crate) const RAID_MODULE_NAME: &str = "raid";
+
+/// Strip size in KiB when none is given.
+const DEFAULT_STRIP_KB: u32 = 64;
+
+/// RAID level of a RAID bdev.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Level {
+    Raid0,
+    Raid5,
+}
+
+impl Level {
+    /// Minimum number of disks of the level.
+    fn min_disks(&self) -> usize {
+        match self {
+            Level::Raid0 => 2,
+            Level::Raid5 => 3,
+        }
+    }
+
+    fn raid_level(&self) -> raid_level {
+        match self {
+            Level::Raid0 => raid_level::RAID0,
+            Level::Raid5 => raid_level::RAID5F,
+        }
+    }
+}
+
+pub(super) struct Raid {
+    /// Name of the bdev, which is the URI path.
+    name: String,
+    alias: String,
+    level: Level,
+    strip_kb: u32,
+    /// Disks of the RAID bdev, in the order of their slots.
+    disks: Vec<String>,
+    uuid: Option<uuid::Uuid>,
+}
+
+impl Debug for Raid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Raid '{}' ({:?}, {} disks)",
+            self.name,
+            self.level,
+            self.disks.len()
+        )
+    }
+}
+
+/// Parse the strip size, in KiB unless suffixed with `k` or `m`.
+fn strip_size(url: &Url, value: &str) -> Result<u32, BdevError> {
+    let (number, scale) = match value.to_lowercase() {
+        v if v.ends_with('m') => (v.trim_end_matches('m').to_string(), 1024),
+        v => (v.trim_end_matches('k').to_string(), 1),
+    };
+    let kb = number
+        .parse::<u32>()
+        .context(bdev_api::IntParamParseFailed {
+            uri: url.to_string(),
+            parameter: String::from("strip"),
+            value: value.to_string(),
+        })?
+        .saturating_mul(scale);
+
+    if kb < 4 || !kb.is_power_of_two() {
+        return Err(BdevError::InvalidUri {
+            uri: url.to_string(),
+            message: "'strip' must be a power of two of at least 4k"
+                .to_string(),
+        });
+    }
+    Ok(kb)
+}
+
+/// Convert a URI to a Raid "object"
+impl TryFrom<&Url> for Raid {
+    type Error = BdevError;
+
+    fn try_from(url: &Url) -> Result<Self, Self::Error> {
+        let level = match url.scheme() {
+            "raid0" => Level::Raid0,
+            _ => Level::Raid5,
+        };
+
+        let disks = url
+            .path()
+            .split(',')
+            .map(|d| d.trim_start_matches('/'))
+            .filter(|d| !d.is_empty())
+            .map(|d| {
+                if d.starts_with("dev/") {
+                    format!("/{}", d)
+                } else {
+                    format!("/dev/{}", d)
+                }
+            })
+            .collect::<Vec<_>>();
+
+        if disks.len() < level.min_disks() {
+            return Err(BdevError::InvalidUri {
+                uri: url.to_string(),
+                message: format!(
+                    "{} requires at least {} disks",
+                    url.scheme(),
+                    level.min_disks()
+                ),
+            });
+        }
+        if disks.len() > u8::MAX as usize {
+            return Err(BdevError::InvalidUri {
+                uri: url.to_string(),
+                message: "too many disks".to_string(),
+            });
+        }
+        if (1 .. disks.len()).any(|i| disks[.. i].contains(&disks[i])) {
+            return Err(BdevError::InvalidUri {
+                uri: url.to_string(),
+                message: "a disk is given more than once".to_string(),
+            });
+        }
+
+        let mut parameters: HashMap<String, String> =
+            url.query_pairs().into_owned().collect();
+
+        let strip_kb = match parameters.remove("strip") {
+            Some(value) => strip_size(url, &value)?,
+            None => DEFAULT_STRIP_KB,
+        };
+
+        let uuid = uri::uuid(parameters.remove("uuid")).context(
+            bdev_api::UuidParamParseFailed {
+                uri: url.to_string(),
+            },
+        )?;
+
+        reject_unknown_parameters(url, parameters)?;
+
+        Ok(Raid {
+            name: url.path().into(),
+            alias: url.to_string(),
+            level,
+            strip_kb,
+            disks,
+            uuid,
+        })
+    }
+}
+
+impl GetName for Raid {
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+impl Raid {
+    /// Returns the AIO bdev of a disk of the RAID bdev.
+    fn disk(disk: &str) -> Result<Aio, BdevError> {
+        let url = Url::parse(&format!("aio://{}", disk)).context(
+            bdev_api::UriParseFailed {
+                uri: disk.to_string(),
+            },
+        )?;
+        Aio::try_from(&url)
+    }
+
+    /// Destroy the AIO bdevs of the given disks.
+    async fn destroy_disks(&self, disks: &[String]) {
+        for disk in disks {
+            let result = match Self::disk(disk) {
+                Ok(aio) => Box::new(aio).destroy().await,
+                Err(error) => Err(error),
+            };
+            if let Err(error) = result {
+                warn!(
+                    "{:?}: failed to destroy the bdev of disk {}: {}",
+                    self,
+                    disk,
+                    error.verbose()
+                );
+            }
+        }
+    }
+
+    /// Create the RAID bdev over the bdevs of its disks.
+    fn create_raid(&self) -> Result<(), BdevError> {
+        let cname = CString::new(self.get_name()).unwrap();
+        let mut raid: *mut raid_bdev = std::ptr::null_mut();
+
+        let errno = unsafe {
+            raid_bdev_create(
+                cname.as_ptr(),
+                self.strip_kb,
+                self.disks.len() as u8,
+                self.level.raid_level(),
+                &mut raid,
+            )
+        };
+        if errno != 0 {
+            return Err(BdevError::CreateBdevInvalidParams {
+                source: Errno::from_i32(errno.abs()),
+                name: self.get_name(),
+            });
+        }
+
+        for (slot, disk) in self.disks.iter().enumerate() {
+            let cdisk = CString::new(disk.as_str()).unwrap();
+            let errno = unsafe {
+                raid_bdev_add_base_device(raid, cdisk.as_ptr(), slot as u8)
+            };
+            if errno != 0 {
+                return Err(BdevError::CreateBdevFailed {
+                    source: Errno::from_i32(errno.abs()),
+                    name: self.get_name(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if the RAID bdev exists, whether registered or not.
+    fn raid_exists(&self) -> bool {
+        let cname = CString::new(self.get_name()).unwrap();
+        !unsafe { raid_bdev_find_by_name(cname.as_ptr()) }.is_null()
+    }
+
+    /// Delete the RAID bdev, leaving the bdevs of its disks.
+    async fn delete_raid(&self) -> Result<(), BdevError> {
+        let cname = CString::new(self.get_name()).unwrap();
+        let raid = unsafe { raid_bdev_find_by_name(cname.as_ptr()) };
+        if raid.is_null() {
+            return Err(BdevError::BdevNotFound {
+                name: self.get_name(),
+            });
+        }
+
+        let (sender, receiver) = oneshot::channel::<ErrnoResult<()>>();
+        unsafe {
+            raid_bdev_delete(raid, Some(done_errno_cb), cb_arg(sender));
+        }
+        receiver
+            .await
+            .context(bdev_api::BdevCommandCanceled {
+                name: self.get_name(),
+            })?
+            .context(bdev_api::DestroyBdevFailed {
+                name: self.get_name(),
+            })
+    }
+}
+
+#[async_trait(?Send)]
+impl CreateDestroy for Raid {
+    type Error = BdevError;
+
+    /// Create a RAID bdev, and the bdevs of its disks
+    async fn create(&self) -> Result<String, Self::Error> {
+        if UntypedBdev::lookup_by_name(&self.name).is_some() {
+            return Err(BdevError::BdevExists {
+                name: self.get_name(),
+            });
+        }
+
+        debug!("{:?}: creating bdev", self);
+
+        // the bdevs of the disks are created here, and left alone if they
+        // exist already, e.g. when a previous attempt failed half way
+        let mut created = Vec::new();
+        for disk in &self.disks {
+            if UntypedBdev::lookup_by_name(disk).is_some() {
+                continue;
+            }
+            match Self::disk(disk)?.create().await {
+                Ok(_) => created.push(disk.clone()),
+                Err(error) => {
+                    error!("{:?} error: {}", self, error.verbose());
+                    self.destroy_disks(&created).await;
+                    return Err(error);
+                }
+            }
+        }
+
+        if let Err(error) = self.create_raid() {
+            error!("{:?} error: {}", self, error.verbose());
+            if self.raid_exists() {
+                let _ = self.delete_raid().await;
+            }
+            self.destroy_disks(&created).await;
+            return Err(error);
+        }
+
+        if let Some(mut bdev) = UntypedBdev::lookup_by_name(&self.name) {
+            if let Some(uuid) = self.uuid {
+                unsafe { bdev.set_raw_uuid(uuid.into()) };
+            }
+
+            if !bdev.add_alias(&self.alias) {
+                warn!("{:?}: failed to add alias '{}'", self, self.alias);
+            }
+
+            return Ok(self.get_name());
+        }
+
+        // the RAID bdev is only registered once all of its disks are
+        // configured
+        let _ = self.delete_raid().await;
+        self.destroy_disks(&created).await;
+        Err(BdevError::BdevNotFound {
+            name: self.get_name(),
+        })
+    }
+
+    /// Destroy the given RAID bdev, and the bdevs of its disks
+    async fn destroy(self: Box<Self>) -> Result<(), Self::Error> {
+        debug!("{:?}: deleting", self);
+
+        if let Some(mut bdev) = UntypedBdev::lookup_by_name(&self.name) {
+            bdev.remove_alias(&self.alias);
+        }
+        self.delete_raid().await?;
+        self.destroy_disks(&self.disks).await;
+        Ok(())
+    }
+}
+
+/// Disk of a RAID bdev, as listed by the `raid_list` json-rpc method.
+#[derive(Debug, Serialize)]
+pub struct RaidDisk {
+    pub name: String,
+    pub slot: u8,
+    /// The disk is part of the RAID bdev.
+    pub configured: bool,
+}
+
+/// RAID bdev, as listed by the `raid_list` json-rpc method.
+#[derive(Debug, Serialize)]
+pub struct RaidInfo {
+    pub name: String,
+    pub level: String,
+    pub strip_kb: u32,
+    /// "online", "configuring" while disks are missing before it is
+    /// registered, or "offline" once a disk is gone.
+    pub state: String,
+    pub num_disks: u8,
+    pub num_disks_discovered: u8,
+    pub disks: Vec<RaidDisk>,
+}
+
+impl RaidInfo {
+    /// Returns the RAID bdev of the given name, if there is one.
+    pub fn lookup(name: &str) -> Option<Self> {
+        let cname = CString::new(name).ok()?;
+        let raid = unsafe { raid_bdev_find_by_name(cname.as_ptr()) };
+        if raid.is_null() {
+            return None;
+        }
+        let raid = unsafe { &*raid };
+
+        let disks = (0 .. raid.num_base_bdevs)
+            .map(|slot| {
+                let info = unsafe { &*raid.base_bdev_info.add(slot as usize) };
+                RaidDisk {
+                    name: if info.name.is_null() {
+                        String::new()
+                    } else {
+                        unsafe { CStr::from_ptr(info.name) }
+                            .to_string_lossy()
+                            .into_owned()
+                    },
+                    slot,
+                    configured: !info.bdev.is_null(),
+                }
+            })
+            .collect();
+
+        Some(Self {
+            name: name.to_string(),
+            level: match raid.level {
+                raid_level::RAID0 => "raid0",
+                raid_level::RAID5F => "raid5",
+                _ => "unknown",
+            }
+            .to_string(),
+            strip_kb: raid.strip_size_kb,
+            state: match raid.state {
+                raid_bdev_state::RAID_BDEV_STATE_ONLINE => "online",
+                raid_bdev_state::RAID_BDEV_STATE_CONFIGURING => "configuring",
+                _ => "offline",
+            }
+            .to_string(),
+            num_disks: raid.num_base_bdevs,
+            num_disks_discovered: raid.num_base_bdevs_discovered,
+            disks,
+        })
+    }
+
+    /// Returns the registered RAID bdevs.
+    pub fn list() -> Vec<Self> {
+        UntypedBdev::bdev_first()
+            .map(|bdev| {
+                bdev.into_iter()
+                    .filter(|b| b.driver() == RAID_MODULE_NAME)
+                    .filter_map(|b| Self::lookup(b.name()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Register the RAID json-rpc methods.
+pub(crate) fn register_rpc_methods() {
+    jsonrpc_register::<(), _, _, JsonRpcError>("raid_list", |_| {
+        async move { Ok(RaidInfo::list()) }.boxed_local()
+    });
+}
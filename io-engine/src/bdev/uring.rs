@@ -8,7 +8,13 @@ use url::Url;
 use spdk_rs::libspdk::{create_uring_bdev, delete_uring_bdev};
 
 use crate::{
-    bdev::{dev::reject_unknown_parameters, util::uri, CreateDestroy, GetName},
+    bdev::{
+        aio::refuse_claimed,
+        dev::reject_unknown_parameters,
+        util::uri,
+        CreateDestroy,
+        GetName,
+    },
     bdev_api::{self, BdevError},
     core::UntypedBdev,
     ffihelper::{cb_arg, done_errno_cb, ErrnoResult},
@@ -20,6 +26,8 @@ pub(super) struct Uring {
     alias: String,
     blk_size: u32,
     uuid: Option<uuid::Uuid>,
+    /// Use the device even if the kernel uses it.
+    force: bool,
 }
 
 /// Convert a URI to an Uring "object"
@@ -39,16 +47,7 @@ impl TryFrom<&Url> for Uring {
         let mut parameters: HashMap<String, String> =
             url.query_pairs().into_owned().collect();
 
-        let blk_size: u32 = match parameters.remove("blk_size") {
-            Some(value) => {
-                value.parse().context(bdev_api::IntParamParseFailed {
-                    uri: url.to_string(),
-                    parameter: String::from("blk_size"),
-                    value: value.clone(),
-                })?
-            }
-            None => 512,
-        };
+        let blk_size = uri::block_size(url, parameters.remove("blk_size"))?;
 
         let uuid = uri::uuid(parameters.remove("uuid")).context(
             bdev_api::UuidParamParseFailed {
@@ -56,6 +55,8 @@ impl TryFrom<&Url> for Uring {
             },
         )?;
 
+        let force = uri::force(url, parameters.remove("force"))?;
+
         reject_unknown_parameters(url, parameters)?;
 
         Ok(Uring {
@@ -63,6 +64,7 @@ impl TryFrom<&Url> for Uring {
             alias: url.to_string(),
             blk_size,
             uuid,
+            force,
         })
     }
 }
@@ -85,6 +87,8 @@ impl CreateDestroy for Uring {
             });
         }
 
+        refuse_claimed(&self.name, self.force)?;
+
         let cname = CString::new(self.get_name()).unwrap();
 
         if let Some(mut bdev) = UntypedBdev::checked_from_ptr(unsafe {
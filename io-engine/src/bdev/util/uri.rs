@@ -1,9 +1,12 @@
 //! Simple utility functions to help with parsing URIs.
 
-use std::str::ParseBoolError;
+use std::{os::unix::fs::FileTypeExt, str::ParseBoolError};
 
+use snafu::ResultExt;
 use url::Url;
 
+use crate::bdev_api::{self, BdevError};
+
 pub(crate) fn segments(url: &Url) -> Vec<&str> {
     if let Some(iter) = url.path_segments() {
         let mut segments: Vec<&str> = iter.collect();
@@ -46,3 +49,56 @@ pub(crate) fn uuid(
 ) -> Result<Option<uuid::Uuid>, uuid::Error> {
     value.map(|uuid| uuid::Uuid::parse_str(&uuid)).transpose()
 }
+
+/// Parse the `blk_size` parameter of a file or block device URI, which must be
+/// 512, 4096 or `auto`, and defaults to 512. With `auto`, 0 is returned for a
+/// block device so that its logical block size is detected when the bdev is
+/// created, which avoids emulating 512 byte blocks on 4Kn drives, and 512 is
+/// returned for a file.
+pub(crate) fn block_size(
+    url: &Url,
+    value: Option<String>,
+) -> Result<u32, BdevError> {
+    let blk_size = match value.as_deref() {
+        None => return Ok(512),
+        Some("auto") => {
+            let block_device = std::fs::metadata(url.path())
+                .map(|m| m.file_type().is_block_device())
+                .unwrap_or(false);
+            return Ok(if block_device { 0 } else { 512 });
+        }
+        Some(value) => {
+            value.parse().context(bdev_api::IntParamParseFailed {
+                uri: url.to_string(),
+                parameter: String::from("blk_size"),
+                value: value.to_string(),
+            })?
+        }
+    };
+
+    if blk_size != 512 && blk_size != 4096 {
+        return Err(BdevError::InvalidUri {
+            uri: url.to_string(),
+            message: "'blk_size' must be one of: 512, 4096, auto".to_string(),
+        });
+    }
+    Ok(blk_size)
+}
+
+/// Parse the `force` parameter of a file or block device URI, which allows
+/// to use a block device the kernel uses.
+pub(crate) fn force(
+    url: &Url,
+    value: Option<String>,
+) -> Result<bool, BdevError> {
+    match value {
+        Some(value) => {
+            boolean(&value, true).context(bdev_api::BoolParamParseFailed {
+                uri: url.to_string(),
+                parameter: String::from("force"),
+                value: value.clone(),
+            })
+        }
+        None => Ok(false),
+    }
+}
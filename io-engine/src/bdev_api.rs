@@ -1,10 +1,14 @@
-use futures::channel::oneshot::Canceled;
+use futures::{channel::oneshot::Canceled, FutureExt};
 use nix::errno::Errno;
 use snafu::Snafu;
 use std::{convert::TryFrom, num::ParseIntError, str::ParseBoolError};
-use url::ParseError;
+use url::{ParseError, Url};
 
-use crate::{bdev::uri, core::Bdev};
+use crate::{
+    bdev::uri,
+    core::{Bdev, UntypedBdev},
+    jsonrpc::{jsonrpc_register, Code, RpcErrorCode},
+};
 
 // parse URI and bdev create/destroy errors common for all types of bdevs
 #[derive(Debug, Snafu, Clone)]
@@ -91,6 +95,64 @@ pub enum BdevError {
     // Command canceled.
     #[snafu(display("Command canceled for a BDEV '{}'", name))]
     BdevCommandCanceled { source: Canceled, name: String },
+    // Device in use by the kernel.
+    #[snafu(display(
+        "Device '{}' is in use: {}; add 'force=true' to the URI to use it \
+        regardless",
+        path,
+        claims
+    ))]
+    DeviceClaimed { path: String, claims: String },
+    // Invalid typed options.
+    #[snafu(display("Invalid options for a BDEV '{}': {}", name, message))]
+    InvalidOptions { name: String, message: String },
+}
+
+impl RpcErrorCode for BdevError {
+    fn rpc_error_code(&self) -> Code {
+        match self {
+            BdevError::BdevExists {
+                ..
+            }
+            | BdevError::BdevWrongUuid {
+                ..
+            } => Code::AlreadyExists,
+            BdevError::BdevNotFound {
+                ..
+            } => Code::NotFound,
+            BdevError::UriParseFailed {
+                ..
+            }
+            | BdevError::BdevNoMatchingUri {
+                ..
+            }
+            | BdevError::UriSchemeUnsupported {
+                ..
+            }
+            | BdevError::InvalidUri {
+                ..
+            }
+            | BdevError::BoolParamParseFailed {
+                ..
+            }
+            | BdevError::IntParamParseFailed {
+                ..
+            }
+            | BdevError::UuidParamParseFailed {
+                ..
+            }
+            | BdevError::CreateBdevInvalidParams {
+                ..
+            }
+            | BdevError::InvalidOptions {
+                ..
+            }
+            | BdevError::DeviceClaimed {
+                ..
+            } => Code::InvalidParams,
+            _ => Code::InternalError,
+        }
+    }
 }
 
 /// Parse URI and create bdev described in the URI.
@@ -121,6 +183,8 @@ where
             bdev.driver()
                 == match uri.scheme() {
                     "nvmf" | "pcie" => "nvme",
+                    "pmem" => "aio",
+                    "raid0" | "raid5" => "raid",
                     scheme => scheme,
                 }
         }
@@ -138,6 +202,8 @@ where
             bdev.driver()
                 == match uri.scheme() {
                     "nvmf" | "pcie" => "nvme",
+                    "pmem" => "aio",
+                    "raid0" | "raid5" => "raid",
                     scheme => scheme,
                 }
         }
@@ -170,3 +236,235 @@ where
         })
     }
 }
+
+/// Options of a bdev backed by a file or a block device.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FileBdevOptions {
+    /// Path of the file or the block device.
+    pub path: String,
+    /// Block size, 512 or 4096, detected for a block device if not given.
+    #[serde(default)]
+    pub blk_size: Option<u32>,
+    #[serde(default)]
+    pub uuid: Option<String>,
+    /// Use the block device even if the kernel uses it.
+    #[serde(default)]
+    pub force: bool,
+}
+
+/// Options of a bdev held in memory, or discarding its data, of a size given
+/// either in MiB or in blocks.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MemoryBdevOptions {
+    pub name: String,
+    #[serde(default)]
+    pub size_mb: Option<u32>,
+    #[serde(default)]
+    pub num_blocks: Option<u32>,
+    /// Block size, 512 if not given.
+    #[serde(default)]
+    pub blk_size: Option<u32>,
+    #[serde(default)]
+    pub uuid: Option<String>,
+}
+
+/// Options of a bdev connected to a remote NVMf target.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NvmfBdevOptions {
+    /// Address of the target.
+    pub host: String,
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// NQN of the subsystem.
+    pub subnqn: String,
+    /// Check the reference tag and the guard of the protection information.
+    #[serde(default)]
+    pub reftag: bool,
+    #[serde(default)]
+    pub guard: bool,
+    /// NQN of the host, the one of the io-engine if not given.
+    #[serde(default)]
+    pub hostnqn: Option<String>,
+    /// Reference to the pre-shared key of the connection, see
+    /// `core::secret`.
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default)]
+    pub uuid: Option<String>,
+}
+
+/// Bdev to create, given by the options of its type rather than by its URI.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BdevSpec {
+    Aio(FileBdevOptions),
+    Uring(FileBdevOptions),
+    Malloc(MemoryBdevOptions),
+    Null(MemoryBdevOptions),
+    #[serde(alias = "nvmx")]
+    Nvmf(NvmfBdevOptions),
+}
+
+/// Append the given parameter to the URI, if it is set.
+fn append_param(url: &mut Url, name: &str, value: Option<impl ToString>) {
+    if let Some(value) = value {
+        url.query_pairs_mut().append_pair(name, &value.to_string());
+    }
+}
+
+impl BdevSpec {
+    /// Returns the URI of the bdev.
+    pub fn uri(&self) -> Result<String, BdevError> {
+        let url = match self {
+            BdevSpec::Aio(opts) => Self::file_uri("aio", opts)?,
+            BdevSpec::Uring(opts) => Self::file_uri("uring", opts)?,
+            BdevSpec::Malloc(opts) => Self::memory_uri("malloc", opts)?,
+            BdevSpec::Null(opts) => Self::memory_uri("null", opts)?,
+            BdevSpec::Nvmf(opts) => Self::nvmf_uri(opts)?,
+        };
+        Ok(url.to_string())
+    }
+
+    fn base_uri(uri: String, name: &str) -> Result<Url, BdevError> {
+        Url::parse(&uri).map_err(|e| BdevError::InvalidOptions {
+            name: name.to_string(),
+            message: e.to_string(),
+        })
+    }
+
+    fn file_uri(
+        scheme: &str,
+        opts: &FileBdevOptions,
+    ) -> Result<Url, BdevError> {
+        if !opts.path.starts_with('/') {
+            return Err(BdevError::InvalidOptions {
+                name: opts.path.clone(),
+                message: "the path must be absolute".to_string(),
+            });
+        }
+        let mut url =
+            Self::base_uri(format!("{}://{}", scheme, opts.path), &opts.path)?;
+        append_param(&mut url, "blk_size", opts.blk_size);
+        append_param(&mut url, "uuid", opts.uuid.as_ref());
+        append_param(&mut url, "force", Some(opts.force).filter(|f| *f));
+        Ok(url)
+    }
+
+    fn memory_uri(
+        scheme: &str,
+        opts: &MemoryBdevOptions,
+    ) -> Result<Url, BdevError> {
+        if opts.name.is_empty() || opts.name.contains(&['/', '?', '#'][..]) {
+            return Err(BdevError::InvalidOptions {
+                name: opts.name.clone(),
+                message: "the name must not be empty, nor contain any of \
+                    '/', '?' and '#'"
+                    .to_string(),
+            });
+        }
+        if opts.size_mb.is_some() == opts.num_blocks.is_some() {
+            return Err(BdevError::InvalidOptions {
+                name: opts.name.clone(),
+                message: "either the size in MiB or the number of blocks \
+                    must be given"
+                    .to_string(),
+            });
+        }
+        let mut url =
+            Self::base_uri(format!("{}:///{}", scheme, opts.name), &opts.name)?;
+        append_param(&mut url, "size_mb", opts.size_mb);
+        append_param(&mut url, "num_blocks", opts.num_blocks);
+        append_param(&mut url, "blk_size", opts.blk_size);
+        append_param(&mut url, "uuid", opts.uuid.as_ref());
+        Ok(url)
+    }
+
+    fn nvmf_uri(opts: &NvmfBdevOptions) -> Result<Url, BdevError> {
+        // IPv6 addresses are enclosed in brackets in a URI
+        let host = if opts.host.contains(':') && !opts.host.starts_with('[') {
+            format!("[{}]", opts.host)
+        } else {
+            opts.host.clone()
+        };
+        let mut url = Self::base_uri(
+            match opts.port {
+                Some(port) => {
+                    format!("nvmf://{}:{}/{}", host, port, opts.subnqn)
+                }
+                None => format!("nvmf://{}/{}", host, opts.subnqn),
+            },
+            &opts.subnqn,
+        )?;
+        append_param(&mut url, "reftag", Some(opts.reftag).filter(|r| *r));
+        append_param(&mut url, "guard", Some(opts.guard).filter(|g| *g));
+        append_param(&mut url, "hostnqn", opts.hostnqn.as_ref());
+        append_param(&mut url, "secret", opts.secret.as_ref());
+        append_param(&mut url, "uuid", opts.uuid.as_ref());
+        Ok(url)
+    }
+}
+
+/// Create the bdev given by its typed options.
+/// Return the bdev name.
+pub async fn bdev_create_typed(spec: &BdevSpec) -> Result<String, BdevError> {
+    bdev_create(&spec.uri()?).await
+}
+
+/// Destroy the bdev of the given name, which must have been created from
+/// a URI.
+pub async fn bdev_destroy_by_name(name: &str) -> Result<(), BdevError> {
+    let bdev = UntypedBdev::lookup_by_name(name).ok_or_else(|| {
+        BdevError::BdevNotFound {
+            name: name.to_string(),
+        }
+    })?;
+    let uri = Url::try_from(bdev)?;
+    bdev_destroy(uri.as_str()).await
+}
+
+/// Bdev created by the `bdev_create_typed` json-rpc method.
+#[derive(Debug, Serialize)]
+struct BdevReply {
+    name: String,
+    uri: String,
+    uuid: String,
+    num_blocks: u64,
+    blk_size: u32,
+    product_name: String,
+}
+
+/// Arguments of the `bdev_destroy_typed` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct DestroyTypedArgs {
+    /// Name of the bdev.
+    name: String,
+}
+
+/// Register the bdev json-rpc methods, which create and destroy the bdevs
+/// from their typed options.
+pub(crate) fn register_rpc_methods() {
+    jsonrpc_register("bdev_create_typed", |spec: BdevSpec| {
+        async move {
+            let uri = spec.uri()?;
+            let name = bdev_create(&uri).await?;
+            let bdev = UntypedBdev::lookup_by_name(&name).ok_or(
+                BdevError::BdevNotFound {
+                    name: name.clone(),
+                },
+            )?;
+            Ok(BdevReply {
+                name,
+                uri,
+                uuid: bdev.uuid_as_string(),
+                num_blocks: bdev.num_blocks(),
+                blk_size: bdev.block_len(),
+                product_name: bdev.product_name().to_string(),
+            })
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("bdev_destroy_typed", |args: DestroyTypedArgs| {
+        async move { bdev_destroy_by_name(&args.name).await }.boxed_local()
+    });
+}
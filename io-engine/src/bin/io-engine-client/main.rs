@@ -30,6 +30,8 @@ pub enum ClientError {
     },
     #[snafu(display("Missing value for {}", field))]
     MissingValue { field: String },
+    #[snafu(display("Invalid topology file {}: {}", path, msg))]
+    InvalidTopology { path: String, msg: String },
 }
 
 pub type Result<T, E = ClientError> = std::result::Result<T, E>;
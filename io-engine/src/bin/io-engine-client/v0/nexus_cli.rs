@@ -107,11 +107,11 @@ pub fn subcommands<'a, 'b>() -> App<'a, 'b> {
     let publish = SubCommand::with_name("publish")
         .about("publish the nexus")
         .arg(Arg::with_name("protocol").short("p").long("protocol").value_name("PROTOCOL")
-            .help("Name of a protocol (nvmf) used for publishing the nexus remotely"))
+            .help("Name of a protocol (nvmf, iscsi) used for publishing the nexus remotely"))
         .arg(Arg::with_name("uuid").required(true).index(1)
             .help("uuid for the nexus"))
         .arg(Arg::with_name("key").required(false).index(2)
-            .help("crypto key to use"))
+            .help("crypto key to use, or CHAP secret of the initiators for iscsi"))
         .arg(
             Arg::with_name("allowed-host")
                 .long("allowed-host")
@@ -753,6 +753,7 @@ async fn nexus_publish(
     let protocol = match matches.value_of("protocol") {
         None => v0::ShareProtocolNexus::NexusNbd,
         Some("nvmf") => v0::ShareProtocolNexus::NexusNvmf,
+        Some("iscsi") => v0::ShareProtocolNexus::NexusIscsi,
         Some(_) => {
             return Err(Status::new(
                 Code::Internal,
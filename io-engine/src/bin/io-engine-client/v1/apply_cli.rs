@@ -0,0 +1,474 @@
+//! Declarative setup of the pools, replicas and nexuses of an io-engine.
+//!
+//! `io-engine-client apply -f topology.yaml` reads the resources the
+//! io-engine should have, and creates those it has not, so that applying the
+//! same file twice changes nothing:
+//!
+//! ```yaml
+//! pools:
+//!   - name: pool0
+//!     disks: [/dev/sdb]
+//! replicas:
+//!   - name: replica0
+//!     uuid: 5ae4d8f2-4b5a-4e5c-9a3c-2b8f0c9e7d11
+//!     pool: pool0
+//!     size: 1GiB
+//!     share: nvmf
+//! nexuses:
+//!   - name: nexus0
+//!     uuid: 9f3d2a44-1c6e-4f0b-8d55-0a7e2b1c3d4f
+//!     size: 1GiB
+//!     children: [bdev:///replica0]
+//!     share: nvmf
+//! ```
+//!
+//! An existing replica is shared or unshared as described, and an existing
+//! nexus is given the children it misses and published or unpublished.
+//! Resources are never destroyed, and what cannot be changed in place, such
+//! as the disks of a pool or the size of a replica, is reported.
+
+use crate::{
+    context::{Context, OutputFormat},
+    parse_size,
+    ClientError,
+    GrpcStatus,
+};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use colored_json::ToColoredJson;
+use mayastor_api::v1 as v1rpc;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use tonic::Status;
+
+pub fn subcommands<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("apply")
+        .settings(&[AppSettings::ColoredHelp, AppSettings::ColorAlways])
+        .about("Create the pools, replicas and nexuses described in a file")
+        .arg(
+            Arg::with_name("file")
+                .short("f")
+                .long("file")
+                .required(true)
+                .value_name("FILE")
+                .help("YAML description of the topology"),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Print the changes without making them"),
+        )
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Topology {
+    #[serde(default)]
+    pools: Vec<PoolSpec>,
+    #[serde(default)]
+    replicas: Vec<ReplicaSpec>,
+    #[serde(default)]
+    nexuses: Vec<NexusSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PoolSpec {
+    name: String,
+    disks: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReplicaSpec {
+    name: String,
+    uuid: String,
+    /// Name or uuid of the pool.
+    pool: String,
+    size: String,
+    #[serde(default)]
+    thin: bool,
+    #[serde(default)]
+    share: Option<String>,
+    #[serde(default)]
+    allowed_hosts: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NexusSpec {
+    name: String,
+    uuid: String,
+    size: String,
+    children: Vec<String>,
+    #[serde(default)]
+    share: Option<String>,
+    #[serde(default)]
+    allowed_hosts: Vec<String>,
+    #[serde(default = "default_min_cntl_id")]
+    min_cntl_id: u32,
+    #[serde(default = "default_max_cntl_id")]
+    max_cntl_id: u32,
+    #[serde(default)]
+    resv_key: u64,
+    #[serde(default)]
+    nexus_info_key: String,
+}
+
+fn default_min_cntl_id() -> u32 {
+    1
+}
+
+fn default_max_cntl_id() -> u32 {
+    0xffef
+}
+
+/// Change made, or to be made, to a resource.
+#[derive(Debug, Serialize)]
+struct Change {
+    resource: &'static str,
+    name: String,
+    action: String,
+}
+
+impl Change {
+    fn new(resource: &'static str, name: &str, action: String) -> Self {
+        Self {
+            resource,
+            name: name.to_string(),
+            action,
+        }
+    }
+}
+
+fn parse_share(share: &Option<String>) -> Result<i32, Status> {
+    match share.as_deref() {
+        None | Some("none") => Ok(v1rpc::common::ShareProtocol::None as i32),
+        Some("nvmf") => Ok(v1rpc::common::ShareProtocol::Nvmf as i32),
+        Some("iscsi") => Ok(v1rpc::common::ShareProtocol::Iscsi as i32),
+        Some(s) => Err(Status::invalid_argument(format!(
+            "Invalid value of share protocol '{}'",
+            s
+        ))),
+    }
+}
+
+fn size_bytes(size: &str) -> Result<u64, Status> {
+    parse_size(size)
+        .map(|s| s.get_bytes() as u64)
+        .map_err(|s| Status::invalid_argument(format!("Bad size '{}'", s)))
+}
+
+pub async fn handler(
+    mut ctx: Context,
+    matches: &ArgMatches<'_>,
+) -> crate::Result<()> {
+    let path =
+        matches
+            .value_of("file")
+            .ok_or_else(|| ClientError::MissingValue {
+                field: "file".to_string(),
+            })?;
+    let dry_run = matches.is_present("dry-run");
+    let invalid = |msg: String| ClientError::InvalidTopology {
+        path: path.to_string(),
+        msg,
+    };
+    let bytes = std::fs::read(path).map_err(|e| invalid(e.to_string()))?;
+    let topology: Topology =
+        serde_yaml::from_slice(&bytes).map_err(|e| invalid(e.to_string()))?;
+
+    let mut changes = Vec::new();
+    apply_pools(&mut ctx, &topology.pools, dry_run, &mut changes).await?;
+    apply_replicas(&mut ctx, &topology.replicas, dry_run, &mut changes).await?;
+    apply_nexuses(&mut ctx, &topology.nexuses, dry_run, &mut changes).await?;
+
+    match ctx.output {
+        OutputFormat::Json => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&changes)
+                    .unwrap()
+                    .to_colored_json_auto()
+                    .unwrap()
+            );
+        }
+        OutputFormat::Default => {
+            if changes.is_empty() {
+                ctx.v1("Nothing to change");
+                return Ok(());
+            }
+            let table = changes
+                .iter()
+                .map(|c| {
+                    vec![
+                        c.resource.to_string(),
+                        c.name.clone(),
+                        c.action.clone(),
+                    ]
+                })
+                .collect();
+            ctx.print_list(vec!["RESOURCE", "NAME", "ACTION"], table);
+        }
+    };
+
+    Ok(())
+}
+
+async fn apply_pools(
+    ctx: &mut Context,
+    pools: &[PoolSpec],
+    dry_run: bool,
+    changes: &mut Vec<Change>,
+) -> crate::Result<()> {
+    let existing = ctx
+        .v1
+        .pool
+        .list_pools(v1rpc::pool::ListPoolOptions {
+            name: None,
+            pooltype: None,
+        })
+        .await
+        .context(GrpcStatus)?
+        .into_inner()
+        .pools;
+
+    for spec in pools {
+        match existing.iter().find(|p| p.name == spec.name) {
+            Some(pool) => {
+                if pool.disks.len() != spec.disks.len() {
+                    changes.push(Change::new(
+                        "pool",
+                        &spec.name,
+                        format!(
+                            "exists on {}, the disks cannot be changed",
+                            pool.disks.join(" ")
+                        ),
+                    ));
+                }
+            }
+            None => {
+                if !dry_run {
+                    ctx.v2(&format!("Creating pool {}", spec.name));
+                    ctx.v1
+                        .pool
+                        .create_pool(v1rpc::pool::CreatePoolRequest {
+                            name: spec.name.clone(),
+                            uuid: None,
+                            disks: spec.disks.clone(),
+                            pooltype: v1rpc::pool::PoolType::Lvs as i32,
+                        })
+                        .await
+                        .context(GrpcStatus)?;
+                }
+                changes.push(Change::new("pool", &spec.name, "create".into()));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn apply_replicas(
+    ctx: &mut Context,
+    replicas: &[ReplicaSpec],
+    dry_run: bool,
+    changes: &mut Vec<Change>,
+) -> crate::Result<()> {
+    let existing = ctx
+        .v1
+        .replica
+        .list_replicas(v1rpc::replica::ListReplicaOptions {
+            name: None,
+            poolname: None,
+        })
+        .await
+        .context(GrpcStatus)?
+        .into_inner()
+        .replicas;
+
+    for spec in replicas {
+        let share = parse_share(&spec.share).context(GrpcStatus)?;
+        let size = size_bytes(&spec.size).context(GrpcStatus)?;
+
+        let replica = match existing.iter().find(|r| r.uuid == spec.uuid) {
+            Some(replica) => replica,
+            None => {
+                if !dry_run {
+                    ctx.v2(&format!("Creating replica {}", spec.name));
+                    ctx.v1
+                        .replica
+                        .create_replica(v1rpc::replica::CreateReplicaRequest {
+                            name: spec.name.clone(),
+                            uuid: spec.uuid.clone(),
+                            pooluuid: spec.pool.clone(),
+                            thin: spec.thin,
+                            share,
+                            size,
+                            allowed_hosts: spec.allowed_hosts.clone(),
+                        })
+                        .await
+                        .context(GrpcStatus)?;
+                }
+                changes.push(Change::new(
+                    "replica",
+                    &spec.name,
+                    "create".into(),
+                ));
+                continue;
+            }
+        };
+
+        if replica.size < size {
+            changes.push(Change::new(
+                "replica",
+                &spec.name,
+                format!(
+                    "exists with {} bytes, the size cannot be changed",
+                    replica.size
+                ),
+            ));
+        }
+        if replica.share == share {
+            continue;
+        }
+        if !dry_run {
+            if share == v1rpc::common::ShareProtocol::None as i32 {
+                ctx.v1
+                    .replica
+                    .unshare_replica(v1rpc::replica::UnshareReplicaRequest {
+                        uuid: spec.uuid.clone(),
+                    })
+                    .await
+                    .context(GrpcStatus)?;
+            } else {
+                ctx.v1
+                    .replica
+                    .share_replica(v1rpc::replica::ShareReplicaRequest {
+                        uuid: spec.uuid.clone(),
+                        share,
+                        allowed_hosts: spec.allowed_hosts.clone(),
+                    })
+                    .await
+                    .context(GrpcStatus)?;
+            }
+        }
+        changes.push(Change::new(
+            "replica",
+            &spec.name,
+            format!("share {}", spec.share.as_deref().unwrap_or("none")),
+        ));
+    }
+    Ok(())
+}
+
+async fn apply_nexuses(
+    ctx: &mut Context,
+    nexuses: &[NexusSpec],
+    dry_run: bool,
+    changes: &mut Vec<Change>,
+) -> crate::Result<()> {
+    let existing = ctx
+        .v1
+        .nexus
+        .list_nexus(v1rpc::nexus::ListNexusOptions {
+            name: None,
+        })
+        .await
+        .context(GrpcStatus)?
+        .into_inner()
+        .nexus_list;
+
+    for spec in nexuses {
+        let share = parse_share(&spec.share).context(GrpcStatus)?;
+        let size = size_bytes(&spec.size).context(GrpcStatus)?;
+
+        let (children, published) = match existing
+            .iter()
+            .find(|n| n.uuid == spec.uuid)
+        {
+            Some(nexus) => (
+                nexus
+                    .children
+                    .iter()
+                    .map(|c| c.uri.clone())
+                    .collect::<Vec<_>>(),
+                !nexus.device_uri.is_empty(),
+            ),
+            None => {
+                if !dry_run {
+                    ctx.v2(&format!("Creating nexus {}", spec.name));
+                    ctx.v1
+                        .nexus
+                        .create_nexus(v1rpc::nexus::CreateNexusRequest {
+                            name: spec.name.clone(),
+                            uuid: spec.uuid.clone(),
+                            size,
+                            min_cntl_id: spec.min_cntl_id,
+                            max_cntl_id: spec.max_cntl_id,
+                            resv_key: spec.resv_key,
+                            preempt_key: 0,
+                            children: spec.children.clone(),
+                            nexus_info_key: spec.nexus_info_key.clone(),
+                            resv_type: None,
+                            preempt_policy: 0,
+                        })
+                        .await
+                        .context(GrpcStatus)?;
+                }
+                changes.push(Change::new("nexus", &spec.name, "create".into()));
+                (spec.children.clone(), false)
+            }
+        };
+
+        for uri in spec.children.iter().filter(|c| !children.contains(c)) {
+            if !dry_run {
+                ctx.v1
+                    .nexus
+                    .add_child_nexus(v1rpc::nexus::AddChildNexusRequest {
+                        uuid: spec.uuid.clone(),
+                        uri: uri.clone(),
+                        norebuild: false,
+                    })
+                    .await
+                    .context(GrpcStatus)?;
+            }
+            changes.push(Change::new(
+                "nexus",
+                &spec.name,
+                format!("add child {}", uri),
+            ));
+        }
+
+        let unshared = share == v1rpc::common::ShareProtocol::None as i32;
+        if !published && !unshared {
+            if !dry_run {
+                ctx.v1
+                    .nexus
+                    .publish_nexus(v1rpc::nexus::PublishNexusRequest {
+                        uuid: spec.uuid.clone(),
+                        key: String::new(),
+                        share,
+                        allowed_hosts: spec.allowed_hosts.clone(),
+                    })
+                    .await
+                    .context(GrpcStatus)?;
+            }
+            changes.push(Change::new(
+                "nexus",
+                &spec.name,
+                format!("publish {}", spec.share.as_deref().unwrap_or("")),
+            ));
+        } else if published && unshared {
+            if !dry_run {
+                ctx.v1
+                    .nexus
+                    .unpublish_nexus(v1rpc::nexus::UnpublishNexusRequest {
+                        uuid: spec.uuid.clone(),
+                    })
+                    .await
+                    .context(GrpcStatus)?;
+            }
+            changes.push(Change::new("nexus", &spec.name, "unpublish".into()));
+        }
+    }
+    Ok(())
+}
@@ -1,3 +1,4 @@
+pub mod apply_cli;
 pub mod bdev_cli;
 pub mod controller_cli;
 pub mod device_cli;
@@ -75,6 +76,7 @@ pub(super) async fn main_() -> crate::Result<()> {
         .subcommand(snapshot_cli::subcommands())
         .subcommand(jsonrpc_cli::subcommands())
         .subcommand(controller_cli::subcommands())
+        .subcommand(apply_cli::subcommands())
         .get_matches();
 
     let ctx = context::Context::new(&matches)
@@ -82,6 +84,7 @@ pub(super) async fn main_() -> crate::Result<()> {
         .context(ContextCreate)?;
 
     let status = match matches.subcommand() {
+        ("apply", Some(args)) => apply_cli::handler(ctx, args).await,
         ("bdev", Some(args)) => bdev_cli::handler(ctx, args).await,
         ("device", Some(args)) => device_cli::handler(ctx, args).await,
         ("nexus", Some(args)) => nexus_cli::handler(ctx, args).await,
@@ -13,6 +13,13 @@ use snafu::ResultExt;
 use tonic::{Code, Status};
 use uuid::Uuid;
 
+// Share protocols which the share protocol enum does not list.
+/// The nexus is attached as a block device on its node.
+const SHARE_PROTOCOL_LOCAL: i32 = 3;
+/// The nexus is exposed to the guests of its node as an emulated NVMe
+/// controller.
+const SHARE_PROTOCOL_VFIO_USER: i32 = 4;
+
 pub fn subcommands<'a, 'b>() -> App<'a, 'b> {
     let create = SubCommand::with_name("create")
         .about("Create a new nexus device")
@@ -91,7 +98,7 @@ pub fn subcommands<'a, 'b>() -> App<'a, 'b> {
         .arg(Arg::with_name("uuid").required(true).index(1)
             .help("uuid for the nexus"))
         .arg(Arg::with_name("key").required(false).index(2)
-            .help("crypto key to use"))
+            .help("crypto key to use, or CHAP secret of the initiators for iscsi"))
         .arg(
             Arg::with_name("allowed-host")
                 .long("allowed-host")
@@ -100,7 +107,7 @@ pub fn subcommands<'a, 'b>() -> App<'a, 'b> {
                 .required(false)
                 .help("NQN of hosts which are allowed to connect to the target"))
         .arg(Arg::with_name("protocol").short("p").long("protocol").value_name("PROTOCOL")
-            .help("Name of a protocol (nvmf) used for publishing the nexus remotely"));
+            .help("Name of a protocol (nvmf, iscsi) used for publishing the nexus remotely, local to attach it as a block device on its node, or vfio-user to expose it to its VMs"));
 
     let unpublish = SubCommand::with_name("unpublish")
         .about("unpublish the nexus")
@@ -589,6 +596,9 @@ async fn nexus_publish(
     let protocol = match matches.value_of("protocol") {
         None => v1::common::ShareProtocol::Nvmf as i32,
         Some("nvmf") => v1::common::ShareProtocol::Nvmf as i32,
+        Some("iscsi") => v1::common::ShareProtocol::Iscsi as i32,
+        Some("local") => SHARE_PROTOCOL_LOCAL,
+        Some("vfio-user") => SHARE_PROTOCOL_VFIO_USER,
         Some(_) => {
             return Err(Status::new(
                 Code::Internal,
@@ -1,7 +1,7 @@
 #[macro_use]
 extern crate tracing;
 
-use std::{env, path::Path};
+use std::{env, path::Path, time::Duration};
 
 use futures::future::FutureExt;
 use structopt::StructOpt;
@@ -9,8 +9,10 @@ use structopt::StructOpt;
 use io_engine::{
     bdev::util::uring,
     core::{
+        crash_report,
         device_monitor_loop,
         diagnostics::process_diagnostics_cli,
+        hugepages,
         lock::{
             ProtectedSubsystems,
             ResourceLockManager,
@@ -21,27 +23,36 @@ use io_engine::{
         MayastorCliArgs,
         MayastorEnvironment,
         Mthread,
+        Reactor,
         Reactors,
     },
     grpc,
+    handoff,
     logger,
+    metrics::MetricsServer,
     persistent_store::PersistentStore,
+    reconcile::reconcile,
     subsys::Registration,
 };
 use version_info::fmt_package_info;
 
-const PAGES_NEEDED: u32 = 1024;
-
 io_engine::CPS_INIT!();
 fn start_tokio_runtime(args: &MayastorCliArgs) {
     let grpc_address = grpc::endpoint(args.grpc_endpoint.clone());
     let registration_addr = args.registration_endpoint.clone();
+    let metrics_addr = args.metrics_endpoint;
     let rpc_address = args.rpc_address.clone();
     let api_versions = args.api_versions.clone();
     let node_name = grpc::node_name(&args.node_name);
     let node_nqn = args.make_hostnqn();
 
     let persistent_store_endpoint = args.persistent_store_endpoint.clone();
+    let persistent_store_lease_ttl =
+        Duration::from_secs(args.persistent_store_lease_ttl);
+    let reconcile_policy = args.reconcile_policy;
+    let handoff_socket = args.handoff_socket.clone();
+    let take_over = args.take_over.clone();
+    let handoff_max_pause = Duration::from_secs(args.handoff_max_pause);
 
     let reactor_freeze_detection = args.reactor_freeze_detection;
     let reactor_freeze_timeout = args.reactor_freeze_timeout;
@@ -54,8 +65,33 @@ fn start_tokio_runtime(args: &MayastorCliArgs) {
     Mthread::spawn_unaffinitized(move || {
         runtime::block_on(async move {
             let mut futures = Vec::new();
-            PersistentStore::init(persistent_store_endpoint).await;
+            PersistentStore::init(
+                persistent_store_endpoint,
+                persistent_store_lease_ttl,
+            )
+            .await;
+            // Take over the pools and nexuses of the io-engine being
+            // upgraded, before serving any request. Should the handoff fail,
+            // the io-engine being upgraded keeps them.
+            if let Some(path) = take_over {
+                if let Err(error) =
+                    handoff::take_over(&path, handoff_max_pause).await
+                {
+                    error!(
+                        "Failed to take over from the running io-engine: {}",
+                        error
+                    );
+                }
+            }
+            if let Some(path) = handoff_socket {
+                runtime::spawn(handoff::serve(path));
+            }
+            // Pools have been imported by now, reconcile what they hold with
+            // what has been recorded in the persistent store.
+            Reactor::spawn_at_primary(reconcile(reconcile_policy))
+                .expect("Failed to start the startup reconciliation");
             runtime::spawn(device_monitor_loop());
+            runtime::spawn(crash_report::abort_report_loop());
 
             // Launch reactor health monitor if diagnostics is enabled.
             if reactor_freeze_detection {
@@ -73,6 +109,10 @@ fn start_tokio_runtime(args: &MayastorCliArgs) {
                 .boxed(),
             );
 
+            if let Some(metrics_addr) = metrics_addr {
+                futures.push(MetricsServer::run(metrics_addr).boxed());
+            }
+
             if let Some(registration_addr) = registration_addr {
                 Registration::init(
                     &node_name,
@@ -91,93 +131,39 @@ fn start_tokio_runtime(args: &MayastorCliArgs) {
     });
 }
 
-fn hugepage_get_nr(hugepage_path: &Path) -> (u32, u32) {
-    let nr_pages = match sysfs::parse_value(hugepage_path, "nr_hugepages") {
-        Ok(nr_pages) => nr_pages,
-        Err(error) => {
-            warn!(
-                %error,
-                "Failed to read the number of pages at {}",
-                hugepage_path.display()
-            );
-            // NOTE: We check for 1g pages but the directory for 1g pages won't
-            // exist if they are not enabled. Effectively this means
-            // that zero 1g hugepages are available, so it
-            // seems sensible to fall back to 0 if reads fail. See discussion
-            // here: https://github.com/openebs/mayastor/issues/1273.
-            0
-        }
-    };
-
-    let free_pages = match sysfs::parse_value(hugepage_path, "free_hugepages") {
-        Ok(free_pages) => free_pages,
-        Err(error) => {
-            warn!(
-                %error,
-                "Failed to read the number of free pages at {}",
-                hugepage_path.display()
-            );
-            0
-        }
-    };
-
-    (nr_pages, free_pages)
-}
-
-fn hugepage_check() {
-    let (nr_pages, free_pages) =
-        hugepage_get_nr(Path::new("/sys/kernel/mm/hugepages/hugepages-2048kB"));
-    let (nr_1g_pages, free_1g_pages) = hugepage_get_nr(Path::new(
-        "/sys/kernel/mm/hugepages/hugepages-1048576kB",
-    ));
-
-    if nr_pages + nr_1g_pages * 512 < PAGES_NEEDED {
-        error!(
-            ?PAGES_NEEDED,
-            ?nr_pages,
-            ?nr_1g_pages,
-            "insufficient pages available"
-        );
-        if !cfg!(debug_assertions) {
-            std::process::exit(1)
-        }
-    }
-
-    if free_pages + free_1g_pages * 512 < PAGES_NEEDED {
-        error!(
-            ?PAGES_NEEDED,
-            ?free_pages,
-            ?free_1g_pages,
-            "insufficient free pages available"
-        );
-        if !cfg!(debug_assertions) {
-            std::process::exit(1)
-        }
-    }
-
-    info!("free_pages 2MB: {} nr_pages 2MB: {}", free_pages, nr_pages);
-    info!(
-        "free_pages 1GB: {} nr_pages 1GB: {}",
-        free_1g_pages, nr_1g_pages
-    );
-}
-
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = MayastorCliArgs::from_args();
 
     let log_format = args.log_format.unwrap_or_default();
+    let otlp_endpoint = args.otlp_endpoint.as_deref();
 
     // setup our logger first if -L is passed, raise the log level
     // automatically. trace maps to debug at FFI level. If RUST_LOG is
     // passed, we will use it regardless.
     if !args.log_components.is_empty() {
-        logger::init_ex("TRACE", log_format);
+        logger::init_ex("TRACE", log_format, otlp_endpoint);
     } else {
-        logger::init_ex("INFO", log_format);
+        logger::init_ex("INFO", log_format, otlp_endpoint);
     }
 
     info!("{}", fmt_package_info!());
 
+    if let Some(dir) = &args.crash_report_dir {
+        crash_report::install(dir.into());
+    }
+
+    if let Some(path) = &args.audit_log {
+        grpc::audit::install(
+            path.into(),
+            args.audit_log_size * 1024 * 1024,
+            args.audit_log_files,
+        );
+    }
+
+    if let Some(path) = &args.grpc_auth_policy {
+        grpc::auth::install(Path::new(path))?;
+    }
+
     // Handle diagnostics-related commands before initializing the agent.
     // Once diagnostics command is executed (regardless of status), exit the
     // agent.
@@ -185,7 +171,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         return res;
     }
 
-    hugepage_check();
+    if args.simulate {
+        warn!("Running in simulation mode, without hugepages");
+    } else if let Err(error) = hugepages::check(&args) {
+        error!("{}", error);
+        if !cfg!(debug_assertions) {
+            std::process::exit(1)
+        }
+    }
 
     let nvme_core_path = Path::new("/sys/module/nvme_core/parameters");
     let nvme_mp: String =
@@ -221,5 +214,6 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     Reactors::current().poll_reactor();
 
     ms.fini();
+    logger::fini();
     Ok(())
 }
@@ -18,11 +18,13 @@ use crate::{
         BlockDeviceIoStats,
         CoreError,
         DescriptorGuard,
+        ShareIscsi,
         ShareNvmf,
+        UnshareIscsi,
         UnshareNvmf,
     },
     subsys::NvmfSubsystem,
-    target::nvmf,
+    target::{iscsi, nvmf},
 };
 
 /// Newtype structure that represents a block device. The soundness of the API
@@ -165,6 +167,24 @@ where
             }),
         }
     }
+
+    /// share the bdev over iSCSI, the initiators authenticating with CHAP
+    /// if a secret is given
+    pub fn share_iscsi(
+        &self,
+        allowed_hosts: &[String],
+        chap_secret: Option<&str>,
+    ) -> Result<String, CoreError> {
+        iscsi::share(self, allowed_hosts, chap_secret).context(ShareIscsi {})
+    }
+
+    /// share the bdev as an emulated NVMe controller over vfio-user, to the
+    /// guests of the node
+    pub async fn share_vfio_user(&self) -> Result<String, CoreError> {
+        let subsystem = NvmfSubsystem::try_from(self).context(ShareNvmf {})?;
+        subsystem.start_vfio_user().await.context(ShareNvmf {})?;
+        Ok(self.share_uri().unwrap_or_default())
+    }
 }
 
 #[async_trait(? Send)]
@@ -220,7 +240,15 @@ where
                         .context(ShareNvmf {})?;
                 }
             }
-            Some(Protocol::Off) | None => {}
+            // the initiators allowed are fixed when the target is created
+            // the guest is the only host of a vfio-user controller
+            Some(
+                Protocol::Iscsi
+                | Protocol::VfioUser
+                | Protocol::Local
+                | Protocol::Off,
+            )
+            | None => {}
         }
 
         Ok(())
@@ -229,13 +257,17 @@ where
     /// unshare the bdev regardless of current active share
     async fn unshare(self: Pin<&mut Self>) -> Result<(), Self::Error> {
         match self.shared() {
-            Some(Protocol::Nvmf) => {
+            Some(Protocol::Nvmf | Protocol::VfioUser) => {
                 if let Some(ss) = NvmfSubsystem::nqn_lookup(self.name()) {
                     ss.stop().await.context(UnshareNvmf {})?;
                     ss.destroy();
                 }
             }
-            Some(Protocol::Off) | None => {}
+            Some(Protocol::Iscsi) => {
+                iscsi::unshare(self.name()).await.context(UnshareIscsi {})?;
+            }
+            // a local disk is detached by its owner
+            Some(Protocol::Local | Protocol::Off) | None => {}
         }
 
         Ok(())
@@ -245,7 +277,13 @@ where
     /// TODO: we could do better here
     fn shared(&self) -> Option<Protocol> {
         match self.claimed_by() {
-            Some(t) if t == "NVMe-oF Target" => Some(Protocol::Nvmf),
+            Some(t) if t == "NVMe-oF Target" => {
+                match NvmfSubsystem::nqn_lookup(self.name()) {
+                    Some(ss) if ss.is_vfio_user() => Some(Protocol::VfioUser),
+                    _ => Some(Protocol::Nvmf),
+                }
+            }
+            _ if iscsi::target(self.name()).is_some() => Some(Protocol::Iscsi),
             _ => Some(Protocol::Off),
         }
     }
@@ -253,7 +291,10 @@ where
     /// return share URI for nvmf (does "share path" not sound better?)
     fn share_uri(&self) -> Option<String> {
         match self.shared() {
-            Some(Protocol::Nvmf) => nvmf::get_uri(self.name()),
+            Some(Protocol::Nvmf | Protocol::VfioUser) => {
+                nvmf::get_uri(self.name())
+            }
+            Some(Protocol::Iscsi) => iscsi::get_uri(self.name()),
             _ => Some(format!("bdev:///{}", self.name())),
         }
     }
@@ -266,6 +307,9 @@ where
                     None => vec![],
                 }
             }
+            Some(Protocol::Iscsi) => iscsi::target(self.name())
+                .map(|t| t.allowed_hosts)
+                .unwrap_or_default(),
             _ => vec![],
         }
     }
@@ -0,0 +1,351 @@
+//! Per-bdev I/O latency histograms.
+//!
+//! SPDK can track the latency of every I/O completed by a bdev in a
+//! histogram of exponentially sized buckets. Once enabled, the histogram is
+//! kept per I/O channel and merged by SPDK when it is retrieved; the
+//! percentiles are computed here, so that the latency of the replicas and of
+//! the nexus on top of them can be compared directly.
+
+use std::os::raw::{c_int, c_void};
+
+use futures::{channel::oneshot, FutureExt};
+use nix::errno::Errno;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+
+use spdk_rs::libspdk::{
+    spdk_bdev_histogram_enable,
+    spdk_bdev_histogram_get,
+    spdk_get_ticks_hz,
+    spdk_histogram_data,
+};
+
+use crate::{
+    core::{Bdev, CoreError, HistogramEnable, HistogramGet, UntypedBdev},
+    ffihelper::{cb_arg, errno_result_from_i32, ErrnoResult},
+    jsonrpc::{jsonrpc_register, Code, JsonRpcError},
+};
+
+/// Bucket shift used by SPDK for the bdev histograms
+/// (SPDK_HISTOGRAM_BUCKET_SHIFT_DEFAULT).
+const BUCKET_SHIFT: u32 = 7;
+
+/// Number of buckets in each range of the histogram.
+const BUCKETS_PER_RANGE: u64 = 1 << BUCKET_SHIFT;
+
+/// Number of ranges of the histogram.
+const BUCKET_RANGES: u64 = 64 - BUCKET_SHIFT as u64 + 1;
+
+/// Percentiles reported when none are asked for.
+const DEFAULT_PERCENTILES: [f64; 5] = [50.0, 90.0, 99.0, 99.9, 99.99];
+
+/// Histogram data passed to SPDK to merge the per-channel histograms into.
+struct HistogramData {
+    data: spdk_histogram_data,
+    /// Storage of the buckets, `data.bucket` points into it.
+    storage: Box<[u64]>,
+}
+
+impl HistogramData {
+    fn new() -> Box<Self> {
+        let mut buckets =
+            vec![0u64; (BUCKETS_PER_RANGE * BUCKET_RANGES) as usize]
+                .into_boxed_slice();
+        Box::new(Self {
+            data: spdk_histogram_data {
+                bucket_shift: BUCKET_SHIFT,
+                bucket: buckets.as_mut_ptr(),
+            },
+            storage: buckets,
+        })
+    }
+
+    /// Returns the first tick accounted in the given bucket, this is a port of
+    /// `__spdk_histogram_data_get_bucket_start()`. The start of the bucket
+    /// following the last one does not fit in 64 bits, it is clamped.
+    fn bucket_start(range: u64, index: u64) -> u64 {
+        let index = index + 1;
+        if range > 0 {
+            let shl = |value: u64, shift: u64| {
+                value.checked_shl(shift as u32).unwrap_or(u64::MAX)
+            };
+            shl(1, range + BUCKET_SHIFT as u64 - 1)
+                .saturating_add(shl(index, range - 1))
+        } else {
+            index
+        }
+    }
+
+    /// Returns the non-empty buckets, as (start, end, count) in ticks.
+    fn buckets(&self) -> Vec<(u64, u64, u64)> {
+        let mut buckets = Vec::new();
+        let mut end = 0;
+        for range in 0 .. BUCKET_RANGES {
+            for index in 0 .. BUCKETS_PER_RANGE {
+                let count =
+                    self.storage[((range << BUCKET_SHIFT) + index) as usize];
+                let start = end;
+                end = Self::bucket_start(range, index);
+                if count > 0 {
+                    buckets.push((start, end, count));
+                }
+            }
+        }
+        buckets
+    }
+}
+
+/// Bucket of a latency histogram.
+#[derive(Debug, Clone, Serialize)]
+pub struct HistogramBucket {
+    /// Lower bound of the bucket, in microseconds.
+    pub start_us: f64,
+    /// Upper bound of the bucket, in microseconds.
+    pub end_us: f64,
+    /// Number of I/Os which completed within the bounds of the bucket.
+    pub count: u64,
+}
+
+/// Latency histogram of a bdev.
+#[derive(Debug, Clone, Serialize)]
+pub struct BdevHistogram {
+    /// Name of the bdev.
+    pub name: String,
+    /// Number of I/Os accounted for.
+    pub total: u64,
+    /// Non-empty buckets, ordered by latency.
+    pub buckets: Vec<HistogramBucket>,
+}
+
+impl BdevHistogram {
+    fn new(name: &str, data: &HistogramData) -> Self {
+        let hz = unsafe { spdk_get_ticks_hz() } as f64;
+        let to_us = |ticks: u64| ticks as f64 * 1_000_000.0 / hz;
+
+        let buckets = data
+            .buckets()
+            .into_iter()
+            .map(|(start, end, count)| HistogramBucket {
+                start_us: to_us(start),
+                end_us: to_us(end),
+                count,
+            })
+            .collect::<Vec<_>>();
+
+        Self {
+            name: name.to_string(),
+            total: buckets.iter().map(|b| b.count).sum(),
+            buckets,
+        }
+    }
+
+    /// Returns the latency, in microseconds, under which the given percentage
+    /// of the I/Os completed. The upper bound of the bucket is returned, so
+    /// the precision is that of the bucket.
+    pub fn percentile(&self, percentile: f64) -> Option<f64> {
+        if self.total == 0 {
+            return None;
+        }
+
+        let threshold = (self.total as f64 * percentile / 100.0).ceil() as u64;
+        let mut so_far = 0;
+        self.buckets
+            .iter()
+            .find(|b| {
+                so_far += b.count;
+                so_far >= threshold
+            })
+            .or_else(|| self.buckets.last())
+            .map(|b| b.end_us)
+    }
+}
+
+impl<T> Bdev<T>
+where
+    T: spdk_rs::BdevOps,
+{
+    /// Enable or disable the tracking of the I/O latency of the bdev.
+    /// Disabling the histogram discards the data collected so far.
+    pub async fn histogram_enable(
+        &self,
+        enable: bool,
+    ) -> Result<(), CoreError> {
+        extern "C" fn histogram_enable_cb(arg: *mut c_void, status: c_int) {
+            let sender = unsafe {
+                Box::from_raw(arg as *mut oneshot::Sender<ErrnoResult<()>>)
+            };
+            sender
+                .send(errno_result_from_i32((), status))
+                .expect("histogram enable receiver is gone");
+        }
+
+        let (s, r) = oneshot::channel::<ErrnoResult<()>>();
+
+        unsafe {
+            spdk_bdev_histogram_enable(
+                self.unsafe_inner_ptr() as *mut _,
+                Some(histogram_enable_cb),
+                cb_arg(s),
+                enable,
+            );
+        }
+
+        r.await.expect("histogram enable callback is gone").context(
+            HistogramEnable {
+                name: self.name().to_string(),
+            },
+        )
+    }
+
+    /// Get the latency histogram of the bdev. Fails with EFAULT if the
+    /// histogram has not been enabled.
+    pub async fn histogram(&self) -> Result<BdevHistogram, CoreError> {
+        extern "C" fn histogram_get_cb(
+            arg: *mut c_void,
+            status: c_int,
+            _histogram: *mut spdk_histogram_data,
+        ) {
+            let sender = unsafe {
+                Box::from_raw(arg as *mut oneshot::Sender<ErrnoResult<()>>)
+            };
+            sender
+                .send(errno_result_from_i32((), status))
+                .expect("histogram get receiver is gone");
+        }
+
+        let mut data = HistogramData::new();
+        let (s, r) = oneshot::channel::<ErrnoResult<()>>();
+
+        unsafe {
+            spdk_bdev_histogram_get(
+                self.unsafe_inner_ptr() as *mut _,
+                &mut data.data,
+                Some(histogram_get_cb),
+                cb_arg(s),
+            );
+        }
+
+        r.await.expect("histogram get callback is gone").context(
+            HistogramGet {
+                name: self.name().to_string(),
+            },
+        )?;
+
+        Ok(BdevHistogram::new(self.name(), &data))
+    }
+}
+
+/// Arguments of the `enable_bdev_histogram` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct EnableBdevHistogramArgs {
+    /// Name of the bdev.
+    name: String,
+    /// Whether to enable or to disable the histogram.
+    enable: bool,
+}
+
+/// Arguments of the `get_bdev_histogram` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct GetBdevHistogramArgs {
+    /// Name of the bdev.
+    name: String,
+    /// Percentiles to compute, defaults to DEFAULT_PERCENTILES.
+    #[serde(default)]
+    percentiles: Vec<f64>,
+}
+
+/// Latency under which the given percentage of the I/Os completed.
+#[derive(Debug, Serialize)]
+struct Percentile {
+    percentile: f64,
+    latency_us: Option<f64>,
+}
+
+/// Reply of the `get_bdev_histogram` json-rpc method.
+#[derive(Debug, Serialize)]
+struct GetBdevHistogramReply {
+    #[serde(flatten)]
+    histogram: BdevHistogram,
+    percentiles: Vec<Percentile>,
+}
+
+fn lookup(name: &str) -> Result<UntypedBdev, JsonRpcError> {
+    UntypedBdev::lookup_by_name(name).ok_or_else(|| {
+        JsonRpcError::new(Code::NotFound, format!("bdev {} not found", name))
+    })
+}
+
+fn rpc_error(error: CoreError) -> JsonRpcError {
+    match error {
+        CoreError::HistogramGet {
+            source: Errno::EFAULT,
+            ..
+        } => JsonRpcError::new(Code::InvalidParams, error.to_string()),
+        error => JsonRpcError::new(Code::InternalError, error.to_string()),
+    }
+}
+
+/// Register the bdev histogram json-rpc methods.
+pub(crate) fn register_rpc_methods() {
+    jsonrpc_register(
+        "enable_bdev_histogram",
+        |args: EnableBdevHistogramArgs| {
+            async move {
+                lookup(&args.name)?
+                    .histogram_enable(args.enable)
+                    .await
+                    .map_err(rpc_error)
+            }
+            .boxed_local()
+        },
+    );
+
+    jsonrpc_register("get_bdev_histogram", |args: GetBdevHistogramArgs| {
+        async move {
+            let histogram =
+                lookup(&args.name)?.histogram().await.map_err(rpc_error)?;
+
+            let percentiles = if args.percentiles.is_empty() {
+                DEFAULT_PERCENTILES.to_vec()
+            } else {
+                args.percentiles
+            };
+
+            Ok::<_, JsonRpcError>(GetBdevHistogramReply {
+                percentiles: percentiles
+                    .into_iter()
+                    .map(|percentile| Percentile {
+                        percentile,
+                        latency_us: histogram.percentile(percentile),
+                    })
+                    .collect(),
+                histogram,
+            })
+        }
+        .boxed_local()
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HistogramData, BUCKETS_PER_RANGE, BUCKET_RANGES};
+
+    #[test]
+    fn test_last_bucket() {
+        let last = HistogramData::bucket_start(
+            BUCKET_RANGES - 1,
+            BUCKETS_PER_RANGE - 1,
+        );
+        assert_eq!(last, u64::MAX);
+
+        // the buckets keep increasing up to the last one
+        let mut prev = 0;
+        for range in 0 .. BUCKET_RANGES {
+            for index in 0 .. BUCKETS_PER_RANGE {
+                let start = HistogramData::bucket_start(range, index);
+                assert!(start >= prev, "bucket {}/{}", range, index);
+                prev = start;
+            }
+        }
+    }
+}
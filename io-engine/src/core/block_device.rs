@@ -5,11 +5,12 @@ use spdk_rs::{DmaBuf, DmaError, IoVec};
 use async_trait::async_trait;
 use merge::Merge;
 use nix::errno::Errno;
+use serde::Serialize;
 use std::os::raw::c_void;
 use uuid::Uuid;
 
 /// TODO
-#[derive(Debug, Default, Clone, Copy, Merge)]
+#[derive(Debug, Default, Clone, Copy, Merge, Serialize)]
 pub struct BlockDeviceIoStats {
     #[merge(strategy = merge::num::saturating_add)]
     pub num_read_ops: u64,
@@ -25,6 +26,31 @@ pub struct BlockDeviceIoStats {
     pub bytes_unmapped: u64,
 }
 
+/// Statistics of the I/O queue pairs of an NVMe-oF device, as seen from the
+/// initiator side.
+#[derive(Debug, Default, Clone, Copy, Merge, Serialize)]
+pub struct BlockDeviceQpairStats {
+    /// Number of completed I/O operations, successful or not.
+    #[merge(strategy = merge::num::saturating_add)]
+    pub num_completions: u64,
+    /// Number of I/O operations completed with an error.
+    #[merge(strategy = merge::num::saturating_add)]
+    pub num_errors: u64,
+    /// Number of failed I/O operations which the target allows to retry.
+    #[merge(strategy = merge::num::saturating_add)]
+    pub num_retryable_errors: u64,
+    /// Number of I/O operations which could not be submitted due to the lack
+    /// of request resources and had to be queued by the caller.
+    #[merge(strategy = merge::num::saturating_add)]
+    pub num_no_resources: u64,
+    /// Number of times a qpair has been disconnected from the target.
+    #[merge(strategy = merge::num::saturating_add)]
+    pub num_disconnects: u64,
+    /// Number of times a qpair has been reconnected to the target.
+    #[merge(strategy = merge::num::saturating_add)]
+    pub num_reconnects: u64,
+}
+
 /// Core trait that represents a block device.
 /// TODO: Add text.
 #[async_trait(?Send)]
@@ -56,9 +82,29 @@ pub trait BlockDevice {
     /// Checks whether target I/O type is supported by the device.
     fn io_type_supported(&self, io_type: IoType) -> bool;
 
+    /// Returns the maximum number of blocks of a single I/O of the given
+    /// type, or None if the device accepts I/Os of any size.
+    fn max_io_blocks(&self, _io_type: IoType) -> Option<u64> {
+        None
+    }
+
+    /// Returns the size of the metadata of a block, which holds its
+    /// protection information if the device is formatted with any.
+    fn md_size(&self) -> u64 {
+        0
+    }
+
     /// Obtains I/O statistics for the device.
     async fn io_stats(&self) -> Result<BlockDeviceIoStats, CoreError>;
 
+    /// Obtains the statistics of the I/O queue pairs of the device, if the
+    /// device is accessed over queue pairs.
+    async fn qpair_stats(
+        &self,
+    ) -> Result<Option<BlockDeviceQpairStats>, CoreError> {
+        Ok(None)
+    }
+
     /// Checks if block device has been claimed.
     fn claimed_by(&self) -> Option<String>;
 
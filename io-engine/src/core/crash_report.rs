@@ -0,0 +1,362 @@
+//! Crash reports.
+//!
+//! Core dumps of the io-engine are huge as they include the hugepages, and
+//! are seldom available. Instead, when the process panics or aborts, a compact
+//! JSON report is written out with the most recent trace events and the state
+//! of the reactors, nexuses, children and rebuild jobs.
+//!
+//! The state is collected from whichever thread is crashing, without any
+//! synchronisation with the reactors, so the report is a best effort.
+//!
+//! An abort, e.g. an assertion of SPDK, is handled in a signal handler,
+//! where nothing which is not async-signal-safe may run: no allocation,
+//! formatting or locking. Its report is rendered beforehand, and re-rendered
+//! periodically with a snapshot of the state, so the handler merely writes
+//! out the most recent one.
+
+use std::{
+    cell::UnsafeCell,
+    ffi::CString,
+    fs,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+
+use super::{reactor::REACTOR_LIST, reactor_is_frozen, Reactor};
+use crate::{bdev::nexus::nexus_iter, logger};
+
+/// Directory the crash reports are written to.
+static REPORT_DIR: OnceCell<PathBuf> = OnceCell::new();
+
+/// Report written on abort, rendered on startup.
+static ABORT_REPORT: OnceCell<AbortReport> = OnceCell::new();
+
+/// Set once a report has been written, as a panic is followed by an abort.
+static REPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Interval at which the report written on abort is re-rendered.
+const ABORT_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Report written on abort, rendered beforehand as the signal handler cannot
+/// allocate.
+///
+/// The body is double buffered: the buffer at `current` holds a complete
+/// rendering, which the handler writes out, while the other one is
+/// re-rendered, on the primary reactor only.
+struct AbortReport {
+    path: CString,
+    started: String,
+    bodies: [UnsafeCell<Vec<u8>>; 2],
+    current: AtomicUsize,
+    message: Vec<u8>,
+}
+
+// The buffer at `current` is never written to, and the other one is only
+// written to from the primary reactor.
+unsafe impl Sync for AbortReport {}
+
+/// Content of the report written on abort.
+#[derive(Serialize)]
+struct AbortContent<'a> {
+    /// Start time of the process, in RFC 3339 format.
+    started: &'a str,
+    /// Time the state was taken at, as the time of the abort cannot be
+    /// formatted.
+    snapshot_time: String,
+    pid: u32,
+    version: &'static str,
+    reason: &'static str,
+    #[serde(flatten)]
+    state: EngineState,
+}
+
+impl AbortReport {
+    fn new(dir: &Path) -> Option<Self> {
+        let pid = std::process::id();
+        let path = dir.join(format!("io-engine-abort-{}.json", pid));
+        let report = Self {
+            path: CString::new(path.as_os_str().as_bytes()).ok()?,
+            started: chrono::Local::now().to_rfc3339(),
+            bodies: Default::default(),
+            current: AtomicUsize::new(0),
+            message: format!("crash report written to {}\n", path.display())
+                .into_bytes(),
+        };
+        report.render();
+        Some(report)
+    }
+
+    /// Render the report with a snapshot of the current state, and make it
+    /// the one written out on abort.
+    fn render(&self) {
+        // the handler may be reading either buffer by now
+        if REPORTED.load(Ordering::SeqCst) {
+            return;
+        }
+        let content = AbortContent {
+            started: &self.started,
+            snapshot_time: chrono::Local::now().to_rfc3339(),
+            pid: std::process::id(),
+            version: env!("CARGO_PKG_VERSION"),
+            reason: "received SIGABRT",
+            state: EngineState::collect(),
+        };
+        let next = 1 - self.current.load(Ordering::Acquire);
+        let body = unsafe { &mut *self.bodies[next].get() };
+        body.clear();
+        if serde_json::to_writer_pretty(&mut *body, &content).is_ok() {
+            self.current.store(next, Ordering::Release);
+        }
+    }
+
+    /// Write the report out. Only calls async-signal-safe functions.
+    fn write(&self) {
+        unsafe {
+            let fd = libc::open(
+                self.path.as_ptr(),
+                libc::O_WRONLY
+                    | libc::O_CREAT
+                    | libc::O_TRUNC
+                    | libc::O_CLOEXEC,
+                0o644,
+            );
+            if fd < 0 {
+                return;
+            }
+            let body =
+                &*self.bodies[self.current.load(Ordering::Acquire)].get();
+            libc::write(fd, body.as_ptr() as *const _, body.len());
+            libc::close(fd);
+            libc::write(
+                libc::STDERR_FILENO,
+                self.message.as_ptr() as *const _,
+                self.message.len(),
+            );
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ReactorReport {
+    core: u32,
+    tid: u64,
+    state: String,
+    frozen: bool,
+}
+
+#[derive(Serialize)]
+struct RebuildReport {
+    source: String,
+    state: String,
+    progress: u64,
+    blocks_recovered: u64,
+    blocks_total: u64,
+}
+
+#[derive(Serialize)]
+struct ChildReport {
+    uri: String,
+    state: String,
+    rebuild: Option<RebuildReport>,
+}
+
+#[derive(Serialize)]
+struct NexusReport {
+    name: String,
+    uuid: String,
+    status: String,
+    children: Vec<ChildReport>,
+}
+
+/// State of the io-engine, collected when a report is rendered.
+#[derive(Serialize)]
+struct EngineState {
+    reactors: Vec<ReactorReport>,
+    nexuses: Vec<NexusReport>,
+    /// Most recent trace events, oldest first.
+    recent_events: Vec<String>,
+}
+
+impl EngineState {
+    fn collect() -> Self {
+        // the nexuses can only be looked at once the reactors (and thus SPDK)
+        // are up
+        let reactors = REACTOR_LIST.get().map(|list| {
+            list.into_iter()
+                .map(|r| ReactorReport {
+                    core: r.core(),
+                    tid: r.tid(),
+                    state: r.get_state().to_string(),
+                    frozen: reactor_is_frozen(r.core()),
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let nexuses = if reactors.is_some() {
+            nexus_iter()
+                .map(|n| NexusReport {
+                    name: n.name.clone(),
+                    uuid: n.uuid().to_string(),
+                    status: n.status().to_string(),
+                    children: n
+                        .children_iter()
+                        .map(|c| ChildReport {
+                            uri: c.uri().to_string(),
+                            state: c.state().to_string(),
+                            rebuild: c.rebuild_job().map(|job| {
+                                let stats = job.stats();
+                                RebuildReport {
+                                    source: job.src_uri.clone(),
+                                    state: job.state().to_string(),
+                                    progress: stats.progress,
+                                    blocks_recovered: stats.blocks_recovered,
+                                    blocks_total: stats.blocks_total,
+                                }
+                            }),
+                        })
+                        .collect(),
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        Self {
+            reactors: reactors.unwrap_or_default(),
+            nexuses,
+            recent_events: logger::recent_events(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CrashReport {
+    /// Time of the crash, in RFC 3339 format.
+    time: String,
+    pid: u32,
+    version: &'static str,
+    /// Panic message and location, or the signal which was received.
+    reason: String,
+    /// Name of the crashing thread.
+    thread: Option<String>,
+    #[serde(flatten)]
+    state: EngineState,
+}
+
+impl CrashReport {
+    fn new(reason: &str) -> Self {
+        Self {
+            time: chrono::Local::now().to_rfc3339(),
+            pid: std::process::id(),
+            version: env!("CARGO_PKG_VERSION"),
+            reason: reason.to_string(),
+            thread: std::thread::current().name().map(String::from),
+            state: EngineState::collect(),
+        }
+    }
+
+    /// Write the report to a new file in the given directory.
+    fn write(&self, dir: &Path) -> std::io::Result<PathBuf> {
+        fs::create_dir_all(dir)?;
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let path =
+            dir.join(format!("io-engine-crash-{}-{}.json", self.pid, secs));
+        let file = fs::File::create(&path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(path)
+    }
+}
+
+/// Write the crash report, unless it has been written already.
+fn report(reason: &str) {
+    let dir = match REPORT_DIR.get() {
+        Some(dir) => dir,
+        None => return,
+    };
+
+    if REPORTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    match CrashReport::new(reason).write(dir) {
+        Ok(path) => eprintln!("crash report written to {}", path.display()),
+        Err(error) => eprintln!("failed to write the crash report: {}", error),
+    }
+}
+
+/// Write the report rendered for an abort, unless a report has been written
+/// already. Runs in the signal handler.
+fn report_abort() {
+    let report = match ABORT_REPORT.get() {
+        Some(report) => report,
+        None => return,
+    };
+
+    if REPORTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    report.write();
+}
+
+/// Periodically re-render the report written on abort with the current
+/// state, on the primary reactor.
+pub async fn abort_report_loop() {
+    if ABORT_REPORT.get().is_none() {
+        return;
+    }
+    let mut interval = tokio::time::interval(ABORT_REPORT_INTERVAL);
+    loop {
+        interval.tick().await;
+        let rendered = Reactor::spawn_at_primary(async {
+            if let Some(report) = ABORT_REPORT.get() {
+                report.render();
+            }
+        });
+        if let Ok(rendered) = rendered {
+            rendered.await.ok();
+        }
+    }
+}
+
+/// Install the panic and abort handlers writing crash reports to the given
+/// directory.
+pub fn install(dir: PathBuf) {
+    // the abort handler cannot create the directory
+    if let Err(error) = fs::create_dir_all(&dir) {
+        error!(
+            %error,
+            "Failed to create the crash report directory {}",
+            dir.display()
+        );
+    }
+    if let Some(report) = AbortReport::new(&dir) {
+        let _ = ABORT_REPORT.set(report);
+    }
+    if REPORT_DIR.set(dir).is_err() {
+        return;
+    }
+
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        previous(info);
+        report(&info.to_string());
+    }));
+
+    // covers the assertions of SPDK and of the other C libraries
+    if let Err(error) = unsafe {
+        signal_hook::low_level::register(
+            signal_hook::consts::SIGABRT,
+            report_abort,
+        )
+    } {
+        error!(%error, "Failed to install the SIGABRT crash report handler");
+    }
+}
@@ -1,19 +1,78 @@
+//! Descriptors of the bdevs.
+//!
+//! SPDK requires a bdev descriptor to be closed on the thread it was opened
+//! on, and asserts so. Descriptors are however dropped on whatever thread
+//! their last user runs on: a target thread, a poll group, or a thread which
+//! is not an SPDK thread at all. Each descriptor thus records the thread
+//! which opened it, and is closed by a message to that thread when dropped
+//! elsewhere, or fails with an error rather than a panic when the thread
+//! has exited since.
+//!
+//! The open descriptors are kept in a registry along with their thread, so
+//! that the descriptors holding a bdev open can be listed.
+
 use std::{
+    collections::HashMap,
     convert::TryFrom,
     fmt::{Debug, Error, Formatter},
     ops::Deref,
+    os::raw::c_void,
 };
 
-use spdk_rs::{BdevDesc, BdevModule, BdevOps, Thread};
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use spdk_rs::{
+    libspdk::{spdk_thread_get_by_id, spdk_thread_send_msg},
+    BdevDesc,
+    BdevModule,
+    BdevOps,
+    Thread,
+};
 
 use crate::{
     bdev::nexus::NEXUS_MODULE_NAME,
     core::{Bdev, BdevHandle, CoreError},
+    jsonrpc::{jsonrpc_register, JsonRpcError},
 };
 
+/// Open descriptors, by address.
+static DESCRIPTORS: Lazy<Mutex<HashMap<usize, DescriptorInfo>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Open descriptor, as kept in the registry.
+#[derive(Serialize, Debug, Clone)]
+pub struct DescriptorInfo {
+    /// Name of the bdev.
+    pub bdev: String,
+    /// Name and id of the thread which opened the descriptor.
+    pub thread: String,
+    pub thread_id: u64,
+    /// Time the descriptor was opened, in RFC 3339 format.
+    pub opened: String,
+}
+
+/// Returns the open descriptors of the given bdev, or of all the bdevs.
+pub fn open_descriptors(bdev: Option<&str>) -> Vec<DescriptorInfo> {
+    let mut list = DESCRIPTORS
+        .lock()
+        .values()
+        .filter(|d| bdev.map_or(true, |b| d.bdev == b))
+        .cloned()
+        .collect::<Vec<_>>();
+    list.sort_by(|a, b| a.bdev.cmp(&b.bdev).then(a.opened.cmp(&b.opened)));
+    list
+}
+
 /// RAII Wrapper for spdk_rs::BdevDesc<T>.
 /// When this structure is dropped, the descriptor is closed.
-pub struct DescriptorGuard<T: BdevOps>(BdevDesc<T>);
+pub struct DescriptorGuard<T: BdevOps> {
+    desc: BdevDesc<T>,
+    /// Thread the descriptor was opened on, which must close it.
+    owner: Thread,
+    closed: bool,
+}
 
 pub type UntypedDescriptorGuard = DescriptorGuard<()>;
 
@@ -21,14 +80,28 @@ impl<T: BdevOps> Deref for DescriptorGuard<T> {
     type Target = BdevDesc<T>;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.desc
     }
 }
 
 impl<T: BdevOps> DescriptorGuard<T> {
-    /// TODO
+    /// Wrap a descriptor opened on the current thread.
     pub(crate) fn new(d: BdevDesc<T>) -> Self {
-        Self(d)
+        let owner = Thread::current().unwrap_or_else(Thread::primary);
+        DESCRIPTORS.lock().insert(
+            d.legacy_as_ptr() as usize,
+            DescriptorInfo {
+                bdev: d.bdev().name().to_string(),
+                thread: owner.name().to_string(),
+                thread_id: owner.id(),
+                opened: chrono::Utc::now().to_rfc3339(),
+            },
+        );
+        Self {
+            desc: d,
+            owner,
+            closed: false,
+        }
     }
 
     /// claim the bdev for exclusive access, when the descriptor is in read-only
@@ -37,7 +110,7 @@ impl<T: BdevOps> DescriptorGuard<T> {
     /// Conversely, Preexisting writers will not be downgraded.
     pub fn claim(&self) -> bool {
         match BdevModule::find_by_name(NEXUS_MODULE_NAME) {
-            Ok(m) => m.claim_bdev(&self.0.bdev(), &self.0).is_ok(),
+            Ok(m) => m.claim_bdev(&self.desc.bdev(), &self.desc).is_ok(),
             Err(err) => {
                 error!("{}", err);
                 false
@@ -49,7 +122,7 @@ impl<T: BdevOps> DescriptorGuard<T> {
     pub fn unclaim(&self) {
         match BdevModule::find_by_name(NEXUS_MODULE_NAME) {
             Ok(m) => {
-                if let Err(err) = m.release_bdev(&self.0.bdev()) {
+                if let Err(err) = m.release_bdev(&self.desc.bdev()) {
                     error!("{}", err)
                 }
             }
@@ -62,13 +135,67 @@ impl<T: BdevOps> DescriptorGuard<T> {
     /// Return the bdev associated with this descriptor, a descriptor cannot
     /// exist without a bdev
     pub fn bdev(&self) -> Bdev<T> {
-        Bdev::new(self.0.bdev())
+        Bdev::new(self.desc.bdev())
     }
 
     /// Consumes the descriptor and returns a handle.
     pub fn into_handle(self) -> Result<BdevHandle<T>, CoreError> {
         BdevHandle::try_from(self)
     }
+
+    /// Close the descriptor, on the thread it was opened on. When called
+    /// from another thread, the descriptor is closed once that thread has
+    /// processed the message.
+    pub fn close(mut self) -> Result<(), CoreError> {
+        self.release()
+    }
+
+    fn release(&mut self) -> Result<(), CoreError> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        let info = DESCRIPTORS
+            .lock()
+            .remove(&(self.desc.legacy_as_ptr() as usize));
+
+        if Thread::current() == Some(self.owner) {
+            self.desc.close();
+            return Ok(());
+        }
+
+        let error = |reason: String| CoreError::DescriptorClose {
+            name: self.desc.bdev().name().to_string(),
+            reason,
+        };
+
+        // the owner thread may have exited since the descriptor was opened
+        let owner = unsafe { spdk_thread_get_by_id(self.owner.id()) };
+        if owner.is_null() {
+            return Err(error(format!(
+                "thread '{}' which opened it has exited",
+                info.map_or_else(|| self.owner.id().to_string(), |i| i.thread)
+            )));
+        }
+
+        extern "C" fn close_desc<T: BdevOps>(arg: *mut c_void) {
+            let mut desc = unsafe { Box::from_raw(arg as *mut BdevDesc<T>) };
+            desc.close();
+        }
+
+        let desc = Box::into_raw(Box::new(self.desc.clone()));
+        let rc = unsafe {
+            spdk_thread_send_msg(owner, Some(close_desc::<T>), desc.cast())
+        };
+        if rc != 0 {
+            drop(unsafe { Box::from_raw(desc) });
+            return Err(error(format!(
+                "failed to send the close to its thread: {}",
+                rc
+            )));
+        }
+        Ok(())
+    }
 }
 
 /// When we get removed we might be asked to close ourselves, however, this
@@ -76,10 +203,8 @@ impl<T: BdevOps> DescriptorGuard<T> {
 /// running on their own thread.
 impl<T: BdevOps> Drop for DescriptorGuard<T> {
     fn drop(&mut self) {
-        if Thread::current().unwrap() == Thread::primary() {
-            self.0.close()
-        } else {
-            Thread::primary().send_msg(self.0.clone(), |mut d| d.close());
+        if let Err(error) = self.release() {
+            error!("{:?}: {}", self, error);
         }
     }
 }
@@ -90,7 +215,29 @@ impl<T: BdevOps> Debug for DescriptorGuard<T> {
             f,
             "Descriptor {:p} for bdev: {}",
             self.legacy_as_ptr(),
-            self.0.bdev().name()
+            self.desc.bdev().name()
         )
     }
 }
+
+/// Arguments of the `bdev_descriptors` json-rpc method.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct DescriptorsArgs {
+    /// Name of the bdev, all the bdevs if not given.
+    bdev: Option<String>,
+}
+
+/// Register the descriptor json-rpc methods.
+pub(crate) fn register_rpc_methods() {
+    jsonrpc_register::<_, _, _, JsonRpcError>(
+        "bdev_descriptors",
+        |args: Option<DescriptorsArgs>| {
+            async move {
+                let args = args.unwrap_or_default();
+                Ok(open_descriptors(args.bdev.as_deref()))
+            }
+            .boxed_local()
+        },
+    );
+}
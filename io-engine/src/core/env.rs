@@ -42,6 +42,7 @@ use crate::{
     bdev::{bdev_io_ctx_pool_init, nexus, nvme_io_ctx_pool_init},
     constants::NVME_NQN_PREFIX,
     core::{
+        hugepages,
         nic,
         reactor::{Reactor, ReactorState, Reactors},
         Cores,
@@ -49,9 +50,16 @@ use crate::{
         Mthread,
     },
     grpc,
-    grpc::MayastorGrpcServer,
+    grpc::{v1::state::start_state_publisher, MayastorGrpcServer},
     logger,
+    lvs::{
+        start_snapshot_export_monitor,
+        start_snapshot_scheduler,
+        start_usage_monitor,
+    },
+    metrics::MetricsServer,
     persistent_store::PersistentStore,
+    reconcile::{reconcile, ReconcilePolicy},
     subsys::{
         self,
         registration::registration_grpc::ApiVersion,
@@ -95,6 +103,41 @@ pub struct MayastorCliArgs {
     #[structopt(short = "R")]
     /// Registration grpc endpoint
     pub registration_endpoint: Option<Uri>,
+    #[structopt(long = "metrics-endpoint", env = "METRICS_ENDPOINT")]
+    /// IP address and port for the Prometheus metrics exporter to listen on.
+    /// The exporter is disabled if not specified.
+    pub metrics_endpoint: Option<std::net::SocketAddr>,
+    #[structopt(long = "otlp-endpoint", env = "OTLP_ENDPOINT")]
+    /// OpenTelemetry collector endpoint (i.e http://collector:4317) to export
+    /// the trace spans to, over OTLP/gRPC. Tracing is disabled if not
+    /// specified.
+    pub otlp_endpoint: Option<String>,
+    #[structopt(long = "crash-report-dir", env = "CRASH_REPORT_DIR")]
+    /// Directory to write a crash report to, should the io-engine panic or
+    /// abort. No report is written if not specified.
+    pub crash_report_dir: Option<String>,
+    #[structopt(long = "audit-log", env = "AUDIT_LOG")]
+    /// File to append the audit log of the gRPC operations to. The log is
+    /// only kept in memory if not specified.
+    pub audit_log: Option<String>,
+    #[structopt(
+        long = "audit-log-size",
+        default_value = "16",
+        env = "AUDIT_LOG_SIZE"
+    )]
+    /// Size of the audit log file, in MiB, beyond which it is rotated.
+    pub audit_log_size: u64,
+    #[structopt(
+        long = "audit-log-files",
+        default_value = "4",
+        env = "AUDIT_LOG_FILES"
+    )]
+    /// Number of rotated audit log files kept.
+    pub audit_log_files: usize,
+    #[structopt(long = "grpc-auth-policy", env = "GRPC_AUTH_POLICY")]
+    /// YAML file of the policy authenticating and authorizing the gRPC
+    /// requests. Any request is accepted if not specified.
+    pub grpc_auth_policy: Option<String>,
     #[structopt(short = "L")]
     /// Enable logging for sub components.
     pub log_components: Vec<String>,
@@ -139,6 +182,38 @@ pub struct MayastorCliArgs {
     #[structopt(short = "p")]
     /// Endpoint of the persistent store.
     pub persistent_store_endpoint: Option<String>,
+    #[structopt(
+        long = "ps-lease-ttl",
+        default_value = "10",
+        env = "PS_LEASE_TTL"
+    )]
+    /// Time-to-live, in seconds, of the persistent store lease to which
+    /// ownership records are attached.
+    pub persistent_store_lease_ttl: u64,
+    #[structopt(
+        long = "reconcile-policy",
+        default_value = "report",
+        env = "RECONCILE_POLICY"
+    )]
+    /// Policy applied on startup to the nexuses and replicas recorded in the
+    /// persistent store: report, restore or prune.
+    pub reconcile_policy: ReconcilePolicy,
+    #[structopt(long = "handoff-socket", env = "HANDOFF_SOCKET")]
+    /// Path of the unix socket on which to hand over the pools, nexuses and
+    /// NVMf listeners to a new io-engine process, for in-place upgrades.
+    pub handoff_socket: Option<String>,
+    #[structopt(long = "take-over", env = "TAKE_OVER")]
+    /// Path of the handoff socket of a running io-engine process to take
+    /// over the pools, nexuses and NVMf listeners from.
+    pub take_over: Option<String>,
+    #[structopt(
+        long = "handoff-max-pause",
+        default_value = "10",
+        env = "HANDOFF_MAX_PAUSE"
+    )]
+    /// Longest time, in seconds, for which the I/O of the volumes may be
+    /// paused when taking over, past which the handoff is aborted.
+    pub handoff_max_pause: u64,
     #[structopt(long = "bdev-pool-size", default_value = "65535")]
     /// Number of entries in memory pool for bdev I/O contexts
     pub bdev_io_ctx_pool_size: u64,
@@ -169,6 +244,47 @@ pub struct MayastorCliArgs {
         env = "REACTOR_FREEZE_TIMEOUT"
     )]
     pub reactor_freeze_timeout: Option<u64>,
+    /// Run without hugepages and PCIe devices, allocating the I/O memory
+    /// from the heap and allowing only malloc and null bdevs. Meant for
+    /// functional tests on machines which cannot reserve hugepages.
+    #[structopt(long)]
+    pub simulate: bool,
+    /// Advertise the NVMF discovery controller and the shared subsystems
+    /// over mDNS/DNS-SD, for hosts discovering the targets without asking
+    /// the control plane.
+    #[structopt(long, env = "MDNS_ADVERTISE")]
+    pub mdns: bool,
+    /// Maximum ratio of the capacity of the replicas of a pool to its
+    /// physical capacity, beyond which new replicas are refused. Unbounded
+    /// if not specified.
+    #[structopt(long, env = "OVERCOMMIT_RATIO")]
+    pub overcommit_ratio: Option<f64>,
+    /// Number of cores of each nexus connecting to its remote children, the
+    /// other cores forwarding their I/Os to them. All the cores connect if
+    /// not specified.
+    #[structopt(long, env = "CHILD_SHARD_CORES")]
+    pub child_shard_cores: Option<u32>,
+    /// Interval in seconds at which the NVMF poll groups are rebalanced over
+    /// the cores by their load. Only rebalanced on request if not specified.
+    #[structopt(long, env = "POLL_GROUP_REBALANCE_INTERVAL")]
+    pub poll_group_rebalance_interval: Option<u64>,
+    /// Physical usage watermarks of the pools, in percent, which raise an
+    /// alert when crossed.
+    #[structopt(
+        long,
+        value_delimiter = ",",
+        default_value = "60,80,90",
+        env = "POOL_WATERMARKS"
+    )]
+    pub pool_watermarks: Vec<u8>,
+    /// Reserve the hugepages missing on the NUMA nodes of the reactor cores
+    /// at startup, which requires write access to sysfs.
+    #[structopt(long, env = "HUGEPAGE_RESERVE")]
+    pub hugepage_reserve: bool,
+    /// Pre-fault the hugepage memory required on each NUMA node of the
+    /// reactor cores at startup, rather than allocating it on demand.
+    #[structopt(long, env = "HUGEPAGE_PREFAULT")]
+    pub hugepage_prefault: bool,
 }
 
 /// Mayastor features.
@@ -194,7 +310,19 @@ impl Default for MayastorCliArgs {
     fn default() -> Self {
         Self {
             grpc_endpoint: grpc::default_endpoint().to_string(),
+            metrics_endpoint: None,
+            otlp_endpoint: None,
+            crash_report_dir: None,
+            audit_log: None,
+            audit_log_size: 16,
+            audit_log_files: 4,
+            grpc_auth_policy: None,
             persistent_store_endpoint: None,
+            persistent_store_lease_ttl: 10,
+            reconcile_policy: ReconcilePolicy::default(),
+            handoff_socket: None,
+            take_over: None,
+            handoff_max_pause: 10,
             node_name: None,
             env_context: None,
             reactor_mask: "0x1".into(),
@@ -216,6 +344,14 @@ impl Default for MayastorCliArgs {
             diagnose_stack: None,
             reactor_freeze_detection: false,
             reactor_freeze_timeout: None,
+            simulate: false,
+            mdns: false,
+            overcommit_ratio: None,
+            child_shard_cores: None,
+            poll_group_rebalance_interval: None,
+            pool_watermarks: vec![60, 80, 90],
+            hugepage_reserve: false,
+            hugepage_prefault: false,
         }
     }
 }
@@ -227,6 +363,11 @@ impl MayastorCliArgs {
     }
 }
 
+/// IP address of the NVMF target its listeners were migrated to, which
+/// overrides the address detected at startup.
+static MIGRATED_TGT_IP: Lazy<Mutex<Option<String>>> =
+    Lazy::new(|| Mutex::new(None));
+
 /// Global exit code of the program, initially set to -1 to capture double
 /// shutdown during test cases
 pub static GLOBAL_RC: Lazy<Arc<Mutex<i32>>> =
@@ -276,7 +417,11 @@ pub struct MayastorEnvironment {
     node_nqn: Option<String>,
     pub grpc_endpoint: Option<std::net::SocketAddr>,
     pub registration_endpoint: Option<Uri>,
+    metrics_endpoint: Option<std::net::SocketAddr>,
     persistent_store_endpoint: Option<String>,
+    persistent_store_lease_ttl: u64,
+    reconcile_policy: ReconcilePolicy,
+    pub take_over: Option<String>,
     mayastor_config: Option<String>,
     ptpl_dir: Option<String>,
     pool_config: Option<String>,
@@ -309,6 +454,12 @@ pub struct MayastorEnvironment {
     nvme_ctl_io_ctx_pool_size: u64,
     nvmf_tgt_interface: Option<String>,
     api_versions: Vec<ApiVersion>,
+    simulate: bool,
+    mdns: bool,
+    overcommit_ratio: Option<f64>,
+    child_shard_cores: Option<u32>,
+    poll_group_rebalance_interval: Option<u64>,
+    pool_watermarks: Vec<u8>,
 }
 
 impl Default for MayastorEnvironment {
@@ -318,7 +469,11 @@ impl Default for MayastorEnvironment {
             node_nqn: None,
             grpc_endpoint: None,
             registration_endpoint: None,
+            metrics_endpoint: None,
             persistent_store_endpoint: None,
+            persistent_store_lease_ttl: 10,
+            reconcile_policy: ReconcilePolicy::default(),
+            take_over: None,
             mayastor_config: None,
             ptpl_dir: None,
             pool_config: None,
@@ -351,6 +506,12 @@ impl Default for MayastorEnvironment {
             nvme_ctl_io_ctx_pool_size: 65535,
             nvmf_tgt_interface: None,
             api_versions: vec![ApiVersion::V0, ApiVersion::V1],
+            simulate: false,
+            mdns: false,
+            overcommit_ratio: None,
+            child_shard_cores: None,
+            poll_group_rebalance_interval: None,
+            pool_watermarks: vec![60, 80, 90],
         }
     }
 }
@@ -437,12 +598,24 @@ static MAYASTOR_FEATURES: OnceCell<MayastorFeatures> = OnceCell::new();
 
 static MAYASTOR_DEFAULT_ENV: OnceCell<MayastorEnvironment> = OnceCell::new();
 
+/// Memory, in MiB, reserved from the heap in simulation mode when no memory
+/// size is given.
+const SIMULATE_MEM_SIZE: i32 = 1024;
+
+/// Largest memory pools of I/O contexts in simulation mode, so that they fit
+/// in the memory reserved.
+const SIMULATE_IO_CTX_POOL_SIZE: u64 = 4096;
+
 impl MayastorEnvironment {
     pub fn new(args: MayastorCliArgs) -> Self {
         Self {
             grpc_endpoint: Some(grpc::endpoint(args.grpc_endpoint)),
             registration_endpoint: args.registration_endpoint,
+            metrics_endpoint: args.metrics_endpoint,
             persistent_store_endpoint: args.persistent_store_endpoint,
+            persistent_store_lease_ttl: args.persistent_store_lease_ttl,
+            reconcile_policy: args.reconcile_policy,
+            take_over: args.take_over,
             node_name: args.node_name.clone().unwrap_or_else(|| {
                 env::var("HOSTNAME").unwrap_or_else(|_| "mayastor-node".into())
             }),
@@ -462,15 +635,68 @@ impl MayastorEnvironment {
             hugedir: args.hugedir,
             env_context: args.env_context,
             core_list: args.core_list,
-            bdev_io_ctx_pool_size: args.bdev_io_ctx_pool_size,
-            nvme_ctl_io_ctx_pool_size: args.nvme_ctl_io_ctx_pool_size,
+            bdev_io_ctx_pool_size: if args.simulate {
+                args.bdev_io_ctx_pool_size.min(SIMULATE_IO_CTX_POOL_SIZE)
+            } else {
+                args.bdev_io_ctx_pool_size
+            },
+            nvme_ctl_io_ctx_pool_size: if args.simulate {
+                args.nvme_ctl_io_ctx_pool_size
+                    .min(SIMULATE_IO_CTX_POOL_SIZE)
+            } else {
+                args.nvme_ctl_io_ctx_pool_size
+            },
             nvmf_tgt_interface: args.nvmf_tgt_interface,
             api_versions: args.api_versions,
+            simulate: args.simulate,
+            mdns: args.mdns,
+            overcommit_ratio: args.overcommit_ratio,
+            child_shard_cores: args.child_shard_cores,
+            poll_group_rebalance_interval: args.poll_group_rebalance_interval,
+            pool_watermarks: {
+                let mut watermarks = args.pool_watermarks;
+                watermarks.sort_unstable();
+                watermarks.dedup();
+                watermarks
+            },
             ..Default::default()
         }
         .setup_static()
     }
 
+    /// Returns true if the io-engine runs in simulation mode, without
+    /// hugepages and with malloc and null bdevs only.
+    pub fn simulate(&self) -> bool {
+        self.simulate
+    }
+
+    /// Returns true if the NVMF targets are advertised over mDNS.
+    pub fn mdns(&self) -> bool {
+        self.mdns
+    }
+
+    /// Returns the default overcommit ratio of the pools.
+    pub fn overcommit_ratio(&self) -> Option<f64> {
+        self.overcommit_ratio
+    }
+
+    /// Returns the number of cores of each nexus connecting to its remote
+    /// children, if sharded.
+    pub fn child_shard_cores(&self) -> Option<u32> {
+        self.child_shard_cores
+    }
+
+    /// Returns the interval in seconds at which the poll groups are
+    /// rebalanced, if periodically.
+    pub fn poll_group_rebalance_interval(&self) -> Option<u64> {
+        self.poll_group_rebalance_interval
+    }
+
+    /// Returns the default usage watermarks of the pools, in percent.
+    pub fn pool_watermarks(&self) -> Vec<u8> {
+        self.pool_watermarks.clone()
+    }
+
     /// Get the persistence through power loss directory.
     pub fn ptpl_dir(&self) -> Option<String> {
         self.ptpl_dir.clone()
@@ -523,7 +749,31 @@ impl MayastorEnvironment {
             args.push(CString::new("--no-shconf").unwrap());
         }
 
-        if self.mem_size >= 0 {
+        if self.simulate {
+            // without hugepages DPDK reserves the given amount of memory from
+            // the heap upfront, so it cannot be left unlimited
+            let mem_size = if self.mem_size > 0 {
+                self.mem_size
+            } else {
+                SIMULATE_MEM_SIZE
+            };
+            args.push(CString::new(format!("-m {}", mem_size)).unwrap());
+            args.push(CString::new("--no-huge").unwrap());
+        } else if let Some(socket_mem) = hugepages::socket_mem() {
+            // the memory of each node is allocated upfront, which supersedes
+            // the memory size
+            args.push(
+                CString::new(format!(
+                    "--socket-mem={}",
+                    socket_mem
+                        .iter()
+                        .map(|mb| mb.to_string())
+                        .collect::<Vec<_>>()
+                        .join(",")
+                ))
+                .unwrap(),
+            );
+        } else if self.mem_size >= 0 {
             args.push(CString::new(format!("-m {}", self.mem_size)).unwrap());
         }
 
@@ -534,15 +784,15 @@ impl MayastorEnvironment {
             );
         }
 
-        if self.no_pci {
+        if self.no_pci || self.simulate {
             args.push(CString::new("--no-pci").unwrap());
         }
 
-        if self.hugepage_single_segments {
+        if self.hugepage_single_segments && !self.simulate {
             args.push(CString::new("--single-file-segments").unwrap());
         }
 
-        if self.hugedir.is_some() {
+        if self.hugedir.is_some() && !self.simulate {
             args.push(
                 CString::new(format!(
                     "--huge-dir={}",
@@ -575,7 +825,7 @@ impl MayastorEnvironment {
             args.push(CString::new("--proc-type=auto").unwrap());
         }
 
-        if self.unlink_hugepage {
+        if self.unlink_hugepage && !self.simulate {
             args.push(CString::new("--huge-unlink".to_string()).unwrap());
         }
 
@@ -584,7 +834,10 @@ impl MayastorEnvironment {
         args.push(CString::new("--log-level=lib.eal:6").unwrap());
         args.push(CString::new("--log-level=lib.cryptodev:5").unwrap());
         args.push(CString::new("--log-level=user1:6").unwrap());
-        args.push(CString::new("--match-allocations").unwrap());
+        if !self.simulate {
+            // matching allocations requires hugepages
+            args.push(CString::new("--match-allocations").unwrap());
+        }
 
         // any additional parameters we want to pass down to the eal. These
         // arguments are not checked or validated.
@@ -661,6 +914,9 @@ impl MayastorEnvironment {
     /// Returns NVMF target's IP address.
     pub(crate) fn get_nvmf_tgt_ip() -> Result<String, String> {
         static TGT_IP: OnceCell<String> = OnceCell::new();
+        if let Some(ip) = MIGRATED_TGT_IP.lock().unwrap().clone() {
+            return Ok(ip);
+        }
         TGT_IP
             .get_or_try_init(|| {
                 match Self::global_or_default().nvmf_tgt_interface {
@@ -671,9 +927,17 @@ impl MayastorEnvironment {
             .map(|s| s.clone())
     }
 
+    /// Sets the IP address of the NVMF target, once its listeners have been
+    /// migrated to it.
+    pub(crate) fn set_nvmf_tgt_ip(ip: &str) {
+        *MIGRATED_TGT_IP.lock().unwrap() = Some(ip.to_string());
+    }
+
     /// Detects IP address for NVMF target by the interface specified in CLI
     /// arguments.
-    fn detect_nvmf_tgt_iface_ip(iface: &str) -> Result<String, String> {
+    pub(crate) fn detect_nvmf_tgt_iface_ip(
+        iface: &str,
+    ) -> Result<String, String> {
         info!(
             "Detecting IP address for NVMF target network interface \
                 specified as '{}' ...",
@@ -893,10 +1157,17 @@ impl MayastorEnvironment {
             assert!(receiver.await.unwrap());
         });
 
-        // load any pools that need to be created
+        // load any pools that need to be created, unless they are to be
+        // handed over by another io-engine process
         if let Some(config) = pool_config {
-            config.import_pools();
+            if self.take_over.is_none() {
+                config.import_pools();
+            }
         }
+        start_usage_monitor();
+        start_snapshot_scheduler();
+        start_snapshot_export_monitor();
+        start_state_publisher();
 
         self
     }
@@ -921,16 +1192,25 @@ impl MayastorEnvironment {
         let node_nqn = self.node_nqn.clone();
 
         let grpc_endpoint = self.grpc_endpoint;
+        let metrics_endpoint = self.metrics_endpoint;
         let rpc_addr = self.rpc_addr.clone();
         let api_versions = self.api_versions.clone();
         let persistent_store_endpoint = self.persistent_store_endpoint.clone();
+        let persistent_store_lease_ttl =
+            Duration::from_secs(self.persistent_store_lease_ttl);
+        let reconcile_policy = self.reconcile_policy;
         let ms = self.init();
 
         let rt = Builder::new_current_thread().enable_all().build().unwrap();
 
         rt.block_on(async {
-            PersistentStore::init(persistent_store_endpoint).await;
+            PersistentStore::init(
+                persistent_store_endpoint,
+                persistent_store_lease_ttl,
+            )
+            .await;
             let master = Reactors::current();
+            master.send_future(reconcile(reconcile_policy));
             master.send_future(async { f() });
             let mut futures: Vec<
                 Pin<Box<dyn future::Future<Output = FutureResult>>>,
@@ -944,6 +1224,9 @@ impl MayastorEnvironment {
                     api_versions,
                 )));
             }
+            if let Some(metrics_endpoint) = metrics_endpoint {
+                futures.push(Box::pin(MetricsServer::run(metrics_endpoint)));
+            }
             futures.push(Box::pin(subsys::Registration::run()));
             futures.push(Box::pin(master));
             let _out = future::try_join_all(futures).await;
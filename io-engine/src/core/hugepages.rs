@@ -0,0 +1,308 @@
+//! Hugepage requirements of the io-engine, checked at startup.
+//!
+//! All the I/O memory of the io-engine is allocated from hugepages, and
+//! DPDK allocates them from the NUMA nodes of the cores it runs on. A node
+//! short of hugepages only shows once the memory of a pool or a nexus fails
+//! to be allocated, so the hugepages are checked before DPDK is initialised:
+//! the memory required, the memory size given to the io-engine or 2GiB by
+//! default, is spread evenly over the NUMA nodes of the reactor cores, and
+//! every node must have that much memory in free hugepages, of any size.
+//!
+//! When allowed to, the io-engine reserves the missing hugepages of the
+//! default size itself, which requires write access to sysfs, that is a
+//! privileged container. The memory of the nodes can also be pre-faulted:
+//! DPDK is then given the memory required per node, which it allocates and
+//! pins upfront rather than on demand, so that the I/O path never waits for
+//! hugepages to be faulted in. The outcome of the check lists the deficit of
+//! every node short of hugepages, along with how to reserve them.
+
+use std::{
+    collections::BTreeSet,
+    fmt::{Display, Formatter},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use once_cell::sync::OnceCell;
+use snafu::Snafu;
+
+use super::MayastorCliArgs;
+
+/// Memory required when no memory size is given, in MiB.
+const DEFAULT_REQUIRED_MB: u64 = 2048;
+
+/// Sizes of the hugepages accounted for, in KiB.
+const PAGE_SIZES_KB: [u64; 2] = [2048, 1_048_576];
+
+/// Size of the hugepages reserved by the io-engine, in KiB.
+const RESERVED_PAGE_SIZE_KB: u64 = 2048;
+
+const NODE_DIR: &str = "/sys/devices/system/node";
+const CPU_DIR: &str = "/sys/devices/system/cpu";
+const HUGEPAGES_DIR: &str = "/sys/kernel/mm/hugepages";
+
+/// Memory pre-faulted by DPDK on each NUMA node, in MiB, indexed by node.
+static SOCKET_MEM: OnceCell<Vec<u64>> = OnceCell::new();
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)), context(suffix(false)))]
+pub enum HugepageError {
+    #[snafu(display(
+        "Insufficient hugepages: {}. Reserve them with {}, or run the \
+        io-engine privileged with --hugepage-reserve",
+        deficits,
+        remedy
+    ))]
+    Insufficient { deficits: String, remedy: String },
+}
+
+/// Hugepages of a NUMA node.
+#[derive(Debug, Clone)]
+pub struct NodeHugepages {
+    pub node: u32,
+    /// Memory in free hugepages, in MiB.
+    pub free_mb: u64,
+    /// Memory in hugepages, free or not, in MiB.
+    pub total_mb: u64,
+    /// Memory required on the node, in MiB.
+    pub required_mb: u64,
+}
+
+impl NodeHugepages {
+    /// Returns the memory missing on the node, in MiB.
+    pub fn deficit_mb(&self) -> u64 {
+        self.required_mb.saturating_sub(self.free_mb)
+    }
+
+    /// Returns the number of hugepages of the reserved size the node is
+    /// missing.
+    fn deficit_pages(&self) -> u64 {
+        let page_mb = RESERVED_PAGE_SIZE_KB / 1024;
+        (self.deficit_mb() + page_mb - 1) / page_mb
+    }
+}
+
+impl Display for NodeHugepages {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "node {}: {} MiB free of {} MiB required, {} pages of {}kB \
+            missing",
+            self.node,
+            self.free_mb,
+            self.required_mb,
+            self.deficit_pages(),
+            RESERVED_PAGE_SIZE_KB
+        )
+    }
+}
+
+/// Returns the directory of the hugepages of the given size of a node, or
+/// of the system if it has no NUMA nodes.
+fn pages_dir(node: Option<u32>, size_kb: u64) -> PathBuf {
+    match node {
+        Some(node) => Path::new(NODE_DIR)
+            .join(format!("node{}", node))
+            .join("hugepages"),
+        None => PathBuf::from(HUGEPAGES_DIR),
+    }
+    .join(format!("hugepages-{}kB", size_kb))
+}
+
+/// Returns the hugepages of a node, the total and the free ones, in MiB.
+/// A size of hugepages which is not enabled has no pages.
+fn node_pages(node: Option<u32>) -> (u64, u64) {
+    PAGE_SIZES_KB
+        .iter()
+        .fold((0, 0), |(total, free), &size_kb| {
+            let dir = pages_dir(node, size_kb);
+            let nr =
+                sysfs::parse_value::<u64>(&dir, "nr_hugepages").unwrap_or(0);
+            let nr_free =
+                sysfs::parse_value::<u64>(&dir, "free_hugepages").unwrap_or(0);
+            (total + nr * size_kb / 1024, free + nr_free * size_kb / 1024)
+        })
+}
+
+/// Returns the cores of the given list, e.g. "0-3,6".
+fn parse_core_list(list: &str) -> Option<BTreeSet<u32>> {
+    let mut cores = BTreeSet::new();
+    for range in list.trim_matches(|c| c == '[' || c == ']').split(',') {
+        match range.split_once('-') {
+            Some((first, last)) => {
+                let first = first.trim().parse::<u32>().ok()?;
+                let last = last.trim().parse::<u32>().ok()?;
+                cores.extend(first ..= last);
+            }
+            None => {
+                cores.insert(range.trim().parse().ok()?);
+            }
+        }
+    }
+    Some(cores)
+}
+
+/// Returns the cores of the given mask, e.g. "0x3".
+fn parse_core_mask(mask: &str) -> Option<BTreeSet<u32>> {
+    let mask = u128::from_str_radix(mask.trim_start_matches("0x"), 16).ok()?;
+    Some((0 .. 128).filter(|c| mask & (1 << c) != 0).collect())
+}
+
+/// Returns the NUMA node of the given core, if the system has NUMA nodes.
+fn core_node(core: u32) -> Option<u32> {
+    fs::read_dir(Path::new(CPU_DIR).join(format!("cpu{}", core)))
+        .ok()?
+        .filter_map(|e| e.ok())
+        .find_map(|e| {
+            e.file_name()
+                .to_str()
+                .and_then(|n| n.strip_prefix("node"))
+                .and_then(|n| n.parse().ok())
+        })
+}
+
+/// Returns the hugepages of the NUMA nodes of the reactor cores, with the
+/// memory required on each of them.
+pub fn node_hugepages(args: &MayastorCliArgs) -> Vec<NodeHugepages> {
+    let cores = match &args.core_list {
+        Some(list) => parse_core_list(list),
+        None => parse_core_mask(&args.reactor_mask),
+    }
+    .unwrap_or_default();
+
+    let nodes = cores
+        .iter()
+        .filter_map(|&c| core_node(c))
+        .collect::<Vec<_>>();
+    let nodes = if nodes.is_empty() || !Path::new(NODE_DIR).exists() {
+        vec![None]
+    } else {
+        nodes
+            .into_iter()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(Some)
+            .collect()
+    };
+
+    let required_mb = if args.mem_size > 0 {
+        args.mem_size as u64
+    } else {
+        DEFAULT_REQUIRED_MB
+    };
+    let per_node_mb =
+        (required_mb + nodes.len() as u64 - 1) / nodes.len() as u64;
+
+    nodes
+        .into_iter()
+        .map(|node| {
+            let (total_mb, free_mb) = node_pages(node);
+            NodeHugepages {
+                node: node.unwrap_or_default(),
+                free_mb,
+                total_mb,
+                required_mb: per_node_mb,
+            }
+        })
+        .collect()
+}
+
+/// Reserve the hugepages the given node is missing.
+fn reserve(node: &NodeHugepages, numa: bool) {
+    let dir = pages_dir(numa.then(|| node.node), RESERVED_PAGE_SIZE_KB);
+    let nr = sysfs::parse_value::<u64>(&dir, "nr_hugepages").unwrap_or(0);
+    let wanted = nr + node.deficit_pages();
+    info!(
+        "Reserving {} hugepages of {}kB on NUMA node {}",
+        node.deficit_pages(),
+        RESERVED_PAGE_SIZE_KB,
+        node.node
+    );
+    if let Err(error) = sysfs::write_value(&dir, "nr_hugepages", wanted) {
+        warn!(
+            %error,
+            "Failed to reserve hugepages at {}",
+            dir.display()
+        );
+        return;
+    }
+    // the kernel reserves fewer pages if its memory is too fragmented
+    let reserved = sysfs::parse_value::<u64>(&dir, "nr_hugepages").unwrap_or(0);
+    if reserved < wanted {
+        warn!(
+            "Only {} of the {} hugepages of {}kB wanted were reserved on \
+            NUMA node {}",
+            reserved, wanted, RESERVED_PAGE_SIZE_KB, node.node
+        );
+    }
+}
+
+/// Check the hugepages of the NUMA nodes of the reactor cores, reserving
+/// the missing ones if allowed to, and set up their pre-faulting if
+/// required.
+pub fn check(
+    args: &MayastorCliArgs,
+) -> Result<Vec<NodeHugepages>, HugepageError> {
+    let numa = Path::new(NODE_DIR).exists();
+    let mut nodes = node_hugepages(args);
+
+    if args.hugepage_reserve && nodes.iter().any(|n| n.deficit_mb() > 0) {
+        nodes
+            .iter()
+            .filter(|n| n.deficit_mb() > 0)
+            .for_each(|n| reserve(n, numa));
+        nodes = node_hugepages(args);
+    }
+
+    for node in &nodes {
+        info!(
+            "NUMA node {}: {} MiB of hugepages, {} MiB free, {} MiB required",
+            node.node, node.total_mb, node.free_mb, node.required_mb
+        );
+    }
+
+    let short = nodes
+        .iter()
+        .filter(|n| n.deficit_mb() > 0)
+        .collect::<Vec<_>>();
+    if !short.is_empty() {
+        let remedy = short
+            .iter()
+            .map(|n| {
+                let dir =
+                    pages_dir(numa.then(|| n.node), RESERVED_PAGE_SIZE_KB);
+                let nr = sysfs::parse_value::<u64>(&dir, "nr_hugepages")
+                    .unwrap_or(0);
+                format!(
+                    "`echo {} > {}`",
+                    nr + n.deficit_pages(),
+                    dir.join("nr_hugepages").display()
+                )
+            })
+            .collect::<Vec<_>>();
+        return Err(HugepageError::Insufficient {
+            deficits: short
+                .iter()
+                .map(|n| n.to_string())
+                .collect::<Vec<_>>()
+                .join("; "),
+            remedy: remedy.join(" and "),
+        });
+    }
+
+    if args.hugepage_prefault {
+        let max = nodes.iter().map(|n| n.node).max().unwrap_or_default();
+        let mut socket_mem = vec![0; max as usize + 1];
+        nodes
+            .iter()
+            .for_each(|n| socket_mem[n.node as usize] = n.required_mb);
+        SOCKET_MEM.set(socket_mem).ok();
+    }
+    Ok(nodes)
+}
+
+/// Returns the memory DPDK pre-faults on each NUMA node, in MiB, indexed by
+/// node, if the memory is pre-faulted.
+pub(crate) fn socket_mem() -> Option<&'static Vec<u64>> {
+    SOCKET_MEM.get()
+}
@@ -0,0 +1,289 @@
+//! Hugepage memory usage accounting.
+//!
+//! All the I/O memory of the io-engine is allocated from the DPDK hugepage
+//! heap, either as plain DMA buffers or as memory pools of fixed size
+//! elements, which DPDK backs with memzones named after the pool. The usage
+//! is reported per NUMA socket for the heap, and per subsystem for the
+//! memzones, the subsystem being derived from the name of the pool or of the
+//! memzone. The fill levels of the memory pools are reported as well, along
+//! with the number of failed allocations from the pools of the io-engine, as
+//! an exhausted pool is what eventually throttles the I/O.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    os::raw::{c_char, c_int, c_uint, c_void},
+};
+
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::jsonrpc::{jsonrpc_register, JsonRpcError};
+
+/// Maximum length of DPDK memzone and memory pool names
+/// (RTE_MEMZONE_NAMESIZE).
+const NAME_SIZE: usize = 32;
+
+/// Prefix of the memzones which back a memory pool (RTE_MEMPOOL_MZ_PREFIX).
+const MEMPOOL_MZ_PREFIX: &str = "MP_";
+
+/// Prefix of the memzones which back a ring (RTE_RING_MZ_PREFIX).
+const RING_MZ_PREFIX: &str = "RG_";
+
+/// Heap statistics of a socket (struct rte_malloc_socket_stats).
+#[repr(C)]
+#[derive(Default)]
+#[allow(dead_code)]
+struct RteMallocSocketStats {
+    heap_totalsz_bytes: usize,
+    heap_freesz_bytes: usize,
+    greatest_free_size: usize,
+    free_count: c_uint,
+    alloc_count: c_uint,
+    heap_allocsz_bytes: usize,
+}
+
+/// Leading fields of struct rte_memzone.
+#[repr(C)]
+#[allow(dead_code)]
+struct RteMemzone {
+    name: [c_char; NAME_SIZE],
+    iova: u64,
+    addr: *mut c_void,
+    len: usize,
+    hugepage_sz: u64,
+    socket_id: i32,
+    flags: u32,
+}
+
+/// Leading field of struct rte_mempool, which is otherwise opaque.
+#[repr(C)]
+struct RteMempool {
+    name: [c_char; NAME_SIZE],
+}
+
+extern "C" {
+    fn rte_socket_count() -> c_uint;
+    fn rte_socket_id_by_idx(idx: c_uint) -> c_int;
+    fn rte_malloc_get_socket_stats(
+        socket: c_int,
+        stats: *mut RteMallocSocketStats,
+    ) -> c_int;
+    fn rte_memzone_walk(
+        func: Option<unsafe extern "C" fn(*const RteMemzone, *mut c_void)>,
+        arg: *mut c_void,
+    );
+    fn rte_mempool_walk(
+        func: Option<unsafe extern "C" fn(*mut RteMempool, *mut c_void)>,
+        arg: *mut c_void,
+    );
+    fn rte_mempool_avail_count(mp: *const RteMempool) -> c_uint;
+    fn rte_mempool_in_use_count(mp: *const RteMempool) -> c_uint;
+}
+
+/// Names of the memory pools created by the io-engine itself.
+static ENGINE_POOLS: Lazy<Mutex<HashSet<String>>> =
+    Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Number of failed allocations from the memory pools of the io-engine.
+static ALLOC_FAILURES: Lazy<Mutex<HashMap<String, u64>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Record a memory pool created by the io-engine.
+pub(crate) fn register_pool(name: &str) {
+    ENGINE_POOLS.lock().insert(name.to_string());
+}
+
+/// Account a failed allocation from a memory pool of the io-engine.
+pub(crate) fn account_alloc_failure(name: &str) {
+    *ALLOC_FAILURES.lock().entry(name.to_string()).or_default() += 1;
+}
+
+/// Get the subsystem which owns the memory pool or memzone with the given
+/// name.
+fn subsystem(name: &str) -> &'static str {
+    let name = name.trim_start_matches(RING_MZ_PREFIX);
+    let name = name.trim_start_matches(MEMPOOL_MZ_PREFIX);
+
+    if ENGINE_POOLS
+        .lock()
+        .iter()
+        .any(|pool| name.starts_with(pool.as_str()))
+    {
+        "io-engine"
+    } else if name.contains("nvmf") {
+        "nvmf"
+    } else if name.starts_with("iobuf") || name.starts_with("buf_") {
+        "buffers"
+    } else if name.starts_with("bdev") {
+        "bdev"
+    } else if name.starts_with("blob") || name.starts_with("lvol") {
+        "lvs"
+    } else if name.starts_with("nvme") {
+        "nvme"
+    } else if name.starts_with("msgpool") || name.starts_with("evtpool") {
+        "events"
+    } else {
+        "other"
+    }
+}
+
+/// Convert a DPDK name into a string.
+fn name_to_string(name: &[c_char; NAME_SIZE]) -> String {
+    let bytes = unsafe {
+        std::slice::from_raw_parts(name.as_ptr() as *const u8, NAME_SIZE)
+    };
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(NAME_SIZE);
+    String::from_utf8_lossy(&bytes[.. len]).into_owned()
+}
+
+/// Hugepage heap usage of a NUMA socket.
+#[derive(Serialize, Debug)]
+pub struct SocketMemoryUsage {
+    pub socket_id: i32,
+    /// Size of the heap in bytes.
+    pub heap_size: u64,
+    /// Number of free bytes of the heap.
+    pub free_size: u64,
+    /// Number of allocated bytes of the heap.
+    pub allocated_size: u64,
+    /// Size of the largest free block in bytes.
+    pub largest_free_block: u64,
+    /// Number of allocated blocks.
+    pub alloc_count: u32,
+}
+
+/// Hugepage memory reserved by a subsystem in memzones.
+#[derive(Serialize, Debug)]
+pub struct SubsystemMemoryUsage {
+    pub subsystem: String,
+    /// Total size of the memzones of the subsystem in bytes.
+    pub size: u64,
+    /// Number of memzones of the subsystem.
+    pub memzones: u64,
+}
+
+/// Fill level of a DPDK memory pool.
+#[derive(Serialize, Debug)]
+pub struct MemoryPoolUsage {
+    pub name: String,
+    pub subsystem: String,
+    /// Number of elements of the pool.
+    pub capacity: u64,
+    /// Number of elements currently allocated from the pool.
+    pub in_use: u64,
+    /// Number of failed allocations from the pool, for the pools of the
+    /// io-engine only.
+    pub alloc_failures: Option<u64>,
+}
+
+/// Memory usage of the io-engine.
+#[derive(Serialize, Debug)]
+pub struct MemoryUsage {
+    pub sockets: Vec<SocketMemoryUsage>,
+    pub subsystems: Vec<SubsystemMemoryUsage>,
+    pub pools: Vec<MemoryPoolUsage>,
+}
+
+/// Get the hugepage heap usage of every NUMA socket.
+fn sockets_usage() -> Vec<SocketMemoryUsage> {
+    (0 .. unsafe { rte_socket_count() })
+        .filter_map(|idx| {
+            let socket_id = unsafe { rte_socket_id_by_idx(idx) };
+            let mut stats = RteMallocSocketStats::default();
+            if socket_id < 0
+                || unsafe { rte_malloc_get_socket_stats(socket_id, &mut stats) }
+                    < 0
+            {
+                return None;
+            }
+            Some(SocketMemoryUsage {
+                socket_id,
+                heap_size: stats.heap_totalsz_bytes as u64,
+                free_size: stats.heap_freesz_bytes as u64,
+                allocated_size: stats.heap_allocsz_bytes as u64,
+                largest_free_block: stats.greatest_free_size as u64,
+                alloc_count: stats.alloc_count,
+            })
+        })
+        .collect()
+}
+
+/// Get the size of the memzones reserved by every subsystem.
+fn subsystems_usage() -> Vec<SubsystemMemoryUsage> {
+    unsafe extern "C" fn memzone_cb(mz: *const RteMemzone, arg: *mut c_void) {
+        let memzones = &mut *(arg as *mut Vec<(String, u64)>);
+        memzones.push((name_to_string(&(*mz).name), (*mz).len as u64));
+    }
+
+    let mut memzones: Vec<(String, u64)> = Vec::new();
+    unsafe {
+        rte_memzone_walk(
+            Some(memzone_cb),
+            &mut memzones as *mut _ as *mut c_void,
+        );
+    }
+
+    let mut subsystems = BTreeMap::<&str, SubsystemMemoryUsage>::new();
+    for (name, len) in memzones {
+        let subsystem = subsystem(&name);
+        let usage = subsystems.entry(subsystem).or_insert_with(|| {
+            SubsystemMemoryUsage {
+                subsystem: subsystem.to_string(),
+                size: 0,
+                memzones: 0,
+            }
+        });
+        usage.size += len;
+        usage.memzones += 1;
+    }
+    subsystems.into_values().collect()
+}
+
+/// Get the fill level of every memory pool.
+fn pools_usage() -> Vec<MemoryPoolUsage> {
+    unsafe extern "C" fn mempool_cb(mp: *mut RteMempool, arg: *mut c_void) {
+        let pools = &mut *(arg as *mut Vec<(String, u64, u64)>);
+        let available = rte_mempool_avail_count(mp) as u64;
+        let in_use = rte_mempool_in_use_count(mp) as u64;
+        pools.push((name_to_string(&(*mp).name), available + in_use, in_use));
+    }
+
+    let mut pools: Vec<(String, u64, u64)> = Vec::new();
+    unsafe {
+        rte_mempool_walk(Some(mempool_cb), &mut pools as *mut _ as *mut c_void);
+    }
+
+    let failures = ALLOC_FAILURES.lock().clone();
+    let engine_pools = ENGINE_POOLS.lock().clone();
+    let mut pools = pools
+        .into_iter()
+        .map(|(name, capacity, in_use)| MemoryPoolUsage {
+            subsystem: subsystem(&name).to_string(),
+            capacity,
+            in_use,
+            alloc_failures: engine_pools
+                .contains(&name)
+                .then(|| failures.get(&name).copied().unwrap_or_default()),
+            name,
+        })
+        .collect::<Vec<_>>();
+    pools.sort_by(|a, b| a.name.cmp(&b.name));
+    pools
+}
+
+/// Get the memory usage of the io-engine.
+pub fn memory_usage() -> MemoryUsage {
+    MemoryUsage {
+        sockets: sockets_usage(),
+        subsystems: subsystems_usage(),
+        pools: pools_usage(),
+    }
+}
+
+pub(crate) fn register_rpc_methods() {
+    jsonrpc_register::<(), _, _, JsonRpcError>("get_memory_usage", |_| {
+        async move { Ok(memory_usage()) }.boxed_local()
+    });
+}
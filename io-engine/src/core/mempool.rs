@@ -15,7 +15,7 @@ use spdk_rs::libspdk::{
     spdk_mempool_put,
 };
 
-use crate::ffihelper::IntoCString;
+use crate::{core::memory_usage, ffihelper::IntoCString};
 
 pub struct MemoryPool<T: Sized> {
     pool: NonNull<spdk_mempool>,
@@ -51,6 +51,7 @@ impl<T: Sized> MemoryPool<T> {
             "Memory pool '{}' with {} elements ({} bytes size) successfully created",
             name, size, size_of::<T>()
         );
+        memory_usage::register_pool(name);
         Some(Self {
             pool: NonNull::new(pool).unwrap(),
             name: String::from(name),
@@ -66,6 +67,7 @@ impl<T: Sized> MemoryPool<T> {
             unsafe { spdk_mempool_get(self.pool.as_ptr()) } as *mut T;
 
         if ptr.is_null() {
+            memory_usage::account_alloc_failure(&self.name);
             return None;
         }
 
@@ -11,6 +11,7 @@ pub use block_device::{
     BlockDeviceDescriptor,
     BlockDeviceHandle,
     BlockDeviceIoStats,
+    BlockDeviceQpairStats,
     DeviceIoController,
     DeviceTimeoutAction,
     IoCompletionCallback,
@@ -21,7 +22,12 @@ pub use block_device::{
     ReadMode,
 };
 pub use cpu_cores::{Core, Cores};
-pub use descriptor::{DescriptorGuard, UntypedDescriptorGuard};
+pub use descriptor::{
+    open_descriptors,
+    DescriptorGuard,
+    DescriptorInfo,
+    UntypedDescriptorGuard,
+};
 pub use device_events::{
     DeviceEventDispatcher,
     DeviceEventListener,
@@ -43,10 +49,12 @@ pub use env::{
 pub use handle::{BdevHandle, UntypedBdevHandle};
 pub use io_device::IoDevice;
 pub use reactor::{
+    reactor_is_frozen,
     reactor_monitor_loop,
     Reactor,
     ReactorState,
     Reactors,
+    ThreadLoad,
     REACTOR_LIST,
 };
 
@@ -62,24 +70,30 @@ pub use share::{Protocol, PtplProps, Share, ShareProps, UpdateProps};
 pub use spdk_rs::{cpu_cores, GenericStatusCode, IoStatus, IoType, NvmeStatus};
 pub use thread::Mthread;
 
-use crate::subsys::NvmfError;
+use crate::{subsys::NvmfError, target::iscsi::Error as IscsiError};
 
 mod bdev;
+pub mod bdev_histogram;
 mod block_device;
-mod descriptor;
+pub mod crash_report;
+pub(crate) mod descriptor;
 mod device_events;
 mod device_monitor;
 pub mod diagnostics;
 mod env;
 mod handle;
+pub mod hugepages;
 mod io_device;
 pub mod io_driver;
 pub mod lock;
+pub mod memory_usage;
 pub mod mempool;
 mod nic;
 pub mod partition;
 mod reactor;
+pub mod reactor_profile;
 pub mod runtime;
+pub mod secret;
 mod share;
 pub(crate) mod thread;
 mod work_queue;
@@ -220,9 +234,15 @@ pub enum CoreError {
         offset: u64,
         len: u64,
     },
-    #[snafu(display("NVMe Admin command {:x}h failed", opcode))]
+    #[snafu(display("Unmap failed at offset {} length {}", offset, len))]
+    UnmapFailed {
+        offset: u64,
+        len: u64,
+    },
+    #[snafu(display("NVMe Admin command {:x}h failed: {:?}", opcode, status))]
     NvmeAdminFailed {
         opcode: u16,
+        status: NvmeStatus,
     },
     #[snafu(display("NVMe IO Passthru command {:x}h failed", opcode))]
     NvmeIoPassthruFailed {
@@ -236,6 +256,14 @@ pub enum CoreError {
     UnshareNvmf {
         source: NvmfError,
     },
+    #[snafu(display("failed to share over iSCSI: {}", source))]
+    ShareIscsi {
+        source: IscsiError,
+    },
+    #[snafu(display("failed to unshare from iSCSI: {}", source))]
+    UnshareIscsi {
+        source: IscsiError,
+    },
     #[snafu(display("the operation is invalid for this bdev: {}", source))]
     NotSupported {
         source: Errno,
@@ -254,6 +282,20 @@ pub enum CoreError {
     },
     #[snafu(display("No devices available for I/O"))]
     NoDevicesAvailable {},
+    #[snafu(display(
+        "Failed to enable histogram of bdev {}: {}",
+        name,
+        source
+    ))]
+    HistogramEnable {
+        name: String,
+        source: Errno,
+    },
+    #[snafu(display("Failed to get histogram of bdev {}: {}", name, source))]
+    HistogramGet {
+        name: String,
+        source: Errno,
+    },
     #[snafu(display(
         "NVMe persistence through power-loss failure: {}",
         reason
@@ -261,6 +303,20 @@ pub enum CoreError {
     Ptpl {
         reason: String,
     },
+    #[snafu(display("Failed to profile reactor {}: {}", core, reason))]
+    ReactorProfile {
+        core: u32,
+        reason: String,
+    },
+    #[snafu(display(
+        "Failed to close descriptor of bdev {}: {}",
+        name,
+        reason
+    ))]
+    DescriptorClose {
+        name: String,
+        reason: String,
+    },
 }
 
 /// Logical volume layer failure.
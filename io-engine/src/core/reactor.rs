@@ -31,7 +31,7 @@
 //! SPDK messages, these futures -- are allocated before they execute.
 use std::{
     cell::{Cell, RefCell},
-    collections::VecDeque,
+    collections::{HashSet, VecDeque},
     fmt::{self, Debug, Display, Formatter},
     future::Future,
     os::raw::c_void,
@@ -40,7 +40,7 @@ use std::{
     time::Duration,
 };
 
-use once_cell::sync::OnceCell;
+use once_cell::sync::{Lazy, OnceCell};
 
 use crossbeam::channel::{unbounded, Receiver, Sender};
 use futures::{
@@ -49,19 +49,26 @@ use futures::{
 };
 
 use spdk_rs::libspdk::{
+    spdk_cpuset,
     spdk_cpuset_get_cpu,
+    spdk_cpuset_set_cpu,
+    spdk_cpuset_zero,
     spdk_env_thread_launch_pinned,
     spdk_env_thread_wait_all,
     spdk_thread,
     spdk_thread_get_cpumask,
+    spdk_thread_get_stats,
     spdk_thread_lib_init_ext,
     spdk_thread_op,
     spdk_thread_send_msg,
+    spdk_thread_set_cpumask,
+    spdk_thread_stats,
     SPDK_DEFAULT_MSG_MEMPOOL_SIZE,
     SPDK_THREAD_OP_NEW,
+    SPDK_THREAD_OP_RESCHED,
 };
 
-use crate::core::{CoreError, Cores};
+use crate::core::{reactor_profile, CoreError, Cores};
 use gettid::gettid;
 use nix::errno::Errno;
 
@@ -88,6 +95,15 @@ impl Display for ReactorState {
 #[derive(Debug)]
 pub struct Reactors(Vec<Reactor>);
 
+/// Busy time of an SPDK thread of a reactor.
+#[derive(Debug, Clone)]
+pub struct ThreadLoad {
+    pub id: u64,
+    pub name: String,
+    /// Ticks the thread spent busy since it was created.
+    pub busy_ticks: u64,
+}
+
 unsafe impl Sync for Reactors {}
 unsafe impl Send for Reactors {}
 
@@ -111,6 +127,9 @@ pub struct Reactor {
     /// incoming threads that have been scheduled to this core but are not
     /// polled yet
     incoming: crossbeam::queue::SegQueue<spdk_rs::Thread>,
+    /// threads of this core which have been rescheduled to another core but
+    /// are not moved yet
+    outgoing: RefCell<Vec<spdk_rs::Thread>>,
     /// the logical core this reactor is created on
     lcore: u32,
     /// represents the state of the reactor
@@ -161,13 +180,14 @@ impl Reactors {
 
     /// advertise what scheduling options we support
     extern "C" fn can_op(op: spdk_thread_op) -> bool {
-        matches!(op, SPDK_THREAD_OP_NEW)
+        matches!(op, SPDK_THREAD_OP_NEW | SPDK_THREAD_OP_RESCHED)
     }
 
     /// do the advertised scheduling option
     extern "C" fn do_op(thread: *mut spdk_thread, op: spdk_thread_op) -> i32 {
         match op {
             SPDK_THREAD_OP_NEW => Self::schedule(thread),
+            SPDK_THREAD_OP_RESCHED => Self::reschedule(thread),
             _ => -1,
         }
     }
@@ -199,6 +219,16 @@ impl Reactors {
         }
     }
 
+    /// reschedule a thread of the current core after its cpumask changed, it
+    /// is moved once the reactor is done polling its threads.
+    fn reschedule(thread: *mut spdk_thread) -> i32 {
+        Reactors::current()
+            .outgoing
+            .borrow_mut()
+            .push(spdk_rs::Thread::from_ptr(thread));
+        0
+    }
+
     /// launch the poll loop on the master core, this is implemented somewhat
     /// different from the remote cores.
     pub fn launch_master() {
@@ -286,6 +316,7 @@ impl Reactor {
         Self {
             threads: RefCell::new(VecDeque::new()),
             incoming: crossbeam::queue::SegQueue::new(),
+            outgoing: RefCell::new(Vec::new()),
             lcore: core,
             flags: Cell::new(ReactorState::Init),
             tid: Cell::new(0),
@@ -325,8 +356,9 @@ impl Reactor {
 
     /// receive futures if any
     fn receive_futures(&self) {
+        // the futures are wrapped for profiling when they are sent
         self.rx.try_iter().for_each(|m| {
-            self.spawn_local(m).detach();
+            Self::spawn(m).detach();
         });
     }
 
@@ -335,12 +367,23 @@ impl Reactor {
     where
         F: Future<Output = ()> + 'static,
     {
-        self.sx.send(Box::pin(future)).unwrap();
+        self.sx
+            .send(Box::pin(reactor_profile::Profiled::new(future)))
+            .unwrap();
     }
 
     /// spawn a future locally on this core; note that you can *not* use the
     /// handle to complete the future with a different runtime.
     pub fn spawn_local<F, R>(&self, future: F) -> async_task::Task<R>
+    where
+        F: Future<Output = R> + 'static,
+        R: 'static,
+    {
+        Self::spawn(reactor_profile::Profiled::new(future))
+    }
+
+    /// spawn a future locally on this core
+    fn spawn<F, R>(future: F) -> async_task::Task<R>
     where
         F: Future<Output = R> + 'static,
         R: 'static,
@@ -477,16 +520,44 @@ impl Reactor {
     /// now
     #[inline]
     pub fn poll_once(&self) {
+        if reactor_profile::is_active() {
+            return self.poll_once_profiled();
+        }
+
+        self.receive_futures();
+        self.run_futures();
+        let threads = self.threads.borrow();
+        threads.iter().for_each(|t| {
+            t.poll();
+        });
+
+        drop(threads);
+
+        self.add_incoming();
+    }
+
+    /// polls the reactor once, accounting the time spent in the futures and
+    /// in each thread to the profile of the reactor
+    fn poll_once_profiled(&self) {
+        let start = reactor_profile::ticks();
         self.receive_futures();
         self.run_futures();
+        reactor_profile::account_futures(self.lcore, start);
+
         let threads = self.threads.borrow();
         threads.iter().for_each(|t| {
+            let start = reactor_profile::ticks();
             t.poll();
+            reactor_profile::account(
+                || format!("reactor_{};thread;{}", self.lcore, t.name()),
+                start,
+            );
         });
 
         drop(threads);
 
         self.add_incoming();
+        reactor_profile::end_iteration(self.lcore);
     }
 
     /// poll the threads n times but only poll the futures queue once and look
@@ -511,11 +582,68 @@ impl Reactor {
     }
 
     fn add_incoming(&self) {
+        self.move_outgoing();
         while let Some(i) = self.incoming.pop() {
             self.threads.borrow_mut().push_back(i);
         }
     }
 
+    /// Hand the rescheduled threads over to the reactors of their new cores.
+    fn move_outgoing(&self) {
+        let outgoing = self.outgoing.take();
+        if outgoing.is_empty() {
+            return;
+        }
+
+        self.threads
+            .borrow_mut()
+            .retain(|t| !outgoing.iter().any(|o| o.as_ptr() == t.as_ptr()));
+        outgoing.into_iter().for_each(|t| {
+            if Reactors::schedule(t.as_ptr()) != 0 {
+                // keep polling the thread here rather than losing it
+                self.threads.borrow_mut().push_back(t);
+            }
+        });
+    }
+
+    /// Move the given thread of the current core to another core. Must be
+    /// called from the core of the thread.
+    pub fn move_thread(
+        thread: &spdk_rs::Thread,
+        core: u32,
+    ) -> Result<(), CoreError> {
+        let rc = thread.with(|| unsafe {
+            let mut mask = spdk_cpuset::default();
+            spdk_cpuset_zero(&mut mask);
+            spdk_cpuset_set_cpu(&mut mask, core, true);
+            spdk_thread_set_cpumask(&mut mask)
+        });
+        if rc != 0 {
+            return Err(CoreError::ReactorConfigureFailed {
+                source: Errno::from_i32(-rc),
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns the time each thread of this reactor spent busy since it was
+    /// created. Must be called from the core of the reactor.
+    pub fn thread_loads(&self) -> Vec<ThreadLoad> {
+        self.threads
+            .borrow()
+            .iter()
+            .map(|t| {
+                let mut stats = spdk_thread_stats::default();
+                t.with(|| unsafe { spdk_thread_get_stats(&mut stats) });
+                ThreadLoad {
+                    id: t.id(),
+                    name: t.name().to_string(),
+                    busy_ticks: stats.busy_tsc,
+                }
+            })
+            .collect()
+    }
+
     /// Removes from the reactor and destroys all existed SPDK threads.
     fn destroy_exited(&self) {
         let mut removed = Vec::new();
@@ -678,6 +806,16 @@ impl Future for &'static Reactor {
 /// Heartbeat timeout (in seconds) to classify a reactor as frozen.
 const REACTOR_HEARTBEAT_TIMEOUT: u64 = 3;
 
+/// Cores whose reactor is currently considered frozen by the reactor monitor.
+static FROZEN_REACTORS: Lazy<parking_lot::Mutex<HashSet<u32>>> =
+    Lazy::new(|| parking_lot::Mutex::new(HashSet::new()));
+
+/// Returns whether the reactor monitor considers the reactor running on the
+/// given core frozen. Always false if the reactor monitor is not running.
+pub fn reactor_is_frozen(core: u32) -> bool {
+    FROZEN_REACTORS.lock().contains(&core)
+}
+
 /// Monitor health for all reactors: all available reactors are constantly
 /// monitored for liveness.
 pub async fn reactor_monitor_loop(freeze_timeout: Option<u64>) {
@@ -752,12 +890,14 @@ pub async fn reactor_monitor_loop(freeze_timeout: Option<u64>) {
                 if tick - r.reactor_tick.load(Ordering::Relaxed) == 0 {
                     info!(core = r.core, "Reactor is healthy again");
                     r.frozen = false;
+                    FROZEN_REACTORS.lock().remove(&r.core);
                 }
             } else {
                 // Reactor didn't respond within allowed number of intervals,
                 // assume it is frozen.
                 if tick - r.reactor_tick.load(Ordering::Relaxed) >= timeout {
                     r.frozen = true;
+                    FROZEN_REACTORS.lock().insert(r.core);
                     crate::core::diagnostics::diagnose_reactor(r.reactor);
                 }
             }
@@ -0,0 +1,292 @@
+//! Runtime CPU profiling of the reactors.
+//!
+//! A reactor spends its cycles polling its SPDK threads, which run the
+//! pollers and messages of the subsystems, and running the futures sent to
+//! it. While a reactor is profiled, the cycles spent on each of these are
+//! accounted, the futures being identified by their type, and once the
+//! profiling period elapses the profile is returned as folded stacks, as
+//! consumed by flamegraph tools, the count of each stack being the number of
+//! microseconds spent in it.
+//!
+//! Profiling is enabled for one reactor at a time and for a given period, so
+//! that a busy core can be looked into without restarting the io-engine with
+//! an external profiler. A reactor which is not profiled only checks a flag
+//! on each iteration of its poll loop.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{channel::oneshot, FutureExt};
+use serde::{Deserialize, Serialize};
+
+use spdk_rs::libspdk::{spdk_get_ticks, spdk_get_ticks_hz};
+
+use crate::{
+    core::{CoreError, Reactors},
+    jsonrpc::{jsonrpc_register, Code, JsonRpcError},
+};
+
+/// Longest profiling period.
+const MAX_DURATION: Duration = Duration::from_secs(300);
+
+/// Profile of a reactor.
+#[derive(Debug, Serialize)]
+pub struct ReactorProfile {
+    pub core: u32,
+    /// Profiling period in microseconds.
+    pub duration_us: u64,
+    /// Time spent in every stack in microseconds, by decreasing time.
+    pub stacks: Vec<(String, u64)>,
+}
+
+impl ReactorProfile {
+    /// Returns the profile as folded stacks, one `stack count` line per
+    /// stack.
+    pub fn folded(&self) -> String {
+        self.stacks
+            .iter()
+            .map(|(stack, us)| format!("{} {}\n", stack, us))
+            .collect()
+    }
+}
+
+/// Profile being collected on the current reactor.
+struct ActiveProfile {
+    /// Ticks at which profiling started.
+    start: u64,
+    /// Ticks at which profiling ends.
+    end: u64,
+    /// Ticks spent in every stack.
+    stacks: HashMap<String, u64>,
+    /// Ticks spent in the futures polled during the current iteration of
+    /// the poll loop.
+    futures: u64,
+    sender: oneshot::Sender<ReactorProfile>,
+}
+
+thread_local! {
+    /// Set while the reactor of the current core is profiled.
+    static ACTIVE: Cell<bool> = Cell::new(false);
+    static PROFILE: RefCell<Option<ActiveProfile>> = RefCell::new(None);
+}
+
+/// Returns true if the reactor of the current core is profiled.
+#[inline(always)]
+pub(crate) fn is_active() -> bool {
+    ACTIVE.with(|a| a.get())
+}
+
+/// Returns the current ticks.
+#[inline(always)]
+pub(crate) fn ticks() -> u64 {
+    unsafe { spdk_get_ticks() }
+}
+
+fn ticks_to_us(ticks: u64) -> u64 {
+    ticks * 1_000_000 / unsafe { spdk_get_ticks_hz() }
+}
+
+/// Account the ticks elapsed since `start` to the given stack of the current
+/// reactor, built lazily as it is only needed while profiling.
+pub(crate) fn account(stack: impl FnOnce() -> String, start: u64) {
+    let elapsed = ticks().saturating_sub(start);
+    PROFILE.with(|p| {
+        if let Some(profile) = p.borrow_mut().as_mut() {
+            *profile.stacks.entry(stack()).or_default() += elapsed;
+        }
+    });
+}
+
+/// Account the ticks elapsed since `start` running the futures of the
+/// current reactor, outside of the futures themselves which are accounted
+/// on their own.
+pub(crate) fn account_futures(core: u32, start: u64) {
+    let elapsed = ticks().saturating_sub(start);
+    PROFILE.with(|p| {
+        if let Some(profile) = p.borrow_mut().as_mut() {
+            let own = elapsed.saturating_sub(profile.futures);
+            *profile
+                .stacks
+                .entry(format!("reactor_{};futures", core))
+                .or_default() += own;
+        }
+    });
+}
+
+/// End an iteration of the poll loop of the current reactor, completing the
+/// profile once the profiling period elapsed.
+pub(crate) fn end_iteration(core: u32) {
+    let now = ticks();
+    PROFILE.with(|p| {
+        let mut p = p.borrow_mut();
+        match p.as_mut() {
+            Some(profile) if now < profile.end => {
+                profile.futures = 0;
+                return;
+            }
+            Some(_) => {}
+            None => return,
+        }
+
+        let profile = p.take().unwrap();
+        ACTIVE.with(|a| a.set(false));
+
+        let mut stacks = profile
+            .stacks
+            .into_iter()
+            .map(|(stack, ticks)| (stack, ticks_to_us(ticks)))
+            .filter(|(_, us)| *us > 0)
+            .collect::<Vec<_>>();
+        stacks.sort_by(|a, b| b.1.cmp(&a.1));
+
+        let _ = profile.sender.send(ReactorProfile {
+            core,
+            duration_us: ticks_to_us(now - profile.start),
+            stacks,
+        });
+    });
+}
+
+/// Wraps the futures run by the reactors, to account the cycles spent
+/// polling them while the reactor is profiled.
+pub(crate) struct Profiled<F> {
+    future: F,
+}
+
+impl<F: Future> Profiled<F> {
+    pub(crate) fn new(future: F) -> Self {
+        Self {
+            future,
+        }
+    }
+}
+
+impl<F: Future> Future for Profiled<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        // the future is never moved out of the wrapper
+        let future = unsafe { self.map_unchecked_mut(|p| &mut p.future) };
+        if !is_active() {
+            return future.poll(cx);
+        }
+
+        let start = ticks();
+        let result = future.poll(cx);
+        let elapsed = ticks().saturating_sub(start);
+        PROFILE.with(|p| {
+            if let Some(profile) = p.borrow_mut().as_mut() {
+                profile.futures += elapsed;
+                *profile
+                    .stacks
+                    .entry(format!(
+                        "reactor_{};futures;{}",
+                        Reactors::current().core(),
+                        std::any::type_name::<F>()
+                    ))
+                    .or_default() += elapsed;
+            }
+        });
+        result
+    }
+}
+
+/// Start profiling the reactor of the current core.
+fn start(duration: Duration) -> Result<oneshot::Receiver<ReactorProfile>, ()> {
+    PROFILE.with(|p| {
+        let mut p = p.borrow_mut();
+        if p.is_some() {
+            return Err(());
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        let start = ticks();
+        let hz = unsafe { spdk_get_ticks_hz() };
+        *p = Some(ActiveProfile {
+            start,
+            end: start + duration.as_micros() as u64 * hz / 1_000_000,
+            stacks: HashMap::new(),
+            futures: 0,
+            sender,
+        });
+        ACTIVE.with(|a| a.set(true));
+        Ok(receiver)
+    })
+}
+
+/// Profile the reactor of the given core for the given period.
+pub async fn profile_reactor(
+    core: u32,
+    duration: Duration,
+) -> Result<ReactorProfile, CoreError> {
+    let reactor_profile_err = |reason: &str| CoreError::ReactorProfile {
+        core,
+        reason: reason.to_string(),
+    };
+
+    if duration.is_zero() || duration > MAX_DURATION {
+        return Err(reactor_profile_err("invalid profiling period"));
+    }
+    let reactor = Reactors::get_by_core(core)
+        .ok_or_else(|| reactor_profile_err("no reactor on this core"))?;
+
+    let (sender, receiver) = oneshot::channel();
+    reactor.send_future(async move {
+        let _ = sender.send(start(duration));
+    });
+
+    let profile = receiver
+        .await
+        .map_err(|_| reactor_profile_err("reactor is not running"))?
+        .map_err(|_| reactor_profile_err("reactor is already profiled"))?;
+
+    info!("Profiling reactor {} for {:?}", core, duration);
+    profile
+        .await
+        .map_err(|_| reactor_profile_err("profiling was interrupted"))
+}
+
+/// Arguments of the `profile_reactor` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct ProfileReactorArgs {
+    core: u32,
+    /// Profiling period in seconds.
+    duration_secs: u64,
+}
+
+/// Reply of the `profile_reactor` json-rpc method.
+#[derive(Debug, Serialize)]
+struct ProfileReactorReply {
+    #[serde(flatten)]
+    profile: ReactorProfile,
+    /// The profile as folded stacks.
+    folded: String,
+}
+
+/// Register the reactor profiling json-rpc methods.
+pub(crate) fn register_rpc_methods() {
+    jsonrpc_register("profile_reactor", |args: ProfileReactorArgs| {
+        async move {
+            let profile = profile_reactor(
+                args.core,
+                Duration::from_secs(args.duration_secs),
+            )
+            .await
+            .map_err(|error| {
+                JsonRpcError::new(Code::InvalidParams, error.to_string())
+            })?;
+
+            Ok::<_, JsonRpcError>(ProfileReactorReply {
+                folded: profile.folded(),
+                profile,
+            })
+        }
+        .boxed_local()
+    });
+}
@@ -0,0 +1,189 @@
+//! Secrets referred to by the bdev URIs.
+//!
+//! A URI is shown in logs and returned by the API as the name or the alias of
+//! its bdev, and as the URI of a nexus child, so it must not hold a credential
+//! itself. It holds a reference to the credential instead, `<provider>:<name>`,
+//! which is resolved by the provider it names when the bdev is created:
+//!
+//! - `env:<variable>` is the value of an environment variable of the io-engine;
+//! - `file:<path>` is the content of a file, such as a secret mounted into the
+//!   container of the io-engine.
+//!
+//! Other providers, such as a secret store, can be registered with
+//! [`register_secret_provider`]. A resolved secret never shows in logs. A URI
+//! holding a credential inline is refused, and the credential is redacted
+//! from the error and from the arguments of the gRPC call recorded in the
+//! logs.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug, Display, Formatter},
+    path::Path,
+    sync::Arc,
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use snafu::Snafu;
+use url::Url;
+
+/// Query parameters of a URI which hold a credential inline.
+const CREDENTIAL_PARAMETERS: &[&str] = &["psk", "key", "password", "dhchap"];
+
+/// Replacement of the credentials redacted from a URI.
+const REDACTED: &str = "redacted";
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)), context(suffix(false)))]
+pub enum SecretError {
+    #[snafu(display("invalid secret reference '{}'", reference))]
+    InvalidReference { reference: String },
+    #[snafu(display("no secret provider '{}'", provider))]
+    UnknownProvider { provider: String },
+    #[snafu(display("secret '{}' not found: {}", reference, reason))]
+    NotFound { reference: String, reason: String },
+}
+
+/// Resolved secret, which is never shown.
+#[derive(Clone, PartialEq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    /// Returns the value of the secret.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret({})", REDACTED)
+    }
+}
+
+impl Display for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", REDACTED)
+    }
+}
+
+/// Provider of the secrets referred to as `<provider>:<name>`.
+pub trait SecretProvider: Send + Sync {
+    /// Resolve the secret of the given name.
+    fn resolve(&self, name: &str) -> Result<Secret, String>;
+}
+
+/// Secrets held by the environment variables of the io-engine.
+struct EnvProvider;
+
+impl SecretProvider for EnvProvider {
+    fn resolve(&self, name: &str) -> Result<Secret, String> {
+        std::env::var(name).map(Secret).map_err(|e| e.to_string())
+    }
+}
+
+/// Secrets held by files, such as mounted secrets.
+struct FileProvider;
+
+impl SecretProvider for FileProvider {
+    fn resolve(&self, name: &str) -> Result<Secret, String> {
+        if !Path::new(name).is_absolute() {
+            return Err("the path of the file must be absolute".to_string());
+        }
+        std::fs::read_to_string(name)
+            .map(|value| Secret(value.trim_end().to_string()))
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Registered secret providers, by name.
+static PROVIDERS: Lazy<RwLock<HashMap<String, Arc<dyn SecretProvider>>>> =
+    Lazy::new(|| {
+        let mut providers: HashMap<String, Arc<dyn SecretProvider>> =
+            HashMap::new();
+        providers.insert("env".to_string(), Arc::new(EnvProvider));
+        providers.insert("file".to_string(), Arc::new(FileProvider));
+        RwLock::new(providers)
+    });
+
+/// Register a secret provider, replacing the provider of the same name.
+pub fn register_secret_provider(name: &str, provider: Arc<dyn SecretProvider>) {
+    PROVIDERS.write().insert(name.to_string(), provider);
+}
+
+/// Resolve the given secret reference, `<provider>:<name>`.
+pub fn resolve_secret(reference: &str) -> Result<Secret, SecretError> {
+    let (provider, name) = reference
+        .split_once(':')
+        .filter(|(p, n)| !p.is_empty() && !n.is_empty())
+        .ok_or_else(|| SecretError::InvalidReference {
+            reference: reference.to_string(),
+        })?;
+    let provider_impl =
+        PROVIDERS.read().get(provider).cloned().ok_or_else(|| {
+            SecretError::UnknownProvider {
+                provider: provider.to_string(),
+            }
+        })?;
+    provider_impl
+        .resolve(name)
+        .map_err(|reason| SecretError::NotFound {
+            reference: reference.to_string(),
+            reason,
+        })
+}
+
+/// Returns true if the given URI holds a credential inline.
+pub fn has_inline_credentials(url: &Url) -> bool {
+    url.password().is_some()
+        || url
+            .query_pairs()
+            .any(|(k, _)| CREDENTIAL_PARAMETERS.contains(&k.as_ref()))
+}
+
+/// Returns the given URI with its inline credentials redacted.
+pub fn redact_uri(uri: &str) -> String {
+    let mut url = match Url::parse(uri) {
+        Ok(url) if has_inline_credentials(&url) => url,
+        _ => return uri.to_string(),
+    };
+    if url.password().is_some() {
+        url.set_password(Some(REDACTED)).ok();
+    }
+    let pairs = url
+        .query_pairs()
+        .map(|(k, v)| {
+            let v = if CREDENTIAL_PARAMETERS.contains(&k.as_ref()) {
+                REDACTED.into()
+            } else {
+                v
+            };
+            (k.into_owned(), v.into_owned())
+        })
+        .collect::<Vec<_>>();
+    url.query_pairs_mut().clear().extend_pairs(pairs);
+    url.to_string()
+}
+
+/// Returns the given text, such as the arguments of an API call, with the
+/// inline credentials of the quoted URIs it holds redacted.
+pub fn redact_quoted_uris(text: &str) -> String {
+    if !text.contains("://") {
+        return text.to_string();
+    }
+    text.split('"')
+        .enumerate()
+        .map(|(i, part)| {
+            if i % 2 == 1 && part.contains("://") {
+                redact_uri(part)
+            } else {
+                part.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\"")
+}
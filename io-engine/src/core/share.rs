@@ -11,6 +11,13 @@ pub enum Protocol {
     Off,
     /// shared as NVMe-oF TCP
     Nvmf,
+    /// shared as iSCSI
+    Iscsi,
+    /// attached as a kernel block device on the local node
+    Local,
+    /// exposed to the guests of the local node as an emulated NVMe
+    /// controller over vfio-user
+    VfioUser,
 }
 
 impl TryFrom<i32> for Protocol {
@@ -20,7 +27,9 @@ impl TryFrom<i32> for Protocol {
         match value {
             0 => Ok(Self::Off),
             1 => Ok(Self::Nvmf),
-            // 2 was for iSCSI
+            2 => Ok(Self::Iscsi),
+            3 => Ok(Self::Local),
+            4 => Ok(Self::VfioUser),
             // the gRPC code does not validate enums so we have
             // to do it here
             _ => Err(LvsError::ReplicaShareProtocol {
@@ -35,6 +44,9 @@ impl Display for Protocol {
         let p = match self {
             Self::Off => "Not shared",
             Self::Nvmf => "NVMe-oF TCP",
+            Self::Iscsi => "iSCSI",
+            Self::Local => "Local block device",
+            Self::VfioUser => "vfio-user NVMe",
         };
         write!(f, "{}", p)
     }
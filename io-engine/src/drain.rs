@@ -0,0 +1,181 @@
+//! Draining of the node.
+//!
+//! Before a node is cordoned and shut down, the control plane drains it:
+//! no new nexus, replica or pool can be created on the node anymore, the
+//! running rebuilds are paused and the nexuses are unshared, which
+//! disconnects their NVMf hosts so that they fail over to the other paths
+//! of their volumes. The definitions of the nexuses are then recorded in the
+//! persistent store, so that they can be restored on another node.
+//!
+//! Rebuilds pause at the end of the segment being copied, and the hosts may
+//! take a moment to disconnect, so the drain completes asynchronously: the
+//! node is ready for shutdown once the drain status reports it as such.
+//!
+//! Undraining the node allows the creation of new resources again and
+//! resumes the rebuilds paused by the drain, the nexuses are shared again by
+//! the control plane.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::{
+    bdev::nexus::{nexus_iter, nexus_lookup_mut, Nexus},
+    core::{Protocol, Share, VerboseError},
+    jsonrpc::{jsonrpc_register, JsonRpcError},
+    rebuild::RebuildState,
+};
+
+/// Set while the node is drained.
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// Rebuild jobs paused by the drain, as (nexus, destination child uri).
+static PAUSED_REBUILDS: Lazy<Mutex<Vec<(String, String)>>> =
+    Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Returns true if the node is drained and new resources must not be
+/// created on it.
+pub fn is_draining() -> bool {
+    DRAINING.load(Ordering::SeqCst)
+}
+
+/// Returns true if the nexus is shared, over NVMf, iSCSI or NBD.
+fn is_shared(nexus: &Nexus) -> bool {
+    nexus.get_share_uri().is_some()
+        || matches!(nexus.shared(), Some(Protocol::Nvmf | Protocol::Iscsi))
+}
+
+/// Drain status of the node.
+#[derive(Serialize, Debug)]
+pub struct DrainStatus {
+    /// The node does not accept the creation of new resources.
+    pub draining: bool,
+    /// Nexuses which are still shared.
+    pub shared_nexuses: Vec<String>,
+    /// Destination children of the rebuilds which are still running.
+    pub running_rebuilds: Vec<String>,
+    /// Errors hit while draining the node.
+    pub errors: Vec<String>,
+    /// The node is drained and can be shut down.
+    pub ready: bool,
+}
+
+/// Get the drain status of the node.
+pub fn drain_status() -> DrainStatus {
+    let mut shared_nexuses = Vec::new();
+    let mut running_rebuilds = Vec::new();
+    for nexus in nexus_iter() {
+        if is_shared(nexus) {
+            shared_nexuses.push(nexus.name.clone());
+        }
+        running_rebuilds.extend(
+            nexus
+                .children_iter()
+                .filter_map(|c| c.rebuild_job())
+                .filter(|j| j.state() == RebuildState::Running)
+                .map(|j| j.dst_uri.clone()),
+        );
+    }
+
+    let draining = is_draining();
+    DrainStatus {
+        draining,
+        ready: draining
+            && shared_nexuses.is_empty()
+            && running_rebuilds.is_empty(),
+        shared_nexuses,
+        running_rebuilds,
+        errors: Vec::new(),
+    }
+}
+
+/// Drain the node: stop accepting new resources, pause the running rebuilds,
+/// unshare the nexuses and record their definitions.
+pub async fn drain_node() -> DrainStatus {
+    if !DRAINING.swap(true, Ordering::SeqCst) {
+        info!("Draining the node...");
+    }
+
+    let mut errors = Vec::new();
+    let names = nexus_iter().map(|n| n.name.clone()).collect::<Vec<_>>();
+    for name in names {
+        let mut nexus = match nexus_lookup_mut(&name) {
+            Some(nexus) => nexus,
+            None => continue,
+        };
+
+        let rebuilds = nexus
+            .children_iter()
+            .filter_map(|c| c.rebuild_job())
+            .filter(|j| j.state() == RebuildState::Running)
+            .map(|j| j.dst_uri.clone())
+            .collect::<Vec<_>>();
+        for dst_uri in rebuilds {
+            match nexus.as_mut().pause_rebuild(&dst_uri).await {
+                Ok(_) => PAUSED_REBUILDS.lock().push((name.clone(), dst_uri)),
+                Err(error) => errors.push(error.verbose()),
+            }
+        }
+
+        if is_shared(&nexus) {
+            info!("{:?}: unsharing to drain the node", nexus);
+            if let Err(error) = nexus.as_mut().unshare_nexus().await {
+                errors.push(error.verbose());
+            }
+        }
+
+        nexus.persist_spec().await;
+    }
+
+    for error in &errors {
+        error!("Failed to drain the node: {}", error);
+    }
+
+    let mut status = drain_status();
+    status.ready &= errors.is_empty();
+    status.errors = errors;
+    status
+}
+
+/// Undrain the node: accept new resources again and resume the rebuilds
+/// paused by the drain.
+pub async fn undrain_node() -> DrainStatus {
+    if DRAINING.swap(false, Ordering::SeqCst) {
+        info!("Undraining the node...");
+    }
+
+    let mut errors = Vec::new();
+    let paused = std::mem::take(&mut *PAUSED_REBUILDS.lock());
+    for (name, dst_uri) in paused {
+        if let Some(nexus) = nexus_lookup_mut(&name) {
+            if let Err(error) = nexus.resume_rebuild(&dst_uri).await {
+                errors.push(error.verbose());
+            }
+        }
+    }
+
+    for error in &errors {
+        error!("Failed to undrain the node: {}", error);
+    }
+
+    let mut status = drain_status();
+    status.errors = errors;
+    status
+}
+
+pub(crate) fn register_rpc_methods() {
+    jsonrpc_register::<(), _, _, JsonRpcError>("drain_node", |_| {
+        async move { Ok(drain_node().await) }.boxed_local()
+    });
+
+    jsonrpc_register::<(), _, _, JsonRpcError>("drain_status", |_| {
+        async move { Ok(drain_status()) }.boxed_local()
+    });
+
+    jsonrpc_register::<(), _, _, JsonRpcError>("undrain_node", |_| {
+        async move { Ok(undrain_node().await) }.boxed_local()
+    });
+}
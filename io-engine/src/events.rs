@@ -0,0 +1,216 @@
+//! Events of the io-engine.
+//!
+//! Operations which the io-engine runs on its own, rather than on request of
+//! the control plane, publish their outcome as events, so that the control
+//! plane learns about them without polling every resource. Events are logged
+//! and kept in a bounded history, numbered in the order they were published,
+//! which is read over json-rpc from a given sequence number, so that a reader
+//! only gets the events it has not seen yet.
+//!
+//! A failing resource may publish the same event over and over, e.g. an
+//! error for every I/O failing on a child, which would flood the history and
+//! the logs, and push the other events out. Identical events published
+//! within a window of `DEDUP_WINDOW` are hence published once, the repeats
+//! being counted rather than published; once the window is over, a summary
+//! of the repeats, the same event with the number of repeats as its count,
+//! is published. An event storm therefore publishes at most two events per
+//! window for each distinct event.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::jsonrpc::{jsonrpc_register, JsonRpcError};
+
+/// Number of events kept in the history.
+const MAX_EVENTS: usize = 1024;
+
+/// Window within which identical events are deduplicated.
+const DEDUP_WINDOW: Duration = Duration::from_secs(1);
+
+static EVENTS: Lazy<Mutex<Events>> =
+    Lazy::new(|| Mutex::new(Events::default()));
+
+/// Identity of an event, identical events having the same key.
+type EventKey = (EventCategory, EventSeverity, String, String, String);
+
+/// Repeats of an event within its deduplication window.
+struct Repeats {
+    /// Start of the window.
+    since: Instant,
+    /// Number of repeats, not published yet.
+    count: u64,
+}
+
+#[derive(Default)]
+struct Events {
+    /// Most recent events, oldest first.
+    history: VecDeque<Event>,
+    /// Sequence number of the next event.
+    next_seq: u64,
+    /// Events published within their deduplication window.
+    repeats: HashMap<EventKey, Repeats>,
+}
+
+impl Events {
+    /// Add an event to the history.
+    fn push(&mut self, key: &EventKey, count: u64) {
+        let (category, severity, kind, source, message) = key.clone();
+        self.history.push_back(Event {
+            seq: self.next_seq,
+            time: chrono::Utc::now().to_rfc3339(),
+            category,
+            severity,
+            kind,
+            source,
+            message,
+            count,
+        });
+        self.next_seq += 1;
+        while self.history.len() > MAX_EVENTS {
+            self.history.pop_front();
+        }
+    }
+
+    /// Publish the summaries of the events whose deduplication window is
+    /// over, and forget about them.
+    fn flush_repeats(&mut self, now: Instant) {
+        let expired = self
+            .repeats
+            .iter()
+            .filter(|(_, r)| now.duration_since(r.since) >= DEDUP_WINDOW)
+            .map(|(k, _)| k.clone())
+            .collect::<Vec<_>>();
+        for key in expired {
+            let repeats = self.repeats.remove(&key).unwrap();
+            if repeats.count > 0 {
+                log(
+                    key.1,
+                    &key.3,
+                    &format!("{} (repeated {} times)", key.4, repeats.count),
+                );
+                self.push(&key, repeats.count);
+            }
+        }
+    }
+}
+
+/// Category of an event.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum EventCategory {
+    Nexus,
+    Pool,
+    Snapshot,
+}
+
+/// Severity of an event.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum EventSeverity {
+    Info,
+    Warning,
+    Error,
+}
+
+/// Event published by the io-engine.
+#[derive(Serialize, Debug, Clone)]
+pub struct Event {
+    /// Sequence number of the event.
+    pub seq: u64,
+    /// Time of the event, in RFC 3339 format.
+    pub time: String,
+    pub category: EventCategory,
+    pub severity: EventSeverity,
+    /// What happened, e.g. "created".
+    pub kind: String,
+    /// Name or uuid of the resource the event is about.
+    pub source: String,
+    pub message: String,
+    /// Number of times the event happened, more than one for the summary of
+    /// the repeats of an event.
+    pub count: u64,
+}
+
+fn log(severity: EventSeverity, source: &str, message: &str) {
+    match severity {
+        EventSeverity::Info => info!("{}: {}", source, message),
+        EventSeverity::Warning => warn!("{}: {}", source, message),
+        EventSeverity::Error => error!("{}: {}", source, message),
+    }
+}
+
+/// Publish an event.
+pub fn publish(
+    category: EventCategory,
+    severity: EventSeverity,
+    kind: &str,
+    source: &str,
+    message: String,
+) {
+    let now = Instant::now();
+    let mut events = EVENTS.lock();
+    events.flush_repeats(now);
+
+    let key = (
+        category,
+        severity,
+        kind.to_string(),
+        source.to_string(),
+        message,
+    );
+    if let Some(repeats) = events.repeats.get_mut(&key) {
+        repeats.count += 1;
+        return;
+    }
+
+    log(severity, source, &key.4);
+    events.push(&key, 1);
+    events.repeats.insert(
+        key,
+        Repeats {
+            since: now,
+            count: 0,
+        },
+    );
+}
+
+/// Arguments of the events_get json-rpc method.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct EventsGetArgs {
+    /// Only return the events from this sequence number.
+    since: Option<u64>,
+    /// Only return the events of this category.
+    category: Option<EventCategory>,
+    /// Return at most this number of events, the oldest ones.
+    limit: Option<usize>,
+}
+
+pub(crate) fn register_rpc_methods() {
+    jsonrpc_register::<_, _, _, JsonRpcError>(
+        "events_get",
+        |args: Option<EventsGetArgs>| {
+            async move {
+                let args = args.unwrap_or_default();
+                let mut events = EVENTS.lock();
+                events.flush_repeats(Instant::now());
+                Ok(events
+                    .history
+                    .iter()
+                    .filter(|e| e.seq >= args.since.unwrap_or_default())
+                    .filter(|e| args.category.map_or(true, |c| c == e.category))
+                    .take(args.limit.unwrap_or(MAX_EVENTS))
+                    .cloned()
+                    .collect::<Vec<_>>())
+            }
+            .boxed_local()
+        },
+    );
+}
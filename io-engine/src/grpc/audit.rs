@@ -0,0 +1,267 @@
+//! Audit log of the gRPC operations.
+//!
+//! Every gRPC operation which changes the state of the node is recorded once
+//! completed, with the caller and its identity, a digest of its arguments, its
+//! result and its duration, so that what the control plane asked the node to do
+//! can be reconstructed after an incident. Only a digest of the arguments is
+//! kept, as they can carry secrets such as encryption keys: it tells apart
+//! calls made with different arguments, and can be matched against the
+//! arguments logged by the caller. The requests rejected by the authorization
+//! policy are recorded as well, whatever the method, see `auth`.
+//!
+//! The most recent records are kept in memory. When a log file is given, the
+//! records are also appended to it, one JSON record per line, and the file is
+//! rotated once it reaches its maximum size, keeping a number of previous
+//! files with a numbered suffix, so that the log survives restarts.
+
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use futures::FutureExt;
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tonic::Status;
+
+use super::GrpcClientContext;
+use crate::jsonrpc::{jsonrpc_register, JsonRpcError};
+
+/// Number of records kept in memory.
+const MAX_RECORDS: usize = 1024;
+
+/// Number of records returned by default.
+const DEFAULT_LIMIT: usize = 100;
+
+/// Most recent records, oldest first.
+static RECORDS: Lazy<Mutex<VecDeque<AuditRecord>>> =
+    Lazy::new(|| Mutex::new(VecDeque::new()));
+
+/// Log file the records are appended to, if any.
+static AUDIT_FILE: OnceCell<Mutex<AuditFile>> = OnceCell::new();
+
+/// Record of a gRPC operation.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditRecord {
+    /// Start of the operation, in RFC 3339 format.
+    pub time: String,
+    /// Name of the gRPC method.
+    pub method: String,
+    /// Address of the caller.
+    pub caller: Option<String>,
+    /// Identity of the caller, when authenticated.
+    #[serde(default)]
+    pub identity: Option<String>,
+    /// User agent of the caller.
+    pub user_agent: Option<String>,
+    /// SHA-256 digest of the arguments, in hex.
+    pub args_digest: String,
+    /// gRPC status code of the result, "Ok" on success.
+    pub result: String,
+    /// Error message, on failure.
+    pub error: Option<String>,
+    /// Duration of the operation, in milliseconds.
+    pub duration_ms: i64,
+}
+
+/// Log file of the records, rotated once it reaches its maximum size.
+struct AuditFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    /// Number of rotated files kept.
+    files: usize,
+}
+
+impl AuditFile {
+    fn open(
+        path: PathBuf,
+        max_size: u64,
+        files: usize,
+    ) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+            files,
+        })
+    }
+
+    /// Returns the path of the rotated file with the given index, the
+    /// current file having index 0.
+    fn rotated(path: &Path, index: usize) -> PathBuf {
+        if index == 0 {
+            path.to_path_buf()
+        } else {
+            PathBuf::from(format!("{}.{}", path.display(), index))
+        }
+    }
+
+    /// Shift the rotated files, dropping the oldest, and start a new file.
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.files == 0 {
+            fs::remove_file(&self.path)?;
+        }
+        for index in (0 .. self.files).rev() {
+            let from = Self::rotated(&self.path, index);
+            if from.exists() {
+                fs::rename(&from, Self::rotated(&self.path, index + 1))?;
+            }
+        }
+        *self = Self::open(self.path.clone(), self.max_size, self.files)?;
+        Ok(())
+    }
+
+    fn append(&mut self, record: &AuditRecord) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(&line)?;
+        self.file.flush()?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Returns the records of the log files, oldest first.
+    fn read(&self) -> Vec<AuditRecord> {
+        (0 ..= self.files)
+            .rev()
+            .map(|index| Self::rotated(&self.path, index))
+            .filter_map(|path| File::open(path).ok())
+            .flat_map(|file| BufReader::new(file).lines())
+            .filter_map(|line| serde_json::from_str(&line.ok()?).ok())
+            .collect()
+    }
+}
+
+/// Append the records to the given log file, rotated once it reaches the
+/// given size in bytes, keeping the given number of previous files.
+pub fn install(path: PathBuf, max_size: u64, files: usize) {
+    match AuditFile::open(path.clone(), max_size, files) {
+        Ok(file) => {
+            info!("Writing the gRPC audit log to {}", path.display());
+            let _ = AUDIT_FILE.set(Mutex::new(file));
+        }
+        Err(error) => {
+            error!(
+                "Failed to open the gRPC audit log {}: {}",
+                path.display(),
+                error
+            );
+        }
+    }
+}
+
+/// Returns true if the gRPC method changes the state of the node.
+pub(crate) fn is_mutating(method: &str) -> bool {
+    !["list_", "get_", "stat_"]
+        .iter()
+        .any(|prefix| method.starts_with(prefix))
+}
+
+/// Record the completion of the gRPC operation, if it changes the state of
+/// the node.
+pub(crate) fn record<T>(ctx: &GrpcClientContext, result: &Result<T, Status>) {
+    if !is_mutating(&ctx.id) {
+        return;
+    }
+    let record = AuditRecord {
+        time: ctx.started.to_rfc3339(),
+        method: ctx.id.clone(),
+        caller: ctx.caller.clone(),
+        identity: ctx.identity.clone(),
+        user_agent: ctx.user_agent.clone(),
+        args_digest: hex::encode(Sha256::digest(ctx.args.as_bytes())),
+        result: match result {
+            Ok(_) => "Ok".to_string(),
+            Err(status) => format!("{:?}", status.code()),
+        },
+        error: result.as_ref().err().map(|s| s.message().to_string()),
+        duration_ms: (chrono::Utc::now() - ctx.started).num_milliseconds(),
+    };
+    append(record);
+}
+
+/// Record a request rejected by the authorization policy, before any of its
+/// arguments have been read.
+pub(crate) fn record_rejected(
+    method: &str,
+    identity: Option<&str>,
+    user_agent: Option<&str>,
+    status: &Status,
+) {
+    append(AuditRecord {
+        time: chrono::Utc::now().to_rfc3339(),
+        method: method.to_string(),
+        caller: None,
+        identity: identity.map(|i| i.to_string()),
+        user_agent: user_agent.map(|u| u.to_string()),
+        args_digest: String::new(),
+        result: format!("{:?}", status.code()),
+        error: Some(status.message().to_string()),
+        duration_ms: 0,
+    });
+}
+
+/// Keep the record, and append it to the log file.
+fn append(record: AuditRecord) {
+    if let Some(file) = AUDIT_FILE.get() {
+        if let Err(error) = file.lock().append(&record) {
+            error!("Failed to write the gRPC audit log: {}", error);
+        }
+    }
+    let mut records = RECORDS.lock();
+    records.push_back(record);
+    while records.len() > MAX_RECORDS {
+        records.pop_front();
+    }
+}
+
+/// Arguments of the audit_log json-rpc method.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct AuditLogArgs {
+    /// Only return the records of this gRPC method.
+    method: Option<String>,
+    /// Maximum number of records returned, the most recent ones.
+    limit: Option<usize>,
+}
+
+/// Register the audit log json-rpc methods.
+pub(crate) fn register_rpc_methods() {
+    jsonrpc_register::<_, _, _, JsonRpcError>(
+        "audit_log",
+        |args: Option<AuditLogArgs>| {
+            async move {
+                let args = args.unwrap_or_default();
+                // the log files outlive the io-engine, the memory does not
+                let records = match AUDIT_FILE.get() {
+                    Some(file) => file.lock().read(),
+                    None => RECORDS.lock().iter().cloned().collect(),
+                };
+                let mut records = records
+                    .into_iter()
+                    .filter(|r| {
+                        args.method.as_ref().map_or(true, |m| &r.method == m)
+                    })
+                    .collect::<Vec<_>>();
+                let limit = args.limit.unwrap_or(DEFAULT_LIMIT);
+                if records.len() > limit {
+                    records.drain(.. records.len() - limit);
+                }
+                Ok(records)
+            }
+            .boxed_local()
+        },
+    );
+}
@@ -0,0 +1,368 @@
+//! Authentication and authorization of the gRPC requests.
+//!
+//! The gRPC server accepts any request unless a policy is installed. Once it
+//! is, every request is given an identity, and is rejected unless the role
+//! of the identity allows the method called:
+//!
+//! - a static token, sent as `authorization: Bearer <token>`, is mapped to the
+//!   identity and role it has been configured with,
+//! - with mTLS, terminated by a proxy in front of the server, the identity of
+//!   the client certificate is taken from the header the proxy forwards it in:
+//!   the URI SAN or the common name of the subject of an Envoy
+//!   `x-forwarded-client-cert` header, or the whole value of a header carrying
+//!   the identity only. The server must then only be reachable through the
+//!   proxy, which must strip the header sent by its clients,
+//! - any other request is anonymous, with the anonymous role, none by default.
+//!
+//! The `read_only` role allows the methods listing or reading the state of
+//! the node, the `admin` role allows all of them. The role required by a
+//! method can be overridden, by its full gRPC path or by the path of its
+//! service.
+//!
+//! The identity is passed on to the services in the `x-io-engine-identity`
+//! header, from which it is recorded in the audit log, as are the rejected
+//! requests.
+//!
+//! A policy is a YAML file:
+//!
+//! ```yaml
+//! tokens:
+//!   - identity: control-plane
+//!     token: "..."
+//!     role: admin
+//! mtls:
+//!   header: x-forwarded-client-cert
+//!   identities:
+//!     spiffe://cluster.local/ns/mayastor/sa/agent-core: admin
+//!     monitoring: read_only
+//! anonymous: none
+//! methods:
+//!   /mayastor.v1.HostRpc/GetMayastorInfo: none
+//! ```
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    task::{Context, Poll},
+};
+
+use futures::future::BoxFuture;
+use http::{header::HeaderValue, Request, Response};
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tonic::{body::BoxBody, transport::Body, Status};
+use tower::{Layer, Service};
+
+use super::audit;
+
+/// Header the identity of the caller is passed on to the services in.
+pub(crate) const IDENTITY_HEADER: &str = "x-io-engine-identity";
+
+/// Installed policy, if any.
+static AUTH_POLICY: OnceCell<AuthPolicy> = OnceCell::new();
+
+/// Role of an identity, each role allowing what the previous ones do.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, PartialOrd)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Nothing is allowed.
+    None,
+    /// Listing and reading the state of the node.
+    ReadOnly,
+    /// Everything.
+    Admin,
+}
+
+impl Default for Role {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+/// Static token.
+#[derive(Deserialize, Debug)]
+struct TokenPolicy {
+    identity: String,
+    token: String,
+    role: Role,
+}
+
+/// Identities of the client certificates.
+#[derive(Deserialize, Debug)]
+struct MtlsPolicy {
+    /// Header the identity is forwarded in by the proxy.
+    header: String,
+    /// Role of each identity.
+    #[serde(default)]
+    identities: HashMap<String, Role>,
+}
+
+/// Authentication and authorization policy of the gRPC server.
+#[derive(Deserialize, Debug, Default)]
+pub struct AuthPolicy {
+    #[serde(default)]
+    tokens: Vec<TokenPolicy>,
+    #[serde(default)]
+    mtls: Option<MtlsPolicy>,
+    /// Role of the requests without any identity.
+    #[serde(default)]
+    anonymous: Role,
+    /// Role required by a method, or by the methods of a service.
+    #[serde(default)]
+    methods: HashMap<String, Role>,
+}
+
+/// Identity of the caller of a gRPC method.
+#[derive(Debug, Clone, PartialEq)]
+struct Identity {
+    name: String,
+    role: Role,
+}
+
+impl AuthPolicy {
+    /// Returns the identity of the caller, or an error if the credentials
+    /// given are not valid.
+    fn identify<B>(&self, req: &Request<B>) -> Result<Identity, Status> {
+        if let Some(value) = req.headers().get(http::header::AUTHORIZATION) {
+            let token = value
+                .to_str()
+                .ok()
+                .and_then(|v| v.strip_prefix("Bearer "))
+                .ok_or_else(|| {
+                    Status::unauthenticated("malformed authorization header")
+                })?;
+            // the digests are compared, so that the time taken does not
+            // tell how much of a token is right
+            let digest = Sha256::digest(token.trim().as_bytes());
+            return self
+                .tokens
+                .iter()
+                .find(|t| Sha256::digest(t.token.as_bytes()) == digest)
+                .map(|t| Identity {
+                    name: t.identity.clone(),
+                    role: t.role,
+                })
+                .ok_or_else(|| Status::unauthenticated("invalid token"));
+        }
+
+        if let Some(mtls) = &self.mtls {
+            let name = req
+                .headers()
+                .get(mtls.header.as_str())
+                .and_then(|v| v.to_str().ok())
+                .and_then(client_cert_identity);
+            if let Some(name) = name {
+                let role = mtls.identities.get(&name).copied();
+                return role
+                    .map(|role| Identity {
+                        name: name.clone(),
+                        role,
+                    })
+                    .ok_or_else(|| {
+                        Status::unauthenticated(format!(
+                            "unknown client certificate identity '{}'",
+                            name
+                        ))
+                    });
+            }
+        }
+
+        Ok(Identity {
+            name: "anonymous".to_string(),
+            role: self.anonymous,
+        })
+    }
+
+    /// Returns the role required by the method of the given gRPC path.
+    fn required_role(&self, path: &str) -> Role {
+        let service = path.rsplit_once('/').map_or(path, |(s, _)| s);
+        self.methods
+            .get(path)
+            .or_else(|| self.methods.get(service))
+            .copied()
+            .unwrap_or_else(|| {
+                if audit::is_mutating(&method_id(path)) {
+                    Role::Admin
+                } else {
+                    Role::ReadOnly
+                }
+            })
+    }
+
+    /// Returns an error unless the identity is allowed to call the method of
+    /// the request.
+    fn authorize<B>(
+        &self,
+        req: &Request<B>,
+        identity: &Identity,
+    ) -> Result<(), Status> {
+        if self.required_role(req.uri().path()) > identity.role {
+            return Err(Status::permission_denied(format!(
+                "'{}' is not allowed to call {}",
+                identity.name,
+                req.uri().path()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Returns the identity of a client certificate from the value of the header
+/// it is forwarded in.
+fn client_cert_identity(value: &str) -> Option<String> {
+    let value = value.trim();
+    if !value.contains('=') {
+        return Some(value.to_string()).filter(|v| !v.is_empty());
+    }
+    // Envoy: By=...;Hash=...;Subject="CN=...,O=...";URI=...
+    let fields = value
+        .split(';')
+        .filter_map(|f| f.split_once('='))
+        .map(|(k, v)| {
+            (k.trim().to_ascii_lowercase(), v.trim().trim_matches('"'))
+        })
+        .collect::<Vec<_>>();
+    let field = |name: &str| {
+        fields
+            .iter()
+            .find(|(k, v)| k == name && !v.is_empty())
+            .map(|(_, v)| v.to_string())
+    };
+    field("uri").or_else(|| {
+        field("subject")?
+            .split(',')
+            .filter_map(|rdn| rdn.trim().strip_prefix("CN="))
+            .map(|cn| cn.to_string())
+            .next()
+    })
+}
+
+/// Returns the method id, as used by the services, of a gRPC path: the name
+/// of the method in snake case.
+fn method_id(path: &str) -> String {
+    let method = path.rsplit('/').next().unwrap_or_default();
+    let mut id = String::with_capacity(method.len() + 4);
+    for (i, c) in method.chars().enumerate() {
+        if c.is_ascii_uppercase() {
+            if i > 0 {
+                id.push('_');
+            }
+            id.push(c.to_ascii_lowercase());
+        } else {
+            id.push(c);
+        }
+    }
+    id
+}
+
+/// Install the authentication and authorization policy of the given file.
+pub fn install(path: &Path) -> Result<(), String> {
+    let policy = std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|s| {
+            serde_yaml::from_str::<AuthPolicy>(&s).map_err(|e| e.to_string())
+        })
+        .map_err(|e| {
+            format!(
+                "Failed to load the gRPC auth policy {}: {}",
+                path.display(),
+                e
+            )
+        })?;
+    info!(
+        "gRPC auth policy {}: {} token(s), mTLS {}, anonymous role {:?}",
+        path.display(),
+        policy.tokens.len(),
+        if policy.mtls.is_some() {
+            "enabled"
+        } else {
+            "disabled"
+        },
+        policy.anonymous
+    );
+    AUTH_POLICY
+        .set(policy)
+        .map_err(|_| "The gRPC auth policy is already installed".to_string())
+}
+
+/// Layer authenticating and authorizing the requests of the gRPC server.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AuthLayer;
+
+impl<S> Layer<S> for AuthLayer {
+    type Service = AuthService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AuthService {
+            inner,
+        }
+    }
+}
+
+/// Service rejecting the requests not allowed by the policy.
+#[derive(Debug, Clone)]
+pub(crate) struct AuthService<S> {
+    inner: S,
+}
+
+impl<S> Service<Request<Body>> for AuthService<S>
+where
+    S: Service<Request<Body>, Response = Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        // the identity is only ever set here
+        req.headers_mut().remove(IDENTITY_HEADER);
+
+        let policy = match AUTH_POLICY.get() {
+            Some(policy) => policy,
+            None => return Box::pin(self.inner.call(req)),
+        };
+        let identity = match policy.identify(&req) {
+            Ok(identity) => identity,
+            Err(status) => return reject(&req, None, status),
+        };
+        if let Err(status) = policy.authorize(&req, &identity) {
+            return reject(&req, Some(&identity.name), status);
+        }
+        if let Ok(value) = HeaderValue::from_str(&identity.name) {
+            req.headers_mut().insert(IDENTITY_HEADER, value);
+        }
+        Box::pin(self.inner.call(req))
+    }
+}
+
+/// Record the rejection of the request, and answer it with the given status.
+fn reject<E: Send + 'static>(
+    req: &Request<Body>,
+    identity: Option<&str>,
+    status: Status,
+) -> BoxFuture<'static, Result<Response<BoxBody>, E>> {
+    warn!(
+        "Rejected gRPC request {}: {}",
+        req.uri().path(),
+        status.message()
+    );
+    audit::record_rejected(
+        &method_id(req.uri().path()),
+        identity,
+        req.headers()
+            .get(http::header::USER_AGENT)
+            .and_then(|v| v.to_str().ok()),
+        &status,
+    );
+    let response = status.to_http();
+    Box::pin(async move { Ok(response) })
+}
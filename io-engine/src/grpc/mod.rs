@@ -6,27 +6,26 @@ use std::{
 };
 
 use futures::channel::oneshot::Receiver;
+use http::HeaderMap;
+use opentelemetry::{global, propagation::Extractor};
 pub use server::MayastorGrpcServer;
 use tonic::{Request, Response, Status};
+use tracing::{Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 
 use crate::{
     bdev_api::BdevError,
-    core::{CoreError, Reactor},
+    core::{secret::redact_quoted_uris, CoreError, Reactor},
+    jsonrpc::{Code, RpcErrorCode},
 };
 
 impl From<BdevError> for tonic::Status {
     fn from(e: BdevError) -> Self {
-        match e {
-            BdevError::UriParseFailed {
-                ..
-            } => Status::invalid_argument(e.to_string()),
-            BdevError::UriSchemeUnsupported {
-                ..
-            } => Status::invalid_argument(e.to_string()),
-            BdevError::InvalidUri {
-                ..
-            } => Status::invalid_argument(e.to_string()),
-            e => Status::internal(e.to_string()),
+        match e.rpc_error_code() {
+            Code::AlreadyExists => Status::already_exists(e.to_string()),
+            Code::NotFound => Status::not_found(e.to_string()),
+            Code::InvalidParams => Status::invalid_argument(e.to_string()),
+            _ => Status::internal(e.to_string()),
         }
     }
 }
@@ -37,6 +36,8 @@ impl From<CoreError> for tonic::Status {
     }
 }
 
+pub mod audit;
+pub mod auth;
 pub mod controller_grpc;
 mod server;
 pub mod v0 {
@@ -52,6 +53,7 @@ pub mod v1 {
     pub mod nexus;
     pub mod pool;
     pub mod replica;
+    pub mod state;
 }
 
 /// Default timeout for gRPC calls, in seconds. Should be enforced in case
@@ -60,7 +62,7 @@ pub const DEFAULT_GRPC_TIMEOUT_SEC: u64 = 15;
 
 /// Structure that holds sensitive information about the current gRPC
 /// method being executed.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct GrpcClientContext {
     /// Method arguments.
     pub args: String,
@@ -68,6 +70,14 @@ pub(crate) struct GrpcClientContext {
     pub id: String,
     /// Method timeout.
     pub timeout: Duration,
+    /// Address of the caller.
+    pub caller: Option<String>,
+    /// Identity of the caller, when authenticated.
+    pub identity: Option<String>,
+    /// User agent of the caller.
+    pub user_agent: Option<String>,
+    /// Start of the method.
+    pub started: chrono::DateTime<chrono::Utc>,
 }
 
 impl GrpcClientContext {
@@ -78,8 +88,20 @@ impl GrpcClientContext {
     {
         Self {
             timeout: get_request_timeout(req),
-            args: format!("{:?}", req.get_ref()),
+            args: redact_quoted_uris(&format!("{:?}", req.get_ref())),
             id: fid.to_string(),
+            caller: req.remote_addr().map(|a| a.to_string()),
+            identity: req
+                .metadata()
+                .get(auth::IDENTITY_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string()),
+            user_agent: req
+                .metadata()
+                .get("user-agent")
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string()),
+            started: chrono::Utc::now(),
         }
     }
 }
@@ -116,10 +138,46 @@ where
     F: Future<Output = Result<R, E>> + 'static,
     R: Send + Debug + 'static,
 {
-    Reactor::spawn_at_primary(future)
+    // the state published for the listing methods may change, both while the
+    // future is executed and once it has completed
+    v1::state::invalidate();
+    let future = async move {
+        let result = future.await;
+        v1::state::invalidate();
+        result
+    };
+
+    // carry the span of the gRPC operation over to the reactor, so that the
+    // spans created while executing the future are attached to it
+    Reactor::spawn_at_primary(future.instrument(Span::current()))
         .map_err(|_| Status::resource_exhausted("ENOMEM"))
 }
 
+/// Extracts the trace context from the metadata of gRPC requests.
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+/// Create the span of a gRPC operation. If the caller (i.e. the control
+/// plane) propagated its trace context in the request metadata, the span is
+/// made a child of the caller's span.
+pub(crate) fn grpc_span(req: &http::Request<()>) -> Span {
+    let span = info_span!("grpc", method = %req.uri().path());
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(req.headers()))
+    });
+    span.set_parent(parent);
+    span
+}
+
 macro_rules! default_ip {
     () => {
         "0.0.0.0"
@@ -73,7 +73,41 @@ impl MayastorGrpcServer {
             "{:?} gRPC server configured at address {}",
             api_versions, endpoint
         );
+        let listener =
+            match crate::handoff::grpc_listener(endpoint).and_then(|l| {
+                l.set_nonblocking(true)?;
+                tokio::net::TcpListener::from_std(l)
+            }) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!("Failed to listen on {}: {}", endpoint, e);
+                    return Err(());
+                }
+            };
+        // accept errors are transient, such as running out of descriptors,
+        // and are not to stop the server
+        let incoming =
+            futures::stream::unfold(listener, |listener| async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, _)) => {
+                            return Some((
+                                Ok::<_, std::io::Error>(stream),
+                                listener,
+                            ))
+                        }
+                        Err(e) => {
+                            warn!("Failed to accept a gRPC connection: {}", e);
+                            tokio::time::sleep(Duration::from_millis(100))
+                                .await;
+                        }
+                    }
+                }
+            });
+
         let svc = Server::builder()
+            .trace_fn(super::grpc_span)
+            .layer(super::auth::AuthLayer)
             .add_optional_service(
                 enable_v1
                     .map(|_| v1::bdev::BdevRpcServer::new(BdevService::new())),
@@ -114,7 +148,7 @@ impl MayastorGrpcServer {
             .add_optional_service(
                 enable_v0.map(|_| BdevRpcServer::new(BdevSvc::new())),
             )
-            .serve(endpoint);
+            .serve_with_incoming(incoming);
 
         select! {
             result = svc.fuse() => {
@@ -22,6 +22,7 @@ use crate::{
         UntypedBdev,
     },
     grpc::{
+        audit,
         controller_grpc::{
             controller_stats,
             list_controllers,
@@ -39,7 +40,7 @@ use crate::{
         Serializer,
     },
     host::{blk_device, resource},
-    lvs::{Error as LvsError, Lvol, LvolSpaceUsage, Lvs},
+    lvs::{in_maintenance, Error as LvsError, Lvol, LvolSpaceUsage, Lvs},
     pool_backend::PoolArgs,
     rebuild::{RebuildState, RebuildStats},
     subsys::PoolConfig,
@@ -101,7 +102,7 @@ where
         // Request completed, remove the marker.
         let ctx = guard.take().expect("gRPC context disappeared");
 
-        match r {
+        let r = match r {
             Ok(r) => r,
             Err(_e) => {
                 warn!("{}: gRPC method panicked, args: {}", ctx.id, ctx.args);
@@ -110,7 +111,9 @@ where
                     ctx.id
                 )))
             }
-        }
+        };
+        audit::record(&ctx, &r);
+        r
     }
 }
 
@@ -243,6 +246,24 @@ impl From<LvsError> for Status {
             LvsError::InvalidBdev {
                 source, ..
             } => source.into(),
+            LvsError::NodeDraining {
+                ..
+            } => Status::unavailable(e.to_string()),
+            LvsError::Overcommit {
+                ..
+            } => Status::resource_exhausted(e.to_string()),
+            LvsError::PoolMaintenance {
+                ..
+            } => Status::failed_precondition(e.to_string()),
+            LvsError::TenantQuota {
+                ..
+            } => Status::resource_exhausted(e.to_string()),
+            LvsError::DiskSignatures {
+                ..
+            } => Status::failed_precondition(e.to_string()),
+            LvsError::DiskLabel {
+                ..
+            } => Status::invalid_argument(e.to_string()),
             _ => Status::internal(e.verbose()),
         }
     }
@@ -253,6 +274,9 @@ impl From<Protocol> for i32 {
         match p {
             Protocol::Off => 0,
             Protocol::Nvmf => 1,
+            Protocol::Iscsi => 2,
+            Protocol::Local => 3,
+            Protocol::VfioUser => 4,
         }
     }
 }
@@ -262,7 +286,12 @@ impl From<Lvs> for Pool {
         Self {
             name: l.name().into(),
             disks: vec![l.base_bdev().bdev_uri().unwrap_or_else(|| "".into())],
-            state: PoolState::PoolOnline.into(),
+            // a pool in maintenance is retired, and takes no new replica
+            state: if in_maintenance(l.name()) {
+                PoolState::PoolDegraded.into()
+            } else {
+                PoolState::PoolOnline.into()
+            },
             capacity: l.capacity(),
             used: l.used(),
         }
@@ -958,6 +987,12 @@ impl mayastor_server::Mayastor for MayastorSvc {
                                 Protocol::Off => {
                                     lvol.as_mut().unshare().await?;
                                 }
+                                // replicas are shared over NVMF only
+                                Protocol::Iscsi | Protocol::Local | Protocol::VfioUser => {
+                                    return Err(LvsError::ReplicaShareProtocol {
+                                        value: args.share,
+                                    });
+                                }
                                 Protocol::Nvmf => {
                                     let props = ShareProps::new()
                                         .with_allowed_hosts(args.allowed_hosts)
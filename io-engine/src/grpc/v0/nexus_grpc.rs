@@ -43,6 +43,7 @@ impl From<ChildState> for rpc::ChildState {
                 Reason::IoError => rpc::ChildState::ChildFaulted,
                 Reason::ByClient => rpc::ChildState::ChildFaulted,
                 Reason::AdminCommandFailed => rpc::ChildState::ChildFaulted,
+                Reason::Offline => rpc::ChildState::ChildDegraded,
             },
         }
     }
@@ -65,6 +66,7 @@ impl From<ChildState> for rpc::ChildStateReason {
                 Reason::IoError => Self::IoFailure,
                 Reason::ByClient => Self::ByClient,
                 Reason::AdminCommandFailed => Self::AdminFailed,
+                Reason::Offline => Self::ByClient,
             },
         }
     }
@@ -153,7 +153,26 @@ impl BdevRpc for BdevService {
                 })
             }
 
-            Err(_) => {
+            Ok(Protocol::VfioUser) => {
+                rpc_submit::<_, Bdev, CoreError>(async move {
+                    let bdev =
+                        core::UntypedBdev::lookup_by_name(&bdev_name).unwrap();
+                    bdev.share_vfio_user().await?;
+                    Ok(bdev.into())
+                })
+            }
+
+            Ok(Protocol::Iscsi) => {
+                rpc_submit::<_, Bdev, CoreError>(async move {
+                    let bdev =
+                        core::UntypedBdev::lookup_by_name(&bdev_name).unwrap();
+                    bdev.share_iscsi(&r.allowed_hosts, None)?;
+                    Ok(bdev.into())
+                })
+            }
+
+            // nexuses only are attached locally
+            Ok(Protocol::Local) | Err(_) => {
                 return Err(Status::invalid_argument(protocol.to_string()))
             }
 
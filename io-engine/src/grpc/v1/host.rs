@@ -2,6 +2,7 @@ use crate::{
     bdev::{nexus, NvmeControllerState},
     core::{BlockDeviceIoStats, CoreError, MayastorFeatures},
     grpc::{
+        audit,
         controller_grpc::{
             controller_stats,
             list_controllers,
@@ -63,7 +64,7 @@ where
         // Request completed, remove the marker.
         let ctx = context_guard.take().expect("gRPC context disappeared");
 
-        match r {
+        let r = match r {
             Ok(r) => r,
             Err(_e) => {
                 warn!("{}: gRPC method panicked, args: {}", ctx.id, ctx.args);
@@ -72,7 +73,9 @@ where
                     ctx.id
                 )))
             }
-        }
+        };
+        audit::record(&ctx, &r);
+        r
     }
 }
 
@@ -8,7 +8,13 @@ use crate::{
         Protocol,
         Share,
     },
-    grpc::{rpc_submit, GrpcClientContext, GrpcResult},
+    grpc::{
+        audit,
+        rpc_submit,
+        v1::state::state_snapshot,
+        GrpcClientContext,
+        GrpcResult,
+    },
     rebuild::{RebuildJob, RebuildState, RebuildStats},
 };
 use futures::FutureExt;
@@ -64,11 +70,12 @@ impl NexusService {
     {
         let lock_manager = ResourceLockManager::get_instance();
         let fut = AssertUnwindSafe(f).catch_unwind();
+        let audit_ctx = ctx.clone();
 
         // Schedule a Tokio task to detach it from the high-level gRPC future
         // and avoid task cancellation when the top-level gRPC future is
         // cancelled.
-        match tokio::spawn(async move {
+        let r = match tokio::spawn(async move {
             // Grab global operation lock, if requested.
             let _global_guard = if global_operation {
                 match lock_manager.lock(Some(ctx.timeout)).await {
@@ -109,7 +116,9 @@ impl NexusService {
         .await {
             Ok(r) => r,
             Err(_) => Err(Status::cancelled("gRPC call cancelled"))
-        }
+        };
+        audit::record(&audit_ctx, &r);
+        r
     }
 }
 
@@ -150,6 +159,7 @@ fn map_child_state(s: nexus::ChildState) -> (ChildState, ChildStateReason) {
             Reason::IoError => (Faulted, IoFailure),
             Reason::ByClient => (Faulted, ByClient),
             Reason::AdminCommandFailed => (Faulted, AdminFailed),
+            Reason::Offline => (Degraded, ByClient),
         },
     }
 }
@@ -441,29 +451,10 @@ impl NexusRpc for NexusService {
         let args = request.into_inner();
         trace!("{:?}", args);
 
-        let rx = rpc_submit::<_, _, nexus::Error>(async move {
-            let mut nexus_list: Vec<Nexus> = Vec::new();
-            if let Some(name) = args.name {
-                if let Some(nexus) = nexus::nexus_lookup(&name) {
-                    nexus_list.push(nexus.into_grpc().await);
-                }
-            } else {
-                for n in nexus::nexus_iter() {
-                    if n.state.lock().deref() != &nexus::NexusState::Init {
-                        nexus_list.push(n.into_grpc().await);
-                    }
-                }
-            }
-
-            Ok(ListNexusResponse {
-                nexus_list,
-            })
-        })?;
-
-        rx.await
-            .map_err(|_| Status::cancelled("cancelled"))?
-            .map_err(Status::from)
-            .map(Response::new)
+        let snapshot = state_snapshot().await?;
+        Ok(Response::new(ListNexusResponse {
+            nexus_list: snapshot.nexuses(args.name),
+        }))
     }
 
     #[named]
@@ -683,8 +674,15 @@ impl NexusRpc for NexusService {
                     }
                 };
 
-                // error out if nbd or iscsi
-                if !matches!(share_protocol, Protocol::Off | Protocol::Nvmf) {
+                // error out if nbd
+                if !matches!(
+                    share_protocol,
+                    Protocol::Off
+                        | Protocol::Nvmf
+                        | Protocol::Iscsi
+                        | Protocol::Local
+                        | Protocol::VfioUser
+                ) {
                     return Err(nexus::Error::InvalidShareProtocol {
                         sp_value: args.share as i32,
                     });
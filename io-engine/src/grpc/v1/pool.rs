@@ -1,7 +1,14 @@
 use crate::{
     core::Share,
-    grpc::{rpc_submit, GrpcClientContext, GrpcResult, Serializer},
-    lvs::{Error as LvsError, Lvs},
+    grpc::{
+        audit,
+        rpc_submit,
+        v1::state::state_snapshot,
+        GrpcClientContext,
+        GrpcResult,
+        Serializer,
+    },
+    lvs::{in_maintenance, Error as LvsError, Lvs},
     pool_backend::{PoolArgs, PoolBackend},
 };
 use futures::FutureExt;
@@ -53,7 +60,7 @@ where
         // Request completed, remove the marker.
         let ctx = context_guard.take().expect("gRPC context disappeared");
 
-        match r {
+        let r = match r {
             Ok(r) => r,
             Err(_e) => {
                 warn!("{}: gRPC method panicked, args: {}", ctx.id, ctx.args);
@@ -62,7 +69,9 @@ where
                     ctx.id
                 )))
             }
-        }
+        };
+        audit::record(&ctx, &r);
+        r
     }
 }
 
@@ -141,7 +150,12 @@ impl From<Lvs> for Pool {
             uuid: l.uuid(),
             name: l.name().into(),
             disks: vec![l.base_bdev().bdev_uri().unwrap_or_else(|| "".into())],
-            state: PoolState::PoolOnline.into(),
+            // a pool in maintenance is retired, and takes no new replica
+            state: if in_maintenance(l.name()) {
+                PoolState::PoolDegraded.into()
+            } else {
+                PoolState::PoolOnline.into()
+            },
             capacity: l.capacity(),
             used: l.used(),
             pooltype: PoolType::Lvs as i32,
@@ -302,25 +316,11 @@ impl PoolRpc for PoolService {
         self.locked(
             GrpcClientContext::new(&request, function_name!()),
             async move {
-                let rx = rpc_submit::<_, _, LvsError>(async move {
-                    let mut pools = Vec::new();
-                    let args = request.into_inner();
-                    if let Some(name) = args.name {
-                        if let Some(l) = Lvs::lookup(&name) {
-                            pools.push(l.into())
-                        };
-                    } else {
-                        Lvs::iter().for_each(|l| pools.push(l.into()));
-                    }
-                    Ok(ListPoolsResponse {
-                        pools,
-                    })
-                })?;
-
-                rx.await
-                    .map_err(|_| Status::cancelled("cancelled"))?
-                    .map_err(Status::from)
-                    .map(Response::new)
+                let args = request.into_inner();
+                let snapshot = state_snapshot().await?;
+                Ok(Response::new(ListPoolsResponse {
+                    pools: snapshot.pools(args.name),
+                }))
             },
         )
         .await
@@ -1,8 +1,15 @@
 use crate::{
     bdev::PtplFileOps,
     bdev_api::BdevError,
-    core::{Bdev, Protocol, Share, ShareProps, UntypedBdev, UpdateProps},
-    grpc::{rpc_submit, GrpcClientContext, GrpcResult, Serializer},
+    core::{Bdev, Protocol, Share, ShareProps, UpdateProps},
+    grpc::{
+        audit,
+        rpc_submit,
+        v1::state::state_snapshot,
+        GrpcClientContext,
+        GrpcResult,
+        Serializer,
+    },
     lvs::{Error as LvsError, Lvol, LvolSpaceUsage, Lvs},
 };
 use ::function_name::named;
@@ -47,7 +54,7 @@ where
         // Request completed, remove the marker.
         let ctx = context_guard.take().expect("gRPC context disappeared");
 
-        match r {
+        let r = match r {
             Ok(r) => r,
             Err(_e) => {
                 warn!("{}: gRPC method panicked, args: {}", ctx.id, ctx.args);
@@ -56,7 +63,9 @@ where
                     ctx.id
                 )))
             }
-        }
+        };
+        audit::record(&ctx, &r);
+        r
     }
 }
 
@@ -229,45 +238,10 @@ impl ReplicaRpc for ReplicaService {
         self.locked(GrpcClientContext::new(&request, function_name!()), async {
             let args = request.into_inner();
             trace!("{:?}", args);
-            let rx = rpc_submit::<_, _, LvsError>(async move {
-                let mut lvols = Vec::new();
-                if let Some(bdev) = UntypedBdev::bdev_first() {
-                    lvols = bdev
-                        .into_iter()
-                        .filter(|b| b.driver() == "lvol")
-                        .map(|b| Lvol::try_from(b).unwrap())
-                        .collect();
-                }
-
-                // perform filtering on lvols
-                if let Some(pool_name) = args.poolname {
-                    lvols = lvols
-                        .into_iter()
-                        .filter(|l| l.pool_name() == pool_name)
-                        .collect();
-                }
-
-                // convert lvols to replicas
-                let mut replicas: Vec<Replica> =
-                    lvols.into_iter().map(Replica::from).collect();
-
-                // perform the filtering on the replica list
-                if let Some(name) = args.name {
-                    replicas = replicas
-                        .into_iter()
-                        .filter(|r| r.name == name)
-                        .collect();
-                }
-
-                Ok(ListReplicasResponse {
-                    replicas,
-                })
-            })?;
-
-            rx.await
-                .map_err(|_| Status::cancelled("cancelled"))?
-                .map_err(Status::from)
-                .map(Response::new)
+            let snapshot = state_snapshot().await?;
+            Ok(Response::new(ListReplicasResponse {
+                replicas: snapshot.replicas(args.poolname, args.name),
+            }))
         })
         .await
     }
@@ -309,6 +283,12 @@ impl ReplicaRpc for ReplicaService {
                                             .to_string(),
                                     })
                                 }
+                                // replicas are shared over NVMF only
+                                Protocol::Iscsi | Protocol::Local | Protocol::VfioUser => {
+                                    return Err(LvsError::ReplicaShareProtocol {
+                                        value: args.share,
+                                    })
+                                }
                                 Protocol::Nvmf => {
                                     let props = ShareProps::new()
                                         .with_allowed_hosts(args.allowed_hosts)
@@ -0,0 +1,181 @@
+//! State of the pools, replicas and nexuses published for the listing gRPC
+//! methods.
+//!
+//! Listing the resources means walking the bdevs and the pools on the master
+//! reactor, which also does I/O: heavy listing traffic competes with the
+//! data path. The master reactor instead publishes the state periodically
+//! into a shared snapshot, which the gRPC server reads from its own threads
+//! without involving the reactor. Readers never wait on the reactor: the
+//! snapshot is immutable once published, and replacing it only swaps an
+//! `Arc`.
+//!
+//! The snapshot must not hide the outcome of a gRPC call to its caller: every
+//! call handled on the reactor invalidates it, both when it is submitted and
+//! when it completes, and an invalidated snapshot is only used again once
+//! republished. Until then the listing methods have the state collected on
+//! the reactor, which also publishes it. The changes made by the io-engine on
+//! its own, such as a child being faulted, show within the publish interval.
+
+use std::{
+    convert::TryFrom,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use mayastor_api::v1::{nexus::Nexus, pool::Pool, replica::Replica};
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use tonic::Status;
+
+use crate::{
+    bdev::nexus::{nexus_iter, NexusState},
+    core::{Reactor, Reactors, UntypedBdev},
+    lvs::{Lvol, Lvs},
+    sleep::mayastor_sleep,
+};
+
+/// Interval at which the state is published.
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Generation of the state, increased whenever it may have changed.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Last published snapshot of the state.
+static SNAPSHOT: Lazy<RwLock<Option<Arc<StateSnapshot>>>> =
+    Lazy::new(|| RwLock::new(None));
+
+/// Nexus as listed, along with what it is looked up by.
+#[derive(Debug)]
+struct NexusEntry {
+    alias: Option<String>,
+    /// Nexuses still being created are only listed when looked up.
+    listed: bool,
+    nexus: Nexus,
+}
+
+/// Snapshot of the state of the pools, replicas and nexuses.
+#[derive(Debug, Default)]
+pub(crate) struct StateSnapshot {
+    /// Generation of the state the snapshot was taken at.
+    generation: u64,
+    pools: Vec<Pool>,
+    replicas: Vec<Replica>,
+    nexuses: Vec<NexusEntry>,
+}
+
+impl StateSnapshot {
+    /// Collect the state, on the master reactor.
+    async fn collect() -> Self {
+        let generation = GENERATION.load(Ordering::SeqCst);
+        let pools = Lvs::iter().map(Pool::from).collect();
+        let replicas = UntypedBdev::bdev_first()
+            .map(|bdev| {
+                bdev.into_iter()
+                    .filter(|b| b.driver() == "lvol")
+                    .filter_map(|b| Lvol::try_from(b).ok())
+                    .map(Replica::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut nexuses = Vec::new();
+        for n in nexus_iter() {
+            nexuses.push(NexusEntry {
+                alias: n.alias(),
+                listed: n.state.lock().deref() != &NexusState::Init,
+                nexus: n.into_grpc().await,
+            });
+        }
+
+        Self {
+            generation,
+            pools,
+            replicas,
+            nexuses,
+        }
+    }
+
+    /// Returns the pools, or the pool with the given name.
+    pub(crate) fn pools(&self, name: Option<String>) -> Vec<Pool> {
+        self.pools
+            .iter()
+            .filter(|p| name.as_ref().map_or(true, |n| &p.name == n))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the replicas, of the given pool and with the given name.
+    pub(crate) fn replicas(
+        &self,
+        poolname: Option<String>,
+        name: Option<String>,
+    ) -> Vec<Replica> {
+        self.replicas
+            .iter()
+            .filter(|r| poolname.as_ref().map_or(true, |p| &r.poolname == p))
+            .filter(|r| name.as_ref().map_or(true, |n| &r.name == n))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the nexuses, or the nexus with the given name or alias.
+    pub(crate) fn nexuses(&self, name: Option<String>) -> Vec<Nexus> {
+        match name {
+            Some(name) => self
+                .nexuses
+                .iter()
+                .find(|e| {
+                    e.nexus.name == name || e.alias.as_ref() == Some(&name)
+                })
+                .map(|e| vec![e.nexus.clone()])
+                .unwrap_or_default(),
+            None => self
+                .nexuses
+                .iter()
+                .filter(|e| e.listed)
+                .map(|e| e.nexus.clone())
+                .collect(),
+        }
+    }
+}
+
+/// Record that the state may have changed, so that the current snapshot is
+/// no longer used.
+pub(crate) fn invalidate() {
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+fn publish(snapshot: StateSnapshot) -> Arc<StateSnapshot> {
+    let snapshot = Arc::new(snapshot);
+    *SNAPSHOT.write() = Some(snapshot.clone());
+    snapshot
+}
+
+/// Returns the current snapshot of the state, or the state collected on the
+/// master reactor if the snapshot is out of date.
+pub(crate) async fn state_snapshot() -> Result<Arc<StateSnapshot>, Status> {
+    if let Some(snapshot) = SNAPSHOT.read().clone() {
+        if snapshot.generation == GENERATION.load(Ordering::SeqCst) {
+            return Ok(snapshot);
+        }
+    }
+
+    let rx = Reactor::spawn_at_primary(async {
+        publish(StateSnapshot::collect().await)
+    })
+    .map_err(|_| Status::resource_exhausted("ENOMEM"))?;
+    rx.await.map_err(|_| Status::cancelled("cancelled"))
+}
+
+/// Publish the state periodically.
+pub fn start_state_publisher() {
+    Reactors::master().send_future(async {
+        loop {
+            mayastor_sleep(PUBLISH_INTERVAL).await.ok();
+            publish(StateSnapshot::collect().await);
+        }
+    });
+}
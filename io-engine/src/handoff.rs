@@ -0,0 +1,652 @@
+//! Handoff of the pools, nexuses and NVMf listeners between two io-engine
+//! processes running on the same node, for in-place upgrades.
+//!
+//! The running io-engine is started with a handoff socket, on which the new
+//! io-engine connects when started with the take over option. The new process
+//! initialises without importing its pools and without listening on the NVMf
+//! ports, then the handoff goes as follows:
+//!
+//! 1. the new process sends a `release` request;
+//! 2. the old process records its pools, the replicas it shares and its
+//!    nexuses, destroys the nexuses (keeping their definitions), exports the
+//!    pools and stops listening, and replies with the recorded state, passing
+//!    along its gRPC listen socket;
+//! 3. the new process starts listening, imports the pools, re-shares the
+//!    replicas the pool import did not share and re-creates and re-shares the
+//!    nexuses, the pools all at once and then the nexuses all at once;
+//! 4. the new process sends a `complete` request, upon which the old process
+//!    stops its gRPC server and exits, and the new process serves gRPC on the
+//!    socket it has been passed.
+//!
+//! Should the new process fail to restore the state, it gives back what it
+//! has restored so far and sends an `abort` request, and the old process
+//! restores the state instead; the same happens if the new process goes away
+//! or does not complete the handoff in time.
+//!
+//! The gRPC listen socket is passed over the handoff socket, so that the
+//! connections made while the old process stops serving are accepted by the
+//! new one rather than refused. The NVMf listen sockets however are owned by
+//! SPDK, which cannot adopt sockets inherited from another process, so they
+//! are closed by the old process and opened again by the new one. The hosts
+//! lose their
+//! connections for the duration of the handoff and reconnect once the nexuses
+//! are shared again, so I/O is paused but not failed. The pause is bounded:
+//! the new process aborts the handoff if the state is not restored within the
+//! maximum pause it has been given, counted from the `release` request, which
+//! must be kept below the controller loss timeout of the hosts.
+//!
+//! Messages are newline delimited json documents. The reply to the `release`
+//! request is preceded by a single byte message, which carries the file
+//! descriptor of the gRPC listen socket if the state has been released.
+
+use std::{
+    io,
+    net::{SocketAddr, TcpListener},
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
+    path::Path,
+    pin::Pin,
+    time::{Duration, Instant},
+};
+
+use futures::{future::join_all, Future};
+use nix::{
+    cmsg_space,
+    sys::{
+        socket::{
+            recvmsg,
+            sendmsg,
+            ControlMessage,
+            ControlMessageOwned,
+            MsgFlags,
+        },
+        uio::IoVec,
+    },
+};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Interest},
+    net::{UnixListener, UnixStream},
+};
+
+use crate::{
+    bdev::{
+        nexus::{
+            nexus_create_v2,
+            nexus_iter,
+            nexus_iter_mut,
+            nexus_lookup,
+            nexus_lookup_mut,
+        },
+        PtplFileOps,
+    },
+    core::{
+        mayastor_env_stop,
+        Protocol,
+        Reactor,
+        Share,
+        ShareProps,
+        VerboseError,
+    },
+    grpc::MayastorGrpcServer,
+    lvs::Lvs,
+    pool_backend::PoolArgs,
+    reconcile::NexusSpec,
+    subsys::NvmfTarget,
+};
+
+/// Time the old process waits for the new one to complete the handoff,
+/// before restoring the state it released.
+const HANDOFF_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The gRPC listen socket, which the gRPC server serves on and which is
+/// passed over to the process taking over.
+static GRPC_LISTENER: Lazy<Mutex<Option<TcpListener>>> =
+    Lazy::new(|| Mutex::new(None));
+
+/// Pool handed over.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HandoffPool {
+    pub name: String,
+    pub disks: Vec<String>,
+    pub uuid: String,
+}
+
+/// Replica shared over NVMf handed over.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HandoffReplica {
+    pub pool: String,
+    pub name: String,
+    /// Hosts allowed to connect to the replica.
+    pub allowed_hosts: Vec<String>,
+}
+
+/// Nexus handed over.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HandoffNexus {
+    pub spec: NexusSpec,
+    /// Children which were healthy when the nexus was released, the nexus
+    /// is re-created with these only.
+    pub healthy_children: Vec<String>,
+    /// Whether the nexus was shared over NVMf.
+    pub shared: bool,
+    /// Hosts allowed to connect to the nexus.
+    pub allowed_hosts: Vec<String>,
+}
+
+/// State handed over from one io-engine process to another.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HandoffState {
+    /// Version of the io-engine which released the state.
+    pub version: String,
+    pub pools: Vec<HandoffPool>,
+    /// Replicas of the pools which were shared over NVMf.
+    #[serde(default)]
+    pub replicas: Vec<HandoffReplica>,
+    pub nexuses: Vec<HandoffNexus>,
+}
+
+/// Request sent by the new process.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "request", rename_all = "snake_case")]
+enum Request {
+    /// Release the pools, nexuses and NVMf listeners.
+    Release,
+    /// The state has been restored, the old process can exit.
+    Complete,
+    /// The state could not be restored, the old process must restore it.
+    Abort,
+}
+
+/// Reply sent by the old process.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "reply", rename_all = "snake_case")]
+enum Reply {
+    Released { state: HandoffState },
+    Done,
+    Failed { error: String },
+}
+
+/// Run the given future on the primary reactor and wait for its output.
+async fn on_primary<F, T>(f: F) -> Result<T, String>
+where
+    F: Future<Output = Result<T, String>> + 'static,
+    T: Send + std::fmt::Debug + 'static,
+{
+    Reactor::spawn_at_primary(f)
+        .map_err(|e| e.to_string())?
+        .await
+        .map_err(|_| "primary reactor is gone".to_string())?
+}
+
+/// Record the pools, the shared replicas and the nexuses of this process.
+pub async fn capture() -> HandoffState {
+    let pools = Lvs::iter()
+        .map(|lvs| HandoffPool {
+            name: lvs.name().to_string(),
+            disks: lvs.base_bdev().bdev_uri().into_iter().collect(),
+            uuid: lvs.uuid(),
+        })
+        .collect();
+
+    let replicas = Lvs::iter()
+        .flat_map(|lvs| lvs.lvols().into_iter().flatten())
+        .filter(|lvol| lvol.shared() == Some(Protocol::Nvmf))
+        .map(|lvol| HandoffReplica {
+            pool: lvol.pool_name(),
+            name: lvol.name(),
+            allowed_hosts: lvol.allowed_hosts(),
+        })
+        .collect();
+
+    let mut nexuses = Vec::new();
+    for nexus in nexus_iter() {
+        nexuses.push(HandoffNexus {
+            spec: nexus.spec().await,
+            healthy_children: nexus
+                .children_iter()
+                .filter(|c| c.is_healthy())
+                .map(|c| c.uri().to_string())
+                .collect(),
+            shared: nexus.shared() == Some(Protocol::Nvmf),
+            allowed_hosts: nexus.allowed_hosts(),
+        });
+    }
+
+    HandoffState {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        pools,
+        replicas,
+        nexuses,
+    }
+}
+
+/// Release the nexuses, the pools and the NVMf listeners, in this order as
+/// the nexuses may have children on the local pools.
+#[allow(clippy::needless_collect)]
+pub async fn release(state: &HandoffState) -> Result<(), String> {
+    info!(
+        "Releasing {} nexuses and {} pools",
+        state.nexuses.len(),
+        state.pools.len()
+    );
+
+    let nexuses: Vec<_> = nexus_iter_mut().collect();
+    for nexus in nexuses.into_iter() {
+        let name = nexus.name.clone();
+        nexus.destroy_ext(true).await.map_err(|e| {
+            format!("failed to release nexus {}: {}", name, e.verbose())
+        })?;
+    }
+
+    for pool in &state.pools {
+        if let Some(lvs) = Lvs::lookup(&pool.name) {
+            lvs.export().await.map_err(|e| {
+                format!("failed to release pool {}: {}", pool.name, e)
+            })?;
+        }
+    }
+
+    NvmfTarget::stop_listening();
+    Ok(())
+}
+
+/// Fail if the given deadline, if any, has passed.
+fn check_deadline(deadline: Option<Instant>) -> Result<(), String> {
+    match deadline {
+        Some(deadline) if Instant::now() >= deadline => {
+            Err("the maximum I/O pause has been reached".to_string())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Re-create and re-share the given nexus, unless it exists already.
+async fn restore_nexus(
+    nexus: &HandoffNexus,
+    deadline: Option<Instant>,
+) -> Result<(), String> {
+    let spec = &nexus.spec;
+    if nexus_lookup(&spec.name).is_none() {
+        if nexus.healthy_children.is_empty() {
+            warn!("Not restoring nexus {}: no healthy children", spec.name);
+            return Ok(());
+        }
+        nexus_create_v2(
+            &spec.name,
+            spec.size,
+            &spec.uuid,
+            spec.nvme_params(),
+            &nexus.healthy_children,
+            spec.nexus_info_key.clone(),
+        )
+        .await
+        .map_err(|e| {
+            format!("failed to create nexus {}: {}", spec.name, e.verbose())
+        })?;
+        spec.restore_settings().await;
+    }
+
+    check_deadline(deadline)?;
+    if nexus.shared {
+        if let Some(n) = nexus_lookup_mut(&spec.name) {
+            n.share_ext(Protocol::Nvmf, None, nexus.allowed_hosts.clone())
+                .await
+                .map_err(|e| {
+                    format!(
+                        "failed to share nexus {}: {}",
+                        spec.name,
+                        e.verbose()
+                    )
+                })?;
+        }
+    }
+    Ok(())
+}
+
+/// Re-share the given replica, unless the import of its pool shared it.
+async fn restore_replica(replica: &HandoffReplica) -> Result<(), String> {
+    let lvol = Lvs::lookup(&replica.pool)
+        .and_then(|lvs| lvs.lvols())
+        .and_then(|mut lvols| lvols.find(|l| l.name() == replica.name));
+    let mut lvol = match lvol {
+        Some(lvol) if lvol.shared().is_none() => lvol,
+        Some(_) => return Ok(()),
+        None => {
+            warn!(
+                "Not restoring replica {}: not found in pool {}",
+                replica.name, replica.pool
+            );
+            return Ok(());
+        }
+    };
+
+    let props = ShareProps::new()
+        .with_allowed_hosts(replica.allowed_hosts.clone())
+        .with_ptpl(lvol.ptpl().create().unwrap_or_default());
+    Pin::new(&mut lvol)
+        .share_nvmf(Some(props))
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("failed to share replica {}: {}", replica.name, e))
+}
+
+/// Restore the given state, failing if it is not restored by the given
+/// deadline. Resources which already exist are left alone, so that a
+/// partially released state can be restored.
+pub async fn restore(
+    state: &HandoffState,
+    deadline: Option<Instant>,
+) -> Result<(), String> {
+    info!(
+        "Restoring {} nexuses and {} pools released by io-engine {}",
+        state.nexuses.len(),
+        state.pools.len(),
+        state.version
+    );
+
+    NvmfTarget::start_listening()
+        .map_err(|e| format!("failed to start the nvmf listeners: {}", e))?;
+
+    let pools = state
+        .pools
+        .iter()
+        .filter(|pool| Lvs::lookup(&pool.name).is_none())
+        .map(|pool| async move {
+            Lvs::import_from_args(PoolArgs {
+                name: pool.name.clone(),
+                disks: pool.disks.clone(),
+                uuid: Some(pool.uuid.clone()),
+            })
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("failed to import pool {}: {}", pool.name, e))
+        });
+    join_all(pools)
+        .await
+        .into_iter()
+        .collect::<Result<(), _>>()?;
+    check_deadline(deadline)?;
+
+    // the pool import shares the replicas recorded as shared on disk, which
+    // are not necessarily all of those which were shared
+    join_all(state.replicas.iter().map(restore_replica))
+        .await
+        .into_iter()
+        .collect::<Result<(), _>>()?;
+    check_deadline(deadline)?;
+
+    let nexuses = state
+        .nexuses
+        .iter()
+        .map(|nexus| restore_nexus(nexus, deadline));
+    join_all(nexuses)
+        .await
+        .into_iter()
+        .collect::<Result<(), _>>()?;
+    check_deadline(deadline)
+}
+
+async fn send<T: Serialize>(
+    stream: &mut UnixStream,
+    message: &T,
+) -> Result<(), String> {
+    let mut data = serde_json::to_vec(message).map_err(|e| e.to_string())?;
+    data.push(b'\n');
+    stream.write_all(&data).await.map_err(|e| e.to_string())
+}
+
+async fn receive<T: serde::de::DeserializeOwned>(
+    reader: &mut BufReader<&mut UnixStream>,
+) -> Result<T, String> {
+    let mut line = String::new();
+    match reader.read_line(&mut line).await {
+        Ok(0) => Err("connection closed".to_string()),
+        Ok(_) => serde_json::from_str(&line).map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Send a single byte message on the given stream, carrying the given file
+/// descriptors.
+pub async fn send_fds(
+    stream: &UnixStream,
+    fds: &[RawFd],
+) -> Result<(), String> {
+    let data = [0u8];
+    let iov = [IoVec::from_slice(&data)];
+    let rights = [ControlMessage::ScmRights(fds)];
+    let cmsgs = if fds.is_empty() { &[][..] } else { &rights[..] };
+    loop {
+        stream.writable().await.map_err(|e| e.to_string())?;
+        match stream.try_io(Interest::WRITABLE, || {
+            sendmsg(stream.as_raw_fd(), &iov, cmsgs, MsgFlags::empty(), None)
+                .map_err(io::Error::from)
+        }) {
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+/// Receive a single byte message on the given stream, and the file
+/// descriptors it carries.
+pub async fn receive_fds(stream: &UnixStream) -> Result<Vec<RawFd>, String> {
+    let mut data = [0u8];
+    let mut space = cmsg_space!([RawFd; 1]);
+    loop {
+        stream.readable().await.map_err(|e| e.to_string())?;
+        match stream.try_io(Interest::READABLE, || {
+            let iov = [IoVec::from_mut_slice(&mut data)];
+            recvmsg(
+                stream.as_raw_fd(),
+                &iov,
+                Some(&mut space),
+                MsgFlags::MSG_CMSG_CLOEXEC,
+            )
+            .map(|msg| {
+                let fds = msg
+                    .cmsgs()
+                    .flat_map(|cmsg| match cmsg {
+                        ControlMessageOwned::ScmRights(fds) => fds,
+                        _ => Vec::new(),
+                    })
+                    .collect::<Vec<_>>();
+                (msg.bytes, fds)
+            })
+            .map_err(io::Error::from)
+        }) {
+            Ok((0, _)) => return Err("connection closed".to_string()),
+            Ok((_, fds)) => return Ok(fds),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+/// The gRPC listen socket to serve on: the one passed over by the process
+/// this one took over from if any, otherwise a socket bound to the given
+/// endpoint. It is kept to be passed over in turn.
+pub fn grpc_listener(endpoint: SocketAddr) -> io::Result<TcpListener> {
+    let mut listener = GRPC_LISTENER.lock();
+    if listener.is_none() {
+        *listener = Some(TcpListener::bind(endpoint)?);
+    }
+    listener.as_ref().unwrap().try_clone()
+}
+
+/// Hand over the state of this process to the new process connected on the
+/// given stream.
+async fn hand_over(mut stream: UnixStream) -> Result<(), String> {
+    match receive(&mut BufReader::new(&mut stream)).await? {
+        Request::Release => {}
+        request => {
+            return Err(format!("unexpected handoff request {:?}", request))
+        }
+    }
+
+    let state = match on_primary(async {
+        let state = capture().await;
+        match release(&state).await {
+            Ok(_) => Ok(state),
+            Err(error) => {
+                if let Err(e) = restore(&state, None).await {
+                    error!("Failed to restore the released state: {}", e);
+                }
+                Err(error)
+            }
+        }
+    })
+    .await
+    {
+        Ok(state) => state,
+        Err(error) => {
+            send_fds(&stream, &[]).await.ok();
+            send(
+                &mut stream,
+                &Reply::Failed {
+                    error: error.clone(),
+                },
+            )
+            .await
+            .ok();
+            return Err(error);
+        }
+    };
+
+    let fds: Vec<RawFd> = GRPC_LISTENER
+        .lock()
+        .as_ref()
+        .map(|l| l.as_raw_fd())
+        .into_iter()
+        .collect();
+    send_fds(&stream, &fds).await?;
+    send(
+        &mut stream,
+        &Reply::Released {
+            state: state.clone(),
+        },
+    )
+    .await?;
+
+    let request = tokio::time::timeout(
+        HANDOFF_TIMEOUT,
+        receive::<Request>(&mut BufReader::new(&mut stream)),
+    )
+    .await
+    .unwrap_or_else(|_| Err("timed out".to_string()));
+
+    match request {
+        Ok(Request::Complete) => {
+            info!("Handoff complete, shutting down");
+            MayastorGrpcServer::get_or_init().fini();
+            send(&mut stream, &Reply::Done).await.ok();
+            on_primary(async {
+                mayastor_env_stop(0);
+                Ok(())
+            })
+            .await
+        }
+        request => {
+            warn!("Handoff not completed ({:?}), restoring state", request);
+            on_primary(async move { restore(&state, None).await }).await
+        }
+    }
+}
+
+/// Serve handoff requests on the given unix socket.
+pub async fn serve(path: String) {
+    if Path::new(&path).exists() {
+        std::fs::remove_file(&path).ok();
+    }
+
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(error) => {
+            error!("Failed to bind the handoff socket {}: {}", path, error);
+            return;
+        }
+    };
+    info!("Serving handoff requests on {}", path);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                if let Err(error) = hand_over(stream).await {
+                    error!("Handoff failed: {}", error);
+                }
+            }
+            Err(error) => {
+                error!("Failed to accept a handoff connection: {}", error);
+            }
+        }
+    }
+}
+
+/// Take over the pools, nexuses and NVMf listeners of the io-engine process
+/// serving handoff requests on the given unix socket, pausing their I/O for
+/// no longer than the given maximum pause, and its gRPC listen socket, which
+/// the gRPC server of this process then serves on. Should the handoff fail,
+/// the state is left to the io-engine process serving the requests.
+pub async fn take_over(path: &str, max_pause: Duration) -> Result<(), String> {
+    info!("Taking over from the io-engine serving on {}", path);
+
+    let mut stream = UnixStream::connect(path)
+        .await
+        .map_err(|e| format!("failed to connect to {}: {}", path, e))?;
+
+    let started = Instant::now();
+    send(&mut stream, &Request::Release).await?;
+    // the socket is closed when dropped, unless the handoff completes
+    let listeners = receive_fds(&stream)
+        .await?
+        .into_iter()
+        .map(|fd| unsafe { TcpListener::from_raw_fd(fd) })
+        .collect::<Vec<_>>();
+    let state = match receive(&mut BufReader::new(&mut stream)).await? {
+        Reply::Released {
+            state,
+        } => state,
+        Reply::Failed {
+            error,
+        } => return Err(format!("failed to release the state: {}", error)),
+        reply => return Err(format!("unexpected handoff reply {:?}", reply)),
+    };
+
+    let restored = state.clone();
+    let deadline = Some(started + max_pause);
+    if let Err(error) =
+        on_primary(async move { restore(&restored, deadline).await }).await
+    {
+        // give back what has been restored so far, for the old process to
+        // restore all of it
+        if let Err(e) = on_primary(async {
+            let state = capture().await;
+            release(&state).await
+        })
+        .await
+        {
+            error!("Failed to release the partially restored state: {}", e);
+        }
+        send(&mut stream, &Request::Abort).await.ok();
+        return Err(error);
+    }
+
+    send(&mut stream, &Request::Complete).await?;
+    match receive(&mut BufReader::new(&mut stream)).await? {
+        Reply::Done => {
+            if let Some(listener) = listeners.into_iter().next() {
+                *GRPC_LISTENER.lock() = Some(listener);
+            }
+            info!(
+                "Took over {} nexuses and {} pools, I/O paused for {:?}",
+                state.nexuses.len(),
+                state.pools.len(),
+                started.elapsed()
+            );
+            Ok(())
+        }
+        reply => Err(format!("unexpected handoff reply {:?}", reply)),
+    }
+}
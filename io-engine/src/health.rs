@@ -0,0 +1,193 @@
+//! Health and readiness of the node.
+//!
+//! The io-engine answering gRPC calls does not make it fit to be given
+//! work: its pools may have failed to import, the startup reconciliation may
+//! still be running, or its NVMf target may not listen yet while resources
+//! are handed over by the io-engine being upgraded. The health of the node
+//! is instead made of checks of its internal state, each of them either
+//! passing, degraded or failed, with the reason of the latter:
+//!
+//! - reactors: all the reactors are running, and none of them is frozen,
+//! - startup: the startup reconciliation has completed,
+//! - pools: the pools of the pool configuration have all been imported,
+//! - persistent store: the persistent store, if any, is reachable, and no
+//!   ownership record has been lost,
+//! - nvmf: the NVMf target, if enabled, is listening,
+//! - drain: the node is not being drained.
+//!
+//! The node is live unless its reactors have failed, and ready if none of
+//! its checks has failed. A degraded check does not make the node unready,
+//! its reason is reported for the operators.
+//!
+//! The health is returned by the `mayastor_health` json-rpc method, which is
+//! also reachable over the gRPC json service, and served over HTTP by the
+//! metrics server on `/healthz` for the liveness and `/readyz` for the
+//! readiness, with a status of 503 when the node is not, so that the probes
+//! of the orchestrators can rely on it.
+
+use futures::FutureExt;
+use serde::Serialize;
+
+use crate::{
+    core::{reactor_is_frozen, ReactorState, Reactors},
+    drain::is_draining,
+    jsonrpc::{jsonrpc_register, JsonRpcError},
+    persistent_store::PersistentStore,
+    reconcile::{ReconcileState, StartupReport},
+    subsys::{pools_not_imported, Config, NvmfTarget},
+};
+
+/// Status of a health check.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Passing,
+    Degraded,
+    Failed,
+}
+
+/// Outcome of a health check.
+#[derive(Serialize, Debug, Clone)]
+pub struct HealthCheck {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    /// Reason of a degraded or failed check.
+    pub reason: Option<String>,
+}
+
+impl HealthCheck {
+    fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Passing,
+            reason: None,
+        }
+    }
+
+    fn degraded(mut self, reason: String) -> Self {
+        self.status = CheckStatus::Degraded;
+        self.reason = Some(reason);
+        self
+    }
+
+    fn failed(mut self, reason: String) -> Self {
+        self.status = CheckStatus::Failed;
+        self.reason = Some(reason);
+        self
+    }
+}
+
+/// Health of the node.
+#[derive(Serialize, Debug, Clone)]
+pub struct NodeHealth {
+    /// The io-engine makes progress.
+    pub live: bool,
+    /// The io-engine can be given work.
+    pub ready: bool,
+    pub checks: Vec<HealthCheck>,
+}
+
+fn check_reactors() -> HealthCheck {
+    let check = HealthCheck::new("reactors");
+    let mut failed = Vec::new();
+    for r in Reactors::iter() {
+        if reactor_is_frozen(r.core()) {
+            failed.push(format!("reactor {} is frozen", r.core()));
+        } else if !matches!(
+            r.get_state(),
+            ReactorState::Running | ReactorState::Delayed
+        ) {
+            failed.push(format!("reactor {} is {}", r.core(), r.get_state()));
+        }
+    }
+    if failed.is_empty() {
+        check
+    } else {
+        check.failed(failed.join(", "))
+    }
+}
+
+fn check_startup() -> HealthCheck {
+    let check = HealthCheck::new("startup");
+    match StartupReport::get().state {
+        ReconcileState::Completed | ReconcileState::Disabled => check,
+        ReconcileState::NotStarted => {
+            check.failed("startup reconciliation not started".to_string())
+        }
+        ReconcileState::Running => {
+            check.failed("startup reconciliation in progress".to_string())
+        }
+    }
+}
+
+fn check_pools() -> HealthCheck {
+    let check = HealthCheck::new("pools");
+    match pools_not_imported() {
+        missing if missing.is_empty() => check,
+        missing => {
+            check.failed(format!("pools not imported: {}", missing.join(", ")))
+        }
+    }
+}
+
+fn check_persistent_store() -> HealthCheck {
+    let check = HealthCheck::new("persistent_store");
+    let health = PersistentStore::health();
+    if !health.enabled {
+        check
+    } else if !health.connected {
+        check.failed(format!(
+            "persistent store {} unreachable",
+            health.endpoint.unwrap_or_default()
+        ))
+    } else if !health.lost_keys.is_empty() {
+        check.degraded(format!(
+            "ownership of {} record(s) lost",
+            health.lost_keys.len()
+        ))
+    } else {
+        check
+    }
+}
+
+fn check_nvmf() -> HealthCheck {
+    let check = HealthCheck::new("nvmf");
+    if !Config::get().nexus_opts.nvmf_enable || NvmfTarget::is_listening() {
+        check
+    } else {
+        check.failed("NVMf target not listening".to_string())
+    }
+}
+
+fn check_drain() -> HealthCheck {
+    let check = HealthCheck::new("drain");
+    if is_draining() {
+        check.failed("node being drained".to_string())
+    } else {
+        check
+    }
+}
+
+/// Get the health of the node. Must be called from the master core.
+pub fn node_health() -> NodeHealth {
+    let checks = vec![
+        check_reactors(),
+        check_startup(),
+        check_pools(),
+        check_persistent_store(),
+        check_nvmf(),
+        check_drain(),
+    ];
+    NodeHealth {
+        live: checks[0].status != CheckStatus::Failed,
+        ready: checks.iter().all(|c| c.status != CheckStatus::Failed),
+        checks,
+    }
+}
+
+/// Register the health json-rpc methods.
+pub(crate) fn register_rpc_methods() {
+    jsonrpc_register::<(), _, _, JsonRpcError>("mayastor_health", |_| {
+        async move { Ok(node_health()) }.boxed_local()
+    });
+}
@@ -20,15 +20,28 @@
 //!    logically implies that the device is not currently mounted, for the sake
 //!    of consistency, the mount table is also checked to ENSURE that the device
 //!    is not mounted)
+//!
+//! Each device also carries its capabilities: whether it is a persistent
+//! memory (PMEM) namespace, and whether it supports DAX, in which case a pool
+//! created on it is backed by the pmem bdev and its writes are durable once
+//! flushed. Since the gRPC device listing has no room for these, they are
+//! listed by the host_block_device_capabilities json-rpc method.
 
 use std::{
     collections::HashMap,
     ffi::{OsStr, OsString},
     io::Error,
+    path::{Path, PathBuf},
 };
 
-use crate::constants::{NEXUS_CAS_DRIVER, NVME_CONTROLLER_MODEL_ID};
+use crate::{
+    bdev::pmem,
+    constants::{NEXUS_CAS_DRIVER, NVME_CONTROLLER_MODEL_ID},
+    jsonrpc::{jsonrpc_register, Code, JsonRpcError},
+};
+use futures::FutureExt;
 use proc_mounts::{MountInfo, MountIter};
+use serde::{Deserialize, Serialize};
 use udev::{Device, Enumerator};
 
 // Struct representing a property value in a udev::Device struct (and possibly
@@ -68,6 +81,8 @@ pub struct BlockDevice {
     pub partition: Option<Partition>,
     pub filesystem: Option<FileSystem>,
     pub available: bool,
+    pub pmem: bool,
+    pub dax: bool,
 }
 
 impl From<Property<'_>> for String {
@@ -257,6 +272,8 @@ fn new_device(
             && (partition.is_none() || usable_partition(&partition))
             && filesystem.is_none();
 
+        let dax = dax_device(Path::new(devname));
+
         return Some(BlockDevice {
             devname: String::from(devname.to_str().unwrap_or("")),
             devtype: Property(device.property_value("DEVTYPE")).into(),
@@ -277,11 +294,47 @@ fn new_device(
             partition,
             filesystem,
             available,
+            pmem: pmem_device(Path::new(devname)),
+            dax,
         });
     }
     None
 }
 
+// Returns the sysfs directory of a block device, given its device node or a
+// link to it.
+fn sysfs_block_dir(device: &Path) -> Option<PathBuf> {
+    let device = device.canonicalize().ok()?;
+    let name = device.file_name()?;
+    Some(Path::new("/sys/class/block").join(name))
+}
+
+/// Returns true if the given block device, or the disk of the given
+/// partition, is a persistent memory namespace, that is it sits on a
+/// libnvdimm bus.
+pub fn pmem_device(device: &Path) -> bool {
+    sysfs_block_dir(device)
+        .and_then(|dir| dir.canonicalize().ok())
+        .map_or(false, |dir| dir.to_string_lossy().contains("/ndbus"))
+}
+
+/// Returns true if the given block device, or the disk of the given
+/// partition, supports DAX, i.e. its writes bypass the page cache to the
+/// persistent memory.
+pub fn dax_device(device: &Path) -> bool {
+    let dir = match sysfs_block_dir(device) {
+        Some(dir) => dir,
+        None => return false,
+    };
+    // the queue attributes of a partition are the ones of its disk
+    let queue = if dir.join("partition").exists() {
+        dir.join("../queue/dax")
+    } else {
+        dir.join("queue/dax")
+    };
+    std::fs::read_to_string(queue).map_or(false, |dax| dax.trim() == "1")
+}
+
 // Get the list of current filesystem mounts.
 fn get_mounts() -> Result<HashMap<OsString, Vec<MountInfo>>, Error> {
     let mut table: HashMap<OsString, Vec<MountInfo>> = HashMap::new();
@@ -363,3 +416,59 @@ pub async fn list_block_devices(all: bool) -> Result<Vec<BlockDevice>, Error> {
     let mounts = get_mounts()?;
     get_disks(all, &mounts)
 }
+
+/// Arguments of the host_block_device_capabilities json-rpc method.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct CapabilitiesArgs {
+    /// List all the devices, not only the available ones.
+    all: bool,
+}
+
+/// Capabilities of a block device, as listed over json-rpc.
+#[derive(Debug, Serialize)]
+struct BlockDeviceCapabilities {
+    devname: String,
+    available: bool,
+    pmem: bool,
+    dax: bool,
+    /// Scheme of the bdev a pool created on the device is backed by.
+    scheme: &'static str,
+    /// Durability of the writes to a pool created on the device.
+    durability: &'static str,
+}
+
+impl From<BlockDevice> for BlockDeviceCapabilities {
+    fn from(device: BlockDevice) -> Self {
+        let scheme = if device.dax { "pmem" } else { "aio" };
+        Self {
+            devname: device.devname,
+            available: device.available,
+            pmem: device.pmem,
+            dax: device.dax,
+            scheme,
+            durability: pmem::durability(scheme),
+        }
+    }
+}
+
+/// Register the block device json-rpc methods.
+pub fn register_rpc_methods() {
+    jsonrpc_register(
+        "host_block_device_capabilities",
+        |args: CapabilitiesArgs| {
+            async move {
+                list_block_devices(args.all)
+                    .await
+                    .map(|devices| {
+                        devices
+                            .into_iter()
+                            .map(BlockDeviceCapabilities::from)
+                            .collect::<Vec<_>>()
+                    })
+                    .map_err(|e| JsonRpcError::new(Code::InternalError, e))
+            }
+            .boxed_local()
+        },
+    );
+}
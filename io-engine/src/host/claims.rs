@@ -0,0 +1,184 @@
+//! Claims of the kernel on the block devices of the host.
+//!
+//! A block device of the host given to the io-engine, for a pool or as an
+//! aio or uring child, is written to without the kernel knowing: if the
+//! kernel uses the device, because it holds a mounted filesystem, is a swap
+//! area, or is a member of a device-mapper device (LVM, dm-crypt, multipath)
+//! or of a MD RAID array, whatever it holds is destroyed. The device, and
+//! the partitions of a whole disk, are therefore checked for such claims
+//! before a bdev is created on it, from sysfs for the holders of the device,
+//! and from the mount table and the swap areas of the host for the others.
+
+use std::{
+    fmt::{Display, Formatter},
+    fs,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+const SYS_BLOCK: &str = "/sys/class/block";
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+const SWAPS: &str = "/proc/swaps";
+
+/// Kind of claim of the kernel on a block device.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClaimKind {
+    /// Holds a mounted filesystem.
+    Mounted,
+    /// Is an active swap area.
+    Swap,
+    /// Is a member of a device-mapper device.
+    DmMember,
+    /// Is a member of a MD RAID array.
+    MdMember,
+    /// Is held by another device.
+    Held,
+}
+
+/// Claim of the kernel on a block device.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceClaim {
+    /// Device claimed, the device itself or one of its partitions.
+    pub device: String,
+    pub kind: ClaimKind,
+    /// What claims the device: the mount point, or the holder.
+    pub by: String,
+}
+
+impl Display for DeviceClaim {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            ClaimKind::Mounted => {
+                write!(f, "{} is mounted on {}", self.device, self.by)
+            }
+            ClaimKind::Swap => write!(f, "{} is a swap area", self.device),
+            ClaimKind::DmMember => write!(
+                f,
+                "{} is a member of device-mapper device {}",
+                self.device, self.by
+            ),
+            ClaimKind::MdMember => write!(
+                f,
+                "{} is a member of RAID array {}",
+                self.device, self.by
+            ),
+            ClaimKind::Held => {
+                write!(f, "{} is held by {}", self.device, self.by)
+            }
+        }
+    }
+}
+
+/// Returns the device number of the given block device, as "major:minor".
+fn device_number(sysfs: &Path) -> Option<String> {
+    fs::read_to_string(sysfs.join("dev"))
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+/// Returns the name of a holder of a device, the device-mapper name of a
+/// dm device.
+fn holder_claim(device: &str, holder: &str) -> DeviceClaim {
+    let sysfs = Path::new(SYS_BLOCK).join(holder);
+    if let Ok(name) = fs::read_to_string(sysfs.join("dm").join("name")) {
+        return DeviceClaim {
+            device: device.to_string(),
+            kind: ClaimKind::DmMember,
+            by: format!("{} ({})", name.trim(), holder),
+        };
+    }
+    DeviceClaim {
+        device: device.to_string(),
+        kind: if sysfs.join("md").exists() {
+            ClaimKind::MdMember
+        } else {
+            ClaimKind::Held
+        },
+        by: holder.to_string(),
+    }
+}
+
+/// Returns the claims on the given block device alone.
+fn claims_of(name: &str, mountinfo: &str, swaps: &str) -> Vec<DeviceClaim> {
+    let sysfs = Path::new(SYS_BLOCK).join(name);
+    let device = format!("/dev/{}", name);
+    let mut claims = Vec::new();
+
+    if let Ok(holders) = fs::read_dir(sysfs.join("holders")) {
+        claims.extend(
+            holders.filter_map(|h| h.ok()).map(|h| {
+                holder_claim(&device, &h.file_name().to_string_lossy())
+            }),
+        );
+    }
+
+    // the fields of a mount are its id, the id of its parent, the number of
+    // its device, its root and its mount point
+    if let Some(number) = device_number(&sysfs) {
+        claims.extend(
+            mountinfo
+                .lines()
+                .map(|l| l.split_whitespace().collect::<Vec<_>>())
+                .filter(|f| f.len() > 4 && f[2] == number)
+                .map(|f| DeviceClaim {
+                    device: device.clone(),
+                    kind: ClaimKind::Mounted,
+                    by: f[4].to_string(),
+                }),
+        );
+    }
+
+    if swaps
+        .lines()
+        .skip(1)
+        .filter_map(|l| l.split_whitespace().next())
+        .any(|s| fs::canonicalize(s).map_or(false, |p| p == Path::new(&device)))
+    {
+        claims.push(DeviceClaim {
+            device: device.clone(),
+            kind: ClaimKind::Swap,
+            by: String::new(),
+        });
+    }
+    claims
+}
+
+/// Returns the claims of the kernel on the given block device, and on its
+/// partitions if it is a whole disk. A path which is not a block device,
+/// such as a file, has no claims.
+pub fn device_claims(path: &Path) -> Vec<DeviceClaim> {
+    let device: PathBuf = match fs::canonicalize(path) {
+        Ok(device) => device,
+        Err(_) => return Vec::new(),
+    };
+    let is_block_device = fs::metadata(&device)
+        .map(|m| m.file_type().is_block_device())
+        .unwrap_or(false);
+    let name = match device.file_name().and_then(|n| n.to_str()) {
+        Some(name) if is_block_device => name.to_string(),
+        _ => return Vec::new(),
+    };
+
+    let mountinfo = fs::read_to_string(MOUNTINFO).unwrap_or_default();
+    let swaps = fs::read_to_string(SWAPS).unwrap_or_default();
+
+    let mut claims = claims_of(&name, &mountinfo, &swaps);
+    // the partitions of a disk are the directories of its sysfs directory
+    // which have a partition number
+    if let Ok(entries) = fs::read_dir(Path::new(SYS_BLOCK).join(&name)) {
+        entries
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().join("partition").exists())
+            .for_each(|e| {
+                claims.extend(claims_of(
+                    &e.file_name().to_string_lossy(),
+                    &mountinfo,
+                    &swaps,
+                ))
+            });
+    }
+    claims
+}
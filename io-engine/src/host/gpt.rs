@@ -0,0 +1,249 @@
+//! Labeling of the GPT partitions of the host block devices.
+//!
+//! The entry of a partition in the GPT of its disk holds the type and the
+//! name of the partition, which are shown by the tools of the host, such as
+//! lsblk or blkid. A partition used by a pool is labeled by changing them in
+//! both the primary and the backup GPT of the disk, so that it is not
+//! mistaken for a free partition on the host. Only the partition entry is
+//! changed: the data of the partition, and so the pool, is not touched.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Error, ErrorKind},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+};
+
+use uuid::Uuid;
+
+/// Signature of a GPT header.
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+
+/// Offsets of the fields of a GPT header.
+const HDR_SIZE: usize = 12;
+const HDR_CRC: usize = 16;
+const HDR_MY_LBA: usize = 24;
+const HDR_ALTERNATE_LBA: usize = 32;
+const HDR_ENTRIES_LBA: usize = 72;
+const HDR_NUM_ENTRIES: usize = 80;
+const HDR_ENTRY_SIZE: usize = 84;
+const HDR_ENTRIES_CRC: usize = 88;
+
+/// Offsets of the fields of a GPT partition entry.
+const ENTRY_TYPE: usize = 0;
+const ENTRY_FIRST_LBA: usize = 32;
+const ENTRY_NAME: usize = 56;
+
+/// Length of the name of a partition, in UTF-16 code units.
+const NAME_LEN: usize = 36;
+
+/// Partition of a disk of the host.
+#[derive(Debug)]
+pub struct HostPartition {
+    /// Device of the disk of the partition.
+    pub disk: PathBuf,
+    /// Number of the partition, starting at 1.
+    pub number: u32,
+    /// First sector of the partition, in 512 byte sectors.
+    pub start: u64,
+}
+
+/// Returns the partition the given device is, if it is one.
+pub fn host_partition(device: &Path) -> Result<Option<HostPartition>, Error> {
+    let device = fs::canonicalize(device)?;
+    let name = device.file_name().ok_or_else(|| {
+        Error::new(ErrorKind::InvalidInput, "not a block device")
+    })?;
+    let sysfs = fs::canonicalize(Path::new("/sys/class/block").join(name))?;
+    if !sysfs.join("partition").exists() {
+        return Ok(None);
+    }
+
+    let read = |attr: &str| -> Result<u64, Error> {
+        fs::read_to_string(sysfs.join(attr))?
+            .trim()
+            .parse()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    };
+    let disk = sysfs.parent().and_then(|p| p.file_name()).ok_or_else(|| {
+        Error::new(ErrorKind::NotFound, "no disk for the partition")
+    })?;
+
+    Ok(Some(HostPartition {
+        disk: Path::new("/dev").join(disk),
+        number: read("partition")? as u32,
+        start: read("start")?,
+    }))
+}
+
+/// Returns the logical block size of the given disk.
+fn logical_block_size(disk: &Path) -> Result<u64, Error> {
+    let name = disk.file_name().unwrap_or_default();
+    fs::read_to_string(
+        Path::new("/sys/class/block")
+            .join(name)
+            .join("queue/logical_block_size"),
+    )?
+    .trim()
+    .parse()
+    .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[offset .. offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[offset .. offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Returns the GPT encoding of a GUID, whose first three fields are little
+/// endian.
+fn guid_bytes(guid: &Uuid) -> [u8; 16] {
+    let mut bytes = *guid.as_bytes();
+    bytes[0 .. 4].reverse();
+    bytes[4 .. 6].reverse();
+    bytes[6 .. 8].reverse();
+    bytes
+}
+
+/// GPT header along with its partition entries.
+struct GptTable {
+    header: Vec<u8>,
+    entries: Vec<u8>,
+    block_size: u64,
+}
+
+impl GptTable {
+    /// Read the GPT header at the given block, and its entries.
+    fn read(file: &File, lba: u64, block_size: u64) -> Result<Self, Error> {
+        let mut header = vec![0; block_size as usize];
+        file.read_exact_at(&mut header, lba * block_size)?;
+        if &header[.. 8] != GPT_SIGNATURE {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("no GPT header at block {}", lba),
+            ));
+        }
+        let header_size = u32_at(&header, HDR_SIZE) as usize;
+        if header_size < HDR_ENTRIES_CRC + 4
+            || header_size > header.len()
+            || (u32_at(&header, HDR_ENTRY_SIZE) as usize)
+                < ENTRY_NAME + NAME_LEN * 2
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "invalid GPT header",
+            ));
+        }
+
+        let len = u32_at(&header, HDR_NUM_ENTRIES) as usize
+            * u32_at(&header, HDR_ENTRY_SIZE) as usize;
+        let mut entries = vec![0; len];
+        file.read_exact_at(
+            &mut entries,
+            u64_at(&header, HDR_ENTRIES_LBA) * block_size,
+        )?;
+        if crc::crc32::checksum_ieee(&entries)
+            != u32_at(&header, HDR_ENTRIES_CRC)
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "invalid checksum of the GPT entries",
+            ));
+        }
+
+        Ok(Self {
+            header,
+            entries,
+            block_size,
+        })
+    }
+
+    /// Returns the entry of the given partition number.
+    fn entry_mut(&mut self, number: u32) -> Option<&mut [u8]> {
+        let size = u32_at(&self.header, HDR_ENTRY_SIZE) as usize;
+        self.entries
+            .chunks_mut(size)
+            .nth(number.checked_sub(1)? as usize)
+    }
+
+    /// Write back the entries and the header, updating their checksums.
+    fn write(&mut self, file: &File) -> Result<(), Error> {
+        let entries_crc = crc::crc32::checksum_ieee(&self.entries);
+        self.header[HDR_ENTRIES_CRC .. HDR_ENTRIES_CRC + 4]
+            .copy_from_slice(&entries_crc.to_le_bytes());
+        self.header[HDR_CRC .. HDR_CRC + 4].copy_from_slice(&[0; 4]);
+        let header_size = u32_at(&self.header, HDR_SIZE) as usize;
+        let header_crc =
+            crc::crc32::checksum_ieee(&self.header[.. header_size]);
+        self.header[HDR_CRC .. HDR_CRC + 4]
+            .copy_from_slice(&header_crc.to_le_bytes());
+
+        file.write_all_at(
+            &self.entries,
+            u64_at(&self.header, HDR_ENTRIES_LBA) * self.block_size,
+        )?;
+        file.write_all_at(
+            &self.header,
+            u64_at(&self.header, HDR_MY_LBA) * self.block_size,
+        )
+    }
+}
+
+/// Set the type and the name of the given partition in the primary and the
+/// backup GPT of its disk.
+pub fn label_partition(
+    partition: &HostPartition,
+    type_guid: &Uuid,
+    name: &str,
+) -> Result<(), Error> {
+    let block_size = logical_block_size(&partition.disk)?;
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&partition.disk)?;
+
+    let primary = GptTable::read(&file, 1, block_size)?;
+    let backup_lba = u64_at(&primary.header, HDR_ALTERNATE_LBA);
+    let backup = GptTable::read(&file, backup_lba, block_size)?;
+
+    let mut label = [0u8; NAME_LEN * 2];
+    for (i, unit) in name.encode_utf16().take(NAME_LEN).enumerate() {
+        label[i * 2 .. i * 2 + 2].copy_from_slice(&unit.to_le_bytes());
+    }
+
+    // both tables are changed before either is written, so that a table
+    // which does not match the partition leaves the disk untouched
+    let mut tables = [primary, backup];
+    for table in tables.iter_mut() {
+        let entry = table.entry_mut(partition.number).ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("no GPT entry for partition {}", partition.number),
+            )
+        })?;
+        // the entry must be the one of the partition seen by the kernel
+        if u64_at(entry, ENTRY_FIRST_LBA) * block_size / 512 != partition.start
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "GPT entry {} does not match the partition",
+                    partition.number
+                ),
+            ));
+        }
+        entry[ENTRY_TYPE .. ENTRY_TYPE + 16]
+            .copy_from_slice(&guid_bytes(type_guid));
+        entry[ENTRY_NAME .. ENTRY_NAME + NAME_LEN * 2].copy_from_slice(&label);
+    }
+    for table in tables.iter_mut() {
+        table.write(&file)?;
+    }
+    file.sync_all()
+}
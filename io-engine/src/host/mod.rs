@@ -1,2 +1,4 @@
 pub mod blk_device;
+pub mod claims;
+pub mod gpt;
 pub mod resource;
@@ -0,0 +1,713 @@
+//! In-process I/O tests.
+//!
+//! An I/O test drives a block device of the node, a nexus, a replica or any
+//! other bdev, directly from a reactor of the io-engine: it keeps the given
+//! number of I/Os of the given size in flight, following the given pattern,
+//! until the given runtime elapses. This validates the data path of a device
+//! and measures its node-local performance without any initiator, and so
+//! without the overhead of the fabric.
+//!
+//! With verification enabled, every block written is stamped with a header
+//! carrying its LBA, the generation of the write and a crc32c of the rest of
+//! the block, and every block read is checked against its stamp: a block
+//! which is torn, misplaced or stale is reported with its LBA and the test
+//! fails. Patterns which only write read back every block written. Blocks
+//! which were never stamped read as zeroes and are counted, not reported,
+//! so that a verifying read test can follow a verifying write test of the
+//! same device, for example on either side of a rebuild or of a failover.
+//!
+//! Tests run in the background and are referred to by name: their progress
+//! and their results are queried while they run and once they complete.
+
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::{future::join_all, FutureExt};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use spdk_rs::DmaBuf;
+
+use crate::{
+    bdev::device_open,
+    core::{BlockDeviceHandle, Reactors},
+    jsonrpc::{jsonrpc_register, Code, JsonRpcError},
+    sleep::mayastor_sleep,
+};
+
+/// Longest runtime of a test.
+const MAX_RUNTIME: Duration = Duration::from_secs(3600);
+
+/// Most mismatches reported by a test, the following ones are only counted.
+const MAX_MISMATCHES: usize = 64;
+
+/// Magic number starting every block stamped by a test.
+const STAMP_MAGIC: u64 = 0x4d59_4954_5354_414d;
+/// Size of the stamp: magic, LBA, generation and crc32c.
+const STAMP_LEN: usize = 28;
+
+/// I/O pattern of a test.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IoTestPattern {
+    Read,
+    Write,
+    RandRead,
+    RandWrite,
+    /// Random reads and writes, in the proportion given by the read
+    /// percentage of the test.
+    RandRw,
+}
+
+impl IoTestPattern {
+    fn is_random(&self) -> bool {
+        matches!(self, Self::RandRead | Self::RandWrite | Self::RandRw)
+    }
+
+    fn writes(&self) -> bool {
+        !matches!(self, Self::Read | Self::RandRead)
+    }
+}
+
+/// Specification of a test.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IoTestSpec {
+    /// Name of the test.
+    pub name: String,
+    /// Name of the block device tested.
+    pub device: String,
+    pub pattern: IoTestPattern,
+    /// Size of the I/Os in bytes, a multiple of the block size of the device.
+    #[serde(default = "default_io_size")]
+    pub io_size: u64,
+    #[serde(default = "default_queue_depth")]
+    pub queue_depth: u32,
+    #[serde(default = "default_runtime_secs")]
+    pub runtime_secs: u64,
+    /// Percentage of reads of the `rand_rw` pattern.
+    #[serde(default = "default_read_percent")]
+    pub read_percent: u8,
+    /// Stamp every block written and check the stamp of every block read.
+    #[serde(default)]
+    pub verify: bool,
+    /// Core of the reactor the test runs on, the core of the management
+    /// reactor if not given.
+    #[serde(default)]
+    pub core: Option<u32>,
+}
+
+fn default_io_size() -> u64 {
+    4096
+}
+
+fn default_queue_depth() -> u32 {
+    32
+}
+
+fn default_runtime_secs() -> u64 {
+    10
+}
+
+fn default_read_percent() -> u8 {
+    50
+}
+
+/// State of a test.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IoTestState {
+    Running,
+    Completed,
+    Stopped,
+    Failed,
+}
+
+/// Statistics of a test.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct IoTestStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Number of blocks read whose stamp matched.
+    pub verified_blocks: u64,
+    /// Number of blocks read which were never stamped.
+    pub unwritten_blocks: u64,
+    /// Number of blocks read whose stamp did not match.
+    pub mismatched_blocks: u64,
+    /// Sum of the latencies of the I/Os in microseconds.
+    pub total_latency_us: u64,
+    pub max_latency_us: u64,
+}
+
+/// Status of a test.
+#[derive(Debug, Clone, Serialize)]
+pub struct IoTestStatus {
+    pub spec: IoTestSpec,
+    pub state: IoTestState,
+    /// Time the test has been running for, in milliseconds.
+    pub elapsed_ms: u64,
+    pub stats: IoTestStats,
+    pub iops: u64,
+    /// Bandwidth in KiB/s.
+    pub bandwidth_kib: u64,
+    pub avg_latency_us: u64,
+    /// Error which failed the test.
+    pub error: Option<String>,
+    /// First blocks whose stamp did not match.
+    pub mismatches: Vec<BlockMismatch>,
+}
+
+/// A block read whose stamp did not match.
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockMismatch {
+    pub lba: u64,
+    /// Generation of the last write to the block by the test, if any.
+    pub expected_generation: Option<u64>,
+    pub reason: String,
+}
+
+/// A test, shared between the reactor running it and the callers querying
+/// its status.
+struct IoTest {
+    spec: IoTestSpec,
+    started: Instant,
+    stop: AtomicBool,
+    inner: Mutex<IoTestInner>,
+    slots: Mutex<SlotTracker>,
+}
+
+struct IoTestInner {
+    state: IoTestState,
+    finished: Option<Instant>,
+    stats: IoTestStats,
+    error: Option<String>,
+    mismatches: Vec<BlockMismatch>,
+}
+
+/// Tracks the slots of the device which are being accessed and the
+/// generation of the last write to every slot written by the test, so that
+/// the stamps read are checked against the data the test wrote last.
+#[derive(Default)]
+struct SlotTracker {
+    /// Generation of the last write issued.
+    generation: u64,
+    busy: HashSet<u64>,
+    generations: HashMap<u64, u64>,
+}
+
+impl IoTest {
+    fn status(&self) -> IoTestStatus {
+        let inner = self.inner.lock();
+        let elapsed = inner
+            .finished
+            .unwrap_or_else(Instant::now)
+            .saturating_duration_since(self.started);
+        let elapsed_us = elapsed.as_micros().max(1) as u64;
+        let stats = &inner.stats;
+        let ios = stats.reads + stats.writes;
+        let bytes = stats.bytes_read + stats.bytes_written;
+
+        IoTestStatus {
+            spec: self.spec.clone(),
+            state: inner.state,
+            elapsed_ms: elapsed.as_millis() as u64,
+            stats: stats.clone(),
+            iops: ios * 1_000_000 / elapsed_us,
+            bandwidth_kib: bytes * 1_000_000 / elapsed_us / 1024,
+            avg_latency_us: stats
+                .total_latency_us
+                .checked_div(ios)
+                .unwrap_or(0),
+            error: inner.error.clone(),
+            mismatches: inner.mismatches.clone(),
+        }
+    }
+
+    /// Account a completed I/O.
+    fn account(&self, read: bool, bytes: u64, latency: Duration) {
+        let latency_us = latency.as_micros() as u64;
+        let mut inner = self.inner.lock();
+        let stats = &mut inner.stats;
+        if read {
+            stats.reads += 1;
+            stats.bytes_read += bytes;
+        } else {
+            stats.writes += 1;
+            stats.bytes_written += bytes;
+        }
+        stats.total_latency_us += latency_us;
+        stats.max_latency_us = stats.max_latency_us.max(latency_us);
+    }
+
+    fn finish(&self, result: Result<(), String>) {
+        let mut inner = self.inner.lock();
+        inner.finished = Some(Instant::now());
+        inner.state = match &result {
+            Ok(()) if self.stop.load(Ordering::Relaxed) => IoTestState::Stopped,
+            Ok(()) => IoTestState::Completed,
+            Err(_) => IoTestState::Failed,
+        };
+        inner.error = result.err();
+        info!(
+            "I/O test '{}' on '{}' finished: {:?}",
+            self.spec.name, self.spec.device, inner.state
+        );
+    }
+
+    /// Run the test on the current reactor.
+    async fn run(self: Arc<Self>) -> Result<(), String> {
+        let spec = &self.spec;
+        let descriptor = device_open(&spec.device, spec.pattern.writes())
+            .map_err(|e| e.to_string())?;
+        let handle = descriptor.into_handle().map_err(|e| e.to_string())?;
+
+        let device = handle.get_device();
+        let block_len = device.block_len();
+        if spec.io_size == 0 || spec.io_size % block_len != 0 {
+            return Err(format!(
+                "I/O size {} is not a multiple of the block size {}",
+                spec.io_size, block_len
+            ));
+        }
+        let slots = device.size_in_bytes() / spec.io_size;
+        if slots == 0 {
+            return Err("I/O size exceeds the size of the device".to_string());
+        }
+        if spec.verify && spec.queue_depth as u64 > slots {
+            return Err(format!(
+                "the queue depth {} exceeds the {} I/Os of the device, which \
+                verification requires to be distinct",
+                spec.queue_depth, slots
+            ));
+        }
+
+        let deadline =
+            Instant::now() + Duration::from_secs(self.spec.runtime_secs);
+        let workers = (0 .. spec.queue_depth as u64)
+            .map(|i| self.worker(&*handle, i, slots, block_len, deadline));
+        join_all(workers)
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .map(|_| ())
+    }
+
+    /// Keep an I/O in flight until the test ends.
+    async fn worker(
+        &self,
+        hdl: &dyn BlockDeviceHandle,
+        index: u64,
+        slots: u64,
+        block_len: u64,
+        deadline: Instant,
+    ) -> Result<(), String> {
+        let spec = &self.spec;
+        let io_size = spec.io_size;
+        let mut buf = hdl.dma_malloc(io_size).map_err(|e| e.to_string())?;
+        let mut verify_buf = if spec.verify
+            && matches!(
+                spec.pattern,
+                IoTestPattern::Write | IoTestPattern::RandWrite
+            ) {
+            Some(hdl.dma_malloc(io_size).map_err(|e| e.to_string())?)
+        } else {
+            None
+        };
+
+        // sequential workers share the slots of the device, one every queue
+        // depth slots
+        let mut next_slot = index % slots;
+        let mut generation = 0u64;
+
+        while Instant::now() < deadline && !self.stop.load(Ordering::Relaxed) {
+            let read = match spec.pattern {
+                IoTestPattern::Read | IoTestPattern::RandRead => true,
+                IoTestPattern::Write | IoTestPattern::RandWrite => false,
+                IoTestPattern::RandRw => {
+                    rand::thread_rng().gen_range(0 .. 100)
+                        < spec.read_percent as u32
+                }
+            };
+            if spec.pattern.is_random() {
+                next_slot = rand::thread_rng().gen_range(0 .. slots);
+            }
+            let slot = if spec.verify {
+                self.acquire_slot(next_slot, slots)
+            } else {
+                next_slot
+            };
+            let offset = slot * io_size;
+
+            let result = if read {
+                self.read(hdl, &mut buf, slot, block_len).await
+            } else {
+                // every write carries a new generation, so that a write
+                // which is lost is detected by the verification
+                if spec.verify {
+                    self.stamp(&mut buf, slot, block_len);
+                } else {
+                    generation += 1;
+                    buf.fill(((offset / block_len) ^ generation) as u8);
+                }
+                self.write(hdl, &buf, slot).await
+            };
+            let result = match (result, read, verify_buf.as_mut()) {
+                (Ok(()), false, Some(verify_buf)) => {
+                    self.read(hdl, verify_buf, slot, block_len).await
+                }
+                (result, ..) => result,
+            };
+            if spec.verify {
+                self.slots.lock().busy.remove(&slot);
+            }
+            result?;
+
+            if !spec.pattern.is_random() {
+                next_slot = (next_slot + spec.queue_depth as u64) % slots;
+            }
+        }
+        Ok(())
+    }
+
+    /// Mark the given slot, or the next one which is not being accessed, as
+    /// busy, so that the stamps of a slot are not read while it is written.
+    /// The queue depth does not exceed the number of slots, so there is
+    /// always one.
+    fn acquire_slot(&self, slot: u64, slots: u64) -> u64 {
+        let mut tracker = self.slots.lock();
+        let slot = (0 .. slots)
+            .map(|i| (slot + i) % slots)
+            .find(|s| !tracker.busy.contains(s))
+            .expect("all the slots are busy");
+        tracker.busy.insert(slot);
+        slot
+    }
+
+    /// Stamp every block of the buffer for a write to the given slot, with
+    /// the next generation of the test.
+    fn stamp(&self, buf: &mut DmaBuf, slot: u64, block_len: u64) {
+        let generation = {
+            let mut tracker = self.slots.lock();
+            tracker.generation += 1;
+            tracker.generation
+        };
+        let first_lba = slot * self.spec.io_size / block_len;
+        for (i, block) in buf
+            .as_mut_slice()
+            .chunks_mut(block_len as usize)
+            .enumerate()
+        {
+            stamp_block(block, first_lba + i as u64, generation);
+        }
+    }
+
+    async fn write(
+        &self,
+        hdl: &dyn BlockDeviceHandle,
+        buf: &DmaBuf,
+        slot: u64,
+    ) -> Result<(), String> {
+        let io_size = self.spec.io_size;
+        let offset = slot * io_size;
+        let start = Instant::now();
+        hdl.write_at(offset, buf).await.map_err(|e| {
+            format!("write of {} bytes at {} failed: {}", io_size, offset, e)
+        })?;
+        self.account(false, io_size, start.elapsed());
+
+        if self.spec.verify {
+            let generation = stamped_generation(buf.as_slice());
+            self.slots.lock().generations.insert(slot, generation);
+        }
+        Ok(())
+    }
+
+    /// Read the given slot, checking the stamps of its blocks if the test
+    /// verifies the data.
+    async fn read(
+        &self,
+        hdl: &dyn BlockDeviceHandle,
+        buf: &mut DmaBuf,
+        slot: u64,
+        block_len: u64,
+    ) -> Result<(), String> {
+        let io_size = self.spec.io_size;
+        let offset = slot * io_size;
+        let start = Instant::now();
+        hdl.read_at(offset, buf).await.map_err(|e| {
+            format!("read of {} bytes at {} failed: {}", io_size, offset, e)
+        })?;
+        self.account(true, io_size, start.elapsed());
+
+        if !self.spec.verify {
+            return Ok(());
+        }
+
+        let expected = self.slots.lock().generations.get(&slot).copied();
+        let first_lba = offset / block_len;
+        let mut inner = self.inner.lock();
+        let mut first_mismatch = None;
+        for (i, block) in buf.as_slice().chunks(block_len as usize).enumerate()
+        {
+            let lba = first_lba + i as u64;
+            match check_block(block, lba, expected) {
+                Ok(true) => inner.stats.verified_blocks += 1,
+                Ok(false) => inner.stats.unwritten_blocks += 1,
+                Err(reason) => {
+                    error!(
+                        "I/O test '{}': mismatch at LBA {} of '{}': {}",
+                        self.spec.name, lba, self.spec.device, reason
+                    );
+                    inner.stats.mismatched_blocks += 1;
+                    if inner.mismatches.len() < MAX_MISMATCHES {
+                        inner.mismatches.push(BlockMismatch {
+                            lba,
+                            expected_generation: expected,
+                            reason,
+                        });
+                    }
+                    first_mismatch.get_or_insert(lba);
+                }
+            }
+        }
+        match first_mismatch {
+            Some(lba) => Err(format!(
+                "data read at LBA {} of '{}' does not match its stamp",
+                lba, self.spec.device
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Fill the payload of a block, following the stamp, with data derived from
+/// its LBA and generation.
+fn fill_payload(payload: &mut [u8], lba: u64, generation: u64) {
+    let seed = lba.wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ generation;
+    for (i, chunk) in payload.chunks_mut(8).enumerate() {
+        let word = (seed ^ i as u64).to_le_bytes();
+        chunk.copy_from_slice(&word[.. chunk.len()]);
+    }
+}
+
+/// crc32c of a stamped block, over the block without its checksum.
+fn stamp_checksum(block: &[u8]) -> u32 {
+    use crc::crc32::{update, CASTAGNOLI_TABLE};
+    let crc = update(0, &CASTAGNOLI_TABLE, &block[.. STAMP_LEN - 4]);
+    update(crc, &CASTAGNOLI_TABLE, &block[STAMP_LEN ..])
+}
+
+fn stamp_block(block: &mut [u8], lba: u64, generation: u64) {
+    block[0 .. 8].copy_from_slice(&STAMP_MAGIC.to_le_bytes());
+    block[8 .. 16].copy_from_slice(&lba.to_le_bytes());
+    block[16 .. 24].copy_from_slice(&generation.to_le_bytes());
+    fill_payload(&mut block[STAMP_LEN ..], lba, generation);
+    let crc = stamp_checksum(block);
+    block[24 .. STAMP_LEN].copy_from_slice(&crc.to_le_bytes());
+}
+
+fn stamp_field(block: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(block[offset .. offset + 8].try_into().unwrap())
+}
+
+/// Generation of the first block of a stamped buffer.
+fn stamped_generation(buf: &[u8]) -> u64 {
+    stamp_field(buf, 16)
+}
+
+/// Check the stamp of a block read at the given LBA, against the generation
+/// of the last write to it by the test if any. Returns whether the block
+/// carries a stamp, or why the stamp does not match.
+fn check_block(
+    block: &[u8],
+    lba: u64,
+    expected: Option<u64>,
+) -> Result<bool, String> {
+    if block.iter().all(|b| *b == 0) {
+        return match expected {
+            Some(generation) => Err(format!(
+                "the block reads as zeroes, generation {} was written",
+                generation
+            )),
+            None => Ok(false),
+        };
+    }
+    if stamp_field(block, 0) != STAMP_MAGIC {
+        return Err("the block does not carry a stamp".to_string());
+    }
+    let stamped_lba = stamp_field(block, 8);
+    if stamped_lba != lba {
+        return Err(format!(
+            "the block carries the stamp of LBA {}",
+            stamped_lba
+        ));
+    }
+    let crc = u32::from_le_bytes(block[24 .. STAMP_LEN].try_into().unwrap());
+    if crc != stamp_checksum(block) {
+        return Err("the checksum does not match, the block is torn or \
+            corrupt"
+            .to_string());
+    }
+    let generation = stamp_field(block, 16);
+    match expected {
+        Some(expected) if expected != generation => Err(format!(
+            "the block carries generation {} instead of {}",
+            generation, expected
+        )),
+        _ => Ok(true),
+    }
+}
+
+/// Tests which are running or have completed, by name.
+static IO_TESTS: Lazy<Mutex<HashMap<String, Arc<IoTest>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Start a test in the background, replacing the test of the same name if
+/// it has completed.
+pub fn start_io_test(spec: IoTestSpec) -> Result<IoTestStatus, String> {
+    if spec.queue_depth == 0 {
+        return Err("the queue depth must not be 0".to_string());
+    }
+    if spec.runtime_secs == 0 || spec.runtime_secs > MAX_RUNTIME.as_secs() {
+        return Err(format!(
+            "the runtime must be between 1 and {} seconds",
+            MAX_RUNTIME.as_secs()
+        ));
+    }
+    if spec.read_percent > 100 {
+        return Err("the read percentage must not exceed 100".to_string());
+    }
+
+    let reactor = match spec.core {
+        Some(core) => Reactors::get_by_core(core)
+            .ok_or_else(|| format!("no reactor on core {}", core))?,
+        None => Reactors::master(),
+    };
+
+    let mut tests = IO_TESTS.lock();
+    if matches!(
+        tests.get(&spec.name).map(|t| t.status().state),
+        Some(IoTestState::Running)
+    ) {
+        return Err(format!("I/O test '{}' is already running", spec.name));
+    }
+
+    info!("Starting I/O test: {:?}", spec);
+    let test = Arc::new(IoTest {
+        spec: spec.clone(),
+        started: Instant::now(),
+        stop: AtomicBool::new(false),
+        inner: Mutex::new(IoTestInner {
+            state: IoTestState::Running,
+            finished: None,
+            stats: IoTestStats::default(),
+            error: None,
+            mismatches: Vec::new(),
+        }),
+        slots: Mutex::new(SlotTracker::default()),
+    });
+    tests.insert(spec.name, test.clone());
+
+    let status = test.status();
+    reactor.send_future(async move {
+        let result = test.clone().run().await;
+        test.finish(result);
+    });
+    Ok(status)
+}
+
+/// Returns the status of the given test.
+pub fn io_test_status(name: &str) -> Option<IoTestStatus> {
+    IO_TESTS.lock().get(name).map(|t| t.status())
+}
+
+/// Returns the status of all the tests.
+pub fn io_test_list() -> Vec<IoTestStatus> {
+    IO_TESTS.lock().values().map(|t| t.status()).collect()
+}
+
+/// Stop the given test, waiting for its I/Os in flight to complete, and
+/// return its final status.
+pub async fn stop_io_test(name: &str) -> Option<IoTestStatus> {
+    let test = IO_TESTS.lock().get(name).cloned()?;
+    test.stop.store(true, Ordering::Relaxed);
+
+    while test.status().state == IoTestState::Running {
+        mayastor_sleep(Duration::from_millis(10)).await.ok();
+    }
+    Some(test.status())
+}
+
+/// Remove the given test once it is no longer running.
+pub fn remove_io_test(name: &str) -> Result<(), String> {
+    let mut tests = IO_TESTS.lock();
+    match tests.get(name).map(|t| t.status().state) {
+        None => Err(format!("I/O test '{}' not found", name)),
+        Some(IoTestState::Running) => {
+            Err(format!("I/O test '{}' is still running", name))
+        }
+        Some(_) => {
+            tests.remove(name);
+            Ok(())
+        }
+    }
+}
+
+/// Arguments of the json-rpc methods on a single test.
+#[derive(Debug, Deserialize)]
+struct IoTestArgs {
+    /// Name of the test.
+    name: String,
+}
+
+fn not_found(name: &str) -> JsonRpcError {
+    JsonRpcError::new(Code::NotFound, format!("I/O test '{}' not found", name))
+}
+
+/// Register the I/O test json-rpc methods.
+pub(crate) fn register_rpc_methods() {
+    jsonrpc_register("io_test_start", |spec: IoTestSpec| {
+        async move {
+            start_io_test(spec)
+                .map_err(|e| JsonRpcError::new(Code::InvalidParams, e))
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("io_test_status", |args: IoTestArgs| {
+        async move {
+            io_test_status(&args.name).ok_or_else(|| not_found(&args.name))
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register::<(), _, _, JsonRpcError>("io_test_list", |_| {
+        async move { Ok(io_test_list()) }.boxed_local()
+    });
+
+    jsonrpc_register("io_test_stop", |args: IoTestArgs| {
+        async move {
+            stop_io_test(&args.name)
+                .await
+                .ok_or_else(|| not_found(&args.name))
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("io_test_remove", |args: IoTestArgs| {
+        async move {
+            remove_io_test(&args.name)
+                .map_err(|e| JsonRpcError::new(Code::InvalidParams, e))
+        }
+        .boxed_local()
+    });
+}
@@ -0,0 +1,391 @@
+//! Labels of the pools, replicas and nexuses.
+//!
+//! Pools, replicas and nexuses can be given arbitrary key/value labels, so
+//! that the control plane and scripts can keep the owner, tenant or purpose
+//! of a resource along with it, rather than in a mapping of their own. The
+//! resources of a kind can then be listed by label, with a selector in the
+//! syntax of the equality based selectors of Kubernetes: `key=value`,
+//! `key!=value`, `key` and `!key` requirements separated by commas, which
+//! must all be met.
+//!
+//! The labels of a replica are stored on disk with the replica, the labels
+//! of a nexus are recorded with its definition in the persistent store, and
+//! the labels of a pool are recorded in the persistent store on their own,
+//! or only kept until the io-engine restarts if there is no store.
+//!
+//! The `openebs.io/tenant` label tags a resource into a tenant namespace,
+//! whose quotas are checked as the label is set, see the tenant module.
+
+use std::{collections::BTreeMap, convert::TryFrom, pin::Pin, str::FromStr};
+
+use futures::FutureExt;
+
+use crate::{
+    bdev::nexus::{nexus_iter, nexus_lookup, nexus_lookup_uuid_mut},
+    core::{Share, UntypedBdev, VerboseError},
+    jsonrpc::{jsonrpc_register, Code, JsonRpcError, RpcErrorCode},
+    lvs::{Error as LvsError, Lvol, Lvs},
+    tenant::{check_quota, tenant_of, TenantUsage},
+};
+
+/// Labels of a resource.
+pub type Labels = BTreeMap<String, String>;
+
+/// Maximum number of labels of a resource.
+const MAX_LABELS: usize = 64;
+
+/// Maximum length of the name of a label key, and of a label value.
+const MAX_NAME_LEN: usize = 63;
+
+/// Maximum length of the prefix of a label key.
+const MAX_PREFIX_LEN: usize = 253;
+
+/// Returns true if the given name is a valid label name or value: alphanumeric
+/// characters, with dashes, underscores and dots in between.
+fn valid_name(name: &str) -> bool {
+    name.len() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name.ends_with(|c: char| c.is_ascii_alphanumeric())
+}
+
+/// Returns true if the given key is a valid label key: a name, with an
+/// optional DNS subdomain prefix.
+fn valid_key(key: &str) -> bool {
+    match key.split_once('/') {
+        Some((prefix, name)) => {
+            !prefix.is_empty()
+                && prefix.len() <= MAX_PREFIX_LEN
+                && prefix.split('.').all(|p| {
+                    !p.is_empty()
+                        && p.chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+                })
+                && !name.is_empty()
+                && valid_name(name)
+        }
+        None => !key.is_empty() && valid_name(key),
+    }
+}
+
+/// Check that the given labels can be set on a resource.
+pub fn validate_labels(labels: &Labels) -> Result<(), String> {
+    if labels.len() > MAX_LABELS {
+        return Err(format!("more than {} labels", MAX_LABELS));
+    }
+    for (key, value) in labels {
+        if !valid_key(key) {
+            return Err(format!("invalid label key '{}'", key));
+        }
+        if !value.is_empty() && !valid_name(value) {
+            return Err(format!("invalid value '{}' of label {}", value, key));
+        }
+    }
+    Ok(())
+}
+
+/// Requirement of a label selector.
+#[derive(Debug, Clone, PartialEq)]
+enum Requirement {
+    Equals(String, String),
+    NotEquals(String, String),
+    Exists(String),
+    NotExists(String),
+}
+
+impl Requirement {
+    fn matches(&self, labels: &Labels) -> bool {
+        match self {
+            Requirement::Equals(key, value) => labels.get(key) == Some(value),
+            Requirement::NotEquals(key, value) => {
+                labels.get(key) != Some(value)
+            }
+            Requirement::Exists(key) => labels.contains_key(key),
+            Requirement::NotExists(key) => !labels.contains_key(key),
+        }
+    }
+}
+
+/// Label selector, met by the labels meeting all of its requirements.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LabelSelector(Vec<Requirement>);
+
+impl LabelSelector {
+    /// Returns true if the given labels meet the selector.
+    pub fn matches(&self, labels: &Labels) -> bool {
+        self.0.iter().all(|r| r.matches(labels))
+    }
+}
+
+impl FromStr for LabelSelector {
+    type Err = String;
+
+    fn from_str(selector: &str) -> Result<Self, Self::Err> {
+        let key = |key: &str| {
+            let key = key.trim();
+            if valid_key(key) {
+                Ok(key.to_string())
+            } else {
+                Err(format!("invalid label key '{}' in selector", key))
+            }
+        };
+        let value = |value: &str| {
+            let value = value.trim();
+            if value.is_empty() || valid_name(value) {
+                Ok(value.to_string())
+            } else {
+                Err(format!("invalid label value '{}' in selector", value))
+            }
+        };
+
+        selector
+            .split(',')
+            .filter(|r| !r.trim().is_empty())
+            .map(|r| {
+                if let Some((k, v)) = r.split_once("!=") {
+                    Ok(Requirement::NotEquals(key(k)?, value(v)?))
+                } else if let Some((k, v)) = r.split_once('=') {
+                    let v = v.strip_prefix('=').unwrap_or(v);
+                    Ok(Requirement::Equals(key(k)?, value(v)?))
+                } else if let Some(k) = r.trim().strip_prefix('!') {
+                    Ok(Requirement::NotExists(key(k)?))
+                } else {
+                    Ok(Requirement::Exists(key(r)?))
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(LabelSelector)
+    }
+}
+
+/// Apply the given changes to the labels: a `None` value removes the label.
+pub fn merge_labels(
+    labels: &Labels,
+    changes: &BTreeMap<String, Option<String>>,
+) -> Labels {
+    let mut merged = labels.clone();
+    for (key, value) in changes {
+        match value {
+            Some(value) => merged.insert(key.clone(), value.clone()),
+            None => merged.remove(key),
+        };
+    }
+    merged
+}
+
+/// Kind of a labeled resource.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ResourceKind {
+    Pool,
+    Replica,
+    Nexus,
+}
+
+/// Arguments of the `labels_set` json-rpc method.
+#[derive(Deserialize)]
+struct LabelsSetArgs {
+    kind: ResourceKind,
+    /// Name, alias or uuid of the resource.
+    name: String,
+    /// Labels to set, a null value removes the label.
+    labels: BTreeMap<String, Option<String>>,
+}
+
+/// Arguments of the `labels_list` json-rpc method.
+#[derive(Deserialize)]
+struct LabelsListArgs {
+    kind: ResourceKind,
+    /// Selector the labels of the resources listed must meet, all resources
+    /// are listed if not given.
+    selector: Option<String>,
+}
+
+/// Labels of a resource.
+#[derive(Serialize)]
+struct ResourceLabels {
+    kind: ResourceKind,
+    name: String,
+    uuid: String,
+    labels: Labels,
+}
+
+/// Look up a replica by its name, alias or uuid.
+fn replica_lookup(name: &str) -> Option<Lvol> {
+    UntypedBdev::lookup_by_name(name)
+        .or_else(|| UntypedBdev::lookup_by_uuid_str(name))
+        .and_then(|bdev| Lvol::try_from(bdev).ok())
+}
+
+fn lvs_error(e: LvsError) -> JsonRpcError {
+    JsonRpcError {
+        code: match e {
+            LvsError::Invalid {
+                ..
+            } => Code::InvalidParams,
+            _ => Code::InternalError,
+        },
+        message: e.to_string(),
+    }
+}
+
+fn not_found(kind: ResourceKind, name: &str) -> JsonRpcError {
+    JsonRpcError {
+        code: Code::NotFound,
+        message: format!("{:?} {} not found", kind, name).to_lowercase(),
+    }
+}
+
+/// Check that a resource of the given usage can be tagged into the tenant of
+/// the new labels, if it changes.
+async fn check_tenant(
+    current: &Labels,
+    labels: &Labels,
+    usage: TenantUsage,
+) -> Result<(), JsonRpcError> {
+    match tenant_of(labels) {
+        Some(tenant) if tenant_of(current) != Some(tenant) => {
+            check_quota(tenant, &usage)
+                .await
+                .map_err(|message| JsonRpcError {
+                    code: Code::InvalidParams,
+                    message,
+                })
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn labels_set(
+    args: LabelsSetArgs,
+) -> Result<ResourceLabels, JsonRpcError> {
+    match args.kind {
+        ResourceKind::Pool => {
+            let lvs = Lvs::lookup(&args.name)
+                .or_else(|| Lvs::lookup_by_uuid(&args.name))
+                .ok_or_else(|| not_found(args.kind, &args.name))?;
+            let labels = merge_labels(&lvs.labels().await, &args.labels);
+            lvs.set_labels(&labels).await.map_err(lvs_error)?;
+            Ok(ResourceLabels {
+                kind: args.kind,
+                name: lvs.name().to_string(),
+                uuid: lvs.uuid(),
+                labels,
+            })
+        }
+        ResourceKind::Replica => {
+            let mut lvol = replica_lookup(&args.name)
+                .ok_or_else(|| not_found(args.kind, &args.name))?;
+            let current = lvol.labels().await;
+            let labels = merge_labels(&current, &args.labels);
+            check_tenant(
+                &current,
+                &labels,
+                TenantUsage::replica(lvol.size(), lvol.shared().is_some()),
+            )
+            .await?;
+            Pin::new(&mut lvol)
+                .set_labels(&labels)
+                .await
+                .map_err(lvs_error)?;
+            Ok(ResourceLabels {
+                kind: args.kind,
+                name: lvol.name(),
+                uuid: lvol.uuid(),
+                labels,
+            })
+        }
+        ResourceKind::Nexus => {
+            let nexus = nexus_lookup(&args.name)
+                .map(|n| n.uuid().to_string())
+                .or_else(|| Some(args.name.clone()))
+                .and_then(|uuid| nexus_lookup_uuid_mut(&uuid))
+                .ok_or_else(|| not_found(args.kind, &args.name))?;
+            let current = nexus.labels();
+            let labels = merge_labels(&current, &args.labels);
+            if nexus.shared().is_some() {
+                check_tenant(&current, &labels, TenantUsage::shared_target())
+                    .await?;
+            }
+            nexus.set_labels(labels.clone()).await.map_err(|e| {
+                JsonRpcError {
+                    code: e.rpc_error_code(),
+                    message: e.verbose(),
+                }
+            })?;
+            Ok(ResourceLabels {
+                kind: args.kind,
+                name: nexus.name.clone(),
+                uuid: nexus.uuid().to_string(),
+                labels,
+            })
+        }
+    }
+}
+
+async fn labels_list(
+    args: LabelsListArgs,
+) -> Result<Vec<ResourceLabels>, JsonRpcError> {
+    let selector = args
+        .selector
+        .as_deref()
+        .unwrap_or_default()
+        .parse::<LabelSelector>()
+        .map_err(|message| JsonRpcError {
+            code: Code::InvalidParams,
+            message,
+        })?;
+
+    let mut resources = Vec::new();
+    match args.kind {
+        ResourceKind::Pool => {
+            for lvs in Lvs::iter() {
+                resources.push(ResourceLabels {
+                    kind: args.kind,
+                    name: lvs.name().to_string(),
+                    uuid: lvs.uuid(),
+                    labels: lvs.labels().await,
+                });
+            }
+        }
+        ResourceKind::Replica => {
+            let lvols = Lvs::iter()
+                .filter_map(|lvs| lvs.lvols())
+                .flatten()
+                .filter(|l| !l.is_snapshot())
+                .collect::<Vec<_>>();
+            for lvol in lvols {
+                resources.push(ResourceLabels {
+                    kind: args.kind,
+                    name: lvol.name(),
+                    uuid: lvol.uuid(),
+                    labels: lvol.labels().await,
+                });
+            }
+        }
+        ResourceKind::Nexus => {
+            resources.extend(nexus_iter().map(|n| ResourceLabels {
+                kind: args.kind,
+                name: n.name.clone(),
+                uuid: n.uuid().to_string(),
+                labels: n.labels(),
+            }));
+        }
+    }
+    resources.retain(|r| selector.matches(&r.labels));
+    resources.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(resources)
+}
+
+/// Register the labels json-rpc methods.
+pub(crate) fn register_rpc_methods() {
+    jsonrpc_register("labels_set", |args: LabelsSetArgs| {
+        async move { labels_set(args).await }.boxed_local()
+    });
+
+    jsonrpc_register("labels_list", |args: LabelsListArgs| {
+        async move { labels_list(args).await }.boxed_local()
+    });
+}
@@ -12,23 +12,34 @@ extern crate spdk_rs;
 
 #[macro_use]
 pub mod core;
+pub mod backup;
 pub mod bdev;
 pub mod delay;
+pub mod drain;
+pub mod events;
 pub use spdk_rs::ffihelper;
 pub mod bdev_api;
 pub mod constants;
 pub mod grpc;
+pub mod handoff;
+pub mod health;
 pub mod host;
+pub mod io_test;
 pub mod jsonrpc;
+pub mod labels;
 pub mod logger;
 pub mod lvs;
+pub mod metrics;
 pub mod persistent_store;
 pub mod pool_backend;
 pub mod rebuild;
+pub mod reconcile;
+pub mod rename;
 mod sleep;
 pub mod store;
 pub mod subsys;
 pub mod target;
+pub mod tenant;
 
 /// TODO
 #[macro_export]
@@ -44,4 +55,25 @@ pub extern "C" fn cps_init() {
     subsys::register_subsystem();
     bdev::nexus::register_module();
     bdev::null_ng::register();
+    bdev::raid::register_rpc_methods();
+    backup::register_rpc_methods();
+    bdev_api::register_rpc_methods();
+    core::bdev_histogram::register_rpc_methods();
+    core::descriptor::register_rpc_methods();
+    core::memory_usage::register_rpc_methods();
+    core::reactor_profile::register_rpc_methods();
+    drain::register_rpc_methods();
+    events::register_rpc_methods();
+    grpc::audit::register_rpc_methods();
+    health::register_rpc_methods();
+    host::blk_device::register_rpc_methods();
+    io_test::register_rpc_methods();
+    labels::register_rpc_methods();
+    logger::register_rpc_methods();
+    lvs::register_rpc_methods();
+    persistent_store::register_rpc_methods();
+    rebuild::register_rpc_methods();
+    reconcile::register_rpc_methods();
+    rename::register_rpc_methods();
+    tenant::register_rpc_methods();
 }
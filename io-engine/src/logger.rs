@@ -1,21 +1,47 @@
-use std::{ffi::CStr, fmt::Write, os::raw::c_char, path::Path, str::FromStr};
+use std::{
+    collections::VecDeque,
+    ffi::CStr,
+    fmt::Write,
+    os::raw::c_char,
+    path::Path,
+    str::FromStr,
+};
 
 use ansi_term::{Colour, Style};
-
-use tracing_core::{event::Event, Level, Metadata};
+use once_cell::sync::{Lazy, OnceCell};
+use opentelemetry::{
+    global,
+    sdk::{propagation::TraceContextPropagator, trace, Resource},
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use serde::Deserialize;
+
+use futures::FutureExt;
+use serde_json::{json, Map, Value};
+use tracing_core::{
+    event::Event,
+    field::{Field, Visit},
+    Level,
+    Metadata,
+};
 use tracing_log::{LogTracer, NormalizeEvent};
+use tracing_opentelemetry::OpenTelemetryLayer;
 use tracing_subscriber::{
     fmt::{
         format::{FmtSpan, FormatEvent, FormatFields},
         FmtContext,
         FormattedFields,
     },
+    layer::{Context, Layer, SubscriberExt},
     registry::LookupSpan,
     EnvFilter,
 };
 
 use spdk_rs::libspdk::{spdk_log_get_print_level, spdk_log_level};
 
+use crate::jsonrpc::{jsonrpc_register, Code, JsonRpcError};
+
 fn from_spdk_level(level: spdk_log_level) -> log::Level {
     match level {
         spdk_rs::libspdk::SPDK_LOG_ERROR => log::Level::Error,
@@ -238,6 +264,7 @@ impl std::fmt::Display for Location<'_> {
 pub enum LogStyle {
     Default,
     Compact,
+    Json,
 }
 
 // Custom struct used to format trace events.
@@ -268,6 +295,7 @@ impl FromStr for LogFormat {
             match p {
                 "default" => r.style = LogStyle::Default,
                 "compact" => r.style = LogStyle::Compact,
+                "json" => r.style = LogStyle::Json,
                 "color" => r.ansi = true,
                 "nocolor" => r.ansi = false,
                 "nodate" => r.no_date = true,
@@ -294,6 +322,7 @@ where
         match self.style {
             LogStyle::Default => self.default_style(ctx, w, evt),
             LogStyle::Compact => self.compact_style(ctx, w, evt),
+            LogStyle::Json => self.json_style(ctx, w, evt),
         }
     }
 }
@@ -380,15 +409,289 @@ impl LogFormat {
 
         writeln!(writer)
     }
+
+    /// Formats an event as a single line JSON object.
+    fn json_style<S, N>(
+        &self,
+        context: &FmtContext<'_, S, N>,
+        writer: &mut dyn Write,
+        event: &Event<'_>,
+    ) -> std::fmt::Result
+    where
+        S: tracing_core::subscriber::Subscriber + for<'s> LookupSpan<'s>,
+        N: for<'w> FormatFields<'w> + 'static,
+    {
+        let normalized = event.normalized_metadata();
+        let meta = normalized.as_ref().unwrap_or_else(|| event.metadata());
+
+        let mut fields = Map::new();
+        event.record(&mut JsonVisitor(&mut fields));
+
+        let spans = event
+            .parent()
+            .and_then(|id| context.span(id))
+            .or_else(|| context.lookup_current())
+            .into_iter()
+            .flat_map(|span| span.scope().from_root())
+            .map(|span| {
+                let extensions = span.extensions();
+                let fields = extensions
+                    .get::<FormattedFields<N>>()
+                    .map(|f| f.to_string())
+                    .unwrap_or_default();
+                json!({
+                    "name": span.metadata().name(),
+                    "fields": fields,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let line = json!({
+            "timestamp": chrono::Local::now().to_rfc3339(),
+            "level": meta.level().to_string(),
+            "target": meta.target(),
+            "location": Location::new(meta).to_string(),
+            "spans": spans,
+            "fields": fields,
+        });
+
+        writeln!(writer, "{}", line)
+    }
+}
+
+/// Records the fields of an event as JSON values.
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl JsonVisitor<'_> {
+    fn insert(&mut self, field: &Field, value: Value) {
+        // skip the fields added by LogTracer, they are part of the normalized
+        // metadata already
+        if !field.name().starts_with("log.") {
+            self.0.insert(field.name().to_string(), value);
+        }
+    }
+}
+
+impl Visit for JsonVisitor<'_> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.insert(field, format!("{:?}", value).into());
+    }
+}
+
+/// Number of events kept by the trace ring.
+const TRACE_RING_SIZE: usize = 256;
+
+static TRACE_RING: Lazy<parking_lot::Mutex<VecDeque<String>>> =
+    Lazy::new(|| {
+        parking_lot::Mutex::new(VecDeque::with_capacity(TRACE_RING_SIZE))
+    });
+
+/// Layer keeping the most recent events in memory, so that they can be
+/// included in crash reports.
+struct TraceRing;
+
+/// Records the fields of an event as a single line of text.
+struct LineVisitor<'a>(&'a mut String);
+
+impl Visit for LineVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, " {:?}", value);
+        } else if !field.name().starts_with("log.") {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+impl<S> Layer<S> for TraceRing
+where
+    S: tracing_core::Subscriber,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let normalized = event.normalized_metadata();
+        let meta = normalized.as_ref().unwrap_or_else(|| event.metadata());
+
+        let mut line = format!(
+            "{} {} {} {}:",
+            chrono::Local::now().format("%FT%T%.6f"),
+            meta.level(),
+            meta.target(),
+            Location::new(meta),
+        );
+        event.record(&mut LineVisitor(&mut line));
+
+        let mut ring = TRACE_RING.lock();
+        if ring.len() == TRACE_RING_SIZE {
+            ring.pop_front();
+        }
+        ring.push_back(line);
+    }
+}
+
+/// Returns the most recent events, oldest first. As this is meant to be
+/// called when things went wrong already, it gives up rather than waiting for
+/// the ring to be unlocked.
+pub fn recent_events() -> Vec<String> {
+    TRACE_RING
+        .try_lock()
+        .map(|ring| ring.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// Filter of the global subscriber, which can be replaced at runtime.
+struct LogFilter {
+    /// Directives of the current filter.
+    directives: String,
+    /// Replaces the filter of the global subscriber.
+    reload: Box<dyn Fn(EnvFilter) -> Result<(), String> + Send + Sync>,
+}
+
+static LOG_FILTER: OnceCell<parking_lot::Mutex<LogFilter>> = OnceCell::new();
+
+/// Returns the directives of the current log filter.
+pub fn log_filter() -> Option<String> {
+    LOG_FILTER.get().map(|f| f.lock().directives.clone())
+}
+
+/// Replace the log filter with the given directives, using the same syntax as
+/// `RUST_LOG`, i.e `info,io_engine::bdev::nexus::nexus_channel=trace`.
+/// Directives may also be restricted to the spans of a single object, for
+/// example `info,[child_open{nexus=nexus-1}]=trace`.
+/// Returns the directives of the filter being replaced.
+pub fn set_log_filter(directives: &str) -> Result<String, JsonRpcError> {
+    let filter = EnvFilter::try_new(directives).map_err(|e| {
+        JsonRpcError::new(
+            Code::InvalidParams,
+            format!("invalid log filter '{}': {}", directives, e),
+        )
+    })?;
+
+    let mut current = LOG_FILTER
+        .get()
+        .ok_or_else(|| {
+            JsonRpcError::new(Code::InternalError, "logger not initialised")
+        })?
+        .lock();
+
+    (current.reload)(filter)
+        .map_err(|e| JsonRpcError::new(Code::InternalError, e))?;
+
+    info!(
+        "log filter changed from '{}' to '{}'",
+        current.directives, directives
+    );
+    Ok(std::mem::replace(
+        &mut current.directives,
+        directives.to_string(),
+    ))
+}
+
+/// Arguments of the `set_log_level` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct SetLogLevelArgs {
+    /// New filter directives.
+    filter: String,
+}
+
+/// Register the logger json-rpc methods.
+pub(crate) fn register_rpc_methods() {
+    jsonrpc_register::<(), _, _, JsonRpcError>("get_log_level", |_| {
+        async move { Ok(json!({ "filter": log_filter() })) }.boxed_local()
+    });
+
+    jsonrpc_register::<SetLogLevelArgs, _, _, JsonRpcError>(
+        "set_log_level",
+        |args| {
+            async move {
+                let previous = set_log_filter(&args.filter)?;
+                Ok(json!({
+                    "filter": args.filter,
+                    "previous": previous,
+                }))
+            }
+            .boxed_local()
+        },
+    );
+}
+
+/// Runtime driving the export of trace spans to the OTLP collector. It is
+/// separate from the main tokio runtime as it must be up before the
+/// environment (and thus the reactors) is initialised.
+static OTLP_RUNTIME: OnceCell<tokio::runtime::Runtime> = OnceCell::new();
+
+/// Create a layer exporting the spans to an OpenTelemetry collector, via OTLP
+/// over gRPC. The W3C trace context propagator is installed as well, so that
+/// the trace context sent by the control plane along with gRPC requests
+/// can be picked up.
+fn otlp_layer<S>(endpoint: &str) -> Option<OpenTelemetryLayer<S, trace::Tracer>>
+where
+    S: tracing_core::Subscriber + for<'s> LookupSpan<'s>,
+{
+    let runtime = OTLP_RUNTIME.get_or_try_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .worker_threads(1)
+            .thread_name("otlp")
+            .build()
+    });
+
+    let runtime = match runtime {
+        Ok(runtime) => runtime,
+        Err(error) => {
+            eprintln!("failed to create the OTLP runtime: {}", error);
+            return None;
+        }
+    };
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let _guard = runtime.enter();
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(trace::config().with_resource(Resource::new(vec![
+            KeyValue::new("service.name", "io-engine"),
+        ])))
+        .install_batch(opentelemetry::runtime::Tokio);
+
+    match tracer {
+        Ok(tracer) => Some(tracing_opentelemetry::layer().with_tracer(tracer)),
+        Err(error) => {
+            eprintln!("failed to install the OTLP trace exporter: {}", error);
+            None
+        }
+    }
 }
 
 /// This function configures the logging format. The loglevel is also processed
 /// here i.e `RUST_LOG=io_engine=TRACE` will print all trace!() and higher
 /// messages to the console.
 ///
+/// If an OTLP endpoint is given, the spans are also exported to it.
+///
 /// We might want to suppress certain messages, as some of them are redundant,
 /// in particular, the NOTICE messages as such, they are mapped to debug.
-pub fn init_ex(level: &str, format: LogFormat) {
+pub fn init_ex(level: &str, format: LogFormat, otlp_endpoint: Option<&str>) {
     // Set up a "logger" that simply translates any "log" messages it receives
     // to trace events. This is for our custom spdk log messages, but also
     // for any other third party crates still using the logging facade.
@@ -399,15 +702,37 @@ pub fn init_ex(level: &str, format: LogFormat) {
         .with_span_events(FmtSpan::FULL)
         .event_format(format);
 
-    let subscriber = match EnvFilter::try_from_default_env() {
-        Ok(filter) => builder.with_env_filter(filter).finish(),
-        Err(_) => builder.with_env_filter(level).finish(),
-    };
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(level));
+    let directives = filter.to_string();
+
+    let builder = builder.with_env_filter(filter).with_filter_reloading();
+    let handle = builder.reload_handle();
+    LOG_FILTER.get_or_init(|| {
+        parking_lot::Mutex::new(LogFilter {
+            directives,
+            reload: Box::new(move |filter| {
+                handle.reload(filter).map_err(|e| e.to_string())
+            }),
+        })
+    });
+
+    let subscriber = builder
+        .finish()
+        .with(TraceRing)
+        .with(otlp_endpoint.and_then(otlp_layer));
 
     tracing::subscriber::set_global_default(subscriber)
         .expect("failed to set default subscriber");
 }
 
 pub fn init(level: &str) {
-    init_ex(level, Default::default())
+    init_ex(level, Default::default(), None)
+}
+
+/// Flush the spans which have not been exported yet and stop the exporter.
+pub fn fini() {
+    if OTLP_RUNTIME.get().is_some() {
+        global::shutdown_tracer_provider();
+    }
 }
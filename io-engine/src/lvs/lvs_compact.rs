@@ -0,0 +1,702 @@
+//! Compaction of pools.
+//!
+//! Thin replicas allocate the clusters of the blobstore as they are first
+//! written, so over time the clusters of the replicas of a pool end up
+//! interleaved, with the free clusters scattered in between, which degrades
+//! sequential performance. The blobstore always allocates the lowest free
+//! cluster, so copying a replica to a new replica packs its data into the
+//! holes at the start of the pool and returns its former clusters to the
+//! free space, which becomes contiguous as the replicas are relocated.
+//!
+//! A compaction job relocates the idle thin replicas of a pool one at a
+//! time: the data is copied to a temporary replica, then the replica is
+//! destroyed, recreated with the same name and uuid, and the data is copied
+//! back. The replica is claimed while it is copied, so it cannot be shared
+//! or added to a nexus, and replicas which are in use or are written to
+//! during the copy are skipped. The temporary replica records the uuid of
+//! the replica it holds the data of, and whether that replica has been
+//! destroyed, so that the next job on the pool completes or rolls back a
+//! relocation interrupted by a crash.
+//!
+//! A job can be restricted to a daily window, in UTC hours, in which case it
+//! waits for the window before relocating each replica, as it does while the
+//! jobs of its pool are paused for maintenance. An aborted job stops
+//! at the end of the current chunk, or once the replica being relocated has
+//! been restored if it has already been destroyed.
+
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    pin::Pin,
+    time::Duration,
+};
+
+use chrono::Timelike;
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+
+use super::{
+    lvs_maintenance::jobs_paused,
+    Error,
+    Lvol,
+    Lvs,
+    PropName,
+    PropValue,
+};
+use crate::{
+    core::{
+        CoreError,
+        Protocol,
+        Reactors,
+        Share,
+        UntypedBdev,
+        UntypedBdevHandle,
+    },
+    jsonrpc::{jsonrpc_register, Code, RpcErrorCode},
+    sleep::mayastor_sleep,
+};
+
+/// Suffix of the name of the temporary replica of a relocation.
+const COMPACT_SUFFIX: &str = ".compact";
+
+/// Suffix of the source recorded on the temporary replica once the replica
+/// it holds the data of has been destroyed.
+const RESTORE_MARKER: &str = ":restore";
+
+/// Size of the chunks the data is copied in.
+const CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Interval at which a job waiting for its window checks the time.
+const WINDOW_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Compaction jobs, by pool.
+static JOBS: Lazy<Mutex<HashMap<String, CompactJob>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Pools whose compaction has been aborted.
+static ABORTED: Lazy<Mutex<HashSet<String>>> =
+    Lazy::new(|| Mutex::new(HashSet::new()));
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)), context(suffix(false)))]
+pub enum CompactError {
+    #[snafu(display("Pool {} not found", name))]
+    PoolNotFound { name: String },
+    #[snafu(display(
+        "Invalid compaction window {}-{}: hours must differ and be below 24",
+        start_hour,
+        end_hour
+    ))]
+    InvalidWindow { start_hour: u32, end_hour: u32 },
+    #[snafu(display("Compaction of pool {} is already running", pool))]
+    JobRunning { pool: String },
+    #[snafu(display("No compaction of pool {} is running", pool))]
+    JobNotFound { pool: String },
+    #[snafu(display("Compaction of pool {} has been aborted", pool))]
+    JobAborted { pool: String },
+    #[snafu(display("Failed to relocate replica {}: {}", name, source))]
+    Relocate { source: Error, name: String },
+    #[snafu(display("I/O failed on {}: {}", name, source))]
+    ReplicaIo { source: CoreError, name: String },
+}
+
+impl RpcErrorCode for CompactError {
+    fn rpc_error_code(&self) -> Code {
+        match self {
+            CompactError::PoolNotFound {
+                ..
+            }
+            | CompactError::JobNotFound {
+                ..
+            } => Code::NotFound,
+            CompactError::JobRunning {
+                ..
+            } => Code::AlreadyExists,
+            CompactError::InvalidWindow {
+                ..
+            } => Code::InvalidParams,
+            _ => Code::InternalError,
+        }
+    }
+}
+
+/// State of a compaction job.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CompactState {
+    /// Waiting for its window, or for the jobs of its pool to resume.
+    Waiting,
+    Running,
+    Completed,
+    Failed,
+    Aborted,
+}
+
+/// Daily window of a compaction job, in UTC hours. The window wraps around
+/// midnight if it ends before it starts.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct CompactWindow {
+    pub start_hour: u32,
+    pub end_hour: u32,
+}
+
+impl CompactWindow {
+    /// Returns true if the given hour is within the window.
+    fn contains(&self, hour: u32) -> bool {
+        if self.start_hour < self.end_hour {
+            hour >= self.start_hour && hour < self.end_hour
+        } else {
+            hour >= self.start_hour || hour < self.end_hour
+        }
+    }
+}
+
+/// Compaction job of a pool, along with its progress.
+#[derive(Serialize, Debug, Clone)]
+pub struct CompactJob {
+    pub pool: String,
+    pub state: CompactState,
+    pub window: Option<CompactWindow>,
+    /// Number of thin replicas of the pool when the job started.
+    pub replicas: u64,
+    /// Replicas relocated so far.
+    pub relocated: Vec<String>,
+    /// Replicas which were in use, written to during their copy, or which
+    /// the pool did not have the free space to relocate.
+    pub skipped: Vec<String>,
+    /// Replica being relocated.
+    pub current: Option<String>,
+    /// Number of bytes to copy, and copied so far. The data of each replica
+    /// is copied twice.
+    pub total_bytes: u64,
+    pub done_bytes: u64,
+    /// Free space of the pool when the job started, and now.
+    pub free_bytes_start: u64,
+    pub free_bytes: u64,
+    pub error: Option<String>,
+}
+
+impl CompactJob {
+    fn new(lvs: &Lvs, window: Option<CompactWindow>) -> Self {
+        Self {
+            pool: lvs.name().to_string(),
+            state: CompactState::Running,
+            window,
+            replicas: 0,
+            relocated: Vec::new(),
+            skipped: Vec::new(),
+            current: None,
+            total_bytes: 0,
+            done_bytes: 0,
+            free_bytes_start: lvs.available(),
+            free_bytes: lvs.available(),
+            error: None,
+        }
+    }
+
+    /// Make the progress of the job visible.
+    fn publish(&self) {
+        JOBS.lock().insert(self.pool.clone(), self.clone());
+    }
+
+    /// Fail if the job has been aborted.
+    fn check_aborted(&self) -> Result<(), CompactError> {
+        if ABORTED.lock().contains(&self.pool) {
+            return JobAborted {
+                pool: self.pool.clone(),
+            }
+            .fail();
+        }
+        Ok(())
+    }
+
+    /// Wait until the current time is within the window of the job, and
+    /// the jobs of its pool are not paused for maintenance.
+    async fn wait_for_window(&mut self) -> Result<(), CompactError> {
+        let window = self.window;
+        let in_window =
+            || window.map_or(true, |w| w.contains(chrono::Utc::now().hour()));
+        while !in_window() || jobs_paused(&self.pool) {
+            if self.state != CompactState::Waiting {
+                self.state = CompactState::Waiting;
+                self.publish();
+            }
+            self.check_aborted()?;
+            let _ = mayastor_sleep(WINDOW_POLL_INTERVAL).await;
+        }
+        if self.state == CompactState::Waiting {
+            self.state = CompactState::Running;
+            self.publish();
+        }
+        Ok(())
+    }
+}
+
+/// Returns the lvol with the given name, if any.
+fn lookup_lvol(name: &str) -> Option<Lvol> {
+    UntypedBdev::lookup_by_name(name).and_then(|b| Lvol::try_from(b).ok())
+}
+
+/// Returns true if the lvol is a thin replica which can be relocated.
+fn relocatable(lvol: &Lvol) -> bool {
+    lvol.is_thin()
+        && !lvol.is_snapshot()
+        && !lvol.is_clone()
+        && !lvol.name().ends_with(COMPACT_SUFFIX)
+}
+
+/// Returns true if the lvol is shared or opened by a nexus.
+fn in_use(lvol: &Lvol) -> bool {
+    !matches!(lvol.shared(), None | Some(Protocol::Off))
+        || lvol.as_bdev().is_claimed()
+}
+
+/// Returns the number of write and unmap operations of the lvol so far.
+async fn write_ops(lvol: &Lvol) -> Option<u64> {
+    lvol.as_bdev()
+        .stats_async()
+        .await
+        .ok()
+        .map(|s| s.num_write_ops + s.num_unmap_ops)
+}
+
+/// Copy the given ranges of the source to the destination replica, which is
+/// claimed for the duration of the copy. Only the copy of a replica which
+/// has not been destroyed yet can be aborted.
+async fn copy(
+    job: &mut CompactJob,
+    src: &UntypedBdevHandle,
+    dst_name: &str,
+    ranges: &[(u64, u64)],
+    abortable: bool,
+) -> Result<(), CompactError> {
+    let dst =
+        UntypedBdevHandle::open(dst_name, true, true).context(ReplicaIo {
+            name: dst_name,
+        })?;
+    for &(offset, len) in ranges {
+        let mut done = 0;
+        while done < len {
+            if abortable {
+                job.check_aborted()?;
+            }
+            let size = CHUNK_SIZE.min(len - done);
+            let mut buf = dst
+                .dma_malloc(size)
+                .map_err(|_| CoreError::DmaAllocationFailed {
+                    size,
+                })
+                .context(ReplicaIo {
+                    name: dst_name,
+                })?;
+            src.read_at(offset + done, &mut buf)
+                .await
+                .context(ReplicaIo {
+                    name: src.get_bdev().name(),
+                })?;
+            dst.write_at(offset + done, &buf).await.context(ReplicaIo {
+                name: dst_name,
+            })?;
+            done += size;
+            job.done_bytes += size;
+            job.publish();
+        }
+    }
+    Ok(())
+}
+
+/// Destroy the temporary replica of a relocation which has been given up.
+async fn discard(tmp: Lvol) {
+    let name = tmp.name();
+    if let Err(error) = tmp.destroy().await {
+        error!("Failed to destroy compaction copy {}: {}", name, error);
+    }
+}
+
+/// Relocate the data of the replica to the lowest free clusters of the
+/// pool. Returns false if the replica has been skipped.
+async fn relocate(
+    job: &mut CompactJob,
+    lvs: &Lvs,
+    lvol: Lvol,
+) -> Result<bool, CompactError> {
+    let name = lvol.name();
+    let uuid = lvol.uuid();
+    let size = lvol.size();
+    let allocated = lvol.usage().allocated_bytes;
+
+    // the copy must fit next to the replica
+    if lvs.available() < allocated + CHUNK_SIZE {
+        warn!(
+            "Not enough free space in pool {} to relocate replica {}",
+            job.pool, name
+        );
+        return Ok(false);
+    }
+
+    // claim the replica so that it can't be shared nor added to a nexus
+    let src = match UntypedBdevHandle::open(&name, false, true) {
+        Ok(src) => src,
+        Err(_) => return Ok(false),
+    };
+    let writes = write_ops(&lvol).await;
+
+    let tmp_name = format!("{}{}", name, COMPACT_SUFFIX);
+    let mut tmp = lvs
+        .create_lvol_unchecked(&tmp_name, size, None, true)
+        .await
+        .context(Relocate {
+            name: &name,
+        })?;
+    if let Err(error) =
+        copy(job, &src, &tmp_name, &lvol.allocated_extents(), true).await
+    {
+        drop(src);
+        discard(tmp).await;
+        return Err(error);
+    }
+    if writes.is_none() || write_ops(&lvol).await != writes {
+        warn!(
+            "Replica {} has been written to while being copied, skipping it",
+            name
+        );
+        drop(src);
+        discard(tmp).await;
+        return Ok(false);
+    }
+
+    // from here on the copy holds the data of the replica
+    let recorded = async {
+        Pin::new(&mut tmp)
+            .set_no_sync(PropValue::CompactSource(uuid.clone()))
+            .await?;
+        if let Some(alias) = lvol.alias().await {
+            Pin::new(&mut tmp)
+                .set_no_sync(PropValue::Alias(alias))
+                .await?;
+        }
+        Pin::new(&mut tmp).sync_metadata().await
+    }
+    .await;
+    if let Err(error) = recorded {
+        drop(src);
+        discard(tmp).await;
+        return Err(error).context(Relocate {
+            name,
+        });
+    }
+
+    drop(src);
+    lvol.destroy().await.context(Relocate {
+        name: &name,
+    })?;
+    restore(job, lvs, tmp, &name, &uuid).await?;
+    Ok(true)
+}
+
+/// Recreate the replica of the given name and uuid from the temporary
+/// replica of its relocation, then destroy the temporary replica.
+async fn restore(
+    job: &mut CompactJob,
+    lvs: &Lvs,
+    mut tmp: Lvol,
+    name: &str,
+    uuid: &str,
+) -> Result<(), CompactError> {
+    Pin::new(&mut tmp)
+        .set(PropValue::CompactSource(format!(
+            "{}{}",
+            uuid, RESTORE_MARKER
+        )))
+        .await
+        .context(Relocate {
+            name,
+        })?;
+    let alias = tmp.alias().await;
+
+    // an interrupted restore is started over
+    if let Some(partial) = UntypedBdev::lookup_by_uuid_str(uuid)
+        .and_then(|b| Lvol::try_from(b).ok())
+    {
+        partial.destroy().await.context(Relocate {
+            name,
+        })?;
+    }
+
+    let mut lvol = lvs
+        .create_lvol_unchecked(name, tmp.size(), Some(uuid), true)
+        .await
+        .context(Relocate {
+            name,
+        })?;
+    let src = UntypedBdevHandle::open(&tmp.name(), false, true).context(
+        ReplicaIo {
+            name: tmp.name(),
+        },
+    )?;
+    copy(job, &src, name, &tmp.allocated_extents(), false).await?;
+    drop(src);
+    tmp.destroy().await.context(Relocate {
+        name,
+    })?;
+
+    if let Some(alias) = alias {
+        if let Err(error) = Pin::new(&mut lvol).rename(Some(alias)).await {
+            warn!("Failed to restore the alias of {}: {}", name, error);
+        }
+    }
+    info!("Relocated replica {} in pool {}", name, job.pool);
+    Ok(())
+}
+
+/// Complete or roll back the relocations of the pool interrupted by a crash.
+async fn recover(job: &mut CompactJob, lvs: &Lvs) -> Result<(), CompactError> {
+    let leftovers = lvs
+        .lvols()
+        .map(|lvols| {
+            lvols
+                .filter(|l| l.name().ends_with(COMPACT_SUFFIX))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    for tmp in leftovers {
+        let tmp_name = tmp.name();
+        let name = tmp_name
+            .strip_suffix(COMPACT_SUFFIX)
+            .unwrap_or(&tmp_name)
+            .to_string();
+        let source = match tmp.get(PropName::CompactSource).await {
+            Ok(PropValue::CompactSource(source)) => Some(source),
+            _ => None,
+        };
+        match source {
+            // the replica has been destroyed, or was being destroyed
+            Some(source)
+                if source.ends_with(RESTORE_MARKER)
+                    || UntypedBdev::lookup_by_uuid_str(&source).is_none() =>
+            {
+                let uuid = source
+                    .strip_suffix(RESTORE_MARKER)
+                    .unwrap_or(&source)
+                    .to_string();
+                info!(
+                    "Restoring replica {} from interrupted compaction copy {}",
+                    name, tmp_name
+                );
+                job.total_bytes += tmp.usage().allocated_bytes;
+                restore(job, lvs, tmp, &name, &uuid).await?;
+            }
+            // the replica is intact
+            _ => {
+                info!("Discarding interrupted compaction copy {}", tmp_name);
+                discard(tmp).await;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Relocate the thin replicas of the pool.
+async fn run(job: &mut CompactJob) -> Result<(), CompactError> {
+    let lvs =
+        Lvs::lookup(&job.pool).ok_or_else(|| CompactError::PoolNotFound {
+            name: job.pool.clone(),
+        })?;
+    recover(job, &lvs).await?;
+
+    let candidates = lvs
+        .lvols()
+        .map(|lvols| lvols.filter(relocatable).collect::<Vec<_>>())
+        .unwrap_or_default();
+    job.replicas = candidates.len() as u64;
+    job.total_bytes += candidates
+        .iter()
+        .map(|l| 2 * l.usage().allocated_bytes)
+        .sum::<u64>();
+    let candidates =
+        candidates.iter().map(|l| l.name()).collect::<Vec<String>>();
+    job.publish();
+
+    for name in candidates {
+        job.wait_for_window().await?;
+        job.check_aborted()?;
+
+        let lvs = Lvs::lookup(&job.pool).ok_or_else(|| {
+            CompactError::PoolNotFound {
+                name: job.pool.clone(),
+            }
+        })?;
+        // the replica may have been destroyed in the meantime
+        let lvol = match lookup_lvol(&name) {
+            Some(lvol) => lvol,
+            None => continue,
+        };
+        let bytes = 2 * lvol.usage().allocated_bytes;
+        let done_bytes = job.done_bytes;
+
+        let relocated = if in_use(&lvol) {
+            false
+        } else {
+            job.current = Some(name.clone());
+            job.publish();
+            relocate(job, &lvs, lvol).await?
+        };
+        if relocated {
+            job.relocated.push(name);
+        } else {
+            job.skipped.push(name);
+            job.total_bytes = job.total_bytes.saturating_sub(bytes);
+            job.done_bytes = done_bytes;
+        }
+        job.current = None;
+        job.free_bytes = lvs.available();
+        job.publish();
+    }
+    Ok(())
+}
+
+/// Start the compaction job of the pool.
+fn start(mut job: CompactJob) -> Result<CompactJob, CompactError> {
+    {
+        let mut jobs = JOBS.lock();
+        if matches!(
+            jobs.get(&job.pool).map(|j| j.state),
+            Some(CompactState::Running | CompactState::Waiting)
+        ) {
+            return JobRunning {
+                pool: job.pool,
+            }
+            .fail();
+        }
+        jobs.insert(job.pool.clone(), job.clone());
+    }
+    ABORTED.lock().remove(&job.pool);
+
+    let reply = job.clone();
+    Reactors::master().send_future(async move {
+        info!(
+            "Starting compaction of pool {}, window {:?}",
+            job.pool, job.window
+        );
+        match run(&mut job).await {
+            Ok(_) => {
+                info!(
+                    "Compaction of pool {} completed: {} replicas relocated, \
+                    {} skipped",
+                    job.pool,
+                    job.relocated.len(),
+                    job.skipped.len()
+                );
+                job.state = CompactState::Completed;
+            }
+            Err(CompactError::JobAborted {
+                ..
+            }) => {
+                info!("Compaction of pool {} aborted", job.pool);
+                job.state = CompactState::Aborted;
+            }
+            Err(error) => {
+                error!("Compaction of pool {} failed: {}", job.pool, error);
+                job.state = CompactState::Failed;
+                job.error = Some(error.to_string());
+            }
+        }
+        job.current = None;
+        if let Some(lvs) = Lvs::lookup(&job.pool) {
+            job.free_bytes = lvs.available();
+        }
+        ABORTED.lock().remove(&job.pool);
+        job.publish();
+    });
+    Ok(reply)
+}
+
+/// Arguments of the pool_compact json-rpc method.
+#[derive(Debug, Deserialize)]
+struct PoolCompactArgs {
+    pool: String,
+    /// Daily window in UTC hours, the job runs at any time without it.
+    start_hour: Option<u32>,
+    end_hour: Option<u32>,
+}
+
+/// Arguments of the compaction json-rpc methods acting on a pool.
+#[derive(Debug, Deserialize)]
+struct PoolCompactJobArgs {
+    pool: String,
+}
+
+/// Register the compaction json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("pool_compact", |args: PoolCompactArgs| {
+        async move {
+            let lvs = Lvs::lookup(&args.pool).ok_or_else(|| {
+                CompactError::PoolNotFound {
+                    name: args.pool.clone(),
+                }
+            })?;
+            let window = match (args.start_hour, args.end_hour) {
+                (None, None) => None,
+                (start_hour, end_hour) => {
+                    let start_hour = start_hour.unwrap_or(0);
+                    let end_hour = end_hour.unwrap_or(0);
+                    if start_hour == end_hour
+                        || start_hour >= 24
+                        || end_hour >= 24
+                    {
+                        return InvalidWindow {
+                            start_hour,
+                            end_hour,
+                        }
+                        .fail();
+                    }
+                    Some(CompactWindow {
+                        start_hour,
+                        end_hour,
+                    })
+                }
+            };
+            start(CompactJob::new(&lvs, window))
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("pool_compact_status", |args: PoolCompactJobArgs| {
+        async move {
+            JOBS.lock().get(&args.pool).cloned().ok_or_else(|| {
+                CompactError::JobNotFound {
+                    pool: args.pool,
+                }
+            })
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register::<(), _, _, CompactError>("pool_compact_list", |_| {
+        async move {
+            let mut jobs = JOBS.lock().values().cloned().collect::<Vec<_>>();
+            jobs.sort_by(|a, b| a.pool.cmp(&b.pool));
+            Ok(jobs)
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("pool_compact_abort", |args: PoolCompactJobArgs| {
+        async move {
+            match JOBS.lock().get(&args.pool).map(|j| j.state) {
+                Some(CompactState::Running | CompactState::Waiting) => {
+                    ABORTED.lock().insert(args.pool);
+                    Ok(())
+                }
+                _ => Err(CompactError::JobNotFound {
+                    pool: args.pool,
+                }),
+            }
+        }
+        .boxed_local()
+    });
+}
@@ -0,0 +1,421 @@
+//! Copy of replicas across nodes.
+//!
+//! Moving a replica to another node otherwise means building a temporary
+//! nexus over the source and a new replica, and letting it rebuild. A copy
+//! job is run by the io-engine of the destination instead: it connects to
+//! the source, a replica shared over NVMF by its node, creates the replica
+//! on one of its pools, copies the data to it, and disconnects.
+//!
+//! The copy is either:
+//!
+//! - full: every block of the source is written, so the source must not be
+//!   written to while it is copied;
+//! - snapshot based: the source is a snapshot exported by its node, which
+//!   cannot change while it is copied. The blocks which read as zeroes are not
+//!   written, so that a thin replica only allocates the clusters holding data,
+//!   and the replica is snapshotted once copied, with the name given, so that
+//!   it holds the same point in time as the source.
+//!
+//! A job can be cancelled, at the end of the current chunk. A cancelled or
+//! failed job destroys the replica it created. The jobs are not recorded, so
+//! a copy interrupted by a restart of the io-engine leaves a partial replica
+//! behind, for the control plane to destroy.
+
+use std::collections::{HashMap, HashSet};
+
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+
+use super::{Error, Lvol, Lvs};
+use crate::{
+    bdev_api::{bdev_create, bdev_destroy, BdevError},
+    core::{CoreError, Reactors, UntypedBdev, UntypedBdevHandle},
+    jsonrpc::{jsonrpc_register, Code, RpcErrorCode},
+};
+
+/// Size of the chunks the data is copied in.
+const CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Copy jobs known to this node, by id.
+static JOBS: Lazy<Mutex<HashMap<String, CopyJob>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Jobs which have been asked to stop.
+static CANCELLED: Lazy<Mutex<HashSet<String>>> =
+    Lazy::new(|| Mutex::new(HashSet::new()));
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)), context(suffix(false)))]
+pub enum CopyError {
+    #[snafu(display("Pool {} not found", name))]
+    PoolNotFound { name: String },
+    #[snafu(display("Replica {} already exists", name))]
+    ReplicaExists { name: String },
+    #[snafu(display("A snapshot based copy needs a snapshot name"))]
+    SnapshotNameMissing {},
+    #[snafu(display("Copy job {} already exists", id))]
+    JobExists { id: String },
+    #[snafu(display("Copy job {} not found", id))]
+    JobNotFound { id: String },
+    #[snafu(display("Copy job {} is not running", id))]
+    JobNotRunning { id: String },
+    #[snafu(display("Copy job {} is still running", id))]
+    JobRunning { id: String },
+    #[snafu(display("Copy job {} has been cancelled", id))]
+    JobCancelled { id: String },
+    #[snafu(display("Failed to connect to source {}: {}", uri, source))]
+    SourceConnect { source: BdevError, uri: String },
+    #[snafu(display("Failed to create replica {}: {}", name, source))]
+    ReplicaCreate { source: Error, name: String },
+    #[snafu(display("Failed to snapshot replica {}: {}", name, source))]
+    ReplicaSnapshot { source: Error, name: String },
+    #[snafu(display("I/O failed on {}: {}", name, source))]
+    ReplicaIo { source: CoreError, name: String },
+}
+
+impl RpcErrorCode for CopyError {
+    fn rpc_error_code(&self) -> Code {
+        match self {
+            CopyError::PoolNotFound {
+                ..
+            }
+            | CopyError::JobNotFound {
+                ..
+            } => Code::NotFound,
+            CopyError::ReplicaExists {
+                ..
+            }
+            | CopyError::JobExists {
+                ..
+            }
+            | CopyError::JobRunning {
+                ..
+            } => Code::AlreadyExists,
+            CopyError::SnapshotNameMissing {
+                ..
+            }
+            | CopyError::JobNotRunning {
+                ..
+            } => Code::InvalidParams,
+            _ => Code::InternalError,
+        }
+    }
+}
+
+/// Kind of copy.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CopyMode {
+    /// Copy every block of a replica.
+    Full,
+    /// Copy the data of an exported snapshot, then snapshot the copy.
+    Snapshot,
+}
+
+/// State of a copy job.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CopyState {
+    /// Connecting to the source and creating the replica.
+    Starting,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+/// Parameters of a copy job.
+#[derive(Deserialize, Debug, Clone)]
+pub struct CopyParams {
+    /// Id of the job.
+    pub id: String,
+    /// URI of the source, such as the NVMF URI of a shared replica or of an
+    /// exported snapshot.
+    pub source: String,
+    /// Pool to create the replica on, along with its name and uuid.
+    pub pool: String,
+    pub name: String,
+    pub uuid: Option<String>,
+    #[serde(default)]
+    pub thin: bool,
+    pub mode: CopyMode,
+    /// Name of the snapshot taken of the replica once copied, for the
+    /// snapshot based copies.
+    pub snapshot: Option<String>,
+}
+
+/// Copy job, along with its progress.
+#[derive(Serialize, Debug, Clone)]
+pub struct CopyJob {
+    pub id: String,
+    pub source: String,
+    pub pool: String,
+    pub replica: String,
+    pub mode: CopyMode,
+    pub state: CopyState,
+    pub error: Option<String>,
+    /// Number of bytes to copy, and copied so far.
+    pub total_bytes: u64,
+    pub done_bytes: u64,
+    /// Number of bytes of the source which read as zeroes and have not been
+    /// written.
+    pub skipped_bytes: u64,
+}
+
+impl CopyJob {
+    /// Returns an error if the job has been asked to stop.
+    fn check_cancelled(&self) -> Result<(), CopyError> {
+        if CANCELLED.lock().contains(&self.id) {
+            return JobCancelled {
+                id: self.id.clone(),
+            }
+            .fail();
+        }
+        Ok(())
+    }
+
+    fn publish(&self) {
+        JOBS.lock().insert(self.id.clone(), self.clone());
+    }
+}
+
+/// Returns the copy job with the given id.
+pub fn copy_job(id: &str) -> Option<CopyJob> {
+    JOBS.lock().get(id).cloned()
+}
+
+/// Start copying the given source into a new replica.
+pub fn copy_replica(params: CopyParams) -> Result<CopyJob, CopyError> {
+    if Lvs::lookup(&params.pool).is_none() {
+        return PoolNotFound {
+            name: params.pool,
+        }
+        .fail();
+    }
+    if UntypedBdev::lookup_by_name(&params.name).is_some() {
+        return ReplicaExists {
+            name: params.name,
+        }
+        .fail();
+    }
+    if params.mode == CopyMode::Snapshot && params.snapshot.is_none() {
+        return SnapshotNameMissing {}.fail();
+    }
+
+    let job = CopyJob {
+        id: params.id.clone(),
+        source: params.source.clone(),
+        pool: params.pool.clone(),
+        replica: params.name.clone(),
+        mode: params.mode,
+        state: CopyState::Starting,
+        error: None,
+        total_bytes: 0,
+        done_bytes: 0,
+        skipped_bytes: 0,
+    };
+    {
+        let mut jobs = JOBS.lock();
+        if jobs.contains_key(&job.id) {
+            return JobExists {
+                id: job.id,
+            }
+            .fail();
+        }
+        jobs.insert(job.id.clone(), job.clone());
+    }
+    CANCELLED.lock().remove(&job.id);
+
+    info!(
+        "Copying {} to replica {} on pool {} ({:?})",
+        params.source, params.name, params.pool, params.mode
+    );
+    let mut running = job.clone();
+    Reactors::master().send_future(async move {
+        let source = params.source.clone();
+        let mut created = None;
+        let result = run(&mut running, &params, &mut created).await;
+        if let Err(error) = bdev_destroy(&source).await {
+            warn!("Failed to disconnect from source {}: {}", source, error);
+        }
+
+        match result {
+            Ok(()) => {
+                info!("Copy job {} completed", running.id);
+                running.state = CopyState::Completed;
+            }
+            Err(error) => {
+                if matches!(error, CopyError::JobCancelled { .. }) {
+                    info!("Copy job {} cancelled", running.id);
+                    running.state = CopyState::Cancelled;
+                } else {
+                    error!("Copy job {} failed: {}", running.id, error);
+                    running.state = CopyState::Failed;
+                    running.error = Some(error.to_string());
+                }
+                if let Some(lvol) = created {
+                    if let Err(error) = lvol.destroy().await {
+                        error!(
+                            "Failed to destroy partial copy {}: {}",
+                            running.replica, error
+                        );
+                    }
+                }
+            }
+        }
+        running.publish();
+        CANCELLED.lock().remove(&running.id);
+    });
+
+    Ok(job)
+}
+
+/// Connect to the source, create the replica and copy the data to it.
+async fn run(
+    job: &mut CopyJob,
+    params: &CopyParams,
+    created: &mut Option<Lvol>,
+) -> Result<(), CopyError> {
+    let src_name =
+        bdev_create(&params.source).await.context(SourceConnect {
+            uri: params.source.clone(),
+        })?;
+    let src = UntypedBdevHandle::open(&src_name, false, false).context(
+        ReplicaIo {
+            name: src_name.clone(),
+        },
+    )?;
+    let size = src.get_bdev().size_in_bytes();
+    job.check_cancelled()?;
+
+    let lvs = Lvs::lookup(&params.pool).ok_or(CopyError::PoolNotFound {
+        name: params.pool.clone(),
+    })?;
+    let lvol = lvs
+        .create_lvol(&params.name, size, params.uuid.as_deref(), params.thin)
+        .await
+        .context(ReplicaCreate {
+            name: params.name.clone(),
+        })?;
+    *created = Some(lvol.clone());
+
+    let dst = UntypedBdevHandle::open(&params.name, true, true).context(
+        ReplicaIo {
+            name: params.name.clone(),
+        },
+    )?;
+    job.state = CopyState::Running;
+    job.total_bytes = size;
+    job.publish();
+
+    let sparse = params.mode == CopyMode::Snapshot;
+    while job.done_bytes < size {
+        job.check_cancelled()?;
+        let offset = job.done_bytes;
+        let len = CHUNK_SIZE.min(size - offset);
+        let mut buf = dst
+            .dma_malloc(len)
+            .map_err(|_| CoreError::DmaAllocationFailed {
+                size: len,
+            })
+            .context(ReplicaIo {
+                name: params.name.clone(),
+            })?;
+        src.read_at(offset, &mut buf).await.context(ReplicaIo {
+            name: src_name.clone(),
+        })?;
+        if sparse && buf.as_slice().iter().all(|b| *b == 0) {
+            job.skipped_bytes += len;
+        } else {
+            dst.write_at(offset, &buf).await.context(ReplicaIo {
+                name: params.name.clone(),
+            })?;
+        }
+        job.done_bytes += len;
+        job.publish();
+    }
+    drop(dst);
+
+    if let Some(snapshot) = params.snapshot.as_deref().filter(|_| sparse) {
+        lvol.snapshot(snapshot).await.context(ReplicaSnapshot {
+            name: params.name.clone(),
+        })?;
+    }
+    Ok(())
+}
+
+/// Ask the given copy job to stop.
+pub fn cancel_copy(id: &str) -> Result<(), CopyError> {
+    match copy_job(id).map(|j| j.state) {
+        Some(CopyState::Starting | CopyState::Running) => {
+            CANCELLED.lock().insert(id.to_string());
+            Ok(())
+        }
+        Some(_) => JobNotRunning {
+            id,
+        }
+        .fail(),
+        None => JobNotFound {
+            id,
+        }
+        .fail(),
+    }
+}
+
+/// Arguments of the copy json-rpc methods acting on a single job.
+#[derive(Debug, Deserialize)]
+struct CopyJobArgs {
+    id: String,
+}
+
+/// Register the replica copy json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("replica_copy", |args: CopyParams| {
+        async move { copy_replica(args) }.boxed_local()
+    });
+
+    jsonrpc_register("replica_copy_status", |args: CopyJobArgs| {
+        async move {
+            copy_job(&args.id).ok_or(CopyError::JobNotFound {
+                id: args.id,
+            })
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("replica_copy_cancel", |args: CopyJobArgs| {
+        async move { cancel_copy(&args.id) }.boxed_local()
+    });
+
+    jsonrpc_register::<(), _, _, CopyError>("replica_copy_list", |_| {
+        async move {
+            let mut jobs = JOBS.lock().values().cloned().collect::<Vec<_>>();
+            jobs.sort_by(|a, b| a.id.cmp(&b.id));
+            Ok(jobs)
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("replica_copy_remove", |args: CopyJobArgs| {
+        async move {
+            match copy_job(&args.id).map(|j| j.state) {
+                Some(CopyState::Starting | CopyState::Running) => JobRunning {
+                    id: args.id,
+                }
+                .fail(),
+                Some(_) => {
+                    JOBS.lock().remove(&args.id);
+                    Ok(())
+                }
+                None => JobNotFound {
+                    id: args.id,
+                }
+                .fail(),
+            }
+        }
+        .boxed_local()
+    });
+}
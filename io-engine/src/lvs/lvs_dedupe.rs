@@ -0,0 +1,370 @@
+//! Offline deduplication analysis of pools.
+//!
+//! A dedupe scan reads the clusters allocated by the replicas and snapshots
+//! of a pool, fingerprints them and reports how much space could be saved
+//! if the duplicate clusters were stored once, and the zeroed clusters not
+//! at all. The scan only reads the data, the replicas remain usable, which
+//! makes the report an estimate when they are written to during the scan.
+//!
+//! The blobstore can only share clusters between a snapshot and its clones,
+//! through the clusters which the clones have not written, so the duplicate
+//! clusters found by the scan cannot be rewritten as shared references.
+//! Scans asking for it are rejected rather than reporting savings that are
+//! not made.
+
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+};
+
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::{ResultExt, Snafu};
+
+use super::{lvs_maintenance::wait_jobs_resumed, Lvol, Lvs};
+use crate::{
+    core::{CoreError, Reactors, UntypedBdev, UntypedBdevHandle},
+    jsonrpc::{jsonrpc_register, Code, RpcErrorCode},
+};
+
+/// Dedupe scans, by pool.
+static SCANS: Lazy<Mutex<HashMap<String, DedupeScan>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Pools whose dedupe scan has been aborted.
+static ABORTED: Lazy<Mutex<HashSet<String>>> =
+    Lazy::new(|| Mutex::new(HashSet::new()));
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)), context(suffix(false)))]
+pub enum DedupeError {
+    #[snafu(display("Pool {} not found", name))]
+    PoolNotFound { name: String },
+    #[snafu(display("Dedupe scan of pool {} is already running", pool))]
+    ScanRunning { pool: String },
+    #[snafu(display("No dedupe scan of pool {}", pool))]
+    ScanNotFound { pool: String },
+    #[snafu(display("Dedupe scan of pool {} has been aborted", pool))]
+    ScanAborted { pool: String },
+    #[snafu(display(
+        "Cannot rewrite the duplicate clusters of pool {}: the blobstore \
+        only shares clusters between a snapshot and its clones",
+        pool
+    ))]
+    RewriteNotSupported { pool: String },
+    #[snafu(display("I/O failed on {}: {}", name, source))]
+    ReplicaIo { source: CoreError, name: String },
+}
+
+impl RpcErrorCode for DedupeError {
+    fn rpc_error_code(&self) -> Code {
+        match self {
+            DedupeError::PoolNotFound {
+                ..
+            }
+            | DedupeError::ScanNotFound {
+                ..
+            } => Code::NotFound,
+            DedupeError::ScanRunning {
+                ..
+            } => Code::AlreadyExists,
+            DedupeError::RewriteNotSupported {
+                ..
+            } => Code::InvalidParams,
+            _ => Code::InternalError,
+        }
+    }
+}
+
+/// State of a dedupe scan.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DedupeState {
+    Running,
+    Completed,
+    Failed,
+    Aborted,
+}
+
+/// Dedupe scan of a pool, along with its findings so far.
+#[derive(Serialize, Debug, Clone)]
+pub struct DedupeScan {
+    pub pool: String,
+    pub state: DedupeState,
+    pub cluster_size: u64,
+    /// Number of bytes allocated by the replicas and snapshots of the pool,
+    /// and scanned so far.
+    pub total_bytes: u64,
+    pub scanned_bytes: u64,
+    /// Replica or snapshot being scanned.
+    pub current: Option<String>,
+    /// Number of allocated clusters scanned.
+    pub clusters: u64,
+    /// Number of distinct non-zero clusters among them.
+    pub unique_clusters: u64,
+    /// Number of clusters holding only zeroes.
+    pub zero_clusters: u64,
+    /// Number of clusters which duplicate another scanned cluster.
+    pub duplicate_clusters: u64,
+    /// Space which deduplication would save, in bytes.
+    pub dedupe_bytes: u64,
+    /// Ratio of the allocated space to the space needed after
+    /// deduplication.
+    pub dedupe_ratio: f64,
+    pub error: Option<String>,
+}
+
+impl DedupeScan {
+    fn new(lvs: &Lvs) -> Self {
+        Self {
+            pool: lvs.name().to_string(),
+            state: DedupeState::Running,
+            cluster_size: lvs.cluster_size(),
+            total_bytes: 0,
+            scanned_bytes: 0,
+            current: None,
+            clusters: 0,
+            unique_clusters: 0,
+            zero_clusters: 0,
+            duplicate_clusters: 0,
+            dedupe_bytes: 0,
+            dedupe_ratio: 1.0,
+            error: None,
+        }
+    }
+
+    /// Make the progress of the scan visible.
+    fn publish(&self) {
+        SCANS.lock().insert(self.pool.clone(), self.clone());
+    }
+
+    /// Fail if the scan has been aborted.
+    fn check_aborted(&self) -> Result<(), DedupeError> {
+        if ABORTED.lock().contains(&self.pool) {
+            return ScanAborted {
+                pool: self.pool.clone(),
+            }
+            .fail();
+        }
+        Ok(())
+    }
+
+    /// Account for a scanned cluster, given whether its fingerprint has
+    /// been seen before.
+    fn add_cluster(&mut self, zero: bool, seen: bool) {
+        self.clusters += 1;
+        if zero {
+            self.zero_clusters += 1;
+        } else if seen {
+            self.duplicate_clusters += 1;
+        } else {
+            self.unique_clusters += 1;
+        }
+        self.dedupe_bytes =
+            (self.zero_clusters + self.duplicate_clusters) * self.cluster_size;
+        if self.unique_clusters > 0 {
+            self.dedupe_ratio =
+                self.clusters as f64 / self.unique_clusters as f64;
+        }
+    }
+}
+
+/// Fingerprint the clusters allocated by the lvol.
+async fn scan_lvol(
+    scan: &mut DedupeScan,
+    lvol: &Lvol,
+    fingerprints: &mut HashSet<[u8; 32]>,
+) -> Result<(), DedupeError> {
+    let name = lvol.name();
+    let handle =
+        UntypedBdevHandle::open(&name, false, false).context(ReplicaIo {
+            name: &name,
+        })?;
+    let mut buf = handle
+        .dma_malloc(scan.cluster_size)
+        .map_err(|_| CoreError::DmaAllocationFailed {
+            size: scan.cluster_size,
+        })
+        .context(ReplicaIo {
+            name: &name,
+        })?;
+
+    for (offset, len) in lvol.allocated_extents() {
+        let mut done = 0;
+        while done < len {
+            scan.check_aborted()?;
+            wait_jobs_resumed(&scan.pool, || scan.check_aborted()).await?;
+            handle.read_at(offset + done, &mut buf).await.context(
+                ReplicaIo {
+                    name: &name,
+                },
+            )?;
+            let data = buf.as_slice();
+            let zero = data.iter().all(|b| *b == 0);
+            let seen =
+                !zero && !fingerprints.insert(Sha256::digest(data).into());
+            scan.add_cluster(zero, seen);
+            scan.scanned_bytes += scan.cluster_size;
+            done += scan.cluster_size;
+        }
+        scan.publish();
+    }
+    Ok(())
+}
+
+/// Fingerprint the clusters of the replicas and snapshots of the pool.
+async fn run(scan: &mut DedupeScan) -> Result<(), DedupeError> {
+    let lvs =
+        Lvs::lookup(&scan.pool).ok_or_else(|| DedupeError::PoolNotFound {
+            name: scan.pool.clone(),
+        })?;
+    let lvols = lvs
+        .lvols()
+        .map(|l| l.collect::<Vec<_>>())
+        .unwrap_or_default();
+    scan.total_bytes =
+        lvols.iter().map(|l| l.usage().allocated_bytes).sum::<u64>();
+    let names = lvols.iter().map(|l| l.name()).collect::<Vec<_>>();
+    scan.publish();
+
+    let mut fingerprints = HashSet::new();
+    for name in names {
+        // the replica may have been destroyed in the meantime
+        let lvol = match UntypedBdev::lookup_by_name(&name)
+            .and_then(|b| Lvol::try_from(b).ok())
+        {
+            Some(lvol) => lvol,
+            None => continue,
+        };
+        scan.current = Some(name);
+        scan.publish();
+        scan_lvol(scan, &lvol, &mut fingerprints).await?;
+    }
+    Ok(())
+}
+
+/// Start the dedupe scan of the pool.
+fn start(mut scan: DedupeScan) -> Result<DedupeScan, DedupeError> {
+    {
+        let mut scans = SCANS.lock();
+        if matches!(
+            scans.get(&scan.pool).map(|s| s.state),
+            Some(DedupeState::Running)
+        ) {
+            return ScanRunning {
+                pool: scan.pool,
+            }
+            .fail();
+        }
+        scans.insert(scan.pool.clone(), scan.clone());
+    }
+    ABORTED.lock().remove(&scan.pool);
+
+    let reply = scan.clone();
+    Reactors::master().send_future(async move {
+        info!("Starting dedupe scan of pool {}", scan.pool);
+        match run(&mut scan).await {
+            Ok(_) => {
+                info!(
+                    "Dedupe scan of pool {} completed: {} clusters, {} \
+                    duplicate, {} zeroed, ratio {:.2}",
+                    scan.pool,
+                    scan.clusters,
+                    scan.duplicate_clusters,
+                    scan.zero_clusters,
+                    scan.dedupe_ratio
+                );
+                scan.state = DedupeState::Completed;
+            }
+            Err(DedupeError::ScanAborted {
+                ..
+            }) => {
+                info!("Dedupe scan of pool {} aborted", scan.pool);
+                scan.state = DedupeState::Aborted;
+            }
+            Err(error) => {
+                error!("Dedupe scan of pool {} failed: {}", scan.pool, error);
+                scan.state = DedupeState::Failed;
+                scan.error = Some(error.to_string());
+            }
+        }
+        scan.current = None;
+        ABORTED.lock().remove(&scan.pool);
+        scan.publish();
+    });
+    Ok(reply)
+}
+
+/// Arguments of the pool_dedupe_scan json-rpc method.
+#[derive(Debug, Deserialize)]
+struct PoolDedupeScanArgs {
+    pool: String,
+    /// Rewrite the duplicate clusters as shared references.
+    #[serde(default)]
+    rewrite: bool,
+}
+
+/// Arguments of the dedupe json-rpc methods acting on a pool.
+#[derive(Debug, Deserialize)]
+struct PoolDedupeArgs {
+    pool: String,
+}
+
+/// Register the dedupe json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("pool_dedupe_scan", |args: PoolDedupeScanArgs| {
+        async move {
+            let lvs = Lvs::lookup(&args.pool).ok_or_else(|| {
+                DedupeError::PoolNotFound {
+                    name: args.pool.clone(),
+                }
+            })?;
+            if args.rewrite {
+                return RewriteNotSupported {
+                    pool: args.pool,
+                }
+                .fail();
+            }
+            start(DedupeScan::new(&lvs))
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("pool_dedupe_status", |args: PoolDedupeArgs| {
+        async move {
+            SCANS.lock().get(&args.pool).cloned().ok_or_else(|| {
+                DedupeError::ScanNotFound {
+                    pool: args.pool,
+                }
+            })
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register::<(), _, _, DedupeError>("pool_dedupe_list", |_| {
+        async move {
+            let mut scans = SCANS.lock().values().cloned().collect::<Vec<_>>();
+            scans.sort_by(|a, b| a.pool.cmp(&b.pool));
+            Ok(scans)
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("pool_dedupe_abort", |args: PoolDedupeArgs| {
+        async move {
+            match SCANS.lock().get(&args.pool).map(|s| s.state) {
+                Some(DedupeState::Running) => {
+                    ABORTED.lock().insert(args.pool);
+                    Ok(())
+                }
+                _ => Err(DedupeError::ScanNotFound {
+                    pool: args.pool,
+                }),
+            }
+        }
+        .boxed_local()
+    });
+}
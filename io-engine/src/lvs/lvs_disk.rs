@@ -0,0 +1,371 @@
+//! Validation and labeling of the pool disks.
+//!
+//! A pool is only created on a disk which does not hold a pool already, but
+//! the disk may still hold the data of something else: a partition table, a
+//! filesystem, or a member of a RAID array or of a LVM volume group. Before a
+//! pool is created, the disk is probed for the signatures of these, at the
+//! offsets blkid looks for them, and the pool is refused if any is found,
+//! unless its creation is forced, in which case the signatures are wiped
+//! first, as wipefs does: the blocks holding them are zeroed, the rest of the
+//! disk is left as it is.
+//!
+//! A pool created on a GPT partition of a host disk can also be labeled: the
+//! type of the partition is set to the one of the pools and its name to the
+//! name of the pool, so that the partition shows as used on the host.
+
+use std::path::Path;
+
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use url::Url;
+use uuid::Uuid;
+
+use super::{lvs_overcommit::lookup_pool, Error, Lvs};
+use crate::{
+    bdev::{
+        nvme::{bdev_cmb_info, CmbInfo},
+        pmem,
+    },
+    core::{CoreError, Share, UntypedBdev, UntypedBdevHandle},
+    host::gpt::{host_partition, label_partition},
+    jsonrpc::{jsonrpc_register, Code, JsonRpcError},
+    pool_backend::PoolArgs,
+};
+
+/// Type of the GPT partitions of the pools.
+pub const POOL_PARTITION_TYPE: &str = "9a926014-91a0-43f0-8cb7-e8ee5a2033f8";
+
+/// Length of the start and of the end of the disk probed for signatures.
+const PROBE_LEN: u64 = 128 * 1024;
+
+/// RAID superblock magic, in little endian.
+const MD_MAGIC: &[u8] = &[0xfc, 0x4e, 0x2b, 0xa9];
+
+/// Signatures looked for at the start of the disk, along with their offset.
+const HEAD_SIGNATURES: &[(&str, u64, &[u8])] = &[
+    ("gpt", 512, b"EFI PART"),
+    ("gpt", 4096, b"EFI PART"),
+    ("dos", 510, &[0x55, 0xaa]),
+    ("xfs", 0, b"XFSB"),
+    ("ext4", 1080, &[0x53, 0xef]),
+    ("btrfs", 65600, b"_BHRfS_M"),
+    ("swap", 4086, b"SWAPSPACE2"),
+    ("swap", 4086, b"SWAP-SPACE"),
+    ("crypto_LUKS", 0, b"LUKS\xba\xbe"),
+    ("LVM2_member", 24, b"LVM2 001"),
+    ("LVM2_member", 536, b"LVM2 001"),
+    // RAID superblocks of version 1.1 and 1.2
+    ("linux_raid_member", 0, MD_MAGIC),
+    ("linux_raid_member", 4096, MD_MAGIC),
+];
+
+/// Options of the creation of a pool.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct PoolDiskOptions {
+    /// Wipe the signatures found on the disk rather than refusing it.
+    pub force: bool,
+    /// Label the GPT partition the pool is created on.
+    pub label: bool,
+}
+
+/// Signature found on a disk.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiskSignature {
+    /// Type of the signature, as reported by blkid.
+    pub kind: &'static str,
+    /// Offset of the signature, in bytes.
+    pub offset: u64,
+    /// Length of the signature, in bytes.
+    pub len: u64,
+}
+
+/// Returns the signatures looked for at the end of a disk of the given size.
+fn tail_signatures(
+    size: u64,
+    block_len: u64,
+) -> Vec<(&'static str, u64, &'static [u8])> {
+    let mut signatures = vec![("gpt", size - block_len, &b"EFI PART"[..])];
+    // RAID superblocks of version 0.90 and 1.0
+    if size >= 0x20000 {
+        signatures.push((
+            "linux_raid_member",
+            (size & !0xffff) - 0x10000,
+            MD_MAGIC,
+        ));
+    }
+    signatures.push((
+        "linux_raid_member",
+        size.saturating_sub(8192) & !0xfff,
+        MD_MAGIC,
+    ));
+    signatures
+}
+
+/// Probe the given bdev for the signatures of other data than a pool.
+pub async fn probe_signatures(
+    bdev: &str,
+) -> Result<Vec<DiskSignature>, CoreError> {
+    let handle = UntypedBdevHandle::open(bdev, false, false)?;
+    let disk = handle.get_bdev();
+    let block_len = disk.block_len() as u64;
+    let size = disk.size_in_bytes();
+    let len = PROBE_LEN.min(size) / block_len * block_len;
+
+    let dma_malloc = |size| {
+        handle
+            .dma_malloc(size)
+            .map_err(|_| CoreError::DmaAllocationFailed {
+                size,
+            })
+    };
+    let mut head = dma_malloc(len)?;
+    handle.read_at(0, &mut head).await?;
+    let mut tail = dma_malloc(len)?;
+    let tail_offset = size - len;
+    handle.read_at(tail_offset, &mut tail).await?;
+
+    let found = |buf: &[u8], offset: u64, magic: &[u8]| {
+        let offset = offset as usize;
+        buf.get(offset .. offset + magic.len()) == Some(magic)
+    };
+
+    let mut signatures = HEAD_SIGNATURES
+        .iter()
+        .filter(|(_, offset, magic)| found(head.as_slice(), *offset, magic))
+        .map(|(kind, offset, magic)| DiskSignature {
+            kind: *kind,
+            offset: *offset,
+            len: magic.len() as u64,
+        })
+        .collect::<Vec<_>>();
+    signatures.extend(
+        tail_signatures(size, block_len)
+            .into_iter()
+            .filter(|(_, offset, magic)| {
+                *offset >= tail_offset
+                    && found(tail.as_slice(), offset - tail_offset, magic)
+            })
+            .map(|(kind, offset, magic)| DiskSignature {
+                kind,
+                offset,
+                len: magic.len() as u64,
+            }),
+    );
+    Ok(signatures)
+}
+
+/// Zero the blocks of the given bdev holding the given signatures.
+async fn wipe_signatures(
+    bdev: &str,
+    signatures: &[DiskSignature],
+) -> Result<(), CoreError> {
+    let handle = UntypedBdevHandle::open(bdev, true, false)?;
+    let block_len = handle.get_bdev().block_len() as u64;
+    let zeroes = handle.dma_malloc(block_len).map_err(|_| {
+        CoreError::DmaAllocationFailed {
+            size: block_len,
+        }
+    })?;
+
+    for signature in signatures {
+        let first = signature.offset / block_len;
+        let last = (signature.offset + signature.len - 1) / block_len;
+        for block in first ..= last {
+            handle.write_at(block * block_len, &zeroes).await?;
+        }
+        info!(
+            "Wiped the {} signature at offset {} of {}",
+            signature.kind, signature.offset, bdev
+        );
+    }
+    Ok(())
+}
+
+/// Refuse to create the pool on the given bdev if it holds the signatures of
+/// other data, unless forced to wipe them.
+pub(super) async fn check_disk(
+    pool: &str,
+    bdev: &str,
+    force: bool,
+) -> Result<(), Error> {
+    let signatures =
+        probe_signatures(bdev)
+            .await
+            .map_err(|source| Error::DiskProbe {
+                source,
+                name: pool.to_string(),
+                disk: bdev.to_string(),
+            })?;
+    if signatures.is_empty() {
+        return Ok(());
+    }
+
+    let kinds = signatures
+        .iter()
+        .map(|s| format!("{} at offset {}", s.kind, s.offset))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if !force {
+        return Err(Error::DiskSignatures {
+            name: pool.to_string(),
+            disk: bdev.to_string(),
+            signatures: kinds,
+        });
+    }
+
+    warn!(
+        "Wiping the signatures found on {} to create pool {}: {}",
+        bdev, pool, kinds
+    );
+    wipe_signatures(bdev, &signatures).await.map_err(|source| {
+        Error::DiskProbe {
+            source,
+            name: pool.to_string(),
+            disk: bdev.to_string(),
+        }
+    })
+}
+
+/// Label the GPT partition of the host the given disk URI refers to as used
+/// by the pool.
+pub(super) fn label_disk(pool: &str, disk: &str) -> Result<(), Error> {
+    let label_error = |msg: String| Error::DiskLabel {
+        name: pool.to_string(),
+        disk: disk.to_string(),
+        msg,
+    };
+
+    let path = match Url::parse(disk) {
+        Ok(url) if matches!(url.scheme(), "aio" | "pmem" | "uring") => {
+            url.path().to_string()
+        }
+        _ => {
+            return Err(label_error(
+                "only host block devices can be labeled".to_string(),
+            ))
+        }
+    };
+    let partition = host_partition(Path::new(&path))
+        .map_err(|e| label_error(e.to_string()))?
+        .ok_or_else(|| label_error(format!("{} is not a partition", path)))?;
+
+    let type_guid = Uuid::parse_str(POOL_PARTITION_TYPE).unwrap();
+    label_partition(&partition, &type_guid, &format!("mayastor:{}", pool))
+        .map_err(|e| label_error(e.to_string()))?;
+    info!(
+        "Labeled partition {} of {} for pool {}",
+        partition.number,
+        partition.disk.display(),
+        pool
+    );
+    Ok(())
+}
+
+/// Arguments of the pool_create json-rpc method.
+#[derive(Debug, Deserialize)]
+struct PoolCreateArgs {
+    name: String,
+    uuid: Option<String>,
+    disks: Vec<String>,
+    #[serde(flatten)]
+    options: PoolDiskOptions,
+}
+
+/// Arguments of the pool_disk_signatures json-rpc method.
+#[derive(Debug, Deserialize)]
+struct DiskSignaturesArgs {
+    /// Name of the bdev of the disk.
+    bdev: String,
+}
+
+/// Arguments of the pool_disk_info json-rpc method.
+#[derive(Debug, Deserialize)]
+struct PoolDiskInfoArgs {
+    pool: String,
+}
+
+/// Pool, and its disk, as reported over json-rpc.
+#[derive(Debug, Serialize)]
+struct PoolDiskInfo {
+    name: String,
+    uuid: String,
+    disk: String,
+    capacity: u64,
+    /// Durability of the writes to the pool.
+    durability: &'static str,
+    /// Controller memory buffer of the disk, if it is a local NVMe device.
+    cmb: Option<CmbInfo>,
+}
+
+impl From<&Lvs> for PoolDiskInfo {
+    fn from(pool: &Lvs) -> Self {
+        let base = pool.base_bdev();
+        let disk = base.bdev_uri().unwrap_or_default();
+        let scheme = Url::parse(&disk)
+            .map(|url| url.scheme().to_string())
+            .unwrap_or_default();
+        Self {
+            name: pool.name().to_string(),
+            uuid: pool.uuid(),
+            durability: pmem::durability(&scheme),
+            cmb: bdev_cmb_info(&base),
+            disk,
+            capacity: pool.capacity(),
+        }
+    }
+}
+
+/// Register the pool disk json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("pool_create", |args: PoolCreateArgs| {
+        async move {
+            let pool = Lvs::create_or_import_ext(
+                PoolArgs {
+                    name: args.name,
+                    disks: args.disks,
+                    uuid: args.uuid,
+                },
+                args.options,
+            )
+            .await
+            .map_err(|e| {
+                let code = match e {
+                    Error::DiskSignatures {
+                        ..
+                    }
+                    | Error::DiskLabel {
+                        ..
+                    }
+                    | Error::Invalid {
+                        ..
+                    } => Code::InvalidParams,
+                    _ => Code::InternalError,
+                };
+                JsonRpcError::new(code, e)
+            })?;
+            Ok(PoolDiskInfo::from(&pool))
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("pool_disk_info", |args: PoolDiskInfoArgs| {
+        async move { Ok(PoolDiskInfo::from(&lookup_pool(&args.pool)?)) }
+            .boxed_local()
+    });
+
+    jsonrpc_register("pool_disk_signatures", |args: DiskSignaturesArgs| {
+        async move {
+            UntypedBdev::lookup_by_name(&args.bdev).ok_or_else(|| {
+                JsonRpcError::new(
+                    Code::NotFound,
+                    format!("bdev {} not found", args.bdev),
+                )
+            })?;
+            probe_signatures(&args.bdev)
+                .await
+                .map_err(|e| JsonRpcError::new(Code::InternalError, e))
+        }
+        .boxed_local()
+    });
+}
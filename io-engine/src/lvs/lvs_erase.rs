@@ -0,0 +1,489 @@
+//! Erasure of the space of destroyed pools and replicas.
+//!
+//! The data of a destroyed replica remains on the disk until its clusters
+//! are reused by another replica, and the data of a destroyed pool until the
+//! disk is reused, which compliance requirements on data remanence may not
+//! allow. A pool or a replica can instead be destroyed along with the erasure
+//! of its space, which runs as a background job whose progress is reported:
+//!
+//! - a replica is claimed, so that no nexus can open it, its clusters are
+//!   erased, and it is destroyed once they all are;
+//! - a pool is destroyed, but its disk is kept claimed until it has been erased
+//!   as a whole, and is only released then.
+//!
+//! The space is either deallocated (DEALLOCATE, TRIM or UNMAP), zero-filled,
+//! or, for a pool on a NVMe disk, securely erased by formatting the namespace
+//! with a user data erase. A disk which does not support the method asked
+//! for is zero-filled instead.
+
+use std::{collections::HashMap, convert::TryFrom, pin::Pin};
+
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+use spdk_rs::{libspdk::spdk_nvme_cmd, nvme_admin_opc};
+
+use super::{Error, Lvol, Lvs};
+use crate::{
+    core::{
+        CoreError,
+        IoType,
+        Protocol,
+        Reactors,
+        Share,
+        UntypedBdev,
+        UntypedBdevHandle,
+    },
+    jsonrpc::{jsonrpc_register, Code, RpcErrorCode},
+};
+
+/// Size of the chunks the space is erased in.
+const CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Opcode of the NVMe Format NVM admin command.
+const NVME_OPC_FORMAT_NVM: u8 = 0x80;
+
+/// Secure erase setting of the Format NVM command erasing the user data.
+const NVME_SES_USER_DATA_ERASE: u32 = 1;
+
+/// Namespace of the NVMe bdevs of the pools.
+const NVME_NSID: u32 = 1;
+
+/// Erasure jobs, by target.
+static JOBS: Lazy<Mutex<HashMap<(EraseTarget, String), EraseJob>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)), context(suffix(false)))]
+pub enum EraseError {
+    #[snafu(display("Pool {} not found", name))]
+    PoolNotFound { name: String },
+    #[snafu(display("Replica {} not found", name))]
+    ReplicaNotFound { name: String },
+    #[snafu(display("Replica {} is in use", name))]
+    ReplicaInUse { name: String },
+    #[snafu(display("Replica {} is a snapshot and cannot be erased", name))]
+    ReplicaSnapshot { name: String },
+    #[snafu(display("Erasure of {} is already running", name))]
+    JobRunning { name: String },
+    #[snafu(display("Failed to destroy {}: {}", name, source))]
+    Destroy { source: Error, name: String },
+    #[snafu(display("Failed to erase {}: {}", name, source))]
+    Erase { source: CoreError, name: String },
+}
+
+impl RpcErrorCode for EraseError {
+    fn rpc_error_code(&self) -> Code {
+        match self {
+            EraseError::PoolNotFound {
+                ..
+            }
+            | EraseError::ReplicaNotFound {
+                ..
+            } => Code::NotFound,
+            EraseError::ReplicaInUse {
+                ..
+            }
+            | EraseError::JobRunning {
+                ..
+            } => Code::AlreadyExists,
+            EraseError::ReplicaSnapshot {
+                ..
+            } => Code::InvalidParams,
+            _ => Code::InternalError,
+        }
+    }
+}
+
+/// Method of erasure of the space of a pool or of a replica.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EraseMethod {
+    /// Deallocate the space.
+    Deallocate,
+    /// Fill the space with zeroes.
+    Zero,
+    /// Format the NVMe namespace of a pool with a user data erase.
+    SecureErase,
+}
+
+/// What is erased.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum EraseTarget {
+    Pool,
+    Replica,
+}
+
+/// State of an erasure job.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EraseState {
+    Running,
+    Completed,
+    Failed,
+}
+
+/// Erasure job of a pool or of a replica, along with its progress.
+#[derive(Serialize, Debug, Clone)]
+pub struct EraseJob {
+    pub target: EraseTarget,
+    /// Name of the pool or of the replica.
+    pub name: String,
+    /// Bdev erased.
+    pub bdev: String,
+    pub method: EraseMethod,
+    /// Method the space has been erased with, which differs from the one
+    /// asked for when the disk does not support it.
+    pub applied: Option<EraseMethod>,
+    pub state: EraseState,
+    /// Number of bytes to erase, and erased so far.
+    pub total_bytes: u64,
+    pub done_bytes: u64,
+    pub error: Option<String>,
+}
+
+impl EraseJob {
+    /// Make the progress of the job visible.
+    fn publish(&self) {
+        JOBS.lock()
+            .insert((self.target, self.name.clone()), self.clone());
+    }
+}
+
+/// Returns the erasure job of the given pool or replica.
+pub fn erase_job(target: EraseTarget, name: &str) -> Option<EraseJob> {
+    JOBS.lock().get(&(target, name.to_string())).cloned()
+}
+
+/// Register the job, unless the target is already being erased.
+fn register(job: &EraseJob) -> Result<(), EraseError> {
+    let mut jobs = JOBS.lock();
+    if matches!(
+        jobs.get(&(job.target, job.name.clone())).map(|j| j.state),
+        Some(EraseState::Running)
+    ) {
+        return JobRunning {
+            name: job.name.clone(),
+        }
+        .fail();
+    }
+    jobs.insert((job.target, job.name.clone()), job.clone());
+    Ok(())
+}
+
+/// Securely erase the namespace of the NVMe bdev of the handle.
+async fn secure_erase(handle: &UntypedBdevHandle) -> Result<(), CoreError> {
+    // the format must keep the current LBA format of the namespace
+    let mut identify = handle.dma_malloc(4096).map_err(|_| {
+        CoreError::DmaAllocationFailed {
+            size: 4096,
+        }
+    })?;
+    let mut cmd = spdk_nvme_cmd::default();
+    cmd.set_opc(nvme_admin_opc::IDENTIFY.into());
+    cmd.nsid = NVME_NSID;
+    handle.nvme_admin(&cmd, Some(&mut identify)).await?;
+    let flbas = identify.as_slice()[26] as u32;
+    let lbaf = (flbas & 0xf) | ((flbas >> 5) & 0x3) << 4;
+
+    let mut cmd = spdk_nvme_cmd::default();
+    cmd.set_opc(NVME_OPC_FORMAT_NVM.into());
+    cmd.nsid = NVME_NSID;
+    unsafe {
+        *spdk_rs::libspdk::nvme_cmd_cdw10_get(&mut cmd) =
+            (lbaf & 0xf) | NVME_SES_USER_DATA_ERASE << 9 | (lbaf >> 4) << 12;
+    }
+    handle.nvme_admin(&cmd, None).await
+}
+
+/// Erase the given ranges of the bdev of the handle.
+async fn erase_ranges(
+    job: &mut EraseJob,
+    handle: &UntypedBdevHandle,
+    ranges: &[(u64, u64)],
+) -> Result<(), CoreError> {
+    let bdev = handle.get_bdev();
+
+    if job.method == EraseMethod::SecureErase
+        && job.target == EraseTarget::Pool
+        && bdev.driver() == "nvme"
+    {
+        match secure_erase(handle).await {
+            Ok(()) => {
+                job.applied = Some(EraseMethod::SecureErase);
+                job.done_bytes = job.total_bytes;
+                job.publish();
+                return Ok(());
+            }
+            Err(error) => warn!(
+                "Failed to securely erase {}, zero-filling it instead: {}",
+                job.bdev, error
+            ),
+        }
+    }
+
+    let applied = if job.method == EraseMethod::Deallocate
+        && bdev.io_type_supported(IoType::Unmap)
+    {
+        EraseMethod::Deallocate
+    } else {
+        EraseMethod::Zero
+    };
+    job.applied = Some(applied);
+    job.publish();
+
+    for &(offset, len) in ranges {
+        let mut done = 0;
+        while done < len {
+            let size = CHUNK_SIZE.min(len - done);
+            match applied {
+                EraseMethod::Deallocate => {
+                    handle.unmap_at(offset + done, size).await?
+                }
+                _ => handle.write_zeroes_at(offset + done, size).await?,
+            }
+            done += size;
+            job.done_bytes += size;
+            job.publish();
+        }
+    }
+    Ok(())
+}
+
+/// Publish the outcome of the job.
+fn finish(mut job: EraseJob, result: Result<(), EraseError>) {
+    match result {
+        Ok(()) => {
+            info!(
+                "Erased {:?} {} with {:?}",
+                job.target,
+                job.name,
+                job.applied.unwrap_or(job.method)
+            );
+            job.state = EraseState::Completed;
+        }
+        Err(error) => {
+            error!("Failed to erase {:?} {}: {}", job.target, job.name, error);
+            job.state = EraseState::Failed;
+            job.error = Some(error.to_string());
+        }
+    }
+    job.publish();
+}
+
+/// Erase the disk of a destroyed pool, and release it.
+async fn erase_pool(
+    job: &mut EraseJob,
+    base_bdev: &UntypedBdev,
+) -> Result<(), EraseError> {
+    // the disk is claimed until it has been erased
+    let handle =
+        UntypedBdevHandle::open(&job.bdev, true, true).context(Erase {
+            name: job.name.clone(),
+        })?;
+    let ranges = [(0, job.total_bytes)];
+    erase_ranges(job, &handle, &ranges).await.context(Erase {
+        name: job.name.clone(),
+    })?;
+    drop(handle);
+    Lvs::destroy_base_bdev(base_bdev).await.context(Destroy {
+        name: job.name.clone(),
+    })
+}
+
+/// Erase the given ranges of a replica, and destroy it.
+async fn erase_replica(
+    job: &mut EraseJob,
+    lvol: Lvol,
+    handle: UntypedBdevHandle,
+    ranges: &[(u64, u64)],
+) -> Result<(), EraseError> {
+    erase_ranges(job, &handle, ranges).await.context(Erase {
+        name: job.name.clone(),
+    })?;
+    drop(handle);
+    lvol.destroy().await.context(Destroy {
+        name: job.name.clone(),
+    })?;
+    Ok(())
+}
+
+/// Returns the replica with the given name or uuid.
+fn lookup_replica(replica: &str) -> Result<Lvol, EraseError> {
+    UntypedBdev::lookup_by_name(replica)
+        .or_else(|| UntypedBdev::lookup_by_uuid_str(replica))
+        .and_then(|b| Lvol::try_from(b).ok())
+        .ok_or_else(|| EraseError::ReplicaNotFound {
+            name: replica.to_string(),
+        })
+}
+
+/// Destroy the pool, erasing its disk in the background before releasing
+/// it.
+pub async fn destroy_pool_erased(
+    name: &str,
+    method: EraseMethod,
+) -> Result<EraseJob, EraseError> {
+    let lvs = Lvs::lookup(name).ok_or_else(|| EraseError::PoolNotFound {
+        name: name.to_string(),
+    })?;
+    let base_bdev = lvs.base_bdev();
+    let job = EraseJob {
+        target: EraseTarget::Pool,
+        name: name.to_string(),
+        bdev: base_bdev.name().to_string(),
+        method,
+        applied: None,
+        state: EraseState::Running,
+        total_bytes: base_bdev.size_in_bytes(),
+        done_bytes: 0,
+        error: None,
+    };
+    register(&job)?;
+
+    if let Err(error) = lvs.destruct().await {
+        JOBS.lock().remove(&(job.target, job.name.clone()));
+        return Err(error).context(Destroy {
+            name,
+        });
+    }
+
+    let reply = job.clone();
+    let mut job = job;
+    Reactors::master().send_future(async move {
+        let result = erase_pool(&mut job, &base_bdev).await;
+        finish(job, result);
+    });
+    Ok(reply)
+}
+
+/// Destroy the replica once its clusters have been erased in the
+/// background.
+pub async fn destroy_replica_erased(
+    name: &str,
+    method: EraseMethod,
+) -> Result<EraseJob, EraseError> {
+    let mut lvol = lookup_replica(name)?;
+    let name = lvol.name();
+    if lvol.is_snapshot() {
+        return ReplicaSnapshot {
+            name,
+        }
+        .fail();
+    }
+    if lvol.as_bdev().is_claimed() {
+        return ReplicaInUse {
+            name,
+        }
+        .fail();
+    }
+    if !matches!(lvol.shared(), None | Some(Protocol::Off)) {
+        Pin::new(&mut lvol).unshare().await.context(Destroy {
+            name: name.clone(),
+        })?;
+    }
+
+    // only the clusters of a thin replica hold its data
+    let ranges = if lvol.is_thin() {
+        lvol.allocated_extents()
+    } else {
+        vec![(0, lvol.size())]
+    };
+    let job = EraseJob {
+        target: EraseTarget::Replica,
+        name: name.clone(),
+        bdev: name.clone(),
+        method,
+        applied: None,
+        state: EraseState::Running,
+        total_bytes: ranges.iter().map(|(_, len)| len).sum(),
+        done_bytes: 0,
+        error: None,
+    };
+    // the replica is claimed until it is destroyed
+    let handle = UntypedBdevHandle::open(&name, true, true).context(Erase {
+        name: name.clone(),
+    })?;
+    register(&job)?;
+
+    let reply = job.clone();
+    let mut job = job;
+    Reactors::master().send_future(async move {
+        let result = erase_replica(&mut job, lvol, handle, &ranges).await;
+        finish(job, result);
+    });
+    Ok(reply)
+}
+
+/// Arguments of the pool_destroy json-rpc method.
+#[derive(Debug, Deserialize)]
+struct PoolDestroyArgs {
+    name: String,
+    /// Erase the disk of the pool before releasing it.
+    erase: Option<EraseMethod>,
+}
+
+/// Arguments of the replica_destroy json-rpc method.
+#[derive(Debug, Deserialize)]
+struct ReplicaDestroyArgs {
+    /// Name or uuid of the replica.
+    replica: String,
+    /// Erase the clusters of the replica before destroying it.
+    erase: Option<EraseMethod>,
+}
+
+/// Register the erasure json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("pool_destroy", |args: PoolDestroyArgs| {
+        async move {
+            match args.erase {
+                Some(method) => {
+                    destroy_pool_erased(&args.name, method).await.map(Some)
+                }
+                None => {
+                    let lvs = Lvs::lookup(&args.name).ok_or_else(|| {
+                        EraseError::PoolNotFound {
+                            name: args.name.clone(),
+                        }
+                    })?;
+                    lvs.destroy().await.context(Destroy {
+                        name: args.name,
+                    })?;
+                    Ok(None)
+                }
+            }
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("replica_destroy", |args: ReplicaDestroyArgs| {
+        async move {
+            match args.erase {
+                Some(method) => destroy_replica_erased(&args.replica, method)
+                    .await
+                    .map(Some),
+                None => {
+                    let lvol = lookup_replica(&args.replica)?;
+                    let name = lvol.name();
+                    lvol.destroy().await.context(Destroy {
+                        name,
+                    })?;
+                    Ok(None)
+                }
+            }
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register::<(), _, _, EraseError>("erase_list", |_| {
+        async move {
+            let mut jobs = JOBS.lock().values().cloned().collect::<Vec<_>>();
+            jobs.sort_by(|a, b| a.name.cmp(&b.name));
+            Ok(jobs)
+        }
+        .boxed_local()
+    });
+}
@@ -3,7 +3,11 @@ use snafu::Snafu;
 
 use super::PropName;
 
-use crate::{bdev_api::BdevError, core::CoreError};
+use crate::{
+    bdev_api::BdevError,
+    core::CoreError,
+    store::store_defs::StoreError,
+};
 
 #[derive(Debug, Snafu)]
 #[snafu(visibility(pub(crate)), context(suffix(false)))]
@@ -57,6 +61,16 @@ pub enum Error {
         source: Errno,
         name: String,
     },
+    #[snafu(display("errno: {} failed to resize lvol {}", source, name))]
+    RepResize {
+        source: Errno,
+        name: String,
+    },
+    #[snafu(display("errno: {} failed to create snapshot {}", source, name))]
+    SnapshotCreate {
+        source: Errno,
+        name: String,
+    },
     #[snafu(display("bdev {} is not a lvol", name))]
     NotALvol {
         source: Errno,
@@ -108,4 +122,81 @@ pub enum Error {
     ReplicaShareProtocol {
         value: i32,
     },
+    #[snafu(display(
+        "cannot create {} of {} bytes: pool {} would be committed {:.2} \
+        times its capacity, above its overcommit ratio of {:.2}",
+        name,
+        size,
+        pool,
+        ratio,
+        limit
+    ))]
+    Overcommit {
+        name: String,
+        size: u64,
+        pool: String,
+        ratio: f64,
+        limit: f64,
+    },
+    #[snafu(display(
+        "cannot create {}: pool {} is in maintenance",
+        name,
+        pool
+    ))]
+    PoolMaintenance {
+        name: String,
+        pool: String,
+    },
+    #[snafu(display("cannot create {}: the node is drained", name))]
+    NodeDraining {
+        name: String,
+    },
+    #[snafu(display(
+        "cannot create pool {}: disk {} holds {}, creation must be forced \
+        to wipe them",
+        name,
+        disk,
+        signatures
+    ))]
+    DiskSignatures {
+        name: String,
+        disk: String,
+        signatures: String,
+    },
+    #[snafu(display(
+        "failed to probe disk {} of pool {}: {}",
+        disk,
+        name,
+        source
+    ))]
+    DiskProbe {
+        source: CoreError,
+        name: String,
+        disk: String,
+    },
+    #[snafu(display(
+        "failed to label disk {} of pool {}: {}",
+        disk,
+        name,
+        msg
+    ))]
+    DiskLabel {
+        name: String,
+        disk: String,
+        msg: String,
+    },
+    #[snafu(display(
+        "failed to record the labels of pool {}: {}",
+        name,
+        source
+    ))]
+    PoolLabels {
+        source: StoreError,
+        name: String,
+    },
+    #[snafu(display("cannot share replica {}: {}", name, msg))]
+    TenantQuota {
+        name: String,
+        msg: String,
+    },
 }
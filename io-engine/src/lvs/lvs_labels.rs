@@ -0,0 +1,118 @@
+//! Labels of the pools and of the replicas.
+//!
+//! The labels of a replica are stored on disk, in its metadata. A pool has
+//! no metadata of its own to store them in, so its labels are recorded in
+//! the persistent store, by uuid of the pool, and cached once read.
+
+use std::{collections::HashMap, pin::Pin};
+
+use nix::errno::Errno;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use super::{Error, Lvol, Lvs, PropName, PropValue};
+use crate::{
+    labels::{validate_labels, Labels},
+    persistent_store::PersistentStore,
+    reconcile::node_prefix,
+};
+
+/// Labels of the pools read from or written to the persistent store, by uuid
+/// of the pool.
+static POOL_LABELS: Lazy<Mutex<HashMap<String, Labels>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Key of the record of the labels of the given pool.
+fn pool_labels_key(uuid: &str) -> String {
+    format!("{}/pool-labels/{}", node_prefix(), uuid)
+}
+
+impl Lvs {
+    /// Returns the labels of the pool.
+    pub async fn labels(&self) -> Labels {
+        let uuid = self.uuid();
+        if let Some(labels) = POOL_LABELS.lock().get(&uuid) {
+            return labels.clone();
+        }
+        if !PersistentStore::enabled() {
+            return Labels::new();
+        }
+
+        let labels = PersistentStore::get(&pool_labels_key(&uuid))
+            .await
+            .ok()
+            .and_then(|value| serde_json::from_value::<Labels>(value).ok())
+            .unwrap_or_default();
+        POOL_LABELS.lock().insert(uuid, labels.clone());
+        labels
+    }
+
+    /// Replace the labels of the pool.
+    pub async fn set_labels(&self, labels: &Labels) -> Result<(), Error> {
+        validate_labels(labels).map_err(|msg| Error::Invalid {
+            source: Errno::EINVAL,
+            msg,
+        })?;
+
+        let uuid = self.uuid();
+        if PersistentStore::enabled() {
+            PersistentStore::put(&pool_labels_key(&uuid), labels)
+                .await
+                .map_err(|source| Error::PoolLabels {
+                    source,
+                    name: self.name().to_string(),
+                })?;
+        }
+        info!("{:?}: labels set to {:?}", self, labels);
+        POOL_LABELS.lock().insert(uuid, labels.clone());
+        Ok(())
+    }
+}
+
+/// Forget the labels of a destroyed pool.
+pub(super) async fn forget_pool_labels(uuid: &str) {
+    POOL_LABELS.lock().remove(uuid);
+    if !PersistentStore::enabled() {
+        return;
+    }
+    if let Err(e) = PersistentStore::delete(&pool_labels_key(uuid)).await {
+        warn!("Failed to delete the labels of pool {}: {}", uuid, e);
+    }
+}
+
+impl Lvol {
+    /// Returns the labels of the replica.
+    pub async fn labels(&self) -> Labels {
+        match self.get(PropName::Labels).await {
+            Ok(PropValue::Labels(labels)) if !labels.is_empty() => {
+                serde_json::from_str(&labels).unwrap_or_default()
+            }
+            _ => Labels::new(),
+        }
+    }
+
+    /// Replace the labels of the replica, which are stored on disk.
+    pub async fn set_labels(
+        self: Pin<&mut Self>,
+        labels: &Labels,
+    ) -> Result<(), Error> {
+        if self.is_snapshot() {
+            return Err(Error::Invalid {
+                source: Errno::EINVAL,
+                msg: format!("cannot label snapshot {}", self.name()),
+            });
+        }
+        validate_labels(labels).map_err(|msg| Error::Invalid {
+            source: Errno::EINVAL,
+            msg,
+        })?;
+
+        info!("{:?}: labels set to {:?}", self, labels);
+        let value = if labels.is_empty() {
+            String::new()
+        } else {
+            serde_json::to_string(labels).unwrap()
+        };
+        self.set(PropValue::Labels(value)).await
+    }
+}
@@ -0,0 +1,235 @@
+//! Leases of the replicas.
+//!
+//! A nexus which opens a replica as one of its children takes a lease on
+//! it, recorded in the metadata of the replica along with the node of the
+//! nexus, and renews it for as long as the child is open. While the lease
+//! holds, the replica cannot be opened as a child of another nexus, which
+//! protects it from being written by two nexuses at once, for example when
+//! a nexus is recreated on another node while the previous one is still
+//! running on a node which cannot be reached.
+//!
+//! A lease which is not renewed expires, so that the replica can be used
+//! again once its nexus is gone. A lease can also be broken explicitly, when
+//! the nexus holding it is known to be gone for good.
+
+use std::{
+    convert::TryFrom,
+    pin::Pin,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+
+use super::{Error, Lvol, PropName, PropValue};
+use crate::{
+    core::UntypedBdev,
+    jsonrpc::{jsonrpc_register, Code, RpcErrorCode},
+};
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)), context(suffix(false)))]
+pub enum LeaseError {
+    #[snafu(display("Replica {} not found", name))]
+    ReplicaNotFound { name: String },
+    #[snafu(display(
+        "Replica {} is leased to nexus {} on node {} for {} more seconds",
+        name,
+        nexus,
+        node,
+        remaining
+    ))]
+    LeaseHeld {
+        name: String,
+        nexus: String,
+        node: String,
+        remaining: u64,
+    },
+    #[snafu(display(
+        "Failed to update the lease of replica {}: {}",
+        name,
+        source
+    ))]
+    LeaseUpdate { source: Error, name: String },
+}
+
+impl RpcErrorCode for LeaseError {
+    fn rpc_error_code(&self) -> Code {
+        match self {
+            LeaseError::ReplicaNotFound {
+                ..
+            } => Code::NotFound,
+            LeaseError::LeaseHeld {
+                ..
+            } => Code::AlreadyExists,
+            _ => Code::InternalError,
+        }
+    }
+}
+
+/// Lease of a nexus on a replica.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReplicaLease {
+    /// Uuid of the nexus holding the lease.
+    pub nexus: String,
+    /// Node of the nexus.
+    pub node: String,
+    /// Expiry of the lease, in seconds since the Unix epoch.
+    pub expires: u64,
+}
+
+impl ReplicaLease {
+    /// Returns the number of seconds until the lease expires.
+    pub fn remaining(&self) -> u64 {
+        self.expires.saturating_sub(now())
+    }
+
+    /// Returns true if the lease has expired.
+    pub fn is_expired(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    /// Returns true if the lease is held by the given nexus on the given
+    /// node: a nexus recreated with the same uuid on another node must not
+    /// take over the lease of the previous one.
+    pub fn is_held_by(&self, nexus: &str, node: &str) -> bool {
+        self.nexus == nexus && self.node == node
+    }
+}
+
+/// Returns the current time, in seconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl Lvol {
+    /// Returns the lease on the replica, if any, expired or not.
+    pub async fn lease(&self) -> Option<ReplicaLease> {
+        match self.get(PropName::Lease).await {
+            Ok(PropValue::Lease(lease)) if !lease.is_empty() => {
+                serde_json::from_str(&lease).ok()
+            }
+            _ => None,
+        }
+    }
+
+    /// Record the lease on the replica, or clear it.
+    async fn set_lease(
+        self: Pin<&mut Self>,
+        lease: Option<&ReplicaLease>,
+    ) -> Result<(), LeaseError> {
+        let name = self.name();
+        let value = lease
+            .map(|l| serde_json::to_string(l).unwrap())
+            .unwrap_or_default();
+        self.set(PropValue::Lease(value))
+            .await
+            .context(LeaseUpdate {
+                name,
+            })
+    }
+
+    /// Take or renew the lease of the given nexus on the replica, for the
+    /// given duration, unless another nexus, or the same nexus on another
+    /// node, holds it.
+    pub async fn acquire_lease(
+        self: Pin<&mut Self>,
+        nexus: &str,
+        node: &str,
+        ttl: Duration,
+    ) -> Result<ReplicaLease, LeaseError> {
+        // the lease is read and written on the master core without yielding
+        // in between, the check cannot race with another acquisition
+        if let Some(lease) = self.lease().await {
+            if !lease.is_held_by(nexus, node) && !lease.is_expired() {
+                return LeaseHeld {
+                    name: self.name(),
+                    nexus: lease.nexus,
+                    node: lease.node,
+                    remaining: lease.remaining(),
+                }
+                .fail();
+            }
+        }
+        let lease = ReplicaLease {
+            nexus: nexus.to_string(),
+            node: node.to_string(),
+            expires: now() + ttl.as_secs(),
+        };
+        self.set_lease(Some(&lease)).await?;
+        Ok(lease)
+    }
+
+    /// Give up the lease of the given nexus on the replica, if it holds it
+    /// from the given node.
+    pub async fn release_lease(
+        self: Pin<&mut Self>,
+        nexus: &str,
+        node: &str,
+    ) -> Result<(), LeaseError> {
+        match self.lease().await {
+            Some(lease) if lease.is_held_by(nexus, node) => {
+                self.set_lease(None).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Break the lease on the replica, whichever nexus holds it, returning
+    /// the lease broken.
+    pub async fn break_lease(
+        self: Pin<&mut Self>,
+    ) -> Result<Option<ReplicaLease>, LeaseError> {
+        let lease = self.lease().await;
+        if let Some(lease) = &lease {
+            warn!(
+                "Breaking the lease of nexus {} on node {} on replica {}",
+                lease.nexus,
+                lease.node,
+                self.name()
+            );
+            self.set_lease(None).await?;
+        }
+        Ok(lease)
+    }
+}
+
+/// Returns the replica with the given name or uuid.
+fn lookup_replica(replica: &str) -> Result<Lvol, LeaseError> {
+    UntypedBdev::lookup_by_name(replica)
+        .or_else(|| UntypedBdev::lookup_by_uuid_str(replica))
+        .and_then(|b| Lvol::try_from(b).ok())
+        .ok_or_else(|| LeaseError::ReplicaNotFound {
+            name: replica.to_string(),
+        })
+}
+
+/// Arguments of the replica lease json-rpc methods.
+#[derive(Debug, Deserialize)]
+struct ReplicaLeaseArgs {
+    /// Name or uuid of the replica.
+    replica: String,
+}
+
+/// Register the lease json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("replica_lease_get", |args: ReplicaLeaseArgs| {
+        async move {
+            let lvol = lookup_replica(&args.replica)?;
+            Ok(lvol.lease().await)
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("replica_lease_break", |args: ReplicaLeaseArgs| {
+        async move {
+            let mut lvol = lookup_replica(&args.replica)?;
+            Pin::new(&mut lvol).break_lease().await
+        }
+        .boxed_local()
+    });
+}
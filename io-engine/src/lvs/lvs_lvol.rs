@@ -18,20 +18,28 @@ use spdk_rs::libspdk::{
     spdk_bdev_io_get_thread,
     spdk_blob,
     spdk_blob_calc_used_clusters,
+    spdk_blob_get_id,
+    spdk_blob_get_next_allocated_io_unit,
+    spdk_blob_get_next_unallocated_io_unit,
     spdk_blob_get_num_clusters,
+    spdk_blob_get_parent_snapshot,
     spdk_blob_get_xattr_value,
+    spdk_blob_is_clone,
     spdk_blob_is_read_only,
     spdk_blob_is_snapshot,
     spdk_blob_set_xattr,
     spdk_blob_sync_md,
     spdk_bs_get_cluster_size,
+    spdk_bs_get_io_unit_size,
     spdk_lvol,
     spdk_nvmf_request_complete,
     vbdev_lvol_create_snapshot,
     vbdev_lvol_destroy,
     vbdev_lvol_get_from_bdev,
+    vbdev_lvol_resize,
     LVS_CLEAR_WITH_UNMAP,
     SPDK_BDEV_LARGE_BUF_MAX_SIZE,
+    SPDK_BLOBID_INVALID,
 };
 
 use super::{Error, Lvs};
@@ -56,7 +64,9 @@ use crate::{
         FfiResult,
         IntoCString,
     },
+    reconcile,
     subsys::NvmfReq,
+    tenant::{check_quota, tenant_of, TenantUsage},
 };
 
 // Wipe `WIPE_SUPER_LEN` bytes if unmap is not supported.
@@ -69,6 +79,15 @@ pub(crate) const WIPE_SUPER_LEN: u64 = (1 << 20) * 8;
 pub enum PropValue {
     Shared(bool),
     AllowedHosts(Vec<String>),
+    Alias(String),
+    /// Uuid of the replica whose data a compaction copy holds.
+    CompactSource(String),
+    /// Lease of the nexus using the replica, empty if none.
+    Lease(String),
+    /// Snapshot schedule of the replica, empty if none.
+    SnapshotSchedule(String),
+    /// Labels of the replica, as a json object, empty if none.
+    Labels(String),
 }
 
 #[derive(Debug)]
@@ -76,6 +95,11 @@ pub enum PropValue {
 pub enum PropName {
     Shared,
     AllowedHosts,
+    Alias,
+    CompactSource,
+    Lease,
+    SnapshotSchedule,
+    Labels,
 }
 
 impl From<&PropValue> for PropName {
@@ -83,6 +107,11 @@ impl From<&PropValue> for PropName {
         match v {
             PropValue::Shared(_) => Self::Shared,
             PropValue::AllowedHosts(_) => Self::AllowedHosts,
+            PropValue::Alias(_) => Self::Alias,
+            PropValue::CompactSource(_) => Self::CompactSource,
+            PropValue::Lease(_) => Self::Lease,
+            PropValue::SnapshotSchedule(_) => Self::SnapshotSchedule,
+            PropValue::Labels(_) => Self::Labels,
         }
     }
 }
@@ -97,6 +126,11 @@ impl Display for PropName {
         let name = match self {
             PropName::Shared => "shared",
             PropName::AllowedHosts => "allowed-hosts",
+            PropName::Alias => "alias",
+            PropName::CompactSource => "compact-source",
+            PropName::Lease => "lease",
+            PropName::SnapshotSchedule => "snapshot-schedule",
+            PropName::Labels => "labels",
         };
         write!(f, "{}", name)
     }
@@ -166,6 +200,16 @@ impl Share for Lvol {
         mut self: Pin<&mut Self>,
         props: Option<ShareProps>,
     ) -> Result<Self::Output, Self::Error> {
+        if self.shared().is_none() {
+            if let Some(tenant) = tenant_of(&self.labels().await) {
+                check_quota(tenant, &TenantUsage::shared_target())
+                    .await
+                    .map_err(|msg| Error::TenantQuota {
+                        name: self.name(),
+                        msg,
+                    })?;
+            }
+        }
         let allowed_hosts = props
             .as_ref()
             .map(|s| s.allowed_hosts().clone())
@@ -318,6 +362,90 @@ impl Lvol {
         }
     }
 
+    /// Returns the ranges of the lvol which are allocated in its own
+    /// clusters, as (offset, length) in bytes. Ranges allocated in the
+    /// clusters of the parent of a clone are not included.
+    pub fn allocated_extents(&self) -> Vec<(u64, u64)> {
+        let bs = self.lvs().blob_store();
+        let blob = self.blob_checked();
+        let mut extents = Vec::new();
+        unsafe {
+            let io_unit_size = spdk_bs_get_io_unit_size(bs) as u64;
+            let end = spdk_blob_get_num_clusters(blob)
+                * spdk_bs_get_cluster_size(bs)
+                / io_unit_size;
+            let mut offset = 0;
+            while offset < end {
+                let start = spdk_blob_get_next_allocated_io_unit(blob, offset);
+                if start >= end {
+                    break;
+                }
+                let stop = spdk_blob_get_next_unallocated_io_unit(blob, start)
+                    .min(end);
+                extents.push((
+                    start * io_unit_size,
+                    (stop - start) * io_unit_size,
+                ));
+                offset = stop;
+            }
+        }
+        extents
+    }
+
+    /// Returns the snapshot this lvol has been cloned from, if any.
+    pub fn parent_snapshot(&self) -> Option<Lvol> {
+        let parent = unsafe {
+            spdk_blob_get_parent_snapshot(
+                self.lvs().blob_store(),
+                spdk_blob_get_id(self.blob_checked()),
+            )
+        };
+        if parent == SPDK_BLOBID_INVALID {
+            return None;
+        }
+        self.lvs()
+            .lvols()?
+            .find(|l| unsafe { spdk_blob_get_id(l.blob_checked()) == parent })
+    }
+
+    /// Returns the ranges of the lvol which may differ from the given
+    /// snapshot it descends from, as (offset, length) in bytes: the ranges
+    /// allocated in the clusters of the lvol and of its ancestors up to the
+    /// given snapshot. Without a snapshot, all the ranges holding data are
+    /// returned. Returns None if the lvol does not descend from the snapshot.
+    pub fn allocated_extents_since(
+        &self,
+        base: Option<&Lvol>,
+    ) -> Option<Vec<(u64, u64)>> {
+        let mut extents = Vec::new();
+        let mut current =
+            Some(Self::from_inner_ptr(unsafe { self.as_inner_ptr() }));
+        loop {
+            match (&current, base) {
+                (Some(lvol), Some(base)) if lvol.uuid() == base.uuid() => break,
+                (Some(lvol), _) => {
+                    extents.extend(lvol.allocated_extents());
+                    current = lvol.parent_snapshot();
+                }
+                (None, Some(_)) => return None,
+                (None, None) => break,
+            }
+        }
+
+        // merge the ranges of the whole chain
+        extents.sort_unstable();
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(extents.len());
+        for (offset, len) in extents {
+            match merged.last_mut() {
+                Some((last, last_len)) if *last + *last_len >= offset => {
+                    *last_len = (*last_len).max(offset + len - *last);
+                }
+                _ => merged.push((offset, len)),
+            }
+        }
+        Some(merged)
+    }
+
     /// returns the name of the bdev
     pub fn name(&self) -> String {
         self.as_bdev().name().to_string()
@@ -402,6 +530,11 @@ impl Lvol {
         unsafe { spdk_blob_is_snapshot(self.blob_checked()) }
     }
 
+    /// returns a boolean indicating if the lvol is a clone of a snapshot
+    pub fn is_clone(&self) -> bool {
+        unsafe { spdk_blob_is_clone(self.blob_checked()) }
+    }
+
     /// destroy the lvol
     pub async fn destroy(mut self) -> Result<String, Error> {
         extern "C" fn destroy_cb(sender: *mut c_void, errno: i32) {
@@ -414,6 +547,7 @@ impl Lvol {
         let _ = Pin::new(&mut self).unshare().await;
 
         let name = self.name();
+        let uuid = self.uuid();
         let ptpl = self.ptpl();
 
         let (s, r) = pair::<i32>();
@@ -438,10 +572,42 @@ impl Lvol {
             );
         }
 
+        reconcile::forget_replica(&uuid);
+
         info!("destroyed lvol {}", name);
         Ok(name)
     }
 
+    /// Resize the lvol to the given size in bytes, rounded up to a whole
+    /// number of clusters. Shrinking discards the clusters past the new size,
+    /// and fails if the lvol is open.
+    pub async fn resize(&self, size: u64) -> Result<(), Error> {
+        extern "C" fn resize_cb(sender: *mut c_void, errno: i32) {
+            let sender =
+                unsafe { Box::from_raw(sender as *mut oneshot::Sender<i32>) };
+            sender.send(errno).unwrap();
+        }
+
+        let (s, r) = pair::<i32>();
+        unsafe {
+            vbdev_lvol_resize(
+                self.as_inner_ptr(),
+                size,
+                Some(resize_cb),
+                cb_arg(s),
+            )
+        };
+
+        r.await
+            .expect("lvol resize callback is gone")
+            .to_result(|e| Error::RepResize {
+                source: Errno::from_i32(e),
+                name: self.name(),
+            })?;
+        info!("resized lvol {} to {} bytes", self.name(), self.size());
+        Ok(())
+    }
+
     /// callback executed after synchronizing the lvols metadata
     extern "C" fn blob_sync_cb(sender_ptr: *mut c_void, errno: i32) {
         let sender =
@@ -498,6 +664,27 @@ impl Lvol {
                     name: self.name(),
                 })?;
             }
+            PropValue::Alias(value)
+            | PropValue::CompactSource(value)
+            | PropValue::Lease(value)
+            | PropValue::SnapshotSchedule(value)
+            | PropValue::Labels(value) => {
+                let name = PropName::from(&prop).to_string().into_cstring();
+                let value = value.into_cstring();
+                unsafe {
+                    spdk_blob_set_xattr(
+                        blob,
+                        name.as_ptr(),
+                        value.as_bytes_with_nul().as_ptr() as *const _,
+                        value.as_bytes_with_nul().len() as u16,
+                    )
+                }
+                .to_result(|e| Error::SetProperty {
+                    source: Errno::from_i32(e),
+                    prop: prop.into(),
+                    name: self.name(),
+                })?;
+            }
         }
         Ok(())
     }
@@ -602,6 +789,201 @@ impl Lvol {
                     }),
                 }
             }
+            PropName::Alias => {
+                let name = prop.to_string().into_cstring();
+                let mut value: *const libc::c_char =
+                    std::ptr::null::<libc::c_char>();
+                let mut value_len: u64 = 0;
+                unsafe {
+                    spdk_blob_get_xattr_value(
+                        blob,
+                        name.as_ptr(),
+                        &mut value as *mut *const c_char as *mut *const c_void,
+                        &mut value_len,
+                    )
+                }
+                .to_result(|e| Error::GetProperty {
+                    source: Errno::from_i32(e),
+                    prop,
+                    name: self.name(),
+                })?;
+                match unsafe { CStr::from_ptr(value).to_str() } {
+                    Ok(alias) => Ok(PropValue::Alias(alias.to_string())),
+                    _ => Err(Error::Property {
+                        source: Errno::EINVAL,
+                        name: self.name(),
+                    }),
+                }
+            }
+            PropName::CompactSource => {
+                let name = prop.to_string().into_cstring();
+                let mut value: *const libc::c_char =
+                    std::ptr::null::<libc::c_char>();
+                let mut value_len: u64 = 0;
+                unsafe {
+                    spdk_blob_get_xattr_value(
+                        blob,
+                        name.as_ptr(),
+                        &mut value as *mut *const c_char as *mut *const c_void,
+                        &mut value_len,
+                    )
+                }
+                .to_result(|e| Error::GetProperty {
+                    source: Errno::from_i32(e),
+                    prop,
+                    name: self.name(),
+                })?;
+                match unsafe { CStr::from_ptr(value).to_str() } {
+                    Ok(uuid) => Ok(PropValue::CompactSource(uuid.to_string())),
+                    _ => Err(Error::Property {
+                        source: Errno::EINVAL,
+                        name: self.name(),
+                    }),
+                }
+            }
+            PropName::Lease => {
+                let name = prop.to_string().into_cstring();
+                let mut value: *const libc::c_char =
+                    std::ptr::null::<libc::c_char>();
+                let mut value_len: u64 = 0;
+                unsafe {
+                    spdk_blob_get_xattr_value(
+                        blob,
+                        name.as_ptr(),
+                        &mut value as *mut *const c_char as *mut *const c_void,
+                        &mut value_len,
+                    )
+                }
+                .to_result(|e| Error::GetProperty {
+                    source: Errno::from_i32(e),
+                    prop,
+                    name: self.name(),
+                })?;
+                match unsafe { CStr::from_ptr(value).to_str() } {
+                    Ok(lease) => Ok(PropValue::Lease(lease.to_string())),
+                    _ => Err(Error::Property {
+                        source: Errno::EINVAL,
+                        name: self.name(),
+                    }),
+                }
+            }
+            PropName::SnapshotSchedule => {
+                let name = prop.to_string().into_cstring();
+                let mut value: *const libc::c_char =
+                    std::ptr::null::<libc::c_char>();
+                let mut value_len: u64 = 0;
+                unsafe {
+                    spdk_blob_get_xattr_value(
+                        blob,
+                        name.as_ptr(),
+                        &mut value as *mut *const c_char as *mut *const c_void,
+                        &mut value_len,
+                    )
+                }
+                .to_result(|e| Error::GetProperty {
+                    source: Errno::from_i32(e),
+                    prop,
+                    name: self.name(),
+                })?;
+                match unsafe { CStr::from_ptr(value).to_str() } {
+                    Ok(schedule) => {
+                        Ok(PropValue::SnapshotSchedule(schedule.to_string()))
+                    }
+                    _ => Err(Error::Property {
+                        source: Errno::EINVAL,
+                        name: self.name(),
+                    }),
+                }
+            }
+            PropName::Labels => {
+                let name = prop.to_string().into_cstring();
+                let mut value: *const libc::c_char =
+                    std::ptr::null::<libc::c_char>();
+                let mut value_len: u64 = 0;
+                unsafe {
+                    spdk_blob_get_xattr_value(
+                        blob,
+                        name.as_ptr(),
+                        &mut value as *mut *const c_char as *mut *const c_void,
+                        &mut value_len,
+                    )
+                }
+                .to_result(|e| Error::GetProperty {
+                    source: Errno::from_i32(e),
+                    prop,
+                    name: self.name(),
+                })?;
+                match unsafe { CStr::from_ptr(value).to_str() } {
+                    Ok(labels) => Ok(PropValue::Labels(labels.to_string())),
+                    _ => Err(Error::Property {
+                        source: Errno::EINVAL,
+                        name: self.name(),
+                    }),
+                }
+            }
+        }
+    }
+
+    /// returns the alias of the lvol, if it has been renamed
+    pub async fn alias(&self) -> Option<String> {
+        match self.get(PropName::Alias).await {
+            Ok(PropValue::Alias(alias)) if !alias.is_empty() => Some(alias),
+            _ => None,
+        }
+    }
+
+    /// Rename the lvol: the alias becomes a human-readable name by which the
+    /// lvol can be looked up, in addition to its name and uuid which remain
+    /// stable. The alias is stored on disk and added back when the pool is
+    /// imported. A `None` alias removes the current one.
+    pub async fn rename(
+        mut self: Pin<&mut Self>,
+        alias: Option<String>,
+    ) -> Result<(), Error> {
+        let previous = self.alias().await;
+        if previous == alias {
+            return Ok(());
+        }
+        if self.is_snapshot() {
+            return Err(Error::Invalid {
+                source: Errno::EINVAL,
+                msg: format!("cannot rename snapshot {}", self.name()),
+            });
+        }
+
+        let mut bdev = self.as_bdev();
+        if let Some(alias) = &alias {
+            if UntypedBdev::lookup_by_name(alias).is_some()
+                || !bdev.add_alias(alias)
+            {
+                return Err(Error::RepExists {
+                    source: Errno::EEXIST,
+                    name: alias.clone(),
+                });
+            }
+        }
+
+        let value = alias.clone().unwrap_or_default();
+        if let Err(error) = self.as_mut().set(PropValue::Alias(value)).await {
+            if let Some(alias) = &alias {
+                bdev.remove_alias(alias);
+            }
+            return Err(error);
+        }
+        if let Some(previous) = &previous {
+            bdev.remove_alias(previous);
+        }
+
+        info!("{:?}: renamed from {:?} to {:?}", self, previous, alias);
+        Ok(())
+    }
+
+    /// add the alias stored on disk to the bdev of the lvol
+    pub(crate) async fn restore_alias(&self) {
+        if let Some(alias) = self.alias().await {
+            if !self.as_bdev().add_alias(&alias) {
+                warn!("{:?}: failed to restore alias {}", self, alias);
+            }
         }
     }
 
@@ -611,6 +993,23 @@ impl Lvol {
         format!("{}-snap-{}", base_name, snapshot_time)
     }
 
+    /// Format the name of a snapshot of a group snapshot, whose sequence
+    /// number tells apart the group snapshots taken within the same second
+    pub fn format_group_snapshot_name(
+        base_name: &str,
+        snapshot_time: u64,
+        seq: u32,
+    ) -> String {
+        match seq {
+            0 => Self::format_snapshot_name(base_name, snapshot_time),
+            seq => format!(
+                "{}-{}",
+                Self::format_snapshot_name(base_name, snapshot_time),
+                seq
+            ),
+        }
+    }
+
     /// Create a snapshot
     pub async fn create_snapshot(
         &self,
@@ -688,6 +1087,44 @@ impl Lvol {
         info!("{:?}: creating snapshot '{}'", self, snapshot_name);
     }
 
+    /// Create a snapshot of the lvol with the given name and wait for its
+    /// completion. Must be called on the master core.
+    pub async fn snapshot(&self, snapshot_name: &str) -> Result<(), Error> {
+        extern "C" fn snapshot_done_cb(
+            sender: *mut c_void,
+            _lvol_ptr: *mut spdk_lvol,
+            errno: i32,
+        ) {
+            let sender =
+                unsafe { Box::from_raw(sender as *mut oneshot::Sender<i32>) };
+            sender.send(errno).unwrap();
+        }
+
+        let c_snapshot_name = snapshot_name.into_cstring();
+        let (s, r) = pair::<i32>();
+        unsafe {
+            vbdev_lvol_create_snapshot(
+                self.as_inner_ptr(),
+                c_snapshot_name.as_ptr(),
+                Some(snapshot_done_cb),
+                cb_arg(s),
+            )
+        };
+
+        r.await
+            .expect("lvol snapshot callback is gone")
+            .to_result(|e| {
+                error!("vbdev_lvol_create_snapshot errno {}", e);
+                Error::SnapshotCreate {
+                    source: Errno::from_i32(e),
+                    name: snapshot_name.to_string(),
+                }
+            })?;
+
+        info!("{:?}: created snapshot '{}'", self, snapshot_name);
+        Ok(())
+    }
+
     /// Get a `PtplFileOps` from `&self`.
     pub(crate) fn ptpl(&self) -> impl PtplFileOps {
         LvolPtpl::from(self)
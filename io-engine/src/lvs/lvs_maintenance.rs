@@ -0,0 +1,297 @@
+//! Maintenance mode of pools.
+//!
+//! Before the disk of a pool is retired, the control plane puts the pool in
+//! maintenance: no new replica is created on it, and its replicas are
+//! flagged for migration with the `openebs.io/migrate` label, which is
+//! stored on disk with the other labels of the replicas, so that the
+//! control plane moves them elsewhere at its own pace. The background jobs
+//! of the pool, compaction, dedupe scans and snapshot schedules, can be
+//! paused as well, so that they do not compete with the migration for the
+//! disk.
+//!
+//! A pool in maintenance is reported as degraded, and entering or leaving
+//! maintenance is published as a pool event. The maintenance of a pool lasts
+//! until it is ended or until the io-engine restarts; the replicas keep
+//! their label until the pool leaves maintenance.
+
+use std::{collections::HashMap, pin::Pin, time::Duration};
+
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use super::{lvs_overcommit::lookup_pool, Error, Lvs};
+use crate::{
+    events::{publish, EventCategory, EventSeverity},
+    jsonrpc::{jsonrpc_register, Code, JsonRpcError},
+    sleep::mayastor_sleep,
+};
+
+/// Label flagging a replica for migration off its pool.
+pub const MIGRATE_LABEL: &str = "openebs.io/migrate";
+
+/// Value of the migration label of the replicas of a pool in maintenance.
+const MIGRATE_MAINTENANCE: &str = "pool-maintenance";
+
+/// Interval at which a paused background job checks whether it can resume.
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Pools in maintenance, by name.
+static MAINTENANCE: Lazy<Mutex<HashMap<String, PoolMaintenance>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Maintenance of a pool.
+#[derive(Serialize, Debug, Clone)]
+pub struct PoolMaintenance {
+    pub pool: String,
+    /// The background jobs of the pool are paused.
+    pub pause_jobs: bool,
+    pub reason: Option<String>,
+    /// Time the pool entered maintenance, in RFC 3339 format.
+    pub since: String,
+    /// Replicas flagged for migration, and those which could not be.
+    pub flagged: Vec<String>,
+    pub unflagged: Vec<String>,
+}
+
+impl PoolMaintenance {
+    /// Returns the maintenance of the given pool, if it is in maintenance.
+    pub fn get(pool: &str) -> Option<Self> {
+        MAINTENANCE.lock().get(pool).cloned()
+    }
+}
+
+/// Returns true if the given pool is in maintenance.
+pub fn in_maintenance(pool: &str) -> bool {
+    MAINTENANCE.lock().contains_key(pool)
+}
+
+/// Refuse a replica created on a pool in maintenance.
+pub(super) fn check_maintenance(lvs: &Lvs, name: &str) -> Result<(), Error> {
+    if in_maintenance(lvs.name()) {
+        return Err(Error::PoolMaintenance {
+            name: name.to_string(),
+            pool: lvs.name().to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Returns true if the background jobs of the given pool are paused.
+pub(super) fn jobs_paused(pool: &str) -> bool {
+    MAINTENANCE.lock().get(pool).map_or(false, |m| m.pause_jobs)
+}
+
+/// Wait until the background jobs of the given pool are resumed, failing
+/// with the given check, e.g. when the job is aborted in the meantime.
+pub(super) async fn wait_jobs_resumed<E>(
+    pool: &str,
+    check: impl Fn() -> Result<(), E>,
+) -> Result<(), E> {
+    while jobs_paused(pool) {
+        check()?;
+        let _ = mayastor_sleep(PAUSE_POLL_INTERVAL).await;
+    }
+    Ok(())
+}
+
+/// Set or remove the migration label of the replicas of the pool. Returns
+/// the replicas labelled, and those which could not be.
+async fn flag_replicas(lvs: &Lvs, flag: bool) -> (Vec<String>, Vec<String>) {
+    let mut flagged = Vec::new();
+    let mut unflagged = Vec::new();
+    let lvols = lvs
+        .lvols()
+        .map(|l| l.filter(|l| !l.is_snapshot()).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    for mut lvol in lvols {
+        let mut labels = lvol.labels().await;
+        let changed = if flag {
+            labels
+                .insert(MIGRATE_LABEL.to_string(), MIGRATE_MAINTENANCE.into())
+                .as_deref()
+                != Some(MIGRATE_MAINTENANCE)
+        } else {
+            labels.remove(MIGRATE_LABEL).is_some()
+        };
+        if !changed {
+            flagged.push(lvol.name());
+            continue;
+        }
+        match Pin::new(&mut lvol).set_labels(&labels).await {
+            Ok(()) => flagged.push(lvol.name()),
+            Err(error) => {
+                warn!(
+                    "Failed to update the migration label of replica {} of \
+                    pool {}: {}",
+                    lvol.name(),
+                    lvs.name(),
+                    error
+                );
+                unflagged.push(lvol.name());
+            }
+        }
+    }
+    (flagged, unflagged)
+}
+
+/// Put the pool in maintenance, or update its maintenance.
+pub async fn start_maintenance(
+    lvs: &Lvs,
+    pause_jobs: bool,
+    reason: Option<String>,
+) -> PoolMaintenance {
+    let pool = lvs.name().to_string();
+    let since = PoolMaintenance::get(&pool)
+        .map_or_else(|| chrono::Utc::now().to_rfc3339(), |m| m.since);
+    let started = !in_maintenance(&pool);
+
+    // replicas are refused from now on, before the existing ones are flagged
+    MAINTENANCE.lock().insert(
+        pool.clone(),
+        PoolMaintenance {
+            pool: pool.clone(),
+            pause_jobs,
+            reason: reason.clone(),
+            since: since.clone(),
+            flagged: Vec::new(),
+            unflagged: Vec::new(),
+        },
+    );
+    let (flagged, unflagged) = flag_replicas(lvs, true).await;
+    let maintenance = PoolMaintenance {
+        pool: pool.clone(),
+        pause_jobs,
+        reason,
+        since,
+        flagged,
+        unflagged,
+    };
+    // the maintenance may have been ended while the replicas were flagged
+    if let Some(m) = MAINTENANCE.lock().get_mut(&pool) {
+        *m = maintenance.clone();
+    }
+
+    if started {
+        publish(
+            EventCategory::Pool,
+            if maintenance.unflagged.is_empty() {
+                EventSeverity::Info
+            } else {
+                EventSeverity::Warning
+            },
+            "maintenance_started",
+            &pool,
+            format!(
+                "pool in maintenance{}, {} replicas flagged for migration{}, \
+                background jobs {}",
+                maintenance
+                    .reason
+                    .as_ref()
+                    .map_or_else(String::new, |r| format!(" ({})", r)),
+                maintenance.flagged.len(),
+                match maintenance.unflagged.len() {
+                    0 => String::new(),
+                    n => format!(", {} could not be", n),
+                },
+                if pause_jobs { "paused" } else { "running" }
+            ),
+        );
+    }
+    maintenance
+}
+
+/// Take the pool out of maintenance, returns false if it was not in
+/// maintenance.
+pub async fn end_maintenance(lvs: &Lvs) -> bool {
+    let pool = lvs.name().to_string();
+    if MAINTENANCE.lock().remove(&pool).is_none() {
+        return false;
+    }
+    let (_, unflagged) = flag_replicas(lvs, false).await;
+    publish(
+        EventCategory::Pool,
+        if unflagged.is_empty() {
+            EventSeverity::Info
+        } else {
+            EventSeverity::Warning
+        },
+        "maintenance_ended",
+        &pool,
+        match unflagged.len() {
+            0 => "pool out of maintenance".to_string(),
+            n => format!(
+                "pool out of maintenance, {} replicas are still flagged for \
+                migration",
+                n
+            ),
+        },
+    );
+    true
+}
+
+/// Forget the maintenance of a pool which is gone.
+pub(super) fn forget_maintenance(pool: &str) {
+    MAINTENANCE.lock().remove(pool);
+}
+
+/// Arguments of the pool_maintenance_set json-rpc method.
+#[derive(Debug, Deserialize)]
+struct MaintenanceSetArgs {
+    pool: String,
+    /// Put the pool in maintenance, or take it out of maintenance.
+    enabled: bool,
+    /// Pause the background jobs of the pool while in maintenance.
+    #[serde(default)]
+    pause_jobs: bool,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+/// Arguments of the pool_maintenance json-rpc method.
+#[derive(Debug, Deserialize)]
+struct MaintenanceArgs {
+    pool: String,
+}
+
+/// Register the maintenance json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("pool_maintenance_set", |args: MaintenanceSetArgs| {
+        async move {
+            let lvs = lookup_pool(&args.pool)?;
+            if args.enabled {
+                Ok(Some(
+                    start_maintenance(&lvs, args.pause_jobs, args.reason).await,
+                ))
+            } else if end_maintenance(&lvs).await {
+                Ok(None)
+            } else {
+                Err(JsonRpcError::new(
+                    Code::InvalidParams,
+                    format!("Pool {} is not in maintenance", args.pool),
+                ))
+            }
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("pool_maintenance", |args: MaintenanceArgs| {
+        async move {
+            let lvs = lookup_pool(&args.pool)?;
+            Ok(PoolMaintenance::get(lvs.name()))
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register::<(), _, _, JsonRpcError>("pool_maintenance_list", |_| {
+        async move {
+            let mut list =
+                MAINTENANCE.lock().values().cloned().collect::<Vec<_>>();
+            list.sort_by(|a, b| a.pool.cmp(&b.pool));
+            Ok(list)
+        }
+        .boxed_local()
+    });
+}
@@ -0,0 +1,304 @@
+//! Overcommit policy and usage alerts of pools.
+//!
+//! Thin replicas only allocate space as they are written, so the capacity
+//! promised to the replicas of a pool can exceed its physical capacity. The
+//! overcommit ratio of a pool bounds the capacity of its replicas, thin or
+//! not, to a multiple of its physical capacity: a replica which would take
+//! the pool beyond it is refused. Without a ratio, replicas are only limited
+//! by the physical capacity of the pool.
+//!
+//! As thin replicas are written the pool fills up, possibly to the point
+//! where the writes fail. The physical usage of the pools is checked
+//! periodically, and after each replica creation, against the usage
+//! watermarks, in percent: crossing a watermark raises an alert, which is
+//! logged and kept in a short history, and clears it again once the usage
+//! goes back below it.
+//!
+//! The defaults of the policy are given on the command line, and can be
+//! changed per pool, until the io-engine restarts.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use super::{Error, Lvs};
+use crate::{
+    core::{MayastorEnvironment, Reactors},
+    jsonrpc::{jsonrpc_register, Code, JsonRpcError},
+    sleep::mayastor_sleep,
+};
+
+/// Interval at which the usage of the pools is checked.
+const USAGE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Number of alerts kept in the history.
+const MAX_ALERTS: usize = 256;
+
+/// Policies of the pools which have been changed from the defaults.
+static POLICIES: Lazy<Mutex<HashMap<String, OvercommitPolicy>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Highest watermark the usage of each pool is above.
+static LEVELS: Lazy<Mutex<HashMap<String, u8>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Most recent alerts, oldest first.
+static ALERTS: Lazy<Mutex<VecDeque<PoolAlert>>> =
+    Lazy::new(|| Mutex::new(VecDeque::new()));
+
+/// Overcommit policy of a pool.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OvercommitPolicy {
+    /// Maximum ratio of the capacity of the replicas to the capacity of the
+    /// pool, unbounded if not set.
+    pub ratio: Option<f64>,
+    /// Physical usage watermarks, in percent, in increasing order.
+    pub watermarks: Vec<u8>,
+}
+
+impl Default for OvercommitPolicy {
+    fn default() -> Self {
+        let env = MayastorEnvironment::global_or_default();
+        Self {
+            ratio: env.overcommit_ratio(),
+            watermarks: env.pool_watermarks(),
+        }
+    }
+}
+
+impl OvercommitPolicy {
+    /// Returns the policy of the given pool.
+    pub fn get(pool: &str) -> Self {
+        POLICIES.lock().get(pool).cloned().unwrap_or_default()
+    }
+}
+
+/// Alert raised or cleared as the usage of a pool crosses a watermark.
+#[derive(Serialize, Debug, Clone)]
+pub struct PoolAlert {
+    pub pool: String,
+    /// Watermark crossed, in percent.
+    pub watermark: u8,
+    /// The usage went above the watermark, or back below it.
+    pub raised: bool,
+    /// Physical usage of the pool, in percent.
+    pub usage: f64,
+    /// Time of the alert, in RFC 3339 format.
+    pub time: String,
+}
+
+/// Capacity and overcommit of a pool.
+#[derive(Serialize, Debug, Clone)]
+pub struct OvercommitStatus {
+    pub pool: String,
+    pub policy: OvercommitPolicy,
+    /// Physical capacity and usage, in bytes.
+    pub capacity: u64,
+    pub used: u64,
+    /// Capacity of the replicas, in bytes.
+    pub committed: u64,
+    /// Ratio of the capacity of the replicas to the physical capacity.
+    pub overcommit: f64,
+    /// Physical usage, in percent.
+    pub usage: f64,
+    /// Highest watermark the usage is above.
+    pub watermark: Option<u8>,
+}
+
+impl OvercommitStatus {
+    fn new(lvs: &Lvs) -> Self {
+        let capacity = lvs.capacity();
+        let committed = lvs.committed();
+        Self {
+            pool: lvs.name().to_string(),
+            policy: OvercommitPolicy::get(lvs.name()),
+            capacity,
+            used: lvs.used(),
+            committed,
+            overcommit: ratio(committed, capacity),
+            usage: 100.0 * ratio(lvs.used(), capacity),
+            watermark: LEVELS.lock().get(lvs.name()).cloned(),
+        }
+    }
+}
+
+/// Returns the ratio of a to b, or 0 if b is 0.
+fn ratio(a: u64, b: u64) -> f64 {
+    if b == 0 {
+        0.0
+    } else {
+        a as f64 / b as f64
+    }
+}
+
+/// Refuse a replica of the given size which would take the pool beyond its
+/// overcommit ratio.
+pub(super) fn check_overcommit(
+    lvs: &Lvs,
+    name: &str,
+    size: u64,
+) -> Result<(), Error> {
+    let limit = match OvercommitPolicy::get(lvs.name()).ratio {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+    let overcommit = ratio(lvs.committed() + size, lvs.capacity());
+    if overcommit > limit {
+        return Err(Error::Overcommit {
+            name: name.to_string(),
+            size,
+            pool: lvs.name().to_string(),
+            ratio: overcommit,
+            limit,
+        });
+    }
+    Ok(())
+}
+
+/// Check the usage of the pool against its watermarks, raising or clearing
+/// the alerts of the watermarks crossed since the last check.
+pub(super) fn check_watermarks(lvs: &Lvs) {
+    let pool = lvs.name().to_string();
+    let usage = 100.0 * ratio(lvs.used(), lvs.capacity());
+    let watermarks = OvercommitPolicy::get(&pool).watermarks;
+    let level = watermarks
+        .iter()
+        .filter(|w| usage >= **w as f64)
+        .max()
+        .cloned();
+
+    let previous = match level {
+        Some(level) => LEVELS.lock().insert(pool.clone(), level),
+        None => LEVELS.lock().remove(&pool),
+    };
+    if previous == level {
+        return;
+    }
+
+    let time = chrono::Utc::now().to_rfc3339();
+    let mut alerts = Vec::new();
+    for watermark in watermarks {
+        let above = level.map_or(false, |l| watermark <= l);
+        let was_above = previous.map_or(false, |l| watermark <= l);
+        if above == was_above {
+            continue;
+        }
+        if above {
+            warn!(
+                "Pool {} is {:.1}% full, above its {}% watermark",
+                pool, usage, watermark
+            );
+        } else {
+            info!(
+                "Pool {} is {:.1}% full, back below its {}% watermark",
+                pool, usage, watermark
+            );
+        }
+        alerts.push(PoolAlert {
+            pool: pool.clone(),
+            watermark,
+            raised: above,
+            usage,
+            time: time.clone(),
+        });
+    }
+
+    let mut history = ALERTS.lock();
+    history.extend(alerts);
+    while history.len() > MAX_ALERTS {
+        history.pop_front();
+    }
+}
+
+/// Forget the alert level of a pool which is gone.
+pub(super) fn forget_pool(pool: &str) {
+    LEVELS.lock().remove(pool);
+}
+
+/// Check the usage of the pools periodically.
+pub fn start_usage_monitor() {
+    Reactors::master().send_future(async {
+        loop {
+            Lvs::iter().for_each(|lvs| check_watermarks(&lvs));
+            let _ = mayastor_sleep(USAGE_CHECK_INTERVAL).await;
+        }
+    });
+}
+
+/// Arguments of the pool_overcommit_set json-rpc method.
+#[derive(Debug, Deserialize)]
+struct OvercommitSetArgs {
+    pool: String,
+    /// New ratio, which removes the limit if not set.
+    ratio: Option<f64>,
+    /// New watermarks, the current ones are kept if not set.
+    watermarks: Option<Vec<u8>>,
+}
+
+/// Arguments of the pool_overcommit json-rpc method.
+#[derive(Debug, Deserialize)]
+struct OvercommitArgs {
+    pool: String,
+}
+
+/// Returns the pool of the given name.
+pub(super) fn lookup_pool(pool: &str) -> Result<Lvs, JsonRpcError> {
+    Lvs::lookup(pool).ok_or_else(|| {
+        JsonRpcError::new(Code::NotFound, format!("Pool {} not found", pool))
+    })
+}
+
+/// Register the overcommit json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("pool_overcommit_set", |args: OvercommitSetArgs| {
+        async move {
+            let lvs = lookup_pool(&args.pool)?;
+            if matches!(args.ratio, Some(r) if r <= 0.0 || !r.is_finite()) {
+                return Err(JsonRpcError::new(
+                    Code::InvalidParams,
+                    "overcommit ratio must be positive",
+                ));
+            }
+            let mut policy = OvercommitPolicy::get(&args.pool);
+            policy.ratio = args.ratio;
+            if let Some(mut watermarks) = args.watermarks {
+                if watermarks.iter().any(|w| *w == 0 || *w > 100) {
+                    return Err(JsonRpcError::new(
+                        Code::InvalidParams,
+                        "watermarks must be between 1 and 100 percent",
+                    ));
+                }
+                watermarks.sort_unstable();
+                watermarks.dedup();
+                policy.watermarks = watermarks;
+            }
+            info!(
+                "Setting the overcommit policy of pool {}: {:?}",
+                args.pool, policy
+            );
+            POLICIES.lock().insert(args.pool, policy);
+            check_watermarks(&lvs);
+            Ok(OvercommitStatus::new(&lvs))
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("pool_overcommit", |args: OvercommitArgs| {
+        async move {
+            let lvs = lookup_pool(&args.pool)?;
+            Ok(OvercommitStatus::new(&lvs))
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register::<(), _, _, JsonRpcError>("pool_alerts", |_| {
+        async move { Ok(ALERTS.lock().iter().cloned().collect::<Vec<_>>()) }
+            .boxed_local()
+    });
+}
@@ -0,0 +1,313 @@
+//! Resizing of the replicas.
+//!
+//! A replica can be grown at any time. It can be shrunk when the upper layer
+//! guarantees that the tail cut off is unused, which is validated rather than
+//! trusted: the shrink is refused while any cluster past the new size is
+//! allocated, unless asked to unmap them, discarding their data. All the
+//! clusters of a thick replica are allocated, so its tail can only be
+//! discarded.
+//!
+//! SPDK does not shrink a device which is open, so a replica being shrunk
+//! must not be shared, and the children the nexuses of this node have on it
+//! must be closed, e.g. taken offline. The new size is then propagated to
+//! these nexuses, which are shrunk to fit so that their child can be opened
+//! again. A nexus of another node must be shrunk, or recreated, by the
+//! control plane, as it is not known here.
+
+use std::convert::TryFrom;
+
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+
+use super::{Error, Lvol};
+use crate::{
+    bdev::nexus::{nexus_iter, nexus_lookup_mut, NexusChild},
+    core::{CoreError, Protocol, Share, UntypedBdev, UntypedBdevHandle},
+    jsonrpc::{jsonrpc_register, Code, RpcErrorCode},
+};
+
+/// Size of the chunks the tail is unmapped in.
+const CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)), context(suffix(false)))]
+pub enum ResizeError {
+    #[snafu(display("Replica {} not found", name))]
+    ReplicaNotFound { name: String },
+    #[snafu(display("Replica {} is a snapshot and cannot be resized", name))]
+    ReplicaSnapshot { name: String },
+    #[snafu(display("Replica {} cannot be resized to 0 bytes", name))]
+    InvalidSize { name: String },
+    #[snafu(display(
+        "Replica {} is shared and cannot be shrunk, it must be unshared",
+        name
+    ))]
+    ReplicaShared { name: String },
+    #[snafu(display(
+        "Replica {} is open by nexus {} and cannot be shrunk, its child must \
+        be closed",
+        name,
+        nexus
+    ))]
+    ReplicaOpen { name: String, nexus: String },
+    #[snafu(display("Replica {} is in use and cannot be shrunk", name))]
+    ReplicaInUse { name: String },
+    #[snafu(display(
+        "Replica {} has {} bytes allocated past {} bytes, which must be \
+        unmapped to shrink it",
+        name,
+        allocated,
+        size
+    ))]
+    TailAllocated {
+        name: String,
+        size: u64,
+        allocated: u64,
+    },
+    #[snafu(display(
+        "Failed to unmap the tail of replica {}: {}",
+        name,
+        source
+    ))]
+    UnmapTail { source: CoreError, name: String },
+    #[snafu(display("Failed to resize replica {}: {}", name, source))]
+    Resize { source: Error, name: String },
+}
+
+impl RpcErrorCode for ResizeError {
+    fn rpc_error_code(&self) -> Code {
+        match self {
+            ResizeError::ReplicaNotFound {
+                ..
+            } => Code::NotFound,
+            ResizeError::ReplicaShared {
+                ..
+            }
+            | ResizeError::ReplicaOpen {
+                ..
+            }
+            | ResizeError::ReplicaInUse {
+                ..
+            } => Code::AlreadyExists,
+            ResizeError::ReplicaSnapshot {
+                ..
+            }
+            | ResizeError::InvalidSize {
+                ..
+            }
+            | ResizeError::TailAllocated {
+                ..
+            } => Code::InvalidParams,
+            _ => Code::InternalError,
+        }
+    }
+}
+
+/// Shrink of a nexus of this node, following the shrink of its replica.
+#[derive(Serialize, Debug, Clone)]
+pub struct NexusShrink {
+    pub name: String,
+    /// Size of the nexus once shrunk, None if it failed to be.
+    pub size: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// Outcome of the resize of a replica.
+#[derive(Serialize, Debug, Clone)]
+pub struct ReplicaResize {
+    pub name: String,
+    pub uuid: String,
+    pub previous_size: u64,
+    pub size: u64,
+    /// Number of bytes allocated past the new size which were unmapped.
+    pub unmapped_bytes: u64,
+    /// Nexuses of this node which were shrunk along with the replica.
+    pub nexuses: Vec<NexusShrink>,
+}
+
+/// Returns the replica with the given name or uuid.
+fn lookup_replica(replica: &str) -> Result<Lvol, ResizeError> {
+    UntypedBdev::lookup_by_name(replica)
+        .or_else(|| UntypedBdev::lookup_by_uuid_str(replica))
+        .and_then(|b| Lvol::try_from(b).ok())
+        .ok_or_else(|| ResizeError::ReplicaNotFound {
+            name: replica.to_string(),
+        })
+}
+
+/// Returns true if the child is on the given replica.
+fn is_replica_child(child: &NexusChild, lvol: &Lvol) -> bool {
+    child.match_device_name(&lvol.name())
+        || NexusChild::uuid(child.uri()).as_deref()
+            == Some(lvol.uuid().as_str())
+}
+
+/// Returns the ranges of the lvol allocated past the given size, as (offset,
+/// length) in bytes.
+fn allocated_tail(lvol: &Lvol, size: u64) -> Vec<(u64, u64)> {
+    lvol.allocated_extents()
+        .into_iter()
+        .filter(|(offset, len)| offset + len > size)
+        .map(|(offset, len)| {
+            let start = offset.max(size);
+            (start, offset + len - start)
+        })
+        .collect()
+}
+
+/// Unmap the given ranges of the replica.
+async fn unmap_tail(
+    lvol: &Lvol,
+    ranges: &[(u64, u64)],
+) -> Result<(), CoreError> {
+    let handle = UntypedBdevHandle::open(&lvol.name(), true, false)?;
+    for &(offset, len) in ranges {
+        let mut done = 0;
+        while done < len {
+            let size = CHUNK_SIZE.min(len - done);
+            handle.unmap_at(offset + done, size).await?;
+            done += size;
+        }
+    }
+    Ok(())
+}
+
+/// Resize the replica to the given size in bytes, rounded up to a whole
+/// number of clusters. A replica is only shrunk if nothing is allocated past
+/// the new size, or if asked to unmap what is, and the nexuses of this node
+/// which have it as a child are then shrunk to fit.
+pub async fn resize_replica(
+    replica: &str,
+    size: u64,
+    unmap: bool,
+) -> Result<ReplicaResize, ResizeError> {
+    let lvol = lookup_replica(replica)?;
+    let name = lvol.name();
+    if lvol.is_snapshot() {
+        return ReplicaSnapshot {
+            name,
+        }
+        .fail();
+    }
+    if size == 0 {
+        return InvalidSize {
+            name,
+        }
+        .fail();
+    }
+
+    let cluster_size = lvol.usage().cluster_size;
+    let size = (size + cluster_size - 1) / cluster_size * cluster_size;
+    let mut outcome = ReplicaResize {
+        name: name.clone(),
+        uuid: lvol.uuid(),
+        previous_size: lvol.size(),
+        size,
+        unmapped_bytes: 0,
+        nexuses: Vec::new(),
+    };
+
+    if size >= outcome.previous_size {
+        if size > outcome.previous_size {
+            lvol.resize(size).await.context(Resize {
+                name,
+            })?;
+        }
+        return Ok(outcome);
+    }
+
+    if !matches!(lvol.shared(), None | Some(Protocol::Off)) {
+        return ReplicaShared {
+            name,
+        }
+        .fail();
+    }
+    let mut nexuses = Vec::new();
+    for nexus in nexus_iter() {
+        let children = nexus
+            .children_iter()
+            .filter(|c| is_replica_child(c, &lvol))
+            .collect::<Vec<_>>();
+        if children.iter().any(|c| c.is_device_open()) {
+            return ReplicaOpen {
+                name,
+                nexus: nexus.name.clone(),
+            }
+            .fail();
+        }
+        if !children.is_empty() {
+            nexuses.push(nexus.name.clone());
+        }
+    }
+    if lvol.as_bdev().is_claimed() {
+        return ReplicaInUse {
+            name,
+        }
+        .fail();
+    }
+
+    let tail = allocated_tail(&lvol, size);
+    let allocated = tail.iter().map(|(_, len)| len).sum::<u64>();
+    if allocated > 0 {
+        if !unmap {
+            return TailAllocated {
+                name,
+                size,
+                allocated,
+            }
+            .fail();
+        }
+        unmap_tail(&lvol, &tail).await.context(UnmapTail {
+            name: name.clone(),
+        })?;
+        outcome.unmapped_bytes = allocated;
+    }
+
+    lvol.resize(size).await.context(Resize {
+        name: name.clone(),
+    })?;
+
+    for nexus_name in nexuses {
+        let nexus = match nexus_lookup_mut(&nexus_name) {
+            Some(nexus) => nexus,
+            None => continue,
+        };
+        let result = nexus.shrink(size).await;
+        if let Err(error) = &result {
+            warn!(
+                "Failed to shrink nexus {} along with its replica {}: {}",
+                nexus_name, name, error
+            );
+        }
+        outcome.nexuses.push(NexusShrink {
+            name: nexus_name,
+            size: result.as_ref().ok().copied(),
+            error: result.err().map(|e| e.to_string()),
+        });
+    }
+    Ok(outcome)
+}
+
+/// Arguments of the replica_resize json-rpc method.
+#[derive(Debug, Deserialize)]
+struct ReplicaResizeArgs {
+    /// Name or uuid of the replica.
+    replica: String,
+    /// New size of the replica, in bytes.
+    size: u64,
+    /// Unmap the clusters allocated past the new size rather than refusing
+    /// to shrink the replica.
+    #[serde(default)]
+    unmap_tail: bool,
+}
+
+/// Register the resize json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("replica_resize", |args: ReplicaResizeArgs| {
+        async move {
+            resize_replica(&args.replica, args.size, args.unmap_tail).await
+        }
+        .boxed_local()
+    });
+}
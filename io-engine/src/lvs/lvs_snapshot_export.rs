@@ -0,0 +1,330 @@
+//! Export of the snapshots of the replicas over NVMe-oF.
+//!
+//! A snapshot can be shared as a NVMF namespace of its own, for a backup tool
+//! to read it over the network, without a clone being created first and so
+//! without using any capacity. The blob of a snapshot is read-only, so any
+//! write to the namespace fails.
+//!
+//! An export is temporary: it is torn down on its own once the last initiator
+//! connected to it disconnects, or if no initiator connects to it within its
+//! idle timeout. It is not recorded in the metadata of the snapshot either,
+//! so it does not survive a restart of the io-engine.
+
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    pin::Pin,
+    time::{Duration, Instant},
+};
+
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+
+use super::{Error, Lvol};
+use crate::{
+    core::{Protocol, Reactors, Share, ShareProps, UntypedBdev},
+    events::{publish, EventCategory, EventSeverity},
+    jsonrpc::{jsonrpc_register, Code, RpcErrorCode},
+    sleep::mayastor_sleep,
+    subsys::NvmfSubsystem,
+};
+
+/// Interval at which the initiators connected to the exports are checked.
+const EXPORT_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Time given to the first initiator to connect to an export, by default.
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Exports, by uuid of their snapshot.
+static EXPORTS: Lazy<Mutex<HashMap<String, Export>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)), context(suffix(false)))]
+pub enum SnapshotExportError {
+    #[snafu(display("Snapshot {} not found", name))]
+    SnapshotNotFound { name: String },
+    #[snafu(display("{} is not a snapshot", name))]
+    NotSnapshot { name: String },
+    #[snafu(display("Snapshot {} is already shared", name))]
+    AlreadyShared { name: String },
+    #[snafu(display("Snapshot {} is not exported", name))]
+    NotExported { name: String },
+    #[snafu(display("Failed to export snapshot {}: {}", name, source))]
+    Export { source: Error, name: String },
+    #[snafu(display("Failed to unexport snapshot {}: {}", name, source))]
+    Unexport { source: Error, name: String },
+}
+
+impl RpcErrorCode for SnapshotExportError {
+    fn rpc_error_code(&self) -> Code {
+        match self {
+            SnapshotExportError::SnapshotNotFound {
+                ..
+            }
+            | SnapshotExportError::NotExported {
+                ..
+            } => Code::NotFound,
+            SnapshotExportError::AlreadyShared {
+                ..
+            } => Code::AlreadyExists,
+            SnapshotExportError::NotSnapshot {
+                ..
+            } => Code::InvalidParams,
+            _ => Code::InternalError,
+        }
+    }
+}
+
+/// Export of a snapshot.
+#[derive(Debug, Clone)]
+struct Export {
+    /// Name of the snapshot.
+    name: String,
+    uri: String,
+    created: Instant,
+    idle_timeout: Duration,
+    /// An initiator has connected to the export.
+    connected: bool,
+}
+
+/// Status of the export of a snapshot.
+#[derive(Serialize, Debug, Clone)]
+pub struct SnapshotExport {
+    pub snapshot: String,
+    pub uuid: String,
+    /// URI of the NVMF target of the snapshot.
+    pub uri: String,
+    pub allowed_hosts: Vec<String>,
+    /// Hosts connected to the export, one per controller.
+    pub connected_hosts: Vec<String>,
+}
+
+/// Returns the subsystem the given snapshot is shared with.
+fn subsystem(lvol: &Lvol) -> Option<NvmfSubsystem> {
+    NvmfSubsystem::nqn_lookup(lvol.as_bdev().name())
+}
+
+impl Lvol {
+    /// Export the snapshot as a read-only NVMF target, for the given hosts.
+    /// The export is torn down once the last host connected to it
+    /// disconnects, or if no host connects to it within the idle timeout.
+    pub async fn export_snapshot(
+        mut self: Pin<&mut Self>,
+        allowed_hosts: Vec<String>,
+        idle_timeout: Option<Duration>,
+    ) -> Result<SnapshotExport, SnapshotExportError> {
+        let name = self.name();
+        if !self.is_snapshot() {
+            return NotSnapshot {
+                name,
+            }
+            .fail();
+        }
+        if !matches!(self.shared(), None | Some(Protocol::Off)) {
+            return AlreadyShared {
+                name,
+            }
+            .fail();
+        }
+
+        let uri = self
+            .as_mut()
+            .share_nvmf(Some(
+                ShareProps::new().with_allowed_hosts(allowed_hosts),
+            ))
+            .await
+            .context(Export {
+                name: name.clone(),
+            })?;
+
+        EXPORTS.lock().insert(
+            self.uuid(),
+            Export {
+                name: name.clone(),
+                uri,
+                created: Instant::now(),
+                idle_timeout: idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT),
+                connected: false,
+            },
+        );
+        publish(
+            EventCategory::Snapshot,
+            EventSeverity::Info,
+            "exported",
+            &name,
+            format!("snapshot exported as {}", self.share_uri().unwrap()),
+        );
+
+        self.snapshot_export()
+            .ok_or(SnapshotExportError::NotExported {
+                name,
+            })
+    }
+
+    /// Tear down the export of the snapshot.
+    pub async fn unexport_snapshot(
+        self: Pin<&mut Self>,
+    ) -> Result<(), SnapshotExportError> {
+        let name = self.name();
+        if EXPORTS.lock().remove(&self.uuid()).is_none() {
+            return NotExported {
+                name,
+            }
+            .fail();
+        }
+        self.unshare().await.context(Unexport {
+            name,
+        })
+    }
+
+    /// Returns the status of the export of the snapshot, if exported.
+    pub fn snapshot_export(&self) -> Option<SnapshotExport> {
+        let export = EXPORTS.lock().get(&self.uuid()).cloned()?;
+        let subsystem = subsystem(self)?;
+        Some(SnapshotExport {
+            snapshot: export.name,
+            uuid: self.uuid(),
+            uri: self.share_uri().unwrap_or(export.uri),
+            allowed_hosts: subsystem.allowed_hosts(),
+            connected_hosts: subsystem.connected_hosts(),
+        })
+    }
+}
+
+/// Returns the snapshot with the given name or uuid.
+fn lookup_snapshot(snapshot: &str) -> Result<Lvol, SnapshotExportError> {
+    UntypedBdev::lookup_by_name(snapshot)
+        .or_else(|| UntypedBdev::lookup_by_uuid_str(snapshot))
+        .and_then(|b| Lvol::try_from(b).ok())
+        .ok_or_else(|| SnapshotExportError::SnapshotNotFound {
+            name: snapshot.to_string(),
+        })
+}
+
+/// Tear down the exports whose initiators are all gone.
+async fn check_exports() {
+    let exports = EXPORTS
+        .lock()
+        .iter()
+        .map(|(uuid, export)| (uuid.clone(), export.clone()))
+        .collect::<Vec<_>>();
+
+    for (uuid, export) in exports {
+        // the snapshot was destroyed or unshared behind the export
+        let mut lvol = match lookup_snapshot(&uuid) {
+            Ok(lvol) if lvol.shared() == Some(Protocol::Nvmf) => lvol,
+            _ => {
+                EXPORTS.lock().remove(&uuid);
+                continue;
+            }
+        };
+        let connected = subsystem(&lvol)
+            .map(|s| !s.connected_hosts().is_empty())
+            .unwrap_or_default();
+
+        let reason = if connected {
+            if let Some(export) = EXPORTS.lock().get_mut(&uuid) {
+                export.connected = true;
+            }
+            continue;
+        } else if export.connected {
+            "the last initiator disconnected"
+        } else if export.created.elapsed() >= export.idle_timeout {
+            "no initiator connected"
+        } else {
+            continue;
+        };
+
+        match Pin::new(&mut lvol).unexport_snapshot().await {
+            Ok(()) => publish(
+                EventCategory::Snapshot,
+                EventSeverity::Info,
+                "unexported",
+                &export.name,
+                format!("snapshot export torn down, {}", reason),
+            ),
+            Err(error) => publish(
+                EventCategory::Snapshot,
+                EventSeverity::Error,
+                "unexport_failed",
+                &export.name,
+                error.to_string(),
+            ),
+        }
+    }
+}
+
+/// Tear down the snapshot exports once their initiators are gone.
+pub fn start_snapshot_export_monitor() {
+    Reactors::master().send_future(async {
+        loop {
+            mayastor_sleep(EXPORT_CHECK_INTERVAL).await.ok();
+            check_exports().await;
+        }
+    });
+}
+
+/// Arguments of the snapshot_export json-rpc method.
+#[derive(Debug, Deserialize)]
+struct ExportArgs {
+    /// Name or uuid of the snapshot.
+    snapshot: String,
+    /// Host nqn's allowed to connect to the export.
+    #[serde(default)]
+    allowed_hosts: Vec<String>,
+    /// Time given to the first initiator to connect, in seconds.
+    idle_timeout_secs: Option<u64>,
+}
+
+/// Arguments of the snapshot_unexport json-rpc method.
+#[derive(Debug, Deserialize)]
+struct UnexportArgs {
+    /// Name or uuid of the snapshot.
+    snapshot: String,
+}
+
+/// Register the snapshot export json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("snapshot_export", |args: ExportArgs| {
+        async move {
+            let mut lvol = lookup_snapshot(&args.snapshot)?;
+            Pin::new(&mut lvol)
+                .export_snapshot(
+                    args.allowed_hosts,
+                    args.idle_timeout_secs.map(Duration::from_secs),
+                )
+                .await
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("snapshot_unexport", |args: UnexportArgs| {
+        async move {
+            let mut lvol = lookup_snapshot(&args.snapshot)?;
+            Pin::new(&mut lvol).unexport_snapshot().await
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register::<(), _, _, SnapshotExportError>(
+        "snapshot_exports",
+        |_| {
+            async move {
+                let uuids =
+                    EXPORTS.lock().keys().cloned().collect::<Vec<String>>();
+                let mut exports = uuids
+                    .iter()
+                    .filter_map(|uuid| lookup_snapshot(uuid).ok())
+                    .filter_map(|lvol| lvol.snapshot_export())
+                    .collect::<Vec<_>>();
+                exports.sort_by(|a, b| a.snapshot.cmp(&b.snapshot));
+                Ok(exports)
+            }
+            .boxed_local()
+        },
+    );
+}
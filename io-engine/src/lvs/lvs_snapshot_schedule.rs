@@ -0,0 +1,390 @@
+//! Snapshot schedules of the replicas.
+//!
+//! A replica can be given a schedule, which the io-engine runs on its own:
+//! a snapshot of the replica is taken at every interval, and the oldest
+//! snapshots taken by the schedule are destroyed beyond its retention count.
+//! Snapshots are thereby taken every few minutes if need be, without the
+//! control plane having to request each of them. Each snapshot is crash
+//! consistent with the writes to the replica, and the outcome of every
+//! snapshot and destruction is published as an event.
+//!
+//! The schedule is recorded in the metadata of the replica, and the time of
+//! the last snapshot is the time in the name of the most recent snapshot of
+//! the schedule, so that a schedule carries on where it was after a restart
+//! of the io-engine or an import of the pool. Only the snapshots named by the
+//! schedule are counted and destroyed by it, the snapshots requested by the
+//! control plane are left alone.
+
+use std::{
+    convert::TryFrom,
+    pin::Pin,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+
+use super::{
+    lvs_maintenance::jobs_paused,
+    Error,
+    Lvol,
+    Lvs,
+    PropName,
+    PropValue,
+};
+use crate::{
+    core::{Reactors, UntypedBdev},
+    events::{publish, EventCategory, EventSeverity},
+    jsonrpc::{jsonrpc_register, Code, JsonRpcError, RpcErrorCode},
+    sleep::mayastor_sleep,
+};
+
+/// Interval at which the schedules are checked.
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Shortest interval between two snapshots of a schedule, in seconds.
+const MIN_INTERVAL_SECS: u64 = 30;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)), context(suffix(false)))]
+pub enum SnapshotScheduleError {
+    #[snafu(display("Replica {} not found", name))]
+    ReplicaNotFound { name: String },
+    #[snafu(display(
+        "Invalid snapshot schedule for replica {}: {}",
+        name,
+        reason
+    ))]
+    InvalidSchedule { name: String, reason: String },
+    #[snafu(display(
+        "Failed to update the snapshot schedule of replica {}: {}",
+        name,
+        source
+    ))]
+    ScheduleUpdate { source: Error, name: String },
+}
+
+impl RpcErrorCode for SnapshotScheduleError {
+    fn rpc_error_code(&self) -> Code {
+        match self {
+            SnapshotScheduleError::ReplicaNotFound {
+                ..
+            } => Code::NotFound,
+            SnapshotScheduleError::InvalidSchedule {
+                ..
+            } => Code::InvalidParams,
+            _ => Code::InternalError,
+        }
+    }
+}
+
+/// Snapshot schedule of a replica.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotSchedule {
+    /// Interval between two snapshots, in seconds.
+    pub interval_secs: u64,
+    /// Number of snapshots of the schedule kept.
+    pub retention: u32,
+}
+
+/// Snapshot schedule of a replica along with its snapshots.
+#[derive(Serialize, Debug, Clone)]
+pub struct SnapshotScheduleStatus {
+    pub replica: String,
+    pub uuid: String,
+    pub schedule: SnapshotSchedule,
+    /// Names of the snapshots of the schedule, oldest first.
+    pub snapshots: Vec<String>,
+    /// Time of the last snapshot, in seconds since the Unix epoch.
+    pub last: Option<u64>,
+}
+
+/// Returns the current time, in seconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl Lvol {
+    /// Returns the snapshot schedule of the replica, if any.
+    pub async fn snapshot_schedule(&self) -> Option<SnapshotSchedule> {
+        match self.get(PropName::SnapshotSchedule).await {
+            Ok(PropValue::SnapshotSchedule(schedule))
+                if !schedule.is_empty() =>
+            {
+                serde_json::from_str(&schedule).ok()
+            }
+            _ => None,
+        }
+    }
+
+    /// Record the snapshot schedule of the replica, or clear it.
+    pub async fn set_snapshot_schedule(
+        self: Pin<&mut Self>,
+        schedule: Option<&SnapshotSchedule>,
+    ) -> Result<(), SnapshotScheduleError> {
+        let name = self.name();
+        if let Some(schedule) = schedule {
+            if self.is_snapshot() {
+                return InvalidSchedule {
+                    name,
+                    reason: "the replica is a snapshot",
+                }
+                .fail();
+            }
+            if schedule.interval_secs < MIN_INTERVAL_SECS {
+                return InvalidSchedule {
+                    name,
+                    reason: format!(
+                        "the interval must be at least {} seconds",
+                        MIN_INTERVAL_SECS
+                    ),
+                }
+                .fail();
+            }
+            if schedule.retention == 0 {
+                return InvalidSchedule {
+                    name,
+                    reason: "at least one snapshot must be kept",
+                }
+                .fail();
+            }
+        }
+
+        info!("Setting the snapshot schedule of {}: {:?}", name, schedule);
+        let value = schedule
+            .map(|s| serde_json::to_string(s).unwrap())
+            .unwrap_or_default();
+        self.set(PropValue::SnapshotSchedule(value)).await.context(
+            ScheduleUpdate {
+                name,
+            },
+        )
+    }
+
+    /// Returns the snapshots taken by the schedule of the replica, along with
+    /// their time, oldest first.
+    fn scheduled_snapshots(&self) -> Vec<(u64, Lvol)> {
+        // as named by Lvol::format_snapshot_name
+        let prefix = format!("{}-snap-", scheduled_base(self));
+        let mut snapshots = self
+            .lvs()
+            .lvols()
+            .map(|lvols| {
+                lvols
+                    .filter(|l| l.is_snapshot())
+                    .filter_map(|l| {
+                        let time =
+                            l.name().strip_prefix(&prefix)?.parse().ok()?;
+                        Some((time, l))
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        snapshots.sort_by_key(|(time, _)| *time);
+        snapshots
+    }
+
+    /// Returns the status of the snapshot schedule of the replica, if any.
+    pub async fn snapshot_schedule_status(
+        &self,
+    ) -> Option<SnapshotScheduleStatus> {
+        let schedule = self.snapshot_schedule().await?;
+        let snapshots = self.scheduled_snapshots();
+        Some(SnapshotScheduleStatus {
+            replica: self.name(),
+            uuid: self.uuid(),
+            schedule,
+            last: snapshots.last().map(|(time, _)| *time),
+            snapshots: snapshots.into_iter().map(|(_, s)| s.name()).collect(),
+        })
+    }
+}
+
+/// Returns the base of the names of the snapshots taken by the schedule of
+/// the replica, which sets them apart from the other snapshots.
+fn scheduled_base(lvol: &Lvol) -> String {
+    format!("{}-sched", lvol.name())
+}
+
+/// Take a snapshot of the replica if its schedule is due, and destroy the
+/// snapshots of the schedule beyond its retention.
+async fn run_schedule(lvol: &Lvol, schedule: &SnapshotSchedule) {
+    let uuid = lvol.uuid();
+    let now = now();
+    let last = lvol.scheduled_snapshots().last().map(|(time, _)| *time);
+    if matches!(last, Some(last) if now < last + schedule.interval_secs) {
+        return;
+    }
+
+    let name = Lvol::format_snapshot_name(&scheduled_base(lvol), now);
+    match lvol.snapshot(&name).await {
+        Ok(()) => publish(
+            EventCategory::Snapshot,
+            EventSeverity::Info,
+            "created",
+            &uuid,
+            format!("scheduled snapshot {} created", name),
+        ),
+        Err(error) => {
+            publish(
+                EventCategory::Snapshot,
+                EventSeverity::Error,
+                "create_failed",
+                &uuid,
+                format!(
+                    "failed to create scheduled snapshot {}: {}",
+                    name, error
+                ),
+            );
+            return;
+        }
+    }
+
+    let mut snapshots = lvol.scheduled_snapshots();
+    let excess = snapshots.len().saturating_sub(schedule.retention as usize);
+    for (_, snapshot) in snapshots.drain(.. excess) {
+        let name = snapshot.name();
+        match snapshot.destroy().await {
+            Ok(_) => publish(
+                EventCategory::Snapshot,
+                EventSeverity::Info,
+                "destroyed",
+                &uuid,
+                format!("scheduled snapshot {} destroyed by retention", name),
+            ),
+            Err(error) => {
+                // keep the newer snapshots, the oldest ones go first
+                publish(
+                    EventCategory::Snapshot,
+                    EventSeverity::Warning,
+                    "destroy_failed",
+                    &uuid,
+                    format!(
+                        "failed to destroy scheduled snapshot {}: {}",
+                        name, error
+                    ),
+                );
+                break;
+            }
+        }
+    }
+}
+
+/// Run the schedules of the replicas of all pools which are due, except on
+/// the pools whose jobs are paused for maintenance.
+async fn run_schedules() {
+    let replicas = Lvs::iter()
+        .filter(|lvs| !jobs_paused(lvs.name()))
+        .filter_map(|lvs| lvs.lvols())
+        .flatten()
+        .filter(|l| !l.is_snapshot())
+        .map(|l| l.name())
+        .collect::<Vec<_>>();
+
+    // the replicas are looked up again as they may be gone in the meantime
+    for replica in replicas {
+        if let Ok(lvol) = lookup_replica(&replica) {
+            if let Some(schedule) = lvol.snapshot_schedule().await {
+                run_schedule(&lvol, &schedule).await;
+            }
+        }
+    }
+}
+
+/// Run the snapshot schedules of the replicas periodically.
+pub fn start_snapshot_scheduler() {
+    Reactors::master().send_future(async {
+        loop {
+            mayastor_sleep(SCHEDULE_CHECK_INTERVAL).await.ok();
+            run_schedules().await;
+        }
+    });
+}
+
+/// Returns the replica with the given name or uuid.
+fn lookup_replica(replica: &str) -> Result<Lvol, SnapshotScheduleError> {
+    UntypedBdev::lookup_by_name(replica)
+        .or_else(|| UntypedBdev::lookup_by_uuid_str(replica))
+        .and_then(|b| Lvol::try_from(b).ok())
+        .ok_or_else(|| SnapshotScheduleError::ReplicaNotFound {
+            name: replica.to_string(),
+        })
+}
+
+/// Arguments of the replica_snapshot_schedule_set json-rpc method.
+#[derive(Debug, Deserialize)]
+struct ScheduleSetArgs {
+    /// Name or uuid of the replica.
+    replica: String,
+    #[serde(flatten)]
+    schedule: SnapshotSchedule,
+}
+
+/// Arguments of the other snapshot schedule json-rpc methods.
+#[derive(Debug, Deserialize)]
+struct ScheduleArgs {
+    /// Name or uuid of the replica.
+    replica: String,
+}
+
+/// Register the snapshot schedule json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register(
+        "replica_snapshot_schedule_set",
+        |args: ScheduleSetArgs| {
+            async move {
+                let mut lvol = lookup_replica(&args.replica)?;
+                Pin::new(&mut lvol)
+                    .set_snapshot_schedule(Some(&args.schedule))
+                    .await?;
+                Ok(lvol.snapshot_schedule_status().await)
+            }
+            .boxed_local()
+        },
+    );
+
+    jsonrpc_register(
+        "replica_snapshot_schedule_remove",
+        |args: ScheduleArgs| {
+            async move {
+                let mut lvol = lookup_replica(&args.replica)?;
+                Pin::new(&mut lvol).set_snapshot_schedule(None).await
+            }
+            .boxed_local()
+        },
+    );
+
+    jsonrpc_register("replica_snapshot_schedule_get", |args: ScheduleArgs| {
+        async move {
+            let lvol = lookup_replica(&args.replica)?;
+            Ok(lvol.snapshot_schedule_status().await)
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register::<(), _, _, JsonRpcError>(
+        "replica_snapshot_schedules",
+        |_| {
+            async move {
+                let replicas = Lvs::iter()
+                    .filter_map(|lvs| lvs.lvols())
+                    .flatten()
+                    .filter(|l| !l.is_snapshot())
+                    .collect::<Vec<_>>();
+                let mut schedules = Vec::new();
+                for lvol in replicas {
+                    if let Some(status) = lvol.snapshot_schedule_status().await
+                    {
+                        schedules.push(status);
+                    }
+                }
+                Ok(schedules)
+            }
+            .boxed_local()
+        },
+    );
+}
@@ -2,6 +2,7 @@ use std::{
     convert::TryFrom,
     fmt::Debug,
     os::raw::c_void,
+    path::Path,
     pin::Pin,
     ptr::NonNull,
 };
@@ -33,15 +34,28 @@ use spdk_rs::libspdk::{
 };
 use url::Url;
 
-use super::{Error, Lvol, LvsIter, PropName, PropValue};
+use super::{
+    lvs_disk::{check_disk, label_disk, PoolDiskOptions},
+    lvs_labels::forget_pool_labels,
+    lvs_maintenance::{check_maintenance, forget_maintenance},
+    lvs_overcommit::{check_overcommit, check_watermarks, forget_pool},
+    Error,
+    Lvol,
+    LvsIter,
+    PropName,
+    PropValue,
+};
 
 use crate::{
     bdev::{uri, PtplFileOps},
     bdev_api::{bdev_destroy, BdevError},
     core::{Bdev, IoType, Share, ShareProps, UntypedBdev},
+    drain,
     ffihelper::{cb_arg, pair, AsStr, ErrnoResult, FfiResult, IntoCString},
+    host::blk_device::dax_device,
     lvs::lvs_lvol::WIPE_SUPER_LEN,
     pool_backend::PoolArgs,
+    reconcile,
 };
 
 impl Debug for Lvs {
@@ -174,6 +188,20 @@ impl Lvs {
         }
     }
 
+    /// returns the size of the clusters of the store
+    pub fn cluster_size(&self) -> u64 {
+        unsafe { spdk_bs_get_cluster_size(self.blob_store()) }
+    }
+
+    /// returns the capacity of the replicas of the store, snapshots aside
+    pub fn committed(&self) -> u64 {
+        self.lvols()
+            .map(|lvols| {
+                lvols.filter(|l| !l.is_snapshot()).map(|l| l.size()).sum()
+            })
+            .unwrap_or_default()
+    }
+
     /// returns the used capacity
     pub fn used(&self) -> u64 {
         self.capacity() - self.available()
@@ -197,7 +225,13 @@ impl Lvs {
         let disk = match disks.first() {
             Some(disk) if disks.len() == 1 => {
                 if Url::parse(disk).is_err() {
-                    format!("aio://{}", disk)
+                    // DAX capable devices are backed by the pmem bdev, for
+                    // their writes to be flushed to the persistent memory
+                    if dax_device(Path::new(disk)) {
+                        format!("pmem://{}", disk)
+                    } else {
+                        format!("aio://{}", disk)
+                    }
                 } else {
                     disk.clone()
                 }
@@ -275,6 +309,7 @@ impl Lvs {
                 name: pool_name,
             })
         } else {
+            lvs.restore_aliases().await;
             lvs.share_all().await;
             info!("{:?}: existing lvs imported successfully", lvs);
             Ok(lvs)
@@ -412,6 +447,16 @@ impl Lvs {
     /// imports the pool if it exists, otherwise try to create it
     #[tracing::instrument(level = "debug", err)]
     pub async fn create_or_import(args: PoolArgs) -> Result<Lvs, Error> {
+        Self::create_or_import_ext(args, PoolDiskOptions::default()).await
+    }
+
+    /// imports the pool if it exists, otherwise try to create it on its disk
+    /// once validated, and labeled if requested
+    #[tracing::instrument(level = "debug", err)]
+    pub async fn create_or_import_ext(
+        args: PoolArgs,
+        options: PoolDiskOptions,
+    ) -> Result<Lvs, Error> {
         let disk = Self::parse_disk(args.disks.clone())?;
 
         info!(
@@ -438,6 +483,12 @@ impl Lvs {
             };
         }
 
+        if drain::is_draining() {
+            return Err(Error::NodeDraining {
+                name: args.name,
+            });
+        }
+
         let bdev = match parsed.create().await {
             Err(e) => match e {
                 BdevError::BdevExists {
@@ -471,7 +522,14 @@ impl Lvs {
             Err(Error::Import {
                 source, ..
             }) if source == Errno::EILSEQ => {
-                match Self::create(&args.name, &bdev, args.uuid).await {
+                let checked = async {
+                    check_disk(&args.name, &bdev, options.force).await?;
+                    if options.label {
+                        label_disk(&args.name, &disk)?;
+                    }
+                    Self::create(&args.name, &bdev, args.uuid).await
+                };
+                match checked.await {
                     Err(create) => {
                         let _ = parsed.destroy().await.map_err(|_e| {
                             // we failed to delete the base_bdev be loud about it
@@ -518,6 +576,8 @@ impl Lvs {
             })?;
 
         info!("{}: lvs exported successfully", self_str);
+        forget_pool(&pool);
+        forget_maintenance(&pool);
 
         bdev_destroy(&base_bdev.bdev_uri_original().unwrap())
             .await
@@ -541,6 +601,15 @@ impl Lvs {
         }
     }
 
+    /// give back their alias to the lvols which have been renamed
+    async fn restore_aliases(&self) {
+        if let Some(lvols) = self.lvols() {
+            for l in lvols {
+                l.restore_alias().await;
+            }
+        }
+    }
+
     /// share all lvols who have the shared property set, this is implicitly
     /// shared over nvmf
     async fn share_all(&self) {
@@ -584,11 +653,19 @@ impl Lvs {
     /// un share all targets
     #[tracing::instrument(level = "debug", err)]
     pub async fn destroy(self) -> Result<(), Error> {
+        let base_bdev = self.destruct().await?;
+        Self::destroy_base_bdev(&base_bdev).await
+    }
+
+    /// destroys the pool but not its base bdev, which is returned so that its
+    /// space can be erased before it is released
+    pub(super) async fn destruct(self) -> Result<UntypedBdev, Error> {
         let self_str = format!("{:?}", self);
         info!("{}: destroying lvs...", self_str);
 
         let ptpl = self.ptpl();
         let pool = self.name().to_string();
+        let uuid = self.uuid();
         let (s, r) = pair::<i32>();
 
         // when destroying a pool unshare all volumes
@@ -612,13 +689,9 @@ impl Lvs {
             })?;
 
         info!("{}: lvs destroyed successfully", self_str);
-
-        bdev_destroy(&base_bdev.bdev_uri_original().unwrap())
-            .await
-            .map_err(|e| Error::Destroy {
-                source: e,
-                name: base_bdev.name().to_string(),
-            })?;
+        forget_pool(&pool);
+        forget_maintenance(&pool);
+        forget_pool_labels(&uuid).await;
 
         if let Err(error) = ptpl.destroy() {
             tracing::error!(
@@ -628,7 +701,19 @@ impl Lvs {
             );
         }
 
-        Ok(())
+        Ok(base_bdev)
+    }
+
+    /// destroys the base bdev of a destroyed pool
+    pub(super) async fn destroy_base_bdev(
+        base_bdev: &UntypedBdev,
+    ) -> Result<(), Error> {
+        bdev_destroy(&base_bdev.bdev_uri_original().unwrap())
+            .await
+            .map_err(|e| Error::Destroy {
+                source: e,
+                name: base_bdev.name().to_string(),
+            })
     }
 
     /// return an iterator that filters out all bdevs that patch the pool
@@ -650,13 +735,31 @@ impl Lvs {
             None
         }
     }
-    /// create a new lvol on this pool
+    /// create a new lvol on this pool, within its overcommit ratio and
+    /// unless it is in maintenance
     pub async fn create_lvol(
         &self,
         name: &str,
         size: u64,
         uuid: Option<&str>,
         thin: bool,
+    ) -> Result<Lvol, Error> {
+        if UntypedBdev::lookup_by_name(name).is_none() {
+            check_maintenance(self, name)?;
+            check_overcommit(self, name, size)?;
+        }
+        let lvol = self.create_lvol_unchecked(name, size, uuid, thin).await?;
+        check_watermarks(self);
+        Ok(lvol)
+    }
+
+    /// create a new lvol on this pool, regardless of its overcommit ratio
+    pub(super) async fn create_lvol_unchecked(
+        &self,
+        name: &str,
+        size: u64,
+        uuid: Option<&str>,
+        thin: bool,
     ) -> Result<Lvol, Error> {
         let clear_method = if self.base_bdev().io_type_supported(IoType::Unmap)
         {
@@ -681,6 +784,12 @@ impl Lvs {
             });
         };
 
+        if drain::is_draining() {
+            return Err(Error::NodeDraining {
+                name: name.to_string(),
+            });
+        }
+
         if clear_method != spdk_rs::libspdk::LVS_CLEAR_WITH_UNMAP
             && WIPE_SUPER_LEN > self.available()
         {
@@ -758,6 +867,7 @@ impl Lvs {
         }
 
         info!("{:?}: created", lvol);
+        reconcile::record_replica(&lvol);
         Ok(lvol)
     }
 
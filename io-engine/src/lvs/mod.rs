@@ -1,11 +1,87 @@
 pub use lvs_bdev::LvsBdev;
+pub use lvs_compact::{CompactError, CompactJob, CompactState, CompactWindow};
+pub use lvs_copy::{
+    cancel_copy,
+    copy_job,
+    copy_replica,
+    CopyError,
+    CopyJob,
+    CopyMode,
+    CopyParams,
+    CopyState,
+};
+pub use lvs_dedupe::{DedupeError, DedupeScan, DedupeState};
+pub use lvs_disk::{probe_signatures, DiskSignature, PoolDiskOptions};
+pub use lvs_erase::{
+    destroy_pool_erased,
+    destroy_replica_erased,
+    erase_job,
+    EraseError,
+    EraseJob,
+    EraseMethod,
+    EraseState,
+    EraseTarget,
+};
 pub use lvs_error::Error;
 pub use lvs_iter::{LvsBdevIter, LvsIter};
+pub use lvs_lease::{LeaseError, ReplicaLease};
 pub use lvs_lvol::{Lvol, LvolSpaceUsage, PropName, PropValue};
+pub use lvs_maintenance::{
+    end_maintenance,
+    in_maintenance,
+    start_maintenance,
+    PoolMaintenance,
+    MIGRATE_LABEL,
+};
+pub use lvs_overcommit::{
+    start_usage_monitor,
+    OvercommitPolicy,
+    OvercommitStatus,
+    PoolAlert,
+};
+pub use lvs_resize::{resize_replica, NexusShrink, ReplicaResize, ResizeError};
+pub use lvs_snapshot_export::{
+    start_snapshot_export_monitor,
+    SnapshotExport,
+    SnapshotExportError,
+};
+pub use lvs_snapshot_schedule::{
+    start_snapshot_scheduler,
+    SnapshotSchedule,
+    SnapshotScheduleError,
+    SnapshotScheduleStatus,
+};
 pub use lvs_store::Lvs;
 
 mod lvs_bdev;
+mod lvs_compact;
+mod lvs_copy;
+mod lvs_dedupe;
+mod lvs_disk;
+mod lvs_erase;
 mod lvs_error;
 mod lvs_iter;
+mod lvs_labels;
+mod lvs_lease;
 mod lvs_lvol;
+mod lvs_maintenance;
+mod lvs_overcommit;
+mod lvs_resize;
+mod lvs_snapshot_export;
+mod lvs_snapshot_schedule;
 mod lvs_store;
+
+/// Register the pool json-rpc methods.
+pub(crate) fn register_rpc_methods() {
+    lvs_compact::register_rpc_methods();
+    lvs_copy::register_rpc_methods();
+    lvs_dedupe::register_rpc_methods();
+    lvs_disk::register_rpc_methods();
+    lvs_erase::register_rpc_methods();
+    lvs_lease::register_rpc_methods();
+    lvs_maintenance::register_rpc_methods();
+    lvs_overcommit::register_rpc_methods();
+    lvs_resize::register_rpc_methods();
+    lvs_snapshot_export::register_rpc_methods();
+    lvs_snapshot_schedule::register_rpc_methods();
+}
@@ -0,0 +1,407 @@
+//! Prometheus metrics exporter.
+//!
+//! The metrics are served over HTTP from the tokio runtime, see
+//! [`MetricsServer`]. Each scrape collects a fresh snapshot on the primary
+//! reactor, as most of the underlying objects can only be accessed from
+//! there, and renders it in the Prometheus text exposition format.
+
+use std::fmt::{Display, Write};
+
+use crate::{
+    bdev::nexus::{nexus_iter, ChannelStats},
+    core::{reactor_is_frozen, BlockDeviceIoStats, Reactors, UntypedBdev},
+    lvs::Lvs,
+    subsys::PollGroupStats,
+};
+
+mod server;
+
+pub use server::MetricsServer;
+
+/// Type of a metric family.
+#[derive(Debug, Clone, Copy)]
+enum MetricType {
+    Counter,
+    Gauge,
+}
+
+impl Display for MetricType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Counter => write!(f, "counter"),
+            Self::Gauge => write!(f, "gauge"),
+        }
+    }
+}
+
+/// Writer of metrics in the Prometheus text exposition format.
+#[derive(Default)]
+pub(crate) struct MetricsWriter {
+    out: String,
+}
+
+impl MetricsWriter {
+    /// Start a new metric family.
+    fn family(&mut self, name: &str, help: &str, kind: MetricType) {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+    }
+
+    /// Write a sample of the current metric family.
+    fn sample(
+        &mut self,
+        name: &str,
+        labels: &[(&str, &str)],
+        value: impl Display,
+    ) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            let labels = labels
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+                .collect::<Vec<_>>()
+                .join(",");
+            let _ = write!(self.out, "{{{}}}", labels);
+        }
+        let _ = writeln!(self.out, " {}", value);
+    }
+
+    /// Write a family consisting of a single unlabelled sample.
+    fn single(
+        &mut self,
+        name: &str,
+        help: &str,
+        kind: MetricType,
+        value: impl Display,
+    ) {
+        self.family(name, help, kind);
+        self.sample(name, &[], value);
+    }
+
+    /// Returns the rendered metrics.
+    fn finish(self) -> String {
+        self.out
+    }
+}
+
+/// Escape a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// I/O statistics of a block device, along with its labels.
+struct IoStatsSample {
+    labels: Vec<(&'static str, String)>,
+    stats: BlockDeviceIoStats,
+}
+
+/// Write the I/O statistics of a set of block devices.
+fn write_io_stats(
+    w: &mut MetricsWriter,
+    prefix: &str,
+    samples: &[IoStatsSample],
+) {
+    let counters: [(&str, &str, fn(&BlockDeviceIoStats) -> u64); 6] = [
+        ("read_ops_total", "Number of read operations", |s| {
+            s.num_read_ops
+        }),
+        ("write_ops_total", "Number of write operations", |s| {
+            s.num_write_ops
+        }),
+        ("unmap_ops_total", "Number of unmap operations", |s| {
+            s.num_unmap_ops
+        }),
+        ("read_bytes_total", "Number of bytes read", |s| s.bytes_read),
+        ("written_bytes_total", "Number of bytes written", |s| {
+            s.bytes_written
+        }),
+        ("unmapped_bytes_total", "Number of bytes unmapped", |s| {
+            s.bytes_unmapped
+        }),
+    ];
+
+    for (suffix, help, get) in counters.iter() {
+        let name = format!("{}_{}", prefix, suffix);
+        w.family(&name, help, MetricType::Counter);
+        for sample in samples {
+            let labels = sample
+                .labels
+                .iter()
+                .map(|(k, v)| (*k, v.as_str()))
+                .collect::<Vec<_>>();
+            w.sample(&name, &labels, get(&sample.stats));
+        }
+    }
+}
+
+/// Collect pool capacity metrics.
+fn collect_pools(w: &mut MetricsWriter) {
+    let pools = Lvs::iter()
+        .map(|p| (p.name().to_string(), p.capacity(), p.used(), p.available()))
+        .collect::<Vec<_>>();
+
+    w.family(
+        "io_engine_pool_capacity_bytes",
+        "Total capacity of the pool",
+        MetricType::Gauge,
+    );
+    for (name, capacity, _, _) in &pools {
+        w.sample("io_engine_pool_capacity_bytes", &[("pool", name)], capacity);
+    }
+    w.family(
+        "io_engine_pool_used_bytes",
+        "Used capacity of the pool",
+        MetricType::Gauge,
+    );
+    for (name, _, used, _) in &pools {
+        w.sample("io_engine_pool_used_bytes", &[("pool", name)], used);
+    }
+    w.family(
+        "io_engine_pool_available_bytes",
+        "Available capacity of the pool",
+        MetricType::Gauge,
+    );
+    for (name, _, _, available) in &pools {
+        w.sample(
+            "io_engine_pool_available_bytes",
+            &[("pool", name)],
+            available,
+        );
+    }
+    w.family(
+        "io_engine_pool_committed_bytes",
+        "Capacity of the replicas of the pool",
+        MetricType::Gauge,
+    );
+    for lvs in Lvs::iter() {
+        w.sample(
+            "io_engine_pool_committed_bytes",
+            &[("pool", lvs.name())],
+            lvs.committed(),
+        );
+    }
+}
+
+/// Collect replica I/O metrics.
+async fn collect_replicas(w: &mut MetricsWriter) {
+    let lvols = Lvs::iter()
+        .filter_map(|p| p.lvols())
+        .flatten()
+        .collect::<Vec<_>>();
+
+    let mut samples = Vec::with_capacity(lvols.len());
+    for lvol in lvols {
+        match lvol.as_bdev().stats_async().await {
+            Ok(stats) => samples.push(IoStatsSample {
+                labels: vec![
+                    ("replica", lvol.name()),
+                    ("pool", lvol.pool_name()),
+                ],
+                stats,
+            }),
+            Err(e) => warn!("failed to get stats for {:?}: {}", lvol, e),
+        }
+    }
+
+    write_io_stats(w, "io_engine_replica", &samples);
+}
+
+/// Collect nexus I/O, channel, status and rebuild metrics.
+async fn collect_nexuses(w: &mut MetricsWriter) {
+    let mut samples = Vec::new();
+    let mut status = Vec::new();
+    let mut rebuilds = Vec::new();
+    let mut channels = Vec::new();
+
+    for nexus in nexus_iter() {
+        let name = nexus.name.clone();
+        status.push((name.clone(), nexus.status().to_string()));
+        channels.push((name.clone(), nexus.channel_counters()));
+
+        for child in nexus.children_iter() {
+            if let Some(job) = child.rebuild_job() {
+                let stats = job.stats();
+                rebuilds.push((
+                    name.clone(),
+                    child.uri().to_string(),
+                    stats.progress,
+                    stats.blocks_recovered,
+                    stats.blocks_total,
+                ));
+            }
+        }
+
+        if let Some(bdev) = UntypedBdev::lookup_by_name(&nexus.bdev_name()) {
+            match bdev.stats_async().await {
+                Ok(stats) => samples.push(IoStatsSample {
+                    labels: vec![("nexus", name)],
+                    stats,
+                }),
+                Err(e) => warn!("failed to get stats for {:?}: {}", nexus, e),
+            }
+        }
+    }
+
+    write_io_stats(w, "io_engine_nexus", &samples);
+
+    let counters: [(&str, &str, fn(&ChannelStats) -> u64); 3] = [
+        (
+            "fail_fast_total",
+            "Number of child devices taken out of the IO channels on an \
+            IO error",
+            |s| s.fail_fast,
+        ),
+        (
+            "io_retries_total",
+            "Number of IOs submitted again to the children after a failure",
+            |s| s.retries,
+        ),
+        (
+            "io_requeues_total",
+            "Number of IOs handed back to the bdev layer to be submitted \
+            again",
+            |s| s.requeues,
+        ),
+    ];
+    for (suffix, help, get) in counters.iter() {
+        let name = format!("io_engine_nexus_{}", suffix);
+        w.family(&name, help, MetricType::Counter);
+        for (nexus, stats) in &channels {
+            w.sample(&name, &[("nexus", nexus)], get(stats));
+        }
+    }
+
+    w.family(
+        "io_engine_nexus_status",
+        "Status of the nexus, the sample with the current status is set to 1",
+        MetricType::Gauge,
+    );
+    for (name, current) in &status {
+        for s in &["online", "degraded", "faulted", "shutting_down", "shutdown"]
+        {
+            w.sample(
+                "io_engine_nexus_status",
+                &[("nexus", name), ("status", s)],
+                (current == s) as u8,
+            );
+        }
+    }
+
+    w.family(
+        "io_engine_rebuild_progress_percent",
+        "Progress of the rebuild of a nexus child",
+        MetricType::Gauge,
+    );
+    for (nexus, child, progress, _, _) in &rebuilds {
+        w.sample(
+            "io_engine_rebuild_progress_percent",
+            &[("nexus", nexus), ("child", child)],
+            progress,
+        );
+    }
+    w.family(
+        "io_engine_rebuild_blocks_recovered",
+        "Number of blocks recovered by the rebuild of a nexus child",
+        MetricType::Gauge,
+    );
+    for (nexus, child, _, recovered, _) in &rebuilds {
+        w.sample(
+            "io_engine_rebuild_blocks_recovered",
+            &[("nexus", nexus), ("child", child)],
+            recovered,
+        );
+    }
+    w.family(
+        "io_engine_rebuild_blocks_total",
+        "Number of blocks to recover by the rebuild of a nexus child",
+        MetricType::Gauge,
+    );
+    for (nexus, child, _, _, total) in &rebuilds {
+        w.sample(
+            "io_engine_rebuild_blocks_total",
+            &[("nexus", nexus), ("child", child)],
+            total,
+        );
+    }
+}
+
+/// Collect reactor health metrics.
+fn collect_reactors(w: &mut MetricsWriter) {
+    w.family(
+        "io_engine_reactor_frozen",
+        "Whether the reactor has been found frozen by the reactor monitor",
+        MetricType::Gauge,
+    );
+    for r in Reactors::iter() {
+        w.sample(
+            "io_engine_reactor_frozen",
+            &[("core", &r.core().to_string())],
+            reactor_is_frozen(r.core()) as u8,
+        );
+    }
+    w.family(
+        "io_engine_reactor_state",
+        "State of the reactor, the sample with the current state is set to 1",
+        MetricType::Gauge,
+    );
+    for r in Reactors::iter() {
+        let current = r.get_state().to_string();
+        for s in &["Init", "Running", "Shutdown", "Delayed"] {
+            w.sample(
+                "io_engine_reactor_state",
+                &[("core", &r.core().to_string()), ("state", s)],
+                (current == *s) as u8,
+            );
+        }
+    }
+}
+
+/// Collect NVMe-oF target connection metrics.
+async fn collect_nvmf(w: &mut MetricsWriter) {
+    let stats = PollGroupStats::collect().await;
+    w.single(
+        "io_engine_nvmf_connected_controllers",
+        "Number of NVMe-oF controllers currently connected",
+        MetricType::Gauge,
+        stats.current_admin_qpairs,
+    );
+    w.single(
+        "io_engine_nvmf_connected_io_qpairs",
+        "Number of NVMe-oF I/O queue pairs currently connected",
+        MetricType::Gauge,
+        stats.current_io_qpairs,
+    );
+    w.single(
+        "io_engine_nvmf_controllers_total",
+        "Number of NVMe-oF controllers connected since startup",
+        MetricType::Counter,
+        stats.admin_qpairs,
+    );
+    w.single(
+        "io_engine_nvmf_io_qpairs_total",
+        "Number of NVMe-oF I/O queue pairs connected since startup",
+        MetricType::Counter,
+        stats.io_qpairs,
+    );
+    w.single(
+        "io_engine_nvmf_pending_bdev_io",
+        "Number of NVMe-oF I/Os waiting for a free bdev I/O",
+        MetricType::Gauge,
+        stats.pending_bdev_io,
+    );
+}
+
+/// Collect all metrics and render them in the Prometheus text exposition
+/// format. Must be called from the primary reactor.
+pub(crate) async fn collect() -> String {
+    let mut w = MetricsWriter::default();
+    collect_pools(&mut w);
+    collect_replicas(&mut w).await;
+    collect_nexuses(&mut w).await;
+    collect_reactors(&mut w);
+    collect_nvmf(&mut w).await;
+    w.finish()
+}
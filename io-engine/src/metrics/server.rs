@@ -0,0 +1,124 @@
+use std::{convert::Infallible, net::SocketAddr, time::Duration};
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body,
+    Method,
+    Request,
+    Response,
+    Server,
+    StatusCode,
+};
+
+use crate::{core::Reactor, health::node_health};
+
+/// Content type of the Prometheus text exposition format.
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Content type of the health of the node.
+const HEALTH_CONTENT_TYPE: &str = "application/json";
+
+/// Time the primary reactor is given to report the health of the node,
+/// past which it is taken as not responding.
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// HTTP server exposing the metrics on `/metrics`, and the liveness and the
+/// readiness of the node on `/healthz` and `/readyz`.
+pub struct MetricsServer;
+
+impl MetricsServer {
+    /// Serve the metrics on the given endpoint until the server fails.
+    /// Must be run on the tokio runtime.
+    pub async fn run(endpoint: SocketAddr) -> Result<(), ()> {
+        info!("Metrics server configured at address {}", endpoint);
+
+        let service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(Self::handle))
+        });
+
+        Server::try_bind(&endpoint)
+            .map_err(
+                |error| error!(%error, "Failed to bind the metrics server"),
+            )?
+            .serve(service)
+            .await
+            .map_err(|error| error!(%error, "Metrics server failed"))
+    }
+
+    /// Handle a single HTTP request.
+    async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        if req.method() != Method::GET {
+            return Ok(Self::response(StatusCode::NOT_FOUND, String::new()));
+        }
+        match req.uri().path() {
+            "/metrics" => {}
+            "/healthz" => return Ok(Self::health(false).await),
+            "/readyz" => return Ok(Self::health(true).await),
+            _ => {
+                return Ok(Self::response(StatusCode::NOT_FOUND, String::new()))
+            }
+        }
+
+        let metrics = match Reactor::spawn_at_primary(super::collect()) {
+            Ok(rx) => rx.await.ok(),
+            Err(_) => None,
+        };
+
+        Ok(match metrics {
+            Some(metrics) => Self::response(StatusCode::OK, metrics),
+            None => Self::response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "failed to collect metrics\n".to_string(),
+            ),
+        })
+    }
+
+    /// Report the liveness, or the readiness, of the node.
+    async fn health(readiness: bool) -> Response<Body> {
+        let health = match Reactor::spawn_at_primary(async { node_health() }) {
+            Ok(rx) => tokio::time::timeout(HEALTH_TIMEOUT, rx)
+                .await
+                .ok()
+                .and_then(|h| h.ok()),
+            Err(_) => None,
+        };
+
+        let (status, body) = match health {
+            Some(health) => (
+                if (readiness && health.ready) || (!readiness && health.live) {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                },
+                serde_json::to_string(&health).unwrap(),
+            ),
+            None => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                serde_json::json!({
+                    "live": false,
+                    "ready": false,
+                    "checks": [{
+                        "name": "reactors",
+                        "status": "failed",
+                        "reason": "primary reactor not responding",
+                    }],
+                })
+                .to_string(),
+            ),
+        };
+        Response::builder()
+            .status(status)
+            .header(hyper::header::CONTENT_TYPE, HEALTH_CONTENT_TYPE)
+            .body(Body::from(body))
+            .expect("valid response")
+    }
+
+    /// Build a response with the given status and body.
+    fn response(status: StatusCode, body: String) -> Response<Body> {
+        Response::builder()
+            .status(status)
+            .header(hyper::header::CONTENT_TYPE, CONTENT_TYPE)
+            .body(Body::from(body))
+            .expect("valid response")
+    }
+}
@@ -4,10 +4,17 @@
 //! etcd is used as the backing store and is interacted with through the use of
 //! the etcd-client crate. This crate has a dependency on the tokio async
 //! runtime.
+//!
+//! Ownership records (e.g. which node currently owns a nexus) are attached to
+//! an etcd lease which is kept alive for as long as this process is able to
+//! talk to etcd. If the lease expires, etcd deletes the records so that the
+//! resources can be safely taken over by another node.
 use crate::{
     core,
-    core::Reactor,
+    core::{MayastorEnvironment, Reactor},
+    jsonrpc::{jsonrpc_register, JsonRpcError},
     store::{
+        backoff::Backoff,
         etcd::Etcd,
         store_defs::{
             DeleteWait,
@@ -18,32 +25,146 @@ use crate::{
             StoreKey,
             StoreValue,
         },
+        store_schema::{from_record, to_record, Versioned},
     },
 };
-use futures::channel::oneshot;
+use etcd_client::EventType;
+use futures::{channel::oneshot, FutureExt};
 use once_cell::sync::OnceCell;
 use serde_json::Value;
 use snafu::ResultExt;
-use std::{future::Future, sync::Mutex, time::Duration};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 static DEFAULT_PORT: &str = "2379";
 static STORE_OP_TIMEOUT: Duration = Duration::from_secs(30);
+/// Default time-to-live of the lease used for ownership records.
+pub static DEFAULT_LEASE_TTL: Duration = Duration::from_secs(10);
+/// Initial delay between attempts to (re)connect to the backing store.
+static BACKOFF_BASE: Duration = Duration::from_millis(100);
+/// Maximum delay between attempts to (re)connect to the backing store.
+static BACKOFF_MAX: Duration = Duration::from_secs(10);
 static PERSISTENT_STORE: OnceCell<Option<Mutex<PersistentStore>>> =
     OnceCell::new();
 
+/// Callback invoked when the ownership of a key changes.
+type OwnershipListener = Arc<dyn Fn(OwnershipEvent) + Send + Sync>;
+
 /// Persistent store
 pub struct PersistentStore {
     /// Backing store used for persistence.
     store: Etcd,
     /// Endpoint of the backing store.
     endpoint: String,
+    /// Time-to-live of the lease.
+    lease_ttl: Duration,
+    /// Lease to which ownership records are attached.
+    lease_id: Option<i64>,
+    /// Time of the last successful lease keep-alive.
+    last_keep_alive: Option<Instant>,
+    /// Whether the connection to the backing store is believed to be healthy.
+    connected: bool,
+    /// Number of times the connection to the backing store was re-established.
+    reconnects: u64,
+    /// Number of times the lease expired.
+    lease_expirations: u64,
+    /// Number of currently active watches.
+    active_watches: u64,
+    /// Number of times a watch had to be re-established.
+    watch_restarts: u64,
+    /// Keys owned by this instance, with the watch guarding them.
+    owned_keys: HashMap<String, OwnedKey>,
+    /// Listeners notified about ownership changes.
+    ownership_listeners: Vec<OwnershipListener>,
+}
+
+/// A key owned by this instance.
+struct OwnedKey {
+    /// Watch detecting the key being taken over.
+    _watch: StoreWatch,
+    /// Ownership has been lost and not re-acquired yet.
+    lost: bool,
+}
+
+/// Value of an ownership record.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OwnershipRecord {
+    /// Name of the node owning the resource.
+    pub owner: String,
+}
+
+impl OwnershipRecord {
+    /// Ownership record for this node.
+    fn local() -> Self {
+        Self {
+            owner: MayastorEnvironment::global_or_default().node_name,
+        }
+    }
+}
+
+/// Ownership change notification.
+#[derive(Debug, Clone)]
+pub enum OwnershipEvent {
+    /// Ownership of the key has been lost, either because the lease expired
+    /// or because another node took over the key.
+    Lost { key: String, owner: Option<String> },
+    /// Ownership of a previously lost key has been re-acquired.
+    Reacquired { key: String },
+}
+
+/// Change of a watched key.
+#[derive(Debug, Clone)]
+pub struct StoreWatchEvent {
+    /// The watched key.
+    pub key: String,
+    /// New value of the key, or `None` if the key has been deleted.
+    pub value: Option<Value>,
+}
+
+/// Handle to an active watch. The watch is cancelled when the handle is
+/// dropped.
+pub struct StoreWatch {
+    _cancel: tokio::sync::watch::Sender<bool>,
+}
+
+/// Health of the persistent store client.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct StoreHealth {
+    /// Whether the persistent store is enabled.
+    pub enabled: bool,
+    /// Endpoint of the backing store.
+    pub endpoint: Option<String>,
+    /// Whether the connection to the backing store is healthy.
+    pub connected: bool,
+    /// Lease to which ownership records are attached.
+    pub lease_id: Option<i64>,
+    /// Time-to-live of the lease, in seconds.
+    pub lease_ttl_secs: u64,
+    /// Milliseconds elapsed since the last successful keep-alive.
+    pub last_keep_alive_ms: Option<u64>,
+    /// Number of times the connection was re-established.
+    pub reconnects: u64,
+    /// Number of times the lease expired.
+    pub lease_expirations: u64,
+    /// Number of currently active watches.
+    pub active_watches: u64,
+    /// Number of times a watch had to be re-established.
+    pub watch_restarts: u64,
+    /// Keys currently owned by this instance.
+    pub owned_keys: Vec<String>,
+    /// Keys whose ownership has been lost.
+    pub lost_keys: Vec<String>,
 }
 
 impl PersistentStore {
     /// Initialise the persistent store.
     /// If the supplied endpoint is 'None', the store is uninitalised and
     /// unavailable for use.
-    pub async fn init(endpoint: Option<String>) {
+    pub async fn init(endpoint: Option<String>, lease_ttl: Duration) {
         if endpoint.is_none() {
             // No endpoint means no persistent store.
             warn!("Persistent store not initialised");
@@ -59,8 +180,21 @@ impl PersistentStore {
             Some(Mutex::new(PersistentStore {
                 store,
                 endpoint,
+                lease_ttl,
+                lease_id: None,
+                last_keep_alive: None,
+                connected: true,
+                reconnects: 0,
+                lease_expirations: 0,
+                active_watches: 0,
+                watch_restarts: 0,
+                owned_keys: HashMap::new(),
+                ownership_listeners: Vec::new(),
             }))
         });
+
+        Self::grant_lease().await;
+        core::runtime::spawn(Self::lease_keep_alive_loop());
     }
 
     /// Adds the default port to the endpoint if one isn't already specified.
@@ -71,12 +205,17 @@ impl PersistentStore {
         }
     }
 
+    /// Backoff used for retrying operations against the backing store.
+    fn backoff() -> Backoff {
+        Backoff::new(BACKOFF_BASE, BACKOFF_MAX)
+    }
+
     /// Connect to etcd as the backing store.
     /// A connection to the store will be attempted continuously until
     /// successful. This is necessary as the backing store is essential to the
     /// operation of Mayastor across restarts.
     async fn connect_to_backing_store(endpoint: &str) -> Etcd {
-        let mut output_err = true;
+        let mut backoff = Self::backoff();
         loop {
             match Etcd::new(endpoint).await {
                 Ok(store) => {
@@ -84,16 +223,15 @@ impl PersistentStore {
                     return store;
                 }
                 Err(_) => {
-                    if output_err {
+                    if backoff.attempts() == 0 {
                         // Only output the error on first failure to prevent
                         // flooding the logs.
                         error!(
                             "Failed to connect to etcd on endpoint {}. Retrying...",
                             endpoint
                         );
-                        output_err = false;
                     }
-                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    backoff.wait().await;
                 }
             }
         }
@@ -155,6 +293,38 @@ impl PersistentStore {
         })?
     }
 
+    /// Retrieve all the key-values whose key starts with the given prefix from
+    /// the store.
+    pub async fn get_prefix(
+        key_prefix: &str,
+    ) -> Result<Vec<(String, Value)>, StoreError> {
+        let prefix = key_prefix.to_string();
+        let rx = Self::execute_store_op(async move {
+            info!("Getting keys with prefix {} from store.", prefix);
+            Self::backing_store().get_values_prefix(&prefix).await
+        });
+        rx.await.context(GetWait {
+            key: key_prefix.to_string(),
+        })?
+    }
+
+    /// Put a record in the store, with the current version of its schema.
+    pub async fn put_record<T: Versioned>(
+        key: &impl StoreKey,
+        value: &T,
+    ) -> Result<(), StoreError> {
+        Self::put(key, &to_record(value)?).await
+    }
+
+    /// Retrieve a record, with the given key, from the store, upgraded to
+    /// the current version of its schema.
+    pub async fn get_record<T: Versioned>(
+        key: &impl StoreKey,
+    ) -> Result<T, StoreError> {
+        let record = Self::get(key).await?;
+        from_record(&key.to_string(), record)
+    }
+
     /// Delete the entry in the store with the given key.
     pub async fn delete(key: &impl StoreKey) -> Result<(), StoreError> {
         let key_string = key.to_string();
@@ -176,6 +346,441 @@ impl PersistentStore {
         })?
     }
 
+    /// Take ownership of the given key by writing an ownership record for
+    /// this node, attached to the lease of this instance.
+    /// Fails if the key is owned by another node. Ownership previously held
+    /// by this node (e.g. before a restart) is taken over.
+    pub async fn acquire_ownership(
+        key: &impl StoreKey,
+    ) -> Result<(), StoreError> {
+        let key_string = key.to_string();
+        let rx = Self::execute_store_op(async move {
+            info!("Acquiring ownership of key {}.", key_string);
+            Self::try_acquire_ownership(&key_string).await?;
+
+            let watch_key = key_string.clone();
+            let watch = Self::watch(&key_string, move |event| {
+                Self::check_ownership(&watch_key, event.value)
+            });
+            Self::new().lock().unwrap().owned_keys.insert(
+                key_string.clone(),
+                OwnedKey {
+                    _watch: watch,
+                    lost: false,
+                },
+            );
+            info!("Successfully acquired ownership of key {}", key_string);
+            Ok(())
+        });
+        rx.await.context(PutWait {
+            key: key.to_string(),
+            value: format!("{:?}", OwnershipRecord::local()),
+        })?
+    }
+
+    /// Give up ownership of the given key and delete the ownership record.
+    pub async fn release_ownership(
+        key: &impl StoreKey,
+    ) -> Result<(), StoreError> {
+        let key_string = key.to_string();
+        let rx = Self::execute_store_op(async move {
+            let owned =
+                Self::new().lock().unwrap().owned_keys.remove(&key_string);
+            match owned {
+                Some(owned) if !owned.lost => {
+                    info!("Releasing ownership of key {}.", key_string);
+                    Self::backing_store().delete_kv(&key_string).await
+                }
+                _ => Ok(()),
+            }
+        });
+        rx.await.context(DeleteWait {
+            key: key.to_string(),
+        })?
+    }
+
+    /// Register a listener to be notified about ownership changes.
+    /// Listeners are called from the tokio runtime.
+    pub fn add_ownership_listener(
+        listener: impl Fn(OwnershipEvent) + Send + Sync + 'static,
+    ) {
+        if !Self::enabled() {
+            return;
+        }
+        Self::new()
+            .lock()
+            .unwrap()
+            .ownership_listeners
+            .push(Arc::new(listener));
+    }
+
+    /// Write the ownership record of the given key, provided it does not
+    /// exist or is owned by this node already.
+    async fn try_acquire_ownership(key: &str) -> Result<(), StoreError> {
+        let record = OwnershipRecord::local();
+        let lease_id = Self::lease_id().ok_or(StoreError::NoLease {})?;
+        let mut store = Self::backing_store();
+
+        let mut mod_revision = None;
+        // The record can change in between the two attempts, in which case
+        // another node is competing for it.
+        for _ in 0 .. 2 {
+            let current = store
+                .put_kv_leased_if(&key, &record, lease_id, mod_revision)
+                .await?;
+            match current {
+                None => return Ok(()),
+                Some((Value::Null, _)) => mod_revision = None,
+                Some((value, revision)) => {
+                    match serde_json::from_value::<OwnershipRecord>(value) {
+                        Ok(current) if current == record => {
+                            mod_revision = Some(revision);
+                        }
+                        Ok(current) => {
+                            return Err(StoreError::OwnershipConflict {
+                                key: key.to_string(),
+                                owner: current.owner,
+                            });
+                        }
+                        Err(_) => {
+                            return Err(StoreError::OwnershipConflict {
+                                key: key.to_string(),
+                                owner: "unknown".to_string(),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        Err(StoreError::OwnershipConflict {
+            key: key.to_string(),
+            owner: "unknown".to_string(),
+        })
+    }
+
+    /// Check an ownership record change of an owned key.
+    fn check_ownership(key: &str, value: Option<Value>) {
+        let owner = value
+            .and_then(|v| serde_json::from_value::<OwnershipRecord>(v).ok())
+            .map(|r| r.owner);
+        let ours = owner.as_ref() == Some(&OwnershipRecord::local().owner);
+
+        let listeners = {
+            let mut store = Self::new().lock().unwrap();
+            match store.owned_keys.get_mut(key) {
+                Some(owned) if owned.lost == ours => {
+                    owned.lost = !ours;
+                }
+                _ => return,
+            }
+            store.ownership_listeners.clone()
+        };
+
+        let event = if ours {
+            info!("Ownership of key {} has been re-acquired", key);
+            OwnershipEvent::Reacquired {
+                key: key.to_string(),
+            }
+        } else {
+            error!("Ownership of key {} lost, owner: {:?}", key, owner);
+            OwnershipEvent::Lost {
+                key: key.to_string(),
+                owner,
+            }
+        };
+        listeners.iter().for_each(|l| l(event.clone()));
+    }
+
+    /// Watch the given key, calling `callback` on the tokio runtime for every
+    /// change of the key.
+    /// The watch is automatically re-established after a connection loss or
+    /// a compaction of the store history, resuming from the last observed
+    /// revision. After a compaction, the current value of the key is reported
+    /// as changes may have been missed.
+    pub fn watch(
+        key: &impl StoreKey,
+        callback: impl Fn(StoreWatchEvent) + Send + Sync + 'static,
+    ) -> StoreWatch {
+        let (cancel, mut cancelled) = tokio::sync::watch::channel(false);
+        let key = key.to_string();
+
+        core::runtime::spawn(async move {
+            Self::new().lock().unwrap().active_watches += 1;
+
+            let mut backoff = Self::backoff();
+            let mut next_revision = None;
+            let mut resync = false;
+
+            'watch: loop {
+                let mut store = Self::backing_store();
+
+                if next_revision.is_none() {
+                    match store.get_kv_revision(&key).await {
+                        Ok((value, revision)) => {
+                            next_revision = Some(revision + 1);
+                            if resync {
+                                callback(StoreWatchEvent {
+                                    key: key.clone(),
+                                    value,
+                                });
+                            }
+                        }
+                        Err(e) => {
+                            warn!(
+                                "Failed to resync watch on key {}: {}",
+                                key, e
+                            );
+                            backoff.wait().await;
+                            continue;
+                        }
+                    }
+                }
+
+                let (_watcher, mut stream) =
+                    match store.watch_kv(&key, next_revision).await {
+                        Ok(w) => w,
+                        Err(e) => {
+                            warn!("Failed to watch key {}: {}", key, e);
+                            backoff.wait().await;
+                            continue;
+                        }
+                    };
+                backoff.reset();
+
+                loop {
+                    let message = tokio::select! {
+                        m = stream.message() => m,
+                        _ = cancelled.changed() => break 'watch,
+                    };
+
+                    match message {
+                        Ok(Some(resp)) => {
+                            if resp.compact_revision() > 0 {
+                                warn!(
+                                    "Watch on key {} compacted at revision {}, resyncing",
+                                    key,
+                                    resp.compact_revision()
+                                );
+                                next_revision = None;
+                                resync = true;
+                                break;
+                            }
+                            for event in resp.events() {
+                                if let Some(kv) = event.kv() {
+                                    next_revision = Some(kv.mod_revision() + 1);
+                                    let value = match event.event_type() {
+                                        EventType::Put => {
+                                            serde_json::from_slice(kv.value())
+                                                .ok()
+                                        }
+                                        EventType::Delete => None,
+                                    };
+                                    callback(StoreWatchEvent {
+                                        key: key.clone(),
+                                        value,
+                                    });
+                                }
+                            }
+                            if resp.canceled() {
+                                break;
+                            }
+                        }
+                        Ok(None) => break,
+                        Err(e) => {
+                            warn!("Watch on key {} failed: {}", key, e);
+                            break;
+                        }
+                    }
+                }
+
+                Self::new().lock().unwrap().watch_restarts += 1;
+                backoff.wait().await;
+            }
+
+            Self::new().lock().unwrap().active_watches -= 1;
+        });
+
+        StoreWatch {
+            _cancel: cancel,
+        }
+    }
+
+    /// Grant a new lease, retrying until successful.
+    async fn grant_lease() {
+        let ttl = Self::new().lock().unwrap().lease_ttl;
+        let mut backoff = Self::backoff();
+        loop {
+            match Self::backing_store()
+                .grant_lease(ttl.as_secs() as i64)
+                .await
+            {
+                Ok(lease_id) => {
+                    info!(
+                        "Granted persistent store lease {:x} with TTL {:?}",
+                        lease_id, ttl
+                    );
+                    let mut store = Self::new().lock().unwrap();
+                    store.lease_id = Some(lease_id);
+                    store.last_keep_alive = Some(Instant::now());
+                    return;
+                }
+                Err(e) => {
+                    if backoff.attempts() == 0 {
+                        error!("Failed to grant lease: {}. Retrying...", e);
+                    }
+                    backoff.wait().await;
+                }
+            }
+        }
+    }
+
+    /// Keeps the lease alive for as long as possible, re-establishing the
+    /// connection to the backing store and granting a new lease whenever
+    /// necessary.
+    async fn lease_keep_alive_loop() {
+        let mut backoff = Self::backoff();
+        loop {
+            let (lease_id, ttl) = {
+                let store = Self::new().lock().unwrap();
+                (store.lease_id, store.lease_ttl)
+            };
+            let lease_id = match lease_id {
+                Some(id) => id,
+                None => {
+                    Self::grant_lease().await;
+                    continue;
+                }
+            };
+
+            let error = Self::keep_lease_alive(lease_id, ttl).await;
+            Self::new().lock().unwrap().connected = false;
+
+            let expired = match error {
+                StoreError::LeaseExpired {
+                    ..
+                } => true,
+                _ => Self::new()
+                    .lock()
+                    .unwrap()
+                    .last_keep_alive
+                    .map_or(true, |t| t.elapsed() >= ttl),
+            };
+
+            if expired {
+                error!("Persistent store lease {:x} expired", lease_id);
+                Self::lease_expired().await;
+                backoff.reset();
+            } else {
+                warn!(
+                    "Failed to keep persistent store lease {:x} alive: {}",
+                    lease_id, error
+                );
+                backoff.wait().await;
+                Self::reconnect().await;
+            }
+        }
+    }
+
+    /// Sends keep-alive requests for the lease until a failure occurs, which
+    /// is returned.
+    async fn keep_lease_alive(lease_id: i64, ttl: Duration) -> StoreError {
+        let (mut keeper, mut stream) =
+            match Self::backing_store().keep_alive(lease_id).await {
+                Ok(k) => k,
+                Err(e) => return e,
+            };
+
+        loop {
+            if let Err(source) = keeper.keep_alive().await {
+                return StoreError::LeaseKeepAlive {
+                    lease_id,
+                    source,
+                };
+            }
+
+            match tokio::time::timeout(ttl, stream.message()).await {
+                Ok(Ok(Some(resp))) => {
+                    if resp.ttl() <= 0 {
+                        return StoreError::LeaseExpired {
+                            lease_id,
+                        };
+                    }
+                    let mut store = Self::new().lock().unwrap();
+                    store.last_keep_alive = Some(Instant::now());
+                    store.connected = true;
+                }
+                Ok(Ok(None)) | Err(_) => return StoreError::OpTimeout {},
+                Ok(Err(source)) => {
+                    return StoreError::LeaseKeepAlive {
+                        lease_id,
+                        source,
+                    }
+                }
+            }
+
+            tokio::time::sleep(ttl / 3).await;
+        }
+    }
+
+    /// Handles the expiry of the lease: all ownership records attached to it
+    /// are gone, so notify the listeners, grant a new lease and try to
+    /// re-acquire the ownership of keys that nobody else took over.
+    async fn lease_expired() {
+        let (keys, listeners) = {
+            let mut store = Self::new().lock().unwrap();
+            store.lease_id = None;
+            store.lease_expirations += 1;
+            let keys = store
+                .owned_keys
+                .iter_mut()
+                .filter(|(_, owned)| !owned.lost)
+                .map(|(key, owned)| {
+                    owned.lost = true;
+                    key.clone()
+                })
+                .collect::<Vec<_>>();
+            (keys, store.ownership_listeners.clone())
+        };
+
+        for key in &keys {
+            error!("Ownership of key {} lost: lease expired", key);
+            let event = OwnershipEvent::Lost {
+                key: key.clone(),
+                owner: None,
+            };
+            listeners.iter().for_each(|l| l(event.clone()));
+        }
+
+        Self::reconnect().await;
+        Self::grant_lease().await;
+
+        for key in keys {
+            match Self::try_acquire_ownership(&key).await {
+                Ok(_) => Self::check_ownership(
+                    &key,
+                    serde_json::to_value(OwnershipRecord::local()).ok(),
+                ),
+                Err(StoreError::OwnershipConflict {
+                    owner, ..
+                }) => {
+                    error!("Ownership of key {} taken over by {}", key, owner);
+                    let event = OwnershipEvent::Lost {
+                        key: key.clone(),
+                        owner: Some(owner),
+                    };
+                    listeners.iter().for_each(|l| l(event.clone()));
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to re-acquire ownership of key {}: {}",
+                        key, e
+                    )
+                }
+            }
+        }
+    }
+
     /// Executes a future representing a store operation (i.e. put, get, delete)
     /// on the tokio runtime.
     /// A channel is returned which is signalled when the operation completes.
@@ -189,6 +794,7 @@ impl PersistentStore {
             let result = match tokio::time::timeout(STORE_OP_TIMEOUT, f).await {
                 Ok(result) => result,
                 Err(_) => {
+                    Self::new().lock().unwrap().connected = false;
                     Self::reconnect().await;
                     Err(StoreError::OpTimeout {})
                 }
@@ -213,6 +819,33 @@ impl PersistentStore {
         PERSISTENT_STORE.get().is_some()
     }
 
+    /// Returns the health of the persistent store client.
+    pub fn health() -> StoreHealth {
+        if !Self::enabled() {
+            return StoreHealth::default();
+        }
+
+        let store = Self::new().lock().unwrap();
+        let (lost, owned): (Vec<_>, Vec<_>) =
+            store.owned_keys.iter().partition(|(_, o)| o.lost);
+        StoreHealth {
+            enabled: true,
+            endpoint: Some(store.endpoint.clone()),
+            connected: store.connected,
+            lease_id: store.lease_id,
+            lease_ttl_secs: store.lease_ttl.as_secs(),
+            last_keep_alive_ms: store
+                .last_keep_alive
+                .map(|t| t.elapsed().as_millis() as u64),
+            reconnects: store.reconnects,
+            lease_expirations: store.lease_expirations,
+            active_watches: store.active_watches,
+            watch_restarts: store.watch_restarts,
+            owned_keys: owned.into_iter().map(|(k, _)| k.clone()).collect(),
+            lost_keys: lost.into_iter().map(|(k, _)| k.clone()).collect(),
+        }
+    }
+
     /// Get the persistent store.
     fn new() -> &'static Mutex<PersistentStore> {
         PERSISTENT_STORE
@@ -227,6 +860,11 @@ impl PersistentStore {
         Self::new().lock().unwrap().store.clone()
     }
 
+    /// Get the lease ID, if a lease has been granted.
+    fn lease_id() -> Option<i64> {
+        Self::new().lock().unwrap().lease_id
+    }
+
     /// Get the endpoint of the backing store.
     fn endpoint() -> String {
         Self::new().lock().unwrap().endpoint.clone()
@@ -239,6 +877,17 @@ impl PersistentStore {
         let persistent_store = Self::new();
         let backing_store =
             Self::connect_to_backing_store(&PersistentStore::endpoint()).await;
-        persistent_store.lock().unwrap().store = backing_store;
+        let mut store = persistent_store.lock().unwrap();
+        store.store = backing_store;
+        store.connected = true;
+        store.reconnects += 1;
     }
 }
+
+/// Register the persistent store json-rpc methods.
+pub(crate) fn register_rpc_methods() {
+    jsonrpc_register::<(), _, _, JsonRpcError>(
+        "persistent_store_health",
+        |_| async move { Ok(PersistentStore::health()) }.boxed_local(),
+    );
+}
@@ -1,10 +1,19 @@
 mod rebuild_error;
 mod rebuild_job;
+mod rebuild_pacing;
 mod rebuild_state;
 mod rebuild_task;
 
 pub use rebuild_error::RebuildError;
 pub use rebuild_job::RebuildJob;
+pub(crate) use rebuild_pacing::{
+    latency_tracked,
+    register_rpc_methods,
+    ticks,
+    ticks_to_us,
+    IoLatency,
+};
+pub use rebuild_pacing::{rebuild_pacing, set_rebuild_pacing, RebuildPacing};
 pub use rebuild_state::RebuildState;
 use rebuild_state::RebuildStates;
 use rebuild_task::{RebuildTask, RebuildTasks, TaskResult};
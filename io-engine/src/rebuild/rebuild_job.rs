@@ -1,12 +1,16 @@
 use crossbeam::channel::{unbounded, Receiver, Sender};
 use futures::channel::{mpsc, oneshot};
 use snafu::ResultExt;
-use std::fmt;
+use std::{collections::BTreeSet, fmt, time::Duration};
 
 use spdk_rs::{DmaBuf, LbaRange};
 
 use crate::{
-    bdev::{device_open, nexus::nexus_iter, Nexus},
+    bdev::{
+        device_open,
+        nexus::{nexus_iter, nexus_lookup, RebuildSource},
+        Nexus,
+    },
     bdev_api::bdev_get_name,
     core::{
         Bdev,
@@ -19,10 +23,12 @@ use crate::{
         ReadMode,
         VerboseError,
     },
+    sleep::mayastor_sleep,
 };
 
 use super::{
     rebuild_error::*,
+    rebuild_pacing::Pace,
     RebuildError,
     RebuildState,
     RebuildStates,
@@ -38,6 +44,7 @@ use super::{
 use once_cell::sync::OnceCell;
 use spdk_rs::Thread;
 use std::{cell::UnsafeCell, collections::HashMap};
+use tracing::Instrument;
 
 unsafe impl Sync for RebuildInstances {}
 unsafe impl Send for RebuildInstances {}
@@ -85,6 +92,8 @@ pub struct RebuildJob<'n> {
     pub src_uri: String,
     /// target URI of the out of sync child in need of a rebuild
     pub dst_uri: String,
+    /// why the source was selected among the healthy children, if it was
+    pub source_selection: Option<RebuildSource>,
     /// TODO
     pub(super) block_size: u64,
     /// TODO
@@ -106,6 +115,12 @@ pub struct RebuildJob<'n> {
     pub(super) complete_chan: Vec<oneshot::Sender<RebuildState>>,
     /// rebuild copy error, if any
     pub error: Option<RebuildError>,
+    /// pacing of the copy tasks
+    pub(super) pace: Pace,
+    /// copy tasks which are not running
+    pub(super) idle: Vec<usize>,
+    /// offsets of the segments being copied
+    pub(super) in_flight: BTreeSet<u64>,
 
     // Pre-opened descriptors for source/destination block device.
     pub(super) src_descriptor: Box<dyn BlockDeviceDescriptor>,
@@ -162,16 +177,23 @@ impl<'n> RebuildJob<'n> {
         let source_hdl = Self::get_io_handle(&*src_descriptor)?;
         let destination_hdl = Self::get_io_handle(&*dst_descriptor)?;
 
+        let nexus_descriptor = Bdev::<Nexus>::open_by_name(nexus_name, false)
+            .context(BdevNotFound {
+            bdev: nexus_name.to_string(),
+        })?;
+
+        // the range is in blocks of the nexus, which the children may not
+        // share
+        let block_size = nexus_descriptor.bdev().block_len() as u64;
         if !Self::validate(
             source_hdl.get_device(),
             destination_hdl.get_device(),
             &range,
+            block_size,
         ) {
             return Err(RebuildError::InvalidParameters {});
         };
 
-        // validation passed, block size is the same for both
-        let block_size = destination_hdl.get_device().block_len();
         let segment_size_blks = SEGMENT_SIZE / block_size;
 
         let mut tasks = RebuildTasks {
@@ -195,16 +217,12 @@ impl<'n> RebuildJob<'n> {
             });
         }
 
-        let nexus_descriptor = Bdev::<Nexus>::open_by_name(nexus_name, false)
-            .context(BdevNotFound {
-            bdev: nexus_name.to_string(),
-        })?;
-
         Ok(Self {
             nexus_name: nexus_name.to_string(),
             nexus_descriptor,
             src_uri: src_uri.to_string(),
             dst_uri: dst_uri.to_string(),
+            source_selection: None,
             next: range.start,
             range,
             block_size,
@@ -215,6 +233,9 @@ impl<'n> RebuildJob<'n> {
             states: Default::default(),
             complete_chan: Vec::new(),
             error: None,
+            pace: Pace::new(),
+            idle: Vec::new(),
+            in_flight: BTreeSet::new(),
             src_descriptor,
             dst_descriptor,
         })
@@ -331,7 +352,8 @@ impl<'n> RebuildJob<'n> {
                     None => {
                         match self.states.pending {
                             None | Some(RebuildState::Running) => {
-                                self.start_task_by_id(r.id);
+                                self.idle.push(r.id);
+                                self.pace_tasks().await;
                             }
                             _ => {
                                 // await all active tasks as we might still have
@@ -352,6 +374,8 @@ impl<'n> RebuildJob<'n> {
                 None => {
                     // all senders have disconnected, out of place termination?
                     error!("Out of place termination with potentially {} active tasks", self.task_pool.active);
+                    (0 .. self.task_pool.active)
+                        .for_each(|_| Pace::completed());
                     let _ = self.terminate();
                     break;
                 }
@@ -501,17 +525,23 @@ impl<'n> RebuildJob<'n> {
     }
 
     /// Check if the source and destination block devices are compatible for
-    /// rebuild
+    /// rebuild: the range, in blocks of the given size, must be within both
+    /// devices and aligned on their blocks.
     fn validate(
         source: &dyn BlockDevice,
         destination: &dyn BlockDevice,
         range: &std::ops::Range<u64>,
+        block_size: u64,
     ) -> bool {
         // todo: make sure we don't overwrite the labels
         let data_partition_start = 0;
-        range.within(data_partition_start .. source.num_blocks())
-            && range.within(data_partition_start .. destination.num_blocks())
-            && source.block_len() == destination.block_len()
+        let bytes = range.start * block_size .. range.end * block_size;
+        [source, destination].iter().all(|d| {
+            bytes.within(data_partition_start .. d.size_in_bytes())
+                && bytes.start % d.block_len() == 0
+                && bytes.end % d.block_len() == 0
+                && SEGMENT_SIZE % d.block_len() == 0
+        })
     }
 
     /// Reconciles the pending state to the current and clear the pending.
@@ -543,6 +573,12 @@ impl<'n> RebuildJob<'n> {
         match self.state() {
             RebuildState::Paused | RebuildState::Init => {
                 let dst_uri = self.dst_uri.clone();
+                let span = info_span!(
+                    "rebuild",
+                    nexus = %self.nexus_name,
+                    src = %self.src_uri,
+                    dst = %self.dst_uri
+                );
                 Reactors::master().send_future(async move {
                     let job = match RebuildJob::lookup(&dst_uri) {
                         Ok(job) => job,
@@ -555,7 +591,7 @@ impl<'n> RebuildJob<'n> {
                     };
 
                     if job.reconcile_to_state(RebuildState::Running) {
-                        job.run().await;
+                        job.run().instrument(span).await;
                     }
                 });
             }
@@ -601,6 +637,24 @@ impl<'n> RebuildJob<'n> {
         }
     }
 
+    /// Skips the given number of blocks from the start of the range, which
+    /// the destination already holds, before the job is started.
+    pub fn skip(&mut self, blocks: u64) {
+        if self.state() != RebuildState::Init {
+            return;
+        }
+        self.next = std::cmp::min(self.range.start + blocks, self.range.end);
+        self.task_pool.segments_done =
+            (self.next - self.range.start) / self.segment_size_blks;
+    }
+
+    /// Returns the number of blocks from the start of the range which have
+    /// all been copied, i.e. up to the first segment not copied yet.
+    pub fn checkpoint(&self) -> u64 {
+        self.in_flight.iter().next().copied().unwrap_or(self.next)
+            - self.range.start
+    }
+
     /// Schedules the job to start in a future and returns a complete channel
     /// which can be waited on.
     pub fn start(
@@ -656,36 +710,73 @@ impl<'n> RebuildJob<'n> {
             self.task_pool.active
         );
 
-        for n in 0 .. self.task_pool.total {
-            self.next = match self.send_segment_task(n) {
+        self.pace = Pace::new();
+        self.idle = (0 .. self.task_pool.total).rev().collect();
+        self.start_idle_tasks();
+    }
+
+    /// Starts idle tasks for as long as the pacing of the rebuild allows.
+    fn start_idle_tasks(&mut self) {
+        while self.pace.may_start(self.task_pool.active) {
+            let id = match self.idle.pop() {
+                Some(id) => id,
+                None => break,
+            };
+            match self.send_segment_task(id) {
                 Some(next) => {
                     self.task_pool.active += 1;
-                    next
+                    self.in_flight.insert(self.next);
+                    self.next = next;
+                    Pace::started();
                 }
-                None => break, /* we've already got enough tasks to rebuild
-                                * the bdev */
-            };
+                None => {
+                    // we've already got enough tasks to rebuild the bdev
+                    self.idle.push(id);
+                    break;
+                }
+            }
         }
     }
 
-    /// TODO
-    fn start_task_by_id(&mut self, id: usize) {
-        match self.send_segment_task(id) {
-            Some(next) => {
-                self.task_pool.active += 1;
-                self.next = next;
+    /// Starts the next tasks once the pacing of the rebuild allows it, backing
+    /// off while the latency of the nexus is above the target.
+    async fn pace_tasks(&mut self) {
+        let latency = nexus_lookup(&self.nexus_name)
+            .map(|n| n.io_latency.average_us())
+            .unwrap_or_default();
+        let delay = self.pace.adjust(latency);
+        if !delay.is_zero() {
+            mayastor_sleep(delay).await.ok();
+        }
+
+        loop {
+            if !matches!(
+                self.states.pending,
+                None | Some(RebuildState::Running)
+            ) {
+                return;
             }
-            None => {
-                if self.task_pool.active == 0 {
-                    self.complete();
-                }
+            self.start_idle_tasks();
+            if self.task_pool.active > 0 || self.next >= self.range.end {
+                break;
             }
-        };
+            // the tasks of the other rebuilds of the core use up the pacing
+            mayastor_sleep(Duration::from_millis(1)).await.ok();
+        }
+
+        if self.task_pool.active == 0 {
+            self.complete();
+        }
     }
 
     /// TODO
     async fn await_one_task(&mut self) -> Option<TaskResult> {
-        self.task_pool.await_one_task().await
+        let result = self.task_pool.await_one_task().await;
+        if let Some(r) = &result {
+            Pace::completed();
+            self.in_flight.remove(&r.blk);
+        }
+        result
     }
 
     /// TODO
@@ -717,6 +808,7 @@ impl<'n> RebuildJob<'n> {
                 self.range.end,
             );
             let dst_uri = self.dst_uri.clone();
+            let span = debug_span!("rebuild_segment", id, blk);
 
             Reactors::current().send_future(async move {
                 let job = Self::lookup(&dst_uri).unwrap();
@@ -724,7 +816,11 @@ impl<'n> RebuildJob<'n> {
                 let r = TaskResult {
                     blk,
                     id,
-                    error: job.locked_copy_one(id, blk).await.err(),
+                    error: job
+                        .locked_copy_one(id, blk)
+                        .instrument(span)
+                        .await
+                        .err(),
                 };
 
                 let task = &mut job.task_pool.tasks[id];
@@ -0,0 +1,234 @@
+//! Pacing of the rebuild copies.
+//!
+//! The copy tasks of a rebuild job read from the source child as fast as it
+//! allows, which competes with the I/Os of the applications for the source
+//! and the network. The pacing bounds the number of copies in flight on a
+//! core, across the rebuild jobs of the core, and can delay every copy.
+//!
+//! When a latency target is set, the latency of the I/Os of the nexuses is
+//! measured, and a rebuild job backs off whenever the latency of its nexus
+//! exceeds the target: it halves the number of its copies in flight and
+//! doubles the delay between them, and then speeds up again, one more copy
+//! at a time, while the latency stays below the target. This rebuilds only
+//! as fast as the latency of the applications allows, rather than at a rate
+//! which is either too slow for an idle nexus or too fast for a busy one.
+
+use std::{
+    cell::Cell,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use spdk_rs::libspdk::{spdk_get_ticks, spdk_get_ticks_hz};
+
+use super::SEGMENT_TASKS;
+use crate::jsonrpc::{jsonrpc_register, JsonRpcError};
+
+/// Longest delay between two copies of a rebuild job backing off.
+const MAX_DELAY: Duration = Duration::from_millis(100);
+
+/// Delay a backing off rebuild job starts from when no delay is configured.
+const MIN_BACKOFF_DELAY: Duration = Duration::from_millis(1);
+
+/// Age beyond which the latency of a nexus is stale, when the nexus has not
+/// completed any I/O since.
+const LATENCY_TTL: Duration = Duration::from_secs(1);
+
+/// Pacing of the rebuild jobs of the node.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RebuildPacing {
+    /// Delay between two copies of a copy task, in microseconds.
+    pub delay_us: u64,
+    /// Maximum number of copies in flight on a core.
+    pub max_inflight: usize,
+    /// Latency of the nexus I/Os, in microseconds, above which the rebuild
+    /// jobs of the nexus back off, 0 for static pacing.
+    pub latency_target_us: u64,
+}
+
+impl Default for RebuildPacing {
+    fn default() -> Self {
+        Self {
+            delay_us: 0,
+            max_inflight: SEGMENT_TASKS,
+            latency_target_us: 0,
+        }
+    }
+}
+
+impl RebuildPacing {
+    /// Returns the maximum number of copies in flight on a core.
+    fn core_inflight(&self) -> usize {
+        self.max_inflight.max(1)
+    }
+
+    /// Returns the maximum number of copies in flight of a job.
+    fn job_inflight(&self) -> usize {
+        self.max_inflight.clamp(1, SEGMENT_TASKS)
+    }
+}
+
+static PACING: Lazy<Mutex<RebuildPacing>> =
+    Lazy::new(|| Mutex::new(RebuildPacing::default()));
+
+/// Latency target, cached for the I/O path.
+static LATENCY_TARGET_US: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// Number of copies in flight on this core.
+    static CORE_INFLIGHT: Cell<usize> = Cell::new(0);
+}
+
+/// Returns the pacing of the rebuild jobs.
+pub fn rebuild_pacing() -> RebuildPacing {
+    PACING.lock().clone()
+}
+
+/// Set the pacing of the rebuild jobs, applied by the running jobs from
+/// their next copy.
+pub fn set_rebuild_pacing(pacing: RebuildPacing) {
+    info!("Setting the rebuild pacing to {:?}", pacing);
+    LATENCY_TARGET_US.store(pacing.latency_target_us, Ordering::Relaxed);
+    *PACING.lock() = pacing;
+}
+
+/// Returns true if the latency of the nexus I/Os is measured.
+#[inline(always)]
+pub(crate) fn latency_tracked() -> bool {
+    LATENCY_TARGET_US.load(Ordering::Relaxed) > 0
+}
+
+/// Returns the current ticks.
+#[inline(always)]
+pub(crate) fn ticks() -> u64 {
+    unsafe { spdk_get_ticks() }
+}
+
+/// Converts ticks to microseconds.
+pub(crate) fn ticks_to_us(ticks: u64) -> u64 {
+    ticks * 1_000_000 / unsafe { spdk_get_ticks_hz() }
+}
+
+/// Moving average of the latency of the I/Os of a nexus, updated from all
+/// the cores without synchronisation as an approximation is enough.
+#[derive(Debug, Default)]
+pub(crate) struct IoLatency {
+    /// Moving average of the latency, in microseconds.
+    average_us: AtomicU64,
+    /// Ticks of the last completion.
+    updated: AtomicU64,
+}
+
+impl IoLatency {
+    /// Account an I/O submitted at the given ticks and completed now.
+    #[inline]
+    pub(crate) fn completed(&self, submitted: u64) {
+        let now = ticks();
+        let sample = ticks_to_us(now.saturating_sub(submitted));
+        let average = self.average_us.load(Ordering::Relaxed);
+        let average = if average == 0 {
+            sample
+        } else {
+            average - average / 8 + sample / 8
+        };
+        self.average_us.store(average, Ordering::Relaxed);
+        self.updated.store(now, Ordering::Relaxed);
+    }
+
+    /// Returns the average latency in microseconds, 0 if the nexus did not
+    /// complete any I/O lately.
+    pub(crate) fn average_us(&self) -> u64 {
+        let age = ticks_to_us(
+            ticks().saturating_sub(self.updated.load(Ordering::Relaxed)),
+        );
+        if age > LATENCY_TTL.as_micros() as u64 {
+            0
+        } else {
+            self.average_us.load(Ordering::Relaxed)
+        }
+    }
+}
+
+/// Pacing state of a rebuild job.
+#[derive(Debug)]
+pub(super) struct Pace {
+    /// Number of copies the job may have in flight.
+    window: usize,
+    /// Delay before the next copies.
+    delay: Duration,
+}
+
+impl Pace {
+    pub(super) fn new() -> Self {
+        let pacing = rebuild_pacing();
+        Self {
+            window: pacing.job_inflight(),
+            delay: Duration::from_micros(pacing.delay_us),
+        }
+    }
+
+    /// Adjust the pace to the latency of the nexus of the job, returning the
+    /// delay before the next copies.
+    pub(super) fn adjust(&mut self, latency_us: u64) -> Duration {
+        let pacing = rebuild_pacing();
+        let base = Duration::from_micros(pacing.delay_us);
+
+        if pacing.latency_target_us > 0 && latency_us > pacing.latency_target_us
+        {
+            self.window /= 2;
+            self.delay = (self.delay * 2).max(MIN_BACKOFF_DELAY).min(MAX_DELAY);
+        } else {
+            self.window += 1;
+            // halve the delay until back to the configured one
+            let delay = self.delay / 2;
+            self.delay = if delay < MIN_BACKOFF_DELAY {
+                base
+            } else {
+                delay.max(base)
+            };
+        }
+        self.window = self.window.clamp(1, pacing.job_inflight());
+        self.delay
+    }
+
+    /// Returns true if the job may start another copy with the given number
+    /// of copies in flight.
+    pub(super) fn may_start(&self, active: usize) -> bool {
+        active < self.window
+            && CORE_INFLIGHT.with(|c| c.get())
+                < rebuild_pacing().core_inflight()
+    }
+
+    /// Account a copy started on the current core.
+    pub(super) fn started() {
+        CORE_INFLIGHT.with(|c| c.set(c.get() + 1));
+    }
+
+    /// Account a copy completed on the current core.
+    pub(super) fn completed() {
+        CORE_INFLIGHT.with(|c| c.set(c.get().saturating_sub(1)));
+    }
+}
+
+/// Register the rebuild pacing json-rpc methods.
+pub(crate) fn register_rpc_methods() {
+    jsonrpc_register::<_, _, _, JsonRpcError>(
+        "rebuild_set_pacing",
+        |args: RebuildPacing| {
+            async move {
+                set_rebuild_pacing(args);
+                Ok(rebuild_pacing())
+            }
+            .boxed_local()
+        },
+    );
+
+    jsonrpc_register::<(), _, _, JsonRpcError>("rebuild_get_pacing", |_| {
+        async move { Ok(rebuild_pacing()) }.boxed_local()
+    });
+}
@@ -0,0 +1,822 @@
+//! Startup reconciliation of the resources created on this node.
+//!
+//! The definitions of the nexuses and replicas created on this node are
+//! recorded in the persistent store. On startup, once the local pools have
+//! been imported, the recorded definitions are compared against what has
+//! been discovered locally and the differences are dealt with according to
+//! the configured [`ReconcilePolicy`].
+//!
+//! Additions of children left in doubt by the node going down are then
+//! replayed or rolled back.
+//!
+//! The outcome is kept in a [`StartupReport`] which can be retrieved with the
+//! `get_startup_report` json-rpc method.
+
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    fmt::{Display, Formatter},
+    str::FromStr,
+    time::Instant,
+};
+
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use crate::{
+    bdev::{
+        device_destroy,
+        device_lookup,
+        nexus::{
+            nexus_create_lazy,
+            nexus_create_v2,
+            nexus_lookup,
+            nexus_lookup_mut,
+            ChildIntent,
+            ChildIntentPhase,
+            NexusChild,
+            NexusInfo,
+            NexusNvmeParams,
+            NvmeReservation,
+        },
+    },
+    bdev_api::bdev_get_name,
+    core::{MayastorEnvironment, Reactors, VerboseError},
+    jsonrpc::{jsonrpc_register, JsonRpcError},
+    labels::Labels,
+    lvs::{Lvol, Lvs},
+    persistent_store::PersistentStore,
+    store::store_schema::{from_record, Versioned},
+};
+
+/// Report of the last startup reconciliation.
+static STARTUP_REPORT: Lazy<Mutex<StartupReport>> =
+    Lazy::new(|| Mutex::new(StartupReport::default()));
+
+/// Policy applied to the differences found between the recorded and the
+/// discovered resources.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReconcilePolicy {
+    /// Only report the differences.
+    Report,
+    /// Re-create the recorded nexuses with the children which were healthy
+    /// when the node went down.
+    Restore,
+    /// As `Restore`, and also delete the records of resources which can no
+    /// longer be restored.
+    Prune,
+}
+
+impl Default for ReconcilePolicy {
+    fn default() -> Self {
+        Self::Report
+    }
+}
+
+impl FromStr for ReconcilePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "report" => Ok(Self::Report),
+            "restore" => Ok(Self::Restore),
+            "prune" => Ok(Self::Prune),
+            _ => Err(format!("Bad reconcile policy: {}", s)),
+        }
+    }
+}
+
+impl Display for ReconcilePolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Report => "report",
+                Self::Restore => "restore",
+                Self::Prune => "prune",
+            }
+        )
+    }
+}
+
+/// Definition of a replica, as recorded in the persistent store.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplicaSpec {
+    /// Name of the replica.
+    pub name: String,
+    /// UUID of the replica.
+    pub uuid: String,
+    /// Name of the pool the replica lives on.
+    pub pool: String,
+    /// Size of the replica in bytes.
+    pub size: u64,
+    /// Whether the replica is thin provisioned.
+    pub thin: bool,
+}
+
+impl Versioned for ReplicaSpec {
+    const KIND: &'static str = "replica definition";
+}
+
+impl From<&Lvol> for ReplicaSpec {
+    fn from(lvol: &Lvol) -> Self {
+        Self {
+            name: lvol.name(),
+            uuid: lvol.uuid(),
+            pool: lvol.pool_name(),
+            size: lvol.size(),
+            thin: lvol.is_thin(),
+        }
+    }
+}
+
+/// Definition of a nexus, as recorded in the persistent store.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NexusSpec {
+    /// Name of the nexus.
+    pub name: String,
+    /// Alias of the nexus, if it has been renamed.
+    #[serde(default)]
+    pub alias: Option<String>,
+    /// Labels of the nexus.
+    #[serde(default)]
+    pub labels: Labels,
+    /// Whether the nexus is exported read-only.
+    #[serde(default)]
+    pub read_only: bool,
+    /// UUID of the nexus, as given on creation.
+    pub uuid: String,
+    /// Requested size of the nexus in bytes.
+    pub size: u64,
+    /// URIs of the children.
+    pub children: Vec<String>,
+    /// Key of the persisted NexusInfo structure, if supplied on creation.
+    pub nexus_info_key: Option<String>,
+    /// Minimum NVMe controller ID.
+    pub min_cntlid: u16,
+    /// Maximum NVMe controller ID.
+    pub max_cntlid: u16,
+    /// NVMe reservation key.
+    pub resv_key: u64,
+    /// NVMe reservation type.
+    pub resv_type: u8,
+    /// Block size of the nexus until its remote children are connected, if
+    /// they are connected lazily.
+    #[serde(default)]
+    pub lazy_block_len: Option<u32>,
+}
+
+impl Versioned for NexusSpec {
+    const KIND: &'static str = "nexus definition";
+}
+
+impl NexusSpec {
+    /// NVMe parameters of the nexus.
+    pub(crate) fn nvme_params(&self) -> NexusNvmeParams {
+        let mut params = NexusNvmeParams::default();
+        params.set_min_cntlid(self.min_cntlid);
+        params.set_max_cntlid(self.max_cntlid);
+        params.set_resv_key(self.resv_key);
+        if let Ok(resv_type) = NvmeReservation::try_from(self.resv_type) {
+            params.set_resv_type(resv_type);
+        }
+        params
+    }
+
+    /// Give back its alias, labels and read-only mode to the nexus re-created
+    /// from this definition.
+    pub(crate) async fn restore_settings(&self) {
+        if let Some(mut nexus) = nexus_lookup_mut(&self.name) {
+            if let Err(e) = nexus.as_mut().rename(self.alias.clone()).await {
+                warn!(
+                    "Failed to restore alias {:?} of nexus {}: {}",
+                    self.alias, self.name, e
+                );
+            }
+            if let Err(e) = nexus.set_labels(self.labels.clone()).await {
+                warn!("Failed to restore labels of nexus {}: {}", self.name, e);
+            }
+            if let Err(e) = nexus.set_read_only(self.read_only).await {
+                warn!("Failed to make nexus {} read-only: {}", self.name, e);
+            }
+        }
+    }
+
+    /// Key of the NexusInfo structure.
+    fn nexus_info_key(&self) -> String {
+        self.nexus_info_key
+            .clone()
+            .unwrap_or_else(|| self.uuid.clone())
+    }
+}
+
+/// Progress of the startup reconciliation.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReconcileState {
+    /// Reconciliation has not started yet.
+    NotStarted,
+    /// Reconciliation is in progress.
+    Running,
+    /// Reconciliation has completed.
+    Completed,
+    /// The persistent store is not enabled, nothing to reconcile against.
+    Disabled,
+}
+
+impl Default for ReconcileState {
+    fn default() -> Self {
+        Self::NotStarted
+    }
+}
+
+/// Outcome of the reconciliation of a replica.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplicaOutcome {
+    /// The recorded replica has been found in a local pool.
+    Present,
+    /// The recorded replica has not been found.
+    Missing,
+    /// The replica has been found but has no record.
+    Orphaned,
+    /// The record of the missing replica has been deleted.
+    Pruned,
+}
+
+/// Outcome of the reconciliation of a nexus.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NexusOutcome {
+    /// The nexus already exists.
+    Existing,
+    /// The nexus has not been re-created because of the policy.
+    NotRestored,
+    /// The nexus has been re-created with all its children.
+    Restored,
+    /// The nexus has been re-created with some of its children missing.
+    Degraded,
+    /// None of the children of the nexus were healthy.
+    NoHealthyChildren,
+    /// The nexus could not be re-created.
+    Failed,
+    /// The record of the nexus has been deleted.
+    Pruned,
+}
+
+/// Outcome of the reconciliation of the addition of a child.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChildAdditionOutcome {
+    /// The addition is left in doubt because of the policy.
+    InDoubt,
+    /// The child had been persisted, the addition was complete.
+    Committed,
+    /// The opened child has been added again to the re-created nexus.
+    Replayed,
+    /// What the addition did has been undone.
+    RolledBack,
+}
+
+/// Reconciliation report of the addition of a child.
+#[derive(Serialize, Debug, Clone)]
+pub struct ChildAdditionReport {
+    /// Name of the nexus.
+    pub nexus: String,
+    /// URI of the child.
+    pub child_uri: String,
+    /// Progress of the addition when the node went down.
+    pub phase: ChildIntentPhase,
+    /// Outcome of the reconciliation.
+    pub outcome: ChildAdditionOutcome,
+    /// Error encountered while replaying or rolling back the addition.
+    pub error: Option<String>,
+}
+
+/// Reconciliation report of a replica.
+#[derive(Serialize, Debug, Clone)]
+pub struct ReplicaReport {
+    /// Name of the replica.
+    pub name: String,
+    /// UUID of the replica.
+    pub uuid: String,
+    /// Pool of the replica.
+    pub pool: String,
+    /// Outcome of the reconciliation.
+    pub outcome: ReplicaOutcome,
+}
+
+/// Reconciliation report of a nexus.
+#[derive(Serialize, Debug, Clone)]
+pub struct NexusReport {
+    /// Name of the nexus.
+    pub name: String,
+    /// UUID of the nexus.
+    pub uuid: String,
+    /// Outcome of the reconciliation.
+    pub outcome: NexusOutcome,
+    /// Children the nexus has been (or would be) re-created with.
+    pub children: Vec<String>,
+    /// Children which were not healthy when the node went down.
+    pub missing_children: Vec<String>,
+    /// Error encountered while re-creating the nexus.
+    pub error: Option<String>,
+}
+
+/// Outcome of the startup reconciliation.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct StartupReport {
+    /// Progress of the reconciliation.
+    pub state: ReconcileState,
+    /// Policy the reconciliation has been run with.
+    pub policy: ReconcilePolicy,
+    /// Time the reconciliation started, in RFC 3339 format.
+    pub started_at: Option<String>,
+    /// Duration of the reconciliation in milliseconds.
+    pub duration_ms: u64,
+    /// Pools found on this node.
+    pub pools: Vec<String>,
+    /// Reconciliation reports of the replicas.
+    pub replicas: Vec<ReplicaReport>,
+    /// Reconciliation reports of the nexuses.
+    pub nexuses: Vec<NexusReport>,
+    /// Reconciliation reports of the additions of children left in doubt.
+    pub child_additions: Vec<ChildAdditionReport>,
+    /// Errors which prevented parts of the reconciliation.
+    pub errors: Vec<String>,
+}
+
+impl StartupReport {
+    /// Returns a copy of the current startup report.
+    pub fn get() -> Self {
+        STARTUP_REPORT.lock().clone()
+    }
+}
+
+/// Prefix of the keys of the resources recorded by this node.
+pub(crate) fn node_prefix() -> String {
+    format!(
+        "/io-engine/{}",
+        MayastorEnvironment::global_or_default().node_name
+    )
+}
+
+/// Prefix of the keys of the nexus records.
+fn nexus_prefix() -> String {
+    format!("{}/nexus/", node_prefix())
+}
+
+/// Prefix of the keys of the replica records.
+fn replica_prefix() -> String {
+    format!("{}/replica/", node_prefix())
+}
+
+/// Prefix of the keys of the intents of adding children.
+fn child_intent_prefix() -> String {
+    format!("{}/child-intent/", node_prefix())
+}
+
+/// Key of the record of the given nexus.
+pub(crate) fn nexus_spec_key(name: &str) -> String {
+    format!("{}{}", nexus_prefix(), name)
+}
+
+/// Key of the record of the given replica.
+pub(crate) fn replica_spec_key(uuid: &str) -> String {
+    format!("{}{}", replica_prefix(), uuid)
+}
+
+/// Key of the intent of adding the given child to the given nexus.
+pub(crate) fn child_intent_key(nexus: &str, child_uri: &str) -> String {
+    format!(
+        "{}{}/{}",
+        child_intent_prefix(),
+        nexus,
+        url::form_urlencoded::byte_serialize(child_uri.as_bytes())
+            .collect::<String>()
+    )
+}
+
+/// Record the definition of a replica in the persistent store.
+/// This does not wait for the record to be written.
+pub(crate) fn record_replica(lvol: &Lvol) {
+    if !PersistentStore::enabled() {
+        return;
+    }
+    let spec = ReplicaSpec::from(lvol);
+    Reactors::master().send_future(async move {
+        let key = replica_spec_key(&spec.uuid);
+        if let Err(e) = PersistentStore::put_record(&key, &spec).await {
+            warn!("Failed to record replica {}: {}", spec.uuid, e);
+        }
+    });
+}
+
+/// Remove the record of a replica from the persistent store.
+/// This does not wait for the record to be deleted.
+pub(crate) fn forget_replica(uuid: &str) {
+    if !PersistentStore::enabled() {
+        return;
+    }
+    let key = replica_spec_key(uuid);
+    Reactors::master().send_future(async move {
+        if let Err(e) = PersistentStore::delete(&key).await {
+            warn!("Failed to delete replica record {}: {}", key, e);
+        }
+    });
+}
+
+/// Reconciles the recorded resources with the resources found on this node,
+/// applying the given policy.
+/// Must be called once the local pools have been imported.
+pub async fn reconcile(policy: ReconcilePolicy) {
+    let start = Instant::now();
+    {
+        let mut report = STARTUP_REPORT.lock();
+        *report = StartupReport {
+            state: ReconcileState::Running,
+            policy,
+            started_at: Some(chrono::Utc::now().to_rfc3339()),
+            pools: Lvs::iter().map(|lvs| lvs.name().to_string()).collect(),
+            ..Default::default()
+        };
+        if !PersistentStore::enabled() {
+            report.state = ReconcileState::Disabled;
+            return;
+        }
+    }
+
+    info!("Reconciling local resources with policy '{}'...", policy);
+
+    reconcile_replicas(policy).await;
+    reconcile_nexuses(policy).await;
+    reconcile_child_intents(policy).await;
+
+    let mut report = STARTUP_REPORT.lock();
+    report.state = ReconcileState::Completed;
+    report.duration_ms = start.elapsed().as_millis() as u64;
+    info!(
+        "Reconciliation completed in {}ms: {} replica(s), {} nexus(es), {} \
+        child addition(s), {} error(s)",
+        report.duration_ms,
+        report.replicas.len(),
+        report.nexuses.len(),
+        report.child_additions.len(),
+        report.errors.len()
+    );
+}
+
+/// Fetch the records with the given prefix from the persistent store.
+async fn fetch_specs<T: Versioned>(prefix: &str) -> Vec<T> {
+    let values = match PersistentStore::get_prefix(prefix).await {
+        Ok(values) => values,
+        Err(e) => {
+            report_error(format!("Failed to fetch records {}: {}", prefix, e));
+            return Vec::new();
+        }
+    };
+
+    values
+        .into_iter()
+        .filter_map(|(key, value)| match from_record(&key, value) {
+            Ok(spec) => Some(spec),
+            Err(e) => {
+                report_error(format!("Invalid record {}: {}", key, e));
+                None
+            }
+        })
+        .collect()
+}
+
+/// Record an error in the startup report.
+fn report_error(error: String) {
+    error!("{}", error);
+    STARTUP_REPORT.lock().errors.push(error);
+}
+
+/// Compare the recorded replicas with the replicas found in the local pools.
+async fn reconcile_replicas(policy: ReconcilePolicy) {
+    let specs = fetch_specs::<ReplicaSpec>(&replica_prefix()).await;
+    let mut local = Lvs::iter()
+        .filter_map(|lvs| lvs.lvols())
+        .flatten()
+        .filter(|lvol| !lvol.is_snapshot())
+        .map(|lvol| (lvol.uuid(), ReplicaSpec::from(&lvol)))
+        .collect::<HashMap<_, _>>();
+
+    for spec in specs {
+        let outcome = if local.remove(&spec.uuid).is_some() {
+            ReplicaOutcome::Present
+        } else if policy == ReconcilePolicy::Prune && missing_from_pool(&spec) {
+            warn!("Pruning record of missing replica {}", spec.uuid);
+            match PersistentStore::delete(&replica_spec_key(&spec.uuid)).await {
+                Ok(_) => ReplicaOutcome::Pruned,
+                Err(e) => {
+                    report_error(format!(
+                        "Failed to prune replica {}: {}",
+                        spec.uuid, e
+                    ));
+                    ReplicaOutcome::Missing
+                }
+            }
+        } else {
+            warn!("Replica {} is missing from pool {}", spec.uuid, spec.pool);
+            ReplicaOutcome::Missing
+        };
+        STARTUP_REPORT.lock().replicas.push(ReplicaReport {
+            name: spec.name,
+            uuid: spec.uuid,
+            pool: spec.pool,
+            outcome,
+        });
+    }
+
+    // Whatever is left has not been created through this node's io-engine,
+    // or its record was lost.
+    for (_, spec) in local {
+        warn!("Replica {} has no record", spec.uuid);
+        STARTUP_REPORT.lock().replicas.push(ReplicaReport {
+            name: spec.name,
+            uuid: spec.uuid,
+            pool: spec.pool,
+            outcome: ReplicaOutcome::Orphaned,
+        });
+    }
+}
+
+/// Whether the pool of the replica is imported and holds no lvol of the
+/// replica. A replica whose pool is not imported may well still exist.
+fn missing_from_pool(spec: &ReplicaSpec) -> bool {
+    match Lvs::lookup(&spec.pool) {
+        Some(lvs) => {
+            !lvs.lvols().into_iter().flatten().any(|lvol| {
+                lvol.uuid() == spec.uuid || lvol.name() == spec.name
+            })
+        }
+        None => false,
+    }
+}
+
+/// Re-create the recorded nexuses according to the policy.
+async fn reconcile_nexuses(policy: ReconcilePolicy) {
+    for spec in fetch_specs::<NexusSpec>(&nexus_prefix()).await {
+        let report = reconcile_nexus(&spec, policy).await;
+        STARTUP_REPORT.lock().nexuses.push(report);
+    }
+}
+
+/// Re-create a single nexus with the children which were healthy when the
+/// nexus was last persisted.
+async fn reconcile_nexus(
+    spec: &NexusSpec,
+    policy: ReconcilePolicy,
+) -> NexusReport {
+    let mut report = NexusReport {
+        name: spec.name.clone(),
+        uuid: spec.uuid.clone(),
+        outcome: NexusOutcome::NotRestored,
+        children: Vec::new(),
+        missing_children: Vec::new(),
+        error: None,
+    };
+
+    if nexus_lookup(&spec.name).is_some() {
+        report.outcome = NexusOutcome::Existing;
+        report.children = spec.children.clone();
+        return report;
+    }
+
+    // Without the persisted health information, we cannot tell which
+    // children hold valid data.
+    let healthy =
+        match PersistentStore::get_record(&spec.nexus_info_key()).await {
+            Ok(info) => healthy_children(info),
+            Err(e) => {
+                report.error = Some(e.to_string());
+                HashSet::new()
+            }
+        };
+
+    let (children, missing): (Vec<_>, Vec<_>) =
+        spec.children.iter().cloned().partition(|uri| {
+            NexusChild::uuid(uri).map_or(false, |uuid| healthy.contains(&uuid))
+        });
+    report.children = children;
+    report.missing_children = missing;
+
+    if report.children.is_empty() {
+        warn!("Nexus {} has no healthy children to restore", spec.name);
+        report.outcome = NexusOutcome::NoHealthyChildren;
+        if policy == ReconcilePolicy::Prune {
+            match PersistentStore::delete(&nexus_spec_key(&spec.name)).await {
+                Ok(_) => report.outcome = NexusOutcome::Pruned,
+                Err(e) => report.error = Some(e.to_string()),
+            }
+        }
+        return report;
+    }
+
+    if policy == ReconcilePolicy::Report {
+        return report;
+    }
+
+    info!(
+        "Restoring nexus {} with children {:?}",
+        spec.name, report.children
+    );
+    let created = match spec.lazy_block_len {
+        Some(block_len) => {
+            nexus_create_lazy(
+                &spec.name,
+                spec.size,
+                &spec.uuid,
+                spec.nvme_params(),
+                &report.children,
+                spec.nexus_info_key.clone(),
+                block_len,
+            )
+            .await
+        }
+        None => {
+            nexus_create_v2(
+                &spec.name,
+                spec.size,
+                &spec.uuid,
+                spec.nvme_params(),
+                &report.children,
+                spec.nexus_info_key.clone(),
+            )
+            .await
+        }
+    };
+    report.outcome = match created {
+        Ok(_) => {
+            spec.restore_settings().await;
+            if report.missing_children.is_empty() {
+                NexusOutcome::Restored
+            } else {
+                NexusOutcome::Degraded
+            }
+        }
+        Err(e) => {
+            error!("Failed to restore nexus {}: {}", spec.name, e.verbose());
+            report.error = Some(e.verbose());
+            NexusOutcome::Failed
+        }
+    };
+    report
+}
+
+/// Replay or roll back the additions of children left in doubt, once the
+/// nexuses have been re-created.
+async fn reconcile_child_intents(policy: ReconcilePolicy) {
+    for intent in fetch_specs::<ChildIntent>(&child_intent_prefix()).await {
+        let report = reconcile_child_intent(&intent, policy).await;
+        STARTUP_REPORT.lock().child_additions.push(report);
+    }
+}
+
+/// Resolve the addition of a child left in doubt: the addition is committed
+/// if the child has been persisted in the definition of the nexus, replayed
+/// if the child had been opened and the nexus has been re-created, and
+/// rolled back otherwise.
+async fn reconcile_child_intent(
+    intent: &ChildIntent,
+    policy: ReconcilePolicy,
+) -> ChildAdditionReport {
+    let mut report = ChildAdditionReport {
+        nexus: intent.nexus.clone(),
+        child_uri: intent.child_uri.clone(),
+        phase: intent.phase,
+        outcome: ChildAdditionOutcome::InDoubt,
+        error: None,
+    };
+
+    // the definition of the nexus is only used if it is still the same nexus
+    let spec = PersistentStore::get_record::<NexusSpec>(&nexus_spec_key(
+        &intent.nexus,
+    ))
+    .await
+    .ok()
+    .filter(|spec| spec.uuid == intent.nexus_uuid);
+    let persisted = spec
+        .as_ref()
+        .map_or(false, |spec| spec.children.contains(&intent.child_uri));
+
+    if persisted {
+        report.outcome = ChildAdditionOutcome::Committed;
+    } else if policy == ReconcilePolicy::Report {
+        warn!(
+            "Addition of child '{}' to nexus {} is in doubt",
+            intent.child_uri, intent.nexus
+        );
+        return report;
+    } else {
+        if intent.phase == ChildIntentPhase::Opened {
+            match replay_child_addition(intent).await {
+                Ok(true) => report.outcome = ChildAdditionOutcome::Replayed,
+                Ok(false) => {}
+                Err(e) => report.error = Some(e),
+            }
+        }
+        if report.outcome != ChildAdditionOutcome::Replayed {
+            if let Err(e) =
+                rollback_child_addition(intent, spec.is_some()).await
+            {
+                report.error = Some(e);
+            }
+            report.outcome = ChildAdditionOutcome::RolledBack;
+        }
+    }
+
+    info!(
+        "Addition of child '{}' to nexus {}: {:?}",
+        intent.child_uri, intent.nexus, report.outcome
+    );
+    let key = child_intent_key(&intent.nexus, &intent.child_uri);
+    if let Err(e) = PersistentStore::delete(&key).await {
+        report_error(format!("Failed to delete intent {}: {}", key, e));
+    }
+    report
+}
+
+/// Add the opened child again to the nexus, if it has been re-created.
+/// Returns whether the child has been added.
+async fn replay_child_addition(intent: &ChildIntent) -> Result<bool, String> {
+    let nexus = match nexus_lookup_mut(&intent.nexus) {
+        Some(nexus) if nexus.uuid().to_string() == intent.nexus_uuid => nexus,
+        _ => return Ok(false),
+    };
+    if nexus.lookup_child(&intent.child_uri).is_some() {
+        return Ok(true);
+    }
+
+    info!(
+        "Replaying the addition of child '{}' to nexus {}",
+        intent.child_uri, intent.nexus
+    );
+    nexus
+        .add_child(&intent.child_uri, false)
+        .await
+        .map(|_| true)
+        .map_err(|e| e.verbose())
+}
+
+/// Undo the addition of a child: the child is removed from the persisted
+/// NexusInfo structure, if the nexus still belongs to this node, and its
+/// device destroyed, unless a nexus uses it.
+async fn rollback_child_addition(
+    intent: &ChildIntent,
+    owned: bool,
+) -> Result<(), String> {
+    info!(
+        "Rolling back the addition of child '{}' to nexus {}",
+        intent.child_uri, intent.nexus
+    );
+
+    let uuid = NexusChild::uuid(&intent.child_uri).filter(|_| owned);
+    if let Some(uuid) = uuid {
+        let key = &intent.nexus_info_key;
+        if let Ok(mut info) =
+            PersistentStore::get_record::<NexusInfo>(key).await
+        {
+            let count = info.children.len();
+            info.children.retain(|c| c.uuid != uuid);
+            if info.children.len() != count {
+                PersistentStore::put_record(key, &info)
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+    }
+
+    let in_use = nexus_lookup(&intent.nexus)
+        .map_or(false, |n| n.lookup_child(&intent.child_uri).is_some());
+    let exists = bdev_get_name(&intent.child_uri)
+        .map_or(false, |name| device_lookup(&name).is_some());
+    if exists && !in_use {
+        device_destroy(&intent.child_uri)
+            .await
+            .map_err(|e| e.verbose())?;
+    }
+    Ok(())
+}
+
+/// UUIDs of the children marked healthy in a persisted NexusInfo structure.
+fn healthy_children(info: NexusInfo) -> HashSet<String> {
+    info.children
+        .into_iter()
+        .filter(|c| c.healthy)
+        .map(|c| c.uuid)
+        .collect()
+}
+
+/// Register the startup reconciliation json-rpc methods.
+pub(crate) fn register_rpc_methods() {
+    jsonrpc_register::<(), _, _, JsonRpcError>("get_startup_report", |_| {
+        async move { Ok(StartupReport::get()) }.boxed_local()
+    });
+}
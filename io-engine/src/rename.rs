@@ -0,0 +1,112 @@
+//! Renaming of nexuses and replicas.
+//!
+//! Nexuses and replicas keep the name and uuid they are created with, which
+//! are stable, and can be given a mutable human-readable alias on top of them.
+//! An alias resolves to its resource wherever the resource is looked up by
+//! name, which allows the control plane to adopt resources created under an
+//! older naming scheme without re-creating them.
+//!
+//! The alias of a nexus is recorded with its definition in the persistent
+//! store, the alias of a replica is stored on disk with the replica.
+
+use std::{convert::TryFrom, pin::Pin};
+
+use futures::FutureExt;
+
+use crate::{
+    bdev::nexus::{nexus_lookup_mut, nexus_lookup_uuid_mut},
+    core::{UntypedBdev, VerboseError},
+    jsonrpc::{jsonrpc_register, Code, JsonRpcError, RpcErrorCode},
+    lvs::{Error as LvsError, Lvol, Lvs},
+};
+
+/// Arguments of the `rename` json-rpc method.
+#[derive(Deserialize)]
+struct RenameArgs {
+    /// Name, alias or uuid of the nexus or replica.
+    name: String,
+    /// New alias, the current alias is removed if not given.
+    alias: Option<String>,
+}
+
+/// Reply of the `rename` json-rpc method.
+#[derive(Serialize)]
+struct RenameReply {
+    /// Name given on creation.
+    name: String,
+    uuid: String,
+    alias: Option<String>,
+}
+
+/// Look up a replica by its name, alias or uuid.
+fn replica_lookup(name: &str) -> Option<Lvol> {
+    UntypedBdev::lookup_by_name(name)
+        .and_then(|bdev| Lvol::try_from(bdev).ok())
+        .or_else(|| {
+            Lvs::iter()
+                .filter_map(|lvs| lvs.lvols())
+                .flatten()
+                .find(|lvol| lvol.uuid() == name)
+        })
+}
+
+async fn rename(args: RenameArgs) -> Result<RenameReply, JsonRpcError> {
+    if args.alias.as_deref() == Some("") {
+        return Err(JsonRpcError {
+            code: Code::InvalidParams,
+            message: "empty alias".to_string(),
+        });
+    }
+
+    let nexus = nexus_lookup_mut(&args.name)
+        .or_else(|| nexus_lookup_uuid_mut(&args.name));
+    if let Some(mut nexus) = nexus {
+        nexus
+            .as_mut()
+            .rename(args.alias)
+            .await
+            .map_err(|e| JsonRpcError {
+                code: e.rpc_error_code(),
+                message: e.verbose(),
+            })?;
+        return Ok(RenameReply {
+            name: nexus.name.clone(),
+            uuid: nexus.uuid().to_string(),
+            alias: nexus.alias(),
+        });
+    }
+
+    if let Some(mut lvol) = replica_lookup(&args.name) {
+        Pin::new(&mut lvol).rename(args.alias).await.map_err(|e| {
+            JsonRpcError {
+                code: match e {
+                    LvsError::RepExists {
+                        ..
+                    } => Code::AlreadyExists,
+                    LvsError::Invalid {
+                        ..
+                    } => Code::InvalidParams,
+                    _ => Code::InternalError,
+                },
+                message: e.to_string(),
+            }
+        })?;
+        return Ok(RenameReply {
+            name: lvol.name(),
+            uuid: lvol.uuid(),
+            alias: lvol.alias().await,
+        });
+    }
+
+    Err(JsonRpcError {
+        code: Code::NotFound,
+        message: format!("no nexus or replica named {}", args.name),
+    })
+}
+
+/// Register the `rename` json-rpc method.
+pub(crate) fn register_rpc_methods() {
+    jsonrpc_register("rename", |args: RenameArgs| {
+        async move { rename(args).await }.boxed_local()
+    });
+}
@@ -0,0 +1,59 @@
+//! Exponential backoff with jitter used when retrying operations against the
+//! backing store.
+//!
+//! The jitter spreads reconnection attempts of many io-engine instances over
+//! time, so that they do not hammer etcd in lock-step once it comes back.
+
+use rand::Rng;
+use std::time::Duration;
+
+/// Exponential backoff state.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    /// Delay used for the first retry.
+    base: Duration,
+    /// Upper bound of the delay.
+    max: Duration,
+    /// Number of attempts made since the last reset.
+    attempt: u32,
+}
+
+impl Backoff {
+    /// Create a new backoff starting at `base` and capped at `max`.
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// Returns the delay to wait before the next attempt and advances the
+    /// backoff. The delay is picked randomly between half and the whole of
+    /// the exponentially growing (and capped) interval.
+    pub fn next_delay(&mut self) -> Duration {
+        let interval = self
+            .base
+            .saturating_mul(1u32 << self.attempt.min(16))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+
+        let ms = interval.as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(ms / 2 ..= ms))
+    }
+
+    /// Sleep for the next delay.
+    pub async fn wait(&mut self) {
+        tokio::time::sleep(self.next_delay()).await;
+    }
+
+    /// Reset the backoff after a successful attempt.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// Number of attempts made since the last reset.
+    pub fn attempts(&self) -> u32 {
+        self.attempt
+    }
+}
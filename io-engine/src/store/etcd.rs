@@ -5,6 +5,11 @@ use crate::store::store_defs::{
     Delete,
     DeserialiseValue,
     Get,
+    GetPrefix,
+    KeyString,
+    LeaseGrant,
+    LeaseKeepAlive,
+    LeaseRevoke,
     Put,
     SerialiseValue,
     Store,
@@ -12,10 +17,26 @@ use crate::store::store_defs::{
     StoreError::MissingEntry,
     StoreKey,
     StoreValue,
+    Txn as TxnError,
     ValueString,
+    Watch,
 };
 use async_trait::async_trait;
-use etcd_client::Client;
+use etcd_client::{
+    Client,
+    Compare,
+    CompareOp,
+    GetOptions,
+    LeaseKeepAliveStream,
+    LeaseKeeper,
+    PutOptions,
+    Txn,
+    TxnOp,
+    TxnOpResponse,
+    WatchOptions,
+    WatchStream,
+    Watcher,
+};
 use serde_json::Value;
 use snafu::ResultExt;
 
@@ -38,6 +59,127 @@ impl Etcd {
                 .context(Connect {})?,
         ))
     }
+
+    /// Grant a lease with the given time-to-live (in seconds) and return the
+    /// lease ID.
+    pub async fn grant_lease(&mut self, ttl: i64) -> Result<i64, StoreError> {
+        let resp =
+            self.0.lease_grant(ttl, None).await.context(LeaseGrant {})?;
+        Ok(resp.id())
+    }
+
+    /// Open a keep-alive stream for the given lease.
+    pub async fn keep_alive(
+        &mut self,
+        lease_id: i64,
+    ) -> Result<(LeaseKeeper, LeaseKeepAliveStream), StoreError> {
+        self.0
+            .lease_keep_alive(lease_id)
+            .await
+            .context(LeaseKeepAlive {
+                lease_id,
+            })
+    }
+
+    /// Revoke the given lease. All keys attached to it are deleted.
+    pub async fn revoke_lease(
+        &mut self,
+        lease_id: i64,
+    ) -> Result<(), StoreError> {
+        self.0.lease_revoke(lease_id).await.context(LeaseRevoke {
+            lease_id,
+        })?;
+        Ok(())
+    }
+
+    /// Atomically put the key-value pair attached to the given lease, but only
+    /// if the key does not exist or was last modified at `mod_revision`.
+    /// On failure, the current value and modification revision of the key are
+    /// returned.
+    pub async fn put_kv_leased_if<K: StoreKey, V: StoreValue>(
+        &mut self,
+        key: &K,
+        value: &V,
+        lease_id: i64,
+        mod_revision: Option<i64>,
+    ) -> Result<Option<(Value, i64)>, StoreError> {
+        let vec_value = serde_json::to_vec(value).context(SerialiseValue)?;
+        let compare = match mod_revision {
+            None => {
+                Compare::create_revision(key.to_string(), CompareOp::Equal, 0)
+            }
+            Some(rev) => {
+                Compare::mod_revision(key.to_string(), CompareOp::Equal, rev)
+            }
+        };
+        let txn = Txn::new()
+            .when(vec![compare])
+            .and_then(vec![TxnOp::put(
+                key.to_string(),
+                vec_value,
+                Some(PutOptions::new().with_lease(lease_id)),
+            )])
+            .or_else(vec![TxnOp::get(key.to_string(), None)]);
+
+        let resp = self.0.txn(txn).await.context(TxnError {
+            key: key.to_string(),
+        })?;
+        if resp.succeeded() {
+            return Ok(None);
+        }
+
+        for op in resp.op_responses() {
+            if let TxnOpResponse::Get(get) = op {
+                if let Some(kv) = get.kvs().first() {
+                    let value = serde_json::from_slice(kv.value()).context(
+                        DeserialiseValue {
+                            value: kv.value_str().context(ValueString {})?,
+                        },
+                    )?;
+                    return Ok(Some((value, kv.mod_revision())));
+                }
+            }
+        }
+        // The key disappeared in between, report it with a null value so that
+        // the caller retries the creation.
+        Ok(Some((Value::Null, 0)))
+    }
+
+    /// 'Get' the value and the modification revision for the given key, along
+    /// with the current revision of the store.
+    pub async fn get_kv_revision<K: StoreKey>(
+        &mut self,
+        key: &K,
+    ) -> Result<(Option<Value>, i64), StoreError> {
+        let resp = self.0.get(key.to_string(), None).await.context(Get {
+            key: key.to_string(),
+        })?;
+        let revision = resp.header().map(|h| h.revision()).unwrap_or_default();
+        match resp.kvs().first() {
+            Some(kv) => Ok((
+                Some(serde_json::from_slice(kv.value()).context(
+                    DeserialiseValue {
+                        value: kv.value_str().context(ValueString {})?,
+                    },
+                )?),
+                revision,
+            )),
+            None => Ok((None, revision)),
+        }
+    }
+
+    /// Watch the given key, starting from `start_revision` if specified.
+    pub async fn watch_kv<K: StoreKey>(
+        &mut self,
+        key: &K,
+        start_revision: Option<i64>,
+    ) -> Result<(Watcher, WatchStream), StoreError> {
+        let options = start_revision
+            .map(|rev| WatchOptions::new().with_start_revision(rev));
+        self.0.watch(key.to_string(), options).await.context(Watch {
+            key: key.to_string(),
+        })
+    }
 }
 
 #[async_trait]
@@ -79,6 +221,33 @@ impl Store for Etcd {
         }
     }
 
+    /// 'Get' all the entries whose key starts with the given prefix from etcd.
+    async fn get_values_prefix(
+        &mut self,
+        key_prefix: &str,
+    ) -> Result<Vec<(String, Value)>, StoreError> {
+        let resp = self
+            .0
+            .get(key_prefix, Some(GetOptions::new().with_prefix()))
+            .await
+            .context(GetPrefix {
+                prefix: key_prefix.to_string(),
+            })?;
+        resp.kvs()
+            .iter()
+            .map(|kv| {
+                Ok((
+                    kv.key_str().context(KeyString {})?.to_string(),
+                    serde_json::from_slice(kv.value()).context(
+                        DeserialiseValue {
+                            value: kv.value_str().context(ValueString {})?,
+                        },
+                    )?,
+                ))
+            })
+            .collect()
+    }
+
     /// 'Delete' the entry with the given key from etcd.
     async fn delete_kv<K: StoreKey>(
         &mut self,
@@ -1,2 +1,4 @@
+pub mod backoff;
 pub mod etcd;
 pub mod store_defs;
+pub mod store_schema;
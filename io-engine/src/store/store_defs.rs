@@ -51,6 +51,13 @@ pub enum StoreError {
         key: String,
         source: futures::channel::oneshot::Canceled,
     },
+    /// Failed to 'get' the entries with the given key prefix from the store.
+    #[snafu(display(
+        "Failed to 'get' entries with prefix {}. Error {}",
+        prefix,
+        source
+    ))]
+    GetPrefix { prefix: String, source: Error },
     /// Failed to find an entry with the given key.
     #[snafu(display("Entry with key {} not found.", key))]
     MissingEntry { key: String },
@@ -96,6 +103,57 @@ pub enum StoreError {
     /// Operation timed out.
     #[snafu(display("Store operation timed out.",))]
     OpTimeout {},
+    /// Failed to grant a lease.
+    #[snafu(display("Failed to grant lease. Error {}", source))]
+    LeaseGrant { source: Error },
+    /// Failed to keep a lease alive.
+    #[snafu(display(
+        "Failed to keep lease {} alive. Error {}",
+        lease_id,
+        source
+    ))]
+    LeaseKeepAlive { lease_id: i64, source: Error },
+    /// Failed to revoke a lease.
+    #[snafu(display("Failed to revoke lease {}. Error {}", lease_id, source))]
+    LeaseRevoke { lease_id: i64, source: Error },
+    /// The lease has expired.
+    #[snafu(display("Lease {} has expired", lease_id))]
+    LeaseExpired { lease_id: i64 },
+    /// No lease has been granted yet.
+    #[snafu(display("No lease has been granted"))]
+    NoLease {},
+    /// Failed to execute a transaction.
+    #[snafu(display(
+        "Failed to execute transaction on key {}. Error {}",
+        key,
+        source
+    ))]
+    Txn { key: String, source: Error },
+    /// The key is owned by someone else.
+    #[snafu(display("Key {} is owned by {}", key, owner))]
+    OwnershipConflict { key: String, owner: String },
+    /// The record has a newer schema than the supported one.
+    #[snafu(display(
+        "Record {} of {} has schema version {}, newer than the supported \
+        version {}",
+        key,
+        kind,
+        version,
+        supported
+    ))]
+    SchemaDowngrade {
+        key: String,
+        kind: String,
+        version: u32,
+        supported: u32,
+    },
+    /// The record cannot be upgraded to the current schema.
+    #[snafu(display("Record {} of {} is not valid: {}", key, kind, reason))]
+    InvalidRecord {
+        key: String,
+        kind: String,
+        reason: String,
+    },
 }
 
 /// Store keys type trait
@@ -121,6 +179,12 @@ pub trait Store: Sync + Send + Clone {
         key: &K,
     ) -> Result<Value, StoreError>;
 
+    /// Get all entries whose key starts with the given prefix from the store.
+    async fn get_values_prefix(
+        &mut self,
+        key_prefix: &str,
+    ) -> Result<Vec<(String, Value)>, StoreError>;
+
     /// Delete an entry from the store.
     async fn delete_kv<K: StoreKey>(
         &mut self,
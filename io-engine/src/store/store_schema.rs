@@ -0,0 +1,125 @@
+//! Schema versions of the records kept in the persistent store.
+//!
+//! Each record of a nexus, of its children and of a replica carries the
+//! version of its schema in its `schema_version` field, the records written
+//! before the field was introduced being version 1. When the structure of a
+//! record changes, its version is bumped by adding a migration from the
+//! previous version: older records are upgraded one version at a time as
+//! they are read, and are written back with the current version the next
+//! time they are saved.
+//!
+//! A record written with a newer version than the one this io-engine
+//! supports is refused rather than downgraded, so that an older io-engine
+//! taking part in a rolling upgrade does not lose the fields it does not
+//! know of by overwriting it.
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use snafu::ResultExt;
+
+use super::store_defs::{
+    DeserialiseValue,
+    InvalidRecord,
+    SchemaDowngrade,
+    SerialiseValue,
+    StoreError,
+};
+
+/// Field of a record holding the version of its schema.
+pub const SCHEMA_VERSION_FIELD: &str = "schema_version";
+
+/// Migration of a record, as a JSON object, from a version of its schema to
+/// the next one.
+pub type Migration = fn(&mut Map<String, Value>) -> Result<(), String>;
+
+/// Record kept in the persistent store, with a versioned schema.
+pub trait Versioned: Serialize + DeserializeOwned {
+    /// Kind of the record, as reported in the errors.
+    const KIND: &'static str;
+    /// Migrations of the record: the migration at index `i` upgrades the
+    /// records of version `i + 1` to version `i + 2`.
+    const MIGRATIONS: &'static [Migration] = &[];
+
+    /// Returns the current version of the schema of the record.
+    fn schema_version() -> u32 {
+        Self::MIGRATIONS.len() as u32 + 1
+    }
+}
+
+/// Returns the schema version of the given record.
+pub fn record_version(record: &Value) -> u32 {
+    record
+        .get(SCHEMA_VERSION_FIELD)
+        .and_then(Value::as_u64)
+        .map_or(1, |v| v.max(1) as u32)
+}
+
+/// Convert the given value into a record of the current version.
+pub fn to_record<T: Versioned>(value: &T) -> Result<Value, StoreError> {
+    let mut record = serde_json::to_value(value).context(SerialiseValue {})?;
+    if let Value::Object(object) = &mut record {
+        object.insert(
+            SCHEMA_VERSION_FIELD.to_string(),
+            Value::from(T::schema_version()),
+        );
+    }
+    Ok(record)
+}
+
+/// Convert the record stored under the given key into a value, upgrading it
+/// to the current version if needed.
+pub fn from_record<T: Versioned>(
+    key: &str,
+    mut record: Value,
+) -> Result<T, StoreError> {
+    let version = record_version(&record);
+    let supported = T::schema_version();
+    if version > supported {
+        return SchemaDowngrade {
+            key,
+            kind: T::KIND,
+            version,
+            supported,
+        }
+        .fail();
+    }
+
+    if version < supported {
+        let object = match record.as_object_mut() {
+            Some(object) => object,
+            None => {
+                return InvalidRecord {
+                    key,
+                    kind: T::KIND,
+                    reason: "not an object",
+                }
+                .fail()
+            }
+        };
+        for (from, migrate) in
+            T::MIGRATIONS.iter().enumerate().skip(version as usize - 1)
+        {
+            migrate(object).map_err(|reason| StoreError::InvalidRecord {
+                key: key.to_string(),
+                kind: T::KIND.to_string(),
+                reason: format!(
+                    "failed to upgrade from version {}: {}",
+                    from + 1,
+                    reason
+                ),
+            })?;
+        }
+        info!(
+            "Upgraded record {} of {} from version {} to {}",
+            key,
+            T::KIND,
+            version,
+            supported
+        );
+        object.insert(SCHEMA_VERSION_FIELD.to_string(), Value::from(supported));
+    }
+
+    T::deserialize(&record).with_context(|_| DeserialiseValue {
+        value: record.to_string(),
+    })
+}
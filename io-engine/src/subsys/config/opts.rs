@@ -80,6 +80,9 @@ pub struct NvmfTgtConfig {
     pub max_namespaces: u32,
     /// TCP transport options
     pub opts: NvmfTcpTransportOpts,
+    /// cores the poll groups are created on, the queue pairs being spread
+    /// round-robin over them, all the reactor cores when empty
+    pub poll_group_cores: Vec<u32>,
 }
 
 impl From<NvmfTgtConfig> for Box<spdk_nvmf_target_opts> {
@@ -97,6 +100,7 @@ impl Default for NvmfTgtConfig {
             name: "mayastor_target".to_string(),
             max_namespaces: 2048,
             opts: NvmfTcpTransportOpts::default(),
+            poll_group_cores: cores_from_env("NVMF_POLL_GROUP_CORES"),
         }
     }
 }
@@ -160,6 +164,30 @@ where
     )
 }
 
+/// try to read a comma separated list of cores from an env variable, or
+/// returns an empty list when not found
+fn cores_from_env(name: &str) -> Vec<u32> {
+    std::env::var(name).map_or_else(
+        |_| Vec::new(),
+        |v| {
+            match v
+                .split(',')
+                .map(|c| c.trim().parse::<u32>())
+                .collect::<Result<Vec<_>, _>>()
+            {
+                Ok(cores) => {
+                    info!("Overriding {} value to '{:?}'", name, cores);
+                    cores
+                }
+                Err(e) => {
+                    error!("Invalid value: {} (error {}) specified for {}. Reverting to default (all cores)", v, e, name);
+                    Vec::new()
+                }
+            }
+        },
+    )
+}
+
 impl Default for NvmfTcpTransportOpts {
     fn default() -> Self {
         Self {
@@ -14,6 +14,22 @@ use crate::{
 
 static CONFIG_FILE: OnceCell<String> = OnceCell::new();
 
+/// Pools of the configuration which failed to be imported on startup.
+static IMPORT_FAILURES: Lazy<Mutex<Vec<String>>> =
+    Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Returns the pools of the configuration which failed to be imported on
+/// startup, and have not been imported since.
+pub fn pools_not_imported() -> Vec<String> {
+    IMPORT_FAILURES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|name| Lvs::lookup(name).is_none())
+        .cloned()
+        .collect()
+}
+
 /// Initialise the config file location
 fn init_config_file<P>(file: P)
 where
@@ -133,6 +149,7 @@ impl PoolConfig {
                         error.verbose()
                     );
                     failures += 1;
+                    IMPORT_FAILURES.lock().unwrap().push(pool.name.clone());
                 }
             }
         }
@@ -3,19 +3,32 @@
 
 pub use config::{
     opts::{NexusOpts, NvmeBdevOpts},
-    pool::PoolConfig,
+    pool::{pools_not_imported, PoolConfig},
     Config,
     ConfigSubsystem,
 };
+pub(crate) use nvmf::evacuate_core;
 pub use nvmf::{
+    add_referral,
     create_snapshot,
+    encode_snapshot_seq,
+    encode_snapshot_time,
+    forget_queue_limits,
+    remove_referral,
     set_snapshot_time,
     Error as NvmfError,
+    LeaseRequest,
     NvmeCpl,
     NvmfReq,
     NvmfSubsystem,
+    PollGroupInfo,
+    PollGroupMove,
+    PollGroupStats,
+    QueueLimits as NvmfQueueLimits,
+    Referral,
     SubType,
     Target as NvmfTarget,
+    REPLICA_LEASE_OPC,
 };
 use spdk_rs::libspdk::{
     spdk_add_subsystem,
@@ -46,6 +59,7 @@ pub(crate) fn register_subsystem() {
         spdk_add_subsystem_depend(Box::into_raw(depend));
     }
     RegistrationSubsystem::register();
+    nvmf::register_rpc_methods();
 }
 
 /// Makes a subsystem serial number from a subsystem UUID or name.
@@ -3,14 +3,17 @@
 use std::{
     convert::TryFrom,
     ffi::c_void,
+    pin::Pin,
     ptr::NonNull,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use serde::{Deserialize, Serialize};
+
 use crate::{
     bdev::nexus,
-    core::{Bdev, Reactors},
-    lvs::Lvol,
+    core::{Bdev, Mthread, Reactors},
+    lvs::{LeaseError, Lvol},
 };
 
 use spdk_rs::{
@@ -29,8 +32,10 @@ use spdk_rs::{
         spdk_nvme_status,
         spdk_nvmf_bdev_ctrlr_nvme_passthru_admin,
         spdk_nvmf_request,
+        spdk_nvmf_request_complete,
         spdk_nvmf_request_get_bdev,
         spdk_nvmf_request_get_cmd,
+        spdk_nvmf_request_get_data,
         spdk_nvmf_request_get_response,
         spdk_nvmf_request_get_subsystem,
         spdk_nvmf_set_custom_admin_cmd_hdlr,
@@ -64,6 +69,19 @@ impl NvmfReq {
     }
 }
 
+impl NvmfReq {
+    /// Complete the request with the given generic NVMe status code.
+    pub(crate) fn complete(&self, sc: u16) {
+        let mut rsp = self.response();
+        let nvme_status = rsp.status();
+        nvme_status.set_sct(0); // SPDK_NVME_SCT_GENERIC
+        nvme_status.set_sc(sc);
+        unsafe {
+            spdk_nvmf_request_complete(self.0.as_ptr());
+        }
+    }
+}
+
 impl From<*mut c_void> for NvmfReq {
     fn from(ptr: *mut c_void) -> Self {
         NvmfReq(NonNull::new(ptr as *mut spdk_nvmf_request).unwrap())
@@ -73,16 +91,42 @@ impl From<*mut c_void> for NvmfReq {
 /// Set the snapshot time in an spdk_nvme_cmd struct to the current time
 /// Returns seconds since Unix epoch
 pub fn set_snapshot_time(cmd: &mut spdk_nvme_cmd) -> u64 {
-    // encode snapshot time in cdw10/11
     let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
         .as_secs();
+    encode_snapshot_time(cmd, now);
+    now as u64
+}
+
+/// Set the given snapshot time in an spdk_nvme_cmd struct, so that snapshots
+/// taken on several devices can share the same name suffix.
+pub fn encode_snapshot_time(cmd: &mut spdk_nvme_cmd, time: u64) {
+    // encode snapshot time in cdw10/11
     unsafe {
-        *nvme_cmd_cdw10_get(&mut *cmd) = now as u32;
-        *nvme_cmd_cdw11_get(&mut *cmd) = (now >> 32) as u32;
+        *nvme_cmd_cdw10_get(&mut *cmd) = time as u32;
+        *nvme_cmd_cdw11_get(&mut *cmd) = (time >> 32) as u32;
     }
-    now as u64
+}
+
+/// Set the given sequence number in an spdk_nvme_cmd struct, which tells
+/// apart the group snapshots taken within the same second.
+pub fn encode_snapshot_seq(cmd: &mut spdk_nvme_cmd, seq: u32) {
+    // encode the sequence number in cdw12
+    unsafe { *(cmd as *mut spdk_nvme_cmd as *mut u32).add(12) = seq };
+}
+
+/// Returns the name of the snapshot of the given lvol the given command asks
+/// for, from the snapshot time and the sequence number it carries.
+fn snapshot_name(lvol: &Lvol, cmd: *const spdk_nvme_cmd) -> String {
+    let (snapshot_time, seq) = unsafe {
+        (
+            nvme_cmd_cdw10_get_val(cmd) as u64
+                | (nvme_cmd_cdw11_get_val(cmd) as u64) << 32,
+            *(cmd as *const u32).add(12),
+        )
+    };
+    Lvol::format_group_snapshot_name(&lvol.name(), snapshot_time, seq)
 }
 
 /// NVMf custom command handler for opcode c0h
@@ -126,12 +170,7 @@ extern "C" fn nvmf_create_snapshot_hdlr(req: *mut spdk_nvmf_request) -> i32 {
     } else if let Ok(lvol) = Lvol::try_from(bd) {
         // Received command on a shared replica (lvol)
         let cmd = unsafe { spdk_nvmf_request_get_cmd(req) };
-        let snapshot_time = unsafe {
-            nvme_cmd_cdw10_get_val(cmd) as u64
-                | (nvme_cmd_cdw11_get_val(cmd) as u64) << 32
-        };
-        let snapshot_name =
-            Lvol::format_snapshot_name(&lvol.name(), snapshot_time);
+        let snapshot_name = snapshot_name(&lvol, cmd);
         let nvmf_req = NvmfReq(NonNull::new(req).unwrap());
         // Blobfs operations must be on md_thread
         Reactors::master().send_future(async move {
@@ -145,17 +184,116 @@ extern "C" fn nvmf_create_snapshot_hdlr(req: *mut spdk_nvmf_request) -> i32 {
 }
 
 pub fn create_snapshot(lvol: Lvol, cmd: &spdk_nvme_cmd, io: *mut spdk_bdev_io) {
-    let snapshot_time = unsafe {
-        nvme_cmd_cdw10_get_val(&*cmd) as u64
-            | (nvme_cmd_cdw11_get_val(&*cmd) as u64) << 32
-    };
-    let snapshot_name = Lvol::format_snapshot_name(&lvol.name(), snapshot_time);
+    let snapshot_name = snapshot_name(&lvol, cmd);
     // Blobfs operations must be on md_thread
     Reactors::master().send_future(async move {
         lvol.create_snapshot_local(io, &snapshot_name).await;
     });
 }
 
+/// Custom NVMe admin opcode taking, renewing or releasing the lease of a
+/// nexus on a shared replica. The lease request is carried in the data of
+/// the command, from the host to the controller.
+pub const REPLICA_LEASE_OPC: u8 = 0xc5;
+
+/// Lease request of the replica lease admin command.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LeaseRequest {
+    /// Uuid of the nexus.
+    pub nexus: String,
+    /// Node of the nexus.
+    pub node: String,
+    /// Duration of the lease in seconds, 0 releases it.
+    pub ttl: u64,
+}
+
+/// Returns the lease request carried in the data of the request, which is
+/// padded with nul bytes.
+fn lease_request(req: *mut spdk_nvmf_request) -> Option<LeaseRequest> {
+    let mut data: *mut c_void = std::ptr::null_mut();
+    let mut len: u32 = 0;
+    unsafe { spdk_nvmf_request_get_data(req, &mut data, &mut len) };
+    if data.is_null() {
+        return None;
+    }
+    let data =
+        unsafe { std::slice::from_raw_parts(data as *const u8, len as usize) };
+    let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    serde_json::from_slice(&data[.. end]).ok()
+}
+
+/// NVMf custom command handler for the replica lease opcode
+/// Return: <0 for any error, caller handles it as unsupported opcode
+extern "C" fn nvmf_replica_lease_hdlr(req: *mut spdk_nvmf_request) -> i32 {
+    let subsys = unsafe { spdk_nvmf_request_get_subsystem(req) };
+    if subsys.is_null()
+        || unsafe { spdk_nvmf_subsystem_get_max_nsid(subsys) } != 1
+    {
+        return -1;
+    }
+
+    let mut bdev: *mut spdk_bdev = std::ptr::null_mut();
+    let mut desc: *mut spdk_bdev_desc = std::ptr::null_mut();
+    let mut ch: *mut spdk_io_channel = std::ptr::null_mut();
+    let rc = unsafe {
+        spdk_nvmf_request_get_bdev(1, req, &mut bdev, &mut desc, &mut ch)
+    };
+    if rc != 0 {
+        return -1;
+    }
+    let mut lvol = match Lvol::try_from(Bdev::checked_from_ptr(bdev).unwrap()) {
+        Ok(lvol) => lvol,
+        Err(_) => return -1,
+    };
+
+    let nvmf_req = NvmfReq(NonNull::new(req).unwrap());
+    let request = match lease_request(req) {
+        Some(request) => request,
+        None => {
+            nvmf_req.complete(0x02); // SPDK_NVME_SC_INVALID_FIELD
+            return 0; // SPDK_NVMF_REQUEST_EXEC_STATUS_COMPLETE
+        }
+    };
+
+    // the request completes on the thread of its qpair
+    let thread = Mthread::current().unwrap();
+    // Blobfs operations must be on md_thread
+    Reactors::master().send_future(async move {
+        let lvol = Pin::new(&mut lvol);
+        let result = if request.ttl == 0 {
+            lvol.release_lease(&request.nexus, &request.node).await
+        } else {
+            lvol.acquire_lease(
+                &request.nexus,
+                &request.node,
+                Duration::from_secs(request.ttl),
+            )
+            .await
+            .map(|_| ())
+        };
+        let sc = match result {
+            Ok(_) => 0,
+            Err(
+                error @ LeaseError::LeaseHeld {
+                    ..
+                },
+            ) => {
+                warn!(
+                    "Refusing the lease of nexus {}: {}",
+                    request.nexus, error
+                );
+                0x83 // SPDK_NVME_SC_RESERVATION_CONFLICT
+            }
+            Err(error) => {
+                error!("{}", error);
+                0x06 // SPDK_NVME_SC_INTERNAL_DEVICE_ERROR
+            }
+        };
+        thread.send_msg(nvmf_req, move |req| req.complete(sc));
+    });
+    1 // SPDK_NVMF_REQUEST_EXEC_STATUS_ASYNCHRONOUS
+}
+
 /// Register custom NVMe admin command handler
 pub fn setup_create_snapshot_hdlr() {
     unsafe {
@@ -165,3 +303,13 @@ pub fn setup_create_snapshot_hdlr() {
         );
     }
 }
+
+/// Register the custom NVMe admin command handler of the replica leases
+pub fn setup_replica_lease_hdlr() {
+    unsafe {
+        spdk_nvmf_set_custom_admin_cmd_hdlr(
+            REPLICA_LEASE_OPC,
+            Some(nvmf_replica_lease_hdlr),
+        );
+    }
+}
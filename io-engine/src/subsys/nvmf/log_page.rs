@@ -0,0 +1,148 @@
+//! Custom handler of the Get Log Page admin command.
+//!
+//! SPDK takes a single custom handler per admin opcode, so the log pages
+//! built here rather than by SPDK are all served by this handler: the
+//! discovery log page while there are referrals, and the error and health
+//! log pages of the shared nexuses. Any other log page is left to SPDK.
+//! The health log page needs the I/O statistics of the nexus, so it is
+//! built on the master core and the command completed asynchronously.
+
+use std::{ffi::c_void, ptr::NonNull};
+
+use spdk_rs::libspdk::{
+    spdk_nvmf_request,
+    spdk_nvmf_request_get_cmd,
+    spdk_nvmf_request_get_data,
+    spdk_nvmf_request_get_subsystem,
+    spdk_nvmf_set_custom_admin_cmd_hdlr,
+};
+
+use super::{referral, NvmfReq, NvmfSubsystem, SubType};
+use crate::{
+    bdev::nexus::{health_log_page, nexus_lookup, VENDOR_ERROR_LOG_LID},
+    core::{Mthread, Reactors},
+};
+
+/// Opcode of the Get Log Page admin command.
+const GET_LOG_PAGE: u8 = 0x02;
+/// Log identifier of the Error Information log page.
+const ERROR_LOG_LID: u32 = 0x01;
+/// Log identifier of the SMART / Health Information log page.
+const HEALTH_LOG_LID: u32 = 0x02;
+/// Namespace id of the nexus within its subsystem.
+const NEXUS_NSID: u32 = 1;
+
+/// Builds the error log page requested, if the subsystem shares a nexus.
+fn nexus_error_log_page(
+    subsystem: &NvmfSubsystem,
+    lid: u32,
+) -> Option<Vec<u8>> {
+    if lid != ERROR_LOG_LID && lid != VENDOR_ERROR_LOG_LID as u32 {
+        return None;
+    }
+    let nexus = nexus_lookup(&subsystem.bdev()?.name())?;
+    if lid == ERROR_LOG_LID {
+        Some(nexus.error_log.error_log_page(NEXUS_NSID))
+    } else {
+        Some(nexus.error_log.vendor_log_page())
+    }
+}
+
+/// Returns the value of the given command dword of the request.
+fn cdw(req: *mut spdk_nvmf_request, n: usize) -> u32 {
+    unsafe { *(spdk_nvmf_request_get_cmd(req) as *const u32).add(n) }
+}
+
+/// Copies the requested part of the log page into the data of the request.
+/// Return: <0 if the request has no data
+fn copy_log_page(req: *mut spdk_nvmf_request, log: &[u8]) -> i32 {
+    // Get Log Page: the number of dwords in cdw10 and cdw11, the offset in
+    // cdw12 and cdw13
+    let numd = ((cdw(req, 11) as u64 & 0xffff) << 16
+        | (cdw(req, 10) as u64 >> 16))
+        + 1;
+    let offset = (cdw(req, 13) as u64) << 32 | cdw(req, 12) as u64;
+
+    let mut data: *mut c_void = std::ptr::null_mut();
+    let mut length: u32 = 0;
+    unsafe { spdk_nvmf_request_get_data(req, &mut data, &mut length) };
+    if data.is_null() {
+        return -1;
+    }
+
+    // the entries past the end of the log are zeroed
+    let start = (offset as usize).min(log.len());
+    let len = (numd as usize * 4)
+        .min(length as usize)
+        .min(log.len() - start);
+    unsafe {
+        std::ptr::write_bytes(data as *mut u8, 0, length as usize);
+        std::ptr::copy_nonoverlapping(
+            log[start ..].as_ptr(),
+            data as *mut u8,
+            len,
+        );
+    }
+    0
+}
+
+/// Builds the health log page of the nexus shared by the subsystem on the
+/// master core, and completes the request on the thread of its qpair.
+/// Return: <0 if the subsystem does not share a nexus
+fn nexus_health_log_page(
+    req: *mut spdk_nvmf_request,
+    subsystem: &NvmfSubsystem,
+) -> i32 {
+    let name = match subsystem.bdev() {
+        Some(bdev) if nexus_lookup(bdev.name()).is_some() => {
+            bdev.name().to_string()
+        }
+        _ => return -1,
+    };
+
+    let thread = Mthread::current().unwrap();
+    let nvmf_req = NvmfReq(NonNull::new(req).unwrap());
+    Reactors::master().send_future(async move {
+        let log = health_log_page(&name).await;
+        thread.send_msg(nvmf_req, move |req| match log {
+            Some(log) if copy_log_page(req.0.as_ptr(), &log) == 0 => {
+                req.complete(0) // SPDK_NVME_SC_SUCCESS
+            }
+            _ => req.complete(0x06), // SPDK_NVME_SC_INTERNAL_DEVICE_ERROR
+        });
+    });
+    1 // SPDK_NVMF_REQUEST_EXEC_STATUS_ASYNCHRONOUS
+}
+
+/// Custom handler of the Get Log Page admin command.
+/// Return: <0 to leave the command to SPDK
+extern "C" fn nvmf_get_log_page_hdlr(req: *mut spdk_nvmf_request) -> i32 {
+    let subsys = unsafe { spdk_nvmf_request_get_subsystem(req) };
+    if subsys.is_null() {
+        return -1;
+    }
+    let subsystem = NvmfSubsystem::from(subsys);
+    let lid = cdw(req, 10) & 0xff;
+
+    let log = match subsystem.subtype() {
+        SubType::Discovery => referral::discovery_log_page(req, subsys, lid),
+        SubType::Nvme if lid == HEALTH_LOG_LID => {
+            return nexus_health_log_page(req, &subsystem);
+        }
+        SubType::Nvme => nexus_error_log_page(&subsystem, lid),
+    };
+    match log {
+        Some(log) => copy_log_page(req, &log), // 0: EXEC_STATUS_COMPLETE
+        None => -1,
+    }
+}
+
+/// Register the Get Log Page handler.
+pub(super) fn setup_get_log_page_hdlr() {
+    unsafe {
+        spdk_nvmf_set_custom_admin_cmd_hdlr(
+            GET_LOG_PAGE,
+            Some(nvmf_get_log_page_hdlr),
+        );
+    }
+}
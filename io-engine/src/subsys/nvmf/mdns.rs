@@ -0,0 +1,174 @@
+//! Advertisement of the NVMF targets over mDNS/DNS-SD.
+//!
+//! When enabled, the discovery controller of the target is advertised as an
+//! `_nvme-disc._tcp` service, as described by the NVMe-oF TP8009, and every
+//! shared subsystem as an `_mayastor-nvmf._tcp` service, so that hosts
+//! outside of the cluster can find the volumes of a node without asking the
+//! control plane. A subsystem is advertised when it is started and withdrawn
+//! when it is stopped.
+//!
+//! The TXT record of a service holds the NQN of the subsystem and the
+//! transport it is reachable over.
+
+use std::collections::HashMap;
+
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+
+use super::{NvmfSubsystem, SubType};
+use crate::{core::MayastorEnvironment, subsys::Config};
+
+/// Service type of the NVMe-oF discovery controllers.
+const DISCOVERY_SERVICE: &str = "_nvme-disc._tcp.local.";
+/// Service type of the shared subsystems.
+const SUBSYSTEM_SERVICE: &str = "_mayastor-nvmf._tcp.local.";
+/// Longest label of a DNS name.
+const MAX_LABEL_LEN: usize = 63;
+
+static ADVERTISER: OnceCell<Advertiser> = OnceCell::new();
+
+/// Advertises the subsystems of the target.
+struct Advertiser {
+    daemon: ServiceDaemon,
+    /// Name of the node, as a DNS label.
+    node: String,
+    host_name: String,
+    address: String,
+    /// Full names of the advertised services, by NQN.
+    services: Mutex<HashMap<String, String>>,
+}
+
+impl Advertiser {
+    fn new() -> Result<Self, String> {
+        let env = MayastorEnvironment::global_or_default();
+        let address = MayastorEnvironment::get_nvmf_tgt_ip()?;
+        let daemon = ServiceDaemon::new()
+            .map_err(|e| format!("failed to start mDNS daemon: {}", e))?;
+
+        let node = dns_label(&env.node_name);
+        Ok(Self {
+            daemon,
+            host_name: format!("{}.local.", node),
+            node,
+            address,
+            services: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn advertise(&self, service: &str, instance: &str, nqn: &str, port: u16) {
+        let properties = HashMap::from([
+            ("nqn".to_string(), nqn.to_string()),
+            ("p".to_string(), "tcp".to_string()),
+        ]);
+        let info = match ServiceInfo::new(
+            service,
+            &dns_label(instance),
+            &self.host_name,
+            self.address.as_str(),
+            port,
+            Some(properties),
+        ) {
+            Ok(info) => info,
+            Err(e) => {
+                error!("Failed to advertise '{}' over mDNS: {}", nqn, e);
+                return;
+            }
+        };
+
+        let full_name = info.get_fullname().to_string();
+        match self.daemon.register(info) {
+            Ok(_) => {
+                info!("Advertising '{}' over mDNS as '{}'", nqn, full_name);
+                self.services.lock().insert(nqn.to_string(), full_name);
+            }
+            Err(e) => {
+                error!("Failed to advertise '{}' over mDNS: {}", nqn, e);
+            }
+        }
+    }
+
+    fn withdraw(&self, nqn: &str) {
+        let full_name = match self.services.lock().remove(nqn) {
+            Some(full_name) => full_name,
+            None => return,
+        };
+        match self.daemon.unregister(&full_name) {
+            Ok(_) => info!("Withdrew '{}' from mDNS", nqn),
+            Err(e) => {
+                error!("Failed to withdraw '{}' from mDNS: {}", nqn, e)
+            }
+        }
+    }
+}
+
+/// Turns a name into a valid DNS label.
+fn dns_label(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .take(MAX_LABEL_LEN)
+        .collect()
+}
+
+/// Starts the mDNS advertisement of the target, if enabled.
+pub(super) fn init() {
+    if !MayastorEnvironment::global_or_default().mdns() {
+        return;
+    }
+
+    match Advertiser::new() {
+        Ok(advertiser) => {
+            let _ = ADVERTISER.set(advertiser);
+        }
+        Err(e) => error!("mDNS advertisement disabled: {}", e),
+    }
+}
+
+/// Advertises a started subsystem.
+pub(super) fn advertise(subsystem: &NvmfSubsystem) {
+    let advertiser = match ADVERTISER.get() {
+        Some(advertiser) => advertiser,
+        None => return,
+    };
+
+    // all subsystems listen on the replica port
+    let port = Config::get().nexus_opts.nvmf_replica_port;
+    let nqn = subsystem.get_nqn();
+    match subsystem.subtype() {
+        SubType::Discovery => advertiser.advertise(
+            DISCOVERY_SERVICE,
+            &advertiser.node,
+            &nqn,
+            port,
+        ),
+        SubType::Nvme => {
+            // the instance is named after the bdev, the NQN being too long
+            // for a DNS label
+            let instance = nqn.rsplit(':').next().unwrap_or(&nqn);
+            advertiser.advertise(SUBSYSTEM_SERVICE, instance, &nqn, port)
+        }
+    }
+}
+
+/// Withdraws a stopped subsystem.
+pub(super) fn withdraw(nqn: &str) {
+    if let Some(advertiser) = ADVERTISER.get() {
+        advertiser.withdraw(nqn);
+    }
+}
+
+/// Withdraws all the advertised subsystems and stops the mDNS daemon.
+pub(super) fn shutdown() {
+    if let Some(advertiser) = ADVERTISER.get() {
+        let nqns = advertiser
+            .services
+            .lock()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        nqns.iter().for_each(|nqn| advertiser.withdraw(nqn));
+        if let Err(e) = advertiser.daemon.shutdown() {
+            error!("Failed to stop mDNS daemon: {}", e);
+        }
+    }
+}
@@ -0,0 +1,377 @@
+//! Blue/green migration of the listeners of the target to another address.
+//!
+//! Changing the address of the storage network of a node would otherwise
+//! require all of its volumes to be unpublished. Instead, the subsystems are
+//! moved to the new address in three steps, by the `nvmf_migrate_listener`
+//! json-rpc method:
+//!
+//! - the target listens on the new address, and every subsystem listening on
+//!   the old address gets a second listener on the new one; when ANA reporting
+//!   is enabled, the old listeners are made inaccessible so that multipath
+//!   hosts move their I/O to the new path,
+//! - the hosts connected through the old address are then waited for to connect
+//!   through the new one, up to a timeout,
+//! - once they all have, the old listeners are removed and the target stops
+//!   listening on the old address, which becomes the address of the target.
+//!
+//! If some hosts have not reconnected in time, the migration stops with both
+//! listeners in place and reports them: calling the method again waits for
+//! them once more, with `force` the old listeners are removed regardless, and
+//! with `abort` the new listeners are removed instead.
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use spdk_rs::libspdk::{
+    SPDK_NVME_ANA_INACCESSIBLE_STATE,
+    SPDK_NVME_ANA_OPTIMIZED_STATE,
+};
+
+use super::{
+    transport::{get_ipv4_address, TransportId},
+    Error,
+    NvmfSubsystem,
+    SubType,
+    Target,
+};
+use crate::{
+    core::MayastorEnvironment,
+    ffihelper::AsStr,
+    jsonrpc::{jsonrpc_register, Code, JsonRpcError},
+    sleep::mayastor_sleep,
+};
+
+/// Interval at which the hosts are checked for reconnection.
+const RECONNECT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Time given to the hosts to reconnect by default, in seconds.
+const DEFAULT_TIMEOUT_SECS: u64 = 60;
+
+/// Address the target listens on besides its own, while a migration is in
+/// progress.
+static STAGED: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
+/// A migration step is running.
+static MIGRATING: AtomicBool = AtomicBool::new(false);
+
+/// Host still connected to a subsystem through the old address only.
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingHost {
+    pub nqn: String,
+    pub host: String,
+}
+
+/// Outcome of a migration of the listeners.
+#[derive(Debug, Clone, Serialize)]
+pub struct ListenerMigration {
+    pub old_address: String,
+    pub new_address: String,
+    /// NQNs of the subsystems listening on the new address.
+    pub subsystems: Vec<String>,
+    /// Hosts which have not reconnected through the new address.
+    pub pending_hosts: Vec<PendingHost>,
+    /// The old listeners have been removed, or the new ones if aborted.
+    pub completed: bool,
+}
+
+/// Marks a migration step as running until dropped.
+struct Running;
+
+impl Running {
+    fn start(address: &str) -> Result<Self, Error> {
+        if MIGRATING.swap(true, Ordering::SeqCst) {
+            return Err(Error::MigrateListener {
+                address: address.to_string(),
+                msg: "another migration step is running".to_string(),
+            });
+        }
+        Ok(Self)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        MIGRATING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Returns the NVMe subsystems of the target.
+fn nvme_subsystems() -> Vec<NvmfSubsystem> {
+    NvmfSubsystem::first()
+        .map(|s| s.into_iter().filter(|s| s.subtype() == SubType::Nvme))
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// Returns the TCP listeners of the subsystem on the given address.
+fn listeners_on(subsystem: &NvmfSubsystem, address: &str) -> Vec<TransportId> {
+    subsystem
+        .listeners_to_vec()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|t| !t.is_vfio_user() && t.traddr.as_str() == address)
+        .collect()
+}
+
+/// Returns the port of a listener.
+fn port(trid: &TransportId) -> u16 {
+    trid.trsvcid.as_str().parse().unwrap_or_default()
+}
+
+/// Sets the ANA state of the listeners of the subsystem on the given address,
+/// if ANA reporting is enabled.
+async fn set_ana_state(
+    subsystem: &NvmfSubsystem,
+    address: &str,
+    ana_state: u32,
+) -> Result<(), Error> {
+    if !subsystem.ana_reporting() {
+        return Ok(());
+    }
+    subsystem.pause().await?;
+    let mut res = Ok(());
+    for trid in listeners_on(subsystem, address) {
+        res = subsystem.set_listener_ana_state(&trid, ana_state).await;
+        if res.is_err() {
+            break;
+        }
+    }
+    subsystem.resume().await?;
+    res
+}
+
+/// Adds a listener on the new address to every subsystem listening on the
+/// old one, returns the NQNs of the subsystems.
+async fn add_listeners(old: &str, new: &str) -> Result<Vec<String>, Error> {
+    let mut nqns = Vec::new();
+    for subsystem in nvme_subsystems() {
+        let existing = listeners_on(&subsystem, new);
+        for trid in listeners_on(&subsystem, old) {
+            if existing.iter().all(|t| port(t) != port(&trid)) {
+                let trid = TransportId::with_address(new, port(&trid));
+                subsystem.add_listener_trid(&trid).await?;
+                info!(
+                    "Subsystem '{}' listening on {}",
+                    subsystem.get_nqn(),
+                    trid
+                );
+            }
+        }
+        if !listeners_on(&subsystem, new).is_empty() {
+            set_ana_state(&subsystem, old, SPDK_NVME_ANA_INACCESSIBLE_STATE)
+                .await?;
+            nqns.push(subsystem.get_nqn());
+        }
+    }
+    Ok(nqns)
+}
+
+/// Removes the listeners of the subsystems on the given address.
+async fn remove_listeners(address: &str) -> Result<(), Error> {
+    for subsystem in nvme_subsystems() {
+        for trid in listeners_on(&subsystem, address) {
+            subsystem.remove_listener_trid(&trid).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the hosts connected through the old address and not through the
+/// new one.
+fn pending_hosts(old: &str, new: &str) -> Vec<PendingHost> {
+    let mut pending = Vec::new();
+    for subsystem in nvme_subsystems() {
+        let hosts = subsystem.connected_host_addresses();
+        let mut waiting = hosts
+            .iter()
+            .filter(|(host, address)| {
+                address == old
+                    && !hosts.iter().any(|(h, a)| h == host && a == new)
+            })
+            .map(|(host, _)| host.clone())
+            .collect::<Vec<_>>();
+        waiting.dedup();
+        pending.extend(waiting.into_iter().map(|host| PendingHost {
+            nqn: subsystem.get_nqn(),
+            host,
+        }));
+    }
+    pending
+}
+
+/// Migrates the listeners of the target to the address of the given
+/// interface, specified as the NVMF target interface is on the command line.
+/// Must be called from the master core.
+pub async fn migrate_listeners(
+    interface: &str,
+    timeout: Duration,
+    force: bool,
+) -> Result<ListenerMigration, Error> {
+    let new = MayastorEnvironment::detect_nvmf_tgt_iface_ip(interface)
+        .map_err(|msg| Error::MigrateListener {
+            address: interface.to_string(),
+            msg,
+        })?;
+    let _running = Running::start(&new)?;
+    let old = get_ipv4_address()?;
+    if old == new {
+        return Err(Error::MigrateListener {
+            address: new,
+            msg: "the target already listens on this address".to_string(),
+        });
+    }
+
+    let staged = STAGED.lock().clone();
+    match staged {
+        Some(staged) if staged != new => {
+            return Err(Error::MigrateListener {
+                address: new,
+                msg: format!(
+                    "a migration to {} is in progress, abort it first",
+                    staged
+                ),
+            });
+        }
+        Some(_) => {}
+        None => {
+            Target::listen_on(&new)?;
+            *STAGED.lock() = Some(new.clone());
+        }
+    }
+    info!("Migrating the nvmf listeners from {} to {}", old, new);
+    add_listeners(&old, &new).await?;
+
+    let deadline = Instant::now() + timeout;
+    let mut pending = pending_hosts(&old, &new);
+    while !pending.is_empty() && Instant::now() < deadline {
+        mayastor_sleep(RECONNECT_POLL_INTERVAL).await.ok();
+        pending = pending_hosts(&old, &new);
+    }
+
+    if !pending.is_empty() && !force {
+        warn!(
+            "{} host(s) have not reconnected through {}, keeping the \
+            listeners on {}",
+            pending.len(),
+            new,
+            old
+        );
+        return Ok(ListenerMigration {
+            old_address: old,
+            subsystems: add_listeners(&old, &new).await?,
+            new_address: new,
+            pending_hosts: pending,
+            completed: false,
+        });
+    }
+
+    // subsystems shared meanwhile listen on the new address from now on,
+    // the ones shared before the switch are given their listener now
+    MayastorEnvironment::set_nvmf_tgt_ip(&new);
+    let subsystems = add_listeners(&old, &new).await?;
+    remove_listeners(&old).await?;
+    Target::stop_listen_on(&old);
+    *STAGED.lock() = None;
+    info!("Migrated the nvmf listeners from {} to {}", old, new);
+
+    Ok(ListenerMigration {
+        old_address: old,
+        new_address: new,
+        subsystems,
+        pending_hosts: pending,
+        completed: true,
+    })
+}
+
+/// Aborts a migration in progress: the listeners on the new address are
+/// removed, and the old ones made accessible again.
+pub async fn abort_migration() -> Result<ListenerMigration, Error> {
+    let old = get_ipv4_address()?;
+    let _running = Running::start(&old)?;
+    let new = match STAGED.lock().clone() {
+        Some(new) => new,
+        None => {
+            return Err(Error::MigrateListener {
+                address: old,
+                msg: "no migration in progress".to_string(),
+            })
+        }
+    };
+
+    let mut subsystems = Vec::new();
+    for subsystem in nvme_subsystems() {
+        if !listeners_on(&subsystem, new.as_str()).is_empty() {
+            set_ana_state(&subsystem, &old, SPDK_NVME_ANA_OPTIMIZED_STATE)
+                .await?;
+            subsystems.push(subsystem.get_nqn());
+        }
+    }
+    remove_listeners(&new).await?;
+    Target::stop_listen_on(&new);
+    *STAGED.lock() = None;
+    info!("Aborted the migration of the nvmf listeners to {}", new);
+
+    Ok(ListenerMigration {
+        old_address: old,
+        new_address: new,
+        subsystems,
+        pending_hosts: Vec::new(),
+        completed: true,
+    })
+}
+
+/// Arguments of the `nvmf_migrate_listener` json-rpc method.
+#[derive(Deserialize)]
+struct MigrateListenerArgs {
+    /// Interface of the new address: a name, or "ip:", "mac:" or "subnet:"
+    /// followed by its address.
+    #[serde(default)]
+    interface: Option<String>,
+    /// Seconds the hosts are given to reconnect through the new address.
+    #[serde(default)]
+    timeout_secs: Option<u64>,
+    /// Remove the old listeners even if some hosts have not reconnected.
+    #[serde(default)]
+    force: bool,
+    /// Abort the migration in progress.
+    #[serde(default)]
+    abort: bool,
+}
+
+/// Register the listener migration json-rpc method.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nvmf_migrate_listener", |args: MigrateListenerArgs| {
+        async move {
+            let res = if args.abort {
+                abort_migration().await
+            } else {
+                let interface = args.interface.ok_or_else(|| {
+                    JsonRpcError::new(
+                        Code::InvalidParams,
+                        "the interface to migrate to is required",
+                    )
+                })?;
+                migrate_listeners(
+                    &interface,
+                    Duration::from_secs(
+                        args.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS),
+                    ),
+                    args.force,
+                )
+                .await
+            };
+            res.map_err(|e| match e {
+                Error::MigrateListener {
+                    ..
+                } => JsonRpcError::new(Code::InvalidParams, e),
+                e => JsonRpcError::new(Code::InternalError, e),
+            })
+        }
+        .boxed_local()
+    });
+}
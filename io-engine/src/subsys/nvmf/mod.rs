@@ -6,15 +6,47 @@
 //! In our case we currently only deal with TCP. We create two transports
 //! one for the frontend (nexus) and one for the backend (replica)
 //!
-//! As connections come on, we randomly schedule them across cores by putting
-//! the qpair in a poll group that is allocated during reactor start.
+//! A nexus can also be exposed to the guests of the node as an emulated NVMe
+//! controller, over the vfio-user transport, which is added on first use.
+//!
+//! As connections come on, we schedule them round-robin across cores by
+//! putting the qpair in a poll group that is allocated during reactor start.
+//! Poll groups are allocated on all the cores, or only on the cores given by
+//! the target configuration, so that some cores can be kept free of I/O
+//! qpairs.
 use std::cell::RefCell;
 
 use nix::errno::Errno;
 use snafu::Snafu;
 
-pub use admin_cmd::{create_snapshot, set_snapshot_time, NvmeCpl, NvmfReq};
+pub use admin_cmd::{
+    create_snapshot,
+    encode_snapshot_seq,
+    encode_snapshot_time,
+    set_snapshot_time,
+    LeaseRequest,
+    NvmeCpl,
+    NvmfReq,
+    REPLICA_LEASE_OPC,
+};
+pub use migrate::{
+    abort_migration,
+    migrate_listeners,
+    ListenerMigration,
+    PendingHost,
+};
 use poll_groups::PollGroup;
+pub use poll_groups::{PollGroupInfo, PollGroupStats};
+pub use queues::{forget_queue_limits, QueueLimits};
+pub(crate) use rebalance::evacuate_core;
+pub use rebalance::{
+    rebalance,
+    CoreLoad,
+    PollGroupMove,
+    RebalanceArgs,
+    RebalanceReport,
+};
+pub use referral::{add_referral, remove_referral, Referral};
 use spdk_rs::libspdk::{
     spdk_subsystem,
     spdk_subsystem_fini_next,
@@ -29,7 +61,13 @@ use crate::{
 };
 
 mod admin_cmd;
+mod log_page;
+mod mdns;
+mod migrate;
 mod poll_groups;
+mod queues;
+mod rebalance;
+mod referral;
 mod subsystem;
 mod target;
 mod transport;
@@ -84,6 +122,14 @@ pub enum Error {
     Listener { nqn: String, trid: String },
     #[snafu(display("Interior nul byte found for host {}", host))]
     HostCstrNul { host: String },
+    #[snafu(display("Invalid referral URI '{}': {}", uri, msg))]
+    InvalidReferral { uri: String, msg: String },
+    #[snafu(display(
+        "Failed to migrate the listeners to {}: {}",
+        address,
+        msg
+    ))]
+    MigrateListener { address: String, msg: String },
 }
 
 thread_local! {
@@ -100,6 +146,9 @@ impl Nvmf {
 
         // set up custom NVMe Admin command handler
         admin_cmd::setup_create_snapshot_hdlr();
+        admin_cmd::setup_replica_lease_hdlr();
+        log_page::setup_get_log_page_hdlr();
+        queues::setup_queue_hdlrs();
 
         if Config::get().nexus_opts.nvmf_enable {
             NVMF_TGT.with(|tgt| tgt.borrow_mut().next_state());
@@ -132,3 +181,12 @@ impl Nvmf {
         Self(Box::into_raw(ss))
     }
 }
+
+/// Register the json-rpc methods of the NVMF target.
+pub(crate) fn register_rpc_methods() {
+    migrate::register_rpc_methods();
+    poll_groups::register_rpc_methods();
+    queues::register_rpc_methods();
+    rebalance::register_rpc_methods();
+    referral::register_rpc_methods();
+}
@@ -1,10 +1,19 @@
+use futures::FutureExt;
+use serde::Serialize;
+
 use spdk_rs::libspdk::{
     spdk_nvmf_poll_group,
     spdk_nvmf_poll_group_create,
+    spdk_nvmf_poll_group_get_stat,
+    spdk_nvmf_poll_group_stat,
     spdk_nvmf_tgt,
 };
 
-use crate::core::Mthread;
+use crate::{
+    core::{Mthread, Reactor},
+    jsonrpc::{jsonrpc_register, JsonRpcError},
+    subsys::nvmf::{target::NVMF_TGT, NVMF_PGS},
+};
 
 #[derive(Clone, Debug)]
 struct Pg(*mut spdk_nvmf_poll_group);
@@ -13,13 +22,16 @@ struct Pg(*mut spdk_nvmf_poll_group);
 #[derive(Clone, Debug)]
 pub(crate) struct PollGroup {
     pub thread: Mthread,
+    /// the core the poll group runs on
+    pub core: u32,
     group: Pg,
 }
 
 impl PollGroup {
-    pub fn new(tgt: *mut spdk_nvmf_tgt, mt: Mthread) -> Self {
+    pub fn new(tgt: *mut spdk_nvmf_tgt, mt: Mthread, core: u32) -> Self {
         Self {
             thread: mt,
+            core,
             group: Pg(unsafe { spdk_nvmf_poll_group_create(tgt) }),
         }
     }
@@ -28,3 +40,108 @@ impl PollGroup {
         self.group.0
     }
 }
+
+/// Queue pair statistics of a poll group, or summed over all the poll groups
+/// of the target.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct PollGroupStats {
+    /// Number of admin queue pairs created since startup.
+    pub admin_qpairs: u64,
+    /// Number of I/O queue pairs created since startup.
+    pub io_qpairs: u64,
+    /// Number of currently connected admin queue pairs, that is the number
+    /// of connected controllers.
+    pub current_admin_qpairs: u64,
+    /// Number of currently connected I/O queue pairs.
+    pub current_io_qpairs: u64,
+    /// Number of bdev I/Os waiting for a free bdev_io.
+    pub pending_bdev_io: u64,
+}
+
+impl PollGroupStats {
+    /// Collect the statistics of every poll group, summed over all of them.
+    /// Must be called from the master core.
+    pub async fn collect() -> Self {
+        let mut total = Self::default();
+        for info in PollGroupInfo::collect().await {
+            let stats = info.stats;
+            total.admin_qpairs += stats.admin_qpairs;
+            total.io_qpairs += stats.io_qpairs;
+            total.current_admin_qpairs += stats.current_admin_qpairs;
+            total.current_io_qpairs += stats.current_io_qpairs;
+            total.pending_bdev_io += stats.pending_bdev_io;
+        }
+        total
+    }
+}
+
+/// A poll group of the target, along with its queue pair statistics.
+#[derive(Debug, Clone, Serialize)]
+pub struct PollGroupInfo {
+    /// The core the poll group runs on.
+    pub core: u32,
+    /// Name of the thread of the poll group.
+    pub thread: String,
+    pub stats: PollGroupStats,
+}
+
+impl PollGroupInfo {
+    /// Collect the statistics of every poll group. The statistics of a poll
+    /// group can only be read from its own thread, so this is dispatched to
+    /// each of them in turn.
+    /// Must be called from the master core.
+    pub async fn collect() -> Vec<Self> {
+        let tgt = NVMF_TGT.with(|t| t.borrow().tgt.as_ptr());
+        let pgs = NVMF_PGS.with(|p| p.borrow().clone());
+
+        let mut groups = Vec::new();
+        for pg in pgs {
+            let thread = pg.thread;
+            let rx = Reactor::spawn_at(&thread, async move {
+                let mut stat = spdk_nvmf_poll_group_stat::default();
+                let rc = thread.with(|| unsafe {
+                    spdk_nvmf_poll_group_get_stat(tgt, &mut stat)
+                });
+                (rc == 0).then(|| PollGroupStats {
+                    admin_qpairs: stat.admin_qpairs as u64,
+                    io_qpairs: stat.io_qpairs as u64,
+                    current_admin_qpairs: stat.current_admin_qpairs as u64,
+                    current_io_qpairs: stat.current_io_qpairs as u64,
+                    pending_bdev_io: stat.pending_bdev_io,
+                })
+            });
+
+            if let Ok(rx) = rx {
+                if let Ok(Some(stats)) = rx.await {
+                    groups.push(Self {
+                        core: pg.core,
+                        thread: thread.name().to_string(),
+                        stats,
+                    });
+                }
+            }
+        }
+        groups.sort_by_key(|g| g.core);
+        groups
+    }
+}
+
+/// Reply of the `nvmf_get_poll_groups` json-rpc method.
+#[derive(Debug, Serialize)]
+struct PollGroupsReply {
+    /// The cores the poll groups are created on.
+    cores: Vec<u32>,
+    poll_groups: Vec<PollGroupInfo>,
+}
+
+pub(crate) fn register_rpc_methods() {
+    jsonrpc_register::<(), _, _, JsonRpcError>("nvmf_get_poll_groups", |_| {
+        async move {
+            Ok(PollGroupsReply {
+                cores: NVMF_TGT.with(|t| t.borrow().poll_group_cores()),
+                poll_groups: PollGroupInfo::collect().await,
+            })
+        }
+        .boxed_local()
+    });
+}
@@ -0,0 +1,316 @@
+//! I/O queues of the shared nexuses.
+//!
+//! The number of I/O queues a host may create on a controller, and their
+//! depth, are set for the whole TCP transport, so every volume is given as
+//! many queues as the busiest one needs, each of them polled by the poll
+//! groups. The limits of a shared nexus can be lowered below those of the
+//! transport, which remain the ceiling, so that small volumes negotiate
+//! fewer and shallower queues while the busy ones keep the full amount.
+//!
+//! The hosts negotiate their queues when they connect: the number of queues
+//! with the Set Features command of the Number of Queues feature, answered
+//! here with the limit of the nexus, and the depth from the maximum number
+//! of outstanding commands of the Identify Controller data, which hosts
+//! clamp the size of their queues to. Both commands are left to SPDK for the
+//! subsystems without limits. A change of the limits applies to the hosts
+//! which connect from then on.
+
+use std::{collections::HashMap, ffi::c_void, mem::size_of};
+
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use spdk_rs::libspdk::{
+    spdk_bdev,
+    spdk_bdev_desc,
+    spdk_io_channel,
+    spdk_nvme_ctrlr_data,
+    spdk_nvmf_ctrlr_identify_ctrlr,
+    spdk_nvmf_request,
+    spdk_nvmf_request_get_bdev,
+    spdk_nvmf_request_get_cmd,
+    spdk_nvmf_request_get_data,
+    spdk_nvmf_request_get_response,
+    spdk_nvmf_request_get_subsystem,
+    spdk_nvmf_set_custom_admin_cmd_hdlr,
+    spdk_nvmf_transport_opts,
+};
+
+use super::{NvmfSubsystem, SubType};
+use crate::{
+    bdev::nexus::{nexus_lookup, NEXUS_MODULE_NAME},
+    core::Bdev,
+    jsonrpc::{jsonrpc_register, Code, JsonRpcError},
+    subsys::Config,
+};
+
+/// Opcodes of the Identify, Set Features and Get Features admin commands.
+const IDENTIFY: u8 = 0x06;
+const SET_FEATURES: u8 = 0x09;
+const GET_FEATURES: u8 = 0x0a;
+/// Controller data structure of the Identify command.
+const CNS_CTRLR: u32 = 0x01;
+/// Feature identifier of the Number of Queues feature.
+const FID_NUMBER_OF_QUEUES: u32 = 0x07;
+
+/// Queue limits of the nexuses which have been given some, by name.
+static LIMITS: Lazy<Mutex<HashMap<String, QueueLimits>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Queue limits of a shared nexus, the limits of the transport apply to
+/// those which are not set.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct QueueLimits {
+    /// Maximum number of I/O queues of a controller.
+    pub io_queues: Option<u16>,
+    /// Maximum number of outstanding commands of an I/O queue.
+    pub queue_depth: Option<u16>,
+}
+
+impl QueueLimits {
+    /// Returns the queue limits of the given nexus.
+    pub fn get(nexus: &str) -> Self {
+        LIMITS.lock().get(nexus).cloned().unwrap_or_default()
+    }
+}
+
+/// Returns the maximum number of I/O queues of a controller, and their
+/// depth, allowed by the transport.
+fn transport_limits() -> (u16, u16) {
+    let opts: spdk_nvmf_transport_opts =
+        Config::get().nvmf_tcp_tgt_conf.opts.into();
+    // one of the queue pairs of a controller is its admin queue
+    (
+        opts.max_qpairs_per_ctrlr.saturating_sub(1).max(1),
+        opts.max_queue_depth,
+    )
+}
+
+/// Forget the queue limits of a nexus which is gone.
+pub fn forget_queue_limits(nexus: &str) {
+    LIMITS.lock().remove(nexus);
+}
+
+/// Returns the queue limits of the nexus the request is for, if it has any.
+fn request_limits(req: *mut spdk_nvmf_request) -> Option<QueueLimits> {
+    let subsys = unsafe { spdk_nvmf_request_get_subsystem(req) };
+    if subsys.is_null()
+        || NvmfSubsystem::from(subsys).subtype() != SubType::Nvme
+        || LIMITS.lock().is_empty()
+    {
+        return None;
+    }
+
+    let mut bdev: *mut spdk_bdev = std::ptr::null_mut();
+    let mut desc: *mut spdk_bdev_desc = std::ptr::null_mut();
+    let mut ch: *mut spdk_io_channel = std::ptr::null_mut();
+    let rc = unsafe {
+        spdk_nvmf_request_get_bdev(1, req, &mut bdev, &mut desc, &mut ch)
+    };
+    if rc != 0 {
+        return None;
+    }
+    let bdev = Bdev::checked_from_ptr(bdev)?;
+    if bdev.driver() != NEXUS_MODULE_NAME {
+        return None;
+    }
+    LIMITS.lock().get(bdev.name()).cloned()
+}
+
+/// Returns the dword of the given index of the command of the request.
+fn cdw(req: *mut spdk_nvmf_request, n: usize) -> u32 {
+    unsafe { *(spdk_nvmf_request_get_cmd(req) as *const u32).add(n) }
+}
+
+/// Custom handler of the Set and Get Features admin commands, which answers
+/// the Number of Queues feature with the limit of the nexus.
+/// Return: <0 to leave the command to SPDK
+extern "C" fn nvmf_features_hdlr(req: *mut spdk_nvmf_request) -> i32 {
+    // Set Features and Get Features: FID in cdw10
+    if cdw(req, 10) & 0xff != FID_NUMBER_OF_QUEUES {
+        return -1;
+    }
+    let io_queues = match request_limits(req).and_then(|l| l.io_queues) {
+        Some(io_queues) => io_queues as u32,
+        None => return -1,
+    };
+
+    // number of submission and completion queues, 0's based, in cdw11 and
+    // in the dword 0 of the completion
+    let allocated = if cdw(req, 0) & 0xff == SET_FEATURES as u32 {
+        let nsqr = cdw(req, 11) & 0xffff;
+        let ncqr = cdw(req, 11) >> 16;
+        (nsqr.min(io_queues - 1), ncqr.min(io_queues - 1))
+    } else {
+        (io_queues - 1, io_queues - 1)
+    };
+    unsafe {
+        (*spdk_nvmf_request_get_response(req)).cdw0 =
+            allocated.0 | allocated.1 << 16;
+    }
+    0 // SPDK_NVMF_REQUEST_EXEC_STATUS_COMPLETE
+}
+
+/// Custom handler of the Identify admin command, which lowers the maximum
+/// number of outstanding commands of the controller data to the queue depth
+/// of the nexus.
+/// Return: <0 to leave the command to SPDK
+extern "C" fn nvmf_identify_hdlr(req: *mut spdk_nvmf_request) -> i32 {
+    // Identify: CNS in cdw10
+    if cdw(req, 10) & 0xff != CNS_CTRLR {
+        return -1;
+    }
+    let queue_depth = match request_limits(req).and_then(|l| l.queue_depth) {
+        Some(queue_depth) => queue_depth,
+        None => return -1,
+    };
+
+    let mut data: *mut c_void = std::ptr::null_mut();
+    let mut length: u32 = 0;
+    unsafe { spdk_nvmf_request_get_data(req, &mut data, &mut length) };
+    if data.is_null() {
+        return -1;
+    }
+
+    let mut cdata = spdk_nvme_ctrlr_data::default();
+    unsafe {
+        let ctrlr = (*(*req).qpair).ctrlr;
+        spdk_nvmf_ctrlr_identify_ctrlr(ctrlr, &mut cdata);
+    }
+    cdata.maxcmd = cdata.maxcmd.min(queue_depth);
+
+    let len = size_of::<spdk_nvme_ctrlr_data>().min(length as usize);
+    unsafe {
+        std::ptr::write_bytes(data as *mut u8, 0, length as usize);
+        std::ptr::copy_nonoverlapping(
+            &cdata as *const spdk_nvme_ctrlr_data as *const u8,
+            data as *mut u8,
+            len,
+        );
+    }
+    0 // SPDK_NVMF_REQUEST_EXEC_STATUS_COMPLETE
+}
+
+/// Register the handlers negotiating the queues of the nexuses.
+pub(super) fn setup_queue_hdlrs() {
+    unsafe {
+        spdk_nvmf_set_custom_admin_cmd_hdlr(IDENTIFY, Some(nvmf_identify_hdlr));
+        spdk_nvmf_set_custom_admin_cmd_hdlr(
+            SET_FEATURES,
+            Some(nvmf_features_hdlr),
+        );
+        spdk_nvmf_set_custom_admin_cmd_hdlr(
+            GET_FEATURES,
+            Some(nvmf_features_hdlr),
+        );
+    }
+}
+
+/// Arguments of the `nexus_set_nvmf_queues` json-rpc method, the limits
+/// which are not given are those of the transport.
+#[derive(Debug, Deserialize)]
+struct SetQueuesArgs {
+    /// Name of the nexus.
+    name: String,
+    #[serde(default)]
+    io_queues: Option<u16>,
+    #[serde(default)]
+    queue_depth: Option<u16>,
+}
+
+/// Arguments of the `nexus_get_nvmf_queues` json-rpc method.
+#[derive(Debug, Deserialize)]
+struct GetQueuesArgs {
+    /// Name of the nexus.
+    name: String,
+}
+
+/// Reply of the queue json-rpc methods.
+#[derive(Debug, Serialize)]
+struct QueuesReply {
+    name: String,
+    limits: QueueLimits,
+    /// Number of I/O queues of a controller and their depth, as negotiated
+    /// by the hosts which connect.
+    io_queues: u16,
+    queue_depth: u16,
+    /// Limits of the transport.
+    max_io_queues: u16,
+    max_queue_depth: u16,
+}
+
+impl QueuesReply {
+    fn new(name: String) -> Self {
+        let limits = QueueLimits::get(&name);
+        let (max_io_queues, max_queue_depth) = transport_limits();
+        Self {
+            name,
+            limits,
+            io_queues: limits.io_queues.unwrap_or(max_io_queues),
+            queue_depth: limits.queue_depth.unwrap_or(max_queue_depth),
+            max_io_queues,
+            max_queue_depth,
+        }
+    }
+}
+
+/// Returns the name of the nexus of the given name or alias.
+fn lookup_nexus(name: &str) -> Result<String, JsonRpcError> {
+    nexus_lookup(name).map(|n| n.name.clone()).ok_or_else(|| {
+        JsonRpcError::new(Code::NotFound, format!("Nexus {} not found", name))
+    })
+}
+
+/// Register the queue json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nexus_set_nvmf_queues", |args: SetQueuesArgs| {
+        async move {
+            let name = lookup_nexus(&args.name)?;
+            let (max_io_queues, max_queue_depth) = transport_limits();
+            if matches!(args.io_queues, Some(q) if q == 0 || q > max_io_queues)
+            {
+                return Err(JsonRpcError::new(
+                    Code::InvalidParams,
+                    format!(
+                        "the number of I/O queues must be between 1 and {}",
+                        max_io_queues
+                    ),
+                ));
+            }
+            if matches!(
+                args.queue_depth,
+                Some(d) if d < 2 || d > max_queue_depth
+            ) {
+                return Err(JsonRpcError::new(
+                    Code::InvalidParams,
+                    format!(
+                        "the queue depth must be between 2 and {}",
+                        max_queue_depth
+                    ),
+                ));
+            }
+
+            let limits = QueueLimits {
+                io_queues: args.io_queues,
+                queue_depth: args.queue_depth,
+            };
+            info!(
+                "Setting the NVMf queue limits of nexus {}: {:?}",
+                name, limits
+            );
+            if limits == QueueLimits::default() {
+                LIMITS.lock().remove(&name);
+            } else {
+                LIMITS.lock().insert(name.clone(), limits);
+            }
+            Ok(QueuesReply::new(name))
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nexus_get_nvmf_queues", |args: GetQueuesArgs| {
+        async move { Ok(QueuesReply::new(lookup_nexus(&args.name)?)) }
+            .boxed_local()
+    });
+}
@@ -0,0 +1,432 @@
+//! Rebalancing of the poll groups over the cores.
+//!
+//! A poll group stays on the core it was created on, while the load of its
+//! queue pairs depends on the hosts connected to it, so that some cores can
+//! end up saturated while others are idle. The rebalancer samples the time
+//! every SPDK thread of the reactors spends busy, and when the load of the
+//! busiest poll group core exceeds the load of the idlest one by more than a
+//! threshold, moves the poll group of the busiest core which best evens out
+//! the two cores to the idlest one.
+//!
+//! A single poll group is moved per run, and only if the move narrows the gap
+//! by a minimum amount, and a poll group which was moved is left on its core
+//! for a while, so that poll groups are not moved back and forth between
+//! cores of close load. Rebalancing is triggered over json-rpc, or
+//! periodically with the `--poll-group-rebalance-interval` option.
+//!
+//! A core can also be evacuated, before it is taken out of the core mask:
+//! all of its poll groups are moved to the other poll group cores, without
+//! disconnecting their hosts, and the core is no longer rebalanced onto.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use futures::{channel::oneshot, FutureExt};
+use serde::{Deserialize, Serialize};
+use spdk_rs::libspdk::spdk_get_ticks;
+
+use crate::{
+    core::{Cores, Reactor, Reactors, ThreadLoad},
+    jsonrpc::{jsonrpc_register, Code, JsonRpcError},
+    sleep::mayastor_sleep,
+    subsys::nvmf::{target::NVMF_TGT, NVMF_PGS},
+};
+
+/// Default period the loads are sampled over.
+const DEFAULT_SAMPLE: Duration = Duration::from_secs(1);
+
+/// Default difference of load, in percent, between the busiest and the
+/// idlest cores beyond which a poll group is moved.
+const DEFAULT_IMBALANCE_PCT: u64 = 25;
+
+/// Least reduction of the difference of load, in percent, for a poll group
+/// to be moved.
+const MIN_GAIN_PCT: u64 = 10;
+
+/// Time a poll group which was moved is left on its core.
+const COOLDOWN: Duration = Duration::from_secs(60);
+
+/// Number of moves kept.
+const MAX_MOVES: usize = 64;
+
+thread_local! {
+    /// Moves of the poll groups, oldest first. Only used on the master core.
+    static MOVES: RefCell<VecDeque<PollGroupMove>> = RefCell::new(VecDeque::new());
+    /// Set while a rebalancing runs.
+    static RUNNING: Cell<bool> = Cell::new(false);
+    /// Cores evacuated, which the poll groups are no longer moved to.
+    static EVACUATED: RefCell<HashSet<u32>> = RefCell::new(HashSet::new());
+}
+
+/// Move of a poll group to another core.
+#[derive(Debug, Clone, Serialize)]
+pub struct PollGroupMove {
+    /// Time of the move, in RFC 3339 format.
+    pub time: String,
+    /// Name of the thread of the poll group.
+    pub thread: String,
+    pub from: u32,
+    pub to: u32,
+    /// Load of the poll group, in percent of a core, not sampled when a
+    /// core is evacuated.
+    pub load_pct: u64,
+    /// Loads of the cores before the move, in percent.
+    pub from_load_pct: u64,
+    pub to_load_pct: u64,
+    #[serde(skip)]
+    at: Option<Instant>,
+}
+
+/// Load of a poll group core.
+#[derive(Debug, Clone, Serialize)]
+pub struct CoreLoad {
+    pub core: u32,
+    /// Time the SPDK threads of the core spent busy, in percent.
+    pub load_pct: u64,
+    /// Names of the threads of the poll groups on the core.
+    pub poll_groups: Vec<String>,
+}
+
+/// Result of a rebalancing.
+#[derive(Debug, Serialize)]
+pub struct RebalanceReport {
+    pub cores: Vec<CoreLoad>,
+    /// Difference of load between the busiest and the idlest cores, in
+    /// percent.
+    pub imbalance_pct: u64,
+    /// Poll group moved, or which would have been moved on a dry run.
+    pub moved: Option<PollGroupMove>,
+    pub dry_run: bool,
+}
+
+/// Options of a rebalancing.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RebalanceArgs {
+    /// Difference of load, in percent, beyond which a poll group is moved.
+    pub imbalance_pct: Option<u64>,
+    /// Period the loads are sampled over, in milliseconds.
+    pub sample_ms: Option<u64>,
+    /// Only report the move which would be made.
+    pub dry_run: bool,
+}
+
+/// Returns the loads of the threads of every reactor, along with their core.
+async fn thread_loads() -> Vec<(u32, ThreadLoad)> {
+    let origin = Cores::current();
+    let mut loads = Vec::new();
+
+    for reactor in Reactors::iter() {
+        let core = reactor.core();
+        let (sender, receiver) = oneshot::channel();
+        reactor.send_future(async move {
+            let loads = Reactors::current().thread_loads();
+            // the futures of a core can only be woken on that core
+            if let Some(r) = Reactors::get_by_core(origin) {
+                r.send_future(async move {
+                    sender.send(loads).ok();
+                });
+            }
+        });
+        if let Ok(threads) = receiver.await {
+            loads.extend(threads.into_iter().map(|t| (core, t)));
+        }
+    }
+    loads
+}
+
+/// Returns true if the poll group thread was moved too recently to be moved
+/// again.
+fn cooling_down(thread: &str) -> bool {
+    MOVES.with(|m| {
+        m.borrow().iter().any(|m| {
+            m.thread == thread
+                && m.at.map_or(false, |at| at.elapsed() < COOLDOWN)
+        })
+    })
+}
+
+/// Move the poll group thread with the given id to the given core.
+async fn move_poll_group(id: u64, to: u32) -> bool {
+    let pg = NVMF_PGS
+        .with(|p| p.borrow().iter().find(|pg| pg.thread.id() == id).cloned());
+    let pg = match pg {
+        Some(pg) => pg,
+        None => return false,
+    };
+
+    let thread = pg.thread;
+    let moved = match Reactor::spawn_at(&thread, async move {
+        Reactor::move_thread(&thread, to)
+            .map_err(|error| {
+                error!("Failed to move thread '{}': {}", thread.name(), error)
+            })
+            .is_ok()
+    }) {
+        Ok(receiver) => receiver.await.unwrap_or(false),
+        Err(_) => false,
+    };
+
+    if moved {
+        NVMF_PGS.with(|p| {
+            p.borrow_mut()
+                .iter_mut()
+                .filter(|pg| pg.thread.id() == id)
+                .for_each(|pg| pg.core = to)
+        });
+    }
+    moved
+}
+
+/// Sample the loads of the poll group cores and move a poll group from the
+/// busiest core to the idlest one if they are too far apart.
+/// Must be called from the master core.
+pub async fn rebalance(
+    args: RebalanceArgs,
+) -> Result<RebalanceReport, JsonRpcError> {
+    if RUNNING.with(|r| r.replace(true)) {
+        return Err(JsonRpcError::new(
+            Code::InternalError,
+            "a rebalancing is already running",
+        ));
+    }
+    let report = rebalance_loads(args).await;
+    RUNNING.with(|r| r.set(false));
+    Ok(report)
+}
+
+async fn rebalance_loads(args: RebalanceArgs) -> RebalanceReport {
+    let sample = args
+        .sample_ms
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_SAMPLE);
+    let threshold = args.imbalance_pct.unwrap_or(DEFAULT_IMBALANCE_PCT);
+
+    let start = unsafe { spdk_get_ticks() };
+    let before = thread_loads()
+        .await
+        .into_iter()
+        .map(|(_, t)| (t.id, t.busy_ticks))
+        .collect::<HashMap<_, _>>();
+    mayastor_sleep(sample).await.ok();
+    let after = thread_loads().await;
+    let elapsed = unsafe { spdk_get_ticks() }.saturating_sub(start).max(1);
+
+    // load of every thread, in percent of a core
+    let threads = after
+        .into_iter()
+        .filter_map(|(core, t)| {
+            let busy = t.busy_ticks.saturating_sub(*before.get(&t.id)?);
+            Some((core, t, busy * 100 / elapsed))
+        })
+        .collect::<Vec<_>>();
+    let pg_threads = NVMF_PGS.with(|p| {
+        p.borrow()
+            .iter()
+            .map(|pg| pg.thread.id())
+            .collect::<Vec<_>>()
+    });
+
+    let mut cores = NVMF_TGT
+        .with(|t| t.borrow().poll_group_cores())
+        .into_iter()
+        .filter(|core| !evacuated(*core))
+        .map(|core| CoreLoad {
+            core,
+            load_pct: threads
+                .iter()
+                .filter(|(c, ..)| *c == core)
+                .map(|(.., load)| load)
+                .sum(),
+            poll_groups: threads
+                .iter()
+                .filter(|(c, t, _)| *c == core && pg_threads.contains(&t.id))
+                .map(|(_, t, _)| t.name.clone())
+                .collect(),
+        })
+        .collect::<Vec<_>>();
+    cores.sort_by_key(|c| c.core);
+
+    let busiest = cores.iter().max_by_key(|c| c.load_pct);
+    let idlest = cores.iter().min_by_key(|c| c.load_pct);
+    let (busiest, idlest) = match (busiest, idlest) {
+        (Some(b), Some(i)) => (b.clone(), i.clone()),
+        _ => {
+            return RebalanceReport {
+                cores,
+                imbalance_pct: 0,
+                moved: None,
+                dry_run: args.dry_run,
+            }
+        }
+    };
+    let gap = busiest.load_pct - idlest.load_pct;
+
+    // the poll group of the busiest core whose move narrows the gap the most
+    let candidate = threads
+        .iter()
+        .filter(|(c, t, _)| {
+            *c == busiest.core
+                && pg_threads.contains(&t.id)
+                && !cooling_down(&t.name)
+        })
+        .map(|(_, t, load)| {
+            let remaining = (gap as i64 - 2 * *load as i64).unsigned_abs();
+            (t, *load, gap.saturating_sub(remaining))
+        })
+        .max_by_key(|(.., gain)| *gain)
+        .filter(|(.., gain)| gap >= threshold && *gain >= MIN_GAIN_PCT);
+
+    let mut moved = None;
+    if let Some((thread, load, _)) = candidate {
+        let mv = PollGroupMove {
+            time: chrono::Utc::now().to_rfc3339(),
+            thread: thread.name.clone(),
+            from: busiest.core,
+            to: idlest.core,
+            load_pct: load,
+            from_load_pct: busiest.load_pct,
+            to_load_pct: idlest.load_pct,
+            at: Some(Instant::now()),
+        };
+
+        if args.dry_run {
+            moved = Some(mv);
+        } else if move_poll_group(thread.id, idlest.core).await {
+            info!(
+                "Moved poll group '{}' ({}%) from core {} ({}%) to core {} \
+                ({}%)",
+                mv.thread,
+                mv.load_pct,
+                mv.from,
+                mv.from_load_pct,
+                mv.to,
+                mv.to_load_pct
+            );
+            MOVES.with(|m| {
+                let mut m = m.borrow_mut();
+                m.push_back(mv.clone());
+                while m.len() > MAX_MOVES {
+                    m.pop_front();
+                }
+            });
+            moved = Some(mv);
+        }
+    }
+
+    RebalanceReport {
+        cores,
+        imbalance_pct: gap,
+        moved,
+        dry_run: args.dry_run,
+    }
+}
+
+/// Returns true if the given core has been evacuated.
+fn evacuated(core: u32) -> bool {
+    EVACUATED.with(|e| e.borrow().contains(&core))
+}
+
+/// Move every poll group of the given core to the other poll group cores,
+/// each to the one with the fewest poll groups, calling `progress` after
+/// each move. The core is no longer rebalanced onto afterwards.
+/// Must be called from the master core.
+pub(crate) async fn evacuate_core(
+    core: u32,
+    mut progress: impl FnMut(&PollGroupMove, usize),
+) -> Result<Vec<PollGroupMove>, String> {
+    let targets = NVMF_TGT
+        .with(|t| t.borrow().poll_group_cores())
+        .into_iter()
+        .filter(|c| *c != core && !evacuated(*c))
+        .collect::<Vec<_>>();
+    if targets.is_empty() {
+        return Err(format!(
+            "no other poll group core to move the poll groups of core {} to",
+            core
+        ));
+    }
+    EVACUATED.with(|e| e.borrow_mut().insert(core));
+
+    let groups = NVMF_PGS.with(|p| {
+        p.borrow()
+            .iter()
+            .filter(|pg| pg.core == core)
+            .map(|pg| (pg.thread.id(), pg.thread.name().to_string()))
+            .collect::<Vec<_>>()
+    });
+    let total = groups.len();
+
+    let mut moves = Vec::new();
+    for (id, thread) in groups {
+        let to = NVMF_PGS.with(|p| {
+            let p = p.borrow();
+            *targets
+                .iter()
+                .min_by_key(|c| p.iter().filter(|pg| pg.core == **c).count())
+                .unwrap()
+        });
+        if !move_poll_group(id, to).await {
+            return Err(format!(
+                "failed to move poll group '{}' from core {} to core {}",
+                thread, core, to
+            ));
+        }
+        info!(
+            "Moved poll group '{}' from evacuated core {} to core {}",
+            thread, core, to
+        );
+
+        let mv = PollGroupMove {
+            time: chrono::Utc::now().to_rfc3339(),
+            thread,
+            from: core,
+            to,
+            load_pct: 0,
+            from_load_pct: 0,
+            to_load_pct: 0,
+            at: Some(Instant::now()),
+        };
+        MOVES.with(|m| {
+            let mut m = m.borrow_mut();
+            m.push_back(mv.clone());
+            while m.len() > MAX_MOVES {
+                m.pop_front();
+            }
+        });
+        moves.push(mv);
+        progress(moves.last().unwrap(), total);
+    }
+    Ok(moves)
+}
+
+/// Rebalance the poll groups at the given interval.
+pub(crate) async fn rebalance_loop(interval: Duration) {
+    info!("Rebalancing the poll groups every {:?}", interval);
+    loop {
+        mayastor_sleep(interval).await.ok();
+        if let Err(error) = rebalance(RebalanceArgs::default()).await {
+            debug!("Skipped rebalancing the poll groups: {}", error);
+        }
+    }
+}
+
+pub(crate) fn register_rpc_methods() {
+    jsonrpc_register(
+        "nvmf_rebalance_poll_groups",
+        |args: Option<RebalanceArgs>| {
+            async move { rebalance(args.unwrap_or_default()).await }
+                .boxed_local()
+        },
+    );
+
+    jsonrpc_register::<(), _, _, JsonRpcError>("nvmf_poll_group_moves", |_| {
+        async move {
+            Ok(MOVES.with(|m| m.borrow().iter().cloned().collect::<Vec<_>>()))
+        }
+        .boxed_local()
+    });
+}
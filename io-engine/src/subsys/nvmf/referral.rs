@@ -0,0 +1,289 @@
+//! Referrals of the subsystems which moved to another node.
+//!
+//! When a volume moves to another node, its nexus is unshared here and
+//! shared there. Hosts which connect through the discovery controller of
+//! this node, with a static configuration, would lose the volume. For a
+//! grace period after the move, the discovery log page of this node refers
+//! them to the new target instead: it lists the subsystem at its new address,
+//! next to the subsystems shared locally.
+//!
+//! The SPDK target has no notion of a remote subsystem, as a listener can
+//! only be added for an address it listens on, so the discovery log page is
+//! built here, for the custom handler of the Get Log Page admin command,
+//! while there are referrals. Without referrals the command is left to SPDK.
+//! Hosts are notified of the change by the discovery log change event SPDK
+//! sends when the moved subsystem is destroyed.
+
+use std::{
+    ffi::CStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use spdk_rs::libspdk::{spdk_nvmf_request, spdk_nvmf_subsystem};
+use url::Url;
+
+use super::{Error, NvmfSubsystem, SubType};
+use crate::{
+    ffihelper::AsStr,
+    jsonrpc::{jsonrpc_register, Code, JsonRpcError},
+};
+
+/// Log identifier of the discovery log page.
+const DISCOVERY_LOG_LID: u32 = 0x70;
+/// Size of the header, and of each entry, of the discovery log page.
+const DISCOVERY_LOG_ENTRY_SIZE: usize = 1024;
+const TRTYPE_TCP: u8 = 3;
+const ADRFAM_IPV4: u8 = 1;
+const SUBTYPE_NVME: u8 = 2;
+/// A secure channel is not required to connect.
+const TREQ_SECURE_CHANNEL_NOT_REQUIRED: u8 = 2;
+/// The controller is allocated dynamically.
+const CNTLID_DYNAMIC: u16 = 0xffff;
+const ADMIN_QUEUE_SIZE: u16 = 32;
+
+/// Referral of a subsystem to its new target.
+#[derive(Debug, Clone, Serialize)]
+pub struct Referral {
+    /// NQN of the subsystem.
+    pub nqn: String,
+    /// Address and port of the target the subsystem moved to.
+    pub address: String,
+    pub port: u16,
+    /// Seconds left before the referral is dropped.
+    pub expires_in_secs: u64,
+    #[serde(skip)]
+    expires: Instant,
+}
+
+impl Referral {
+    /// Creates a referral to the subsystem shared with the given URI, of the
+    /// form nvmf://address:port/nqn.
+    fn parse(uri: &str, grace: Duration) -> Result<Self, Error> {
+        let invalid = |msg: &str| Error::InvalidReferral {
+            uri: uri.to_string(),
+            msg: msg.to_string(),
+        };
+        let url = Url::parse(uri).map_err(|e| invalid(&e.to_string()))?;
+        if url.scheme() != "nvmf" {
+            return Err(invalid("the scheme is not nvmf"));
+        }
+        let address = url
+            .host_str()
+            .ok_or_else(|| invalid("the address is missing"))?;
+        let nqn = url.path().trim_start_matches('/');
+        if nqn.is_empty() {
+            return Err(invalid("the NQN is missing"));
+        }
+
+        Ok(Self {
+            nqn: nqn.to_string(),
+            address: address.to_string(),
+            port: url.port().unwrap_or(4420),
+            expires_in_secs: grace.as_secs(),
+            expires: Instant::now() + grace,
+        })
+    }
+}
+
+/// Referrals in effect, dropped lazily once expired.
+static REFERRALS: Lazy<Mutex<Vec<Referral>>> =
+    Lazy::new(|| Mutex::new(Vec::new()));
+/// Number of changes of the referrals, which bumps the generation counter of
+/// the discovery log page.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the referrals in effect.
+fn live_referrals() -> Vec<Referral> {
+    let now = Instant::now();
+    let mut referrals = REFERRALS.lock();
+    let count = referrals.len();
+    referrals.retain(|r| r.expires > now);
+    if referrals.len() != count {
+        GENERATION.fetch_add(1, Ordering::SeqCst);
+    }
+    referrals
+        .iter()
+        .cloned()
+        .map(|mut r| {
+            r.expires_in_secs = (r.expires - now).as_secs();
+            r
+        })
+        .collect()
+}
+
+/// Refers the hosts discovering the subsystem shared with the given URI to
+/// its new target, for the given grace period. A previous referral of the
+/// subsystem is replaced.
+pub fn add_referral(uri: &str, grace: Duration) -> Result<Referral, Error> {
+    let referral = Referral::parse(uri, grace)?;
+    let mut referrals = REFERRALS.lock();
+    referrals.retain(|r| r.nqn != referral.nqn);
+    referrals.push(referral.clone());
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+    info!(
+        "Referring '{}' to {}:{} for {:?}",
+        referral.nqn, referral.address, referral.port, grace
+    );
+    Ok(referral)
+}
+
+/// Drops the referral of a subsystem, returns false if there was none.
+pub fn remove_referral(nqn: &str) -> bool {
+    let mut referrals = REFERRALS.lock();
+    let count = referrals.len();
+    referrals.retain(|r| r.nqn != nqn);
+    if referrals.len() == count {
+        return false;
+    }
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+    info!("Dropped the referral of '{}'", nqn);
+    true
+}
+
+/// Writes a string padded with spaces, as are the addresses of the discovery
+/// log page entries.
+fn put_padded(buf: &mut [u8], s: &str) {
+    buf.fill(b' ');
+    let len = s.len().min(buf.len());
+    buf[.. len].copy_from_slice(&s.as_bytes()[.. len]);
+}
+
+/// Appends an entry to a discovery log page.
+fn put_entry(log: &mut Vec<u8>, nqn: &str, address: &str, port: &str) {
+    let mut entry = [0u8; DISCOVERY_LOG_ENTRY_SIZE];
+    entry[0] = TRTYPE_TCP;
+    entry[1] = ADRFAM_IPV4;
+    entry[2] = SUBTYPE_NVME;
+    entry[3] = TREQ_SECURE_CHANNEL_NOT_REQUIRED;
+    entry[6 .. 8].copy_from_slice(&CNTLID_DYNAMIC.to_le_bytes());
+    entry[8 .. 10].copy_from_slice(&ADMIN_QUEUE_SIZE.to_le_bytes());
+    put_padded(&mut entry[32 .. 64], port);
+    // the NQN is null terminated
+    let len = nqn.len().min(255);
+    entry[256 .. 256 + len].copy_from_slice(&nqn.as_bytes()[.. len]);
+    put_padded(&mut entry[512 .. 768], address);
+    log.extend_from_slice(&entry);
+}
+
+/// Builds the discovery log page for the given host, listing the local
+/// subsystems it may connect to and the referrals.
+fn discovery_log(
+    subsystems: impl Iterator<Item = NvmfSubsystem>,
+    host: &str,
+    referrals: &[Referral],
+    generation: u64,
+) -> Vec<u8> {
+    let mut log = vec![0u8; DISCOVERY_LOG_ENTRY_SIZE];
+    let mut count = 0u64;
+
+    for subsystem in subsystems {
+        if subsystem.subtype() != SubType::Nvme || !subsystem.host_allowed(host)
+        {
+            continue;
+        }
+        let nqn = subsystem.get_nqn();
+        if referrals.iter().any(|r| r.nqn == nqn) {
+            continue;
+        }
+        for trid in subsystem.listeners_to_vec().unwrap_or_default() {
+            put_entry(
+                &mut log,
+                &nqn,
+                trid.traddr.as_str(),
+                trid.trsvcid.as_str(),
+            );
+            count += 1;
+        }
+    }
+
+    for r in referrals {
+        put_entry(&mut log, &r.nqn, &r.address, &r.port.to_string());
+        count += 1;
+    }
+
+    log[0 .. 8].copy_from_slice(&generation.to_le_bytes());
+    log[8 .. 16].copy_from_slice(&count.to_le_bytes());
+    log
+}
+
+/// Builds the discovery log page requested, while there are referrals.
+/// Return: None to leave the command to SPDK
+pub(super) fn discovery_log_page(
+    req: *mut spdk_nvmf_request,
+    subsys: *mut spdk_nvmf_subsystem,
+    lid: u32,
+) -> Option<Vec<u8>> {
+    if lid != DISCOVERY_LOG_LID {
+        return None;
+    }
+
+    let referrals = live_referrals();
+    if referrals.is_empty() {
+        return None;
+    }
+
+    let (host, tgt, spdk_generation) = unsafe {
+        let ctrlr = (*(*req).qpair).ctrlr;
+        let host = CStr::from_ptr((*ctrlr).hostnqn.as_ptr())
+            .to_string_lossy()
+            .to_string();
+        let tgt = (*subsys).tgt;
+        (host, tgt, (*tgt).discovery_genctr)
+    };
+
+    Some(discovery_log(
+        NvmfSubsystem::iter_target(tgt),
+        &host,
+        &referrals,
+        spdk_generation + GENERATION.load(Ordering::SeqCst),
+    ))
+}
+
+/// Arguments of the `nvmf_add_referral` json-rpc method.
+#[derive(Deserialize)]
+struct AddReferralArgs {
+    /// URI the subsystem is shared with on its new target.
+    uri: String,
+    /// Seconds the referral is kept for.
+    grace_secs: u64,
+}
+
+/// Arguments of the `nvmf_remove_referral` json-rpc method.
+#[derive(Deserialize)]
+struct RemoveReferralArgs {
+    nqn: String,
+}
+
+/// Register the referral json-rpc methods.
+pub(super) fn register_rpc_methods() {
+    jsonrpc_register("nvmf_add_referral", |args: AddReferralArgs| {
+        async move {
+            add_referral(&args.uri, Duration::from_secs(args.grace_secs))
+                .map_err(|e| JsonRpcError::new(Code::InvalidParams, e))
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("nvmf_remove_referral", |args: RemoveReferralArgs| {
+        async move {
+            if remove_referral(&args.nqn) {
+                Ok(())
+            } else {
+                Err(JsonRpcError::new(
+                    Code::NotFound,
+                    format!("No referral of '{}'", args.nqn),
+                ))
+            }
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register::<(), _, _, JsonRpcError>("nvmf_get_referrals", |_| {
+        async move { Ok(live_referrals()) }.boxed_local()
+    });
+}
@@ -2,6 +2,7 @@ use std::{
     ffi::{c_void, CString},
     fmt::{self, Debug, Display, Formatter},
     mem::size_of,
+    path::Path,
     ptr::{self, NonNull},
 };
 
@@ -14,6 +15,7 @@ use spdk_rs::libspdk::{
     nvmf_subsystem_set_cntlid_range,
     spdk_bdev_nvme_opts,
     spdk_nvmf_ns_get_bdev,
+    spdk_nvmf_ns_get_id,
     spdk_nvmf_ns_opts,
     spdk_nvmf_subsystem,
     spdk_nvmf_subsystem_add_host,
@@ -22,6 +24,7 @@ use spdk_rs::libspdk::{
     spdk_nvmf_subsystem_create,
     spdk_nvmf_subsystem_destroy,
     spdk_nvmf_subsystem_disconnect_host,
+    spdk_nvmf_subsystem_get_ana_reporting,
     spdk_nvmf_subsystem_get_first,
     spdk_nvmf_subsystem_get_first_host,
     spdk_nvmf_subsystem_get_first_listener,
@@ -30,9 +33,12 @@ use spdk_rs::libspdk::{
     spdk_nvmf_subsystem_get_next_host,
     spdk_nvmf_subsystem_get_next_listener,
     spdk_nvmf_subsystem_get_nqn,
+    spdk_nvmf_subsystem_host_allowed,
     spdk_nvmf_subsystem_listener_get_trid,
     spdk_nvmf_subsystem_pause,
     spdk_nvmf_subsystem_remove_host,
+    spdk_nvmf_subsystem_remove_listener,
+    spdk_nvmf_subsystem_remove_ns,
     spdk_nvmf_subsystem_resume,
     spdk_nvmf_subsystem_set_allow_any_host,
     spdk_nvmf_subsystem_set_ana_reporting,
@@ -52,7 +58,12 @@ use crate::{
     ffihelper::{cb_arg, AsStr, FfiResult, IntoCString},
     subsys::{
         make_subsystem_serial,
-        nvmf::{transport::TransportId, Error, NVMF_TGT},
+        nvmf::{
+            mdns,
+            transport::{self, TransportId, VFIO_USER_DIR},
+            Error,
+            NVMF_TGT,
+        },
         Config,
     },
 };
@@ -270,6 +281,31 @@ impl NvmfSubsystem {
         }
     }
 
+    /// Remove the namespaces of the paused subsystem. The hosts are notified
+    /// of the removal with a Namespace Attribute Changed event once the
+    /// subsystem is resumed, and their commands to the namespaces fail from
+    /// then on without reaching the bdevs.
+    pub fn remove_namespaces(&self) -> Result<(), Error> {
+        loop {
+            let ns =
+                unsafe { spdk_nvmf_subsystem_get_first_ns(self.0.as_ptr()) };
+            if ns.is_null() {
+                return Ok(());
+            }
+            let ns_id = unsafe { spdk_nvmf_ns_get_id(ns) };
+            let rc = unsafe {
+                spdk_nvmf_subsystem_remove_ns(self.0.as_ptr(), ns_id)
+            };
+            if rc != 0 {
+                return Err(Error::Namespace {
+                    bdev: self.get_nqn(),
+                    msg: format!("failed to remove namespace {}", ns_id),
+                });
+            }
+            debug!(?self, ?ns_id, "removed namespace");
+        }
+    }
+
     /// destroy the subsystem
     pub fn destroy(&self) -> i32 {
         unsafe {
@@ -307,6 +343,18 @@ impl NvmfSubsystem {
         }
     }
 
+    /// Returns true if the given host is allowed to connect to this
+    /// subsystem.
+    pub(super) fn host_allowed(&self, host: &str) -> bool {
+        let host = match CString::new(host) {
+            Ok(host) => host,
+            Err(_) => return false,
+        };
+        unsafe {
+            spdk_nvmf_subsystem_host_allowed(self.0.as_ptr(), host.as_ptr())
+        }
+    }
+
     /// Get a list with all the host nqn's allowed to connect to this subsystem.
     pub fn allowed_hosts(&self) -> Vec<String> {
         let mut hosts = Vec::with_capacity(4);
@@ -327,6 +375,56 @@ impl NvmfSubsystem {
         hosts
     }
 
+    /// Get a list with the host nqn's of the controllers connected to this
+    /// subsystem, one per controller.
+    /// The controllers are only added and removed on the thread of the
+    /// subsystem, so this must be called from the master reactor.
+    pub fn connected_hosts(&self) -> Vec<String> {
+        let mut hosts = Vec::new();
+
+        let mut ctrlr = unsafe { (*self.0.as_ptr()).ctrlrs.tqh_first };
+
+        while !ctrlr.is_null() {
+            let host_str = unsafe { (*ctrlr).hostnqn.as_str() };
+
+            hosts.push(host_str.to_string());
+
+            ctrlr = unsafe { (*ctrlr).link.tqe_next };
+        }
+
+        hosts
+    }
+
+    /// Get the host nqn's of the controllers connected to this subsystem,
+    /// with the address of the listener each of them connected through.
+    /// Must be called from the master reactor.
+    pub(super) fn connected_host_addresses(&self) -> Vec<(String, String)> {
+        let mut hosts = Vec::new();
+
+        let mut ctrlr = unsafe { (*self.0.as_ptr()).ctrlrs.tqh_first };
+
+        while !ctrlr.is_null() {
+            let host_str = unsafe { (*ctrlr).hostnqn.as_str() };
+            let listener = unsafe { (*ctrlr).listener };
+            let address = if listener.is_null() {
+                String::new()
+            } else {
+                unsafe {
+                    (*spdk_nvmf_subsystem_listener_get_trid(listener as *mut _))
+                        .traddr
+                        .as_str()
+                        .to_string()
+                }
+            };
+
+            hosts.push((host_str.to_string(), address));
+
+            ctrlr = unsafe { (*ctrlr).link.tqe_next };
+        }
+
+        hosts
+    }
+
     /// Sets the allowed hosts to connect to the subsystem.
     /// It also disallows and disconnects any previously registered host.
     /// # Warning
@@ -491,22 +589,29 @@ impl NvmfSubsystem {
 
     // we currently allow all listeners to the subsystem
     async fn add_listener(&self) -> Result<(), Error> {
-        extern "C" fn listen_cb(arg: *mut c_void, status: i32) {
-            let s = unsafe { Box::from_raw(arg as *mut oneshot::Sender<i32>) };
-            s.send(status).unwrap();
-        }
-
         let cfg = Config::get();
 
         // dont yet enable both ports, IOW just add one transportID now
 
         let trid_replica = TransportId::new(cfg.nexus_opts.nvmf_replica_port);
+        self.add_listener_trid(&trid_replica).await
+    }
+
+    /// add a listener on the given transport ID
+    pub(super) async fn add_listener_trid(
+        &self,
+        trid: &TransportId,
+    ) -> Result<(), Error> {
+        extern "C" fn listen_cb(arg: *mut c_void, status: i32) {
+            let s = unsafe { Box::from_raw(arg as *mut oneshot::Sender<i32>) };
+            s.send(status).unwrap();
+        }
 
         let (s, r) = oneshot::channel::<i32>();
         unsafe {
             spdk_nvmf_subsystem_add_listener(
                 self.0.as_ptr(),
-                trid_replica.as_ptr(),
+                trid.as_ptr(),
                 Some(listen_cb),
                 cb_arg(s),
             );
@@ -520,6 +625,25 @@ impl NvmfSubsystem {
         })
     }
 
+    /// remove the listener on the given transport ID, the subsystem is paused
+    /// meanwhile
+    pub(super) async fn remove_listener_trid(
+        &self,
+        trid: &TransportId,
+    ) -> Result<(), Error> {
+        self.pause().await?;
+        let res = unsafe {
+            spdk_nvmf_subsystem_remove_listener(self.0.as_ptr(), trid.as_ptr())
+        }
+        .to_result(|e| Error::Subsystem {
+            source: Errno::from_i32(e),
+            nqn: self.get_nqn(),
+            msg: format!("failed to remove listener {}", trid),
+        });
+        self.resume().await?;
+        res
+    }
+
     /// TODO
     async fn change_state(
         &self,
@@ -618,16 +742,80 @@ impl NvmfSubsystem {
 
             Err(e)
         } else {
+            mdns::advertise(&self);
             Ok(self.get_nqn())
         }
     }
 
+    /// Start the subsystem as an emulated NVMe controller of the guests of
+    /// the node, over vfio-user. The socket of the controller is created in a
+    /// directory of its own, named after the subsystem, which is returned.
+    pub async fn start_vfio_user(self) -> Result<String, Error> {
+        let err = |msg: String| Error::Transport {
+            source: Errno::EINVAL,
+            msg,
+        };
+
+        transport::add_vfio_user_transport().await?;
+
+        let nqn = self.get_nqn();
+        let name = nqn.rsplit(':').next().unwrap_or(&nqn).to_string();
+        let dir = Path::new(VFIO_USER_DIR).join(&name);
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            self.destroy();
+            return Err(err(format!(
+                "failed to create {}: {}",
+                dir.display(),
+                e
+            )));
+        }
+
+        // the guest is the only host of the controller
+        self.allow_any(true);
+        let trid = TransportId::vfio_user(&dir);
+        if let Err(e) = self.add_listener_trid(&trid).await {
+            self.destroy();
+            return Err(e);
+        }
+
+        if let Err(e) = self
+            .change_state("start", |ss, cb, arg| unsafe {
+                spdk_nvmf_subsystem_start(ss, cb, arg)
+            })
+            .await
+        {
+            error!(
+                "Failed to start vfio-user subsystem '{}': {}; destroying it",
+                nqn,
+                e.to_string(),
+            );
+            self.destroy();
+            return Err(e);
+        }
+
+        info!(
+            "Started vfio-user controller '{}' in {}",
+            nqn,
+            dir.display()
+        );
+        Ok(dir.to_string_lossy().to_string())
+    }
+
+    /// Returns true if the subsystem listens over vfio-user.
+    pub fn is_vfio_user(&self) -> bool {
+        self.listeners_to_vec()
+            .map(|ids| ids.iter().any(|t| t.is_vfio_user()))
+            .unwrap_or(false)
+    }
+
     /// stop the subsystem
     pub async fn stop(&self) -> Result<(), Error> {
         self.change_state("stop", |ss, cb, arg| unsafe {
             spdk_nvmf_subsystem_stop(ss, cb, arg)
         })
-        .await
+        .await?;
+        mdns::withdraw(&self.get_nqn());
+        Ok(())
     }
 
     /// transition the subsystem to paused state
@@ -667,19 +855,33 @@ impl NvmfSubsystem {
     /// set ANA state: optimized, non_optimized, inaccessible
     /// subsystem must be in paused or inactive state
     pub async fn set_ana_state(&self, ana_state: u32) -> Result<(), Error> {
+        let cfg = Config::get();
+        let trid_replica = TransportId::new(cfg.nexus_opts.nvmf_replica_port);
+        self.set_listener_ana_state(&trid_replica, ana_state).await
+    }
+
+    /// Returns true if Asymmetric Namespace Access (ANA) reporting is enabled.
+    pub(super) fn ana_reporting(&self) -> bool {
+        unsafe { spdk_nvmf_subsystem_get_ana_reporting(self.0.as_ptr()) }
+    }
+
+    /// set the ANA state of the listener on the given transport ID
+    pub(super) async fn set_listener_ana_state(
+        &self,
+        trid: &TransportId,
+        ana_state: u32,
+    ) -> Result<(), Error> {
         extern "C" fn set_ana_state_cb(arg: *mut c_void, status: i32) {
             let s = unsafe { Box::from_raw(arg as *mut oneshot::Sender<i32>) };
             s.send(status).unwrap();
         }
-        let cfg = Config::get();
-        let trid_replica = TransportId::new(cfg.nexus_opts.nvmf_replica_port);
 
         let (s, r) = oneshot::channel::<i32>();
 
         unsafe {
             nvmf_subsystem_set_ana_state(
                 self.0.as_ptr(),
-                trid_replica.as_ptr(),
+                trid.as_ptr(),
                 ana_state,
                 0,
                 Some(set_ana_state_cb),
@@ -742,6 +944,13 @@ impl NvmfSubsystem {
         })
     }
 
+    /// Iterates over the subsystems of the given target, from any thread.
+    pub(super) fn iter_target(
+        tgt: *mut spdk_nvmf_tgt,
+    ) -> NvmfSubsystemIterator {
+        NvmfSubsystemIterator(unsafe { spdk_nvmf_subsystem_get_first(tgt) })
+    }
+
     /// lookup a subsystem by its UUID
     pub fn nqn_lookup(uuid: &str) -> Option<NvmfSubsystem> {
         let nqn = gen_nqn(uuid);
@@ -763,7 +972,7 @@ impl NvmfSubsystem {
         Bdev::checked_from_ptr(unsafe { spdk_nvmf_ns_get_bdev(ns) })
     }
 
-    fn listeners_to_vec(&self) -> Option<Vec<TransportId>> {
+    pub(super) fn listeners_to_vec(&self) -> Option<Vec<TransportId>> {
         unsafe {
             let mut listener =
                 spdk_nvmf_subsystem_get_first_listener(self.0.as_ptr());
@@ -2,12 +2,12 @@ use std::{
     cell::RefCell,
     ffi::{c_void, CString},
     ptr::NonNull,
+    time::Duration,
 };
 
 use nix::errno::Errno;
 
 use spdk_rs::libspdk::{
-    spdk_env_get_core_count,
     spdk_nvmf_listen_opts,
     spdk_nvmf_listen_opts_init,
     spdk_nvmf_poll_group_destroy,
@@ -27,11 +27,13 @@ use spdk_rs::libspdk::{
 
 use crate::{
     constants::NVME_CONTROLLER_MODEL_ID,
-    core::{Cores, Mthread, Reactor, Reactors},
+    core::{Cores, MayastorEnvironment, Mthread, Reactor, Reactors},
     ffihelper::{AsStr, FfiResult},
     subsys::{
         nvmf::{
+            mdns,
             poll_groups::PollGroup,
+            rebalance::rebalance_loop,
             subsystem::NvmfSubsystem,
             transport,
             transport::{get_ipv4_address, TransportId},
@@ -54,8 +56,12 @@ pub struct Target {
     pub(crate) tgt: NonNull<spdk_nvmf_tgt>,
     /// the number of poll groups created for this target
     poll_group_count: u16,
+    /// the cores the poll groups of this target are created on
+    poll_group_cores: Vec<u32>,
     /// The current state of the target
     next_state: TargetState,
+    /// Whether the target listens on the nexus and replica ports
+    listening: bool,
 }
 
 impl Default for Target {
@@ -100,7 +106,9 @@ impl Target {
         Self {
             tgt: NonNull::dangling(),
             poll_group_count: 0,
+            poll_group_cores: Vec::new(),
             next_state: TargetState::Init,
+            listening: false,
         }
     }
 
@@ -184,30 +192,75 @@ impl Target {
         })
     }
 
-    /// init the poll groups per core
-    fn init_poll_groups(&self) {
-        Reactors::iter().for_each(|r| {
-            if let Some(t) = Mthread::new(
-                format!("mayastor_nvmf_tcp_pg_core_{}", r.core()),
-                r.core(),
-            ) {
-                r.send_future(Self::create_poll_group(self.tgt.as_ptr(), t));
-            }
-        });
+    /// Returns the cores to create the poll groups on: the configured cores
+    /// which run a reactor, or all the reactor cores if none is configured.
+    fn select_poll_group_cores() -> Vec<u32> {
+        let configured =
+            Config::get().nvmf_tcp_tgt_conf.poll_group_cores.clone();
+        let reactors = Reactors::iter().map(|r| r.core()).collect::<Vec<_>>();
+        let cores = reactors
+            .iter()
+            .copied()
+            .filter(|c| configured.is_empty() || configured.contains(c))
+            .collect::<Vec<_>>();
+
+        if cores.is_empty() {
+            warn!(
+                "none of the poll group cores {:?} runs a reactor, \
+                creating poll groups on all cores",
+                configured
+            );
+            reactors
+        } else {
+            cores
+        }
+    }
+
+    /// Returns the cores the poll groups are created on.
+    pub(crate) fn poll_group_cores(&self) -> Vec<u32> {
+        self.poll_group_cores.clone()
+    }
+
+    /// init the poll groups on the selected cores
+    fn init_poll_groups(&mut self) {
+        self.poll_group_cores = Self::select_poll_group_cores();
+        info!(
+            "creating nvmf poll groups on cores {:?}",
+            self.poll_group_cores
+        );
+
+        Reactors::iter()
+            .filter(|r| self.poll_group_cores.contains(&r.core()))
+            .for_each(|r| {
+                if let Some(t) = Mthread::new(
+                    format!("mayastor_nvmf_tcp_pg_core_{}", r.core()),
+                    r.core(),
+                ) {
+                    r.send_future(Self::create_poll_group(
+                        self.tgt.as_ptr(),
+                        t,
+                        r.core(),
+                    ));
+                }
+            });
     }
 
     /// init the poll groups implementation
-    async fn create_poll_group(tgt: *mut spdk_nvmf_tgt, mt: Mthread) {
+    async fn create_poll_group(
+        tgt: *mut spdk_nvmf_tgt,
+        mt: Mthread,
+        core: u32,
+    ) {
         mt.with(|| {
-            let pg = PollGroup::new(tgt, mt);
+            let pg = PollGroup::new(tgt, mt, core);
 
             Reactors::master().send_future(async move {
                 NVMF_TGT.with(|tgt| {
                     let mut tgt = tgt.borrow_mut();
                     NVMF_PGS.with(|p| p.borrow_mut().push(pg));
                     tgt.poll_group_count += 1;
-                    if tgt.poll_group_count
-                        == unsafe { spdk_env_get_core_count() as u16 }
+                    if tgt.poll_group_count as usize
+                        == tgt.poll_group_cores.len()
                     {
                         Reactors::master().send_future(async {
                             NVMF_TGT.with(|tgt| {
@@ -221,8 +274,24 @@ impl Target {
     }
 
     /// Listen for incoming connections by default we only listen on the replica
-    /// port
+    /// port. When taking over from another io-engine, the ports are still in
+    /// use and listening is deferred until the resources are handed over.
     fn listen(&mut self) -> Result<()> {
+        if MayastorEnvironment::global_or_default().take_over.is_some() {
+            info!("nvmf target not listening until resources are handed over");
+        } else {
+            self.add_listeners()?;
+        }
+        self.next_state();
+        Ok(())
+    }
+
+    /// Listen on the nexus and replica ports.
+    fn add_listeners(&mut self) -> Result<()> {
+        if self.listening {
+            return Ok(());
+        }
+
         let cfg = Config::get();
         let trid_nexus = TransportId::new(cfg.nexus_opts.nvmf_nexus_port);
         let mut opts = spdk_nvmf_listen_opts::default();
@@ -266,10 +335,120 @@ impl Target {
             trid_nexus.trsvcid.as_str(),
             trid_replica.trsvcid.as_str(),
         );
-        self.next_state();
+        self.listening = true;
         Ok(())
     }
 
+    /// Stop listening on the nexus and replica ports, new connections are
+    /// refused.
+    fn remove_listeners(&mut self) {
+        if !self.listening {
+            return;
+        }
+
+        let cfg = Config::get();
+        let trid_nexus = TransportId::new(cfg.nexus_opts.nvmf_nexus_port);
+        let trid_replica = TransportId::new(cfg.nexus_opts.nvmf_replica_port);
+
+        unsafe {
+            spdk_nvmf_tgt_stop_listen(self.tgt.as_ptr(), trid_replica.as_ptr())
+        };
+
+        unsafe {
+            spdk_nvmf_tgt_stop_listen(self.tgt.as_ptr(), trid_nexus.as_ptr())
+        };
+
+        self.listening = false;
+        info!("nvmf target stopped listening");
+    }
+
+    /// Returns true if the target is running and listens for connections.
+    /// Must be called from the master core.
+    pub fn is_listening() -> bool {
+        NVMF_TGT.with(|t| {
+            let t = t.borrow();
+            t.next_state == TargetState::Running && t.listening
+        })
+    }
+
+    /// Start listening for connections, if the target was not listening yet.
+    /// Must be called from the master core.
+    pub fn start_listening() -> Result<()> {
+        if !Config::get().nexus_opts.nvmf_enable {
+            return Ok(());
+        }
+        NVMF_TGT.with(|t| t.borrow_mut().add_listeners())
+    }
+
+    /// Stop listening for connections, so that another process can listen
+    /// on the same ports. Must be called from the master core.
+    pub fn stop_listening() {
+        if Config::get().nexus_opts.nvmf_enable {
+            NVMF_TGT.with(|t| t.borrow_mut().remove_listeners());
+        }
+    }
+
+    /// Listen on the nexus and replica ports of the given address as well,
+    /// the address of the target being migrated to. Must be called from the
+    /// master core.
+    pub(super) fn listen_on(address: &str) -> Result<()> {
+        let cfg = Config::get();
+        NVMF_TGT.with(|t| {
+            let tgt = t.borrow().tgt;
+            let mut opts = spdk_nvmf_listen_opts::default();
+            unsafe {
+                spdk_nvmf_listen_opts_init(
+                    &mut opts,
+                    std::mem::size_of::<spdk_nvmf_listen_opts>() as u64,
+                );
+            }
+            for port in [
+                cfg.nexus_opts.nvmf_nexus_port,
+                cfg.nexus_opts.nvmf_replica_port,
+            ]
+            .iter()
+            {
+                let trid = TransportId::with_address(address, *port);
+                let rc = unsafe {
+                    spdk_nvmf_tgt_listen_ext(
+                        tgt.as_ptr(),
+                        trid.as_ptr(),
+                        &mut opts,
+                    )
+                };
+                if rc != 0 {
+                    return Err(Error::CreateTarget {
+                        msg: format!("failed to listen on {}", trid),
+                    });
+                }
+            }
+            info!("nvmf target listening on {} too", address);
+            Ok(())
+        })
+    }
+
+    /// Stop listening on the nexus and replica ports of the given address,
+    /// the address of the target migrated from. Must be called from the
+    /// master core.
+    pub(super) fn stop_listen_on(address: &str) {
+        let cfg = Config::get();
+        NVMF_TGT.with(|t| {
+            let tgt = t.borrow().tgt;
+            for port in [
+                cfg.nexus_opts.nvmf_nexus_port,
+                cfg.nexus_opts.nvmf_replica_port,
+            ]
+            .iter()
+            {
+                let trid = TransportId::with_address(address, *port);
+                unsafe {
+                    spdk_nvmf_tgt_stop_listen(tgt.as_ptr(), trid.as_ptr())
+                };
+            }
+        });
+        info!("nvmf target stopped listening on {}", address);
+    }
+
     /// enable discovery for the target -- note that the discovery system is not
     /// started
     fn enable_discovery(&self) {
@@ -309,6 +488,7 @@ impl Target {
         let tgt = self.tgt.as_ptr();
         Reactors::master().send_future(async move {
             NvmfSubsystem::stop_all(tgt).await;
+            mdns::shutdown();
             debug!("All subsystems stopped");
             NvmfSubsystem::destroy_all();
         });
@@ -355,7 +535,14 @@ impl Target {
 
     /// Final state for the target during init.
     pub fn running(&mut self) {
+        mdns::init();
         self.enable_discovery();
+        if let Some(interval) = MayastorEnvironment::global_or_default()
+            .poll_group_rebalance_interval()
+        {
+            Reactors::master()
+                .send_future(rebalance_loop(Duration::from_secs(interval)));
+        }
         info!(
             "nvmf target accepting new connections and is ready to roll..{}",
             '\u{1F483}'
@@ -373,17 +560,7 @@ impl Target {
             }
         }
 
-        let cfg = Config::get();
-        let trid_nexus = TransportId::new(cfg.nexus_opts.nvmf_nexus_port);
-        let trid_replica = TransportId::new(cfg.nexus_opts.nvmf_replica_port);
-
-        unsafe {
-            spdk_nvmf_tgt_stop_listen(self.tgt.as_ptr(), trid_replica.as_ptr())
-        };
-
-        unsafe {
-            spdk_nvmf_tgt_stop_listen(self.tgt.as_ptr(), trid_nexus.as_ptr())
-        };
+        self.remove_listeners();
 
         unsafe {
             spdk_nvmf_tgt_destroy(
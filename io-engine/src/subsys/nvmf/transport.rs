@@ -1,7 +1,10 @@
 use std::{
+    cell::Cell,
     ffi::CString,
     fmt::{Debug, Display, Formatter},
+    mem::size_of,
     ops::{Deref, DerefMut},
+    path::Path,
 };
 
 use futures::channel::oneshot;
@@ -14,7 +17,10 @@ use spdk_rs::{
         spdk_nvme_transport_id,
         spdk_nvmf_tgt_add_transport,
         spdk_nvmf_transport_create,
+        spdk_nvmf_transport_opts,
+        spdk_nvmf_transport_opts_init,
         SPDK_NVME_TRANSPORT_TCP,
+        SPDK_NVME_TRANSPORT_VFIOUSER,
         SPDK_NVMF_ADRFAM_IPV4,
         SPDK_NVMF_TRSVCID_MAX_LEN,
     },
@@ -31,6 +37,16 @@ use crate::{
 
 static TCP_TRANSPORT: Lazy<CString> =
     Lazy::new(|| CString::new("TCP").unwrap());
+static VFIO_USER_TRANSPORT: Lazy<CString> =
+    Lazy::new(|| CString::new("VFIOUSER").unwrap());
+
+/// Directory the vfio-user controllers are created in, one directory each.
+pub(crate) const VFIO_USER_DIR: &str = "/var/tmp/io-engine/vfio-user";
+
+thread_local! {
+    /// The vfio-user transport is added on the first vfio-user share.
+    static VFIO_USER_ADDED: Cell<bool> = Cell::new(false);
+}
 
 pub async fn add_tcp_transport() -> Result<(), Error> {
     let cfg = Config::get();
@@ -62,6 +78,58 @@ pub async fn add_tcp_transport() -> Result<(), Error> {
     Ok(())
 }
 
+/// Add the vfio-user transport to the target, unless it was added already.
+/// It fails if SPDK was built without vfio-user.
+pub async fn add_vfio_user_transport() -> Result<(), Error> {
+    if VFIO_USER_ADDED.with(|a| a.get()) {
+        return Ok(());
+    }
+
+    let mut opts = spdk_nvmf_transport_opts::default();
+    let ok = unsafe {
+        spdk_nvmf_transport_opts_init(
+            VFIO_USER_TRANSPORT.as_ptr(),
+            &mut opts,
+            size_of::<spdk_nvmf_transport_opts>() as u64,
+        )
+    };
+    if !ok {
+        return Err(Error::Transport {
+            source: Errno::ENOTSUP,
+            msg: "vfio-user transport is not available".into(),
+        });
+    }
+
+    let transport = unsafe {
+        spdk_nvmf_transport_create(VFIO_USER_TRANSPORT.as_ptr(), &mut opts)
+    };
+    transport.to_result(|_| Error::Transport {
+        source: Errno::UnknownErrno,
+        msg: "failed to create vfio-user transport".into(),
+    })?;
+
+    let (s, r) = oneshot::channel::<ErrnoResult<()>>();
+    unsafe {
+        NVMF_TGT.with(|t| {
+            spdk_nvmf_tgt_add_transport(
+                t.borrow().tgt.as_ptr(),
+                transport,
+                Some(done_errno_cb),
+                cb_arg(s),
+            );
+        })
+    };
+
+    r.await.unwrap().map_err(|source| Error::Transport {
+        source,
+        msg: "failed to add vfio-user transport".into(),
+    })?;
+
+    VFIO_USER_ADDED.with(|a| a.set(true));
+    debug!("Added vfio-user nvmf transport");
+    Ok(())
+}
+
 pub struct TransportId(pub(crate) spdk_nvme_transport_id);
 impl Deref for TransportId {
     type Target = spdk_nvme_transport_id;
@@ -79,8 +147,11 @@ impl DerefMut for TransportId {
 
 impl TransportId {
     pub fn new(port: u16) -> Self {
-        let address = get_ipv4_address().unwrap();
+        Self::with_address(&get_ipv4_address().unwrap(), port)
+    }
 
+    /// Transport ID of a TCP listener on the given address and port.
+    pub fn with_address(address: &str, port: u16) -> Self {
         let mut trid = spdk_nvme_transport_id {
             trtype: SPDK_NVME_TRANSPORT_TCP,
             adrfam: SPDK_NVMF_ADRFAM_IPV4,
@@ -91,12 +162,31 @@ impl TransportId {
         assert!(port.len() < SPDK_NVMF_TRSVCID_MAX_LEN as usize);
 
         copy_cstr_with_null(&TCP_TRANSPORT, &mut trid.trstring);
-        copy_str_with_null(&address, &mut trid.traddr);
+        copy_str_with_null(address, &mut trid.traddr);
         copy_str_with_null(&port, &mut trid.trsvcid);
 
         Self(trid)
     }
 
+    /// Transport ID of a vfio-user controller, whose socket is created in
+    /// the given directory.
+    pub fn vfio_user(dir: &Path) -> Self {
+        let mut trid = spdk_nvme_transport_id {
+            trtype: SPDK_NVME_TRANSPORT_VFIOUSER,
+            ..Default::default()
+        };
+
+        copy_cstr_with_null(&VFIO_USER_TRANSPORT, &mut trid.trstring);
+        copy_str_with_null(&dir.to_string_lossy(), &mut trid.traddr);
+
+        Self(trid)
+    }
+
+    /// Returns true if the transport is vfio-user.
+    pub fn is_vfio_user(&self) -> bool {
+        self.0.trtype == SPDK_NVME_TRANSPORT_VFIOUSER
+    }
+
     pub fn as_ptr(&self) -> *mut spdk_nvme_transport_id {
         &self.0 as *const _ as *mut spdk_nvme_transport_id
     }
@@ -104,6 +194,9 @@ impl TransportId {
 
 impl Display for TransportId {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.is_vfio_user() {
+            return write!(f, "vfio-user://{}", self.0.traddr.as_str());
+        }
         write!(
             f,
             "nvmf://{}:{}",
@@ -0,0 +1,379 @@
+//! Methods for creating iSCSI targets, for the hosts whose kernel lacks an
+//! NVMe/TCP initiator.
+//!
+//! Every shared bdev gets a target node of its own, named after the bdev,
+//! which exports it as LUN 0 through a single portal group listening on the
+//! address of the NVMF target. The portal group is created on the first
+//! share.
+//!
+//! The hosts allowed to connect to a target node are given by an initiator
+//! group of its own, listing their IQNs, any host is allowed otherwise. When
+//! shared with a key, the hosts must authenticate with CHAP, the name of the
+//! bdev being the user and the key its secret.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    ffi::CString,
+    os::raw::{c_char, c_int},
+    ptr,
+};
+
+use futures::channel::oneshot;
+use nix::errno::Errno;
+use serde::Serialize;
+use snafu::{ResultExt, Snafu};
+use spdk_rs::libspdk::{
+    iscsi_add_auth_group,
+    iscsi_auth_group_add_secret,
+    iscsi_delete_auth_group,
+    iscsi_find_auth_group_by_tag,
+    iscsi_find_tgt_node,
+    iscsi_init_grp_create_from_initiator_list,
+    iscsi_init_grp_destroy,
+    iscsi_init_grp_unregister,
+    iscsi_portal_create,
+    iscsi_portal_grp_add_portal,
+    iscsi_portal_grp_create,
+    iscsi_portal_grp_open,
+    iscsi_portal_grp_register,
+    iscsi_portal_grp_release,
+    iscsi_shutdown_tgt_node_by_name,
+    iscsi_tgt_node_construct,
+    spdk_iscsi_auth_group,
+};
+
+use crate::{
+    core::{Bdev, MayastorEnvironment},
+    ffihelper::{cb_arg, done_errno_cb, ErrnoResult, IntoCString},
+};
+
+/// Prefix of the IQNs of the target nodes.
+const ISCSI_IQN_PREFIX: &str = "iqn.2019-05.io.openebs";
+/// Port of the portal group.
+const ISCSI_PORT: u16 = 3260;
+/// Tag of the portal group.
+const ISCSI_PORTAL_GROUP: c_int = 1;
+/// Queue depth of a target node.
+const ISCSI_QUEUE_DEPTH: c_int = 128;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)), context(suffix(false)))]
+pub enum Error {
+    #[snafu(display("Failed to create portal group on {}", address))]
+    CreatePortalGroup { address: String },
+    #[snafu(display("Failed to create initiator group for '{}'", bdev))]
+    CreateInitiatorGroup { bdev: String },
+    #[snafu(display("Failed to create CHAP secret for '{}'", bdev))]
+    CreateChapSecret { bdev: String },
+    #[snafu(display("Failed to create iSCSI target for '{}'", bdev))]
+    CreateTarget { bdev: String },
+    #[snafu(display("Failed to destroy iSCSI target '{}': {}", iqn, source))]
+    DestroyTarget { source: Errno, iqn: String },
+    #[snafu(display("Destroy of iSCSI target '{}' was canceled", iqn))]
+    DestroyCanceled { iqn: String },
+}
+
+/// Target node of a shared bdev.
+#[derive(Debug, Clone, Serialize)]
+pub struct IscsiTarget {
+    /// IQN of the target node.
+    pub iqn: String,
+    pub bdev: String,
+    /// IQNs of the initiators allowed to connect, any if empty.
+    pub allowed_hosts: Vec<String>,
+    /// The initiators authenticate with CHAP.
+    pub chap: bool,
+    /// Index of the target node, which is also the tag of its initiator
+    /// group and of its CHAP group.
+    #[serde(skip)]
+    index: c_int,
+}
+
+#[derive(Default)]
+struct IscsiState {
+    /// The portal group is created.
+    portal_group: bool,
+    next_index: c_int,
+    /// Target nodes by bdev name.
+    targets: HashMap<String, IscsiTarget>,
+}
+
+thread_local! {
+    /// iSCSI targets are managed on the management core only.
+    static ISCSI: RefCell<IscsiState> = RefCell::new(IscsiState::default());
+}
+
+/// Returns the IQN of the target node of the given bdev.
+pub fn target_name(bdev_name: &str) -> String {
+    format!("{}:{}", ISCSI_IQN_PREFIX, bdev_name)
+}
+
+/// Creates the portal group, listening on the address of the NVMF target.
+fn create_portal_group() -> Result<(), Error> {
+    let address = MayastorEnvironment::get_nvmf_tgt_ip().unwrap_or_else(|_| {
+        warn!("Failed to detect the target address, listening on any");
+        "0.0.0.0".to_string()
+    });
+    let err = || Error::CreatePortalGroup {
+        address: format!("{}:{}", address, ISCSI_PORT),
+    };
+
+    let host = address.clone().into_cstring();
+    let port = ISCSI_PORT.to_string().into_cstring();
+    unsafe {
+        let pg = iscsi_portal_grp_create(ISCSI_PORTAL_GROUP, false);
+        if pg.is_null() {
+            return Err(err());
+        }
+        let portal = iscsi_portal_create(host.as_ptr(), port.as_ptr());
+        if portal.is_null() {
+            iscsi_portal_grp_release(pg);
+            return Err(err());
+        }
+        iscsi_portal_grp_add_portal(pg, portal);
+        if iscsi_portal_grp_open(pg, false) != 0 {
+            iscsi_portal_grp_release(pg);
+            return Err(err());
+        }
+        if iscsi_portal_grp_register(pg) != 0 {
+            iscsi_portal_grp_release(pg);
+            return Err(err());
+        }
+    }
+    info!("Created iSCSI portal group on {}:{}", address, ISCSI_PORT);
+    Ok(())
+}
+
+/// Creates the initiator group of a target node, allowing the given hosts.
+fn create_initiator_group(
+    tag: c_int,
+    bdev_name: &str,
+    allowed_hosts: &[String],
+) -> Result<(), Error> {
+    let any = vec!["ANY".to_string()];
+    let names = if allowed_hosts.is_empty() {
+        &any
+    } else {
+        allowed_hosts
+    };
+    let names = names
+        .iter()
+        .map(|n| n.clone().into_cstring())
+        .collect::<Vec<_>>();
+    let mut name_ptrs = names
+        .iter()
+        .map(|n| n.as_ptr() as *mut c_char)
+        .collect::<Vec<_>>();
+    let mask = "ANY".to_string().into_cstring();
+    let mut mask_ptrs = vec![mask.as_ptr() as *mut c_char];
+
+    let rc = unsafe {
+        iscsi_init_grp_create_from_initiator_list(
+            tag,
+            name_ptrs.len() as c_int,
+            name_ptrs.as_mut_ptr(),
+            mask_ptrs.len() as c_int,
+            mask_ptrs.as_mut_ptr(),
+        )
+    };
+    if rc != 0 {
+        return Err(Error::CreateInitiatorGroup {
+            bdev: bdev_name.to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn destroy_initiator_group(tag: c_int) {
+    unsafe {
+        let ig = iscsi_init_grp_unregister(tag);
+        if !ig.is_null() {
+            iscsi_init_grp_destroy(ig);
+        }
+    }
+}
+
+/// Creates the CHAP group of a target node, with the given secret.
+fn create_chap_group(
+    tag: c_int,
+    bdev_name: &str,
+    secret: &str,
+) -> Result<(), Error> {
+    let err = || Error::CreateChapSecret {
+        bdev: bdev_name.to_string(),
+    };
+    let user = bdev_name.to_string().into_cstring();
+    let secret = CString::new(secret).map_err(|_| err())?;
+
+    let mut group: *mut spdk_iscsi_auth_group = ptr::null_mut();
+    unsafe {
+        if iscsi_add_auth_group(tag, &mut group) != 0 {
+            return Err(err());
+        }
+        if iscsi_auth_group_add_secret(
+            group,
+            user.as_ptr(),
+            secret.as_ptr(),
+            ptr::null(),
+            ptr::null(),
+        ) != 0
+        {
+            iscsi_delete_auth_group(group);
+            return Err(err());
+        }
+    }
+    Ok(())
+}
+
+fn destroy_chap_group(tag: c_int) {
+    unsafe {
+        let group = iscsi_find_auth_group_by_tag(tag);
+        if !group.is_null() {
+            iscsi_delete_auth_group(group);
+        }
+    }
+}
+
+/// Export the given bdev over iSCSI, as LUN 0 of a target node of its own.
+/// Returns the URI of the LUN.
+pub fn share<T>(
+    bdev: &Bdev<T>,
+    allowed_hosts: &[String],
+    chap_secret: Option<&str>,
+) -> Result<String, Error>
+where
+    T: spdk_rs::BdevOps,
+{
+    let bdev_name = bdev.name().to_string();
+    let iqn = target_name(&bdev_name);
+    let c_iqn = iqn.clone().into_cstring();
+
+    if !unsafe { iscsi_find_tgt_node(c_iqn.as_ptr()) }.is_null() {
+        return Ok(get_uri(&bdev_name).unwrap());
+    }
+
+    let index = ISCSI.with(|s| -> Result<c_int, Error> {
+        let mut s = s.borrow_mut();
+        if !s.portal_group {
+            create_portal_group()?;
+            s.portal_group = true;
+        }
+        s.next_index += 1;
+        Ok(s.next_index)
+    })?;
+
+    create_initiator_group(index, &bdev_name, allowed_hosts)?;
+    if let Some(secret) = chap_secret {
+        if let Err(e) = create_chap_group(index, &bdev_name, secret) {
+            destroy_initiator_group(index);
+            return Err(e);
+        }
+    }
+
+    let mut pg_tag = ISCSI_PORTAL_GROUP;
+    let mut ig_tag = index;
+    let lun_bdev = bdev_name.clone().into_cstring();
+    let mut lun_bdevs = [lun_bdev.as_ptr()];
+    let mut lun_id: c_int = 0;
+    let chap = chap_secret.is_some();
+
+    let tgt = unsafe {
+        iscsi_tgt_node_construct(
+            index,
+            c_iqn.as_ptr(),
+            ptr::null(),
+            &mut pg_tag,
+            &mut ig_tag,
+            1,
+            lun_bdevs.as_mut_ptr(),
+            &mut lun_id,
+            1,
+            ISCSI_QUEUE_DEPTH,
+            !chap, // disable_chap
+            chap,  // require_chap
+            false, // mutual_chap
+            if chap { index } else { 0 },
+            false, // header_digest
+            false, // data_digest
+        )
+    };
+    if tgt.is_null() {
+        destroy_initiator_group(index);
+        if chap {
+            destroy_chap_group(index);
+        }
+        return Err(Error::CreateTarget {
+            bdev: bdev_name,
+        });
+    }
+
+    ISCSI.with(|s| {
+        s.borrow_mut().targets.insert(
+            bdev_name.clone(),
+            IscsiTarget {
+                iqn,
+                bdev: bdev_name.clone(),
+                allowed_hosts: allowed_hosts.to_vec(),
+                chap,
+                index,
+            },
+        )
+    });
+    info!("Shared '{}' over iSCSI", bdev_name);
+
+    Ok(get_uri(&bdev_name).unwrap())
+}
+
+/// Un-export the given bdev from the iSCSI target.
+/// Unsharing a bdev which is not shared is not an error.
+pub async fn unshare(bdev_name: &str) -> Result<(), Error> {
+    let target = match ISCSI.with(|s| s.borrow_mut().targets.remove(bdev_name))
+    {
+        Some(target) => target,
+        None => return Ok(()),
+    };
+
+    let (s, r) = oneshot::channel::<ErrnoResult<()>>();
+    let iqn = target.iqn.clone().into_cstring();
+    unsafe {
+        iscsi_shutdown_tgt_node_by_name(
+            iqn.as_ptr(),
+            Some(done_errno_cb),
+            cb_arg(s),
+        );
+    }
+    r.await
+        .map_err(|_| Error::DestroyCanceled {
+            iqn: target.iqn.clone(),
+        })?
+        .context(DestroyTarget {
+            iqn: target.iqn.clone(),
+        })?;
+
+    destroy_initiator_group(target.index);
+    if target.chap {
+        destroy_chap_group(target.index);
+    }
+    info!("Unshared '{}' from iSCSI", bdev_name);
+    Ok(())
+}
+
+/// Returns the URI of the LUN of the given bdev, if shared over iSCSI.
+pub fn get_uri(bdev_name: &str) -> Option<String> {
+    let iqn = ISCSI.with(|s| {
+        s.borrow().targets.get(bdev_name).map(|t| t.iqn.clone())
+    })?;
+    let address = MayastorEnvironment::get_nvmf_tgt_ip().ok()?;
+    Some(format!("iscsi://{}:{}/{}/0", address, ISCSI_PORT, iqn))
+}
+
+/// Returns the target node of the given bdev, if shared over iSCSI.
+pub fn target(bdev_name: &str) -> Option<IscsiTarget> {
+    ISCSI.with(|s| s.borrow().targets.get(bdev_name).cloned())
+}
+
+/// Returns the target nodes of the shared bdevs.
+pub fn targets() -> Vec<IscsiTarget> {
+    ISCSI.with(|s| s.borrow().targets.values().cloned().collect())
+}
@@ -0,0 +1,9 @@
+pub mod iscsi;
+pub mod nvmf;
+pub mod vhost;
+
+// Which kind of target interface to use for a bdev
+pub enum Side {
+    Nexus,
+    Replica,
+}
@@ -0,0 +1,243 @@
+//! Methods for creating vhost-user-blk controllers, which expose bdevs
+//! directly to the QEMU/KVM guests running on the node.
+//!
+//! A controller is a unix socket, in the vhost socket directory, which QEMU
+//! connects to with `-chardev socket,path=<socket>` and a
+//! `vhost-user-blk-pci` device. The guest I/O is served by the reactors from
+//! the shared memory of the guest, without the NVMe-oF loop.
+//!
+//! A controller can be removed only while no guest is connected to it.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    os::raw::{c_char, c_int},
+    path::Path,
+    ptr,
+};
+
+use nix::errno::Errno;
+use serde::Serialize;
+use snafu::Snafu;
+use spdk_rs::libspdk::{
+    spdk_vhost_blk_construct,
+    spdk_vhost_dev_find,
+    spdk_vhost_dev_remove,
+    spdk_vhost_lock,
+    spdk_vhost_set_socket_path,
+    spdk_vhost_unlock,
+};
+
+use crate::ffihelper::IntoCString;
+
+/// Directory the sockets of the controllers are created in.
+const VHOST_SOCKET_DIR: &str = "/var/tmp/io-engine/vhost/";
+/// Prefix of the names of the controllers.
+const VHOST_CTRLR_PREFIX: &str = "vhost.";
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)), context(suffix(false)))]
+pub enum Error {
+    #[snafu(display("Failed to create socket directory {}: {}", dir, msg))]
+    CreateSocketDir { dir: String, msg: String },
+    #[snafu(display(
+        "Failed to create vhost controller '{}' for '{}': {}",
+        name,
+        bdev,
+        source
+    ))]
+    CreateController {
+        source: Errno,
+        name: String,
+        bdev: String,
+    },
+    #[snafu(display("Failed to remove vhost controller '{}': {}", name, source))]
+    RemoveController { source: Errno, name: String },
+    #[snafu(display("vhost controller '{}' does not exist", name))]
+    ControllerNotFound { name: String },
+}
+
+/// vhost-user-blk controller of a bdev.
+#[derive(Debug, Clone, Serialize)]
+pub struct VhostController {
+    pub name: String,
+    pub bdev: String,
+    /// Path of the socket QEMU connects to.
+    pub socket: String,
+    /// Cores the controller is polled on, all the cores if empty.
+    pub cpumask: String,
+    pub read_only: bool,
+}
+
+#[derive(Default)]
+struct VhostState {
+    /// The socket directory is set.
+    socket_dir: bool,
+    /// Controllers by name.
+    controllers: HashMap<String, VhostController>,
+}
+
+thread_local! {
+    /// vhost controllers are managed on the management core only.
+    static VHOST: RefCell<VhostState> = RefCell::new(VhostState::default());
+}
+
+/// Returns the default name of the controller of the given bdev.
+pub fn controller_name(bdev_name: &str) -> String {
+    format!("{}{}", VHOST_CTRLR_PREFIX, bdev_name)
+}
+
+/// Creates the socket directory and makes it the directory of the sockets.
+fn set_socket_dir() -> Result<(), Error> {
+    std::fs::create_dir_all(VHOST_SOCKET_DIR).map_err(|e| {
+        Error::CreateSocketDir {
+            dir: VHOST_SOCKET_DIR.to_string(),
+            msg: e.to_string(),
+        }
+    })?;
+    let dir = VHOST_SOCKET_DIR.to_string().into_cstring();
+    let rc = unsafe { spdk_vhost_set_socket_path(dir.as_ptr()) };
+    if rc != 0 {
+        return Err(Error::CreateSocketDir {
+            dir: VHOST_SOCKET_DIR.to_string(),
+            msg: Errno::from_i32(rc.abs()).to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Creates a vhost-user-blk controller exposing the given bdev, named after
+/// the bdev unless a name is given. The controller is polled on the given
+/// cores, all the cores if none.
+pub fn create(
+    bdev_name: &str,
+    name: Option<&str>,
+    cpumask: Option<&str>,
+    read_only: bool,
+) -> Result<VhostController, Error> {
+    let name = name
+        .map(String::from)
+        .unwrap_or_else(|| controller_name(bdev_name));
+
+    if let Some(ctrlr) = controller(&name) {
+        if ctrlr.bdev == bdev_name {
+            return Ok(ctrlr);
+        }
+        return Err(Error::CreateController {
+            source: Errno::EEXIST,
+            name,
+            bdev: bdev_name.to_string(),
+        });
+    }
+
+    VHOST.with(|s| -> Result<(), Error> {
+        let mut s = s.borrow_mut();
+        if !s.socket_dir {
+            set_socket_dir()?;
+            s.socket_dir = true;
+        }
+        Ok(())
+    })?;
+
+    let c_name = name.clone().into_cstring();
+    let c_bdev = bdev_name.to_string().into_cstring();
+    let c_cpumask = cpumask.map(|m| m.to_string().into_cstring());
+    let rc = unsafe {
+        spdk_vhost_blk_construct(
+            c_name.as_ptr(),
+            c_cpumask
+                .as_ref()
+                .map_or(ptr::null(), |m| m.as_ptr() as *const c_char),
+            c_bdev.as_ptr(),
+            read_only,
+            false, // packed_ring
+        )
+    };
+    if rc != 0 {
+        return Err(Error::CreateController {
+            source: Errno::from_i32(rc.abs()),
+            name,
+            bdev: bdev_name.to_string(),
+        });
+    }
+
+    let ctrlr = VhostController {
+        socket: Path::new(VHOST_SOCKET_DIR)
+            .join(&name)
+            .to_string_lossy()
+            .to_string(),
+        name: name.clone(),
+        bdev: bdev_name.to_string(),
+        cpumask: cpumask.unwrap_or_default().to_string(),
+        read_only,
+    };
+    VHOST.with(|s| s.borrow_mut().controllers.insert(name, ctrlr.clone()));
+    info!(
+        "Created vhost controller '{}' for '{}' at {}",
+        ctrlr.name, bdev_name, ctrlr.socket
+    );
+    Ok(ctrlr)
+}
+
+/// Removes a vhost-user-blk controller, which fails while a guest is
+/// connected to it.
+pub fn remove(name: &str) -> Result<(), Error> {
+    if controller(name).is_none() {
+        return Err(Error::ControllerNotFound {
+            name: name.to_string(),
+        });
+    }
+
+    let c_name = name.to_string().into_cstring();
+    let rc: c_int = unsafe {
+        spdk_vhost_lock();
+        let vdev = spdk_vhost_dev_find(c_name.as_ptr());
+        let rc = if vdev.is_null() {
+            -(Errno::ENODEV as c_int)
+        } else {
+            spdk_vhost_dev_remove(vdev)
+        };
+        spdk_vhost_unlock();
+        rc
+    };
+    // a controller removed behind our back is forgotten
+    if rc != 0 && rc != -(Errno::ENODEV as c_int) {
+        return Err(Error::RemoveController {
+            source: Errno::from_i32(rc.abs()),
+            name: name.to_string(),
+        });
+    }
+
+    VHOST.with(|s| s.borrow_mut().controllers.remove(name));
+    info!("Removed vhost controller '{}'", name);
+    Ok(())
+}
+
+/// Removes the vhost-user-blk controllers of the given bdev.
+pub fn remove_bdev(bdev_name: &str) -> Result<(), Error> {
+    bdev_controllers(bdev_name)
+        .iter()
+        .try_for_each(|c| remove(&c.name))
+}
+
+/// Returns the controller of the given name.
+pub fn controller(name: &str) -> Option<VhostController> {
+    VHOST.with(|s| s.borrow().controllers.get(name).cloned())
+}
+
+/// Returns the controllers of the given bdev.
+pub fn bdev_controllers(bdev_name: &str) -> Vec<VhostController> {
+    VHOST.with(|s| {
+        s.borrow()
+            .controllers
+            .values()
+            .filter(|c| c.bdev == bdev_name)
+            .cloned()
+            .collect()
+    })
+}
+
+/// Returns all the controllers.
+pub fn controllers() -> Vec<VhostController> {
+    VHOST.with(|s| s.borrow().controllers.values().cloned().collect())
+}
@@ -0,0 +1,243 @@
+//! Tenants of the io-engine and their quotas.
+//!
+//! When several control planes share a node, each can keep its resources in
+//! a tenant namespace of its own, by tagging them with the tenant label. A
+//! tenant can be given quotas: the capacity of its replicas, the number of
+//! its replicas and the number of its shared targets, replicas or nexuses.
+//! The quotas are enforced as the resources are tagged into the tenant and
+//! as they are shared: a replica which would take its tenant beyond its
+//! quotas is not tagged, and a target is not shared.
+//!
+//! Resources which are not tagged belong to no tenant and are not limited.
+//! The quotas are kept until the io-engine restarts.
+
+use std::collections::HashMap;
+
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bdev::nexus::nexus_iter,
+    core::Share,
+    jsonrpc::{jsonrpc_register, Code, JsonRpcError},
+    labels::{validate_labels, Labels},
+    lvs::Lvs,
+};
+
+/// Label tagging a resource into a tenant namespace.
+pub const TENANT_LABEL: &str = "openebs.io/tenant";
+
+/// Quotas of the tenants which have been given one.
+static QUOTAS: Lazy<Mutex<HashMap<String, TenantQuota>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Quotas of a tenant, a quota which is not set is unbounded.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct TenantQuota {
+    /// Capacity of the replicas, in bytes.
+    pub capacity: Option<u64>,
+    /// Number of replicas.
+    pub replicas: Option<u64>,
+    /// Number of shared replicas and nexuses.
+    pub shared_targets: Option<u64>,
+}
+
+impl TenantQuota {
+    /// Returns the quotas of the given tenant.
+    pub fn get(tenant: &str) -> Self {
+        QUOTAS.lock().get(tenant).cloned().unwrap_or_default()
+    }
+
+    /// Set the quotas of the given tenant, unbounded quotas remove them.
+    pub fn set(tenant: &str, quota: Self) {
+        info!("Setting quota of tenant {}: {:?}", tenant, quota);
+        if quota == Self::default() {
+            QUOTAS.lock().remove(tenant);
+        } else {
+            QUOTAS.lock().insert(tenant.to_string(), quota);
+        }
+    }
+}
+
+/// Usage of the resources of a tenant.
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct TenantUsage {
+    /// Capacity of the replicas, in bytes.
+    pub capacity: u64,
+    pub replicas: u64,
+    pub shared_targets: u64,
+}
+
+impl TenantUsage {
+    /// Usage of a replica of the given capacity.
+    pub fn replica(capacity: u64, shared: bool) -> Self {
+        Self {
+            capacity,
+            replicas: 1,
+            shared_targets: shared as u64,
+        }
+    }
+
+    /// Usage of a shared target.
+    pub fn shared_target() -> Self {
+        Self {
+            shared_targets: 1,
+            ..Default::default()
+        }
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.capacity += other.capacity;
+        self.replicas += other.replicas;
+        self.shared_targets += other.shared_targets;
+    }
+}
+
+/// Returns the tenant the given labels tag a resource into.
+pub fn tenant_of(labels: &Labels) -> Option<&str> {
+    labels
+        .get(TENANT_LABEL)
+        .map(String::as_str)
+        .filter(|t| !t.is_empty())
+}
+
+/// Returns the usage of the resources of the given tenant.
+pub async fn tenant_usage(tenant: &str) -> TenantUsage {
+    let mut usage = TenantUsage::default();
+
+    let lvols = Lvs::iter()
+        .filter_map(|lvs| lvs.lvols())
+        .flatten()
+        .filter(|l| !l.is_snapshot())
+        .collect::<Vec<_>>();
+    for lvol in lvols {
+        if tenant_of(&lvol.labels().await) == Some(tenant) {
+            usage.add(&TenantUsage::replica(
+                lvol.size(),
+                lvol.shared().is_some(),
+            ));
+        }
+    }
+
+    for nexus in nexus_iter() {
+        if tenant_of(&nexus.labels()) == Some(tenant)
+            && nexus.shared().is_some()
+        {
+            usage.add(&TenantUsage::shared_target());
+        }
+    }
+    usage
+}
+
+/// Check that the given usage can be added to the resources of the given
+/// tenant, within its quotas.
+pub async fn check_quota(
+    tenant: &str,
+    added: &TenantUsage,
+) -> Result<(), String> {
+    let quota = TenantQuota::get(tenant);
+    if quota == TenantQuota::default() {
+        return Ok(());
+    }
+
+    let mut usage = tenant_usage(tenant).await;
+    usage.add(added);
+
+    let exceeded = |what: &str, used: u64, limit: Option<u64>| match limit {
+        Some(limit) if used > limit => Err(format!(
+            "tenant {} would have {} {}, above its quota of {}",
+            tenant, used, what, limit
+        )),
+        _ => Ok(()),
+    };
+    if added.capacity > 0 {
+        exceeded("bytes of replicas", usage.capacity, quota.capacity)?;
+    }
+    if added.replicas > 0 {
+        exceeded("replicas", usage.replicas, quota.replicas)?;
+    }
+    if added.shared_targets > 0 {
+        exceeded("shared targets", usage.shared_targets, quota.shared_targets)?;
+    }
+    Ok(())
+}
+
+/// Quotas and usage of a tenant.
+#[derive(Serialize, Debug)]
+struct TenantStatus {
+    tenant: String,
+    quota: TenantQuota,
+    usage: TenantUsage,
+}
+
+impl TenantStatus {
+    async fn new(tenant: String) -> Self {
+        Self {
+            quota: TenantQuota::get(&tenant),
+            usage: tenant_usage(&tenant).await,
+            tenant,
+        }
+    }
+}
+
+/// Arguments of the `tenant_set_quota` json-rpc method.
+#[derive(Deserialize)]
+struct TenantSetQuotaArgs {
+    tenant: String,
+    #[serde(flatten)]
+    quota: TenantQuota,
+}
+
+/// Arguments of the `tenant_get` json-rpc method.
+#[derive(Deserialize)]
+struct TenantGetArgs {
+    tenant: String,
+}
+
+/// Check that the given tenant name can be the value of the tenant label.
+fn validate_tenant(tenant: &str) -> Result<(), JsonRpcError> {
+    let labels =
+        std::iter::once((TENANT_LABEL.to_string(), tenant.to_string()))
+            .collect::<Labels>();
+    match validate_labels(&labels) {
+        Ok(()) if !tenant.is_empty() => Ok(()),
+        Ok(()) => Err(JsonRpcError::new(Code::InvalidParams, "empty tenant")),
+        Err(e) => Err(JsonRpcError::new(Code::InvalidParams, e)),
+    }
+}
+
+/// Register the tenant json-rpc methods.
+pub(crate) fn register_rpc_methods() {
+    jsonrpc_register("tenant_set_quota", |args: TenantSetQuotaArgs| {
+        async move {
+            validate_tenant(&args.tenant)?;
+            TenantQuota::set(&args.tenant, args.quota);
+            Ok(TenantStatus::new(args.tenant).await)
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register("tenant_get", |args: TenantGetArgs| {
+        async move {
+            validate_tenant(&args.tenant)?;
+            Ok(TenantStatus::new(args.tenant).await)
+        }
+        .boxed_local()
+    });
+
+    jsonrpc_register::<(), _, _, JsonRpcError>("tenant_list", |_| {
+        async move {
+            let tenants = QUOTAS.lock().keys().cloned().collect::<Vec<_>>();
+            let mut list = Vec::new();
+            for tenant in tenants {
+                list.push(TenantStatus::new(tenant).await);
+            }
+            list.sort_by(|a, b| a.tenant.cmp(&b.tenant));
+            Ok(list)
+        }
+        .boxed_local()
+    });
+}
@@ -0,0 +1,294 @@
+use std::{
+    collections::HashMap,
+    convert::{Infallible, TryFrom},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body,
+    Request,
+    Response,
+    Server,
+    StatusCode,
+};
+
+use io_engine::{
+    backup::{
+        lookup_job,
+        restore,
+        resume,
+        BackupError,
+        BackupJob,
+        BackupRestoreArgs,
+        BackupResumeArgs,
+        JobState,
+        S3Config,
+    },
+    core::{MayastorCliArgs, UntypedBdev, UntypedBdevHandle},
+    lvs::{Lvol, Lvs},
+    pool_backend::PoolArgs,
+};
+
+pub mod common;
+
+static POOL_NAME: &str = "restore_pool";
+static POOL_DISK: &str = "malloc:///restore?size_mb=64";
+static BUCKET: &str = "backups";
+static BACKUP: &str = "backup-1";
+static BACKUP_SIZE: u64 = 8 * 1024 * 1024;
+static DATA_LEN: u64 = 64 * 1024;
+static FILL: u8 = 0xa5;
+
+/// Object store serving a single backup, whose data object can be made to
+/// fail.
+struct FakeS3 {
+    objects: HashMap<String, Vec<u8>>,
+    fail_data: AtomicBool,
+}
+
+impl FakeS3 {
+    fn new() -> Arc<Self> {
+        let manifest = serde_json::json!({
+            "version": 1,
+            "id": BACKUP,
+            "parent": null,
+            "snapshot": "snapshot-1",
+            "snapshot_uuid": "9b2f7f39-7d3e-4a5e-9c43-3f2b0c0e0001",
+            "size": BACKUP_SIZE,
+            "extents": [{ "offset": 1024 * 1024, "len": DATA_LEN }],
+            "created": "2022-01-01T00:00:00Z"
+        });
+        let mut objects = HashMap::new();
+        objects.insert(
+            format!("/{}/{}/manifest.json", BUCKET, BACKUP),
+            serde_json::to_vec(&manifest).unwrap(),
+        );
+        objects.insert(
+            format!("/{}/{}/data", BUCKET, BACKUP),
+            vec![FILL; DATA_LEN as usize],
+        );
+        Arc::new(Self {
+            objects,
+            fail_data: AtomicBool::new(false),
+        })
+    }
+
+    fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let path = req.uri().path();
+        let failed =
+            path.ends_with("/data") && self.fail_data.load(Ordering::SeqCst);
+        let object = match self.objects.get(path) {
+            Some(object) if !failed => object,
+            _ => {
+                return Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+        };
+
+        let range = req
+            .headers()
+            .get(hyper::header::RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("bytes="))
+            .and_then(|v| {
+                let mut bounds = v.split('-').map(|b| b.parse::<usize>());
+                match (bounds.next(), bounds.next()) {
+                    (Some(Ok(start)), Some(Ok(end))) => Some((start, end)),
+                    _ => None,
+                }
+            });
+        let body = match range {
+            Some((start, end)) => object[start ..= end].to_vec(),
+            None => object.clone(),
+        };
+        Response::new(Body::from(body))
+    }
+
+    /// Serve the objects on a loopback port.
+    fn serve(self: Arc<Self>) -> SocketAddr {
+        let service = make_service_fn(move |_| {
+            let s3 = self.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let reply = s3.handle(req);
+                    async move { Ok::<_, Infallible>(reply) }
+                }))
+            }
+        });
+        let server =
+            Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+        address
+    }
+}
+
+fn s3_config(address: SocketAddr) -> S3Config {
+    S3Config {
+        endpoint: format!("http://{}", address),
+        insecure: false,
+        region: "us-east-1".to_string(),
+        bucket: BUCKET.to_string(),
+        prefix: String::new(),
+        access_key_id: "access".to_string(),
+        secret_access_key: "secret".to_string(),
+    }
+}
+
+fn restore_args(
+    id: &str,
+    name: &str,
+    address: SocketAddr,
+) -> BackupRestoreArgs {
+    BackupRestoreArgs {
+        id: id.to_string(),
+        backup: BACKUP.to_string(),
+        pool: POOL_NAME.to_string(),
+        name: name.to_string(),
+        uuid: None,
+        s3: s3_config(address),
+    }
+}
+
+/// Wait for the given job to stop running.
+async fn wait_job(
+    ms: &common::MayastorTest<'_>,
+    id: &'static str,
+) -> BackupJob {
+    loop {
+        let job = ms.spawn(async move { lookup_job(id).await.unwrap() }).await;
+        if job.state != JobState::Running {
+            return job;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Check whether the given replica holds the restored data.
+async fn restored(name: &str) -> bool {
+    let hdl = UntypedBdevHandle::open(name, false, false).unwrap();
+    let mut buf = hdl.dma_malloc(DATA_LEN).unwrap();
+    hdl.read_at(1024 * 1024, &mut buf).await.unwrap();
+    buf.as_slice().iter().all(|b| *b == FILL)
+}
+
+fn replica_uuid(name: &str) -> String {
+    Lvol::try_from(UntypedBdev::lookup_by_name(name).unwrap())
+        .unwrap()
+        .uuid()
+}
+
+/// A backup is not restored into an existing replica, and a resumed restore
+/// job only writes to the replica it has created itself.
+#[tokio::test]
+async fn backup_restore_replica() {
+    let s3 = FakeS3::new();
+    let address = s3.clone().serve();
+
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+    ms.spawn(async move {
+        let pool = Lvs::create_or_import(PoolArgs {
+            name: POOL_NAME.into(),
+            disks: vec![POOL_DISK.into()],
+            uuid: None,
+        })
+        .await
+        .unwrap();
+        pool.create_lvol("existing", BACKUP_SIZE, None, true)
+            .await
+            .unwrap();
+
+        let error = restore(restore_args("job-0", "existing", address))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, BackupError::ReplicaExists { .. }));
+        assert!(lookup_job("job-0").await.is_err());
+        assert!(!restored("existing").await);
+
+        restore(restore_args("job-1", "restored-1", address))
+            .await
+            .unwrap();
+    })
+    .await;
+
+    let job = wait_job(&ms, "job-1").await;
+    assert_eq!(job.state, JobState::Completed);
+    assert_eq!(job.done_bytes, DATA_LEN);
+    ms.spawn(async {
+        assert_eq!(
+            lookup_job("job-1").await.unwrap().created,
+            Some(replica_uuid("restored-1"))
+        );
+        assert!(restored("restored-1").await);
+    })
+    .await;
+
+    // the job fails once it has created its replica, and is resumed into it
+    s3.fail_data.store(true, Ordering::SeqCst);
+    ms.spawn(async move {
+        restore(restore_args("job-2", "restored-2", address))
+            .await
+            .unwrap();
+    })
+    .await;
+    assert_eq!(wait_job(&ms, "job-2").await.state, JobState::Failed);
+
+    s3.fail_data.store(false, Ordering::SeqCst);
+    ms.spawn(async move {
+        resume(BackupResumeArgs {
+            id: "job-2".to_string(),
+            s3: s3_config(address),
+        })
+        .await
+        .unwrap();
+    })
+    .await;
+    assert_eq!(wait_job(&ms, "job-2").await.state, JobState::Completed);
+    ms.spawn(async { assert!(restored("restored-2").await) })
+        .await;
+
+    // the replica of the failed job is replaced by another one, which the
+    // resumed job does not write to
+    s3.fail_data.store(true, Ordering::SeqCst);
+    ms.spawn(async move {
+        restore(restore_args("job-3", "restored-3", address))
+            .await
+            .unwrap();
+    })
+    .await;
+    assert_eq!(wait_job(&ms, "job-3").await.state, JobState::Failed);
+
+    s3.fail_data.store(false, Ordering::SeqCst);
+    ms.spawn(async move {
+        let lvol =
+            Lvol::try_from(UntypedBdev::lookup_by_name("restored-3").unwrap())
+                .unwrap();
+        lvol.destroy().await.unwrap();
+        Lvs::lookup(POOL_NAME)
+            .unwrap()
+            .create_lvol("restored-3", BACKUP_SIZE, None, true)
+            .await
+            .unwrap();
+
+        resume(BackupResumeArgs {
+            id: "job-3".to_string(),
+            s3: s3_config(address),
+        })
+        .await
+        .unwrap();
+    })
+    .await;
+    let job = wait_job(&ms, "job-3").await;
+    assert_eq!(job.state, JobState::Failed);
+    assert!(job.error.unwrap().contains("already exists"));
+    ms.spawn(async { assert!(!restored("restored-3").await) })
+        .await;
+}
@@ -0,0 +1,29 @@
+use common::MayastorTest;
+use io_engine::{
+    bdev_api::bdev_create,
+    core::{open_descriptors, MayastorCliArgs, UntypedBdev},
+};
+pub mod common;
+
+#[tokio::test]
+async fn bdev_descriptor_registry() {
+    let ms = MayastorTest::new(MayastorCliArgs::default());
+    ms.spawn(async {
+        bdev_create("malloc:///desc0?blk_size=512&size_mb=8")
+            .await
+            .unwrap();
+
+        let d0 = UntypedBdev::open_by_name("desc0", false).unwrap();
+        let d1 = UntypedBdev::open_by_name("desc0", true).unwrap();
+        let open = open_descriptors(Some("desc0"));
+        assert_eq!(open.len(), 2);
+        assert!(open.iter().all(|d| d.thread_id == open[0].thread_id));
+
+        // an explicit close and a drop both leave the registry
+        d0.close().unwrap();
+        assert_eq!(open_descriptors(Some("desc0")).len(), 1);
+        drop(d1);
+        assert!(open_descriptors(Some("desc0")).is_empty());
+    })
+    .await;
+}
@@ -0,0 +1,56 @@
+use common::MayastorTest;
+use io_engine::{
+    bdev_api::{
+        bdev_create_typed,
+        bdev_destroy_by_name,
+        BdevError,
+        BdevSpec,
+        MemoryBdevOptions,
+    },
+    core::{MayastorCliArgs, UntypedBdev},
+};
+pub mod common;
+
+fn malloc(size_mb: Option<u32>, num_blocks: Option<u32>) -> BdevSpec {
+    BdevSpec::Malloc(MemoryBdevOptions {
+        name: "typed0".to_string(),
+        size_mb,
+        num_blocks,
+        blk_size: Some(4096),
+        uuid: None,
+    })
+}
+
+#[tokio::test]
+async fn bdev_typed() {
+    let ms = MayastorTest::new(MayastorCliArgs::default());
+    ms.spawn(async {
+        assert_eq!(
+            malloc(Some(64), None).uri().unwrap(),
+            "malloc:///typed0?size_mb=64&blk_size=4096"
+        );
+        assert!(matches!(
+            malloc(Some(64), Some(100)).uri(),
+            Err(BdevError::InvalidOptions { .. })
+        ));
+
+        let name = bdev_create_typed(&malloc(Some(64), None)).await.unwrap();
+        assert_eq!(name, "typed0");
+        let bdev = UntypedBdev::lookup_by_name(&name).unwrap();
+        assert_eq!(bdev.block_len(), 4096);
+        assert_eq!(bdev.num_blocks(), (64 << 20) / 4096);
+
+        assert!(matches!(
+            bdev_create_typed(&malloc(Some(64), None)).await,
+            Err(BdevError::BdevExists { .. })
+        ));
+
+        bdev_destroy_by_name(&name).await.unwrap();
+        assert!(UntypedBdev::lookup_by_name(&name).is_none());
+        assert!(matches!(
+            bdev_destroy_by_name(&name).await,
+            Err(BdevError::BdevNotFound { .. })
+        ));
+    })
+    .await;
+}
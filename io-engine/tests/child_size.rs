@@ -99,3 +99,28 @@ async fn too_small_for_metadata() {
         })
         .await;
 }
+
+#[tokio::test]
+async fn children_too_small_reported() {
+    mayastor()
+        .spawn(async {
+            let children = [16, 8, 4]
+                .iter()
+                .enumerate()
+                .map(|(i, size)| format!("malloc:///m{}?size_mb={}", i, size))
+                .collect::<Vec<_>>();
+            let error =
+                nexus_create("core_nexus", 16 * 1024 * 1024, None, &children)
+                    .await
+                    .unwrap_err()
+                    .to_string();
+
+            // every child which is too small is reported, not just the first
+            assert!(!error.contains("malloc:///m0"));
+            assert!(error.contains("malloc:///m1"));
+            assert!(error.contains("malloc:///m2"));
+            assert!(nexus_lookup_mut("core_nexus").is_none());
+            assert_eq!(UntypedBdev::bdev_first().into_iter().count(), 0);
+        })
+        .await;
+}
@@ -1,5 +1,5 @@
 use io_engine::{
-    bdev::nexus::{nexus_create, nexus_lookup_mut, Reason},
+    bdev::nexus::{nexus_create, nexus_lookup_mut, FaultDetail, Reason},
     core::MayastorCliArgs,
 };
 
@@ -32,6 +32,24 @@ async fn fault_child() {
             .fault_child(CHILD_2, Reason::Unknown)
             .await
             .is_ok());
+        // the detail of the fault is kept while the child is faulted
+        assert_eq!(
+            nexus.lookup_child(CHILD_2).unwrap().fault_detail(),
+            Some(FaultDetail::Unknown)
+        );
+        assert_eq!(nexus.lookup_child(CHILD_1).unwrap().fault_detail(), None);
+
+        // every state change is recorded in the history of the nexus, and
+        // outlives the removal of the child
+        nexus.as_mut().remove_child(CHILD_2).await.unwrap();
+        let history = nexus.history();
+        assert!(history.iter().all(|t| !t.unexpected), "{:?}", history);
+        assert!(history.iter().any(|t| t.resource == NEXUS_NAME
+            && t.from == "init"
+            && t.to == "open"));
+        assert!(history
+            .iter()
+            .any(|t| t.resource == CHILD_2 && t.to == "faulted (unknown)"));
     })
     .await;
 }
@@ -0,0 +1,120 @@
+use std::{
+    net::{TcpListener, TcpStream},
+    os::unix::io::{AsRawFd, FromRawFd},
+    pin::Pin,
+};
+
+use io_engine::{
+    bdev::nexus::{nexus_create, nexus_lookup, nexus_lookup_mut},
+    core::{MayastorCliArgs, Protocol, Share, ShareProps},
+    handoff,
+    lvs::{Lvs, PropName, PropValue},
+    pool_backend::PoolArgs,
+};
+use tokio::net::UnixStream;
+
+pub mod common;
+
+static DISKNAME: &str = "/tmp/handoff.img";
+static POOL_NAME: &str = "handoff_pool";
+static REPLICA_NAME: &str = "handoff_replica";
+static NEXUS_NAME: &str = "HandoffNexus";
+static NEXUS_SIZE: u64 = 4 * 1024 * 1024;
+static CHILD: &str = "malloc:///malloc0?blk_size=512&size_mb=20";
+static HOST: &str = "nqn.2019-05.io.openebs:handoff-host";
+
+/// The listen socket passed over the handoff socket is the one the sender
+/// listens on, and a message without descriptors carries none.
+#[tokio::test]
+async fn handoff_pass_listener() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let (sender, receiver) = UnixStream::pair().unwrap();
+
+    handoff::send_fds(&sender, &[]).await.unwrap();
+    assert!(handoff::receive_fds(&receiver).await.unwrap().is_empty());
+
+    handoff::send_fds(&sender, &[listener.as_raw_fd()])
+        .await
+        .unwrap();
+    let fds = handoff::receive_fds(&receiver).await.unwrap();
+    assert_eq!(fds.len(), 1);
+    drop(listener);
+
+    let passed = unsafe { TcpListener::from_raw_fd(fds[0]) };
+    assert_eq!(passed.local_addr().unwrap(), address);
+    let _client = TcpStream::connect(address).unwrap();
+    passed.accept().unwrap();
+
+    drop(sender);
+    assert!(handoff::receive_fds(&receiver).await.is_err());
+}
+
+/// The replicas which were shared over NVMf are shared again once the state
+/// is restored, whether or not their pool records them as shared.
+#[tokio::test]
+async fn handoff_release_restore() {
+    common::delete_file(&[DISKNAME.into()]);
+    common::truncate_file(DISKNAME, 64 * 1024);
+
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+    ms.spawn(async {
+        let pool = Lvs::create_or_import(PoolArgs {
+            name: POOL_NAME.into(),
+            disks: vec![format!("aio://{}", DISKNAME)],
+            uuid: None,
+        })
+        .await
+        .unwrap();
+        let mut lvol = pool
+            .create_lvol(REPLICA_NAME, 8 * 1024 * 1024, None, false)
+            .await
+            .unwrap();
+        let mut lvol = Pin::new(&mut lvol);
+        lvol.as_mut()
+            .share_nvmf(Some(
+                ShareProps::new().with_allowed_hosts(vec![HOST.to_string()]),
+            ))
+            .await
+            .unwrap();
+        // the pool import does not share the replica on its own
+        lvol.as_mut().set(PropValue::Shared(false)).await.unwrap();
+
+        nexus_create(NEXUS_NAME, NEXUS_SIZE, None, &[CHILD.to_string()])
+            .await
+            .unwrap();
+        nexus_lookup_mut(NEXUS_NAME)
+            .unwrap()
+            .share(Protocol::Nvmf, None)
+            .await
+            .unwrap();
+
+        let state = handoff::capture().await;
+        assert_eq!(state.replicas.len(), 1);
+        assert_eq!(state.replicas[0].allowed_hosts, vec![HOST.to_string()]);
+
+        handoff::release(&state).await.unwrap();
+        assert!(Lvs::lookup(POOL_NAME).is_none());
+        assert!(nexus_lookup(NEXUS_NAME).is_none());
+
+        handoff::restore(&state, None).await.unwrap();
+        let lvol = Lvs::lookup(POOL_NAME)
+            .unwrap()
+            .lvols()
+            .unwrap()
+            .find(|l| l.name() == REPLICA_NAME)
+            .unwrap();
+        assert_eq!(lvol.shared(), Some(Protocol::Nvmf));
+        assert_eq!(lvol.allowed_hosts(), vec![HOST.to_string()]);
+        assert_eq!(
+            lvol.get(PropName::Shared).await.unwrap(),
+            PropValue::Shared(true)
+        );
+        assert_eq!(
+            nexus_lookup(NEXUS_NAME).unwrap().shared(),
+            Some(Protocol::Nvmf)
+        );
+    })
+    .await;
+    common::delete_file(&[DISKNAME.into()]);
+}
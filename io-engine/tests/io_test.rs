@@ -0,0 +1,128 @@
+use std::time::Duration;
+
+use io_engine::{
+    bdev::device_open,
+    bdev_api::bdev_create,
+    core::MayastorCliArgs,
+    io_test::{
+        io_test_status,
+        remove_io_test,
+        start_io_test,
+        IoTestPattern,
+        IoTestSpec,
+        IoTestState,
+    },
+};
+
+pub mod common;
+
+#[tokio::test]
+async fn io_test() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+
+    ms.spawn(async {
+        let name = bdev_create("malloc:///io_test0?size_mb=64").await.unwrap();
+
+        start_io_test(IoTestSpec {
+            name: "verify".to_string(),
+            device: name,
+            pattern: IoTestPattern::RandWrite,
+            io_size: 4096,
+            queue_depth: 8,
+            runtime_secs: 1,
+            read_percent: 0,
+            verify: true,
+            core: None,
+        })
+        .unwrap();
+
+        // a test of the same name cannot run twice
+        assert!(start_io_test(IoTestSpec {
+            name: "verify".to_string(),
+            device: "io_test0".to_string(),
+            pattern: IoTestPattern::Read,
+            io_size: 4096,
+            queue_depth: 1,
+            runtime_secs: 1,
+            read_percent: 0,
+            verify: false,
+            core: None,
+        })
+        .is_err());
+    })
+    .await;
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    ms.spawn(async {
+        let status = io_test_status("verify").unwrap();
+        assert_eq!(status.state, IoTestState::Completed, "{:?}", status);
+        assert!(status.stats.writes > 0);
+        assert_eq!(status.stats.verified_blocks, status.stats.writes * 8);
+        assert_eq!(status.stats.mismatched_blocks, 0);
+        remove_io_test("verify").unwrap();
+        assert!(io_test_status("verify").is_none());
+
+        // reads check the stamps of the blocks written by the previous test
+        start_io_test(IoTestSpec {
+            name: "read".to_string(),
+            device: "io_test0".to_string(),
+            pattern: IoTestPattern::RandRead,
+            io_size: 4096,
+            queue_depth: 8,
+            runtime_secs: 1,
+            read_percent: 0,
+            verify: true,
+            core: None,
+        })
+        .unwrap();
+    })
+    .await;
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    ms.spawn(async {
+        let status = io_test_status("read").unwrap();
+        assert_eq!(status.state, IoTestState::Completed, "{:?}", status);
+        assert!(status.stats.verified_blocks > 0);
+        assert_eq!(
+            status.stats.verified_blocks + status.stats.unwritten_blocks,
+            status.stats.reads * 8
+        );
+        remove_io_test("read").unwrap();
+
+        // a block overwritten behind the back of the test is reported
+        let hdl = device_open("io_test0", true)
+            .unwrap()
+            .into_handle()
+            .unwrap();
+        let mut buf = hdl.dma_malloc(512).unwrap();
+        buf.fill(0xff);
+        hdl.write_at(9 * 512, &buf).await.unwrap();
+
+        start_io_test(IoTestSpec {
+            name: "corrupt".to_string(),
+            device: "io_test0".to_string(),
+            pattern: IoTestPattern::Read,
+            io_size: 4096,
+            queue_depth: 1,
+            runtime_secs: 1,
+            read_percent: 0,
+            verify: true,
+            core: None,
+        })
+        .unwrap();
+    })
+    .await;
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    ms.spawn(async {
+        let status = io_test_status("corrupt").unwrap();
+        assert_eq!(status.state, IoTestState::Failed, "{:?}", status);
+        assert_eq!(status.stats.mismatched_blocks, 1);
+        assert_eq!(status.mismatches[0].lba, 9);
+        remove_io_test("corrupt").unwrap();
+    })
+    .await;
+}
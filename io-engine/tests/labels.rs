@@ -0,0 +1,86 @@
+use std::{collections::BTreeMap, pin::Pin};
+
+use io_engine::{
+    core::MayastorCliArgs,
+    labels::{merge_labels, validate_labels, LabelSelector, Labels},
+    lvs::Lvs,
+    pool_backend::PoolArgs,
+};
+
+pub mod common;
+
+static POOL_DISK: &str = "malloc:///labels?size_mb=64";
+
+fn labels(pairs: &[(&str, &str)]) -> Labels {
+    pairs
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+#[test]
+fn label_selectors() {
+    let tenant = labels(&[("tenant", "blue"), ("example.com/owner", "ci")]);
+
+    for (selector, matches) in [
+        ("", true),
+        ("tenant=blue", true),
+        ("tenant==blue", true),
+        ("tenant!=blue", false),
+        ("tenant=red", false),
+        ("example.com/owner", true),
+        ("!example.com/owner", false),
+        ("tenant=blue, !purpose", true),
+        ("tenant=blue,purpose", false),
+    ] {
+        let selector = selector.parse::<LabelSelector>().unwrap();
+        assert_eq!(selector.matches(&tenant), matches, "{:?}", selector);
+    }
+    assert!("tenant=-blue".parse::<LabelSelector>().is_err());
+
+    assert!(validate_labels(&tenant).is_ok());
+    assert!(validate_labels(&labels(&[("", "blue")])).is_err());
+    assert!(validate_labels(&labels(&[("tenant", "blue sky")])).is_err());
+    assert!(validate_labels(&labels(&[("/tenant", "blue")])).is_err());
+}
+
+#[tokio::test]
+async fn replica_and_pool_labels() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+
+    ms.spawn(async {
+        let pool = Lvs::create_or_import(PoolArgs {
+            name: "labels".into(),
+            disks: vec![POOL_DISK.into()],
+            uuid: None,
+        })
+        .await
+        .unwrap();
+        assert!(pool.labels().await.is_empty());
+        let tenant = labels(&[("tenant", "blue")]);
+        pool.set_labels(&tenant).await.unwrap();
+        assert_eq!(pool.labels().await, tenant);
+
+        let mut lvol = pool
+            .create_lvol("replica", 8 * 1024 * 1024, None, true)
+            .await
+            .unwrap();
+        assert!(lvol.labels().await.is_empty());
+
+        let mut changes = BTreeMap::new();
+        changes.insert("tenant".to_string(), Some("blue".to_string()));
+        changes.insert("purpose".to_string(), Some("backup".to_string()));
+        let labelled = merge_labels(&lvol.labels().await, &changes);
+        Pin::new(&mut lvol).set_labels(&labelled).await.unwrap();
+        assert_eq!(lvol.labels().await, labelled);
+
+        changes.insert("purpose".to_string(), None);
+        let labelled = merge_labels(&lvol.labels().await, &changes);
+        assert_eq!(labelled, tenant);
+        Pin::new(&mut lvol).set_labels(&labelled).await.unwrap();
+        assert_eq!(lvol.labels().await, tenant);
+
+        pool.destroy().await.unwrap();
+    })
+    .await;
+}
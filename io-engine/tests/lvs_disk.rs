@@ -0,0 +1,54 @@
+use io_engine::{
+    bdev_api::bdev_create,
+    core::{MayastorCliArgs, UntypedBdevHandle},
+    lvs::{probe_signatures, Error, Lvs, PoolDiskOptions},
+    pool_backend::PoolArgs,
+};
+
+pub mod common;
+
+static DISK: &str = "malloc:///disk0?size_mb=64";
+
+/// Create the disk with a xfs signature on it.
+async fn create_xfs_disk() {
+    bdev_create(DISK).await.unwrap();
+    let hdl = UntypedBdevHandle::open("disk0", true, false).unwrap();
+    let mut buf = hdl.dma_malloc(4096).unwrap();
+    buf.as_mut_slice()[.. 4].copy_from_slice(b"XFSB");
+    hdl.write_at(0, &buf).await.unwrap();
+}
+
+#[tokio::test]
+async fn lvs_disk_signatures() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+
+    ms.spawn(async {
+        create_xfs_disk().await;
+        let signatures = probe_signatures("disk0").await.unwrap();
+        assert_eq!(signatures.len(), 1);
+        assert_eq!(signatures[0].kind, "xfs");
+
+        let args = PoolArgs {
+            name: "disk".into(),
+            disks: vec![DISK.into()],
+            uuid: None,
+        };
+        let error = Lvs::create_or_import(args.clone()).await.unwrap_err();
+        assert!(matches!(error, Error::DiskSignatures { .. }));
+
+        // the disk is released on failure, forcing the creation wipes the
+        // signature
+        create_xfs_disk().await;
+        let pool = Lvs::create_or_import_ext(
+            args,
+            PoolDiskOptions {
+                force: true,
+                label: false,
+            },
+        )
+        .await
+        .unwrap();
+        pool.destroy().await.unwrap();
+    })
+    .await;
+}
@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+use io_engine::{
+    core::MayastorCliArgs,
+    lvs::{
+        destroy_replica_erased,
+        erase_job,
+        EraseError,
+        EraseMethod,
+        EraseState,
+        EraseTarget,
+        Lvs,
+    },
+    pool_backend::PoolArgs,
+};
+
+pub mod common;
+
+static POOL_DISK: &str = "malloc:///erase?size_mb=64";
+
+#[tokio::test]
+async fn lvs_erase_replica() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+
+    ms.spawn(async {
+        let pool = Lvs::create_or_import(PoolArgs {
+            name: "erase".into(),
+            disks: vec![POOL_DISK.into()],
+            uuid: None,
+        })
+        .await
+        .unwrap();
+        pool.create_lvol("replica", 8 * 1024 * 1024, None, false)
+            .await
+            .unwrap();
+        pool.create_lvol("other", 8 * 1024 * 1024, None, true)
+            .await
+            .unwrap()
+            .snapshot("snapshot")
+            .await
+            .unwrap();
+
+        // snapshots are not erased on their own
+        let error = destroy_replica_erased("snapshot", EraseMethod::Zero)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, EraseError::ReplicaSnapshot { .. }));
+
+        let job = destroy_replica_erased("replica", EraseMethod::Zero)
+            .await
+            .unwrap();
+        assert_eq!(job.state, EraseState::Running);
+        assert_eq!(job.total_bytes, 8 * 1024 * 1024);
+    })
+    .await;
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    ms.spawn(async {
+        let job = erase_job(EraseTarget::Replica, "replica").unwrap();
+        assert_eq!(job.state, EraseState::Completed);
+        assert_eq!(job.done_bytes, job.total_bytes);
+        assert_eq!(job.applied, Some(EraseMethod::Zero));
+
+        let pool = Lvs::lookup("erase").unwrap();
+        assert!(pool.lvols().unwrap().all(|l| l.name() != "replica"));
+        pool.destroy().await.unwrap();
+    })
+    .await;
+}
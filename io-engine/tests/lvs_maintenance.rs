@@ -0,0 +1,51 @@
+use io_engine::{
+    core::MayastorCliArgs,
+    lvs::{
+        end_maintenance,
+        in_maintenance,
+        start_maintenance,
+        Error as LvsError,
+        Lvs,
+        MIGRATE_LABEL,
+    },
+    pool_backend::PoolArgs,
+};
+
+pub mod common;
+
+static POOL_DISK: &str = "malloc:///maintenance?size_mb=64";
+static MB: u64 = 1024 * 1024;
+
+#[tokio::test]
+async fn lvs_maintenance() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+
+    ms.spawn(async {
+        let pool = Lvs::create_or_import(PoolArgs {
+            name: "maintenance".into(),
+            disks: vec![POOL_DISK.into()],
+            uuid: None,
+        })
+        .await
+        .unwrap();
+        let lvol = pool.create_lvol("r1", 8 * MB, None, true).await.unwrap();
+
+        // the replicas are flagged for migration, and no new one is created
+        let maintenance =
+            start_maintenance(&pool, true, Some("disk retired".into())).await;
+        assert!(in_maintenance("maintenance"));
+        assert_eq!(maintenance.flagged, vec!["r1".to_string()]);
+        assert!(lvol.labels().await.contains_key(MIGRATE_LABEL));
+        let error = pool.create_lvol("r2", 8 * MB, None, true).await;
+        assert!(matches!(error, Err(LvsError::PoolMaintenance { .. })));
+
+        // until the pool leaves maintenance
+        assert!(end_maintenance(&pool).await);
+        assert!(!end_maintenance(&pool).await);
+        assert!(!lvol.labels().await.contains_key(MIGRATE_LABEL));
+        pool.create_lvol("r2", 8 * MB, None, true).await.unwrap();
+
+        pool.destroy().await.unwrap();
+    })
+    .await;
+}
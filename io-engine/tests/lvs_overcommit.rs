@@ -0,0 +1,49 @@
+use io_engine::{
+    core::MayastorCliArgs,
+    lvs::{Error as LvsError, Lvs, OvercommitPolicy},
+    pool_backend::PoolArgs,
+};
+
+pub mod common;
+
+static POOL_DISK: &str = "malloc:///overcommit?size_mb=64";
+static MB: u64 = 1024 * 1024;
+
+#[tokio::test]
+async fn lvs_overcommit() {
+    let ms = common::MayastorTest::new(MayastorCliArgs {
+        overcommit_ratio: Some(2.0),
+        ..Default::default()
+    });
+
+    ms.spawn(async {
+        let pool = Lvs::create_or_import(PoolArgs {
+            name: "overcommit".into(),
+            disks: vec![POOL_DISK.into()],
+            uuid: None,
+        })
+        .await
+        .unwrap();
+        assert_eq!(OvercommitPolicy::get("overcommit").ratio, Some(2.0));
+
+        // thin replicas may commit up to twice the capacity of the pool
+        let capacity = pool.capacity();
+        pool.create_lvol("thin1", capacity / MB * MB, None, true)
+            .await
+            .unwrap();
+        pool.create_lvol("thin2", capacity / 2 / MB * MB, None, true)
+            .await
+            .unwrap();
+
+        // but not beyond it
+        let error = pool
+            .create_lvol("thin3", capacity / 2 / MB * MB + 8 * MB, None, true)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, LvsError::Overcommit { .. }));
+        assert!(pool.committed() <= 2 * capacity);
+
+        pool.destroy().await.unwrap();
+    })
+    .await;
+}
@@ -3,7 +3,11 @@ use std::{collections::HashMap, ffi::CString, ptr::null_mut};
 use once_cell::sync::OnceCell;
 
 use common::compose::MayastorTest;
-use io_engine::core::{mempool::MemoryPool, MayastorCliArgs};
+use io_engine::core::{
+    memory_usage::memory_usage,
+    mempool::MemoryPool,
+    MayastorCliArgs,
+};
 
 pub mod common;
 
@@ -128,3 +132,33 @@ async fn test_get() {
     })
     .await;
 }
+
+#[tokio::test]
+async fn test_memory_usage() {
+    let ms = get_ms();
+
+    ms.spawn(async move {
+        let pool = MemoryPool::<u64>::create("usage_pool", 8)
+            .expect("Failed to create test memory pool");
+        let items = (0 .. 8).map(|i| pool.get(i).unwrap()).collect::<Vec<_>>();
+        assert!(pool.get(8).is_none());
+
+        let usage = memory_usage();
+        assert!(!usage.sockets.is_empty());
+        assert!(usage.subsystems.iter().any(|s| s.subsystem == "io-engine"));
+
+        let pool_usage = usage
+            .pools
+            .iter()
+            .find(|p| p.name == "usage_pool")
+            .expect("Memory pool not reported");
+        assert_eq!(pool_usage.subsystem, "io-engine");
+        assert_eq!(pool_usage.capacity, 8);
+        assert_eq!(pool_usage.in_use, 8);
+        assert_eq!(pool_usage.alloc_failures, Some(1));
+
+        items.into_iter().for_each(|item| pool.put(item));
+        drop(pool);
+    })
+    .await;
+}
@@ -0,0 +1,69 @@
+use futures::future::join_all;
+
+use io_engine::{
+    bdev::nexus::{nexus_lookup_mut, AdmissionPolicy, ChildState},
+    core::{MayastorCliArgs, UntypedBdevHandle},
+};
+
+pub mod common;
+
+use common::nexus::{create_nexus_on_nexus, set_nexus_read_only};
+
+static INNER_NAME: &str = "AdmissionInner";
+static INNER_SIZE: u64 = 10 * 1024 * 1024;
+static NEXUS_NAME: &str = "AdmissionNexus";
+static NEXUS_SIZE: u64 = 4 * 1024 * 1024;
+static CHILD: &str = "malloc:///malloc0?blk_size=512&size_mb=20";
+
+/// The writes which are not admitted are queued and submitted again as the
+/// writes of the channel complete, and a write which fails on a child which
+/// is not retired is resubmitted, and its admission released once, when it
+/// completes.
+#[tokio::test]
+async fn nexus_admission_retried_write() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+    ms.spawn(async {
+        create_nexus_on_nexus(
+            INNER_NAME, INNER_SIZE, NEXUS_NAME, NEXUS_SIZE, CHILD,
+        )
+        .await;
+
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        nexus.set_admission_policy(AdmissionPolicy {
+            enabled: true,
+            max_nexus_ios: 1,
+            max_child_ios: 4,
+        });
+
+        let hdl = UntypedBdevHandle::open(NEXUS_NAME, true, false).unwrap();
+        let mut buf = hdl.dma_malloc(4096).unwrap();
+        buf.fill(0xaa);
+        let writes = (0 .. 8).map(|i| hdl.write_at(i * 4096, &buf));
+        assert!(join_all(writes).await.iter().all(|w| w.is_ok()));
+        let stats = nexus.admission_stats();
+        assert!(stats.queued_ios > 0);
+        assert_eq!(stats.outstanding_ios, 0);
+
+        nexus.set_admission_policy(AdmissionPolicy {
+            enabled: true,
+            max_nexus_ios: 4,
+            max_child_ios: 4,
+        });
+        hdl.write_at(0, &buf).await.unwrap();
+        assert_eq!(nexus.admission_stats().outstanding_ios, 0);
+
+        // the writes of the inner nexus are failed, the write is retried
+        // once on the child and failed
+        set_nexus_read_only(INNER_NAME, true).await;
+        assert!(hdl.write_at(0, &buf).await.is_err());
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        assert_eq!(nexus.admission_stats().outstanding_ios, 0);
+        assert_eq!(nexus.child_at(0).state(), ChildState::Open);
+
+        set_nexus_read_only(INNER_NAME, false).await;
+        hdl.write_at(0, &buf).await.unwrap();
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        assert_eq!(nexus.admission_stats().outstanding_ios, 0);
+    })
+    .await;
+}
@@ -0,0 +1,163 @@
+use futures::future::join_all;
+
+use io_engine::{
+    bdev::nexus::{nexus_create, nexus_lookup_mut, ChildState},
+    core::{MayastorCliArgs, UntypedBdev, UntypedBdevHandle},
+    lvs::Lvs,
+    pool_backend::PoolArgs,
+};
+
+pub mod common;
+
+static NEXUS_SIZE: u64 = 10 * 1024 * 1024;
+static CHILD_4K_1: &str = "malloc:///malloc4k1?blk_size=4096&size_mb=12";
+static CHILD_4K_2: &str = "malloc:///malloc4k2?blk_size=4096&size_mb=12";
+static CHILD_512: &str = "malloc:///malloc512?blk_size=512&size_mb=12";
+static CHILD_512_2: &str = "malloc:///malloc512b?blk_size=512&size_mb=12";
+static CHILD_4K_3: &str = "malloc:///malloc4k3?blk_size=4096&size_mb=12";
+static POOL_DISK: &str = "malloc:///pool4k?blk_size=4096&size_mb=64";
+
+#[tokio::test]
+async fn nexus_block_size() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+
+    // a nexus with 4Kn children exposes 4096 byte blocks
+    ms.spawn(async {
+        nexus_create(
+            "nexus4k",
+            NEXUS_SIZE,
+            None,
+            &[CHILD_4K_1.to_string(), CHILD_4K_2.to_string()],
+        )
+        .await
+        .unwrap();
+        let nexus = nexus_lookup_mut("nexus4k").unwrap();
+        assert_eq!(nexus.block_len(), 4096);
+    })
+    .await;
+
+    // a child with smaller blocks can be added to it
+    ms.spawn(async {
+        let nexus = nexus_lookup_mut("nexus4k").unwrap();
+        nexus.add_child(CHILD_512, true).await.unwrap();
+        let nexus = nexus_lookup_mut("nexus4k").unwrap();
+        assert_eq!(nexus.children().len(), 3);
+        assert_eq!(nexus.block_len(), 4096);
+        nexus.destroy().await.unwrap();
+    })
+    .await;
+
+    // a nexus created from children with mixed block sizes uses the largest
+    ms.spawn(async {
+        nexus_create(
+            "nexusmixed",
+            NEXUS_SIZE,
+            None,
+            &[CHILD_512.to_string(), CHILD_4K_1.to_string()],
+        )
+        .await
+        .unwrap();
+        let nexus = nexus_lookup_mut("nexusmixed").unwrap();
+        assert_eq!(nexus.block_len(), 4096);
+
+        let hdl = UntypedBdevHandle::open("nexusmixed", true, false).unwrap();
+        let mut buf = hdl.dma_malloc(8192).unwrap();
+        buf.fill(0x5a);
+        hdl.write_at(4096, &buf).await.unwrap();
+        let mut rbuf = hdl.dma_malloc(8192).unwrap();
+        hdl.read_at(4096, &mut rbuf).await.unwrap();
+        assert_eq!(buf.as_slice(), rbuf.as_slice());
+        drop(hdl);
+
+        nexus_lookup_mut("nexusmixed")
+            .unwrap()
+            .destroy()
+            .await
+            .unwrap();
+    })
+    .await;
+
+    // the writes to a child with larger blocks than the nexus are serialised
+    // on its blocks, whether they are aligned on them or not
+    ms.spawn(async {
+        nexus_create("nexus512", NEXUS_SIZE, None, &[CHILD_512_2.to_string()])
+            .await
+            .unwrap();
+        let nexus = nexus_lookup_mut("nexus512").unwrap();
+        nexus.add_child(CHILD_4K_3, true).await.unwrap();
+        let nexus = nexus_lookup_mut("nexus512").unwrap();
+        assert_eq!(nexus.block_len(), 512);
+        assert_eq!(nexus.child_at(1).state(), ChildState::Open);
+
+        // each sector of the first child block written at once
+        let hdl = UntypedBdevHandle::open("nexus512", true, false).unwrap();
+        let bufs = (0 .. 8u8)
+            .map(|i| {
+                let mut buf = hdl.dma_malloc(512).unwrap();
+                buf.fill(i + 1);
+                buf
+            })
+            .collect::<Vec<_>>();
+        let writes = bufs
+            .iter()
+            .enumerate()
+            .map(|(i, buf)| hdl.write_at(i as u64 * 512, buf));
+        assert!(join_all(writes).await.iter().all(|w| w.is_ok()));
+
+        // the second child block written along with one of its sectors
+        let mut block = hdl.dma_malloc(4096).unwrap();
+        block.fill(0xaa);
+        let mut sector = hdl.dma_malloc(512).unwrap();
+        sector.fill(0x55);
+        let (aligned, unaligned) = futures::join!(
+            hdl.write_at(4096, &block),
+            hdl.write_at(4096 + 1024, &sector)
+        );
+        aligned.unwrap();
+        unaligned.unwrap();
+
+        // read a few times, from both children
+        let mut buf = hdl.dma_malloc(8192).unwrap();
+        for _ in 0 .. 8 {
+            hdl.read_at(0, &mut buf).await.unwrap();
+            let data = buf.as_slice();
+            for i in 0 .. 8 {
+                assert!(data[i * 512 .. (i + 1) * 512]
+                    .iter()
+                    .all(|b| *b == i as u8 + 1));
+            }
+            for (i, b) in data[4096 ..].iter().enumerate() {
+                if i / 512 == 2 {
+                    assert!(*b == 0xaa || *b == 0x55);
+                } else {
+                    assert_eq!(*b, 0xaa);
+                }
+            }
+        }
+        drop(hdl);
+
+        let nexus = nexus_lookup_mut("nexus512").unwrap();
+        assert!(nexus.child_at(1).rmw_stats().read_modify_writes >= 9);
+        nexus.destroy().await.unwrap();
+    })
+    .await;
+
+    // replicas of a pool on a 4Kn device have 4096 byte blocks
+    ms.spawn(async {
+        let pool = Lvs::create_or_import(PoolArgs {
+            name: "pool4k".into(),
+            disks: vec![POOL_DISK.into()],
+            uuid: None,
+        })
+        .await
+        .unwrap();
+        let lvol = pool
+            .create_lvol("replica4k", 8 * 1024 * 1024, None, false)
+            .await
+            .unwrap();
+        let bdev = UntypedBdev::lookup_by_name(&lvol.name()).unwrap();
+        assert_eq!(bdev.block_len(), 4096);
+        pool.destroy().await.unwrap();
+    })
+    .await;
+}
@@ -0,0 +1,97 @@
+use futures::{channel::oneshot, future::join_all};
+
+use io_engine::{
+    bdev::nexus::{
+        nexus_create,
+        nexus_lookup,
+        nexus_lookup_mut,
+        ReadRoutingPolicy,
+    },
+    core::{MayastorCliArgs, Reactor, Reactors, UntypedBdevHandle},
+};
+
+pub mod common;
+
+static NEXUS_SIZE: u64 = 10 * 1024 * 1024;
+static NEXUS_COUNT: usize = 4;
+static ROUNDS: u64 = 10;
+
+fn nexus_name(index: usize) -> String {
+    format!("ChannelEpochNexus{}", index)
+}
+
+fn nexus_children(index: usize) -> Vec<String> {
+    (0 .. 2)
+        .map(|c| {
+            format!("malloc:///malloc{}_{}?blk_size=512&size_mb=20", index, c)
+        })
+        .collect()
+}
+
+/// Write to the nexus from the given reactor, through a channel of its
+/// core, which is put once the writes complete.
+fn write_on(
+    reactor: &'static Reactor,
+    name: String,
+    fill: u8,
+) -> oneshot::Receiver<()> {
+    let (s, r) = oneshot::channel();
+    reactor.send_future(async move {
+        let hdl = UntypedBdevHandle::open(&name, true, false).unwrap();
+        let mut buf = hdl.dma_malloc(4096).unwrap();
+        buf.fill(fill);
+        for i in 0 .. 32 {
+            hdl.write_at(i * 4096, &buf).await.unwrap();
+        }
+        drop(hdl);
+        s.send(()).unwrap();
+    });
+    r
+}
+
+/// Create the nexus, write to it from all the cores while reconfiguring its
+/// channels, and destroy it right away, while the channels of the other
+/// cores may still be live.
+async fn nexus_rounds(index: usize) {
+    let name = nexus_name(index);
+    for round in 0 .. ROUNDS {
+        nexus_create(&name, NEXUS_SIZE, None, &nexus_children(index))
+            .await
+            .unwrap();
+
+        let io = join_all(
+            Reactors::iter().map(|r| write_on(r, name.clone(), round as u8)),
+        );
+        let reconfigure = async {
+            for i in 0 .. 8 {
+                nexus_lookup_mut(&name)
+                    .unwrap()
+                    .set_read_routing(ReadRoutingPolicy {
+                        enabled: i % 2 == 0,
+                        ..Default::default()
+                    })
+                    .await;
+            }
+        };
+        let (written, _) = futures::join!(io, reconfigure);
+        assert!(written.into_iter().all(|w| w.is_ok()));
+
+        nexus_lookup_mut(&name).unwrap().destroy().await.unwrap();
+        assert!(nexus_lookup(&name).is_none());
+    }
+}
+
+#[tokio::test]
+async fn nexus_channel_epoch() {
+    let ms = common::MayastorTest::new(MayastorCliArgs {
+        reactor_mask: "0x3".into(),
+        ..Default::default()
+    });
+    ms.spawn(async {
+        join_all((0 .. NEXUS_COUNT).map(nexus_rounds)).await;
+        for index in 0 .. NEXUS_COUNT {
+            assert!(nexus_lookup(&nexus_name(index)).is_none());
+        }
+    })
+    .await;
+}
@@ -0,0 +1,60 @@
+use io_engine::{
+    bdev::nexus::{nexus_create, nexus_lookup_mut, ChecksumAlgo},
+    core::{MayastorCliArgs, UntypedBdevHandle},
+};
+
+pub mod common;
+
+static NEXUS_NAME: &str = "ChecksumNexus";
+static NEXUS_SIZE: u64 = 10 * 1024 * 1024;
+static CHILD_1: &str = "malloc:///malloc0?blk_size=512&size_mb=20";
+static CHILD_2: &str = "malloc:///malloc1?blk_size=512&size_mb=20";
+
+// offset of the data partition on the children
+static DATA_OFFSET: u64 = 10240 * 512;
+
+#[tokio::test]
+async fn nexus_checksum_read_repair() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+    ms.spawn(async {
+        nexus_create(
+            NEXUS_NAME,
+            NEXUS_SIZE,
+            None,
+            &[CHILD_1.to_string(), CHILD_2.to_string()],
+        )
+        .await
+        .unwrap();
+        let mut nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        nexus
+            .as_mut()
+            .checksum_enable(ChecksumAlgo::Crc32c)
+            .await
+            .unwrap();
+
+        let hdl = UntypedBdevHandle::open(NEXUS_NAME, true, false).unwrap();
+        let mut buf = hdl.dma_malloc(4096).unwrap();
+        buf.fill(0xaa);
+        hdl.write_at(0, &buf).await.unwrap();
+
+        // silently corrupt the data of the first child
+        let child = UntypedBdevHandle::open("malloc0", true, false).unwrap();
+        let mut bad = child.dma_malloc(4096).unwrap();
+        bad.fill(0x55);
+        child.write_at(DATA_OFFSET, &bad).await.unwrap();
+
+        // reads are spread over the children, all of them must return the
+        // good copy
+        for _ in 0 .. 4 {
+            let mut read = hdl.dma_malloc(4096).unwrap();
+            hdl.read_at(0, &mut read).await.unwrap();
+            assert!(read.as_slice().iter().all(|b| *b == 0xaa));
+        }
+
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        let stats = nexus.checksum_stats();
+        assert!(stats.get("malloc0").unwrap().mismatches > 0);
+        assert_eq!(stats.get("malloc1").unwrap().mismatches, 0);
+    })
+    .await;
+}
@@ -0,0 +1,112 @@
+pub mod common;
+
+use common::{
+    compose::{
+        rpc::v1::{nexus::ChildState, GrpcConnect},
+        Binary,
+        Builder,
+    },
+    nexus::NexusBuilder,
+    pool::{list_pools, PoolBuilder},
+    replica::{list_replicas, ReplicaBuilder},
+};
+use std::time::Duration;
+
+const POOL_SIZE: u64 = 60;
+const REPL_SIZE: u64 = 22;
+
+#[tokio::test]
+async fn nexus_child_ops() {
+    common::composer_init();
+
+    let test = Builder::new()
+        .name("cargo-test")
+        .network("10.1.0.0/16")
+        .unwrap()
+        .add_container_bin(
+            "ms_0",
+            Binary::from_dbg("io-engine").with_args(vec!["-l", "1"]),
+        )
+        .with_clean(true)
+        .build()
+        .await
+        .unwrap();
+
+    let conn = GrpcConnect::new(&test);
+    let ms_0 = conn.grpc_handle_shared("ms_0").await.unwrap();
+
+    let mut pool_0 = PoolBuilder::new(ms_0.clone())
+        .with_name("pool0")
+        .with_new_uuid()
+        .with_malloc("mem0", POOL_SIZE);
+    let mut pool_1 = PoolBuilder::new(ms_0.clone())
+        .with_name("pool1")
+        .with_new_uuid()
+        .with_malloc("mem1", POOL_SIZE);
+    let mut repl_0 = ReplicaBuilder::new(ms_0.clone())
+        .with_pool(&pool_0)
+        .with_name("r0")
+        .with_new_uuid()
+        .with_size_mb(REPL_SIZE);
+    let mut repl_1 = ReplicaBuilder::new(ms_0.clone())
+        .with_pool(&pool_1)
+        .with_name("r1")
+        .with_new_uuid()
+        .with_size_mb(REPL_SIZE);
+    pool_0.create().await.unwrap();
+    pool_1.create().await.unwrap();
+    repl_0.create().await.unwrap();
+    repl_1.create().await.unwrap();
+
+    let mut nex_0 = NexusBuilder::new(ms_0.clone())
+        .with_name("nexus0")
+        .with_new_uuid()
+        .with_size_mb(REPL_SIZE)
+        .with_replica(&repl_0)
+        .with_replica(&repl_1);
+    nex_0.create().await.unwrap();
+    nex_0.publish().await.unwrap();
+
+    // a faulted child is rebuilt once it is brought back online
+    nex_0.fault_child_replica(&repl_1).await.unwrap();
+    let child = nex_0.get_child_replica(&repl_1).await.unwrap();
+    assert_eq!(child.state, ChildState::Faulted as i32);
+
+    nex_0.online_child_replica(&repl_1).await.unwrap();
+    nex_0
+        .wait_replica_state(
+            &repl_1,
+            ChildState::Online,
+            Duration::from_secs(30),
+        )
+        .await
+        .unwrap();
+
+    // waiting for a state which is not reached times out
+    assert!(nex_0
+        .wait_replica_state(
+            &repl_0,
+            ChildState::Faulted,
+            Duration::from_millis(500)
+        )
+        .await
+        .is_err());
+
+    nex_0.remove_child_replica(&repl_1).await.unwrap();
+    assert_eq!(nex_0.get_nexus().await.unwrap().children.len(), 1);
+
+    nex_0.unpublish().await.unwrap();
+    nex_0.destroy().await.unwrap();
+
+    repl_1.destroy().await.unwrap();
+    assert_eq!(list_replicas(ms_0.clone()).await.unwrap().len(), 1);
+
+    // the replica of an exported pool is back once the pool is imported
+    pool_0.export().await.unwrap();
+    assert_eq!(list_pools(ms_0.clone()).await.unwrap().len(), 1);
+    pool_0.import().await.unwrap();
+    repl_0.get_replica().await.unwrap();
+
+    pool_1.destroy().await.unwrap();
+    assert!(pool_1.get_pool().await.is_err());
+}
@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+use io_engine::{
+    bdev::nexus::{nexus_create, nexus_lookup_mut, ChildState, NexusStatus},
+    core::MayastorCliArgs,
+};
+
+pub mod common;
+
+static NEXUS_NAME: &str = "UndoChildNexus";
+static NEXUS_SIZE: u64 = 10 * 1024 * 1024;
+static CHILD_1: &str = "malloc:///malloc0?blk_size=512&size_mb=10";
+static CHILD_2: &str = "malloc:///malloc1?blk_size=512&size_mb=10";
+
+#[tokio::test]
+async fn nexus_child_undo() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+    ms.spawn(async {
+        nexus_create(
+            NEXUS_NAME,
+            NEXUS_SIZE,
+            None,
+            &[CHILD_1.to_string(), CHILD_2.to_string()],
+        )
+        .await
+        .unwrap();
+        let mut nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        nexus.set_undo_window(Duration::from_secs(1));
+
+        // the child is detached, and restored without a rebuild
+        nexus.as_mut().remove_child(CHILD_2).await.unwrap();
+        assert!(nexus.lookup_child(CHILD_2).is_none());
+        let status = nexus.as_mut().restore_child(CHILD_2).await.unwrap();
+        assert_eq!(status, NexusStatus::Online);
+        assert_eq!(
+            nexus.lookup_child(CHILD_2).unwrap().state(),
+            ChildState::Open
+        );
+
+        // the removal cannot be undone once the window has expired
+        nexus.as_mut().remove_child(CHILD_2).await.unwrap();
+    })
+    .await;
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    ms.spawn(async {
+        let mut nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        assert!(nexus.as_mut().restore_child(CHILD_2).await.is_err());
+        assert_eq!(nexus.child_count(), 1);
+    })
+    .await;
+}
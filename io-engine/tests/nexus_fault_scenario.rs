@@ -0,0 +1,74 @@
+#![cfg(feature = "fault_injection")]
+
+pub mod common;
+
+use common::{
+    compose::rpc::v1::nexus::{ChildState, ChildStateReason},
+    fio::Fio,
+    scenario::{EventKind, ScenarioBuilder, Step},
+};
+use std::time::Duration;
+
+#[tokio::test]
+async fn nexus_fault_scenario() {
+    common::composer_init();
+
+    let mut scenario = ScenarioBuilder::new("cargo-test")
+        .with_nodes(3)
+        .with_replicas(2)
+        .with_nexus_node(2)
+        .with_step(Step::Write {
+            count: 4,
+            buf_size_mb: 1,
+        })
+        .with_step(Step::InjectFault {
+            replica: 1,
+            begin: Duration::ZERO,
+            end: None,
+        })
+        .with_step(Step::Fio(
+            Fio::new()
+                .with_name("fault")
+                .with_rw("randwrite")
+                .with_size("16M"),
+        ))
+        .with_step(Step::ExpectChild {
+            replica: 1,
+            state: ChildState::Faulted,
+            timeout: Duration::from_secs(10),
+        })
+        .build()
+        .await
+        .unwrap();
+
+    scenario.run().await.unwrap();
+
+    scenario.assert_events(&[
+        EventKind::ChildState {
+            replica: 1,
+            state: ChildState::Online,
+            reason: ChildStateReason::None,
+        },
+        EventKind::FaultInjected {
+            replica: 1,
+        },
+        EventKind::FioCompleted {
+            name: "fault".to_string(),
+        },
+        EventKind::ChildState {
+            replica: 1,
+            state: ChildState::Faulted,
+            reason: ChildStateReason::IoFailure,
+        },
+    ]);
+    scenario.assert_no_event(|e| {
+        matches!(
+            e,
+            EventKind::ChildState {
+                replica: 0,
+                state: ChildState::Faulted,
+                ..
+            }
+        )
+    });
+}
@@ -0,0 +1,95 @@
+use futures::future::join_all;
+
+use io_engine::{
+    bdev::nexus::{
+        nexus_create,
+        nexus_lookup_mut,
+        AdmissionPolicy,
+        WriteMergePolicy,
+    },
+    core::{MayastorCliArgs, UntypedBdevHandle},
+};
+
+pub mod common;
+
+static NEXUS_NAME: &str = "DeadlineNexus";
+static NEXUS_SIZE: u64 = 4 * 1024 * 1024;
+static CHILD: &str = "malloc:///malloc0?blk_size=512&size_mb=20";
+static WRITE_SIZE: u64 = 4096;
+static TIMEOUT_MS: u64 = 10;
+
+/// The writes held to be merged past their deadline are failed rather than
+/// merged and submitted, and so is an IO which was not admitted and is
+/// submitted again past its deadline; without a deadline the same IOs
+/// complete.
+#[tokio::test]
+async fn nexus_io_deadline_expired() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+    ms.spawn(async {
+        nexus_create(NEXUS_NAME, NEXUS_SIZE, None, &[CHILD.to_string()])
+            .await
+            .unwrap();
+
+        // the writes are held five times longer than the initiators wait
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        nexus.set_io_timeout(TIMEOUT_MS);
+        nexus.set_write_merge_policy(WriteMergePolicy {
+            enabled: true,
+            max_ios: 4,
+            max_bytes: 4 * WRITE_SIZE,
+            window_us: 5 * TIMEOUT_MS * 1000,
+        });
+
+        let hdl = UntypedBdevHandle::open(NEXUS_NAME, true, false).unwrap();
+        let mut buf = hdl.dma_malloc(WRITE_SIZE).unwrap();
+        buf.fill(0xaa);
+
+        // a write held on its own
+        assert!(hdl.write_at(0, &buf).await.is_err());
+        assert_eq!(nexus.expired_ios(), 1);
+
+        // adjacent writes held to be merged
+        let writes = (0 .. 2).map(|i| hdl.write_at(i * WRITE_SIZE, &buf));
+        assert!(join_all(writes).await.iter().all(|w| w.is_err()));
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        assert_eq!(nexus.expired_ios(), 3);
+        assert_eq!(nexus.write_merge_stats().merged_writes, 0);
+
+        // the held write takes the only IO the channel admits, the read is
+        // queued until the write has expired
+        nexus.set_admission_policy(AdmissionPolicy {
+            enabled: true,
+            max_nexus_ios: 1,
+            max_child_ios: 4,
+        });
+        let mut rbuf = hdl.dma_malloc(WRITE_SIZE).unwrap();
+        let (written, read) = futures::join!(
+            hdl.write_at(0, &buf),
+            hdl.read_at(8 * WRITE_SIZE, &mut rbuf)
+        );
+        assert!(written.is_err());
+        assert!(read.is_err());
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        assert_eq!(nexus.expired_ios(), 5);
+        assert!(nexus.admission_stats().queued_ios > 0);
+        assert_eq!(nexus.admission_stats().outstanding_ios, 0);
+
+        // without a deadline, the same IOs complete
+        nexus.set_io_timeout(0);
+        let (written, read) = futures::join!(
+            hdl.write_at(0, &buf),
+            hdl.read_at(8 * WRITE_SIZE, &mut rbuf)
+        );
+        written.unwrap();
+        read.unwrap();
+        nexus_lookup_mut(NEXUS_NAME)
+            .unwrap()
+            .set_admission_policy(AdmissionPolicy::default());
+        let writes = (0 .. 2).map(|i| hdl.write_at(i * WRITE_SIZE, &buf));
+        assert!(join_all(writes).await.iter().all(|w| w.is_ok()));
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        assert_eq!(nexus.expired_ios(), 5);
+        assert_eq!(nexus.write_merge_stats().merged_writes, 1);
+    })
+    .await;
+}
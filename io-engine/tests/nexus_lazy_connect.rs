@@ -0,0 +1,64 @@
+use io_engine::{
+    bdev::nexus::{nexus_create_lazy, nexus_lookup_mut, NexusNvmeParams},
+    core::{MayastorCliArgs, UntypedBdevHandle},
+};
+
+pub mod common;
+
+static NEXUS_NAME: &str = "LazyNexus";
+static NEXUS_UUID: &str = "0c7f2a1e-5b7d-4f0e-9a3b-6d1c2e3f4a5b";
+static NEXUS_SIZE: u64 = 4 * 1024 * 1024;
+static BLOCK_LEN: u32 = 512;
+static KEY: &str = "00112233445566778899aabbccddeeff";
+
+/// Encrypted child over a remote target which does not exist.
+static CHILD: &str = "crypto:///lazy_secure\
+    ?base=nvmf%3A%2F%2F127.0.0.1%3A8431%2Fnqn.2019-05.io.openebs%3Alazy-missing\
+    &dek=env:LAZY_DEK";
+
+/// Read the first block of the nexus.
+async fn read(hdl: &UntypedBdevHandle) -> bool {
+    let mut buf = hdl.dma_malloc(BLOCK_LEN as u64).unwrap();
+    hdl.read_at(0, &mut buf).await.is_ok()
+}
+
+/// An encrypted child over a remote target is connected on the first IO of
+/// the nexus, whose failure the next IOs fail with rather than connecting
+/// the child again, until it is prefetched.
+#[tokio::test]
+async fn nexus_lazy_connect_failure() {
+    std::env::set_var("LAZY_DEK", KEY);
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+    ms.spawn(async {
+        nexus_create_lazy(
+            NEXUS_NAME,
+            NEXUS_SIZE,
+            NEXUS_UUID,
+            NexusNvmeParams::default(),
+            &[CHILD.to_string()],
+            None,
+            BLOCK_LEN,
+        )
+        .await
+        .unwrap();
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        assert_eq!(nexus.lazy_connect_attempts(), 0);
+
+        let hdl = UntypedBdevHandle::open(NEXUS_NAME, false, false).unwrap();
+        assert!(!read(&hdl).await);
+        assert_eq!(nexus.lazy_connect_attempts(), 1);
+
+        // the failure is latched
+        for _ in 0 .. 2 {
+            assert!(!read(&hdl).await);
+        }
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        assert_eq!(nexus.lazy_connect_attempts(), 1);
+
+        // a prefetch connects the failed child again
+        assert!(nexus.prefetch_children().await.is_err());
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        assert_eq!(nexus.lazy_connect_attempts(), 2);
+    })
+    .await;
+}
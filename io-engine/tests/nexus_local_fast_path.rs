@@ -0,0 +1,93 @@
+use io_engine::{
+    bdev::nexus::{
+        nexus_create,
+        nexus_lookup_mut,
+        AdmissionPolicy,
+        ChannelStats,
+    },
+    core::{MayastorCliArgs, UntypedBdevHandle},
+    lvs::Lvs,
+    pool_backend::PoolArgs,
+};
+
+pub mod common;
+
+static POOL_NAME: &str = "fast_path_pool";
+static POOL_DISK: &str = "malloc:///fast_path?size_mb=64";
+static REPLICA_NAME: &str = "fast_path_replica";
+static NEXUS_NAME: &str = "FastPathNexus";
+static NEXUS_SIZE: u64 = 4 * 1024 * 1024;
+static BUF_SIZE: u64 = 8192;
+
+/// Returns the number of IOs the nexus forwarded to its local child.
+async fn local_ios() -> u64 {
+    let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+    ChannelStats::total(&nexus.channel_stats().await).local
+}
+
+/// Write the given pattern and read it back.
+async fn write_read(hdl: &UntypedBdevHandle, offset: u64, fill: u8) {
+    let mut buf = hdl.dma_malloc(BUF_SIZE).unwrap();
+    buf.fill(fill);
+    hdl.write_at(offset, &buf).await.unwrap();
+    let mut rbuf = hdl.dma_malloc(BUF_SIZE).unwrap();
+    hdl.read_at(offset, &mut rbuf).await.unwrap();
+    assert!(rbuf.as_slice().iter().all(|b| *b == fill));
+}
+
+/// The IOs of a nexus on a single local replica are forwarded to it, unless
+/// a feature which needs the regular path is enabled, and both paths read
+/// back what the other has written.
+#[tokio::test]
+async fn nexus_local_fast_path() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+    ms.spawn(async {
+        let pool = Lvs::create_or_import(PoolArgs {
+            name: POOL_NAME.into(),
+            disks: vec![POOL_DISK.into()],
+            uuid: None,
+        })
+        .await
+        .unwrap();
+        pool.create_lvol(REPLICA_NAME, 8 * 1024 * 1024, None, false)
+            .await
+            .unwrap();
+        nexus_create(
+            NEXUS_NAME,
+            NEXUS_SIZE,
+            None,
+            &[format!("bdev:///{}", REPLICA_NAME)],
+        )
+        .await
+        .unwrap();
+
+        let hdl = UntypedBdevHandle::open(NEXUS_NAME, true, false).unwrap();
+        write_read(&hdl, 0, 0xaa).await;
+        hdl.write_zeroes_at(0, BUF_SIZE / 2).await.unwrap();
+        hdl.unmap_at(BUF_SIZE / 2, BUF_SIZE / 2).await.unwrap();
+        let mut buf = hdl.dma_malloc(BUF_SIZE).unwrap();
+        hdl.read_at(0, &mut buf).await.unwrap();
+        assert!(buf.as_slice()[.. BUF_SIZE as usize / 2]
+            .iter()
+            .all(|b| *b == 0));
+        assert_eq!(local_ios().await, 5);
+
+        // admission control takes the IOs through the regular path
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        nexus.set_admission_policy(AdmissionPolicy {
+            enabled: true,
+            ..Default::default()
+        });
+        write_read(&hdl, BUF_SIZE, 0x55).await;
+        assert_eq!(local_ios().await, 5);
+        assert_eq!(nexus.admission_stats().outstanding_ios, 0);
+
+        // and the data it writes is read through the fast path
+        nexus.set_admission_policy(AdmissionPolicy::default());
+        let mut buf = hdl.dma_malloc(BUF_SIZE).unwrap();
+        hdl.read_at(BUF_SIZE, &mut buf).await.unwrap();
+        assert!(buf.as_slice().iter().all(|b| *b == 0x55));
+        assert_eq!(local_ios().await, 6);
+    })
+    .await;
+}
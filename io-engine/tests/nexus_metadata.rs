@@ -0,0 +1,59 @@
+use io_engine::{
+    bdev::nexus::{nexus_create, nexus_lookup_mut, FilesystemHint},
+    core::{MayastorCliArgs, UntypedBdevHandle},
+};
+
+pub mod common;
+
+static NEXUS_NAME: &str = "MetadataNexus";
+static NEXUS_SIZE: u64 = 10 * 1024 * 1024;
+static CHILD_1: &str = "malloc:///malloc0?blk_size=512&size_mb=20";
+static CHILD_2: &str = "malloc:///malloc1?blk_size=512&size_mb=20";
+
+// offset of the metadata region on the children, after the write-intent log
+static REGION_OFFSET: u64 = 2 * 1024 * 1024;
+
+#[tokio::test]
+async fn nexus_metadata_region() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+    ms.spawn(async {
+        nexus_create(
+            NEXUS_NAME,
+            NEXUS_SIZE,
+            None,
+            &[CHILD_1.to_string(), CHILD_2.to_string()],
+        )
+        .await
+        .unwrap();
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+
+        // the reservation settings are backed up when the nexus is opened
+        assert!(nexus.metadata().reservation.is_some());
+
+        let metadata = nexus
+            .update_metadata(|m| {
+                m.format_generation = 3;
+                m.filesystem = Some(FilesystemHint {
+                    fs_type: "xfs".to_string(),
+                    fs_label: None,
+                    fs_uuid: None,
+                });
+            })
+            .await
+            .unwrap();
+        assert_eq!(metadata.format_generation, 3);
+        assert_eq!(nexus.metadata(), metadata);
+
+        // the region is kept ahead of the data partition of the children
+        for child in ["malloc0", "malloc1"] {
+            let hdl = UntypedBdevHandle::open(child, false, false).unwrap();
+            let mut buf = hdl.dma_malloc(64 * 1024).unwrap();
+            hdl.read_at(REGION_OFFSET, &mut buf).await.unwrap();
+            assert!(buf
+                .as_slice()
+                .chunks(32 * 1024)
+                .any(|slot| &slot[.. 8] == b"MAYANXMD"));
+        }
+    })
+    .await;
+}
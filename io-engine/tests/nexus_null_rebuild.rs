@@ -0,0 +1,58 @@
+#[macro_use]
+extern crate assert_matches;
+
+use io_engine::{
+    bdev::nexus::{
+        nexus_create,
+        nexus_lookup_mut,
+        ChildState,
+        NullRebuildPolicy,
+        Reason,
+    },
+    core::{MayastorCliArgs, UntypedBdevHandle},
+};
+
+pub mod common;
+
+static NEXUS_NAME: &str = "NullRebuildNexus";
+static NEXUS_SIZE: u64 = 10 * 1024 * 1024;
+static CHILD_1: &str = "malloc:///malloc0?blk_size=512&size_mb=20";
+static CHILD_2: &str = "malloc:///malloc1?blk_size=512&size_mb=20";
+static CHILD_3: &str = "malloc:///malloc2?blk_size=512&size_mb=20";
+
+#[tokio::test]
+async fn nexus_null_rebuild() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+    ms.spawn(async {
+        nexus_create(NEXUS_NAME, NEXUS_SIZE, None, &[CHILD_1.to_string()])
+            .await
+            .unwrap();
+        let mut nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        nexus.set_null_rebuild_policy(NullRebuildPolicy {
+            enabled: true,
+            max_per_minute: 2,
+        });
+        assert!(nexus.never_written());
+
+        // the volume has never been written, the child is online right away
+        nexus.as_mut().add_child(CHILD_2, false).await.unwrap();
+        assert_eq!(nexus.child_at(1).state(), ChildState::Open);
+        assert_eq!(nexus.metadata().write_generation, 0);
+
+        // the first write increases the write generation
+        let hdl = UntypedBdevHandle::open(NEXUS_NAME, true, false).unwrap();
+        let mut buf = hdl.dma_malloc(4096).unwrap();
+        buf.fill(0xa5);
+        hdl.write_at(0, &buf).await.unwrap();
+        assert!(!nexus.never_written());
+        assert_eq!(nexus.metadata().write_generation, 1);
+
+        // and children added from then on are rebuilt
+        nexus.as_mut().add_child(CHILD_3, true).await.unwrap();
+        assert_matches!(
+            nexus.child_at(2).state(),
+            ChildState::Faulted(Reason::OutOfSync)
+        );
+    })
+    .await;
+}
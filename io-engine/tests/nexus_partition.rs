@@ -0,0 +1,112 @@
+pub mod common;
+
+use common::{
+    compose::{
+        network::{Netem, Network},
+        rpc::v1::{nexus::ChildState, GrpcConnect},
+        Binary,
+        Builder,
+    },
+    nexus::{find_nexus_by_uuid, test_write_to_nexus, NexusBuilder},
+    pool::PoolBuilder,
+    replica::ReplicaBuilder,
+};
+use std::time::Duration;
+
+const POOL_SIZE: u64 = 60;
+const REPL_SIZE: u64 = 22;
+
+#[tokio::test]
+async fn nexus_partition() {
+    common::composer_init();
+
+    let test = Builder::new()
+        .name("cargo-test")
+        .network("10.1.0.0/16")
+        .unwrap()
+        .add_container_bin(
+            "ms_nex",
+            Binary::from_dbg("io-engine").with_args(vec!["-l", "1"]),
+        )
+        .add_container_bin(
+            "ms_repl",
+            Binary::from_dbg("io-engine").with_args(vec!["-l", "2"]),
+        )
+        .with_clean(true)
+        .build()
+        .await
+        .unwrap();
+
+    let conn = GrpcConnect::new(&test);
+    let ms_nex = conn.grpc_handle_shared("ms_nex").await.unwrap();
+    let ms_repl = conn.grpc_handle_shared("ms_repl").await.unwrap();
+
+    let mut pool_0 = PoolBuilder::new(ms_nex.clone())
+        .with_name("pool0")
+        .with_new_uuid()
+        .with_malloc("mem0", POOL_SIZE);
+    let mut repl_0 = ReplicaBuilder::new(ms_nex.clone())
+        .with_pool(&pool_0)
+        .with_name("r0")
+        .with_new_uuid()
+        .with_size_mb(REPL_SIZE);
+    pool_0.create().await.unwrap();
+    repl_0.create().await.unwrap();
+
+    let mut pool_1 = PoolBuilder::new(ms_repl.clone())
+        .with_name("pool1")
+        .with_new_uuid()
+        .with_malloc("mem1", POOL_SIZE);
+    let mut repl_1 = ReplicaBuilder::new(ms_repl.clone())
+        .with_pool(&pool_1)
+        .with_name("r1")
+        .with_new_uuid()
+        .with_size_mb(REPL_SIZE);
+    pool_1.create().await.unwrap();
+    repl_1.create().await.unwrap();
+    repl_1.share().await.unwrap();
+
+    let mut nex_0 = NexusBuilder::new(ms_nex.clone())
+        .with_name("nexus0")
+        .with_new_uuid()
+        .with_size_mb(REPL_SIZE)
+        .with_replica(&repl_0)
+        .with_replica(&repl_1);
+    nex_0.create().await.unwrap();
+    nex_0.publish().await.unwrap();
+
+    let network = Network::new(&test);
+
+    // latency and packet loss slow the writes down without failing them
+    {
+        let _netem = network
+            .netem(
+                "ms_repl",
+                &Netem::new()
+                    .with_delay(Duration::from_millis(10))
+                    .with_loss(1.0),
+            )
+            .unwrap();
+        test_write_to_nexus(&nex_0, 2, 1).await.unwrap();
+    }
+    nex_0
+        .wait_children_online(Duration::from_secs(10))
+        .await
+        .unwrap();
+
+    // the remote replica is faulted once the nexus loses it
+    let partition = network.partition("ms_nex", "ms_repl").unwrap();
+    test_write_to_nexus(&nex_0, 2, 1).await.unwrap();
+
+    let n = find_nexus_by_uuid(ms_nex.clone(), &nex_0.uuid())
+        .await
+        .unwrap();
+    let child = n
+        .children
+        .iter()
+        .find(|c| c.uri == repl_1.shared_uri())
+        .unwrap();
+    assert_ne!(child.state, ChildState::Online as i32);
+
+    partition.heal();
+}
@@ -0,0 +1,103 @@
+use futures::channel::oneshot;
+use libc::c_void;
+
+use io_engine::{
+    bdev::{
+        device_open,
+        nexus::{nexus_create, nexus_lookup_mut},
+    },
+    core::{
+        BlockDevice,
+        IoCompletionStatus,
+        MayastorCliArgs,
+        NvmeStatus,
+        UntypedBdevHandle,
+    },
+};
+use spdk_rs::{DmaBuf, IoVec};
+
+pub mod common;
+
+static NEXUS_NAME: &str = "ReadOnlyNexus";
+static NEXUS_SIZE: u64 = 10 * 1024 * 1024;
+static CHILD_1: &str = "malloc:///malloc0?blk_size=512&size_mb=20";
+static CHILD_2: &str = "malloc:///malloc1?blk_size=512&size_mb=20";
+
+fn write_completion_callback(
+    _device: &dyn BlockDevice,
+    status: IoCompletionStatus,
+    ctx: *mut c_void,
+) {
+    let sender = unsafe {
+        Box::from_raw(ctx as *mut oneshot::Sender<IoCompletionStatus>)
+    };
+    sender.send(status).unwrap();
+}
+
+#[tokio::test]
+async fn nexus_read_only() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+    ms.spawn(async {
+        nexus_create(
+            NEXUS_NAME,
+            NEXUS_SIZE,
+            None,
+            &[CHILD_1.to_string(), CHILD_2.to_string()],
+        )
+        .await
+        .unwrap();
+
+        let hdl = UntypedBdevHandle::open(NEXUS_NAME, true, false).unwrap();
+        let mut buf = hdl.dma_malloc(4096).unwrap();
+        buf.fill(0xaa);
+        hdl.write_at(0, &buf).await.unwrap();
+
+        let mut nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        nexus.as_mut().set_read_only(true).await.unwrap();
+        assert!(nexus.is_read_only());
+
+        // writes are failed, reads still return the data
+        buf.fill(0x55);
+        assert!(hdl.write_at(0, &buf).await.is_err());
+        let mut read = hdl.dma_malloc(4096).unwrap();
+        hdl.read_at(0, &mut read).await.unwrap();
+        assert!(read.as_slice().iter().all(|b| *b == 0xaa));
+
+        // with "Namespace is Write Protected"
+        let handle = device_open(NEXUS_NAME, false)
+            .unwrap()
+            .into_handle()
+            .unwrap();
+        let mut wbuf = DmaBuf::new(4096, 9).unwrap();
+        wbuf.fill(0x55);
+        let mut iov = IoVec::default();
+        iov.iov_base = *wbuf;
+        iov.iov_len = wbuf.len();
+        let (s, r) = oneshot::channel::<IoCompletionStatus>();
+        handle
+            .writev_blocks(
+                &mut iov,
+                1,
+                0,
+                8,
+                write_completion_callback,
+                Box::into_raw(Box::new(s)) as *mut c_void,
+            )
+            .unwrap();
+        match r.await.unwrap() {
+            IoCompletionStatus::NvmeError(NvmeStatus::Generic(code)) => {
+                assert_eq!(code as u8, 0x20)
+            }
+            status => panic!("unexpected write status {:?}", status),
+        }
+        drop(handle);
+
+        let mut nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        nexus.as_mut().set_read_only(false).await.unwrap();
+        assert!(!nexus.is_read_only());
+        hdl.write_at(0, &buf).await.unwrap();
+        hdl.read_at(0, &mut read).await.unwrap();
+        assert!(read.as_slice().iter().all(|b| *b == 0x55));
+    })
+    .await;
+}
@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+
+use io_engine::{
+    bdev::nexus::{
+        nexus_create,
+        nexus_lookup_mut,
+        ReadRoutingPolicy,
+        Topology,
+    },
+    core::{MayastorCliArgs, UntypedBdevHandle},
+};
+
+pub mod common;
+
+static NEXUS_NAME: &str = "ReadRoutingNexus";
+static NEXUS_SIZE: u64 = 10 * 1024 * 1024;
+static CHILD_1: &str = "malloc:///malloc0?blk_size=512&size_mb=20";
+static CHILD_2: &str = "malloc:///malloc1?blk_size=512&size_mb=20";
+
+fn topology(zone: &str, node: &str) -> Topology {
+    Topology {
+        zone: Some(zone.into()),
+        rack: None,
+        node: Some(node.into()),
+    }
+}
+
+#[tokio::test]
+async fn nexus_read_routing() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+    ms.spawn(async {
+        nexus_create(
+            NEXUS_NAME,
+            NEXUS_SIZE,
+            None,
+            &[CHILD_1.to_string(), CHILD_2.to_string()],
+        )
+        .await
+        .unwrap();
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        nexus
+            .set_read_routing(ReadRoutingPolicy {
+                enabled: true,
+                local: topology("zone-a", "node-2"),
+                children: HashMap::from([
+                    (CHILD_1.to_string(), topology("zone-b", "node-1")),
+                    (CHILD_2.to_string(), topology("zone-a", "node-2")),
+                ]),
+            })
+            .await;
+
+        // all the reads are served by the child on the same node
+        let hdl = UntypedBdevHandle::open(NEXUS_NAME, false, false).unwrap();
+        for _ in 0 .. 8 {
+            let mut buf = hdl.dma_malloc(4096).unwrap();
+            hdl.read_at(0, &mut buf).await.unwrap();
+        }
+
+        let served = nexus_lookup_mut(NEXUS_NAME).unwrap().reads_served();
+        assert_eq!(served.get(CHILD_1), Some(&0));
+        assert_eq!(served.get(CHILD_2), Some(&8));
+    })
+    .await;
+}
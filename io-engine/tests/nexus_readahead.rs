@@ -0,0 +1,62 @@
+use io_engine::{
+    bdev::nexus::{nexus_create, nexus_lookup_mut, ReadaheadPolicy},
+    core::{MayastorCliArgs, UntypedBdevHandle},
+};
+
+pub mod common;
+
+static NEXUS_NAME: &str = "ReadaheadNexus";
+static NEXUS_SIZE: u64 = 10 * 1024 * 1024;
+static CHILD_1: &str = "malloc:///malloc0?blk_size=512&size_mb=20";
+static CHILD_2: &str = "malloc:///malloc1?blk_size=512&size_mb=20";
+
+#[tokio::test]
+async fn nexus_readahead() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+    ms.spawn(async {
+        nexus_create(
+            NEXUS_NAME,
+            NEXUS_SIZE,
+            None,
+            &[CHILD_1.to_string(), CHILD_2.to_string()],
+        )
+        .await
+        .unwrap();
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        nexus
+            .set_readahead(ReadaheadPolicy {
+                enabled: true,
+                window_bytes: 64 * 1024,
+            })
+            .unwrap();
+
+        let hdl = UntypedBdevHandle::open(NEXUS_NAME, false, false).unwrap();
+        let mut buf = hdl.dma_malloc(256 * 1024).unwrap();
+        buf.as_mut_slice()
+            .chunks_mut(4096)
+            .enumerate()
+            .for_each(|(i, chunk)| chunk.fill(i as u8 + 1));
+        hdl.write_at(0, &buf).await.unwrap();
+
+        // the stream is read back, partly from the prefetched data
+        for i in 0 .. 64u64 {
+            let mut chunk = hdl.dma_malloc(4096).unwrap();
+            hdl.read_at(i * 4096, &mut chunk).await.unwrap();
+            assert!(chunk.as_slice().iter().all(|b| *b == i as u8 + 1));
+        }
+        let stats = nexus_lookup_mut(NEXUS_NAME).unwrap().readahead_stats();
+        assert!(stats.prefetches > 0);
+        assert!(stats.hits > 0);
+
+        // a write invalidates the data prefetched ahead of the stream
+        let mut chunk = hdl.dma_malloc(4096).unwrap();
+        hdl.read_at(0, &mut chunk).await.unwrap();
+        hdl.read_at(4096, &mut chunk).await.unwrap();
+        hdl.read_at(8192, &mut chunk).await.unwrap();
+        chunk.fill(0xff);
+        hdl.write_at(12288, &chunk).await.unwrap();
+        hdl.read_at(12288, &mut chunk).await.unwrap();
+        assert!(chunk.as_slice().iter().all(|b| *b == 0xff));
+    })
+    .await;
+}
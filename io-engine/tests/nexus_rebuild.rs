@@ -234,10 +234,14 @@ async fn rebuild_replica() {
             RebuildJob::lookup(&get_dev(child))
                 .expect_err("rebuild job not created yet");
         }
-        let src = RebuildJob::lookup(&get_dev(NUM_CHILDREN))
-            .expect("now the job should exist")
-            .src_uri
-            .clone();
+        let job = RebuildJob::lookup(&get_dev(NUM_CHILDREN))
+            .expect("now the job should exist");
+        let src = job.src_uri.clone();
+
+        // the source is selected among all the healthy children
+        let selection = job.source_selection.clone().unwrap();
+        assert_eq!(selection.uri, src);
+        assert_eq!(selection.candidates.len() as u64, NUM_CHILDREN);
 
         for child in 0 .. NUM_CHILDREN {
             if get_dev(child) != src {
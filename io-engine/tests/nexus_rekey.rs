@@ -0,0 +1,223 @@
+use std::time::Duration;
+
+use io_engine::{
+    bdev::nexus::{nexus_create, nexus_lookup_mut, ChildState, RekeyState},
+    core::{MayastorCliArgs, UntypedBdevHandle},
+    lvs::Lvs,
+    pool_backend::PoolArgs,
+    rebuild::{set_rebuild_pacing, RebuildPacing},
+};
+
+pub mod common;
+
+static POOL_NAME: &str = "rekey_pool";
+static POOL_DISK: &str = "malloc:///rekey?size_mb=64";
+static PLAIN: &str = "bdev:///rekey_plain";
+static NEXUS_NAME: &str = "RekeyNexus";
+static NEXUS_SIZE: u64 = 4 * 1024 * 1024;
+static DATA_LEN: u64 = 1024 * 1024;
+static FILL: u8 = 0x5a;
+static KEYS: [&str; 4] = [
+    "00112233445566778899aabbccddeeff",
+    "ffeeddccbbaa99887766554433221100",
+    "0123456789abcdef0123456789abcdef",
+    "fedcba9876543210fedcba9876543210",
+];
+
+/// URI of the child of the encrypted replica, encrypted with the given key.
+fn secure(key: usize) -> String {
+    format!(
+        "crypto:///rekey_secure{}?base=bdev%3A%2F%2F%2Frekey_secure\
+        &dek=env:REKEY_DEK{}",
+        key, key
+    )
+}
+
+/// Returns the state of the rotation of the nexus.
+async fn rekey_state(ms: &common::MayastorTest<'_>) -> RekeyState {
+    ms.spawn(async {
+        nexus_lookup_mut(NEXUS_NAME)
+            .unwrap()
+            .rekey_status()
+            .unwrap()
+            .state
+    })
+    .await
+}
+
+/// Wait for the rotation to stop running.
+async fn wait_rekey(ms: &common::MayastorTest<'_>) -> RekeyState {
+    loop {
+        let state = rekey_state(ms).await;
+        if state != RekeyState::Running {
+            return state;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Wait for the given child to be online.
+async fn wait_open(ms: &common::MayastorTest<'_>, uri: &'static str) {
+    loop {
+        let state = ms
+            .spawn(async move {
+                nexus_lookup_mut(NEXUS_NAME)
+                    .unwrap()
+                    .lookup_child(uri)
+                    .unwrap()
+                    .state()
+            })
+            .await;
+        if state == ChildState::Open {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Read the given bdev from the start.
+async fn read(name: &str) -> Vec<u8> {
+    let hdl = UntypedBdevHandle::open(name, false, false).unwrap();
+    let mut buf = hdl.dma_malloc(DATA_LEN).unwrap();
+    hdl.read_at(0, &mut buf).await.unwrap();
+    buf.as_slice().to_vec()
+}
+
+/// Pace the rebuilds for a rotation to be running for a few seconds.
+fn slow_rebuilds(slow: bool) {
+    set_rebuild_pacing(if slow {
+        RebuildPacing {
+            delay_us: 100_000,
+            max_inflight: 1,
+            ..Default::default()
+        }
+    } else {
+        RebuildPacing::default()
+    });
+}
+
+/// The key of an encrypted child is rotated in place, on its replica, whose
+/// blocks are encrypted again under the new key, and the rotation resumes
+/// once the nexus is opened again after it went down, or is cancelled.
+#[tokio::test]
+async fn nexus_rekey_in_place() {
+    for (i, key) in KEYS.iter().enumerate() {
+        std::env::set_var(format!("REKEY_DEK{}", i), key);
+    }
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+
+    let ciphertext = ms
+        .spawn(async {
+            let pool = Lvs::create_or_import(PoolArgs {
+                name: POOL_NAME.into(),
+                disks: vec![POOL_DISK.into()],
+                uuid: None,
+            })
+            .await
+            .unwrap();
+            for name in &["rekey_plain", "rekey_secure"] {
+                pool.create_lvol(name, 8 * 1024 * 1024, None, false)
+                    .await
+                    .unwrap();
+            }
+            nexus_create(
+                NEXUS_NAME,
+                NEXUS_SIZE,
+                None,
+                &[PLAIN.to_string(), secure(0)],
+            )
+            .await
+            .unwrap();
+
+            let hdl = UntypedBdevHandle::open(NEXUS_NAME, true, false).unwrap();
+            let mut buf = hdl.dma_malloc(DATA_LEN).unwrap();
+            buf.fill(FILL);
+            hdl.write_at(0, &buf).await.unwrap();
+
+            // the new child must be on the replica of the child
+            let mut nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+            let ciphertext = read("rekey_secure").await;
+            let other =
+                "crypto:///rekey_other?base=bdev%3A%2F%2F%2Frekey_plain\
+                &dek=env:REKEY_DEK1";
+            assert!(nexus
+                .as_mut()
+                .rekey_child(&secure(0), other)
+                .await
+                .is_err());
+            nexus.rekey_child(&secure(0), &secure(1)).await.unwrap();
+            ciphertext
+        })
+        .await;
+
+    assert_eq!(wait_rekey(&ms).await, RekeyState::Completed);
+    ms.spawn(async move {
+        let mut nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        assert!(nexus.lookup_child(&secure(0)).is_none());
+        assert!(nexus.metadata().rekey.is_none());
+        assert_eq!(nexus.rekey_status().unwrap().progress, 100);
+        assert_ne!(read("rekey_secure").await, ciphertext);
+
+        // the replica holds the data under the new key on its own
+        nexus.as_mut().remove_child(PLAIN).await.unwrap();
+        assert!(read(NEXUS_NAME).await.iter().all(|b| *b == FILL));
+        nexus.as_mut().add_child(PLAIN, false).await.unwrap();
+    })
+    .await;
+    wait_open(&ms, PLAIN).await;
+
+    // the nexus goes down while the rotation is running, and is opened again
+    // with the child encrypted with the current key
+    slow_rebuilds(true);
+    ms.spawn(async {
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        nexus.rekey_child(&secure(1), &secure(2)).await.unwrap();
+        nexus_lookup_mut(NEXUS_NAME)
+            .unwrap()
+            .destroy()
+            .await
+            .unwrap();
+        slow_rebuilds(false);
+        nexus_create(
+            NEXUS_NAME,
+            NEXUS_SIZE,
+            None,
+            &[PLAIN.to_string(), secure(1)],
+        )
+        .await
+        .unwrap();
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        assert_ne!(
+            nexus.lookup_child(&secure(1)).map(|c| c.state()),
+            Some(ChildState::Open)
+        );
+    })
+    .await;
+
+    assert_eq!(wait_rekey(&ms).await, RekeyState::Completed);
+    ms.spawn(async {
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        assert!(nexus.lookup_child(&secure(1)).is_none());
+        assert_eq!(
+            nexus.lookup_child(&secure(2)).unwrap().state(),
+            ChildState::Open
+        );
+        assert!(read(NEXUS_NAME).await.iter().all(|b| *b == FILL));
+    })
+    .await;
+
+    // a cancelled rotation leaves the replica out of the nexus
+    slow_rebuilds(true);
+    ms.spawn(async {
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        nexus.rekey_child(&secure(2), &secure(3)).await.unwrap();
+        let mut nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        nexus.as_mut().rekey_cancel().await.unwrap();
+        assert_eq!(nexus.rekey_status().unwrap().state, RekeyState::Cancelled);
+        assert!(nexus.metadata().rekey.is_none());
+        assert_eq!(nexus.children_uris(), vec![PLAIN.to_string()]);
+        assert!(read(NEXUS_NAME).await.iter().all(|b| *b == FILL));
+    })
+    .await;
+    slow_rebuilds(false);
+}
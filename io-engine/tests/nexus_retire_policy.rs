@@ -0,0 +1,80 @@
+use std::time::Duration;
+
+use io_engine::{
+    bdev::nexus::{nexus_create, nexus_lookup_mut, ChildState, RetirePolicy},
+    core::{MayastorCliArgs, UntypedBdevHandle},
+};
+
+pub mod common;
+
+static INNER_NAME: &str = "RetireInner";
+static INNER_SIZE: u64 = 10 * 1024 * 1024;
+static NEXUS_NAME: &str = "RetireNexus";
+static NEXUS_SIZE: u64 = 4 * 1024 * 1024;
+static CHILD: &str = "malloc:///malloc0?blk_size=512&size_mb=20";
+static INNER_CHILD: &str = "malloc:///malloc1?blk_size=512&size_mb=20";
+
+/// A policy which would fail a write without retiring the child it failed
+/// on is refused, and a write failing on every attempt retires the child.
+#[tokio::test]
+async fn nexus_retire_policy_threshold() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+    ms.spawn(async {
+        nexus_create(INNER_NAME, INNER_SIZE, None, &[INNER_CHILD.to_string()])
+            .await
+            .unwrap();
+        nexus_create(
+            NEXUS_NAME,
+            NEXUS_SIZE,
+            None,
+            &[CHILD.to_string(), format!("bdev:///{}", INNER_NAME)],
+        )
+        .await
+        .unwrap();
+
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        let policy = RetirePolicy {
+            error_threshold: 3,
+            max_retries: 1,
+            ..Default::default()
+        };
+        assert!(nexus.set_retire_policy(policy).is_err());
+        assert!(nexus
+            .set_retire_policy(RetirePolicy {
+                error_threshold: 0,
+                ..policy
+            })
+            .is_err());
+        assert_eq!(nexus.retire_policy(), RetirePolicy::default());
+
+        let policy = RetirePolicy {
+            error_threshold: 2,
+            ..policy
+        };
+        nexus.set_retire_policy(policy).unwrap();
+        assert_eq!(nexus.retire_policy(), policy);
+
+        // the write fails on the inner nexus, is retried once and fails
+        // again, which retires it
+        nexus_lookup_mut(INNER_NAME)
+            .unwrap()
+            .set_read_only(true)
+            .await
+            .unwrap();
+        let hdl = UntypedBdevHandle::open(NEXUS_NAME, true, false).unwrap();
+        let mut buf = hdl.dma_malloc(4096).unwrap();
+        buf.fill(0xaa);
+        hdl.write_at(0, &buf).await.ok();
+    })
+    .await;
+
+    // let the retirement of the child go through
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    ms.spawn(async {
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        assert_eq!(nexus.child_at(0).state(), ChildState::Open);
+        assert!(matches!(nexus.child_at(1).state(), ChildState::Faulted(_)));
+    })
+    .await;
+}
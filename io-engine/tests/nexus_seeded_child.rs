@@ -0,0 +1,70 @@
+use io_engine::{
+    bdev::nexus::{
+        nexus_create,
+        nexus_lookup_mut,
+        ChildState,
+        NexusStatus,
+        SeedVerify,
+    },
+    core::MayastorCliArgs,
+};
+
+pub mod common;
+use common::MayastorTest;
+
+static NEXUS_NAME: &str = "seeded_nexus";
+
+static MALLOC0: &str = "malloc:///m0?size_mb=64";
+static MALLOC1: &str = "malloc:///m1?size_mb=64";
+static MALLOC2: &str = "malloc:///m2?size_mb=64";
+
+#[tokio::test]
+async fn nexus_seeded_child() {
+    let ms = MayastorTest::new(MayastorCliArgs::default());
+
+    ms.spawn(async {
+        nexus_create(NEXUS_NAME, 32 * 1024 * 1024, None, &[MALLOC0.into()])
+            .await
+            .expect("Failed to create nexus");
+    })
+    .await;
+
+    // malloc devices are zeroed, so the data of the children matches
+    ms.spawn(async {
+        let mut nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        let outcome = nexus
+            .as_mut()
+            .add_seeded_child(MALLOC1, Some(SeedVerify::default()))
+            .await
+            .expect("Failed to add seeded child");
+        assert_eq!(outcome.verified_samples, 64);
+        assert!(!outcome.rebuilding);
+        assert_eq!(nexus.child_at(1).state(), ChildState::Open);
+        assert_eq!(nexus.status(), NexusStatus::Online);
+    })
+    .await;
+
+    // without verification
+    ms.spawn(async {
+        let mut nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        let outcome = nexus
+            .as_mut()
+            .add_seeded_child(MALLOC2, None)
+            .await
+            .expect("Failed to add seeded child");
+        assert_eq!(outcome.verified_samples, 0);
+        assert!(!outcome.rebuilding);
+        assert_eq!(nexus.child_at(2).state(), ChildState::Open);
+        assert_eq!(nexus.child_count(), 3);
+    })
+    .await;
+
+    ms.spawn(async {
+        nexus_lookup_mut(NEXUS_NAME)
+            .unwrap()
+            .destroy()
+            .await
+            .unwrap();
+    })
+    .await;
+}
@@ -61,6 +61,30 @@ async fn nexus_share_test() {
                 nexus.unshare().await.unwrap();
             });
 
+            // share the nexus over iSCSI, the initiators authenticating
+            // with CHAP
+            Reactor::block_on(async {
+                let mut nexus = nexus_lookup_mut("nexus0").unwrap();
+                let uri = nexus
+                    .as_mut()
+                    .share_ext(
+                        Protocol::Iscsi,
+                        Some("0123456789abcdef".into()),
+                        vec![],
+                    )
+                    .await
+                    .unwrap();
+                assert!(uri.starts_with("iscsi://"), "{}", uri);
+                assert!(uri.ends_with(":nexus0/0"), "{}", uri);
+                assert_eq!(nexus.shared(), Some(Protocol::Iscsi));
+                assert!(nexus
+                    .as_mut()
+                    .share_ext(Protocol::Nvmf, None, vec![])
+                    .await
+                    .is_err());
+                nexus.as_mut().unshare_nexus().await.unwrap();
+            });
+
             Reactor::block_on(async {
                 let nexus = nexus_lookup_mut("nexus0").unwrap();
                 assert_eq!(nexus.shared(), Some(Protocol::Off));
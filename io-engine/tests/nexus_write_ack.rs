@@ -0,0 +1,177 @@
+use std::time::Duration;
+
+use io_engine::{
+    bdev::nexus::{
+        nexus_create,
+        nexus_lookup_mut,
+        ChildState,
+        WriteAckMode,
+        WriteAckPolicy,
+    },
+    constants::NVME_NQN_PREFIX,
+    core::{MayastorCliArgs, Protocol, UntypedBdevHandle},
+};
+
+pub mod common;
+
+use common::{
+    compose::{
+        rpc::v0::{
+            mayastor::{
+                CreateNexusRequest,
+                JsonRpcRequest,
+                PublishNexusRequest,
+            },
+            GrpcConnect,
+        },
+        Builder,
+    },
+    MayastorTest,
+};
+
+static INNER_UUIDS: [&str; 2] = [
+    "6f0b3c9e-8a2d-4c1e-9b57-2d7e1a4c0a01",
+    "6f0b3c9e-8a2d-4c1e-9b57-2d7e1a4c0a02",
+];
+static INNER_SIZE: u64 = 32 * 1024 * 1024;
+static NEXUS_NAME: &str = "WriteAckNexus";
+static NEXUS_SIZE: u64 = 16 * 1024 * 1024;
+static LOCAL_CHILD: &str = "malloc:///local0?blk_size=512&size_mb=64";
+
+/// Read the first blocks of the nexus a few times, so that each of its
+/// readers is picked, and check they hold the given pattern.
+async fn check_reads(hdl: &UntypedBdevHandle, fill: u8) {
+    let mut buf = hdl.dma_malloc(4096).unwrap();
+    for _ in 0 .. 8 {
+        hdl.read_at(0, &mut buf).await.unwrap();
+        assert!(buf.as_slice().iter().all(|b| *b == fill));
+    }
+}
+
+/// An asynchronous write failing on a remote child marks the child stale,
+/// so that reads keep away from it, and retires it.
+#[tokio::test]
+async fn nexus_write_ack_async_failure() {
+    common::composer_init();
+
+    let test = Builder::new()
+        .name("cargo-test")
+        .network("10.1.0.0/16")
+        .unwrap()
+        .add_container_dbg("ms1")
+        .add_container_dbg("ms2")
+        .with_clean(true)
+        .build()
+        .await
+        .unwrap();
+    let grpc = GrpcConnect::new(&test);
+    let mut hdls = grpc.grpc_handles().await.unwrap();
+
+    // each remote child is a nexus, which can be made to fail writes
+    let mut children = vec![LOCAL_CHILD.to_string()];
+    for (hdl, uuid) in hdls.iter_mut().zip(INNER_UUIDS.iter()) {
+        hdl.mayastor
+            .create_nexus(CreateNexusRequest {
+                uuid: uuid.to_string(),
+                size: INNER_SIZE,
+                children: vec!["malloc:///inner0?size_mb=64".into()],
+            })
+            .await
+            .unwrap();
+        hdl.mayastor
+            .publish_nexus(PublishNexusRequest {
+                uuid: uuid.to_string(),
+                key: "".to_string(),
+                share: Protocol::Nvmf as i32,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        children.push(format!(
+            "nvmf://{}:8420/{}:nexus-{}",
+            hdl.endpoint.ip(),
+            NVME_NQN_PREFIX,
+            uuid
+        ));
+    }
+
+    let ms = MayastorTest::new(MayastorCliArgs {
+        no_pci: true,
+        grpc_endpoint: "0.0.0.0".to_string(),
+        ..Default::default()
+    });
+
+    let async_child = ms
+        .spawn(async move {
+            nexus_create(NEXUS_NAME, NEXUS_SIZE, None, &children)
+                .await
+                .unwrap();
+            let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+            nexus.set_write_ack_policy(WriteAckPolicy {
+                mode: WriteAckMode::LocalQuorum,
+                max_lag: 64,
+            });
+
+            let hdl = UntypedBdevHandle::open(NEXUS_NAME, true, false).unwrap();
+            let mut buf = hdl.dma_malloc(4096).unwrap();
+            buf.fill(0xaa);
+            hdl.write_at(0, &buf).await.unwrap();
+
+            // one of the remote children is written asynchronously
+            let stats = nexus.write_ack_stats();
+            nexus
+                .children_iter()
+                .position(|c| {
+                    c.get_device_name()
+                        .and_then(|d| stats.get(&d).map(|s| s.submitted > 0))
+                        .unwrap_or_default()
+                })
+                .unwrap()
+        })
+        .await;
+
+    // the asynchronous writes to the remote child now fail
+    let idx = async_child - 1;
+    hdls[idx]
+        .jsonrpc
+        .json_rpc_call(JsonRpcRequest {
+            method: "nexus_set_read_only".to_string(),
+            params: format!(
+                "{{\"name\": \"{}\", \"read_only\": true}}",
+                INNER_UUIDS[idx]
+            ),
+        })
+        .await
+        .unwrap();
+
+    ms.spawn(async {
+        let hdl = UntypedBdevHandle::open(NEXUS_NAME, true, false).unwrap();
+        let mut buf = hdl.dma_malloc(4096).unwrap();
+        buf.fill(0x55);
+        hdl.write_at(0, &buf).await.unwrap();
+
+        // the data the write acknowledged is read back, whether the
+        // asynchronous write is still in flight or has failed
+        check_reads(&hdl, 0x55).await;
+    })
+    .await;
+
+    // let the retirement of the child go through
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    ms.spawn(async move {
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        for (i, child) in nexus.children_iter().enumerate() {
+            if i == async_child {
+                assert!(matches!(child.state(), ChildState::Faulted(_)));
+            } else {
+                assert_eq!(child.state(), ChildState::Open);
+            }
+        }
+        assert!(nexus.write_ack_stats().values().all(|s| !s.stale));
+
+        let hdl = UntypedBdevHandle::open(NEXUS_NAME, true, false).unwrap();
+        check_reads(&hdl, 0x55).await;
+    })
+    .await;
+}
@@ -0,0 +1,62 @@
+use io_engine::{
+    bdev::nexus::{nexus_create, nexus_lookup_mut},
+    core::{MayastorCliArgs, UntypedBdevHandle},
+};
+
+pub mod common;
+
+static NEXUS_NAME: &str = "WriteIntentNexus";
+static NEXUS_SIZE: u64 = 10 * 1024 * 1024;
+static CHILD_1: &str = "malloc:///malloc0?blk_size=512&size_mb=20";
+static CHILD_2: &str = "malloc:///malloc1?blk_size=512&size_mb=20";
+
+// offset of the write-intent log on the children
+static LOG_OFFSET: u64 = 1024 * 1024;
+
+#[tokio::test]
+async fn nexus_write_intent_log() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+    ms.spawn(async {
+        nexus_create(
+            NEXUS_NAME,
+            NEXUS_SIZE,
+            None,
+            &[CHILD_1.to_string(), CHILD_2.to_string()],
+        )
+        .await
+        .unwrap();
+        let mut nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        nexus
+            .as_mut()
+            .write_intent_enable(Some(1024 * 1024))
+            .await
+            .unwrap();
+
+        let hdl = UntypedBdevHandle::open(NEXUS_NAME, true, false).unwrap();
+        let mut buf = hdl.dma_malloc(4096).unwrap();
+        buf.fill(0xaa);
+        hdl.write_at(0, &buf).await.unwrap();
+        drop(hdl);
+
+        let stats = nexus_lookup_mut(NEXUS_NAME).unwrap().write_intent_stats();
+        assert!(stats.enabled);
+        assert_eq!(stats.regions, 10);
+        assert_eq!(stats.dirty_regions, 1);
+
+        // the log is kept ahead of the data partition of the children
+        let child = UntypedBdevHandle::open("malloc0", false, false).unwrap();
+        let mut header = child.dma_malloc(512).unwrap();
+        child.read_at(LOG_OFFSET, &mut header).await.unwrap();
+        assert_eq!(&header.as_slice()[.. 8], b"MAYAWINT");
+        drop(child);
+
+        nexus_lookup_mut(NEXUS_NAME)
+            .unwrap()
+            .write_intent_disable()
+            .await
+            .unwrap();
+        let stats = nexus_lookup_mut(NEXUS_NAME).unwrap().write_intent_stats();
+        assert!(!stats.enabled);
+    })
+    .await;
+}
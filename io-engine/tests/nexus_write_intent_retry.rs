@@ -0,0 +1,60 @@
+use std::time::Duration;
+
+use io_engine::{
+    bdev::nexus::{nexus_lookup_mut, ChildState},
+    core::{MayastorCliArgs, UntypedBdevHandle},
+};
+
+pub mod common;
+
+use common::nexus::{create_nexus_on_nexus, set_nexus_read_only};
+
+static INNER_NAME: &str = "WriteIntentInner";
+static INNER_SIZE: u64 = 10 * 1024 * 1024;
+static NEXUS_NAME: &str = "WriteIntentRetryNexus";
+static NEXUS_SIZE: u64 = 4 * 1024 * 1024;
+static CHILD: &str = "malloc:///malloc0?blk_size=512&size_mb=20";
+
+/// A write which fails on a child which is not retired is resubmitted, and
+/// accounted once in the write-intent log, whose regions are logged clean
+/// once idle.
+#[tokio::test]
+async fn nexus_write_intent_retried_write() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+    ms.spawn(async {
+        create_nexus_on_nexus(
+            INNER_NAME, INNER_SIZE, NEXUS_NAME, NEXUS_SIZE, CHILD,
+        )
+        .await;
+
+        let mut nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        nexus
+            .as_mut()
+            .write_intent_enable(Some(1024 * 1024))
+            .await
+            .unwrap();
+
+        // the writes of the inner nexus are failed, the write is retried
+        // once on the child and failed
+        set_nexus_read_only(INNER_NAME, true).await;
+        let hdl = UntypedBdevHandle::open(NEXUS_NAME, true, false).unwrap();
+        let mut buf = hdl.dma_malloc(4096).unwrap();
+        buf.fill(0xaa);
+        assert!(hdl.write_at(0, &buf).await.is_err());
+
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        assert_eq!(nexus.child_at(0).state(), ChildState::Open);
+        assert_eq!(nexus.write_intent_stats().dirty_regions, 1);
+    })
+    .await;
+
+    // let two clearing passes of the log go by
+    tokio::time::sleep(Duration::from_secs(12)).await;
+
+    ms.spawn(async {
+        let stats = nexus_lookup_mut(NEXUS_NAME).unwrap().write_intent_stats();
+        assert!(stats.enabled);
+        assert_eq!(stats.dirty_regions, 0);
+    })
+    .await;
+}
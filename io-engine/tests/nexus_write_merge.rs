@@ -0,0 +1,123 @@
+use futures::future::join_all;
+
+use io_engine::{
+    bdev::nexus::{
+        nexus_create,
+        nexus_lookup_mut,
+        ChildState,
+        WriteMergePolicy,
+    },
+    core::{MayastorCliArgs, UntypedBdevHandle},
+};
+
+pub mod common;
+
+use common::{
+    compose::{
+        rpc::v0::{
+            mayastor::{BdevShareRequest, BdevUri},
+            GrpcConnect,
+        },
+        Builder,
+    },
+    MayastorTest,
+};
+
+static NEXUS_NAME: &str = "nexus_merge";
+static NEXUS_SIZE: u64 = 32 * 1024 * 1024;
+static WRITE_SIZE: u64 = 64 * 1024;
+static WRITES: u64 = 8;
+
+/// Merged writes submitted to children with smaller blocks than the nexus
+/// must not exceed the largest IO of the children.
+#[tokio::test]
+async fn nexus_write_merge_mixed_block_size() {
+    common::composer_init();
+
+    let test = Builder::new()
+        .name("cargo-test")
+        .network("10.1.0.0/16")
+        .unwrap()
+        .add_container_dbg("ms1")
+        .with_clean(true)
+        .build()
+        .await
+        .unwrap();
+
+    let grpc = GrpcConnect::new(&test);
+    let mut hdls = grpc.grpc_handles().await.unwrap();
+
+    // a child with 512 byte blocks and one with 4096 byte blocks
+    let mut children = Vec::new();
+    for (name, blk_size) in [("disk512", 512), ("disk4k", 4096)] {
+        hdls[0]
+            .bdev
+            .create(BdevUri {
+                uri: format!(
+                    "malloc:///{}?blk_size={}&size_mb=64",
+                    name, blk_size
+                ),
+            })
+            .await
+            .unwrap();
+        hdls[0]
+            .bdev
+            .share(BdevShareRequest {
+                name: name.into(),
+                proto: "nvmf".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        children.push(format!(
+            "nvmf://{}:8420/nqn.2019-05.io.openebs:{}",
+            hdls[0].endpoint.ip(),
+            name
+        ));
+    }
+
+    let ms = MayastorTest::new(MayastorCliArgs::default());
+    ms.spawn(async move {
+        nexus_create(NEXUS_NAME, NEXUS_SIZE, None, &children)
+            .await
+            .unwrap();
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        assert_eq!(nexus.block_len(), 4096);
+
+        // the writes merge into writes larger than the 512 byte child
+        // accepts, counted in its blocks
+        nexus.set_write_merge_policy(WriteMergePolicy {
+            enabled: true,
+            max_ios: WRITES as u32,
+            max_bytes: WRITES * WRITE_SIZE,
+            window_us: 10_000,
+        });
+
+        let hdl = UntypedBdevHandle::open(NEXUS_NAME, true, false).unwrap();
+        let mut bufs = Vec::new();
+        for i in 0 .. WRITES {
+            let mut buf = hdl.dma_malloc(WRITE_SIZE).unwrap();
+            buf.fill(i as u8 + 1);
+            bufs.push(buf);
+        }
+        let writes = bufs
+            .iter()
+            .enumerate()
+            .map(|(i, buf)| hdl.write_at(i as u64 * WRITE_SIZE, buf));
+        for result in join_all(writes).await {
+            result.unwrap();
+        }
+
+        for (i, buf) in bufs.iter().enumerate() {
+            let mut read = hdl.dma_malloc(WRITE_SIZE).unwrap();
+            hdl.read_at(i as u64 * WRITE_SIZE, &mut read).await.unwrap();
+            assert_eq!(read.as_slice(), buf.as_slice());
+        }
+
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        assert!(nexus.children_iter().all(|c| c.state() == ChildState::Open));
+        drop(hdl);
+        nexus.destroy().await.unwrap();
+    })
+    .await;
+}
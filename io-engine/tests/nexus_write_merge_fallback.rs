@@ -0,0 +1,81 @@
+use futures::future::join_all;
+
+use io_engine::{
+    bdev::nexus::{nexus_lookup_mut, ChildState, WriteMergePolicy},
+    core::{MayastorCliArgs, UntypedBdevHandle},
+};
+
+pub mod common;
+
+use common::nexus::{create_nexus_on_nexus, set_nexus_read_only};
+
+static INNER_NAME: &str = "WriteMergeInner";
+static INNER_SIZE: u64 = 10 * 1024 * 1024;
+static NEXUS_NAME: &str = "WriteMergeNexus";
+static NEXUS_SIZE: u64 = 4 * 1024 * 1024;
+static CHILD: &str = "malloc:///malloc0?blk_size=512&size_mb=20";
+static WRITE_SIZE: u64 = 4096;
+static WRITES: u64 = 4;
+
+/// A write held on its own is submitted once its window elapses, also after
+/// the poller of the window stopped with the previous batch, and the writes
+/// of a merged write which fails on a child are failed one by one.
+#[tokio::test]
+async fn nexus_write_merge_window_and_fallback() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+    ms.spawn(async {
+        create_nexus_on_nexus(
+            INNER_NAME, INNER_SIZE, NEXUS_NAME, NEXUS_SIZE, CHILD,
+        )
+        .await;
+
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        nexus.set_write_merge_policy(WriteMergePolicy {
+            enabled: true,
+            max_ios: WRITES as u32,
+            max_bytes: WRITES * WRITE_SIZE,
+            window_us: 1000,
+        });
+
+        let hdl = UntypedBdevHandle::open(NEXUS_NAME, true, false).unwrap();
+        let mut buf = hdl.dma_malloc(WRITE_SIZE).unwrap();
+        buf.fill(0xaa);
+
+        // each write is held alone until its window elapses
+        for i in 0 .. 3 {
+            hdl.write_at(i * 16 * WRITE_SIZE, &buf).await.unwrap();
+        }
+        assert_eq!(nexus.write_merge_stats().merged_writes, 0);
+
+        let writes = (0 .. WRITES).map(|i| hdl.write_at(i * WRITE_SIZE, &buf));
+        assert!(join_all(writes).await.iter().all(|w| w.is_ok()));
+        let stats = nexus.write_merge_stats();
+        assert_eq!(stats.merged_writes, 1);
+        assert_eq!(stats.merged_ios, WRITES);
+
+        // the merged write fails, and so do the writes on their own
+        set_nexus_read_only(INNER_NAME, true).await;
+        let writes = (0 .. WRITES).map(|i| hdl.write_at(i * WRITE_SIZE, &buf));
+        assert!(join_all(writes).await.iter().all(|w| w.is_err()));
+        let nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+        assert_eq!(nexus.write_merge_stats().fallbacks, 1);
+        assert_eq!(nexus.child_at(0).state(), ChildState::Open);
+
+        // the writes held when the policy is turned off are submitted
+        set_nexus_read_only(INNER_NAME, false).await;
+        let policy = nexus.write_merge_policy();
+        let write = hdl.write_at(0, &buf);
+        let disable = async {
+            nexus_lookup_mut(NEXUS_NAME)
+                .unwrap()
+                .set_write_merge_policy(WriteMergePolicy {
+                    enabled: false,
+                    ..policy
+                });
+        };
+        let (written, _) = futures::join!(write, disable);
+        written.unwrap();
+        hdl.write_at(WRITE_SIZE, &buf).await.unwrap();
+    })
+    .await;
+}
@@ -0,0 +1,70 @@
+use io_engine::{
+    bdev::nexus::{nexus_create, nexus_lookup_mut},
+    core::{MayastorCliArgs, Protocol, Share},
+    drain::{drain_node, drain_status, undrain_node},
+};
+
+pub mod common;
+
+static NEXUS_SIZE: u64 = 10 * 1024 * 1024;
+
+fn children(name: &str) -> Vec<String> {
+    vec![
+        format!("malloc:///{}0?size_mb=12", name),
+        format!("malloc:///{}1?size_mb=12", name),
+    ]
+}
+
+#[tokio::test]
+async fn node_drain() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+
+    ms.spawn(async {
+        nexus_create("drained", NEXUS_SIZE, None, &children("drained"))
+            .await
+            .unwrap();
+        let mut nexus = nexus_lookup_mut("drained").unwrap();
+        nexus.as_mut().share_nvmf(None).await.unwrap();
+        assert!(!drain_status().draining);
+
+        // draining unshares the nexuses and refuses new ones
+        let status = drain_node().await;
+        assert!(status.draining);
+        assert!(status.ready, "{:?}", status);
+        let nexus = nexus_lookup_mut("drained").unwrap();
+        assert_ne!(nexus.shared(), Some(Protocol::Nvmf));
+        assert!(nexus_create(
+            "refused",
+            NEXUS_SIZE,
+            None,
+            &children("refused")
+        )
+        .await
+        .is_err());
+        assert!(nexus_lookup_mut("refused").is_none());
+
+        // existing nexuses can still be created again idempotently
+        nexus_create("drained", NEXUS_SIZE, None, &children("drained"))
+            .await
+            .unwrap();
+
+        let status = undrain_node().await;
+        assert!(!status.draining);
+        assert!(!status.ready);
+        nexus_create("accepted", NEXUS_SIZE, None, &children("accepted"))
+            .await
+            .unwrap();
+
+        nexus_lookup_mut("accepted")
+            .unwrap()
+            .destroy()
+            .await
+            .unwrap();
+        nexus_lookup_mut("drained")
+            .unwrap()
+            .destroy()
+            .await
+            .unwrap();
+    })
+    .await;
+}
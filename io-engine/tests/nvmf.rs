@@ -7,7 +7,7 @@ use io_engine::{
         Reactor,
         UntypedBdev,
     },
-    subsys::{NvmfSubsystem, SubType},
+    subsys::{NvmfSubsystem, PollGroupInfo, SubType},
 };
 
 pub mod common;
@@ -44,6 +44,16 @@ fn nvmf_target() {
                 ss.start().await.unwrap();
             });
 
+            // a poll group is created on every core
+            Reactor::block_on(async {
+                let cores = PollGroupInfo::collect()
+                    .await
+                    .iter()
+                    .map(|pg| pg.core)
+                    .collect::<Vec<_>>();
+                assert_eq!(cores, vec![0, 1]);
+            });
+
             // test we can not create the same one again
             Reactor::block_on(async {
                 let bdev = UntypedBdev::lookup_by_name(BDEVNAME1).unwrap();
@@ -0,0 +1,26 @@
+use std::time::Duration;
+
+use io_engine::subsys::{add_referral, remove_referral};
+
+#[test]
+fn nvmf_referral() {
+    let nqn = "nqn.2019-05.io.openebs:referral";
+    let uri = format!("nvmf://10.1.0.5:8420/{}", nqn);
+
+    let referral = add_referral(&uri, Duration::from_secs(60)).unwrap();
+    assert_eq!(referral.nqn, nqn);
+    assert_eq!(referral.address, "10.1.0.5");
+    assert_eq!(referral.port, 8420);
+
+    // the URI must name the subsystem on its new target
+    assert!(
+        add_referral("nvmf://10.1.0.5:8420", Duration::from_secs(60)).is_err()
+    );
+    assert!(
+        add_referral(&format!("bdev:///{}", nqn), Duration::from_secs(60))
+            .is_err()
+    );
+
+    assert!(remove_referral(nqn));
+    assert!(!remove_referral(nqn));
+}
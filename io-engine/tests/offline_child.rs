@@ -0,0 +1,52 @@
+use io_engine::{
+    bdev::nexus::{
+        nexus_create,
+        nexus_lookup_mut,
+        ChildState,
+        NexusStatus,
+        Reason,
+    },
+    core::MayastorCliArgs,
+};
+
+pub mod common;
+
+static NEXUS_NAME: &str = "OfflineChildNexus";
+static NEXUS_SIZE: u64 = 10 * 1024 * 1024;
+static CHILD_1: &str = "malloc:///malloc0?blk_size=512&size_mb=10";
+static CHILD_2: &str = "malloc:///malloc1?blk_size=512&size_mb=10";
+
+#[tokio::test]
+async fn offline_child() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+    ms.spawn(async {
+        nexus_create(
+            NEXUS_NAME,
+            NEXUS_SIZE,
+            None,
+            &[CHILD_1.to_string(), CHILD_2.to_string()],
+        )
+        .await
+        .unwrap();
+        let mut nexus = nexus_lookup_mut(NEXUS_NAME).unwrap();
+
+        // the child is taken out of the I/O path but stays attached
+        let status = nexus.as_mut().take_child_offline(CHILD_2).await.unwrap();
+        assert_eq!(status, NexusStatus::Degraded);
+        assert_eq!(
+            nexus.lookup_child(CHILD_2).unwrap().state(),
+            ChildState::Faulted(Reason::Offline)
+        );
+
+        // it should not be possible to take the last healthy child offline
+        assert!(nexus.as_mut().take_child_offline(CHILD_1).await.is_err());
+
+        // onlining the child brings it back through a rebuild
+        nexus.as_mut().online_child(CHILD_2).await.unwrap();
+        assert_ne!(
+            nexus.lookup_child(CHILD_2).unwrap().state(),
+            ChildState::Faulted(Reason::Offline)
+        );
+    })
+    .await;
+}
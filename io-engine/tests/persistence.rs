@@ -153,6 +153,105 @@ async fn persist_clean_shutdown() {
     assert!(child.healthy);
 }
 
+/// This test checks that the definition of a nexus is kept when the io-engine
+/// terminates, so that the nexus is restored on startup, and that it is
+/// forgotten once the nexus is destroyed.
+#[tokio::test]
+async fn persist_nexus_restored_on_restart() {
+    let test = start_infrastructure_ext(
+        "persist_nexus_restored_on_restart",
+        vec!["--reconcile-policy", "restore"],
+    )
+    .await;
+    let grpc = GrpcConnect::new(&test);
+    let ms1 = &mut grpc.grpc_handle("ms1").await.unwrap();
+    let ms2 = &mut grpc.grpc_handle("ms2").await.unwrap();
+    let ms3 = &mut grpc.grpc_handle("ms3").await.unwrap();
+
+    // Create bdevs and share over nvmf.
+    let child1 = create_and_share_bdevs(ms2, CHILD1_UUID).await;
+    let child2 = create_and_share_bdevs(ms3, CHILD2_UUID).await;
+
+    // Create a nexus.
+    let nexus_uuid = "8272e9d3-3738-4e33-b8c3-769d8eed5771";
+    create_nexus(ms1, nexus_uuid, vec![child1.clone(), child2.clone()]).await;
+
+    // The nexus is destroyed on termination, and restored on startup.
+    test.restart("ms1")
+        .await
+        .expect("Failed to restart container.");
+    let ms1 = &mut grpc.grpc_handle("ms1").await.unwrap();
+    let nexus = wait_nexus(ms1, nexus_uuid, true)
+        .await
+        .expect("Nexus was not restored");
+    assert_eq!(nexus.children.len(), 2);
+
+    // Once destroyed, the nexus is not restored anymore.
+    ms1.mayastor
+        .destroy_nexus(DestroyNexusRequest {
+            uuid: nexus_uuid.to_string(),
+        })
+        .await
+        .expect("Failed to destroy nexus");
+    test.restart("ms1")
+        .await
+        .expect("Failed to restart container.");
+    let ms1 = &mut grpc.grpc_handle("ms1").await.unwrap();
+    assert!(wait_nexus(ms1, nexus_uuid, false).await.is_none());
+}
+
+/// This test checks that a nexus is owned by the node which created it, and
+/// that another node can only create the same nexus once it has been released.
+#[tokio::test]
+async fn persist_nexus_ownership() {
+    let test = start_infrastructure("persist_nexus_ownership").await;
+    let grpc = GrpcConnect::new(&test);
+    let ms1 = &mut grpc.grpc_handle("ms1").await.unwrap();
+    let ms2 = &mut grpc.grpc_handle("ms2").await.unwrap();
+    let ms3 = &mut grpc.grpc_handle("ms3").await.unwrap();
+    let ms4 = &mut grpc.grpc_handle("ms4").await.unwrap();
+
+    // Create bdevs and share over nvmf.
+    let child1 = create_and_share_bdevs(ms2, CHILD1_UUID).await;
+    let child2 = create_and_share_bdevs(ms3, CHILD2_UUID).await;
+
+    // Create a nexus.
+    let nexus_uuid = "8272e9d3-3738-4e33-b8c3-769d8eed5771";
+    create_nexus(ms1, nexus_uuid, vec![child1.clone(), child2.clone()]).await;
+
+    // The ownership record must be attached to a lease.
+    let owner_key = format!("{}/owner", nexus_uuid);
+    let mut etcd = Client::connect([ETCD_ENDPOINT], None).await.unwrap();
+    let response = etcd.get(owner_key.as_str(), None).await.unwrap();
+    let kv = response.kvs().first().expect("No ownership record found");
+    assert_ne!(kv.lease(), 0);
+
+    // The nexus is owned by ms1, so ms4 cannot create it.
+    let status = ms4
+        .mayastor
+        .create_nexus(CreateNexusRequest {
+            uuid: nexus_uuid.to_string(),
+            size: 20 * 1024 * 1024,
+            children: vec![child1.clone(), child2.clone()],
+        })
+        .await
+        .expect_err("Nexus should be owned by another node");
+    assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+
+    // Destroying the nexus releases the ownership.
+    ms1.mayastor
+        .destroy_nexus(DestroyNexusRequest {
+            uuid: nexus_uuid.to_string(),
+        })
+        .await
+        .expect("Failed to destroy nexus");
+
+    let response = etcd.get(owner_key.as_str(), None).await.unwrap();
+    assert!(response.kvs().is_empty());
+
+    create_nexus(ms4, nexus_uuid, vec![child1, child2]).await;
+}
+
 /// This test checks that the state of a child is successfully updated in the
 /// persistent store when there is an I/O failure.
 #[tokio::test]
@@ -349,9 +448,20 @@ async fn persistent_store_connection() {
 
 /// Start the containers for the tests.
 async fn start_infrastructure(test_name: &str) -> ComposeTest {
+    start_infrastructure_ext(test_name, vec![]).await
+}
+
+/// Start the containers for the tests, with the given extra arguments for
+/// the io-engine of ms1.
+async fn start_infrastructure_ext(
+    test_name: &str,
+    ms1_args: Vec<&str>,
+) -> ComposeTest {
     common::composer_init();
 
     let etcd_endpoint = format!("http://etcd.{}:2379", test_name);
+    let mut args = vec!["-p", etcd_endpoint.as_str()];
+    args.extend(ms1_args);
     let test = Builder::new()
         .name(test_name)
         .add_container_spec(
@@ -369,10 +479,7 @@ async fn start_infrastructure(test_name: &str) -> ComposeTest {
             .with_portmap("2379", "2379")
             .with_portmap("2380", "2380"),
         )
-        .add_container_bin(
-            "ms1",
-            Binary::from_dbg("io-engine").with_args(vec!["-p", &etcd_endpoint]),
-        )
+        .add_container_bin("ms1", Binary::from_dbg("io-engine").with_args(args))
         .add_container_bin(
             "ms2",
             Binary::from_dbg("io-engine").with_args(vec!["-p", &etcd_endpoint]),
@@ -484,6 +591,23 @@ async fn get_nexus(hdl: &mut RpcHandle, uuid: &str) -> Option<Nexus> {
     Some(n[0].clone())
 }
 
+/// Waits for the nexus with the given uuid to be listed, if expected, and
+/// returns it.
+async fn wait_nexus(
+    hdl: &mut RpcHandle,
+    uuid: &str,
+    expected: bool,
+) -> Option<Nexus> {
+    for _ in 0 .. 20 {
+        let nexus = get_nexus(hdl, uuid).await;
+        if nexus.is_some() || !expected {
+            return nexus;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    None
+}
+
 /// Returns the state of the nexus with the given uuid.
 async fn get_nexus_state(hdl: &mut RpcHandle, uuid: &str) -> Option<i32> {
     let list = hdl
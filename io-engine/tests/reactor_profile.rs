@@ -0,0 +1,26 @@
+use std::time::Duration;
+
+use io_engine::core::{reactor_profile::profile_reactor, MayastorCliArgs};
+
+pub mod common;
+
+#[tokio::test]
+async fn reactor_profile() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+
+    ms.spawn(async {
+        let profile = profile_reactor(0, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(profile.core, 0);
+        assert!(profile.duration_us >= 1_000_000);
+        assert!(profile
+            .stacks
+            .iter()
+            .any(|(stack, _)| stack.starts_with("reactor_0;thread;")));
+        assert_eq!(profile.folded().lines().count(), profile.stacks.len());
+
+        // invalid periods and cores are refused
+        assert!(profile_reactor(0, Duration::ZERO).await.is_err());
+        assert!(profile_reactor(1024, Duration::from_secs(1)).await.is_err());
+    })
+    .await;
+}
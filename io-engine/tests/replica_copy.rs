@@ -0,0 +1,58 @@
+use std::time::Duration;
+
+use io_engine::{
+    core::MayastorCliArgs,
+    lvs::{copy_job, copy_replica, CopyMode, CopyParams, CopyState, Lvs},
+    pool_backend::PoolArgs,
+};
+
+pub mod common;
+
+static POOL_DISK: &str = "malloc:///copy?size_mb=64";
+
+#[tokio::test]
+async fn replica_copy_snapshot() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+
+    ms.spawn(async {
+        Lvs::create_or_import(PoolArgs {
+            name: "copy".into(),
+            disks: vec![POOL_DISK.into()],
+            uuid: None,
+        })
+        .await
+        .unwrap();
+
+        let job = copy_replica(CopyParams {
+            id: "job".into(),
+            source: "malloc:///source?size_mb=8".into(),
+            pool: "copy".into(),
+            name: "replica".into(),
+            uuid: None,
+            thin: true,
+            mode: CopyMode::Snapshot,
+            snapshot: Some("replica-snap".into()),
+        })
+        .unwrap();
+        assert_eq!(job.state, CopyState::Starting);
+    })
+    .await;
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    ms.spawn(async {
+        let job = copy_job("job").unwrap();
+        assert_eq!(job.state, CopyState::Completed);
+        assert_eq!(job.total_bytes, 8 * 1024 * 1024);
+        // the source only reads as zeroes
+        assert_eq!(job.skipped_bytes, job.total_bytes);
+
+        let lvs = Lvs::lookup("copy").unwrap();
+        let lvols = lvs.lvols().unwrap().collect::<Vec<_>>();
+        assert!(lvols.iter().any(|l| l.name() == "replica"));
+        assert!(lvols
+            .iter()
+            .any(|l| l.name() == "replica-snap" && l.is_snapshot()));
+    })
+    .await;
+}
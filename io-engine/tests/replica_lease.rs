@@ -0,0 +1,232 @@
+use std::{pin::Pin, time::Duration};
+
+use once_cell::sync::OnceCell;
+
+use io_engine::{
+    bdev::nexus::{nexus_create, nexus_lookup_mut, ChildState},
+    constants::NVME_NQN_PREFIX,
+    core::MayastorCliArgs,
+    lvs::{LeaseError, Lvs},
+    pool_backend::PoolArgs,
+};
+
+pub mod common;
+
+use common::{
+    compose::{
+        rpc::v0::{
+            mayastor::{
+                BdevShareRequest,
+                BdevUri,
+                CreateNexusRequest,
+                CreatePoolRequest,
+                CreateReplicaRequest,
+                JsonRpcRequest,
+            },
+            GrpcConnect,
+        },
+        Builder,
+    },
+    MayastorTest,
+};
+
+static POOL_DISK: &str = "malloc:///lease?size_mb=64";
+static NEXUS_A: &str = "b4a7f7c4-0b58-4b39-9f8f-0e4ac7a1c001";
+static NEXUS_B: &str = "b4a7f7c4-0b58-4b39-9f8f-0e4ac7a1c002";
+
+static REMOTE_POOL: &str = "remote_lease";
+static REMOTE_POOL_DISK: &str = "malloc:///remote_lease?size_mb=64";
+static REPL_UUID: &str = "b4a7f7c4-0b58-4b39-9f8f-0e4ac7a1c003";
+static NXNAME: &str = "lease_nexus";
+static NEXUS_SIZE: u64 = 16 * 1024 * 1024;
+
+static MAYASTOR: OnceCell<MayastorTest> = OnceCell::new();
+
+fn get_ms() -> &'static MayastorTest<'static> {
+    MAYASTOR.get_or_init(|| MayastorTest::new(MayastorCliArgs::default()))
+}
+
+#[tokio::test]
+async fn replica_lease() {
+    let ms = get_ms();
+
+    ms.spawn(async {
+        let pool = Lvs::create_or_import(PoolArgs {
+            name: "lease".into(),
+            disks: vec![POOL_DISK.into()],
+            uuid: None,
+        })
+        .await
+        .unwrap();
+        let mut lvol = pool
+            .create_lvol("replica", 8 * 1024 * 1024, None, true)
+            .await
+            .unwrap();
+        let ttl = Duration::from_secs(60);
+
+        // the lease can be renewed by its nexus, but not taken by another
+        let lease = Pin::new(&mut lvol)
+            .acquire_lease(NEXUS_A, "node-a", ttl)
+            .await
+            .unwrap();
+        assert_eq!(lvol.lease().await, Some(lease));
+        Pin::new(&mut lvol)
+            .acquire_lease(NEXUS_A, "node-a", ttl)
+            .await
+            .unwrap();
+        let error = Pin::new(&mut lvol)
+            .acquire_lease(NEXUS_B, "node-b", ttl)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, LeaseError::LeaseHeld { .. }));
+
+        // nor by the same nexus recreated on another node, which cannot
+        // release it either
+        let error = Pin::new(&mut lvol)
+            .acquire_lease(NEXUS_A, "node-b", ttl)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, LeaseError::LeaseHeld { .. }));
+        Pin::new(&mut lvol)
+            .release_lease(NEXUS_A, "node-b")
+            .await
+            .unwrap();
+        assert_eq!(lvol.lease().await.unwrap().node, "node-a");
+
+        // until it is broken
+        let broken = Pin::new(&mut lvol).break_lease().await.unwrap();
+        assert_eq!(broken.unwrap().nexus, NEXUS_A);
+        Pin::new(&mut lvol)
+            .acquire_lease(NEXUS_B, "node-b", ttl)
+            .await
+            .unwrap();
+
+        // or it expires
+        Pin::new(&mut lvol)
+            .release_lease(NEXUS_A, "node-a")
+            .await
+            .unwrap();
+        assert_eq!(lvol.lease().await.unwrap().nexus, NEXUS_B);
+        Pin::new(&mut lvol)
+            .acquire_lease(NEXUS_B, "node-b", Duration::from_secs(0))
+            .await
+            .unwrap();
+        Pin::new(&mut lvol)
+            .acquire_lease(NEXUS_A, "node-a", ttl)
+            .await
+            .unwrap();
+
+        pool.destroy().await.unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn replica_lease_nexus() {
+    common::composer_init();
+
+    let test = Builder::new()
+        .name("cargo-test")
+        .network("10.1.0.0/16")
+        .unwrap()
+        .add_container_dbg("ms1")
+        .with_clean(true)
+        .build()
+        .await
+        .unwrap();
+
+    let grpc = GrpcConnect::new(&test);
+    let mut hdls = grpc.grpc_handles().await.unwrap();
+
+    // a replica, and a device which is not a replica, hence has no lease
+    hdls[0]
+        .mayastor
+        .create_pool(CreatePoolRequest {
+            name: REMOTE_POOL.to_string(),
+            disks: vec![REMOTE_POOL_DISK.to_string()],
+        })
+        .await
+        .unwrap();
+    hdls[0]
+        .mayastor
+        .create_replica(CreateReplicaRequest {
+            uuid: REPL_UUID.to_string(),
+            pool: REMOTE_POOL.to_string(),
+            size: 32 * 1024 * 1024,
+            thin: false,
+            share: 1,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    hdls[0]
+        .bdev
+        .create(BdevUri {
+            uri: "malloc:///disk0?size_mb=64".into(),
+        })
+        .await
+        .unwrap();
+    hdls[0]
+        .bdev
+        .share(BdevShareRequest {
+            name: "disk0".into(),
+            proto: "nvmf".into(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+    let ip0 = hdls[0].endpoint.ip();
+    let replica =
+        format!("nvmf://{}:8420/{}:{}", ip0, NVME_NQN_PREFIX, REPL_UUID);
+    let disk = format!("nvmf://{}:8420/{}:disk0", ip0, NVME_NQN_PREFIX);
+
+    // the nexus takes the lease of the replica when opened
+    let ms = get_ms();
+    ms.spawn(async move {
+        nexus_create(NXNAME, NEXUS_SIZE, Some(NEXUS_A), &[replica, disk])
+            .await
+            .unwrap();
+    })
+    .await;
+
+    // so that another nexus can not open the replica
+    hdls[0]
+        .mayastor
+        .create_nexus(CreateNexusRequest {
+            uuid: NEXUS_B.to_string(),
+            size: NEXUS_SIZE,
+            children: vec![format!("loopback:///{}", REPL_UUID)],
+        })
+        .await
+        .expect_err("the replica is leased by another nexus");
+
+    // until its lease is broken and taken by the other nexus
+    hdls[0]
+        .jsonrpc
+        .json_rpc_call(JsonRpcRequest {
+            method: "replica_lease_break".to_string(),
+            params: format!("{{\"replica\": \"{}\"}}", REPL_UUID),
+        })
+        .await
+        .unwrap();
+    hdls[0]
+        .mayastor
+        .create_nexus(CreateNexusRequest {
+            uuid: NEXUS_B.to_string(),
+            size: NEXUS_SIZE,
+            children: vec![format!("loopback:///{}", REPL_UUID)],
+        })
+        .await
+        .unwrap();
+
+    // the nexus fails to renew the lease and faults the child of the
+    // replica, but keeps the other one
+    tokio::time::sleep(Duration::from_secs(25)).await;
+    ms.spawn(async {
+        let nexus = nexus_lookup_mut(NXNAME).unwrap();
+        assert!(matches!(nexus.child_at(0).state(), ChildState::Faulted(_)));
+        assert_eq!(nexus.child_at(1).state(), ChildState::Open);
+    })
+    .await;
+}
@@ -0,0 +1,73 @@
+use std::pin::Pin;
+
+use io_engine::{
+    core::MayastorCliArgs,
+    lvs::{Lvs, SnapshotSchedule, SnapshotScheduleError},
+    pool_backend::PoolArgs,
+};
+
+pub mod common;
+
+static POOL_DISK: &str = "malloc:///schedule?size_mb=64";
+
+#[tokio::test]
+async fn replica_snapshot_schedule() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+
+    ms.spawn(async {
+        let pool = Lvs::create_or_import(PoolArgs {
+            name: "schedule".into(),
+            disks: vec![POOL_DISK.into()],
+            uuid: None,
+        })
+        .await
+        .unwrap();
+        let mut lvol = pool
+            .create_lvol("replica", 8 * 1024 * 1024, None, true)
+            .await
+            .unwrap();
+        assert_eq!(lvol.snapshot_schedule().await, None);
+
+        // schedules which are too tight or keep nothing are refused
+        for schedule in [
+            SnapshotSchedule {
+                interval_secs: 1,
+                retention: 4,
+            },
+            SnapshotSchedule {
+                interval_secs: 300,
+                retention: 0,
+            },
+        ] {
+            let error = Pin::new(&mut lvol)
+                .set_snapshot_schedule(Some(&schedule))
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                error,
+                SnapshotScheduleError::InvalidSchedule { .. }
+            ));
+        }
+
+        let schedule = SnapshotSchedule {
+            interval_secs: 300,
+            retention: 4,
+        };
+        Pin::new(&mut lvol)
+            .set_snapshot_schedule(Some(&schedule))
+            .await
+            .unwrap();
+        let status = lvol.snapshot_schedule_status().await.unwrap();
+        assert_eq!(status.schedule, schedule);
+        assert!(status.snapshots.is_empty());
+
+        Pin::new(&mut lvol)
+            .set_snapshot_schedule(None)
+            .await
+            .unwrap();
+        assert_eq!(lvol.snapshot_schedule().await, None);
+
+        pool.destroy().await.unwrap();
+    })
+    .await;
+}
@@ -0,0 +1,37 @@
+use io_engine::{
+    bdev::nexus::{nexus_create, nexus_lookup_mut},
+    bdev_api::bdev_create,
+    core::{MayastorCliArgs, MayastorEnvironment},
+};
+
+pub mod common;
+
+#[tokio::test]
+async fn simulate() {
+    let ms = common::MayastorTest::new(MayastorCliArgs {
+        simulate: true,
+        ..Default::default()
+    });
+
+    ms.spawn(async {
+        assert!(MayastorEnvironment::global_or_default().simulate());
+
+        // only memory backed bdevs can be created
+        assert!(bdev_create("aio:///tmp/simulate.img?blk_size=512")
+            .await
+            .is_err());
+
+        let children = vec![
+            "malloc:///m0?size_mb=32".to_string(),
+            "null:///n0?size_mb=32".to_string(),
+        ];
+        nexus_create("simulate0", 16 * 1024 * 1024, None, &children)
+            .await
+            .unwrap();
+
+        let nexus = nexus_lookup_mut("simulate0").unwrap();
+        assert_eq!(nexus.child_count(), 2);
+        nexus.destroy().await.unwrap();
+    })
+    .await;
+}
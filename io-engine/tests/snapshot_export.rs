@@ -0,0 +1,64 @@
+use std::{convert::TryFrom, pin::Pin, time::Duration};
+
+use io_engine::{
+    core::{MayastorCliArgs, Protocol, Share, UntypedBdev},
+    lvs::{Lvol, Lvs, SnapshotExportError},
+    pool_backend::PoolArgs,
+};
+
+pub mod common;
+
+static POOL_DISK: &str = "malloc:///export?size_mb=64";
+
+fn snapshot() -> Lvol {
+    Lvol::try_from(UntypedBdev::lookup_by_name("snapshot").unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn snapshot_export() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+
+    ms.spawn(async {
+        let pool = Lvs::create_or_import(PoolArgs {
+            name: "export".into(),
+            disks: vec![POOL_DISK.into()],
+            uuid: None,
+        })
+        .await
+        .unwrap();
+        let mut lvol = pool
+            .create_lvol("replica", 8 * 1024 * 1024, None, true)
+            .await
+            .unwrap();
+        lvol.snapshot("snapshot").await.unwrap();
+
+        // only snapshots are exported
+        let error = Pin::new(&mut lvol)
+            .export_snapshot(vec![], None)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, SnapshotExportError::NotSnapshot { .. }));
+
+        let mut snapshot = snapshot();
+        let export = Pin::new(&mut snapshot)
+            .export_snapshot(vec![], Some(Duration::from_secs(1)))
+            .await
+            .unwrap();
+        assert_eq!(export.snapshot, "snapshot");
+        assert!(export.connected_hosts.is_empty());
+        assert_eq!(snapshot.shared(), Some(Protocol::Nvmf));
+    })
+    .await;
+
+    // no initiator connects within the idle timeout
+    tokio::time::sleep(Duration::from_secs(4)).await;
+
+    ms.spawn(async {
+        let snapshot = snapshot();
+        assert!(snapshot.snapshot_export().is_none());
+        assert_eq!(snapshot.shared(), Some(Protocol::Off));
+
+        Lvs::lookup("export").unwrap().destroy().await.unwrap();
+    })
+    .await;
+}
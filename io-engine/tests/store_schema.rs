@@ -0,0 +1,94 @@
+use io_engine::{
+    bdev::NexusInfo,
+    store::{
+        store_defs::StoreError,
+        store_schema::{
+            from_record,
+            record_version,
+            to_record,
+            Migration,
+            Versioned,
+        },
+    },
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+/// Record which gained a field in version 2 and renamed one in version 3.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Record {
+    name: String,
+    size: u64,
+    thin: bool,
+}
+
+fn add_thin(record: &mut Map<String, Value>) -> Result<(), String> {
+    record.insert("thin".to_string(), Value::Bool(false));
+    Ok(())
+}
+
+fn rename_capacity(record: &mut Map<String, Value>) -> Result<(), String> {
+    let capacity = record.remove("capacity").ok_or("no capacity")?;
+    record.insert("size".to_string(), capacity);
+    Ok(())
+}
+
+impl Versioned for Record {
+    const KIND: &'static str = "test record";
+    const MIGRATIONS: &'static [Migration] = &[add_thin, rename_capacity];
+}
+
+#[test]
+fn store_schema_migration() {
+    assert_eq!(Record::schema_version(), 3);
+
+    // a record without version is upgraded through all the migrations
+    let v1 = json!({"name": "r0", "capacity": 8});
+    let record = from_record::<Record>("r0", v1).unwrap();
+    assert_eq!(
+        record,
+        Record {
+            name: "r0".to_string(),
+            size: 8,
+            thin: false,
+        }
+    );
+
+    // a record of version 2 only goes through the last one
+    let v2 =
+        json!({"name": "r0", "capacity": 8, "thin": true, "schema_version": 2});
+    assert!(from_record::<Record>("r0", v2).unwrap().thin);
+
+    // records are written with the current version
+    assert_eq!(record_version(&to_record(&record).unwrap()), 3);
+
+    // and a newer record is refused
+    let v4 =
+        json!({"name": "r0", "size": 8, "thin": true, "schema_version": 4});
+    assert!(matches!(
+        from_record::<Record>("r0", v4),
+        Err(StoreError::SchemaDowngrade {
+            version: 4,
+            supported: 3,
+            ..
+        })
+    ));
+    let broken = json!({"name": "r0", "schema_version": 2});
+    assert!(matches!(
+        from_record::<Record>("r0", broken),
+        Err(StoreError::InvalidRecord { .. })
+    ));
+}
+
+#[test]
+fn store_schema_nexus_info() {
+    let legacy = json!({"clean_shutdown": false, "children": [
+        {"uuid": "c0", "healthy": true}
+    ]});
+    let info = from_record::<NexusInfo>("nexus", legacy).unwrap();
+    assert_eq!(info.children.len(), 1);
+    assert_eq!(
+        record_version(&to_record(&info).unwrap()),
+        NexusInfo::schema_version()
+    );
+}
@@ -0,0 +1,64 @@
+use std::pin::Pin;
+
+use io_engine::{
+    core::{MayastorCliArgs, Share},
+    labels::Labels,
+    lvs::Lvs,
+    pool_backend::PoolArgs,
+    tenant::{tenant_usage, TenantQuota, TENANT_LABEL},
+};
+
+pub mod common;
+
+static POOL_DISK: &str = "malloc:///tenant?size_mb=64";
+
+#[tokio::test]
+async fn tenant_quotas() {
+    let ms = common::MayastorTest::new(MayastorCliArgs::default());
+
+    ms.spawn(async {
+        let pool = Lvs::create_or_import(PoolArgs {
+            name: "tenant".into(),
+            disks: vec![POOL_DISK.into()],
+            uuid: None,
+        })
+        .await
+        .unwrap();
+
+        let tenant =
+            std::iter::once((TENANT_LABEL.to_string(), "blue".to_string()))
+                .collect::<Labels>();
+        let mut lvols = Vec::new();
+        for name in ["replica0", "replica1"] {
+            let mut lvol = pool
+                .create_lvol(name, 8 * 1024 * 1024, None, true)
+                .await
+                .unwrap();
+            Pin::new(&mut lvol).set_labels(&tenant).await.unwrap();
+            lvols.push(lvol);
+        }
+
+        let usage = tenant_usage("blue").await;
+        assert_eq!(usage.replicas, 2);
+        assert_eq!(usage.capacity, 16 * 1024 * 1024);
+        assert_eq!(usage.shared_targets, 0);
+
+        TenantQuota::set(
+            "blue",
+            TenantQuota {
+                shared_targets: Some(1),
+                ..Default::default()
+            },
+        );
+        Pin::new(&mut lvols[0]).share_nvmf(None).await.unwrap();
+        assert!(Pin::new(&mut lvols[1]).share_nvmf(None).await.is_err());
+        assert_eq!(tenant_usage("blue").await.shared_targets, 1);
+
+        // without quotas, the tenant is not limited
+        TenantQuota::set("blue", TenantQuota::default());
+        Pin::new(&mut lvols[1]).share_nvmf(None).await.unwrap();
+
+        pool.destroy().await.unwrap();
+    })
+    .await;
+}